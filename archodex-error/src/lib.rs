@@ -11,6 +11,8 @@ use serde::Serialize;
 pub struct PublicError {
     status_code: axum::http::StatusCode,
     message: String,
+    code: Option<&'static str>,
+    retry_after: Option<std::time::Duration>,
 }
 
 // Generates strings like "409 Conflict: Account already exists"
@@ -25,8 +27,29 @@ impl PublicError {
         Self {
             status_code,
             message: message.into(),
+            code: None,
+            retry_after: None,
         }
     }
+
+    // Sets a machine readable code, e.g. "record_exists", that clients can match on instead of the message
+    #[must_use]
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    // Sets the `Retry-After` header of the response, e.g. for 503 errors caused by transient failures
+    #[must_use]
+    pub fn with_retry_after(mut self, retry_after: std::time::Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    #[must_use]
+    pub fn status_code(&self) -> StatusCode {
+        self.status_code
+    }
 }
 
 pub type Result<T> = std::result::Result<T, PublicError>;
@@ -37,15 +60,93 @@ impl IntoResponse for PublicError {
         #[derive(Serialize)]
         struct PublicErrorMessage {
             message: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            code: Option<&'static str>,
         }
 
-        (
+        let mut response = (
             self.status_code,
             Json(PublicErrorMessage {
                 message: self.message,
+                code: self.code,
             }),
         )
-            .into_response()
+            .into_response();
+
+        if let Some(retry_after) = self.retry_after {
+            response.headers_mut().insert(
+                axum::http::header::RETRY_AFTER,
+                axum::http::HeaderValue::from(retry_after.as_secs()),
+            );
+        }
+
+        response
+    }
+}
+
+// Maps SurrealDB errors that clients can react to, e.g. by retrying or by choosing another ID, to public errors. Every
+// other SurrealDB error remains a 500. Embedded databases return structured errors but remote databases only return
+// error messages, so errors are matched by their messages.
+fn public_error_from_surrealdb_error(err: &surrealdb::Error) -> Option<PublicError> {
+    use surrealdb::error::{Api, Db};
+
+    let message = match err {
+        surrealdb::Error::Db(err) => err.to_string(),
+        surrealdb::Error::Api(Api::Query(message)) => message.clone(),
+        surrealdb::Error::Api(Api::Ws(_) | Api::Http(_) | Api::ConnectionUninitialised) => {
+            return Some(
+                PublicError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Database is unavailable, please retry later",
+                )
+                .with_code("database_unavailable")
+                .with_retry_after(std::time::Duration::from_secs(5)),
+            );
+        }
+        _ => return None,
+    };
+
+    let public_error = if message.starts_with("Database record `")
+        && message.ends_with("` already exists")
+    {
+        PublicError::new(StatusCode::CONFLICT, "Record already exists").with_code("record_exists")
+    } else if message.starts_with("Database index `") && message.contains("` already contains ") {
+        PublicError::new(
+            StatusCode::CONFLICT,
+            "A record with the same unique values already exists",
+        )
+        .with_code("unique_index_violation")
+    } else if message == Db::QueryTimedout.to_string() {
+        PublicError::new(StatusCode::REQUEST_TIMEOUT, "Database query timed out")
+            .with_code("query_timeout")
+    } else if message == Db::TxRetryable.to_string() {
+        PublicError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Database is busy, please retry later",
+        )
+        .with_code("transaction_conflict")
+        .with_retry_after(std::time::Duration::from_secs(1))
+    } else {
+        return None;
+    };
+
+    Some(public_error)
+}
+
+// Logs every context of the error as a separate entry of the `error_chain` field. The event is emitted in the span the
+// error was converted in, so the request span's `request_id` and the `account_id` of account spans are attached to it by
+// the subscriber.
+fn log_error(err: &anyhow::Error, level: tracing::Level) {
+    let error_chain = err.chain().map(ToString::to_string).collect::<Vec<_>>();
+
+    let backtrace = err.backtrace();
+    let backtrace = (backtrace.status() == std::backtrace::BacktraceStatus::Captured)
+        .then(|| tracing::field::display(backtrace));
+
+    if level == tracing::Level::WARN {
+        tracing::warn!(error = %err, ?error_chain, backtrace, "Request failed with a database error");
+    } else {
+        tracing::error!(error = %err, ?error_chain, backtrace, "Request failed with an internal error");
     }
 }
 
@@ -65,7 +166,16 @@ where
             };
         }
 
-        eprintln!("{err:?}\n\n");
+        if let Some(public_error) = err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<surrealdb::Error>())
+            .and_then(public_error_from_surrealdb_error)
+        {
+            log_error(&err, tracing::Level::WARN);
+            return public_error;
+        }
+
+        log_error(&err, tracing::Level::ERROR);
 
         Self::new(
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -1,4 +1,59 @@
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Commit the backend is built from. Builds outside a git checkout (e.g. from a source archive) may set
+// `ARCHODEX_GIT_SHA` instead.
+fn git_sha() -> String {
+    println!("cargo:rerun-if-env-changed=ARCHODEX_GIT_SHA");
+
+    if let Ok(git_sha) = std::env::var("ARCHODEX_GIT_SHA") {
+        return git_sha;
+    }
+
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|output| output.trim().to_string())
+    };
+
+    // Rebuild when a commit is checked out or made, so the SHA and build timestamp don't go stale
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        println!("cargo:rerun-if-changed={git_dir}/refs");
+    }
+
+    git(&["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".to_string())
+}
+
+// Seconds since the Unix epoch, or `SOURCE_DATE_EPOCH` for reproducible builds
+fn build_timestamp() -> u64 {
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|timestamp| timestamp.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("System time should be after the Unix epoch")
+                .as_secs()
+        })
+}
+
 fn main() -> std::io::Result<()> {
-    prost_build::compile_protos(&["src/report_api_key.proto"], &["src/"])?;
+    prost_build::compile_protos(&["src/report_api_key.proto", "src/report.proto"], &["src/"])?;
+
+    println!("cargo:rustc-env=ARCHODEX_GIT_SHA={}", git_sha());
+    println!(
+        "cargo:rustc-env=ARCHODEX_BUILD_TIMESTAMP={}",
+        build_timestamp()
+    );
+
     Ok(())
 }
@@ -0,0 +1,115 @@
+// Reports an AWS Lambda function reading a secret through its IAM role to an Archodex backend.
+//
+// Run from the client's directory:
+//
+//	ARCHODEX_REPORT_API_KEY=... go run ./examples/report
+//
+// ARCHODEX_URL defaults to a self-hosted backend on http://localhost:5732.
+package main
+
+import (
+	"context"
+	"encoding/json"
+	"fmt"
+	"log"
+	"os"
+	"time"
+
+	archodex "github.com/archodex/archodex-backend/clients/go"
+)
+
+type object = map[string]any
+
+// with returns a copy of base with the fields of each of others added
+func with(base object, others ...object) object {
+	merged := object{}
+	for _, fields := range append([]object{base}, others...) {
+		for key, value := range fields {
+			merged[key] = value
+		}
+	}
+	return merged
+}
+
+// buildReport returns the report in the JSON form of `POST /report`, as an agent would capture it
+func buildReport(now string) object {
+	seen := object{"first_seen_at": now, "last_seen_at": now}
+
+	partition := object{"type": "Partition", "id": "aws"}
+	account := object{"type": "Account", "id": "123456789012"}
+	region := object{"type": "Region", "id": "us-east-1"}
+	function := object{"type": "Lambda Function", "id": "orders-api"}
+	role := object{"type": "IAM Role", "id": "orders-api"}
+	secret := object{"type": "Secret", "id": "orders/database-password"}
+
+	return object{
+		"resource_captures": []object{
+			with(partition, seen, object{
+				"globally_unique": true,
+				"contains": []object{
+					with(account, seen, object{
+						"contains": []object{
+							with(region, seen, object{
+								"contains": []object{
+									with(function, seen),
+									with(secret, seen, object{"attributes": object{"rotation_enabled": true}}),
+								},
+							}),
+							with(role, seen),
+						},
+					}),
+				},
+			}),
+		},
+		"event_captures": []object{
+			{
+				"principals": []object{
+					{"id": []object{partition, account, region, function}},
+					{"id": []object{partition, account, role}},
+				},
+				"resources": [][]object{{partition, account, region, secret}},
+				"events":    []object{with(object{"type": "read"}, seen)},
+			},
+		},
+	}
+}
+
+func main() {
+	reportAPIKey := os.Getenv("ARCHODEX_REPORT_API_KEY")
+	if reportAPIKey == "" {
+		log.Fatal("ARCHODEX_REPORT_API_KEY must be set to a report API key of the account")
+	}
+
+	url := os.Getenv("ARCHODEX_URL")
+	if url == "" {
+		url = "http://localhost:5732"
+	}
+
+	reportJSON, err := json.Marshal(buildReport(time.Now().UTC().Format(time.RFC3339)))
+	if err != nil {
+		log.Fatalf("Failed to serialize report: %v", err)
+	}
+
+	var report archodex.Report
+	if err := json.Unmarshal(reportJSON, &report); err != nil {
+		log.Fatalf("Failed to build report: %v", err)
+	}
+
+	configuration := archodex.NewConfiguration()
+	configuration.Servers = archodex.ServerConfigurations{{URL: url}}
+	client := archodex.NewAPIClient(configuration)
+
+	ctx := context.WithValue(context.Background(), archodex.ContextAPIKeys, map[string]archodex.APIKey{
+		"report_api_key": {Key: reportAPIKey},
+	})
+
+	result, _, err := client.ReportAPI.Report(ctx).Report(report).Execute()
+	if err != nil {
+		log.Fatalf("Failed to send report: %v", err)
+	}
+
+	fmt.Printf(
+		"Created %d and updated %d resources, inserted %d and updated %d events\n",
+		result.ResourcesCreated, result.ResourcesUpdated, result.EventsInserted, result.EventsUpdated,
+	)
+}
@@ -0,0 +1,89 @@
+"""Reports an AWS Lambda function reading a secret through its IAM role to an Archodex backend.
+
+Run after installing the client with `pip install .` from the client's directory:
+
+    ARCHODEX_REPORT_API_KEY=... python examples/report.py
+
+`ARCHODEX_URL` defaults to a self-hosted backend on `http://localhost:5732`.
+"""
+
+import os
+import sys
+from datetime import datetime, timezone
+
+import archodex_client
+
+
+def build_report(now: str) -> dict:
+    """The report in the JSON form of `POST /report`, as an agent would capture it."""
+
+    seen = {"first_seen_at": now, "last_seen_at": now}
+
+    partition = {"type": "Partition", "id": "aws"}
+    account = {"type": "Account", "id": "123456789012"}
+    region = {"type": "Region", "id": "us-east-1"}
+    function = {"type": "Lambda Function", "id": "orders-api"}
+    role = {"type": "IAM Role", "id": "orders-api"}
+    secret = {"type": "Secret", "id": "orders/database-password"}
+
+    return {
+        "resource_captures": [
+            {
+                **partition,
+                **seen,
+                "globally_unique": True,
+                "contains": [
+                    {
+                        **account,
+                        **seen,
+                        "contains": [
+                            {
+                                **region,
+                                **seen,
+                                "contains": [
+                                    {**function, **seen},
+                                    {**secret, **seen, "attributes": {"rotation_enabled": True}},
+                                ],
+                            },
+                            {**role, **seen},
+                        ],
+                    }
+                ],
+            }
+        ],
+        "event_captures": [
+            {
+                "principals": [
+                    {"id": [partition, account, region, function]},
+                    {"id": [partition, account, role]},
+                ],
+                "resources": [[partition, account, region, secret]],
+                "events": [{"type": "read", **seen}],
+            }
+        ],
+    }
+
+
+def main() -> None:
+    report_api_key = os.environ.get("ARCHODEX_REPORT_API_KEY")
+    if not report_api_key:
+        sys.exit("ARCHODEX_REPORT_API_KEY must be set to a report API key of the account")
+
+    configuration = archodex_client.Configuration(
+        host=os.environ.get("ARCHODEX_URL", "http://localhost:5732"),
+        api_key={"report_api_key": report_api_key},
+    )
+
+    report = archodex_client.Report.from_dict(build_report(datetime.now(timezone.utc).isoformat()))
+
+    with archodex_client.ApiClient(configuration) as api_client:
+        result = archodex_client.ReportApi(api_client).report(report=report)
+
+    print(
+        f"Created {result.resources_created} and updated {result.resources_updated} resources, "
+        f"inserted {result.events_inserted} and updated {result.events_updated} events"
+    )
+
+
+if __name__ == "__main__":
+    main()
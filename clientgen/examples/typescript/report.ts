@@ -0,0 +1,78 @@
+// Reports an AWS Lambda function reading a secret through its IAM role to an Archodex backend.
+//
+// Run from the client's directory after `npm install`:
+//
+//   ARCHODEX_REPORT_API_KEY=... npx tsx examples/report.ts
+//
+// `ARCHODEX_URL` defaults to a self-hosted backend on `http://localhost:5732`.
+
+import { Configuration, ReportApi, ReportFromJSON } from '../src';
+
+const url = process.env.ARCHODEX_URL ?? 'http://localhost:5732';
+const reportApiKey = process.env.ARCHODEX_REPORT_API_KEY;
+
+// The report in the JSON form of `POST /report`, as an agent would capture it
+function buildReport(now: string) {
+  const seen = { first_seen_at: now, last_seen_at: now };
+
+  const partition = { type: 'Partition', id: 'aws' };
+  const account = { type: 'Account', id: '123456789012' };
+  const region = { type: 'Region', id: 'us-east-1' };
+  const fn = { type: 'Lambda Function', id: 'orders-api' };
+  const role = { type: 'IAM Role', id: 'orders-api' };
+  const secret = { type: 'Secret', id: 'orders/database-password' };
+
+  return {
+    resource_captures: [
+      {
+        ...partition,
+        ...seen,
+        globally_unique: true,
+        contains: [
+          {
+            ...account,
+            ...seen,
+            contains: [
+              {
+                ...region,
+                ...seen,
+                contains: [
+                  { ...fn, ...seen },
+                  { ...secret, ...seen, attributes: { rotation_enabled: true } },
+                ],
+              },
+              { ...role, ...seen },
+            ],
+          },
+        ],
+      },
+    ],
+    event_captures: [
+      {
+        principals: [{ id: [partition, account, region, fn] }, { id: [partition, account, role] }],
+        resources: [[partition, account, region, secret]],
+        events: [{ type: 'read', ...seen }],
+      },
+    ],
+  };
+}
+
+async function main() {
+  if (!reportApiKey) {
+    throw new Error('ARCHODEX_REPORT_API_KEY must be set to a report API key of the account');
+  }
+
+  const api = new ReportApi(new Configuration({ basePath: url, apiKey: reportApiKey }));
+
+  const result = await api.report({ report: ReportFromJSON(buildReport(new Date().toISOString())) });
+
+  console.log(
+    `Created ${result.resourcesCreated} and updated ${result.resourcesUpdated} resources, ` +
+      `inserted ${result.eventsInserted} and updated ${result.eventsUpdated} events`,
+  );
+}
+
+main().catch((err) => {
+  console.error(err);
+  process.exit(1);
+});
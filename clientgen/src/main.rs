@@ -0,0 +1,147 @@
+//! Generates TypeScript, Python, and Go clients of the Archodex API from its OpenAPI specification.
+//!
+//! Run with `cargo run -p clientgen [output directory]` (default `clients`) after changing the API. The specification is
+//! written as `openapi.json`, and each client is generated from it into its own subdirectory by
+//! [OpenAPI Generator](https://openapi-generator.tech) at the version pinned in `clientgen/openapitools.json`. The
+//! generator is run through `npx`, so Node.js and Java must be on the `PATH`.
+//!
+//! Each client also gets an example agent sending a report with it, copied from `clientgen/examples/` into the client's
+//! `examples/` directory. `cargo xtask clients` generates the clients and builds each of them, examples included.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Context as _, bail};
+
+// Version of the npm wrapper that downloads and runs the generator version pinned in `openapitools.json`
+const GENERATOR_CLI_PACKAGE: &str = "@openapitools/openapi-generator-cli@2.20.2";
+
+struct Client {
+    // OpenAPI Generator generator name
+    generator: &'static str,
+    // Subdirectory of the output directory the client is generated into
+    dir: &'static str,
+    // `--additional-properties` of the generator, without the package version
+    properties: &'static str,
+    // Name of the additional property the generator takes the package version from
+    version_property: &'static str,
+    // Further generator arguments
+    extra_args: &'static [&'static str],
+    // Path of the client's example relative to its directory, and the example's source
+    example: (&'static str, &'static str),
+}
+
+const CLIENTS: &[Client] = &[
+    Client {
+        generator: "typescript-fetch",
+        dir: "typescript",
+        properties: "npmName=@archodex/client,supportsES6=true",
+        version_property: "npmVersion",
+        extra_args: &[],
+        example: (
+            "examples/report.ts",
+            include_str!("../examples/typescript/report.ts"),
+        ),
+    },
+    Client {
+        generator: "python",
+        dir: "python",
+        properties: "packageName=archodex_client,projectName=archodex-client",
+        version_property: "packageVersion",
+        extra_args: &[],
+        example: (
+            "examples/report.py",
+            include_str!("../examples/python/report.py"),
+        ),
+    },
+    Client {
+        generator: "go",
+        dir: "go",
+        properties: "packageName=archodex,isGoSubmodule=true",
+        version_property: "packageVersion",
+        // The module is `github.com/archodex/archodex-backend/clients/go`
+        extra_args: &[
+            "--git-host",
+            "github.com",
+            "--git-user-id",
+            "archodex",
+            "--git-repo-id",
+            "archodex-backend/clients/go",
+        ],
+        // Each Go program needs its own package directory
+        example: (
+            "examples/report/main.go",
+            include_str!("../examples/go/main.go"),
+        ),
+    },
+];
+
+fn generate(spec_path: &Path, out_dir: &Path, client: &Client) -> anyhow::Result<()> {
+    let version = env!("CARGO_PKG_VERSION");
+
+    // Run from the crate's directory so the wrapper finds `openapitools.json`
+    let status = Command::new("npx")
+        .args(["--yes", GENERATOR_CLI_PACKAGE, "generate", "--input-spec"])
+        .arg(spec_path)
+        .args(["--generator-name", client.generator, "--output"])
+        .arg(out_dir.join(client.dir))
+        .arg(format!(
+            "--additional-properties={},{}={version}",
+            client.properties, client.version_property
+        ))
+        .args(client.extra_args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .context("Failed to run OpenAPI Generator. Is Node.js (`npx`) installed?")?;
+
+    if !status.success() {
+        bail!(
+            "OpenAPI Generator failed to generate the {} client with {status}",
+            client.generator
+        );
+    }
+
+    let (example_path, example) = client.example;
+    let example_path = out_dir.join(client.dir).join(example_path);
+
+    if let Some(parent) = example_path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+
+    std::fs::write(&example_path, example)
+        .with_context(|| format!("Failed to write {}", example_path.display()))?;
+
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let out_dir = std::env::args()
+        .nth(1)
+        .map_or_else(|| PathBuf::from("clients"), PathBuf::from);
+
+    // The generator runs in another directory, so it's given absolute paths
+    let out_dir = std::path::absolute(&out_dir)
+        .with_context(|| format!("Failed to resolve output directory {}", out_dir.display()))?;
+
+    std::fs::create_dir_all(&out_dir)
+        .with_context(|| format!("Failed to create directory {}", out_dir.display()))?;
+
+    let spec = serde_json::to_string_pretty(&archodex_backend::openapi::spec())
+        .context("Failed to serialize OpenAPI specification")?;
+
+    let spec_path = out_dir.join("openapi.json");
+
+    std::fs::write(&spec_path, spec + "\n")
+        .with_context(|| format!("Failed to write {}", spec_path.display()))?;
+
+    for client in CLIENTS {
+        generate(&spec_path, &out_dir, client)?;
+    }
+
+    println!("Generated clients in {}", out_dir.display());
+
+    Ok(())
+}
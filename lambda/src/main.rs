@@ -1,6 +1,19 @@
-use std::{io, thread};
+use std::{io, net::SocketAddr, thread};
 
+use archodex_backend::scheduled_job::ScheduledJob;
+use axum::{
+    Router,
+    extract::{ConnectInfo, Request},
+};
 use futures_lite::future;
+use lambda_http::{
+    Adapter, LambdaEvent, RequestExt as _, lambda_runtime,
+    request::{LambdaRequest, RequestContext},
+    service_fn,
+    tower::ServiceExt as _,
+};
+use serde::{Deserialize, de::IgnoredAny};
+use serde_json::{json, value::RawValue};
 use tokio::runtime::Builder;
 
 fn setup_logging() {
@@ -21,6 +34,11 @@ fn setup_logging() {
 fn main() -> Result<(), io::Error> {
     setup_logging();
 
+    if let Err(diagnostics) = archodex_backend::env::Env::validate() {
+        eprintln!("{diagnostics}");
+        std::process::exit(1);
+    }
+
     // Create a channel used to send and receive outputs from our lambda handler. Realistically, this would be either an unbounded channel
     // or a bounded channel with a higher capacity as needed.
     let (lambda_tx, lambda_rx) = async_channel::bounded(1);
@@ -38,8 +56,10 @@ fn main() -> Result<(), io::Error> {
 
     // Run the lambda runtime worker thread to completion. The response is sent to the other "runtime" to be processed as needed.
     thread::spawn(move || {
-        let router = archodex_backend::router::router();
-        if let Ok(response) = tokio_runtime.block_on(lambda_http::run(router)) {
+        let router = archodex_backend::router::router()
+            .layer(axum::middleware::map_request(insert_connect_info));
+        let handler = service_fn(move |event| handle(router.clone(), event));
+        if let Ok(response) = tokio_runtime.block_on(lambda_runtime::run(handler)) {
             lambda_tx
                 .send_blocking(response)
                 .expect("send lambda result");
@@ -53,6 +73,67 @@ fn main() -> Result<(), io::Error> {
     future::block_on(shutdown_rx.recv()).map_err(|err| io::Error::other(format!("{err:?}")))
 }
 
+/// Payload of invocations by EventBridge schedules, whose input is set to the job to run, e.g.
+/// `{"scheduled_job": "event_retention"}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduledJobEvent {
+    scheduled_job: ScheduledJob,
+}
+
+/// Tells scheduled events from API Gateway requests by their `scheduled_job` key, whichever job it names.
+#[derive(Deserialize)]
+struct InvocationKind {
+    scheduled_job: Option<IgnoredAny>,
+}
+
+/// Handles an invocation, which is either an API Gateway request served by the router or a scheduled event running a
+/// background job. archodex.com runs background jobs on EventBridge schedules, as Lambda functions can't run workers
+/// between invocations. Failed jobs fail the invocation, so EventBridge retries them.
+async fn handle(
+    router: Router,
+    event: LambdaEvent<Box<RawValue>>,
+) -> Result<serde_json::Value, lambda_http::Error> {
+    let LambdaEvent { payload, context } = event;
+
+    // Scheduled events that don't name a known job fail with the reason rather than as malformed API Gateway requests
+    if serde_json::from_str::<InvocationKind>(payload.get())?
+        .scheduled_job
+        .is_some()
+    {
+        let ScheduledJobEvent { scheduled_job } = serde_json::from_str(payload.get())
+            .map_err(|err| format!("Invalid scheduled job event: {err}"))?;
+
+        scheduled_job.run().await?;
+
+        return Ok(json!({ "scheduled_job": scheduled_job }));
+    }
+
+    let request = serde_json::from_str::<LambdaRequest>(payload.get())?;
+
+    let response = Adapter::from(router)
+        .oneshot(LambdaEvent::new(request, context))
+        .await?;
+
+    Ok(serde_json::to_value(response)?)
+}
+
+/// Records the address API Gateway received the request from as the peer address, which the backend resolves the
+/// client's address from. API Gateway doesn't provide the source port.
+async fn insert_connect_info(mut req: Request) -> Request {
+    let source_ip = match req.request_context_ref() {
+        Some(RequestContext::ApiGatewayV1(context)) => context.identity.source_ip.as_deref(),
+        _ => None,
+    };
+
+    if let Some(source_ip) = source_ip.and_then(|source_ip| source_ip.parse().ok()) {
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::new(source_ip, 0)));
+    }
+
+    req
+}
+
 /// A task to be ran on the custom runtime. Once a response from the lambda runtime is received then a shutdown signal
 /// is sent to the main thread notifying the process to exit.
 pub(crate) async fn app_runtime_task(
@@ -0,0 +1,167 @@
+use std::num::NonZeroUsize;
+
+use anyhow::{Context as _, bail};
+use tokio::task::JoinSet;
+use tracing::{info, instrument, warn};
+
+use crate::{AccountsShard, RESOURCES_MIGRATIONS, apply_pending_migrations, connect};
+
+/// Number of resources databases `migrate_all_account_resources_databases` migrates at a time by default.
+pub const DEFAULT_ACCOUNTS_CONCURRENCY: NonZeroUsize = NonZeroUsize::new(8).unwrap();
+
+// A resources database to migrate, along with its account unless it is the resources database shared by self-hosted
+// accounts
+struct ResourcesDatabase {
+    account_id: Option<String>,
+    surrealdb_url: String,
+    namespace: String,
+}
+
+// Self-hosted accounts share the resources database in the `archodex` namespace of the accounts database's SurrealDB
+// instance
+#[cfg(not(feature = "archodex-com"))]
+fn list_resources_databases(shards: &[AccountsShard]) -> Vec<ResourcesDatabase> {
+    shards
+        .iter()
+        .map(|shard| ResourcesDatabase {
+            account_id: None,
+            surrealdb_url: shard.surrealdb_url.clone(),
+            namespace: "archodex".to_string(),
+        })
+        .collect()
+}
+
+// Each archodex.com account has its own namespace in its service data SurrealDB instance. Deleted accounts and accounts
+// whose resources database hasn't been provisioned yet are skipped.
+#[cfg(feature = "archodex-com")]
+async fn list_resources_databases(
+    shards: &[AccountsShard],
+    creds: Option<surrealdb::opt::auth::Root<'_>>,
+) -> Result<Vec<ResourcesDatabase>, anyhow::Error> {
+    #[derive(serde::Deserialize)]
+    struct AccountRecord {
+        id: String,
+        service_data_surrealdb_url: String,
+    }
+
+    let mut databases = vec![];
+
+    for shard in shards {
+        let accounts = crate::connect_accounts_database(&shard.surrealdb_url, creds)
+            .await?
+            .query("SELECT record::id(id) AS id, service_data_surrealdb_url FROM account WHERE deleted_at IS NONE AND service_data_surrealdb_url IS NOT NONE ORDER BY id")
+            .await?
+            .check()?
+            .take::<Vec<AccountRecord>>(0)?;
+
+        databases.extend(accounts.into_iter().map(|account| ResourcesDatabase {
+            namespace: format!("a{}", account.id),
+            account_id: Some(account.id),
+            surrealdb_url: account.service_data_surrealdb_url,
+        }));
+    }
+
+    Ok(databases)
+}
+
+async fn migrate_resources_database(
+    database: &ResourcesDatabase,
+    creds: Option<surrealdb::opt::auth::Root<'_>>,
+) -> Result<usize, anyhow::Error> {
+    let db = connect(&database.surrealdb_url, creds).await?;
+
+    db.use_ns(&database.namespace).use_db("resources").await?;
+
+    apply_pending_migrations(&db, "resources", RESOURCES_MIGRATIONS).await
+}
+
+/// Applies pending migrations to the resources database of every account in the accounts database shards, e.g. to roll
+/// out a resources schema change to existing accounts, which are otherwise only migrated when they are created.
+///
+/// Up to `concurrency` databases are migrated at a time, and progress is logged as each finishes. An account that fails
+/// to migrate doesn't stop the others from being migrated, and migrating again only applies the migrations still
+/// pending, so a partly failed run can be retried.
+///
+/// # Errors
+///
+/// Will return `Err` if a shard can't be queried for its accounts, or listing the accounts that failed to migrate.
+#[instrument(err, skip(shards, creds))]
+pub async fn migrate_all_account_resources_databases(
+    shards: &[AccountsShard],
+    creds: Option<surrealdb::opt::auth::Root<'static>>,
+    concurrency: NonZeroUsize,
+) -> Result<(), anyhow::Error> {
+    #[cfg(not(feature = "archodex-com"))]
+    let databases = list_resources_databases(shards);
+    #[cfg(feature = "archodex-com")]
+    let databases = list_resources_databases(shards, creds).await?;
+    let total = databases.len();
+
+    info!(total, "Migrating account resources databases...");
+
+    let mut databases = databases.into_iter();
+    let mut tasks = JoinSet::new();
+    let mut finished = 0;
+    let mut applied = 0;
+    let mut failed_accounts = vec![];
+
+    loop {
+        while tasks.len() < concurrency.get()
+            && let Some(database) = databases.next()
+        {
+            tasks.spawn(async move {
+                let res = migrate_resources_database(&database, creds).await;
+                (database, res)
+            });
+        }
+
+        let Some(res) = tasks.join_next().await else {
+            break;
+        };
+
+        let (database, res) = res.context("Resources database migration task panicked")?;
+
+        finished += 1;
+        let progress = format!("{finished}/{total}");
+
+        match res {
+            Ok(database_applied) => {
+                applied += database_applied;
+
+                info!(
+                    account_id = database.account_id,
+                    surrealdb_url = database.surrealdb_url,
+                    applied = database_applied,
+                    progress,
+                    "Migrated account resources database"
+                );
+            }
+            Err(err) => {
+                warn!(
+                    account_id = database.account_id,
+                    surrealdb_url = database.surrealdb_url,
+                    ?err,
+                    progress,
+                    "Failed to migrate account resources database"
+                );
+
+                failed_accounts.push(database.account_id.unwrap_or(database.surrealdb_url));
+            }
+        }
+    }
+
+    if !failed_accounts.is_empty() {
+        bail!(
+            "Failed to migrate {} of {total} account resources databases: {}",
+            failed_accounts.len(),
+            failed_accounts.join(", ")
+        );
+    }
+
+    info!(
+        total,
+        applied, "Successfully migrated account resources databases"
+    );
+
+    Ok(())
+}
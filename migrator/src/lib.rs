@@ -1,6 +1,7 @@
-use std::include_str;
+use std::{collections::BTreeMap, include_str};
 
 use anyhow::{Context as _, bail};
+use serde::Deserialize;
 use surrealdb::{
     Surreal,
     engine::any::Any,
@@ -8,56 +9,315 @@ use surrealdb::{
 };
 use tracing::{info, instrument};
 
+mod all_accounts;
+mod migrations;
+mod reshard;
+mod shards;
+
+pub use all_accounts::{DEFAULT_ACCOUNTS_CONCURRENCY, migrate_all_account_resources_databases};
+pub use migrations::{
+    ACCOUNTS_MIGRATIONS, Migration, MigrationStatus, RESOURCES_MIGRATIONS,
+    apply_pending_migrations, migration_status, revert_migrations,
+};
+pub use reshard::reshard_accounts_databases;
+pub use shards::{
+    AccountsShard, MAX_ACCOUNT_ID, MIN_ACCOUNT_ID, accounts_shard_index, parse_accounts_shards,
+};
+
 /// # Errors
 ///
 /// Will return `Err` if the migration fails for any reason.
 #[instrument(err, skip_all)]
 pub async fn migrate_account_resources_database(db: &Surreal<Any>) -> Result<(), anyhow::Error> {
-    const RESOURCES_SURQL: &str = include_str!("resources.surql");
+    let applied = apply_pending_migrations(db, "resources", RESOURCES_MIGRATIONS).await?;
 
-    info!("Executing queries in file resources.surql...");
+    info!(applied, "Successfully completed migration");
 
-    db.query(RESOURCES_SURQL).await?.check()?;
+    Ok(())
+}
 
-    info!("Successfully completed migration");
+/// Reverts the migrations applied to an account's resources database after `to_version`. See `revert_migrations`.
+///
+/// # Errors
+///
+/// Will return `Err` if the migrations can't be reverted.
+#[instrument(err, skip(db))]
+pub async fn revert_account_resources_database(
+    db: &Surreal<Any>,
+    to_version: &str,
+) -> Result<usize, anyhow::Error> {
+    let reverted = revert_migrations(db, "resources", RESOURCES_MIGRATIONS, to_version).await?;
 
-    Ok(())
+    info!(reverted, "Successfully reverted migrations");
+
+    Ok(reverted)
 }
 
+/// Connects to the resources database of an account. Self-hosted accounts' resources databases are in the same
+/// SurrealDB instance as the accounts database, while archodex.com accounts' are found through their account record.
+///
 /// # Errors
 ///
-/// Will return `Err` if the migration fails for any reason.
-#[instrument(err, skip(creds))]
-pub async fn migrate_accounts_database(
-    surrealdb_url: &str,
+/// Will return `Err` if the account doesn't exist or its resources database can't be connected to.
+#[instrument(err, skip(shards, creds))]
+pub async fn connect_account_resources_database(
+    shards: &[AccountsShard],
+    account_id: &str,
     creds: Option<surrealdb::opt::auth::Root<'_>>,
-) -> Result<(), anyhow::Error> {
-    const ACCOUNTS_SURQL: &str = include_str!("accounts.surql");
+) -> Result<Surreal<Any>, anyhow::Error> {
+    let shard = &shards[accounts_shard_index(
+        shards,
+        account_id
+            .parse()
+            .with_context(|| format!("Invalid account ID {account_id:?}"))?,
+    )];
 
-    info!("Executing queries in file accounts.surql...");
+    #[cfg(not(feature = "archodex-com"))]
+    {
+        let db = connect(&shard.surrealdb_url, creds).await?;
 
-    let res = surrealdb::engine::any::connect((
-        surrealdb_url,
-        Config::default()
-            .capabilities(Capabilities::default().with_live_query_notifications(false))
-            .strict(),
-    ))
-    .await;
+        db.use_ns("archodex").use_db("resources").await?;
 
-    if let Err(surrealdb::Error::Api(surrealdb::error::Api::Ws(err))) = &res {
-        bail!(
-            "Failed to connect to SurrealDB at {surrealdb_url}. Please ensure that the SurrealDB instance is running and accessible. ({err})"
+        Ok(db)
+    }
+
+    #[cfg(feature = "archodex-com")]
+    {
+        let accounts_db = connect_accounts_database(&shard.surrealdb_url, creds).await?;
+
+        let Some(service_data_surrealdb_url) = accounts_db
+            .query("SELECT VALUE service_data_surrealdb_url FROM ONLY type::thing('account', $account_id)")
+            .bind(("account_id", account_id.to_string()))
+            .await?
+            .check()?
+            .take::<Option<String>>(0)?
+        else {
+            bail!("Account {account_id} not found or has no resources database");
+        };
+
+        let db = connect(&service_data_surrealdb_url, creds).await?;
+
+        db.use_ns(format!("a{account_id}"))
+            .use_db("resources")
+            .await?;
+
+        Ok(db)
+    }
+}
+
+/// Indexes of an account's resources database that graph queries rely on, as `(table, index)` pairs. Without them the
+/// queries still work, but scan whole tables.
+pub const REQUIRED_ACCOUNT_RESOURCES_INDEXES: [(&str, &str); 6] = [
+    ("resource", "resource_type"),
+    ("contains", "unique"),
+    ("event", "unique"),
+    ("event", "out"),
+    ("event", "type"),
+    ("principal_chain", "resources"),
+];
+
+#[derive(Deserialize)]
+struct TableInfo {
+    indexes: BTreeMap<String, String>,
+}
+
+/// Returns the `(table, index)` pairs of `REQUIRED_ACCOUNT_RESOURCES_INDEXES` that are missing from an account's
+/// resources database, e.g. because the database hasn't been migrated since the index was added.
+///
+/// # Errors
+///
+/// Will return `Err` if the database can't be queried.
+#[instrument(err, skip_all)]
+pub async fn missing_account_resources_indexes(
+    db: &Surreal<Any>,
+) -> Result<Vec<(&'static str, &'static str)>, anyhow::Error> {
+    let mut missing_indexes = vec![];
+    let mut tables_indexes = BTreeMap::new();
+
+    for (table, index) in REQUIRED_ACCOUNT_RESOURCES_INDEXES {
+        if !tables_indexes.contains_key(table) {
+            let table_info = db
+                .query(format!("INFO FOR TABLE {table};"))
+                .await?
+                .check()?
+                .take::<Option<TableInfo>>(0)?;
+
+            tables_indexes.insert(
+                table,
+                table_info
+                    .map(|table_info| table_info.indexes)
+                    .unwrap_or_default(),
+            );
+        }
+
+        if !tables_indexes[table].contains_key(index) {
+            missing_indexes.push((table, index));
+        }
+    }
+
+    Ok(missing_indexes)
+}
+
+#[derive(Deserialize)]
+struct DatabaseInfo {
+    tables: BTreeMap<String, String>,
+}
+
+/// Rebuilds every index of every table in a database, returning the number of indexes rebuilt. Rebuilding rewrites an
+/// index's entries, dropping stale entries left behind by past writes.
+///
+/// # Errors
+///
+/// Will return `Err` if the database can't be queried or an index fails to rebuild.
+#[instrument(err, skip_all)]
+pub async fn rebuild_indexes(db: &Surreal<Any>) -> Result<usize, anyhow::Error> {
+    let tables = db
+        .query("INFO FOR DB;")
+        .await?
+        .check()?
+        .take::<Option<DatabaseInfo>>(0)?
+        .map(|database_info| database_info.tables)
+        .unwrap_or_default();
+
+    let mut indexes = vec![];
+
+    for table in tables.keys() {
+        let table_info = db
+            .query(format!("INFO FOR TABLE {table};"))
+            .await?
+            .check()?
+            .take::<Option<TableInfo>>(0)?;
+
+        for index in table_info
+            .map(|table_info| table_info.indexes)
+            .unwrap_or_default()
+            .into_keys()
+        {
+            indexes.push((table, index));
+        }
+    }
+
+    for (rebuilt, (table, index)) in indexes.iter().enumerate() {
+        info!(
+            table,
+            index,
+            progress = format!("{}/{}", rebuilt + 1, indexes.len()),
+            "Rebuilding index..."
         );
+
+        db.query(format!("REBUILD INDEX IF EXISTS {index} ON TABLE {table};"))
+            .await?
+            .check()
+            .with_context(|| format!("Failed to rebuild index {index} on table {table}"))?;
     }
 
-    let db = res?;
+    Ok(indexes.len())
+}
 
-    if let Some(creds) = creds {
-        db.signin(creds)
-            .await
-            .context("Failed to sign in to accounts database")?;
+// Maximum number of principal chains or events rewritten per transaction by `hash_principal_chain_ids`
+const HASH_PRINCIPAL_CHAIN_IDS_BATCH_SIZE: u32 = 1000;
+
+#[derive(Deserialize)]
+struct HashPrincipalChainIdsBatch {
+    processed: u64,
+    scanned: u32,
+    last_scanned: Option<surrealdb::RecordId>,
+}
+
+/// Rewrites an account's principal chains to use hashed record IDs, returning the number of chains rewritten. The
+/// account's resources database must already be migrated.
+///
+/// Hashed records are created for every chain first, then events are rewritten to reference them, and then the chains
+/// identified by themselves are deleted. Each step runs in batches that are committed separately, so a failed migration
+/// leaves events referencing chains that exist and can be run again.
+///
+/// # Errors
+///
+/// Will return `Err` if the migration fails for any reason.
+#[instrument(err, skip_all)]
+pub async fn hash_principal_chain_ids(db: &Surreal<Any>) -> Result<u64, anyhow::Error> {
+    const CREATE_HASHED_CHAINS_SURQL: &str =
+        include_str!("hash_principal_chain_ids/1_create_hashed_chains.surql");
+    const REWRITE_EVENTS_SURQL: &str =
+        include_str!("hash_principal_chain_ids/2_rewrite_events.surql");
+    const DELETE_UNHASHED_CHAINS_SURQL: &str =
+        include_str!("hash_principal_chain_ids/3_delete_unhashed_chains.surql");
+
+    let principal_chains_rewritten = run_hash_principal_chain_ids_batches(
+        db,
+        "1_create_hashed_chains.surql",
+        CREATE_HASHED_CHAINS_SURQL,
+    )
+    .await?;
+
+    let events_rewritten =
+        run_hash_principal_chain_ids_batches(db, "2_rewrite_events.surql", REWRITE_EVENTS_SURQL)
+            .await?;
+
+    run_hash_principal_chain_ids_batches(
+        db,
+        "3_delete_unhashed_chains.surql",
+        DELETE_UNHASHED_CHAINS_SURQL,
+    )
+    .await?;
+
+    info!(
+        principal_chains_rewritten,
+        events_rewritten, "Successfully completed migration"
+    );
+
+    Ok(principal_chains_rewritten)
+}
+
+// Runs one of the `hash_principal_chain_ids` queries batch by batch until it has scanned its whole table, returning the
+// number of records it processed
+async fn run_hash_principal_chain_ids_batches(
+    db: &Surreal<Any>,
+    file_name: &str,
+    query: &'static str,
+) -> Result<u64, anyhow::Error> {
+    info!("Executing queries in file hash_principal_chain_ids/{file_name}...");
+
+    let mut processed = 0;
+    let mut after = None;
+
+    loop {
+        let mut res = db
+            .query(query)
+            .bind(("after", after))
+            .bind(("limit", HASH_PRINCIPAL_CHAIN_IDS_BATCH_SIZE))
+            .await?
+            .check()
+            .with_context(|| format!("Failed to execute queries in file {file_name}"))?;
+
+        let Some(batch) =
+            res.take::<Option<HashPrincipalChainIdsBatch>>(res.num_statements() - 1)?
+        else {
+            break;
+        };
+
+        processed += batch.processed;
+
+        if batch.scanned < HASH_PRINCIPAL_CHAIN_IDS_BATCH_SIZE {
+            break;
+        }
+
+        after = batch.last_scanned;
     }
 
+    Ok(processed)
+}
+
+/// # Errors
+///
+/// Will return `Err` if the migration fails for any reason.
+#[instrument(err, skip(creds))]
+pub async fn migrate_accounts_database(
+    surrealdb_url: &str,
+    creds: Option<surrealdb::opt::auth::Root<'_>>,
+) -> Result<(), anyhow::Error> {
+    let db = connect(surrealdb_url, creds).await?;
+
     #[cfg(not(feature = "archodex-com"))]
     {
         db.query("DEFINE NAMESPACE IF NOT EXISTS archodex;")
@@ -78,9 +338,75 @@ pub async fn migrate_accounts_database(
         db.use_ns("archodex").use_db("accounts").await?;
     }
 
-    db.query(ACCOUNTS_SURQL).await?.check()?;
+    let applied = apply_pending_migrations(&db, "accounts", ACCOUNTS_MIGRATIONS).await?;
 
-    info!("Successfully completed migration");
+    info!(applied, "Successfully completed migration");
 
     Ok(())
 }
+
+/// Connects to the accounts database of a shard, which must already exist.
+///
+/// # Errors
+///
+/// Will return `Err` if the shard can't be connected to.
+#[instrument(err, skip(creds))]
+pub async fn connect_accounts_database(
+    surrealdb_url: &str,
+    creds: Option<surrealdb::opt::auth::Root<'_>>,
+) -> Result<Surreal<Any>, anyhow::Error> {
+    let db = connect(surrealdb_url, creds).await?;
+
+    db.use_ns("archodex").use_db("accounts").await?;
+
+    Ok(db)
+}
+
+/// Reverts the migrations applied to an accounts database shard after `to_version`. See `revert_migrations`.
+///
+/// # Errors
+///
+/// Will return `Err` if the shard can't be connected to or the migrations can't be reverted.
+#[instrument(err, skip(creds))]
+pub async fn revert_accounts_database(
+    surrealdb_url: &str,
+    creds: Option<surrealdb::opt::auth::Root<'_>>,
+    to_version: &str,
+) -> Result<usize, anyhow::Error> {
+    let db = connect_accounts_database(surrealdb_url, creds).await?;
+
+    let reverted = revert_migrations(&db, "accounts", ACCOUNTS_MIGRATIONS, to_version).await?;
+
+    info!(reverted, "Successfully reverted migrations");
+
+    Ok(reverted)
+}
+
+async fn connect(
+    surrealdb_url: &str,
+    creds: Option<surrealdb::opt::auth::Root<'_>>,
+) -> Result<Surreal<Any>, anyhow::Error> {
+    let res = surrealdb::engine::any::connect((
+        surrealdb_url,
+        Config::default()
+            .capabilities(Capabilities::default().with_live_query_notifications(false))
+            .strict(),
+    ))
+    .await;
+
+    if let Err(surrealdb::Error::Api(surrealdb::error::Api::Ws(err))) = &res {
+        bail!(
+            "Failed to connect to SurrealDB at {surrealdb_url}. Please ensure that the SurrealDB instance is running and accessible. ({err})"
+        );
+    }
+
+    let db = res?;
+
+    if let Some(creds) = creds {
+        db.signin(creds)
+            .await
+            .context("Failed to sign in to accounts database")?;
+    }
+
+    Ok(db)
+}
@@ -1,4 +1,342 @@
-use std::thread;
+use std::{collections::HashMap, num::NonZeroUsize, thread};
+
+use anyhow::bail;
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use surrealdb::{Surreal, engine::any::Any};
+
+use migrator::{
+    ACCOUNTS_MIGRATIONS, AccountsShard, DEFAULT_ACCOUNTS_CONCURRENCY, MIN_ACCOUNT_ID, Migration,
+    MigrationStatus, RESOURCES_MIGRATIONS,
+};
+
+struct EnvConfig {
+    accounts_shards: Vec<AccountsShard>,
+    // Shards being removed from the layout, whose accounts are moved by resharding
+    drained_surrealdb_urls: Vec<String>,
+    surrealdb_creds: Option<surrealdb::opt::auth::Root<'static>>,
+}
+
+/// Migrates the accounts database shards and accounts' resources databases. Connections are configured through the
+/// `SURREALDB_URL` (self-hosted) or `ACCOUNTS_SURREALDB_URL(S)` (archodex-com), `SURREALDB_USERNAME`, and
+/// `SURREALDB_PASSWORD` environment variables, or the `[surrealdb]` table of the backend's `ARCHODEX_CONFIG` file.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Defaults to `up`
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Shows the applied and pending migrations of every accounts database shard, or of an account's resources database
+    Status {
+        /// Account whose resources database is shown instead
+        #[arg(long = "account", value_name = "ACCOUNT_ID")]
+        account_id: Option<String>,
+    },
+    /// Prints the queries `up` would run without running them
+    Plan {
+        /// Account whose resources database is planned instead
+        #[arg(long = "account", value_name = "ACCOUNT_ID")]
+        account_id: Option<String>,
+    },
+    /// Applies pending migrations to every accounts database shard, or to accounts' resources databases
+    Up {
+        /// Account whose resources database is migrated instead
+        #[arg(
+            long = "account",
+            value_name = "ACCOUNT_ID",
+            conflicts_with = "all_accounts"
+        )]
+        account_id: Option<String>,
+        /// Migrate the resources database of every account instead, e.g. to roll out a resources schema change
+        #[arg(long)]
+        all_accounts: bool,
+        /// Number of resources databases migrated at a time with `--all-accounts`
+        #[arg(long, default_value_t = DEFAULT_ACCOUNTS_CONCURRENCY, requires = "all_accounts")]
+        concurrency: NonZeroUsize,
+    },
+    /// Migrates every accounts database shard, including the drained shards in `RESHARD_DRAINED_SURREALDB_URLS`, then
+    /// moves accounts to the shards that hold them in the layout
+    Reshard,
+    /// Reverts the migrations applied after a version in every accounts database shard, or in an account's resources
+    /// database
+    Down {
+        /// Version of the last migration to keep, e.g. `0001_initial`
+        #[arg(long = "to", value_name = "VERSION")]
+        to_version: String,
+        /// Account whose resources database is reverted instead
+        #[arg(long = "account", value_name = "ACCOUNT_ID")]
+        account_id: Option<String>,
+    },
+}
+
+// Databases a command operates on, with a description to print and the migrations the migrator knows of for them
+async fn connect_databases(
+    accounts_shards: &[AccountsShard],
+    account_id: Option<&str>,
+    surrealdb_creds: Option<surrealdb::opt::auth::Root<'_>>,
+) -> anyhow::Result<Vec<(String, Surreal<Any>, &'static [Migration])>> {
+    if let Some(account_id) = account_id {
+        let db = migrator::connect_account_resources_database(
+            accounts_shards,
+            account_id,
+            surrealdb_creds,
+        )
+        .await?;
+
+        return Ok(vec![(
+            format!("Resources database of account {account_id}"),
+            db,
+            RESOURCES_MIGRATIONS,
+        )]);
+    }
+
+    let mut databases = vec![];
+
+    for shard in accounts_shards {
+        let db = migrator::connect_accounts_database(&shard.surrealdb_url, surrealdb_creds).await?;
+
+        databases.push((
+            format!("Accounts database at {}", shard.surrealdb_url),
+            db,
+            ACCOUNTS_MIGRATIONS,
+        ));
+    }
+
+    Ok(databases)
+}
+
+fn print_status(description: &str, migrations: &[Migration], status: &MigrationStatus) {
+    // Unknown migrations were applied by a newer version, so they are the newest applied
+    let current = status
+        .unknown
+        .last()
+        .map(String::as_str)
+        .or(status.applied.last().copied())
+        .unwrap_or("none");
+    let latest = migrations
+        .last()
+        .map_or("none", |migration| migration.version);
+
+    println!("{description}: current version {current}, latest version {latest}");
+
+    for version in &status.applied {
+        if status.changed.contains(version) {
+            println!(
+                "  changed  {version} (changed after it was applied, add a new migration instead)"
+            );
+        } else {
+            println!("  applied  {version}");
+        }
+    }
+
+    for migration in &status.pending {
+        println!("  pending  {}", migration.version);
+    }
+
+    for version in &status.unknown {
+        println!("  unknown  {version} (applied by a newer version)");
+    }
+}
+
+// Prints the queries of each pending migration. Each migration is applied in its own transaction, which also records it
+// in the database's `migration` table.
+fn print_plan(description: &str, status: &MigrationStatus) -> anyhow::Result<()> {
+    if let Some(version) = status.changed.first() {
+        bail!(
+            "{description} can't be migrated, migration {version} was changed after it was applied"
+        );
+    }
+
+    if status.pending.is_empty() {
+        println!("-- {description}: no pending migrations");
+        return Ok(());
+    }
+
+    for migration in &status.pending {
+        println!("-- {description}: migration {}", migration.version);
+        println!("{}", migration.surql().trim());
+        println!();
+    }
+
+    Ok(())
+}
+
+// The `[surrealdb]` table of the backend's `ARCHODEX_CONFIG` file. The backend's other settings don't apply to the
+// migrator and are ignored.
+#[derive(Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    surrealdb: SurrealdbConfigFile,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SurrealdbConfigFile {
+    url: Option<String>,
+    accounts_url: Option<String>,
+    accounts_urls: Option<Vec<String>>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl ConfigFile {
+    // Reads the file at `ARCHODEX_CONFIG` like the backend does, returning the settings by the environment variable each
+    // stands in for
+    fn load_vars(problems: &mut Vec<(&'static str, String)>) -> HashMap<&'static str, String> {
+        let Some(path) = std::env::var("ARCHODEX_CONFIG")
+            .ok()
+            .filter(|path| !path.is_empty())
+        else {
+            return HashMap::new();
+        };
+
+        let config_file = std::fs::read_to_string(&path)
+            .map_err(|err| format!("Failed to read {path:?}: {err}"))
+            .and_then(|contents| {
+                toml::from_str::<Self>(&contents)
+                    .map_err(|err| format!("Failed to parse {path:?}: {}", err.message()))
+            });
+
+        let surrealdb = match config_file {
+            Ok(config_file) => config_file.surrealdb,
+            Err(problem) => {
+                problems.push(("ARCHODEX_CONFIG", problem));
+                return HashMap::new();
+            }
+        };
+
+        [
+            ("SURREALDB_URL", surrealdb.url),
+            ("ACCOUNTS_SURREALDB_URL", surrealdb.accounts_url),
+            (
+                "ACCOUNTS_SURREALDB_URLS",
+                surrealdb.accounts_urls.map(|urls| urls.join(",")),
+            ),
+            ("SURREALDB_USERNAME", surrealdb.username),
+            ("SURREALDB_PASSWORD", surrealdb.password),
+        ]
+        .into_iter()
+        .filter_map(|(var, value)| Some((var, value?)))
+        .collect()
+    }
+}
+
+// Reads the migrator's environment variables, falling back to the settings standing in for them in the
+// `ARCHODEX_CONFIG` file, and collects every problem so they can be reported together
+fn env_config() -> Result<EnvConfig, Vec<(&'static str, String)>> {
+    let mut problems = vec![];
+
+    let config_file_vars = ConfigFile::load_vars(&mut problems);
+
+    let var = |var: &'static str| {
+        std::env::var(var)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .or_else(|| config_file_vars.get(var).cloned())
+    };
+
+    #[cfg(not(feature = "archodex-com"))]
+    let accounts_shards = {
+        for forbidden_var in ["ACCOUNTS_SURREALDB_URL", "ACCOUNTS_SURREALDB_URLS"] {
+            if var(forbidden_var).is_some() {
+                problems.push((
+                    forbidden_var,
+                    "Must not be set in self-hosted builds".to_string(),
+                ));
+            }
+        }
+
+        match var("SURREALDB_URL") {
+            Some(surrealdb_url) => vec![AccountsShard {
+                first_account_id: MIN_ACCOUNT_ID,
+                surrealdb_url,
+            }],
+            None => {
+                problems.push(("SURREALDB_URL", "Must be set".to_string()));
+                vec![]
+            }
+        }
+    };
+
+    #[cfg(feature = "archodex-com")]
+    let accounts_shards = {
+        if var("SURREALDB_URL").is_some() {
+            problems.push((
+                "SURREALDB_URL",
+                "Must not be set in archodex-com builds".to_string(),
+            ));
+        }
+
+        match (
+            var("ACCOUNTS_SURREALDB_URL"),
+            var("ACCOUNTS_SURREALDB_URLS"),
+        ) {
+            (Some(surrealdb_url), None) => vec![AccountsShard {
+                first_account_id: MIN_ACCOUNT_ID,
+                surrealdb_url,
+            }],
+            (None, Some(shards)) => {
+                migrator::parse_accounts_shards(&shards).unwrap_or_else(|problem| {
+                    problems.push(("ACCOUNTS_SURREALDB_URLS", problem));
+                    vec![]
+                })
+            }
+            (Some(_), Some(_)) => {
+                problems.push((
+                    "ACCOUNTS_SURREALDB_URL, ACCOUNTS_SURREALDB_URLS",
+                    "Must not both be set".to_string(),
+                ));
+                vec![]
+            }
+            (None, None) => {
+                problems.push((
+                    "ACCOUNTS_SURREALDB_URL, ACCOUNTS_SURREALDB_URLS",
+                    "One must be set".to_string(),
+                ));
+                vec![]
+            }
+        }
+    };
+
+    let drained_surrealdb_urls = var("RESHARD_DRAINED_SURREALDB_URLS")
+        .map(|urls| {
+            urls.split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let surrealdb_creds = match (var("SURREALDB_USERNAME"), var("SURREALDB_PASSWORD")) {
+        (Some(surrealdb_username), Some(surrealdb_password)) => Some(surrealdb::opt::auth::Root {
+            username: Box::leak(Box::new(surrealdb_username)),
+            password: Box::leak(Box::new(surrealdb_password)),
+        }),
+        (None, None) => None,
+        _ => {
+            problems.push((
+                "SURREALDB_USERNAME, SURREALDB_PASSWORD",
+                "Must be set or unset together".to_string(),
+            ));
+            None
+        }
+    };
+
+    if problems.is_empty() {
+        Ok(EnvConfig {
+            accounts_shards,
+            drained_surrealdb_urls,
+            surrealdb_creds,
+        })
+    } else {
+        Err(problems)
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -15,6 +353,27 @@ async fn main() -> Result<(), anyhow::Error> {
 
     fmt.with_ansi(false).init();
 
+    let command = Cli::parse().command.unwrap_or(Command::Up {
+        account_id: None,
+        all_accounts: false,
+        concurrency: DEFAULT_ACCOUNTS_CONCURRENCY,
+    });
+
+    let EnvConfig {
+        accounts_shards,
+        drained_surrealdb_urls,
+        surrealdb_creds,
+    } = match env_config() {
+        Ok(config) => config,
+        Err(problems) => {
+            eprintln!("Invalid environment configuration:\n");
+            for (vars, problem) in problems {
+                eprintln!("  {vars}: {problem}");
+            }
+            std::process::exit(1);
+        }
+    };
+
     // Build a single-threaded (or multi-threaded using Builder::new_multi_thread) runtime to spawn our work onto with a larger stack size of SurrealDB.
     let tokio_runtime = tokio::runtime::Builder::new_multi_thread()
         .thread_name("runtime")
@@ -25,42 +384,109 @@ async fn main() -> Result<(), anyhow::Error> {
 
     // Run the lambda runtime worker thread to completion. The response is sent to the other "runtime" to be processed as needed.
     thread::spawn(move || {
-        #[cfg(not(feature = "archodex-com"))]
-        let (_, accounts_surrealdb_url) = (
-            std::env::var("ACCOUNTS_SURREALDB_URL").expect_err(
-                "ACCOUNTS_SURREALDB_URL env var should not be set in non-archodex-com builds",
-            ),
-            std::env::var("SURREALDB_URL").expect("Missing SURREALDB_URL env var"),
-        );
+        tokio_runtime.block_on(async {
+            match command {
+                Command::Status { account_id } => {
+                    for (description, db, migrations) in
+                        connect_databases(&accounts_shards, account_id.as_deref(), surrealdb_creds)
+                            .await?
+                    {
+                        let status = migrator::migration_status(&db, migrations).await?;
 
-        #[cfg(feature = "archodex-com")]
-        let (accounts_surrealdb_url, _) = (
-            std::env::var("ACCOUNTS_SURREALDB_URL")
-                .expect("Missing ACCOUNTS_SURREALDB_URL env var"),
-            std::env::var("SURREALDB_URL")
-                .expect_err("SURREALDB_URL env var should not be set in archodex-com builds"),
-        );
+                        print_status(&description, migrations, &status);
+                    }
+                }
+                Command::Plan { account_id } => {
+                    for (description, db, migrations) in
+                        connect_databases(&accounts_shards, account_id.as_deref(), surrealdb_creds)
+                            .await?
+                    {
+                        let status = migrator::migration_status(&db, migrations).await?;
 
-        let surrealdb_username = std::env::var("SURREALDB_USERNAME").ok();
-        let surrealdb_password = std::env::var("SURREALDB_PASSWORD").ok();
+                        print_plan(&description, &status)?;
+                    }
+                }
+                Command::Up {
+                    account_id: Some(account_id),
+                    ..
+                } => {
+                    let db = migrator::connect_account_resources_database(
+                        &accounts_shards,
+                        &account_id,
+                        surrealdb_creds,
+                    )
+                    .await?;
 
-        let surrealdb_creds = match (surrealdb_username, surrealdb_password) {
-            (Some(surrealdb_username), Some(surrealdb_password)) => {
-                Some(surrealdb::opt::auth::Root {
-                    username: Box::leak(Box::new(surrealdb_username)),
-                    password: Box::leak(Box::new(surrealdb_password)),
-                })
+                    migrator::migrate_account_resources_database(&db).await?;
+                }
+                Command::Up {
+                    all_accounts: true,
+                    concurrency,
+                    ..
+                } => {
+                    migrator::migrate_all_account_resources_databases(
+                        &accounts_shards,
+                        surrealdb_creds,
+                        concurrency,
+                    )
+                    .await?;
+                }
+                Command::Up {
+                    account_id: None,
+                    all_accounts: false,
+                    ..
+                } => {
+                    for shard in &accounts_shards {
+                        migrator::migrate_accounts_database(&shard.surrealdb_url, surrealdb_creds)
+                            .await?;
+                    }
+                }
+                Command::Reshard => {
+                    for shard in accounts_shards
+                        .iter()
+                        .map(|shard| &shard.surrealdb_url)
+                        .chain(&drained_surrealdb_urls)
+                    {
+                        migrator::migrate_accounts_database(shard, surrealdb_creds).await?;
+                    }
+
+                    migrator::reshard_accounts_databases(
+                        &accounts_shards,
+                        &drained_surrealdb_urls,
+                        surrealdb_creds,
+                    )
+                    .await?;
+                }
+                Command::Down {
+                    to_version,
+                    account_id: Some(account_id),
+                } => {
+                    let db = migrator::connect_account_resources_database(
+                        &accounts_shards,
+                        &account_id,
+                        surrealdb_creds,
+                    )
+                    .await?;
+
+                    migrator::revert_account_resources_database(&db, &to_version).await?;
+                }
+                Command::Down {
+                    to_version,
+                    account_id: None,
+                } => {
+                    for shard in &accounts_shards {
+                        migrator::revert_accounts_database(
+                            &shard.surrealdb_url,
+                            surrealdb_creds,
+                            &to_version,
+                        )
+                        .await?;
+                    }
+                }
             }
-            (None, None) => None,
-            _ => panic!(
-                "Both SURREALDB_USERNAME and SURREALDB_PASSWORD must be set or unset together"
-            ),
-        };
 
-        tokio_runtime.block_on(migrator::migrate_accounts_database(
-            &accounts_surrealdb_url,
-            surrealdb_creds,
-        ))
+            anyhow::Ok(())
+        })
     })
     .join()
     .expect("runtime thread should join successfully")
@@ -0,0 +1,336 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context as _, bail};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use surrealdb::{Surreal, engine::any::Any};
+use tracing::{info, instrument, warn};
+
+/// A versioned schema migration. Migrations of a database are applied in order of their versions, each at most once.
+///
+/// Applied migrations must never be changed, as their checksums are verified before pending migrations are applied.
+/// Schema changes are made by adding a migration with the next version instead.
+///
+/// A migration may have a down script, `<version>.down.surql`, which undoes it so the migration can be reverted if it
+/// breaks production.
+pub struct Migration {
+    /// Version of the migration, e.g. `0001_initial`, which orders it among the database's migrations
+    pub version: &'static str,
+    surql: &'static str,
+    down_surql: Option<&'static str>,
+}
+
+impl Migration {
+    /// Hex encoded SHA-256 hash of the migration's queries.
+    #[must_use]
+    pub fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.surql))
+    }
+
+    /// Whether the migration has a down script, without which it can't be reverted.
+    #[must_use]
+    pub fn is_reversible(&self) -> bool {
+        self.down_surql.is_some()
+    }
+
+    /// Queries applying the migration runs, in the transaction that also records it as applied.
+    #[must_use]
+    pub fn surql(&self) -> &'static str {
+        self.surql
+    }
+}
+
+macro_rules! migration {
+    ($database:literal, $version:literal) => {
+        Migration {
+            version: $version,
+            surql: include_str!(concat!("migrations/", $database, "/", $version, ".surql")),
+            down_surql: None,
+        }
+    };
+    ($database:literal, $version:literal, reversible) => {
+        Migration {
+            version: $version,
+            surql: include_str!(concat!("migrations/", $database, "/", $version, ".surql")),
+            down_surql: Some(include_str!(concat!(
+                "migrations/",
+                $database,
+                "/",
+                $version,
+                ".down.surql"
+            ))),
+        }
+    };
+}
+
+/// Migrations of the accounts database, in order.
+pub const ACCOUNTS_MIGRATIONS: &[Migration] = &[
+    migration!("accounts", "0001_initial"),
+    migration!("accounts", "0002_report_transformations", reversible),
+    migration!("accounts", "0003_bulk_operations", reversible),
+];
+
+/// Migrations of each account's resources database, in order.
+pub const RESOURCES_MIGRATIONS: &[Migration] = &[
+    migration!("resources", "0001_initial"),
+    migration!("resources", "0002_report_api_key_suspension", reversible),
+    migration!(
+        "resources",
+        "0003_webhook_verification_and_delivery_history",
+        reversible
+    ),
+];
+
+// Records the migrations applied to a database. The record ID is the migration's version.
+const MIGRATION_TABLE_SURQL: &str = "
+    DEFINE TABLE IF NOT EXISTS migration SCHEMAFULL TYPE NORMAL;
+    DEFINE FIELD IF NOT EXISTS id ON TABLE migration TYPE string READONLY;
+    DEFINE FIELD IF NOT EXISTS checksum ON TABLE migration TYPE string READONLY;
+    DEFINE FIELD IF NOT EXISTS applied_at ON TABLE migration TYPE datetime READONLY;";
+
+#[derive(Deserialize)]
+struct AppliedMigration {
+    version: String,
+    checksum: String,
+}
+
+fn verify_checksum(
+    database: &str,
+    migration: &Migration,
+    applied_checksum: &str,
+) -> anyhow::Result<()> {
+    if migration.checksum() != applied_checksum {
+        bail!(
+            "Migration {} of the {database} database was changed after it was applied, add a new migration instead",
+            migration.version
+        );
+    }
+
+    Ok(())
+}
+
+// Versions and checksums of the migrations applied to a database
+async fn applied_migrations(db: &Surreal<Any>) -> anyhow::Result<BTreeMap<String, String>> {
+    db.query(MIGRATION_TABLE_SURQL).await?.check()?;
+
+    Ok(db
+        .query("SELECT record::id(id) AS version, checksum FROM migration")
+        .await?
+        .check()?
+        .take::<Vec<AppliedMigration>>(0)?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum))
+        .collect())
+}
+
+/// Migrations of a database compared to the migrations this version knows of.
+pub struct MigrationStatus {
+    /// Versions of the applied migrations this version knows of, in order
+    pub applied: Vec<&'static str>,
+    /// Migrations that would be applied, in order
+    pub pending: Vec<&'static Migration>,
+    /// Versions of applied migrations this version doesn't know of, e.g. because a newer version applied them
+    pub unknown: Vec<String>,
+    /// Versions of applied migrations that were changed after they were applied, which block migrating the database
+    pub changed: Vec<&'static str>,
+}
+
+/// Compares the migrations applied to a database with `migrations` without applying any. The database's `migration`
+/// table is defined if it doesn't exist yet.
+///
+/// # Errors
+///
+/// Will return `Err` if the database can't be queried.
+#[instrument(err, skip_all)]
+pub async fn migration_status(
+    db: &Surreal<Any>,
+    migrations: &'static [Migration],
+) -> anyhow::Result<MigrationStatus> {
+    let applied = applied_migrations(db).await?;
+
+    let mut status = MigrationStatus {
+        applied: vec![],
+        pending: vec![],
+        unknown: applied
+            .keys()
+            .filter(|version| {
+                !migrations
+                    .iter()
+                    .any(|migration| migration.version == **version)
+            })
+            .cloned()
+            .collect(),
+        changed: vec![],
+    };
+
+    for migration in migrations {
+        match applied.get(migration.version) {
+            Some(applied_checksum) => {
+                status.applied.push(migration.version);
+
+                if migration.checksum() != *applied_checksum {
+                    status.changed.push(migration.version);
+                }
+            }
+            None => status.pending.push(migration),
+        }
+    }
+
+    Ok(status)
+}
+
+/// Applies the migrations a database hasn't had applied yet, in order, returning the number applied. Each migration is
+/// applied and recorded in a single transaction.
+///
+/// Databases migrated before migrations were versioned have every migration applied, which is safe as the initial
+/// migrations only define what doesn't exist yet.
+///
+/// # Errors
+///
+/// Will return `Err` if an applied migration was changed since it was applied, or if a migration fails.
+#[instrument(err, skip(db, migrations))]
+pub async fn apply_pending_migrations(
+    db: &Surreal<Any>,
+    database: &str,
+    migrations: &[Migration],
+) -> anyhow::Result<usize> {
+    let applied = applied_migrations(db).await?;
+
+    for version in applied.keys() {
+        if !migrations
+            .iter()
+            .any(|migration| migration.version == *version)
+        {
+            warn!(
+                version,
+                "Database has a migration applied that this version doesn't know of, it may have been migrated by a newer version"
+            );
+        }
+    }
+
+    let mut applied_count = 0;
+
+    for migration in migrations {
+        let checksum = migration.checksum();
+
+        if let Some(applied_checksum) = applied.get(migration.version) {
+            verify_checksum(database, migration, applied_checksum)?;
+
+            continue;
+        }
+
+        info!(version = migration.version, "Applying migration...");
+
+        let res = db
+            .query(format!(
+                "BEGIN;\n{}\nCREATE type::thing('migration', $version) SET checksum = $checksum, applied_at = time::now() RETURN NONE;\nCOMMIT;",
+                migration.surql
+            ))
+            .bind(("version", migration.version))
+            .bind(("checksum", checksum.clone()))
+            .await
+            .and_then(surrealdb::Response::check);
+
+        if let Err(err) = res {
+            // Another process migrating the database at the same time may have applied the migration first
+            if applied_migrations(db).await?.get(migration.version) == Some(&checksum) {
+                info!(
+                    version = migration.version,
+                    "Migration was applied concurrently"
+                );
+                continue;
+            }
+
+            return Err(anyhow::Error::from(err).context(format!(
+                "Failed to apply migration {} of the {database} database",
+                migration.version
+            )));
+        }
+
+        applied_count += 1;
+    }
+
+    Ok(applied_count)
+}
+
+/// Reverts the migrations applied to a database after `to_version`, newest first, returning the number reverted. Each
+/// migration is reverted by running its down script and deleting its record in a single transaction.
+///
+/// Nothing is reverted unless every migration to revert has a down script and is unchanged since it was applied. The
+/// backend applies pending migrations when it starts and when accounts are created, so backends must be rolled back to
+/// a version without the reverted migrations before reverting them.
+///
+/// # Errors
+///
+/// Will return `Err` if `to_version` isn't a migration of the database, if the database has migrations applied that
+/// are unknown to this version or that can't be reverted, or if a down script fails. Migrations reverted before the
+/// failure stay reverted.
+#[instrument(err, skip(db, migrations))]
+pub async fn revert_migrations(
+    db: &Surreal<Any>,
+    database: &str,
+    migrations: &[Migration],
+    to_version: &str,
+) -> anyhow::Result<usize> {
+    let Some(to_index) = migrations
+        .iter()
+        .position(|migration| migration.version == to_version)
+    else {
+        bail!("{to_version} is not a migration of the {database} database");
+    };
+
+    let applied = applied_migrations(db).await?;
+
+    if let Some(version) = applied.keys().find(|version| {
+        !migrations
+            .iter()
+            .any(|migration| migration.version == **version)
+    }) {
+        bail!(
+            "Migration {version} of the {database} database was applied by a newer version, revert it with that version's migrator"
+        );
+    }
+
+    let to_revert = migrations[to_index + 1..]
+        .iter()
+        .rev()
+        .filter_map(|migration| {
+            applied
+                .get(migration.version)
+                .map(|applied_checksum| (migration, applied_checksum))
+        })
+        .collect::<Vec<_>>();
+
+    for (migration, applied_checksum) in &to_revert {
+        verify_checksum(database, migration, applied_checksum)?;
+
+        if !migration.is_reversible() {
+            bail!(
+                "Migration {} of the {database} database has no down script and can't be reverted",
+                migration.version
+            );
+        }
+    }
+
+    for (migration, _) in &to_revert {
+        info!(version = migration.version, "Reverting migration...");
+
+        db.query(format!(
+            "BEGIN;\n{}\nDELETE type::thing('migration', $version);\nCOMMIT;",
+            migration
+                .down_surql
+                .expect("Migrations to revert should have down scripts")
+        ))
+        .bind(("version", migration.version))
+        .await
+        .and_then(surrealdb::Response::check)
+        .with_context(|| {
+            format!(
+                "Failed to revert migration {} of the {database} database",
+                migration.version
+            )
+        })?;
+    }
+
+    Ok(to_revert.len())
+}
@@ -0,0 +1,173 @@
+use anyhow::{Context as _, bail};
+use surrealdb::{Surreal, engine::any::Any};
+use tracing::{info, instrument};
+
+use crate::{AccountsShard, accounts_shard_index, connect_accounts_database};
+
+/// Moves the records of accounts to the shards that hold them in `shards`.
+///
+/// Adding a shard or changing the first account ID of a shard leaves accounts in shards that no longer hold them, and
+/// this moves each of them to its new shard. Every account of the shards listed in `drained_surrealdb_urls`, i.e.
+/// shards being removed from the layout, is moved too. All shards must already be migrated.
+///
+/// Each account is copied to its new shard in one transaction and then deleted from its old shard in another, so a
+/// resharding that failed part way can be run again to finish it. Backends must not be serving the accounts while they
+/// are moved, and must be restarted with the new layout afterwards, as they only look for accounts in the shards of the
+/// layout they were started with.
+///
+/// The first shard also holds records that aren't scoped to an account, which are not moved, so its URL must not
+/// change.
+///
+/// # Errors
+///
+/// Will return `Err` if a shard can't be reached or an account fails to be moved. Accounts moved before the failure
+/// stay moved.
+#[instrument(err, skip(creds))]
+pub async fn reshard_accounts_databases(
+    shards: &[AccountsShard],
+    drained_surrealdb_urls: &[String],
+    creds: Option<surrealdb::opt::auth::Root<'_>>,
+) -> Result<(), anyhow::Error> {
+    if let Some(drained_surrealdb_url) = drained_surrealdb_urls
+        .iter()
+        .find(|url| shards.iter().any(|shard| &shard.surrealdb_url == *url))
+    {
+        bail!("Shard {drained_surrealdb_url} can't be drained as it is part of the new layout");
+    }
+
+    let mut dbs = Vec::with_capacity(shards.len());
+    for shard in shards {
+        dbs.push(connect_accounts_database(&shard.surrealdb_url, creds).await?);
+    }
+
+    let mut drained_dbs = Vec::with_capacity(drained_surrealdb_urls.len());
+    for drained_surrealdb_url in drained_surrealdb_urls {
+        drained_dbs.push(connect_accounts_database(drained_surrealdb_url, creds).await?);
+    }
+
+    let sources = dbs
+        .iter()
+        .enumerate()
+        .map(|(index, db)| (&shards[index].surrealdb_url, Some(index), db))
+        .chain(
+            drained_surrealdb_urls
+                .iter()
+                .zip(&drained_dbs)
+                .map(|(url, db)| (url, None, db)),
+        );
+
+    let mut num_moved_accounts = 0;
+
+    for (source_url, source_index, source) in sources {
+        let account_ids = source
+            .query("SELECT VALUE record::id(id) FROM account")
+            .await?
+            .check()?
+            .take::<Vec<String>>(0)?;
+
+        for account_id in account_ids {
+            let target_index = accounts_shard_index(
+                shards,
+                account_id
+                    .parse()
+                    .with_context(|| format!("Invalid account ID {account_id:?}"))?,
+            );
+
+            if Some(target_index) == source_index {
+                continue;
+            }
+
+            let target_url = &shards[target_index].surrealdb_url;
+
+            move_account(source, &dbs[target_index], &account_id)
+                .await
+                .with_context(|| {
+                    format!("Failed to move account {account_id} from {source_url} to {target_url}")
+                })?;
+
+            info!(account_id, source_url, target_url, "Moved account");
+
+            num_moved_accounts += 1;
+        }
+    }
+
+    info!(num_moved_accounts, "Successfully completed resharding");
+
+    Ok(())
+}
+
+// Copies the records of an account to `target`, then deletes them from `source`. User records are copied so the
+// `has_access` edges can be related to them, but are left in `source` as they may have access to other accounts.
+async fn move_account(
+    source: &Surreal<Any>,
+    target: &Surreal<Any>,
+    account_id: &str,
+) -> Result<(), anyhow::Error> {
+    let account = surrealdb::sql::Thing::from((
+        "account",
+        surrealdb::sql::Id::String(account_id.to_string()),
+    ));
+
+    let mut res = source
+        .query("SELECT * FROM ONLY $account")
+        .query(
+            "SELECT * FROM user WHERE id IN (SELECT VALUE in FROM has_access WHERE out = $account)",
+        )
+        .query("SELECT * FROM has_access WHERE out = $account")
+        .query("SELECT * FROM account_transfer WHERE account = $account")
+        .query("SELECT * FROM personal_access_token WHERE account = $account")
+        .query("SELECT * FROM audit_log WHERE account = $account")
+        .query("SELECT * FROM storage_usage WHERE account = $account")
+        .bind(("account", account.clone()))
+        .await?
+        .check()?;
+
+    let account_record = res.take::<surrealdb::Value>(0)?;
+    let users = res.take::<surrealdb::Value>(1)?;
+    let access_edges = res.take::<surrealdb::Value>(2)?;
+    let account_transfers = res.take::<surrealdb::Value>(3)?;
+    let personal_access_tokens = res.take::<surrealdb::Value>(4)?;
+    let audit_log_entries = res.take::<surrealdb::Value>(5)?;
+    let storage_usage = res.take::<surrealdb::Value>(6)?;
+
+    // Records that already exist in the target were copied by an earlier resharding that failed before deleting them
+    // from the source
+    target
+        .query(
+            "BEGIN;
+            INSERT IGNORE INTO user $users RETURN NONE;
+            INSERT IGNORE INTO account $account_record RETURN NONE;
+            INSERT RELATION IGNORE INTO has_access $access_edges RETURN NONE;
+            INSERT IGNORE INTO account_transfer $account_transfers RETURN NONE;
+            INSERT IGNORE INTO personal_access_token $personal_access_tokens RETURN NONE;
+            INSERT IGNORE INTO audit_log $audit_log_entries RETURN NONE;
+            INSERT IGNORE INTO storage_usage $storage_usage RETURN NONE;
+            COMMIT;",
+        )
+        .bind(("users", users))
+        .bind(("account_record", account_record))
+        .bind(("access_edges", access_edges))
+        .bind(("account_transfers", account_transfers))
+        .bind(("personal_access_tokens", personal_access_tokens))
+        .bind(("audit_log_entries", audit_log_entries))
+        .bind(("storage_usage", storage_usage))
+        .await?
+        .check()?;
+
+    source
+        .query(
+            "BEGIN;
+            DELETE has_access WHERE out = $account;
+            DELETE account_transfer WHERE account = $account;
+            DELETE personal_access_token WHERE account = $account;
+            DELETE audit_log WHERE account = $account;
+            DELETE storage_usage WHERE account = $account;
+            DELETE $account;
+            COMMIT;",
+        )
+        .bind(("account", account))
+        .await?
+        .check()?;
+
+    Ok(())
+}
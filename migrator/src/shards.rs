@@ -0,0 +1,104 @@
+/// Lowest account ID. Account IDs are 10-digit numbers without leading zeros.
+pub const MIN_ACCOUNT_ID: u64 = 1_000_000_000;
+
+/// Highest account ID.
+pub const MAX_ACCOUNT_ID: u64 = 9_999_999_999;
+
+/// A shard of the accounts database.
+///
+/// A shard holds the records of accounts with IDs from its `first_account_id` up to the next shard's
+/// `first_account_id`: the `account` records, their `has_access` edges and the `user` records the edges start from, and
+/// their `account_transfer` records. Records that aren't scoped to an account, i.e. account ID reservations and Kafka
+/// consumer offsets, are held by the first shard.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccountsShard {
+    pub first_account_id: u64,
+    pub surrealdb_url: String,
+}
+
+/// Parses a comma separated list of shards in the form `FIRST_ACCOUNT_ID=SURREALDB_URL`, e.g.
+/// `1000000000=wss://accounts-0.example.com,5000000000=wss://accounts-1.example.com`.
+///
+/// The shards must be listed in ascending order of their first account IDs, and the first shard must start at
+/// [`MIN_ACCOUNT_ID`] so that every account ID has a shard.
+///
+/// # Errors
+///
+/// Will return `Err` describing the problem if the list is invalid.
+pub fn parse_accounts_shards(value: &str) -> Result<Vec<AccountsShard>, String> {
+    let mut shards: Vec<AccountsShard> = vec![];
+
+    for shard in value
+        .split(',')
+        .map(str::trim)
+        .filter(|shard| !shard.is_empty())
+    {
+        let Some((first_account_id, surrealdb_url)) = shard.split_once('=') else {
+            return Err(format!(
+                "{shard:?} is not in the form FIRST_ACCOUNT_ID=SURREALDB_URL"
+            ));
+        };
+
+        let first_account_id = match first_account_id.trim().parse::<u64>() {
+            Ok(first_account_id)
+                if (MIN_ACCOUNT_ID..=MAX_ACCOUNT_ID).contains(&first_account_id) =>
+            {
+                first_account_id
+            }
+            _ => {
+                return Err(format!(
+                    "{first_account_id:?} is not a valid account ID (expected a 10-digit number without leading zeros)"
+                ));
+            }
+        };
+
+        match shards.last() {
+            None if first_account_id != MIN_ACCOUNT_ID => {
+                return Err(format!(
+                    "The first shard must start at account ID {MIN_ACCOUNT_ID}"
+                ));
+            }
+            Some(previous) if first_account_id <= previous.first_account_id => {
+                return Err(format!(
+                    "Shards must be listed in ascending order of their first account IDs ({first_account_id} is listed after {})",
+                    previous.first_account_id
+                ));
+            }
+            _ => {}
+        }
+
+        let surrealdb_url = surrealdb_url.trim();
+
+        if shards
+            .iter()
+            .any(|shard| shard.surrealdb_url == surrealdb_url)
+        {
+            return Err(format!("{surrealdb_url:?} is listed more than once"));
+        }
+
+        shards.push(AccountsShard {
+            first_account_id,
+            surrealdb_url: surrealdb_url.to_string(),
+        });
+    }
+
+    if shards.is_empty() {
+        return Err("At least one shard must be listed".to_string());
+    }
+
+    Ok(shards)
+}
+
+/// Returns the index of the shard holding the records of an account.
+///
+/// # Panics
+///
+/// Will panic if `shards` is empty.
+#[must_use]
+pub fn accounts_shard_index(shards: &[AccountsShard], account_id: u64) -> usize {
+    assert!(!shards.is_empty(), "There should be at least one shard");
+
+    shards
+        .partition_point(|shard| shard.first_account_id <= account_id)
+        .saturating_sub(1)
+}
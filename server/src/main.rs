@@ -2,17 +2,28 @@ use anyhow::Context as _;
 use archodex_backend::env::Env;
 use tracing::{info, warn};
 
+mod serve;
+#[cfg(target_os = "linux")]
+mod systemd;
+#[cfg(windows)]
+mod windows;
+
 #[cfg(debug_assertions)]
 const RUNTIME_STACK_SIZE: usize = 20 * 1024 * 1024; // 20MiB in debug mode
 #[cfg(not(debug_assertions))]
 const RUNTIME_STACK_SIZE: usize = 10 * 1024 * 1024; // 10MiB in release mode
 
+fn env_filter() -> tracing_subscriber::EnvFilter {
+    use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+
+    EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy()
+}
+
 fn setup_logging() {
     use std::io::IsTerminal;
-    use tracing_subscriber::{
-        filter::{EnvFilter, LevelFilter},
-        fmt,
-    };
+    use tracing_subscriber::fmt;
 
     let color = std::io::stdout().is_terminal()
         && (match std::env::var("COLORTERM") {
@@ -23,9 +34,24 @@ fn setup_logging() {
             _ => false,
         });
 
-    let env_filter = EnvFilter::builder()
-        .with_default_directive(LevelFilter::INFO.into())
-        .from_env_lossy();
+    let env_filter = env_filter();
+
+    #[cfg(target_os = "linux")]
+    if systemd::is_connected_to_journal() {
+        use tracing_subscriber::layer::SubscriberExt as _;
+        use tracing_subscriber::util::SubscriberInitExt as _;
+
+        match systemd::journald_layer() {
+            Ok(journald) => {
+                tracing_subscriber::registry()
+                    .with(env_filter)
+                    .with(journald)
+                    .init();
+                return;
+            }
+            Err(err) => eprintln!("Failed to connect to journald, logging to stdout: {err}"),
+        }
+    }
 
     let fmt = fmt().with_env_filter(env_filter);
 
@@ -103,45 +129,171 @@ async fn shutdown_signal() {
 }
 
 fn main() -> anyhow::Result<()> {
+    #[cfg(target_os = "linux")]
+    if std::env::args().nth(1).as_deref() == Some("--install-systemd-unit") {
+        return systemd::install_unit();
+    }
+
+    #[cfg(windows)]
+    match std::env::args().nth(1).as_deref() {
+        Some("--install-windows-service") => return windows::install_service(),
+        Some("--uninstall-windows-service") => return windows::uninstall_service(),
+        Some("--windows-service") => {
+            // This is safe to call before the service dispatcher spawns the service thread
+            unsafe { setup_surrealdb_env_vars() };
+
+            return windows::run_service();
+        }
+        _ => {}
+    }
+
     // This is safe to call first thing at process start before any threads may be spawned (e.g. by tokio)
     unsafe { setup_surrealdb_env_vars() };
 
     setup_logging();
 
+    if let Err(diagnostics) = Env::validate() {
+        eprintln!("{diagnostics}");
+        std::process::exit(1);
+    }
+
+    // Imports an account data archive instead of serving, e.g. to move an account from archodex.com to this backend
+    if std::env::args().nth(1).as_deref() == Some("--import-account-data") {
+        let (Some(account_id), Some(path)) = (std::env::args().nth(2), std::env::args().nth(3))
+        else {
+            anyhow::bail!("Usage: --import-account-data <account ID> <archive file>");
+        };
+
+        return import_account_data(&account_id, std::path::Path::new(&path));
+    }
+
+    // Validates a report file instead of serving, e.g. while developing an agent, optionally dry running it against a
+    // backend
+    if std::env::args().nth(1).as_deref() == Some("--validate-report") {
+        let (Some(path), backend_url) = (std::env::args().nth(2), std::env::args().nth(3)) else {
+            anyhow::bail!("Usage: --validate-report <report file> [backend URL]");
+        };
+
+        return runtime().block_on(archodex_backend::report_check::check_file(
+            std::path::Path::new(&path),
+            backend_url.as_deref(),
+        ));
+    }
+
+    // Restores a backup snapshot instead of serving, e.g. after losing the database's disk
+    #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+    if std::env::args().nth(1).as_deref() == Some("--restore-backup") {
+        let Some(snapshot) = std::env::args().nth(2) else {
+            anyhow::bail!("Usage: --restore-backup <snapshot directory or s3://<bucket>/<prefix>>");
+        };
+
+        return restore_backup(&snapshot);
+    }
+
+    run(shutdown_signal())
+}
+
+fn runtime() -> tokio::runtime::Runtime {
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .thread_stack_size(RUNTIME_STACK_SIZE)
         .build()
         .unwrap()
-        .block_on(async {
-            {
-                migrator::migrate_accounts_database(
-                    Env::accounts_surrealdb_url(),
-                    Env::surrealdb_creds(),
+}
+
+async fn migrate_accounts_databases() -> anyhow::Result<()> {
+    for shard in Env::accounts_shards() {
+        migrator::migrate_accounts_database(&shard.surrealdb_url, Env::surrealdb_creds())
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to migrate accounts database for URL {}",
+                    shard.surrealdb_url
                 )
-                .await
-                .with_context(|| {
-                    format!(
-                        "Failed to migrate accounts database for URL {}",
-                        Env::accounts_surrealdb_url()
-                    )
-                })?;
+            })?;
+    }
+
+    Ok(())
+}
+
+// Imports an archive downloaded from an account's `/export` route into an existing account without resources
+fn import_account_data(account_id: &str, path: &std::path::Path) -> anyhow::Result<()> {
+    runtime().block_on(async {
+        migrate_accounts_databases().await?;
+
+        archodex_backend::import::import_file(account_id, path).await
+    })
+}
+
+// Replaces the database with a snapshot written by the backup worker
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+fn restore_backup(snapshot: &str) -> anyhow::Result<()> {
+    runtime().block_on(async {
+        archodex_backend::backup::restore(snapshot).await?;
+
+        migrate_accounts_databases().await
+    })
+}
+
+// Runs the backend until `shutdown` resolves, then gracefully stops serving requests.
+fn run(shutdown: impl Future<Output = ()> + Send + 'static) -> anyhow::Result<()> {
+    runtime().block_on(async {
+        // Snapshots are restored before migrating, so snapshots written by older versions are migrated too
+        #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+        archodex_backend::backup::restore_on_startup().await?;
+
+        migrate_accounts_databases().await?;
+
+        tokio::spawn(archodex_backend::event_delivery::run_worker());
+        tokio::spawn(archodex_backend::report_job::run_worker());
+        tokio::spawn(archodex_backend::bulk_operation::run_worker());
+        tokio::spawn(archodex_backend::resources_indexes::check());
+        tokio::spawn(archodex_backend::external_ids::backfill());
+        tokio::spawn(archodex_backend::storage_usage::run_worker());
+        tokio::spawn(archodex_backend::maintenance::run_worker());
+        tokio::spawn(archodex_backend::reconciliation::run_worker());
+        tokio::spawn(archodex_backend::health::run_worker());
+        tokio::spawn(archodex_backend::inference::run_worker());
+        tokio::spawn(archodex_backend::event_retention::run_worker());
+        tokio::spawn(archodex_backend::resource_retention::run_worker());
+        #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+        tokio::spawn(archodex_backend::backup::run_worker());
+
+        if Env::canary_account_id().is_some() {
+            tokio::spawn(archodex_backend::canary::run());
+        }
+
+        #[cfg(feature = "kafka")]
+        tokio::spawn(async {
+            if let Err(err) = archodex_backend::kafka_report_consumer::run().await {
+                tracing::error!(?err, "Kafka report consumer failed");
             }
+        });
 
-            let port = Env::port();
+        #[cfg(feature = "sqs")]
+        tokio::spawn(archodex_backend::sqs_report_consumer::run());
 
-            let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
-                .await
-                .unwrap_or_else(|_| panic!("Failed to listen on port {port}"));
+        let mut listeners = vec![];
 
-            info!("Listening on port {port}");
+        // Internal routes are served with the public API unless they have listeners of their own
+        let public_router = if Env::internal_bind_addresses().is_empty() {
+            archodex_backend::router::router()
+        } else {
+            let internal_router = archodex_backend::router::internal_router();
 
-            axum::serve(listener, archodex_backend::router::router())
-                .with_graceful_shutdown(shutdown_signal())
-                .await?;
+            for &address in Env::internal_bind_addresses() {
+                listeners.push((serve::bind(address)?, internal_router.clone()));
+                info!("Listening for internal routes on {address}");
+            }
 
-            anyhow::Ok(())
-        })?;
+            archodex_backend::router::public_router()
+        };
 
-    Ok(())
+        for &address in Env::bind_addresses() {
+            listeners.push((serve::bind(address)?, public_router.clone()));
+            info!("Listening on {address}");
+        }
+
+        serve::serve(listeners, shutdown).await
+    })
 }
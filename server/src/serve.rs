@@ -0,0 +1,196 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Context as _;
+use archodex_backend::env::Env;
+use axum::{Router, extract::ConnectInfo};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{Semaphore, watch},
+    task::JoinSet,
+};
+use tower::ServiceExt as _;
+use tracing::{debug, error, info, warn};
+
+// Binds a listener on `address`. IPv6 listeners only accept IPv6 connections, so `[::]` and `0.0.0.0` can be listened
+// on together on the same port and behave the same on every platform.
+pub(crate) fn bind(address: SocketAddr) -> anyhow::Result<TcpListener> {
+    let bind = || -> io::Result<TcpListener> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(address),
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+
+        if address.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+
+        // Allows restarting while connections of the previous process are in TIME_WAIT, as `TcpListener::bind()` does.
+        // On Windows this would allow other processes to bind the same port.
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+
+        socket.set_nonblocking(true)?;
+        socket.bind(&address.into())?;
+        socket.listen(1024)?;
+
+        TcpListener::from_std(socket.into())
+    };
+
+    bind().with_context(|| format!("Failed to listen on {address}"))
+}
+
+// Serves each listener's router until `shutdown` resolves, then waits for open connections to finish their in-flight
+// requests.
+//
+// This replaces `axum::serve()` to protect small self-hosted instances from slowloris-style clients that exhaust them by
+// opening many connections and trickling requests over them:
+// * At most `Env::max_connections()` connections are served at once by each listener. Further connections wait in the
+//   listener's backlog rather than being accepted.
+// * HTTP/1 connections are closed when the headers of their next request aren't received within
+//   `Env::connection_idle_timeout()`, whether the connection is new, between requests, or sending headers slowly.
+// * TCP keepalive closes connections to peers that went away without closing them.
+//
+// Connections may use HTTP/1 or HTTP/2 (including cleartext HTTP/2 with prior knowledge, e.g. behind a TLS terminating
+// proxy). Agent fleets should prefer HTTP/2, which multiplexes every report of an agent over one long-lived connection
+// rather than taking one connection slot per in-flight report. HTTP/2 connections are pinged every
+// `Env::http2_keepalive_interval()` so that connections to peers that went away are closed.
+pub(crate) async fn serve(
+    listeners: Vec<(TcpListener, Router)>,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(Env::connection_idle_timeout());
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(Env::http2_max_concurrent_streams())
+        .keep_alive_interval(Env::http2_keepalive_interval())
+        .keep_alive_timeout(Env::http2_keepalive_timeout());
+
+    let graceful = Arc::new(GracefulShutdown::new());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let mut accept_loops = JoinSet::new();
+
+    for (listener, router) in listeners {
+        accept_loops.spawn(accept_connections(
+            listener,
+            router,
+            builder.clone(),
+            graceful.clone(),
+            shutdown_rx.clone(),
+        ));
+    }
+
+    shutdown.await;
+
+    // Sending can't fail, as `shutdown_rx` is still held
+    let _ = shutdown_tx.send(true);
+
+    accept_loops.join_all().await;
+
+    info!("Waiting for open connections to finish their requests");
+
+    Arc::into_inner(graceful)
+        .expect("Accept loops should have released the graceful shutdown when they finished")
+        .shutdown()
+        .await;
+
+    Ok(())
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    router: Router,
+    builder: auto::Builder<TokioExecutor>,
+    graceful: Arc<GracefulShutdown>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let connection_permits = Arc::new(Semaphore::new(Env::max_connections()));
+    let keepalive = socket2::TcpKeepalive::new().with_time(Env::tcp_keepalive());
+
+    loop {
+        let connection_permit = match connection_permits.clone().try_acquire_owned() {
+            Ok(connection_permit) => connection_permit,
+            Err(_) => {
+                warn!(
+                    max_connections = Env::max_connections(),
+                    "Connection limit reached, waiting for a connection to close before accepting more"
+                );
+
+                tokio::select! {
+                    connection_permit = connection_permits.clone().acquire_owned() => {
+                        connection_permit.expect("Connection semaphore should never be closed")
+                    }
+                    () = shutdown_requested(&mut shutdown) => break,
+                }
+            }
+        };
+
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) if is_connection_error(&err) => continue,
+                Err(err) => {
+                    // E.g. the process ran out of file descriptors, which may resolve as other connections close
+                    error!(%err, "Failed to accept connection");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            () = shutdown_requested(&mut shutdown) => break,
+        };
+
+        if let Err(err) = socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+            warn!(%err, %remote_addr, "Failed to enable TCP keepalive on connection");
+        }
+
+        // The peer's address is used to resolve the client's address through trusted proxies
+        let service =
+            router
+                .clone()
+                .map_request(move |mut req: axum::extract::Request<Incoming>| {
+                    req.extensions_mut().insert(ConnectInfo(remote_addr));
+                    req
+                });
+
+        let connection = builder
+            .serve_connection(TokioIo::new(stream), TowerToHyperService::new(service))
+            .into_owned();
+
+        let connection = graceful.watch(connection);
+
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                debug!(%err, %remote_addr, "Connection closed with an error");
+            }
+
+            drop(connection_permit);
+        });
+    }
+}
+
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    // The sender is only dropped after shutdown was requested
+    let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+}
+
+// Errors that only affect the connection being accepted, rather than the listener
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
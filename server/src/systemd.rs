@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use anyhow::Context as _;
+
+const UNIT_PATH: &str = "/etc/systemd/system/archodex-backend.service";
+const ENVIRONMENT_FILE_PATH: &str = "/etc/archodex/backend.env";
+
+/// Returns whether stderr is connected to the systemd journal, in which case logs should be sent to journald directly
+/// so they retain their priority and structured fields.
+pub(crate) fn is_connected_to_journal() -> bool {
+    std::env::var_os("JOURNAL_STREAM").is_some()
+}
+
+/// Creates a tracing layer that emits events to journald.
+///
+/// The default `tracing-journald` mapping logs INFO events at the NOTICE priority. Map levels one-to-one onto their
+/// syslog counterparts instead so that `journalctl --priority` filters behave as operators expect.
+pub(crate) fn journald_layer() -> std::io::Result<tracing_journald::Layer> {
+    use tracing_journald::{Priority, PriorityMappings};
+
+    Ok(tracing_journald::layer()?
+        .with_syslog_identifier("archodex-backend".to_string())
+        .with_priority_mappings(PriorityMappings {
+            error: Priority::Error,
+            warn: Priority::Warning,
+            info: Priority::Informational,
+            debug: Priority::Debug,
+            trace: Priority::Debug,
+        }))
+}
+
+/// Writes a systemd unit file for the currently running server binary.
+///
+/// Configuration environment variables (e.g. `ARCHODEX_DOMAIN`) are read from an optional environment file so that
+/// secrets don't need to be stored in the unit file itself.
+pub(crate) fn install_unit() -> anyhow::Result<()> {
+    let exe = std::env::current_exe().context("Failed to determine path of server binary")?;
+
+    let unit = format!(
+        "[Unit]
+Description=Archodex Backend
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=exec
+ExecStart={exe}
+EnvironmentFile=-{ENVIRONMENT_FILE_PATH}
+Restart=on-failure
+RestartSec=5
+KillSignal=SIGTERM
+TimeoutStopSec=30
+StateDirectory=archodex-backend
+WorkingDirectory=/var/lib/archodex-backend
+NoNewPrivileges=true
+ProtectSystem=strict
+ProtectHome=true
+PrivateTmp=true
+
+[Install]
+WantedBy=multi-user.target
+",
+        exe = exe.display()
+    );
+
+    std::fs::write(UNIT_PATH, unit).with_context(|| format!("Failed to write {UNIT_PATH}"))?;
+
+    println!("Installed systemd unit {UNIT_PATH}");
+    if !Path::new(ENVIRONMENT_FILE_PATH).exists() {
+        println!(
+            "Configure the backend by setting environment variables in {ENVIRONMENT_FILE_PATH}"
+        );
+    }
+    println!(
+        "Start the backend with `systemctl daemon-reload && systemctl enable --now archodex-backend`"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,366 @@
+use std::{
+    ffi::{OsStr, OsString},
+    os::windows::ffi::OsStrExt as _,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use tracing::{error, info};
+use tracing_subscriber::{Layer, layer::Context as LayerContext};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+use windows_sys::Win32::{
+    Foundation::ERROR_SUCCESS,
+    System::{
+        EventLog::{
+            DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+            EVENTLOG_WARNING_TYPE, RegisterEventSourceW, ReportEventW,
+        },
+        Registry::{
+            HKEY, HKEY_LOCAL_MACHINE, KEY_WRITE, REG_DWORD, REG_EXPAND_SZ, REG_OPTION_NON_VOLATILE,
+            RegCloseKey, RegCreateKeyExW, RegDeleteTreeW, RegSetValueExW,
+        },
+    },
+};
+
+const SERVICE_NAME: &str = "ArchodexBackend";
+const SERVICE_DISPLAY_NAME: &str = "Archodex Backend";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+const SERVICE_ARGUMENT: &str = "--windows-service";
+
+// The event log source is registered under the service name. Event messages are rendered through the message file that
+// ships with the .NET Framework, which formats every event ID as the event's single insertion string. This is the same
+// message file .NET registers for its own event sources.
+const EVENT_LOG_SOURCE_KEY: &str =
+    r"SYSTEM\CurrentControlSet\Services\EventLog\Application\ArchodexBackend";
+const EVENT_MESSAGE_FILE: &str =
+    r"%SystemRoot%\Microsoft.NET\Framework64\v4.0.30319\EventLogMessages.dll";
+
+// How long the service control manager should wait for graceful shutdown before considering the service hung
+const STOP_WAIT_HINT: Duration = Duration::from_secs(30);
+
+fn wide(value: impl AsRef<OsStr>) -> Vec<u16> {
+    value.as_ref().encode_wide().chain(Some(0)).collect()
+}
+
+/// Registers the currently running server binary as an automatically started Windows service and registers its event
+/// log source.
+pub(crate) fn install_service() -> anyhow::Result<()> {
+    let service_manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .context("Failed to connect to the Windows service control manager")?;
+
+    let service = service_manager
+        .create_service(
+            &ServiceInfo {
+                name: OsString::from(SERVICE_NAME),
+                display_name: OsString::from(SERVICE_DISPLAY_NAME),
+                service_type: SERVICE_TYPE,
+                start_type: ServiceStartType::AutoStart,
+                error_control: ServiceErrorControl::Normal,
+                executable_path: std::env::current_exe()
+                    .context("Failed to determine path of server binary")?,
+                launch_arguments: vec![OsString::from(SERVICE_ARGUMENT)],
+                dependencies: vec![],
+                account_name: None, // LocalSystem
+                account_password: None,
+            },
+            ServiceAccess::CHANGE_CONFIG,
+        )
+        .context("Failed to create Windows service")?;
+
+    service
+        .set_description("Archodex self-hosted backend")
+        .context("Failed to set Windows service description")?;
+
+    register_event_source()?;
+
+    println!("Installed Windows service {SERVICE_NAME}");
+    println!(
+        "Configure the backend with system environment variables, then start it with `sc.exe start {SERVICE_NAME}`"
+    );
+
+    Ok(())
+}
+
+/// Stops and removes the Windows service and its event log source.
+pub(crate) fn uninstall_service() -> anyhow::Result<()> {
+    let service_manager =
+        ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .context("Failed to connect to the Windows service control manager")?;
+
+    let service = service_manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .context("Failed to open Windows service")?;
+
+    // The service is removed once it has stopped and all handles to it are closed
+    service
+        .delete()
+        .context("Failed to delete Windows service")?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop().context("Failed to stop Windows service")?;
+    }
+
+    let key = wide(EVENT_LOG_SOURCE_KEY);
+    let status = unsafe { RegDeleteTreeW(HKEY_LOCAL_MACHINE, key.as_ptr()) };
+    if status != ERROR_SUCCESS {
+        eprintln!("Failed to remove event log source registration: Win32 error {status}");
+    }
+
+    println!("Uninstalled Windows service {SERVICE_NAME}");
+
+    Ok(())
+}
+
+fn register_event_source() -> anyhow::Result<()> {
+    let subkey = wide(EVENT_LOG_SOURCE_KEY);
+    let mut key: HKEY = std::ptr::null_mut();
+
+    let status = unsafe {
+        RegCreateKeyExW(
+            HKEY_LOCAL_MACHINE,
+            subkey.as_ptr(),
+            0,
+            std::ptr::null(),
+            REG_OPTION_NON_VOLATILE,
+            KEY_WRITE,
+            std::ptr::null(),
+            &raw mut key,
+            std::ptr::null_mut(),
+        )
+    };
+    anyhow::ensure!(
+        status == ERROR_SUCCESS,
+        "Failed to create event log source registry key: Win32 error {status}"
+    );
+
+    let message_file = wide(EVENT_MESSAGE_FILE);
+    let message_file_name = wide("EventMessageFile");
+    let types_supported: u32 =
+        u32::from(EVENTLOG_ERROR_TYPE | EVENTLOG_WARNING_TYPE | EVENTLOG_INFORMATION_TYPE);
+    let types_supported_name = wide("TypesSupported");
+
+    let status = unsafe {
+        let status = RegSetValueExW(
+            key,
+            message_file_name.as_ptr(),
+            0,
+            REG_EXPAND_SZ,
+            message_file.as_ptr().cast(),
+            u32::try_from(message_file.len() * size_of::<u16>())
+                .expect("Message file path should fit in a registry value"),
+        );
+
+        if status == ERROR_SUCCESS {
+            RegSetValueExW(
+                key,
+                types_supported_name.as_ptr(),
+                0,
+                REG_DWORD,
+                (&raw const types_supported).cast(),
+                u32::try_from(size_of::<u32>()).expect("u32 size should fit in a u32"),
+            )
+        } else {
+            status
+        }
+    };
+
+    unsafe { RegCloseKey(key) };
+
+    anyhow::ensure!(
+        status == ERROR_SUCCESS,
+        "Failed to register event log source: Win32 error {status}"
+    );
+
+    Ok(())
+}
+
+/// A tracing layer that writes events to the Windows Application event log, mapping ERROR and WARN levels onto the
+/// matching event types.
+pub(crate) struct EventLogLayer {
+    // `HANDLE` is a raw pointer, which isn't `Send` or `Sync`. Event log handles may be used from any thread.
+    handle: usize,
+}
+
+impl EventLogLayer {
+    pub(crate) fn new() -> std::io::Result<Self> {
+        let source = wide(SERVICE_NAME);
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            handle: handle as usize,
+        })
+    }
+}
+
+impl Drop for EventLogLayer {
+    fn drop(&mut self) {
+        unsafe { DeregisterEventSource(self.handle as _) };
+    }
+}
+
+#[derive(Default)]
+struct EventMessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl tracing::field::Visit for EventMessageVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        use std::fmt::Write as _;
+
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+}
+
+impl<S: tracing::Subscriber> Layer<S> for EventLogLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: LayerContext<'_, S>) {
+        let event_type = match *event.metadata().level() {
+            tracing::Level::ERROR => EVENTLOG_ERROR_TYPE,
+            tracing::Level::WARN => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+
+        let mut visitor = EventMessageVisitor::default();
+        event.record(&mut visitor);
+
+        let message = wide(format!(
+            "{}: {}{}",
+            event.metadata().target(),
+            visitor.message,
+            visitor.fields
+        ));
+        let strings = [message.as_ptr()];
+
+        unsafe {
+            ReportEventW(
+                self.handle as _,
+                event_type,
+                0,
+                0,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            );
+        }
+    }
+}
+
+/// Runs the server under the Windows service control manager. Blocks until the service is stopped.
+pub(crate) fn run_service() -> anyhow::Result<()> {
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .context("Failed to start Windows service dispatcher")
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    // Services have no console, so logs can only go to the event log
+    match EventLogLayer::new() {
+        Ok(event_log) => {
+            use tracing_subscriber::layer::SubscriberExt as _;
+            use tracing_subscriber::util::SubscriberInitExt as _;
+
+            tracing_subscriber::registry()
+                .with(crate::env_filter())
+                .with(event_log)
+                .init();
+        }
+        Err(_) => crate::setup_logging(),
+    }
+
+    if let Err(diagnostics) = archodex_backend::env::Env::validate() {
+        error!("{diagnostics}");
+        return;
+    }
+
+    if let Err(err) = run_service_until_stopped() {
+        error!(?err, "Windows service failed");
+    }
+}
+
+fn run_service_until_stopped() -> anyhow::Result<()> {
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let shutdown_tx = Mutex::new(Some(shutdown_tx));
+
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                info!("Received Windows service stop request, initiating graceful shutdown");
+                if let Some(shutdown_tx) = shutdown_tx
+                    .lock()
+                    .expect("Shutdown sender mutex should not be poisoned")
+                    .take()
+                {
+                    let _ = shutdown_tx.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })
+        .context("Failed to register Windows service control handler")?;
+
+    let set_state = |current_state, controls_accepted, exit_code, wait_hint| {
+        status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint,
+            process_id: None,
+        })
+    };
+
+    set_state(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ServiceExitCode::Win32(0),
+        Duration::default(),
+    )
+    .context("Failed to set Windows service status to running")?;
+
+    let result = crate::run(async {
+        let _ = shutdown_rx.await;
+    });
+
+    if let Err(err) = &result {
+        error!(?err, "Backend server failed");
+    }
+
+    set_state(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        // ERROR_SERVICE_SPECIFIC_ERROR is reported when a service-specific exit code is set
+        ServiceExitCode::ServiceSpecific(u32::from(result.is_err())),
+        STOP_WAIT_HINT,
+    )
+    .context("Failed to set Windows service status to stopped")?;
+
+    result
+}
@@ -1,21 +1,201 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
 use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac as _};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use surrealdb::sql::statements::{BeginStatement, CommitStatement};
-use tracing::instrument;
+use tracing::{instrument, warn};
+use utoipa::ToSchema;
 
 use crate::{
-    db::{DBConnection, migrate_service_data_database, resources_db},
+    Result,
+    account_lock::{AccountLock, AccountLockMode},
+    clock,
+    db::{
+        DBConnection, PreparedQuery, QueryCheckFirstRealError, accounts_db_for_account,
+        migrate_service_data_database, query_accounts_db_shards, resources_db,
+    },
+    enrichment::EnricherKind,
     env::Env,
-    next_binding, surrealdb_deserializers,
+    next_binding, rng, surrealdb_deserializers,
+    transformation::TransformationRule,
     user::User,
 };
-use archodex_error::anyhow;
+use archodex_error::{anyhow, not_found};
+
+const BASE62_ALPHABET: &[u8; 62] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// Number of base62 digits needed to encode 128 bits. External IDs are zero padded to this length so they can never be
+// mistaken for a legacy 10-digit account ID.
+const EXTERNAL_ACCOUNT_ID_LEN: usize = 22;
+
+// Derives the opaque external ID for an account, or returns `None` if `ARCHODEX_ACCOUNT_ID_HMAC_KEY` isn't set. The
+// external ID is the first 128 bits of HMAC-SHA256 of the account ID, base62 encoded.
+pub(crate) fn external_account_id(account_id: &str) -> Option<String> {
+    let key = Env::account_id_hmac_key()?;
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC should accept keys of any length");
+    mac.update(account_id.as_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let mut value = u128::from_be_bytes(
+        digest[..16]
+            .try_into()
+            .expect("SHA-256 digest should be at least 16 bytes"),
+    );
+
+    let mut external_id = [b'0'; EXTERNAL_ACCOUNT_ID_LEN];
+    for digit in external_id.iter_mut().rev() {
+        *digit = BASE62_ALPHABET
+            [usize::try_from(value % 62).expect("Base62 digit should fit in a usize")];
+        value /= 62;
+    }
+
+    Some(String::from_utf8(external_id.to_vec()).expect("Base62 alphabet should be ASCII"))
+}
+
+fn is_legacy_account_id(account_id: &str) -> bool {
+    account_id.len() == 10 && account_id.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+// Resolves an account ID from a URL path to the internal account ID. Legacy numeric account IDs are always accepted so
+// existing dashboard links and clients keep working after external IDs are enabled.
+#[instrument(err)]
+pub(crate) async fn resolve_account_id(account_id: &str) -> Result<String> {
+    if is_legacy_account_id(account_id) {
+        return Ok(account_id.to_string());
+    }
+
+    if Env::account_id_hmac_key().is_none() {
+        not_found!("Account not found");
+    }
+
+    // External IDs don't reveal the account ID, so every shard is searched
+    let Some(internal_account_id) = query_accounts_db_shards(|db| async move {
+        Ok(db
+            .get_account_id_by_external_id(account_id.to_string())
+            .await?
+            .check_first_real_error()?
+            .take::<Option<String>>(0)?)
+    })
+    .await?
+    .into_iter()
+    .next() else {
+        not_found!("Account not found");
+    };
+
+    Ok(internal_account_id)
+}
+
+// Fetches an account's record, including deleted accounts, from the accounts database shard holding it
+#[instrument(err)]
+pub(crate) async fn get_account(account_id: &str) -> Result<Option<Account>> {
+    Ok(accounts_db_for_account(account_id)
+        .await?
+        .get_account_by_id(account_id.to_string())
+        .await?
+        .check_first_real_error()?
+        .take::<Option<Account>>(0)?)
+}
+
+// How long account records are cached for authenticated requests. Changes made through this backend invalidate cached
+// records immediately; changes made through other backend instances apply once entries expire.
+const ACCOUNT_CACHE_TTL: Duration = Duration::from_secs(10);
+
+// Expired entries are pruned when the cache grows beyond this many entries
+const ACCOUNT_CACHE_PRUNE_THRESHOLD: usize = 10_000;
+
+type AccountCache = HashMap<String, (Account, Instant)>;
+
+static ACCOUNT_CACHE: LazyLock<Mutex<AccountCache>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn account_cache() -> std::sync::MutexGuard<'static, AccountCache> {
+    ACCOUNT_CACHE
+        .lock()
+        .expect("Account cache mutex should not be poisoned")
+}
+
+// Fetches an account's record like `get_account`, but from a short-lived cache. Every authenticated request loads its
+// account, so this saves an accounts database round trip on most reports. Missing accounts aren't cached, so new
+// accounts are usable immediately.
+pub(crate) async fn get_cached_account(account_id: &str) -> Result<Option<Account>> {
+    if let Some((account, cached_at)) = account_cache().get(account_id)
+        && cached_at.elapsed() < ACCOUNT_CACHE_TTL
+    {
+        return Ok(Some(account.clone()));
+    }
+
+    let account = get_account(account_id).await?;
+
+    if let Some(account) = &account {
+        let mut cache = account_cache();
+
+        if cache.len() >= ACCOUNT_CACHE_PRUNE_THRESHOLD {
+            cache.retain(|_, (_, cached_at)| cached_at.elapsed() < ACCOUNT_CACHE_TTL);
+        }
+
+        cache.insert(account_id.to_string(), (account.clone(), Instant::now()));
+    }
+
+    Ok(account)
+}
+
+// Drops an account's cached record. Must be called whenever the account record changes.
+pub(crate) fn invalidate_cached_account(account_id: &str) {
+    account_cache().remove(account_id);
+}
+
+// Fetches the records of the accounts among `account_ids` that exist, including deleted accounts, from every accounts
+// database shard
+#[cfg(feature = "archodex-com")]
+#[instrument(err, skip_all)]
+pub(crate) async fn get_accounts(account_ids: Vec<String>) -> Result<Vec<Account>> {
+    let accounts = account_ids
+        .into_iter()
+        .map(|account_id| {
+            surrealdb::sql::Thing::from(("account", surrealdb::sql::Id::String(account_id)))
+        })
+        .collect::<Vec<_>>();
+    let accounts = &accounts;
+
+    query_accounts_db_shards(|db| async move {
+        Ok(db
+            .query("SELECT * FROM $accounts")
+            .bind(("accounts", accounts.clone()))
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<Account>>(0)?)
+    })
+    .await
+}
+
+// Lists the accounts that weren't deleted from every accounts database shard
+#[instrument(err)]
+pub(crate) async fn list_live_accounts() -> Result<Vec<Account>> {
+    query_accounts_db_shards(|db| async move {
+        Ok(db
+            .query("SELECT * FROM account WHERE deleted_at IS NONE")
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<Account>>(0)?)
+    })
+    .await
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct Account {
     #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
     id: String,
+    #[serde(default)]
+    external_id: Option<String>,
     #[cfg(feature = "archodex-com")]
     endpoint: String,
     #[cfg(feature = "archodex-com")]
@@ -32,9 +212,21 @@ pub(crate) struct Account {
     created_by: Option<User>,
     deleted_at: Option<DateTime<Utc>>,
     deleted_by: Option<User>,
+    #[serde(default)]
+    debug_capture_until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    enrichers: BTreeSet<EnricherKind>,
+    #[serde(default)]
+    event_retention_days: Option<u32>,
+    #[serde(default)]
+    resource_retention_days: Option<u32>,
+    #[serde(default)]
+    lock: Option<AccountLock>,
+    #[serde(default)]
+    transformations: Vec<TransformationRule>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 pub(crate) struct AccountPublic {
     pub(crate) id: String,
     #[cfg(feature = "archodex-com")]
@@ -42,11 +234,43 @@ pub(crate) struct AccountPublic {
 }
 
 impl From<Account> for AccountPublic {
+    fn from(record: Account) -> Self {
+        Self {
+            // Once external IDs are enabled the dashboard builds account URLs from them instead of the numeric ID
+            id: external_account_id(&record.id).unwrap_or(record.id),
+            #[cfg(feature = "archodex-com")]
+            endpoint: record.endpoint,
+        }
+    }
+}
+
+// Account details for the admin API. Unlike `AccountPublic`, this includes the internal account ID and lifecycle
+// metadata, but still omits key material.
+#[derive(Serialize)]
+pub(crate) struct AccountAdmin {
+    id: String,
+    external_id: Option<String>,
+    #[cfg(feature = "archodex-com")]
+    endpoint: String,
+    created_at: Option<DateTime<Utc>>,
+    created_by: Option<User>,
+    deleted_at: Option<DateTime<Utc>>,
+    deleted_by: Option<User>,
+    lock: Option<AccountLock>,
+}
+
+impl From<Account> for AccountAdmin {
     fn from(record: Account) -> Self {
         Self {
             id: record.id,
+            external_id: record.external_id,
             #[cfg(feature = "archodex-com")]
             endpoint: record.endpoint,
+            created_at: record.created_at,
+            created_by: record.created_by,
+            deleted_at: record.deleted_at,
+            deleted_by: record.deleted_by,
+            lock: record.lock.filter(AccountLock::is_active),
         }
     }
 }
@@ -58,21 +282,44 @@ impl Account {
         let service_data_surrealdb_url = if endpoint == Env::endpoint() {
             let service_data_surrealdb_url =
                 archodex_com::create_account_service_database(&id).await?;
-            migrate_service_data_database(&service_data_surrealdb_url, &id).await?;
+
+            let provisioned = match crate::provisioning::record_service_database(
+                &id,
+                &service_data_surrealdb_url,
+            )
+            .await
+            {
+                Ok(()) => migrate_service_data_database(&service_data_surrealdb_url, &id).await,
+                Err(err) => Err(anyhow::anyhow!(err)),
+            };
+
+            if let Err(err) = provisioned {
+                crate::provisioning::discard_service_database(&id, &service_data_surrealdb_url)
+                    .await;
+                return Err(err);
+            }
+
             Some(service_data_surrealdb_url)
         } else {
             None
         };
 
         Ok(Self {
+            external_id: external_account_id(&id),
             id,
             endpoint,
             service_data_surrealdb_url,
-            salt: rand::thread_rng().r#gen::<[u8; 16]>().to_vec(),
+            salt: rng::with_rng(|rng| rng.r#gen::<[u8; 16]>().to_vec()),
             created_at: None,
             created_by: Some(principal),
             deleted_at: None,
             deleted_by: None,
+            debug_capture_until: None,
+            enrichers: BTreeSet::new(),
+            event_retention_days: None,
+            resource_retention_days: None,
+            lock: None,
+            transformations: vec![],
         })
     }
 
@@ -94,30 +341,91 @@ impl Account {
             info!(
                 "API Private Key value was not found in ARCHODEX_API_PRIVATE_KEY environment variable, generating a new key and storing it in the database"
             );
-            Some(rand::thread_rng().r#gen::<[u8; 16]>().to_vec())
+            Some(rng::with_rng(|rng| rng.r#gen::<[u8; 16]>().to_vec()))
         };
 
         Ok(Self {
+            external_id: external_account_id(&id),
             id,
-            salt: rand::thread_rng().r#gen::<[u8; 16]>().to_vec(),
+            salt: rng::with_rng(|rng| rng.r#gen::<[u8; 16]>().to_vec()),
             api_private_key,
             created_at: None,
             created_by: Some(principal),
             deleted_at: None,
             deleted_by: None,
+            debug_capture_until: None,
+            enrichers: BTreeSet::new(),
+            event_retention_days: None,
+            resource_retention_days: None,
+            lock: None,
+            transformations: vec![],
         })
     }
 
-    #[cfg(feature = "archodex-com")]
     pub(crate) fn id(&self) -> &str {
         &self.id
     }
 
+    pub(crate) fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
     #[cfg(feature = "archodex-com")]
     pub(crate) fn service_data_surrealdb_url(&self) -> Option<&str> {
         self.service_data_surrealdb_url.as_deref()
     }
 
+    // Stores the account's external ID if it is missing or was derived from a different HMAC key. Returns whether it was
+    // stored.
+    #[instrument(err, skip_all)]
+    pub(crate) async fn ensure_external_id(&self) -> Result<bool> {
+        let external_id = external_account_id(&self.id);
+
+        if external_id.is_none() || external_id == self.external_id {
+            return Ok(false);
+        }
+
+        accounts_db_for_account(&self.id)
+            .await?
+            .set_account_external_id_query(self, external_id)
+            .await?
+            .check_first_real_error()?;
+
+        invalidate_cached_account(&self.id);
+
+        Ok(true)
+    }
+
+    // Time until which the account's requests and responses are recorded for support, if debug capture was enabled
+    pub(crate) fn debug_capture_until(&self) -> Option<DateTime<Utc>> {
+        self.debug_capture_until
+    }
+
+    // Enrichers that add attributes to the account's reported resources before they are upserted
+    pub(crate) fn enrichers(&self) -> &BTreeSet<EnricherKind> {
+        &self.enrichers
+    }
+
+    // Number of days the account's events are kept after they were last seen, or `None` to keep them forever
+    pub(crate) fn event_retention_days(&self) -> Option<u32> {
+        self.event_retention_days
+    }
+
+    // Number of days the account's resources are kept after they were last seen, or `None` to keep them forever
+    pub(crate) fn resource_retention_days(&self) -> Option<u32> {
+        self.resource_retention_days
+    }
+
+    // Rules that rewrite the account's reports before they are upserted, in the order they apply
+    pub(crate) fn transformations(&self) -> &[TransformationRule] {
+        &self.transformations
+    }
+
+    // The account's lock if it is set and has not expired
+    pub(crate) fn lock(&self) -> Option<&AccountLock> {
+        self.lock.as_ref().filter(|lock| lock.is_active())
+    }
+
     pub(crate) fn salt(&self) -> &[u8] {
         &self.salt
     }
@@ -137,6 +445,23 @@ impl Account {
 
         resources_db(service_data_surrealdb_url, &self.id).await
     }
+
+    // Returns the account's resources database connection, or `None` if it can't be reached. Read-only list endpoints
+    // use this to respond with partial data flagged as `degraded` instead of failing when the resources database is
+    // unavailable.
+    pub(crate) async fn resources_db_if_available(&self) -> Option<DBConnection> {
+        match self.resources_db().await {
+            Ok(db) => Some(db),
+            Err(err) => {
+                warn!(
+                    account_id = self.id,
+                    ?err,
+                    "Resources database is unavailable"
+                );
+                None
+            }
+        }
+    }
 }
 
 pub(crate) trait AccountQueries<'r, C: surrealdb::Connection> {
@@ -146,6 +471,54 @@ pub(crate) trait AccountQueries<'r, C: surrealdb::Connection> {
         principal: &User,
     ) -> surrealdb::method::Query<'r, C>;
     fn get_account_by_id(&'r self, account_id: String) -> surrealdb::method::Query<'r, C>;
+    fn get_account_id_by_external_id(
+        &'r self,
+        external_id: String,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn set_account_external_id_query(
+        &'r self,
+        account: &Account,
+        external_id: Option<String>,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn set_account_debug_capture_until_query(
+        &'r self,
+        account: &Account,
+        debug_capture_until: Option<DateTime<Utc>>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn set_account_enrichers_query(
+        &'r self,
+        account: &Account,
+        enrichers: &BTreeSet<EnricherKind>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn set_account_event_retention_query(
+        &'r self,
+        account: &Account,
+        event_retention_days: Option<u32>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn set_account_resource_retention_query(
+        &'r self,
+        account: &Account,
+        resource_retention_days: Option<u32>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn lock_account_query(
+        &'r self,
+        account: &Account,
+        mode: AccountLockMode,
+        reason: &str,
+        expires_at: Option<DateTime<Utc>>,
+        principal: Option<&User>,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn unlock_account_query(&'r self, account: &Account) -> surrealdb::method::Query<'r, C>;
+    fn set_account_transformations_query(
+        &'r self,
+        account: &Account,
+        transformations: &[TransformationRule],
+        principal: &User,
+    ) -> anyhow::Result<surrealdb::method::Query<'r, C>>;
     fn delete_account_query(
         &'r self,
         account: &Account,
@@ -164,6 +537,7 @@ impl<'r, C: surrealdb::Connection> AccountQueries<'r, C> for surrealdb::Surreal<
         let service_data_surrealdb_url_binding = next_binding();
         let salt_binding = next_binding();
         let api_private_key_binding = next_binding();
+        let external_id_binding = next_binding();
         let created_by_binding = next_binding();
 
         #[cfg(not(feature = "archodex-com"))]
@@ -184,12 +558,13 @@ impl<'r, C: surrealdb::Connection> AccountQueries<'r, C> for surrealdb::Surreal<
 
         let query = self
             .query(BeginStatement::default())
-            .query(format!("CREATE ${account_binding} CONTENT {{ endpoint: ${endpoint_binding}, service_data_surrealdb_url: ${service_data_surrealdb_url_binding}, salt: ${salt_binding}, api_private_key: ${api_private_key_binding}, created_by: ${created_by_binding} }} RETURN NONE"))
+            .query(format!("CREATE ${account_binding} CONTENT {{ endpoint: ${endpoint_binding}, service_data_surrealdb_url: ${service_data_surrealdb_url_binding}, salt: ${salt_binding}, api_private_key: ${api_private_key_binding}, external_id: ${external_id_binding}, created_by: ${created_by_binding} }} RETURN NONE"))
             .bind((account_binding, surrealdb::sql::Thing::from(account)))
             .bind((endpoint_binding, endpoint_value))
             .bind((service_data_surrealdb_url_binding, service_data_surrealdb_url_value))
             .bind((salt_binding, surrealdb::sql::Bytes::from(account.salt.clone())))
             .bind((api_private_key_binding, api_private_key_value))
+            .bind((external_id_binding, account.external_id.clone()))
             .bind((created_by_binding, surrealdb::sql::Thing::from(principal)));
 
         let user_binding = next_binding();
@@ -205,13 +580,180 @@ impl<'r, C: surrealdb::Connection> AccountQueries<'r, C> for surrealdb::Surreal<
     }
 
     fn get_account_by_id(&'r self, account_id: String) -> surrealdb::method::Query<'r, C> {
+        static QUERY: PreparedQuery = PreparedQuery::new("SELECT * FROM ONLY $account");
+
+        self.query(&QUERY).bind((
+            "account",
+            surrealdb::sql::Thing::from(("account", surrealdb::sql::Id::String(account_id))),
+        ))
+    }
+
+    fn get_account_id_by_external_id(
+        &'r self,
+        external_id: String,
+    ) -> surrealdb::method::Query<'r, C> {
+        let external_id_binding = next_binding();
+
+        self.query(format!(
+            "SELECT VALUE record::id(id) FROM ONLY account WHERE external_id = ${external_id_binding} LIMIT 1"
+        ))
+        .bind((external_id_binding, external_id))
+    }
+
+    fn set_account_debug_capture_until_query(
+        &'r self,
+        account: &Account,
+        debug_capture_until: Option<DateTime<Utc>>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+        let debug_capture_until_binding = next_binding();
+        let principal_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE ${account_binding} SET debug_capture_until = ${debug_capture_until_binding}, debug_capture_updated_by = ${principal_binding} RETURN NONE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((
+            debug_capture_until_binding,
+            debug_capture_until.map(surrealdb::sql::Datetime::from),
+        ))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+    }
+
+    fn set_account_enrichers_query(
+        &'r self,
+        account: &Account,
+        enrichers: &BTreeSet<EnricherKind>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
         let account_binding = next_binding();
+        let enrichers_binding = next_binding();
+        let principal_binding = next_binding();
 
-        self.query(format!("SELECT * FROM ONLY ${account_binding}"))
+        self.query(format!(
+            "UPDATE ${account_binding} SET enrichers = ${enrichers_binding}, enrichers_updated_by = ${principal_binding} RETURN NONE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((enrichers_binding, enrichers.clone()))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+    }
+
+    fn set_account_event_retention_query(
+        &'r self,
+        account: &Account,
+        event_retention_days: Option<u32>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+        let event_retention_days_binding = next_binding();
+        let principal_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE ${account_binding} SET event_retention_days = ${event_retention_days_binding}, event_retention_updated_by = ${principal_binding} RETURN NONE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((event_retention_days_binding, event_retention_days))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+    }
+
+    fn set_account_resource_retention_query(
+        &'r self,
+        account: &Account,
+        resource_retention_days: Option<u32>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+        let resource_retention_days_binding = next_binding();
+        let principal_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE ${account_binding} SET resource_retention_days = ${resource_retention_days_binding}, resource_retention_updated_by = ${principal_binding} RETURN NONE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((resource_retention_days_binding, resource_retention_days))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+    }
+
+    // Locks set without a principal were set through the admin API
+    fn lock_account_query(
+        &'r self,
+        account: &Account,
+        mode: AccountLockMode,
+        reason: &str,
+        expires_at: Option<DateTime<Utc>>,
+        principal: Option<&User>,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+        let mode_binding = next_binding();
+        let reason_binding = next_binding();
+        let expires_at_binding = next_binding();
+        let locked_at_binding = next_binding();
+        let principal_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE ${account_binding} SET lock = {{ mode: ${mode_binding}, reason: ${reason_binding}, expires_at: ${expires_at_binding}, locked_at: ${locked_at_binding}, locked_by: ${principal_binding}, set_by_admin: ${principal_binding} IS NONE }} RETURN NONE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((mode_binding, mode))
+        .bind((reason_binding, reason.to_string()))
+        .bind((
+            expires_at_binding,
+            expires_at.map(surrealdb::sql::Datetime::from),
+        ))
+        .bind((
+            locked_at_binding,
+            surrealdb::sql::Datetime::from(clock::now()),
+        ))
+        .bind((principal_binding, principal.map(surrealdb::sql::Thing::from)))
+    }
+
+    fn unlock_account_query(&'r self, account: &Account) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE ${account_binding} SET lock = NONE RETURN NONE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+    }
+
+    fn set_account_transformations_query(
+        &'r self,
+        account: &Account,
+        transformations: &[TransformationRule],
+        principal: &User,
+    ) -> anyhow::Result<surrealdb::method::Query<'r, C>> {
+        let account_binding = next_binding();
+        let transformations_binding = next_binding();
+        let principal_binding = next_binding();
+
+        Ok(self
+            .query(format!(
+                "UPDATE ${account_binding} SET transformations = ${transformations_binding}, transformations_updated_by = ${principal_binding} RETURN NONE"
+            ))
+            .bind((account_binding, surrealdb::sql::Thing::from(account)))
             .bind((
-                account_binding,
-                surrealdb::sql::Thing::from(("account", surrealdb::sql::Id::String(account_id))),
+                transformations_binding,
+                crate::value::surrealdb_value_from_json_value(serde_json::to_value(
+                    transformations,
+                )?),
             ))
+            .bind((principal_binding, surrealdb::sql::Thing::from(principal))))
+    }
+
+    fn set_account_external_id_query(
+        &'r self,
+        account: &Account,
+        external_id: Option<String>,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+        let external_id_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE ${account_binding} SET external_id = ${external_id_binding} RETURN NONE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((external_id_binding, external_id))
     }
 
     fn delete_account_query(
@@ -221,13 +763,15 @@ impl<'r, C: surrealdb::Connection> AccountQueries<'r, C> for surrealdb::Surreal<
     ) -> surrealdb::method::Query<'r, C> {
         let account_binding = next_binding();
         let deleted_by_binding = next_binding();
+        let now_binding = next_binding();
 
-        self.query(format!("UPDATE ${account_binding} CONTENT {{ deleted_at: time::now(), deleted_by: ${deleted_by_binding} }}"))
+        self.query(format!("UPDATE ${account_binding} CONTENT {{ deleted_at: ${now_binding}, deleted_by: ${deleted_by_binding} }}"))
             .bind((
                 account_binding,
                 surrealdb::sql::Thing::from(account)
             ))
             .bind((deleted_by_binding, surrealdb::sql::Thing::from(principal)))
+            .bind((now_binding, clock::now_value()))
     }
 }
 
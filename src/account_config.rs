@@ -0,0 +1,321 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+
+use archodex_error::{
+    anyhow::{Context as _, bail},
+    bad_request,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    auth::DashboardAuth,
+    db::{DBConnection, QueryCheckFirstRealError, is_record_exists_error},
+    report_api_key::{
+        MAX_CREATE_ATTEMPTS, ReportApiKey, ReportApiKeyPublic, ReportApiKeyQueries,
+        create_report_api_key_statement, revoke_report_api_key_statement,
+        update_report_api_key_description_statement,
+    },
+    resource::{ResourceId, set_environments_statement, surrealdb_thing_from_resource_id},
+    revision,
+};
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ReportApiKeyConfig {
+    // Keys without an ID are created. Keys with an ID must already exist in the account.
+    id: Option<u32>,
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ResourceEnvironmentsConfig {
+    resource_id: ResourceId,
+    environments: HashSet<String>,
+}
+
+// The desired state of an account's configuration. If the document lists report API keys, keys present in the account
+// but missing from the list are revoked. Keys are left unchanged if the document doesn't list them. Resource
+// environments are only reconciled for the resources listed in the document.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct AccountConfig {
+    report_api_keys: Option<Vec<ReportApiKeyConfig>>,
+    #[serde(default)]
+    environments: Vec<ResourceEnvironmentsConfig>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum AccountConfigChange {
+    CreateReportApiKey {
+        report_api_key: ReportApiKeyPublic,
+        report_api_key_value: String,
+    },
+    UpdateReportApiKey {
+        report_api_key_id: u32,
+        old_description: Option<String>,
+        new_description: Option<String>,
+    },
+    RevokeReportApiKey {
+        report_api_key_id: u32,
+    },
+    SetEnvironments {
+        resource_id: ResourceId,
+        old_environments: HashSet<String>,
+        new_environments: HashSet<String>,
+    },
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ApplyAccountConfigResponse {
+    changes: Vec<AccountConfigChange>,
+}
+
+#[derive(Deserialize)]
+struct ResourceEnvironmentsResult {
+    environments: HashSet<String>,
+}
+
+// Applies every change in a single transaction, so a failure leaves the account's configuration unchanged
+#[utoipa::path(
+    put,
+    path = "/account/{account_id}/config",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = AccountConfig,
+    responses((status = 200, body = ApplyAccountConfigResponse))
+)]
+#[instrument(err, skip(auth, account, config))]
+pub(crate) async fn apply_config(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(config): Json<AccountConfig>,
+) -> Result<Json<ApplyAccountConfigResponse>> {
+    let db = account.resources_db().await?;
+
+    let mut created_key_descriptions = vec![];
+    let mut key_description_updates = vec![];
+    let mut revoked_key_ids = vec![];
+
+    if let Some(report_api_keys) = config.report_api_keys {
+        let mut desired_key_ids = HashSet::new();
+        for report_api_key in &report_api_keys {
+            if let Some(id) = report_api_key.id
+                && !desired_key_ids.insert(id)
+            {
+                bad_request!("Report key {id} is listed more than once");
+            }
+        }
+
+        let existing_report_api_keys = db
+            .list_report_api_keys_query()
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<ReportApiKey>>(0)?
+            .into_iter()
+            .map(|report_api_key| (report_api_key.id(), report_api_key))
+            .collect::<HashMap<_, _>>();
+
+        if let Some(id) = desired_key_ids
+            .iter()
+            .find(|id| !existing_report_api_keys.contains_key(id))
+        {
+            bad_request!("Report key {id} does not exist, omit the ID to create a new key");
+        }
+
+        for report_api_key_config in report_api_keys {
+            let Some(id) = report_api_key_config.id else {
+                created_key_descriptions.push(report_api_key_config.description);
+                continue;
+            };
+
+            let existing_description = existing_report_api_keys[&id].description();
+
+            if existing_description != report_api_key_config.description.as_deref() {
+                key_description_updates.push((
+                    id,
+                    existing_description.map(str::to_owned),
+                    report_api_key_config.description,
+                ));
+            }
+        }
+
+        revoked_key_ids.extend(
+            existing_report_api_keys
+                .into_keys()
+                .filter(|id| !desired_key_ids.contains(id)),
+        );
+    }
+
+    // Resolve environment changes before applying anything so a document referencing an unknown resource is
+    // rejected without partially applying the rest of the configuration.
+    let mut environment_changes = vec![];
+    for resource_environments in config.environments {
+        let Some(existing) = db
+            .query("SELECT environments FROM $resource_id")
+            .bind((
+                "resource_id",
+                surrealdb_thing_from_resource_id(resource_environments.resource_id.clone()),
+            ))
+            .await?
+            .check_first_real_error()?
+            .take::<Option<ResourceEnvironmentsResult>>(0)?
+        else {
+            bad_request!(
+                "Resource {:?} does not exist",
+                resource_environments.resource_id
+            );
+        };
+
+        if existing.environments != resource_environments.environments {
+            environment_changes.push((
+                resource_environments.resource_id,
+                existing.environments,
+                resource_environments.environments,
+            ));
+        }
+    }
+
+    let mut changes = vec![];
+
+    if !created_key_descriptions.is_empty()
+        || !key_description_updates.is_empty()
+        || !revoked_key_ids.is_empty()
+        || !environment_changes.is_empty()
+    {
+        let created_report_api_keys = apply_changes(
+            &db,
+            &auth,
+            &account,
+            &created_key_descriptions,
+            &key_description_updates,
+            &revoked_key_ids,
+            &environment_changes,
+        )
+        .await?;
+
+        changes.extend(created_report_api_keys.into_iter().map(
+            |(report_api_key, report_api_key_value)| AccountConfigChange::CreateReportApiKey {
+                report_api_key: ReportApiKeyPublic::from(report_api_key),
+                report_api_key_value,
+            },
+        ));
+    }
+
+    changes.extend(key_description_updates.into_iter().map(
+        |(report_api_key_id, old_description, new_description)| {
+            AccountConfigChange::UpdateReportApiKey {
+                report_api_key_id,
+                old_description,
+                new_description,
+            }
+        },
+    ));
+
+    changes.extend(
+        revoked_key_ids
+            .into_iter()
+            .map(|report_api_key_id| AccountConfigChange::RevokeReportApiKey { report_api_key_id }),
+    );
+
+    changes.extend(environment_changes.into_iter().map(
+        |(resource_id, old_environments, new_environments)| AccountConfigChange::SetEnvironments {
+            resource_id,
+            old_environments,
+            new_environments,
+        },
+    ));
+
+    info!(num_changes = changes.len(), "Applied account configuration");
+
+    Ok(Json(ApplyAccountConfigResponse { changes }))
+}
+
+// Applies the changes in a single transaction, returning the created keys and their values. New keys have random IDs,
+// so the transaction is retried with new keys if one of their IDs collides with an existing key.
+async fn apply_changes(
+    db: &DBConnection,
+    auth: &DashboardAuth,
+    account: &Account,
+    created_key_descriptions: &[Option<String>],
+    key_description_updates: &[(u32, Option<String>, Option<String>)],
+    revoked_key_ids: &[u32],
+    environment_changes: &[(ResourceId, HashSet<String>, HashSet<String>)],
+) -> Result<Vec<(ReportApiKey, String)>> {
+    for _ in 0..MAX_CREATE_ATTEMPTS {
+        let mut created_report_api_keys = Vec::with_capacity(created_key_descriptions.len());
+        for description in created_key_descriptions {
+            created_report_api_keys.push(
+                ReportApiKey::generate(
+                    description.clone(),
+                    auth.principal(),
+                    account.id(),
+                    account.salt(),
+                )
+                .await?,
+            );
+        }
+
+        // Keys are created first so the result of each create statement is at the index of the key
+        let mut query = db.query(BeginStatement::default());
+
+        for (report_api_key, _) in &created_report_api_keys {
+            query = create_report_api_key_statement(query, report_api_key);
+        }
+
+        for (id, _, new_description) in key_description_updates {
+            query =
+                update_report_api_key_description_statement(query, *id, new_description.clone());
+        }
+
+        for id in revoked_key_ids {
+            query = revoke_report_api_key_statement(query, *id, auth.principal());
+        }
+
+        for (resource_id, _, new_environments) in environment_changes {
+            query =
+                set_environments_statement(query, resource_id.clone(), new_environments.clone());
+        }
+
+        let res = query
+            .query(CommitStatement::default())
+            .await?
+            .check_first_real_error();
+
+        match res {
+            Ok(mut res) => {
+                if !environment_changes.is_empty() {
+                    revision::bump(db).await?;
+                }
+
+                let mut created = Vec::with_capacity(created_report_api_keys.len());
+
+                for (index, (_, report_api_key_value)) in
+                    created_report_api_keys.into_iter().enumerate()
+                {
+                    let report_api_key = res.take::<Option<ReportApiKey>>(index)?.context(
+                        "Create report API key statement should return a report key instance",
+                    )?;
+
+                    created.push((report_api_key, report_api_key_value));
+                }
+
+                return Ok(created);
+            }
+            Err(err) if !created_report_api_keys.is_empty() && is_record_exists_error(&err) => {
+                warn!("Report key ID collided with an existing key, retrying with new IDs");
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    bail!("Failed to apply account configuration: Key IDs collided {MAX_CREATE_ATTEMPTS} times");
+}
@@ -0,0 +1,402 @@
+use std::time::Duration;
+
+use axum::{
+    Extension, Json,
+    extract::Request,
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use surrealdb::Uuid;
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use archodex_error::{PublicError, bad_request, forbidden};
+
+use crate::{
+    Result,
+    account::{Account, AccountQueries as _, invalidate_cached_account},
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    clock,
+    db::{QueryCheckFirstRealError as _, accounts_db_for_account},
+    router::RequestId,
+    user::User,
+};
+
+const MAX_REASON_LENGTH: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AccountLockMode {
+    /// Dashboard requests that change the account and reports are rejected
+    ReadOnly,
+    /// Reports are rejected, the account can otherwise be used and changed as usual
+    IngestionPaused,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct AccountLock {
+    mode: AccountLockMode,
+    reason: String,
+    expires_at: Option<DateTime<Utc>>,
+    locked_at: DateTime<Utc>,
+    locked_by: Option<User>,
+    #[serde(default)]
+    set_by_admin: bool,
+}
+
+impl AccountLock {
+    pub(crate) fn is_active(&self) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| expires_at > clock::now())
+    }
+
+    pub(crate) fn mode(&self) -> AccountLockMode {
+        self.mode
+    }
+
+    // Rejection for requests the lock doesn't allow. Clients that can wait for a lock with an expiry to be lifted are
+    // told when to retry.
+    pub(crate) fn error(&self, action: &str) -> PublicError {
+        let until = match self.expires_at {
+            Some(expires_at) => format!("until {}", expires_at.to_rfc3339()),
+            None => "until it is unlocked".to_string(),
+        };
+
+        let error = PublicError::new(
+            StatusCode::LOCKED,
+            format!("Account is locked {until}, {action}: {}", self.reason),
+        )
+        .with_code("account_locked");
+
+        match self.expires_at {
+            // Rounded up to whole seconds, so clients don't retry before the lock expires
+            Some(expires_at) => error.with_retry_after(Duration::from_secs(
+                u64::try_from((expires_at - clock::now()).num_milliseconds())
+                    .unwrap_or(0)
+                    .div_ceil(1000),
+            )),
+            None => error,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct AccountLockPublic {
+    mode: AccountLockMode,
+    reason: String,
+    expires_at: Option<DateTime<Utc>>,
+    locked_at: DateTime<Utc>,
+    /// Owner who locked the account, unset if an administrator locked it through the admin API
+    locked_by_user_id: Option<Uuid>,
+    /// Locks set by an administrator can't be changed or removed by the account owner
+    set_by_admin: bool,
+}
+
+impl From<AccountLock> for AccountLockPublic {
+    fn from(record: AccountLock) -> Self {
+        Self {
+            mode: record.mode,
+            reason: record.reason,
+            expires_at: record.expires_at,
+            locked_at: record.locked_at,
+            locked_by_user_id: record.locked_by.map(|user| user.id()),
+            set_by_admin: record.set_by_admin,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct AccountLockResponse {
+    /// The account's lock, unset if the account isn't locked or its lock expired
+    lock: Option<AccountLockPublic>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LockAccountRequest {
+    mode: AccountLockMode,
+    /// Shown to everyone whose requests the lock rejects, e.g. "Importing data from the old account"
+    reason: String,
+    /// The lock is lifted at this time. Accounts stay locked until they are unlocked if not set.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+// Rejects dashboard requests that would change a read-only account. `GET` and `HEAD` requests are always allowed, and
+// routes that only read the account with other methods are registered outside this middleware, as are the lock routes
+// so the account can be unlocked. Account records are cached for a few seconds, so other backend instances may accept changes briefly after
+// the account is locked.
+pub(crate) async fn reject_writes(
+    Extension(account): Extension<Account>,
+    req: Request,
+    next: Next,
+) -> Result<Response> {
+    if let Some(lock) = account.lock()
+        && lock.mode == AccountLockMode::ReadOnly
+        && !matches!(*req.method(), Method::GET | Method::HEAD)
+    {
+        return Err(lock.error("changes are not allowed"));
+    }
+
+    Ok(next.run(req).await)
+}
+
+// Rejects reports for locked accounts, whether they are read-only or only have ingestion paused. Queued report jobs are
+// held until the account is unlocked. Reports consumed from SQS and Kafka are still ingested, as holding them back would
+// block the reports of other accounts sharing the queue or partition.
+pub(crate) async fn reject_reports(
+    Extension(account): Extension<Account>,
+    req: Request,
+    next: Next,
+) -> Result<Response> {
+    if let Some(lock) = account.lock() {
+        return Err(lock.error("reports are not accepted"));
+    }
+
+    Ok(next.run(req).await)
+}
+
+// Sets or replaces the account's lock. `principal` is the owner setting it, or `None` for admins.
+#[instrument(err, skip(account, req), fields(account_id = account.id()))]
+pub(crate) async fn lock(
+    account: &Account,
+    req: &LockAccountRequest,
+    principal: Option<&User>,
+) -> Result<()> {
+    if req.reason.trim().is_empty() {
+        bad_request!("A reason for locking the account is required");
+    }
+
+    if req.reason.len() > MAX_REASON_LENGTH {
+        bad_request!("Reason must be at most {MAX_REASON_LENGTH} bytes");
+    }
+
+    if req
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= clock::now())
+    {
+        bad_request!("expires_at must be in the future");
+    }
+
+    accounts_db_for_account(account.id())
+        .await?
+        .lock_account_query(account, req.mode, &req.reason, req.expires_at, principal)
+        .await?
+        .check_first_real_error()?;
+
+    invalidate_cached_account(account.id());
+
+    Ok(())
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn unlock(account: &Account) -> Result<()> {
+    accounts_db_for_account(account.id())
+        .await?
+        .unlock_account_query(account)
+        .await?
+        .check_first_real_error()?;
+
+    invalidate_cached_account(account.id());
+
+    Ok(())
+}
+
+// Owners can't override a lock an administrator set, e.g. during incident response
+fn validate_not_admin_locked(account: &Account) -> Result<()> {
+    if account.lock().is_some_and(|lock| lock.set_by_admin) {
+        forbidden!("The account was locked by an administrator and can only be unlocked by one");
+    }
+
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/lock",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = AccountLockResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn get_account_lock(
+    Extension(account): Extension<Account>,
+) -> Result<Json<AccountLockResponse>> {
+    Ok(Json(AccountLockResponse {
+        lock: account.lock().cloned().map(AccountLockPublic::from),
+    }))
+}
+
+// Makes the account read-only or pauses its report ingestion, e.g. while importing data or migrating agents. Locked
+// requests are rejected with a 423 response carrying the reason. Replaces any existing lock the owner set.
+#[utoipa::path(
+    put,
+    path = "/account/{account_id}/lock",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = LockAccountRequest,
+    responses((status = 200))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn lock_account(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<LockAccountRequest>,
+) -> Result<Json<()>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    validate_not_admin_locked(&account)?;
+
+    lock(&account, &req, Some(auth.principal())).await?;
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        mode = ?req.mode,
+        expires_at = ?req.expires_at,
+        "Locked account"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::AccountLocked,
+        Some(json!({
+            "mode": req.mode,
+            "reason": req.reason,
+            "expires_at": req.expires_at,
+        })),
+    )
+    .await;
+
+    Ok(Json(()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}/lock",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn unlock_account(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+) -> Result<Json<()>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    validate_not_admin_locked(&account)?;
+
+    unlock(&account).await?;
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        "Unlocked account"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::AccountUnlocked,
+        None,
+    )
+    .await;
+
+    Ok(Json(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{http::header, response::IntoResponse as _};
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::clock::{FixedClock, set_test_clock};
+
+    fn lock(locked_at: DateTime<Utc>, expires_at: Option<DateTime<Utc>>) -> AccountLock {
+        AccountLock {
+            mode: AccountLockMode::ReadOnly,
+            reason: "Incident response".to_string(),
+            expires_at,
+            locked_at,
+            locked_by: None,
+            set_by_admin: true,
+        }
+    }
+
+    fn locked_at() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn lock_without_expiry_is_active() {
+        let lock = lock(locked_at(), None);
+
+        set_test_clock(Arc::new(FixedClock(locked_at() + TimeDelta::days(3650))));
+
+        assert!(lock.is_active());
+    }
+
+    #[test]
+    fn lock_is_active_until_it_expires() {
+        let expires_at = locked_at() + TimeDelta::hours(1);
+        let lock = lock(locked_at(), Some(expires_at));
+
+        set_test_clock(Arc::new(FixedClock(
+            expires_at - TimeDelta::milliseconds(1),
+        )));
+        assert!(lock.is_active());
+
+        set_test_clock(Arc::new(FixedClock(expires_at)));
+        assert!(!lock.is_active());
+    }
+
+    #[test]
+    fn rejections_retry_after_lock_expires() {
+        let expires_at = locked_at() + TimeDelta::hours(1);
+        let lock = lock(locked_at(), Some(expires_at));
+
+        set_test_clock(Arc::new(FixedClock(expires_at - TimeDelta::seconds(90))));
+
+        let response = lock.error("reports are rejected").into_response();
+
+        assert_eq!(response.status(), StatusCode::LOCKED);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "90");
+
+        set_test_clock(Arc::new(FixedClock(
+            expires_at - TimeDelta::milliseconds(89_500),
+        )));
+
+        let response = lock.error("reports are rejected").into_response();
+
+        assert_eq!(response.headers()[header::RETRY_AFTER], "90");
+    }
+
+    #[test]
+    fn rejections_without_expiry_have_no_retry_after() {
+        let lock = lock(locked_at(), None);
+
+        set_test_clock(Arc::new(FixedClock(locked_at())));
+
+        let response = lock.error("reports are rejected").into_response();
+
+        assert!(!response.headers().contains_key(header::RETRY_AFTER));
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::HashMap;
+
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use surrealdb::Uuid;
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use archodex_error::{anyhow::Context as _, bad_request, bail, forbidden, not_found};
+
+use crate::{
+    Result,
+    account::{Account, external_account_id, resolve_account_id},
+    audit_log::{self, AuditAction},
+    auth::{DashboardAuth, invalidate_account_access},
+    clock,
+    db::{QueryCheckFirstRealError, accounts_db_for_account, query_accounts_db_shards},
+    next_binding,
+    router::RequestId,
+    surrealdb_deserializers,
+    user::User,
+};
+
+// Condition on `has_access` edges that grant access to their account. Invited members don't have access until they
+// accept their invitation. Edges without an inviter, e.g. of account creators and transfer recipients, grant access
+// immediately.
+pub(crate) const ACTIVE_ACCESS_CONDITION: &str = "invited_by IS NONE OR accepted_at IS NOT NONE";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AccountRole {
+    /// Created the account or accepted its transfer. Only the owner may manage members, transfer or delete the account,
+    /// and enable debug capture.
+    Owner,
+    /// Invited by the owner
+    Member,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AccountMemberStatus {
+    /// Invited but hasn't accepted yet, so doesn't have access
+    Invited,
+    Active,
+}
+
+// A `has_access` edge
+#[derive(Debug, Deserialize)]
+pub(crate) struct AccountMember {
+    #[serde(rename = "in")]
+    user: User,
+    #[serde(
+        rename = "out",
+        deserialize_with = "surrealdb_deserializers::string::deserialize"
+    )]
+    account: String,
+    // Legacy edges have no role, and were only created for account creators
+    role: Option<AccountRole>,
+    created_at: Option<DateTime<Utc>>,
+    invited_by: Option<User>,
+    accepted_at: Option<DateTime<Utc>>,
+}
+
+impl AccountMember {
+    fn role(&self) -> AccountRole {
+        self.role.unwrap_or(AccountRole::Owner)
+    }
+
+    fn status(&self) -> AccountMemberStatus {
+        if self.invited_by.is_some() && self.accepted_at.is_none() {
+            AccountMemberStatus::Invited
+        } else {
+            AccountMemberStatus::Active
+        }
+    }
+}
+
+// Also kept in the results of bulk operations listing the members of each account
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct AccountMemberPublic {
+    user_id: Uuid,
+    role: AccountRole,
+    status: AccountMemberStatus,
+    /// When the user was given access, or invited if they were invited
+    created_at: Option<DateTime<Utc>>,
+    invited_by_user_id: Option<Uuid>,
+    accepted_at: Option<DateTime<Utc>>,
+}
+
+impl From<AccountMember> for AccountMemberPublic {
+    fn from(record: AccountMember) -> Self {
+        Self {
+            user_id: record.user.id(),
+            role: record.role(),
+            status: record.status(),
+            created_at: record.created_at,
+            invited_by_user_id: record.invited_by.as_ref().map(User::id),
+            accepted_at: record.accepted_at,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct AccountInvitationPublic {
+    account_id: String,
+    role: AccountRole,
+    created_at: Option<DateTime<Utc>>,
+    invited_by_user_id: Option<Uuid>,
+}
+
+impl From<AccountMember> for AccountInvitationPublic {
+    fn from(record: AccountMember) -> Self {
+        let role = record.role();
+
+        Self {
+            account_id: external_account_id(&record.account).unwrap_or(record.account),
+            role,
+            created_at: record.created_at,
+            invited_by_user_id: record.invited_by.as_ref().map(User::id),
+        }
+    }
+}
+
+pub(crate) trait AccountMemberQueries<'r, C: surrealdb::Connection> {
+    fn invite_account_member_query(
+        &'r self,
+        account: &Account,
+        invitee: &User,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn list_account_members_query(&'r self, account: &Account) -> surrealdb::method::Query<'r, C>;
+    fn remove_account_member_query(
+        &'r self,
+        account: &Account,
+        member: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn list_account_invitations_query(
+        &'r self,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn accept_account_invitation_query(
+        &'r self,
+        account_id: &str,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+}
+
+impl<'r, C: surrealdb::Connection> AccountMemberQueries<'r, C> for surrealdb::Surreal<C> {
+    fn invite_account_member_query(
+        &'r self,
+        account: &Account,
+        invitee: &User,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+        let invitee_binding = next_binding();
+        let principal_binding = next_binding();
+
+        // The invitee may never have had access to an account in this shard, and `has_access` edges require the user
+        // record to exist. Inviting a user who already has access or a pending invitation violates the edge's unique
+        // index.
+        self.query(format!(
+            "
+            BEGIN;
+
+            UPSERT ${invitee_binding} RETURN NONE;
+            RELATE ONLY ${invitee_binding}->has_access->${account_binding} SET role = 'member', invited_by = ${principal_binding};
+
+            COMMIT;"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((invitee_binding, surrealdb::sql::Thing::from(invitee)))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+    }
+
+    fn list_account_members_query(&'r self, account: &Account) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+
+        self.query(format!(
+            "SELECT * FROM has_access WHERE out = ${account_binding} ORDER BY created_at"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+    }
+
+    fn remove_account_member_query(
+        &'r self,
+        account: &Account,
+        member: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+        let member_binding = next_binding();
+
+        // Owners can't be removed, as accounts must always have an owner. Ownership is handed over by transferring the
+        // account instead.
+        self.query(format!(
+            "DELETE has_access WHERE in = ${member_binding} AND out = ${account_binding} AND (role ?? 'owner') != 'owner' RETURN BEFORE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((member_binding, surrealdb::sql::Thing::from(member)))
+    }
+
+    fn list_account_invitations_query(
+        &'r self,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let principal_binding = next_binding();
+
+        self.query(format!(
+            "SELECT * FROM has_access WHERE in = ${principal_binding} AND invited_by IS NOT NONE AND accepted_at IS NONE AND out.deleted_at IS NONE"
+        ))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+    }
+
+    fn accept_account_invitation_query(
+        &'r self,
+        account_id: &str,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+        let principal_binding = next_binding();
+        let now_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE has_access SET accepted_at = ${now_binding} WHERE in = ${principal_binding} AND out = ${account_binding} AND invited_by IS NOT NONE AND accepted_at IS NONE AND out.deleted_at IS NONE RETURN AFTER"
+        ))
+        .bind((
+            account_binding,
+            surrealdb::sql::Thing::from((
+                "account",
+                surrealdb::sql::Id::String(account_id.to_string()),
+            )),
+        ))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+        .bind((now_binding, clock::now_value()))
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListAccountMembersResponse {
+    members: Vec<AccountMemberPublic>,
+}
+
+// Lists the users with access to the account, including invited users who haven't accepted yet
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/members",
+    tag = "account_members",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = ListAccountMembersResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn list_account_members(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListAccountMembersResponse>> {
+    let members = accounts_db_for_account(account.id())
+        .await?
+        .list_account_members_query(&account)
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<AccountMember>>(0)?
+        .into_iter()
+        .map(AccountMemberPublic::from)
+        .collect();
+
+    Ok(Json(ListAccountMembersResponse { members }))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct InviteAccountMemberRequest {
+    user_id: Uuid,
+}
+
+// Invites a user to the account as a member. The user gets access once they accept the invitation.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/members",
+    tag = "account_members",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = InviteAccountMemberRequest,
+    responses((status = 200, body = AccountMemberPublic))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn invite_account_member(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<InviteAccountMemberRequest>,
+) -> Result<Json<AccountMemberPublic>> {
+    let principal = auth.principal();
+
+    if req.user_id == principal.id() {
+        bad_request!("Cannot invite yourself to an account");
+    }
+
+    auth.validate_account_owner(account.id()).await?;
+    principal.ensure_user_record_exists(account.id()).await?;
+
+    let account_member = accounts_db_for_account(account.id())
+        .await?
+        .invite_account_member_query(&account, &User::new(req.user_id), principal)
+        .await?
+        .check_first_real_error()?
+        .take::<Option<AccountMember>>(1)?
+        .context("Invite account member query should return a has_access edge")?;
+
+    info!(
+        account_id = account.id(),
+        invited_user_id = %req.user_id,
+        invited_by_user_id = %principal.id(),
+        "Invited account member"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::MemberInvited,
+        Some(json!({ "user_id": req.user_id })),
+    )
+    .await;
+
+    Ok(Json(AccountMemberPublic::from(account_member)))
+}
+
+// Removes a member's access to the account, or cancels their invitation. Owners may remove any member, and members may
+// remove themselves.
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}/member/{user_id}",
+    tag = "account_members",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("user_id" = String, Path)),
+    responses((status = 200))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn remove_account_member(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    let Some(user_id) = params.get("user_id") else {
+        bail!("Missing user_id");
+    };
+
+    let Ok(user_id) = Uuid::parse_str(user_id) else {
+        bad_request!("Invalid user ID");
+    };
+
+    let principal = auth.principal();
+
+    if user_id != principal.id() {
+        auth.validate_account_owner(account.id()).await?;
+    }
+
+    let removed_members = accounts_db_for_account(account.id())
+        .await?
+        .remove_account_member_query(&account, &User::new(user_id))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<AccountMember>>(0)?;
+
+    if removed_members.is_empty() {
+        if user_id == principal.id() {
+            forbidden!("The account owner cannot leave the account, transfer it instead");
+        }
+
+        not_found!("Account member not found");
+    }
+
+    invalidate_account_access(account.id());
+
+    info!(
+        account_id = account.id(),
+        removed_user_id = %user_id,
+        removed_by_user_id = %principal.id(),
+        "Removed account member"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::MemberRemoved,
+        Some(json!({ "user_id": user_id })),
+    )
+    .await;
+
+    Ok(Json(()))
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListAccountInvitationsResponse {
+    account_invitations: Vec<AccountInvitationPublic>,
+}
+
+// Lists pending invitations of the current user to accounts so they can be accepted
+#[utoipa::path(
+    get,
+    path = "/account_invitations",
+    tag = "account_members",
+    security(("dashboard" = [])),
+    responses((status = 200, body = ListAccountInvitationsResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn list_account_invitations(
+    Extension(auth): Extension<DashboardAuth>,
+) -> Result<Json<ListAccountInvitationsResponse>> {
+    let principal = auth.principal();
+
+    let account_invitations = query_accounts_db_shards(|db| async move {
+        Ok(db
+            .list_account_invitations_query(principal)
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<AccountMember>>(0)?)
+    })
+    .await?
+    .into_iter()
+    .map(AccountInvitationPublic::from)
+    .collect();
+
+    Ok(Json(ListAccountInvitationsResponse {
+        account_invitations,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/account_invitation/{account_id}/accept",
+    tag = "account_members",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = AccountMemberPublic))
+)]
+#[instrument(err, skip(auth))]
+pub(crate) async fn accept_account_invitation(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(request_id): Extension<RequestId>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<AccountMemberPublic>> {
+    let Some(account_id) = params.get("account_id") else {
+        bail!("Missing account_id");
+    };
+
+    let account_id = resolve_account_id(account_id).await?;
+    let principal = auth.principal();
+
+    let Some(account_member) = accounts_db_for_account(&account_id)
+        .await?
+        .accept_account_invitation_query(&account_id, principal)
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<AccountMember>>(0)?
+        .into_iter()
+        .next()
+    else {
+        not_found!("Account invitation not found");
+    };
+
+    invalidate_account_access(&account_id);
+
+    info!(
+        account_id,
+        user_id = %principal.id(),
+        invited_by_user_id = ?account_member.invited_by.as_ref().map(User::id),
+        "Accepted account invitation"
+    );
+
+    audit_log::record(
+        &account_id,
+        &auth,
+        request_id,
+        AuditAction::MemberInvitationAccepted,
+        None,
+    )
+    .await;
+
+    Ok(Json(AccountMemberPublic::from(account_member)))
+}
@@ -0,0 +1,343 @@
+use std::collections::{BTreeSet, HashSet};
+
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use surrealdb::Uuid;
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use archodex_error::{bad_request, not_found};
+
+use crate::{
+    Result,
+    account::{
+        Account, AccountQueries as _, get_account, invalidate_cached_account, resolve_account_id,
+    },
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError as _, accounts_db_for_account},
+    enrichment::EnricherKind,
+    event_destination::{EventDestination, EventDestinationPublic, EventDestinationQueries as _},
+    router::RequestId,
+};
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PromoteConfigRequest {
+    /// Account to copy configuration from, e.g. a staging account. The principal must own both accounts.
+    source_account_id: String,
+    /// Returns the changes promoting would make without applying any of them
+    #[serde(default)]
+    dry_run: bool,
+    /// Keys of the changes to apply, as returned by a dry run. All changes are applied if not set.
+    changes: Option<Vec<String>>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum ConfigPromotion {
+    SetEnrichers {
+        old_enabled: BTreeSet<EnricherKind>,
+        new_enabled: BTreeSet<EnricherKind>,
+    },
+    SetEventRetention {
+        old_retention_days: Option<u32>,
+        new_retention_days: Option<u32>,
+    },
+    SetResourceRetention {
+        old_retention_days: Option<u32>,
+        new_retention_days: Option<u32>,
+    },
+    // Destinations are copied with their credentials. Destinations of the target account are never changed or deleted.
+    CreateEventDestination {
+        source_event_destination: EventDestinationPublic,
+    },
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ConfigPromotionChange {
+    /// Selects the change in `changes` of a promotion request, e.g. `enrichers` or `event_destination:<source ID>`
+    key: String,
+    change: ConfigPromotion,
+    applied: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct PromoteConfigResponse {
+    changes: Vec<ConfigPromotionChange>,
+}
+
+// Destinations are the same if they deliver the same events to the same target, regardless of their descriptions and
+// credentials
+fn same_event_destination(a: &EventDestination, b: &EventDestination) -> bool {
+    fn event_types(destination: &EventDestination) -> Option<BTreeSet<&String>> {
+        destination
+            .event_types()
+            .map(|event_types| event_types.iter().collect())
+    }
+
+    a.target() == b.target() && event_types(a) == event_types(b)
+}
+
+// Lists the changes that would make the target account's configuration match the source account's, in the order they
+// are applied
+fn diff(
+    source: &Account,
+    target: &Account,
+    source_event_destinations: Vec<EventDestination>,
+    target_event_destinations: &[EventDestination],
+) -> Vec<(String, ConfigPromotion, Option<EventDestination>)> {
+    let mut changes = vec![];
+
+    if source.enrichers() != target.enrichers() {
+        changes.push((
+            "enrichers".to_string(),
+            ConfigPromotion::SetEnrichers {
+                old_enabled: target.enrichers().clone(),
+                new_enabled: source.enrichers().clone(),
+            },
+            None,
+        ));
+    }
+
+    if source.event_retention_days() != target.event_retention_days() {
+        changes.push((
+            "event_retention".to_string(),
+            ConfigPromotion::SetEventRetention {
+                old_retention_days: target.event_retention_days(),
+                new_retention_days: source.event_retention_days(),
+            },
+            None,
+        ));
+    }
+
+    if source.resource_retention_days() != target.resource_retention_days() {
+        changes.push((
+            "resource_retention".to_string(),
+            ConfigPromotion::SetResourceRetention {
+                old_retention_days: target.resource_retention_days(),
+                new_retention_days: source.resource_retention_days(),
+            },
+            None,
+        ));
+    }
+
+    for source_event_destination in source_event_destinations {
+        if target_event_destinations
+            .iter()
+            .any(|target_event_destination| {
+                same_event_destination(&source_event_destination, target_event_destination)
+            })
+        {
+            continue;
+        }
+
+        changes.push((
+            format!("event_destination:{}", source_event_destination.id()),
+            ConfigPromotion::CreateEventDestination {
+                source_event_destination: EventDestinationPublic::from(
+                    source_event_destination.clone(),
+                ),
+            },
+            Some(source_event_destination),
+        ));
+    }
+
+    changes
+}
+
+async fn list_event_destinations(account: &Account) -> Result<Vec<EventDestination>> {
+    Ok(account
+        .resources_db()
+        .await?
+        .list_event_destinations_query()
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<EventDestination>>(0)?)
+}
+
+async fn apply(
+    auth: &DashboardAuth,
+    target: &Account,
+    change: &ConfigPromotion,
+    source_event_destination: Option<&EventDestination>,
+) -> Result<()> {
+    let accounts_db = || accounts_db_for_account(target.id());
+
+    match change {
+        ConfigPromotion::SetEnrichers { new_enabled, .. } => {
+            accounts_db()
+                .await?
+                .set_account_enrichers_query(target, new_enabled, auth.principal())
+                .await?
+                .check_first_real_error()?;
+        }
+        ConfigPromotion::SetEventRetention {
+            new_retention_days, ..
+        } => {
+            accounts_db()
+                .await?
+                .set_account_event_retention_query(target, *new_retention_days, auth.principal())
+                .await?
+                .check_first_real_error()?;
+        }
+        ConfigPromotion::SetResourceRetention {
+            new_retention_days, ..
+        } => {
+            accounts_db()
+                .await?
+                .set_account_resource_retention_query(target, *new_retention_days, auth.principal())
+                .await?
+                .check_first_real_error()?;
+        }
+        ConfigPromotion::CreateEventDestination { .. } => {
+            let source_event_destination = source_event_destination
+                .expect("Event destination changes should have their source event destination");
+
+            let id = Uuid::now_v7();
+
+            let encrypted_credentials = match source_event_destination.credentials().await? {
+                Some(credentials) => {
+                    Some(EventDestination::encrypt_credentials(id, &credentials).await?)
+                }
+                None => None,
+            };
+
+            target
+                .resources_db()
+                .await?
+                .create_event_destination_query(
+                    id,
+                    source_event_destination.description().map(str::to_owned),
+                    source_event_destination.target(),
+                    source_event_destination
+                        .event_types()
+                        .map(<[String]>::to_vec),
+                    encrypted_credentials,
+                    auth.principal(),
+                )?
+                .await?
+                .check_first_real_error()?;
+        }
+    }
+
+    Ok(())
+}
+
+// Copies the configuration of another account into this one, e.g. to promote the configuration of a staging account to
+// a production account. Enrichers and retention periods are set to the source account's, and event destinations of the
+// source account without an equivalent destination in this account are created with the same credentials. A dry run
+// lists the changes with keys that select which of them a following request applies. Changes are applied one at a time,
+// so a failed promotion may have applied some of them already.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/promote",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = PromoteConfigRequest,
+    responses((status = 200, body = PromoteConfigResponse))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn promote_config(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<PromoteConfigRequest>,
+) -> Result<Json<PromoteConfigResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    let source_account_id = resolve_account_id(&req.source_account_id).await?;
+
+    if source_account_id == account.id() {
+        bad_request!("Configuration can't be promoted from an account to itself");
+    }
+
+    auth.validate_account_access(&source_account_id).await?;
+    auth.validate_account_owner(&source_account_id).await?;
+
+    let Some(source) = get_account(&source_account_id)
+        .await?
+        .filter(|source| !source.is_deleted())
+    else {
+        not_found!("Source account not found");
+    };
+
+    let pending = diff(
+        &source,
+        &account,
+        list_event_destinations(&source).await?,
+        &list_event_destinations(&account).await?,
+    );
+
+    let selected = match &req.changes {
+        Some(keys) => {
+            let pending_keys = pending
+                .iter()
+                .map(|(key, _, _)| key.as_str())
+                .collect::<HashSet<_>>();
+
+            if let Some(key) = keys.iter().find(|key| !pending_keys.contains(key.as_str())) {
+                bad_request!(
+                    "Change {key:?} is not pending, run a dry run to list the pending changes"
+                );
+            }
+
+            keys.iter().map(String::as_str).collect::<HashSet<_>>()
+        }
+        None => pending.iter().map(|(key, _, _)| key.as_str()).collect(),
+    };
+
+    let mut applied = vec![];
+
+    for (key, change, source_event_destination) in &pending {
+        let apply_change = !req.dry_run && selected.contains(key.as_str());
+
+        if apply_change {
+            apply(&auth, &account, change, source_event_destination.as_ref()).await?;
+        }
+
+        applied.push(apply_change);
+    }
+
+    let applied_keys = pending
+        .iter()
+        .zip(&applied)
+        .filter(|(_, applied)| **applied)
+        .map(|((key, _, _), _)| key.clone())
+        .collect::<Vec<_>>();
+
+    if !applied_keys.is_empty() {
+        invalidate_cached_account(account.id());
+
+        info!(
+            account_id = account.id(),
+            source_account_id,
+            user_id = %auth.principal().id(),
+            changes = ?applied_keys,
+            "Promoted account configuration"
+        );
+
+        audit_log::record(
+            account.id(),
+            &auth,
+            request_id,
+            AuditAction::ConfigPromoted,
+            Some(json!({ "source_account_id": source_account_id, "changes": applied_keys })),
+        )
+        .await;
+    }
+
+    Ok(Json(PromoteConfigResponse {
+        changes: pending
+            .into_iter()
+            .zip(applied)
+            .map(|((key, change, _), applied)| ConfigPromotionChange {
+                key,
+                change,
+                applied,
+            })
+            .collect(),
+    }))
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    Uuid,
+    sql::statements::{BeginStatement, CommitStatement},
+};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use archodex_error::{anyhow::Context as _, bad_request, bail, not_found};
+
+use crate::{
+    Result,
+    account::{Account, external_account_id},
+    auth::{DashboardAuth, invalidate_account_access},
+    clock,
+    db::{QueryCheckFirstRealError, accounts_db_for_account, query_accounts_db_shards},
+    next_binding, surrealdb_deserializers,
+    user::User,
+};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AccountTransfer {
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    id: Uuid,
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    account: String,
+    recipient: User,
+    created_at: Option<DateTime<Utc>>,
+    created_by: User,
+    accepted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct AccountTransferPublic {
+    id: Uuid,
+    account_id: String,
+    recipient_user_id: Uuid,
+    created_at: Option<DateTime<Utc>>,
+    created_by_user_id: Uuid,
+    accepted_at: Option<DateTime<Utc>>,
+}
+
+impl From<AccountTransfer> for AccountTransferPublic {
+    fn from(record: AccountTransfer) -> Self {
+        Self {
+            id: record.id,
+            account_id: external_account_id(&record.account).unwrap_or(record.account),
+            recipient_user_id: record.recipient.id(),
+            created_at: record.created_at,
+            created_by_user_id: record.created_by.id(),
+            accepted_at: record.accepted_at,
+        }
+    }
+}
+
+pub(crate) trait AccountTransferQueries<'r, C: surrealdb::Connection> {
+    fn create_account_transfer_query(
+        &'r self,
+        id: Uuid,
+        account: &Account,
+        recipient: &User,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn cancel_account_transfers_query(
+        &'r self,
+        account: &Account,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn list_incoming_account_transfers_query(
+        &'r self,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn accept_account_transfer_query(
+        &'r self,
+        id: Uuid,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+}
+
+impl<'r, C: surrealdb::Connection> AccountTransferQueries<'r, C> for surrealdb::Surreal<C> {
+    fn create_account_transfer_query(
+        &'r self,
+        id: Uuid,
+        account: &Account,
+        recipient: &User,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let transfer_binding = next_binding();
+        let account_binding = next_binding();
+        let recipient_binding = next_binding();
+        let principal_binding = next_binding();
+        let now_binding = next_binding();
+
+        // Only one transfer may be pending for an account, so initiating a new transfer cancels any earlier ones
+        self.query(BeginStatement::default())
+            .query(format!(
+                "UPDATE account_transfer SET cancelled_at = ${now_binding}, cancelled_by = ${principal_binding} WHERE account = ${account_binding} AND accepted_at IS NONE AND cancelled_at IS NONE RETURN NONE"
+            ))
+            .query(format!(
+                "CREATE ${transfer_binding} CONTENT {{ account: ${account_binding}, recipient: ${recipient_binding}, created_by: ${principal_binding} }}"
+            ))
+            .query(CommitStatement::default())
+            .bind((
+                transfer_binding,
+                surrealdb::sql::Thing::from((
+                    "account_transfer",
+                    surrealdb::sql::Id::Uuid(surrealdb::sql::Uuid::from(id)),
+                )),
+            ))
+            .bind((account_binding, surrealdb::sql::Thing::from(account)))
+            .bind((recipient_binding, surrealdb::sql::Thing::from(recipient)))
+            .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+            .bind((now_binding, clock::now_value()))
+    }
+
+    fn cancel_account_transfers_query(
+        &'r self,
+        account: &Account,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+        let principal_binding = next_binding();
+        let now_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE account_transfer SET cancelled_at = ${now_binding}, cancelled_by = ${principal_binding} WHERE account = ${account_binding} AND accepted_at IS NONE AND cancelled_at IS NONE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+        .bind((now_binding, clock::now_value()))
+    }
+
+    fn list_incoming_account_transfers_query(
+        &'r self,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let principal_binding = next_binding();
+
+        self.query(format!(
+            "SELECT * FROM account_transfer WHERE recipient = ${principal_binding} AND accepted_at IS NONE AND cancelled_at IS NONE AND account.deleted_at IS NONE"
+        ))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+    }
+
+    fn accept_account_transfer_query(
+        &'r self,
+        id: Uuid,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let transfer_binding = next_binding();
+        let principal_binding = next_binding();
+        let now_binding = next_binding();
+
+        // The previous owner's access is replaced by the recipient's in the same transaction that marks the transfer
+        // accepted, so the account always has exactly one owner.
+        self.query(format!(
+            "
+            BEGIN;
+
+            LET $transfer = (UPDATE ${transfer_binding} SET accepted_at = ${now_binding} WHERE recipient = ${principal_binding} AND accepted_at IS NONE AND cancelled_at IS NONE AND account.deleted_at IS NONE RETURN AFTER)[0];
+
+            IF $transfer != NONE {{
+                // The recipient may never have had access to an account in this shard, and `has_access` edges require
+                // the user record to exist
+                UPSERT ${principal_binding} RETURN NONE;
+                DELETE has_access WHERE out = $transfer.account AND (in = $transfer.created_by OR in = ${principal_binding});
+                LET $account = $transfer.account;
+                RELATE ${principal_binding}->has_access->$account SET role = 'owner';
+            }};
+
+            RETURN $transfer;
+
+            COMMIT;"
+        ))
+        .bind((
+            transfer_binding,
+            surrealdb::sql::Thing::from((
+                "account_transfer",
+                surrealdb::sql::Id::Uuid(surrealdb::sql::Uuid::from(id)),
+            )),
+        ))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+        .bind((now_binding, clock::now_value()))
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct InitiateAccountTransferRequest {
+    recipient_user_id: Uuid,
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/transfer",
+    tag = "account_transfers",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = InitiateAccountTransferRequest,
+    responses((status = 200, body = AccountTransferPublic))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn initiate_account_transfer(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<InitiateAccountTransferRequest>,
+) -> Result<Json<AccountTransferPublic>> {
+    let principal = auth.principal();
+
+    if req.recipient_user_id == principal.id() {
+        bad_request!("Cannot transfer an account to its current owner");
+    }
+
+    auth.validate_account_owner(account.id()).await?;
+    principal.ensure_user_record_exists(account.id()).await?;
+
+    let account_transfer = accounts_db_for_account(account.id())
+        .await?
+        .create_account_transfer_query(
+            Uuid::now_v7(),
+            &account,
+            &User::new(req.recipient_user_id),
+            principal,
+        )
+        .await?
+        .check_first_real_error()?
+        .take::<Option<AccountTransfer>>(1)?
+        .context("Create account transfer query should return an account transfer instance")?;
+
+    info!(
+        account_id = account.id(),
+        account_transfer_id = %account_transfer.id,
+        from_user_id = %principal.id(),
+        to_user_id = %req.recipient_user_id,
+        "Initiated account transfer"
+    );
+
+    Ok(Json(AccountTransferPublic::from(account_transfer)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}/transfer",
+    tag = "account_transfers",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn cancel_account_transfer(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<()>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    let cancelled_transfers = accounts_db_for_account(account.id())
+        .await?
+        .cancel_account_transfers_query(&account, auth.principal())
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<AccountTransfer>>(0)?;
+
+    if cancelled_transfers.is_empty() {
+        not_found!("No pending account transfer");
+    }
+
+    for account_transfer in cancelled_transfers {
+        info!(
+            account_id = account.id(),
+            account_transfer_id = %account_transfer.id,
+            cancelled_by_user_id = %auth.principal().id(),
+            "Cancelled account transfer"
+        );
+    }
+
+    Ok(Json(()))
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListAccountTransfersResponse {
+    account_transfers: Vec<AccountTransferPublic>,
+}
+
+// Lists pending transfers of accounts to the current user so they can be accepted
+#[utoipa::path(
+    get,
+    path = "/account_transfers",
+    tag = "account_transfers",
+    security(("dashboard" = [])),
+    responses((status = 200, body = ListAccountTransfersResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn list_incoming_account_transfers(
+    Extension(auth): Extension<DashboardAuth>,
+) -> Result<Json<ListAccountTransfersResponse>> {
+    let principal = auth.principal();
+
+    let account_transfers = query_accounts_db_shards(|db| async move {
+        Ok(db
+            .list_incoming_account_transfers_query(principal)
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<AccountTransfer>>(0)?)
+    })
+    .await?
+    .into_iter()
+    .map(AccountTransferPublic::from)
+    .collect();
+
+    Ok(Json(ListAccountTransfersResponse { account_transfers }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/account_transfer/{account_transfer_id}/accept",
+    tag = "account_transfers",
+    security(("dashboard" = [])),
+    params(("account_transfer_id" = String, Path)),
+    responses((status = 200, body = AccountTransferPublic))
+)]
+#[instrument(err, skip(auth))]
+pub(crate) async fn accept_account_transfer(
+    Extension(auth): Extension<DashboardAuth>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<AccountTransferPublic>> {
+    let Some(account_transfer_id) = params.get("account_transfer_id") else {
+        bail!("Missing account_transfer_id");
+    };
+
+    let Ok(account_transfer_id) = Uuid::parse_str(account_transfer_id) else {
+        bad_request!("Invalid account transfer ID");
+    };
+
+    let principal = auth.principal();
+
+    // Transfer IDs don't reveal the account, so the transfer is accepted in whichever shard holds it
+    let Some(account_transfer) = query_accounts_db_shards(|db| async move {
+        Ok(db
+            .accept_account_transfer_query(account_transfer_id, principal)
+            .await?
+            .check_first_real_error()?
+            .take::<Option<AccountTransfer>>(0)?)
+    })
+    .await?
+    .into_iter()
+    .next() else {
+        not_found!("Account transfer not found");
+    };
+
+    invalidate_account_access(&account_transfer.account);
+
+    info!(
+        account_id = account_transfer.account,
+        %account_transfer_id,
+        from_user_id = %account_transfer.created_by.id(),
+        to_user_id = %principal.id(),
+        "Transferred account ownership"
+    );
+
+    #[cfg(feature = "archodex-com")]
+    crate::eventbridge::publish(vec![
+        crate::eventbridge::PlatformEvent::AccountTransferred {
+            account_id: account_transfer.account.clone(),
+            from_user_id: account_transfer.created_by.id(),
+            to_user_id: principal.id(),
+        },
+    ]);
+
+    Ok(Json(AccountTransferPublic::from(account_transfer)))
+}
@@ -1,36 +1,44 @@
 use axum::{Extension, Json};
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
+use utoipa::ToSchema;
 
 use archodex_error::anyhow::Context as _;
 
 use crate::{
     Result,
-    account::{Account, AccountPublic, AccountQueries},
-    auth::DashboardAuth,
-    db::{QueryCheckFirstRealError, accounts_db},
+    account::{Account, AccountPublic, AccountQueries, invalidate_cached_account},
+    audit_log::{self, AuditAction},
+    auth::{DashboardAuth, invalidate_account_access},
+    db::{QueryCheckFirstRealError, accounts_db_for_account},
+    router::RequestId,
 };
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub(crate) struct ListAccountsResponse {
     accounts: Vec<AccountPublic>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/accounts",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    responses((status = 200, body = ListAccountsResponse))
+)]
 pub(crate) async fn list_accounts(
     Extension(auth): Extension<DashboardAuth>,
 ) -> Result<Json<ListAccountsResponse>> {
-    let accounts = auth
-        .principal()
-        .list_accounts()
-        .await?
-        .into_iter()
-        .map(AccountPublic::from)
-        .collect();
+    let accounts = auth.principal().list_accounts().await?;
+
+    auth.record_account_access(accounts.iter().map(Account::id));
+
+    let accounts = accounts.into_iter().map(AccountPublic::from).collect();
 
     Ok(Json(ListAccountsResponse { accounts }))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub(super) struct CreateAccountRequest {
     #[cfg(not(feature = "archodex-com"))]
@@ -39,19 +47,28 @@ pub(super) struct CreateAccountRequest {
     endpoint: Option<String>,
 }
 
+#[utoipa::path(
+    post,
+    path = "/accounts",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    request_body = CreateAccountRequest,
+    responses((status = 200, body = AccountPublic))
+)]
 #[instrument(err, skip(auth))]
 pub(crate) async fn create_account(
     Extension(auth): Extension<DashboardAuth>,
+    Extension(request_id): Extension<RequestId>,
     Json(req): Json<CreateAccountRequest>,
 ) -> Result<Json<AccountPublic>> {
     #[cfg(not(feature = "archodex-com"))]
     {
-        create_local_account(auth, req).await
+        create_local_account(auth, request_id, req).await
     }
 
     #[cfg(feature = "archodex-com")]
     {
-        create_archodex_com_account(auth, req).await
+        create_archodex_com_account(auth, request_id, req).await
     }
 }
 
@@ -59,18 +76,20 @@ pub(crate) async fn create_account(
 #[instrument(err, skip_all)]
 pub(crate) async fn create_local_account(
     auth: DashboardAuth,
+    request_id: RequestId,
     req: CreateAccountRequest,
 ) -> Result<Json<AccountPublic>> {
     verify_no_local_accounts_exist().await?;
 
     let principal = auth.principal();
-    principal.ensure_user_record_exists().await?;
 
     let account = Account::new(req.account_id, principal.clone())
         .await
         .context("Failed to create new account")?;
 
-    accounts_db()
+    principal.ensure_user_record_exists(account.id()).await?;
+
+    accounts_db_for_account(account.id())
         .await?
         .create_account_query(&account, principal)
         .await
@@ -78,6 +97,17 @@ pub(crate) async fn create_local_account(
         .check_first_real_error()
         .context("Failed to create new account record in accounts database")?;
 
+    invalidate_account_access(account.id());
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::AccountCreated,
+        None,
+    )
+    .await;
+
     Ok(Json(account.into()))
 }
 
@@ -86,7 +116,8 @@ pub(crate) async fn create_local_account(
 async fn verify_no_local_accounts_exist() -> Result<()> {
     use archodex_error::{anyhow::anyhow, conflict};
 
-    let local_account_exists: bool = accounts_db()
+    // Self-hosted backends have a single shard
+    let local_account_exists: bool = crate::db::primary_accounts_db()
         .await?
         .query("RETURN COUNT(SELECT id FROM account WHERE deleted_at IS NONE LIMIT 1) > 0")
         .await?
@@ -105,6 +136,7 @@ async fn verify_no_local_accounts_exist() -> Result<()> {
 #[cfg(feature = "archodex-com")]
 pub(crate) async fn create_archodex_com_account(
     auth: DashboardAuth,
+    request_id: RequestId,
     req: CreateAccountRequest,
 ) -> Result<Json<AccountPublic>> {
     use crate::env::Env;
@@ -115,35 +147,77 @@ pub(crate) async fn create_archodex_com_account(
         Env::endpoint().to_string()
     };
 
-    let accounts_db = accounts_db().await?;
-
     let principal = auth.principal();
-    principal.ensure_user_record_exists().await?;
 
     let next_account_id = principal.next_account_id().await?;
 
+    principal
+        .ensure_user_record_exists(&next_account_id)
+        .await?;
+
     let account = Account::new(endpoint, next_account_id, principal.clone())
         .await
         .context("Failed to create new account")?;
 
-    accounts_db
+    let created = accounts_db_for_account(account.id())
+        .await?
         .create_account_query(&account, principal)
         .await
-        .context("Failed to commit account creation transaction")?
-        .check_first_real_error()
-        .context("Failed to create new account record in accounts database")?;
+        .context("Failed to commit account creation transaction")
+        .and_then(|res| {
+            res.check_first_real_error()
+                .context("Failed to create new account record in accounts database")
+        });
+
+    // The service database is only reachable through the account record, so it would be orphaned without one
+    if let Err(err) = created {
+        if let Some(service_data_surrealdb_url) = account.service_data_surrealdb_url() {
+            crate::provisioning::discard_service_database(account.id(), service_data_surrealdb_url)
+                .await;
+        }
+        return Err(err.into());
+    }
+
+    invalidate_account_access(account.id());
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::AccountCreated,
+        None,
+    )
+    .await;
+
+    crate::eventbridge::publish(vec![crate::eventbridge::PlatformEvent::AccountCreated {
+        account_id: account.id().to_owned(),
+        created_by_user_id: auth.principal().id(),
+    }]);
 
     Ok(Json(account.into()))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200))
+)]
 #[instrument(err, skip_all)]
 pub(crate) async fn delete_account(
     Extension(auth): Extension<DashboardAuth>,
     Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
 ) -> Result<()> {
-    auth.principal().ensure_user_record_exists().await?;
+    auth.validate_account_owner(account.id()).await?;
 
-    let db = accounts_db().await?;
+    auth.principal()
+        .ensure_user_record_exists(account.id())
+        .await?;
+
+    let db = accounts_db_for_account(account.id()).await?;
 
     #[cfg(not(feature = "archodex-com"))]
     {
@@ -169,5 +243,23 @@ pub(crate) async fn delete_account(
         .check_first_real_error()
         .context("Failed to delete account record in accounts database")?;
 
+    invalidate_account_access(account.id());
+    invalidate_cached_account(account.id());
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::AccountDeleted,
+        None,
+    )
+    .await;
+
+    #[cfg(feature = "archodex-com")]
+    crate::eventbridge::publish(vec![crate::eventbridge::PlatformEvent::AccountDeleted {
+        account_id: account.id().to_owned(),
+        deleted_by_user_id: auth.principal().id(),
+    }]);
+
     Ok(())
 }
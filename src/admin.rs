@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Request},
+    middleware::Next,
+    response::Response,
+};
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument as _, error_span, info, instrument, warn};
+
+use archodex_error::{
+    anyhow::{Context as _, anyhow},
+    not_found, unauthorized,
+};
+
+use crate::{
+    Result,
+    account::{self, AccountAdmin, resolve_account_id},
+    account_lock::{self, LockAccountRequest},
+    env::Env,
+    maintenance::{self, MaintenanceReport},
+    read_only,
+    reconciliation::{self, ReconcileRequest, ReconciliationReport},
+};
+
+// Carries the headers of a SigV4-signed STS GetCallerIdentity request, as a base64 encoded JSON object. The backend
+// forwards the signed request to STS, which verifies the signature and reports the caller's identity. This is the same
+// scheme HashiCorp Vault uses for IAM authentication, and lets internal tooling authenticate with its IAM role instead
+// of user credentials.
+const IAM_REQUEST_HEADER: &str = "x-archodex-iam-request";
+
+// Must be included in the signed headers with the value of `ARCHODEX_DOMAIN`, so that signed requests made for other
+// services that use the same scheme can't be replayed against this backend.
+const SERVER_ID_HEADER: &str = "x-archodex-server-id";
+
+const STS_URL: &str = "https://sts.amazonaws.com/";
+const STS_CONTENT_TYPE: &str = "application/x-www-form-urlencoded; charset=utf-8";
+const GET_CALLER_IDENTITY_BODY: &str = "Action=GetCallerIdentity&Version=2011-06-15";
+
+// Headers of the signed request that are forwarded to STS. The host, content type, and body are fixed by the backend so
+// the signature only verifies for a GetCallerIdentity call.
+const FORWARDED_HEADERS: [&str; 4] = [
+    "authorization",
+    "x-amz-date",
+    "x-amz-security-token",
+    SERVER_ID_HEADER,
+];
+
+#[derive(Clone, Debug)]
+pub(crate) struct AdminAuth {
+    caller_arn: String,
+}
+
+impl AdminAuth {
+    pub(crate) async fn authenticate(mut req: Request, next: Next) -> Result<Response> {
+        let iam_request = req.headers().get(IAM_REQUEST_HEADER).cloned();
+        let admin_auth = async move {
+            let Some(iam_request) = iam_request else {
+                warn!("Missing {IAM_REQUEST_HEADER} header");
+                unauthorized!();
+            };
+
+            let Some(signed_headers) = iam_request
+                .to_str()
+                .ok()
+                .and_then(|value| BASE64_STANDARD.decode(value).ok())
+                .and_then(|json| serde_json::from_slice::<HashMap<String, String>>(&json).ok())
+            else {
+                warn!("Failed to parse {IAM_REQUEST_HEADER} header");
+                unauthorized!();
+            };
+
+            let signed_headers = signed_headers
+                .into_iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value))
+                .collect::<HashMap<_, _>>();
+
+            if signed_headers.get(SERVER_ID_HEADER).map(String::as_str)
+                != Some(Env::archodex_domain())
+            {
+                warn!("Signed IAM request has a missing or mismatched server ID");
+                unauthorized!();
+            }
+
+            // STS only verifies the headers listed in the signature, so an unsigned server ID header could be added to a
+            // request signed for another service
+            if !signed_headers
+                .get("authorization")
+                .is_some_and(|authorization| signs_header(authorization, SERVER_ID_HEADER))
+            {
+                warn!("Signed IAM request doesn't sign the server ID header");
+                unauthorized!();
+            }
+
+            let Some(caller_arn) = get_caller_identity(&signed_headers).await? else {
+                unauthorized!();
+            };
+
+            if !Env::admin_iam_role_arns()
+                .iter()
+                .any(|role_arn| caller_is_role(&caller_arn, role_arn))
+            {
+                warn!(caller_arn, "IAM caller is not an admin role");
+                unauthorized!();
+            }
+
+            Result::Ok(AdminAuth { caller_arn })
+        }
+        .instrument(error_span!("authenticate"))
+        .await?;
+
+        tracing::Span::current().record("auth", tracing::field::debug(&admin_auth));
+
+        req.extensions_mut().insert(admin_auth);
+
+        Ok(next.run(req).await)
+    }
+}
+
+// Checks whether a SigV4 `authorization` header value lists `header` in its signed headers, e.g.
+// `AWS4-HMAC-SHA256 Credential=..., SignedHeaders=host;x-amz-date;x-archodex-server-id, Signature=...`
+fn signs_header(authorization: &str, header: &str) -> bool {
+    let Some(("AWS4-HMAC-SHA256", components)) = authorization.split_once(' ') else {
+        return false;
+    };
+
+    components
+        .split(',')
+        .filter_map(|component| component.trim().strip_prefix("SignedHeaders="))
+        .flat_map(|signed_headers| signed_headers.split(';'))
+        .any(|signed_header| signed_header.eq_ignore_ascii_case(header))
+}
+
+// Forwards a signed GetCallerIdentity request to STS and returns the caller's ARN, or `None` if STS rejects the request
+#[instrument(err, skip_all)]
+async fn get_caller_identity(signed_headers: &HashMap<String, String>) -> Result<Option<String>> {
+    let request = FORWARDED_HEADERS.iter().fold(
+        reqwest::Client::new()
+            .post(STS_URL)
+            .header(reqwest::header::CONTENT_TYPE, STS_CONTENT_TYPE)
+            .body(GET_CALLER_IDENTITY_BODY),
+        |request, name| match signed_headers.get(*name) {
+            Some(value) => request.header(*name, value),
+            None => request,
+        },
+    );
+
+    let response = request
+        .send()
+        .await
+        .context("Failed to send GetCallerIdentity request to STS")?;
+
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .context("Failed to read GetCallerIdentity response from STS")?;
+
+    if status.is_client_error() {
+        warn!(%status, body, "STS rejected signed GetCallerIdentity request");
+        return Ok(None);
+    }
+
+    if !status.is_success() {
+        return Err(
+            anyhow!("STS GetCallerIdentity request failed with status {status}: {body}").into(),
+        );
+    }
+
+    let arn = body
+        .split_once("<Arn>")
+        .and_then(|(_, rest)| rest.split_once("</Arn>"))
+        .map(|(arn, _)| arn.to_string())
+        .context("GetCallerIdentity response from STS is missing the caller ARN")?;
+
+    Ok(Some(arn))
+}
+
+// Checks whether an STS caller ARN is a session of an IAM role. Role sessions have ARNs like
+// `arn:aws:sts::123456789012:assumed-role/RoleName/SessionName`, while role ARNs look like
+// `arn:aws:iam::123456789012:role/optional/path/RoleName`.
+fn caller_is_role(caller_arn: &str, role_arn: &str) -> bool {
+    let Some((caller_account, caller_resource)) = caller_arn
+        .strip_prefix("arn:aws:sts::")
+        .and_then(|rest| rest.split_once(':'))
+    else {
+        return false;
+    };
+
+    let Some(caller_role_name) = caller_resource
+        .strip_prefix("assumed-role/")
+        .and_then(|rest| rest.split('/').next())
+    else {
+        return false;
+    };
+
+    let Some((role_account, role_resource)) = role_arn
+        .strip_prefix("arn:aws:iam::")
+        .and_then(|rest| rest.split_once(':'))
+    else {
+        return false;
+    };
+
+    let Some(role_name) = role_resource
+        .strip_prefix("role/")
+        .and_then(|rest| rest.rsplit('/').next())
+    else {
+        return false;
+    };
+
+    caller_account == role_account && caller_role_name == role_name
+}
+
+#[instrument(err)]
+pub(crate) async fn get_account(
+    Extension(auth): Extension<AdminAuth>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<AccountAdmin>> {
+    let account_id = params
+        .get("account_id")
+        .expect(":account_id should be in path for admin account routes");
+
+    let account_id = resolve_account_id(account_id).await?;
+
+    let Some(account) = account::get_account(&account_id).await? else {
+        not_found!("Account not found");
+    };
+
+    info!(
+        caller_arn = auth.caller_arn,
+        account_id = account.id(),
+        "Admin retrieved account"
+    );
+
+    Ok(Json(AccountAdmin::from(account)))
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct HashPrincipalChainIdsResponse {
+    principal_chains_rewritten: u64,
+}
+
+// Migrates an account's existing principal chains to hashed record IDs. The account's resources database is migrated
+// first so it has the functions and fields hashed chains need. This is safe to run more than once.
+#[instrument(err)]
+pub(crate) async fn hash_principal_chain_ids(
+    Extension(auth): Extension<AdminAuth>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<HashPrincipalChainIdsResponse>> {
+    let account_id = params
+        .get("account_id")
+        .expect(":account_id should be in path for admin account routes");
+
+    let account_id = resolve_account_id(account_id).await?;
+
+    let Some(account) = account::get_account(&account_id).await? else {
+        not_found!("Account not found");
+    };
+
+    let db = account.resources_db().await?;
+
+    migrator::migrate_account_resources_database(&db)
+        .await
+        .context("Failed to migrate 'resources' database")?;
+
+    let principal_chains_rewritten = migrator::hash_principal_chain_ids(&db)
+        .await
+        .context("Failed to hash principal chain IDs")?;
+
+    info!(
+        caller_arn = auth.caller_arn,
+        account_id = account.id(),
+        principal_chains_rewritten,
+        "Admin hashed principal chain IDs"
+    );
+
+    Ok(Json(HashPrincipalChainIdsResponse {
+        principal_chains_rewritten,
+    }))
+}
+
+// Locks an account for incident response or an operator-run migration. Unlike owner locks, the account owner can't
+// change or remove it.
+#[instrument(err)]
+pub(crate) async fn lock_account(
+    Extension(auth): Extension<AdminAuth>,
+    Path(params): Path<HashMap<String, String>>,
+    Json(req): Json<LockAccountRequest>,
+) -> Result<Json<()>> {
+    let account_id = params
+        .get("account_id")
+        .expect(":account_id should be in path for admin account routes");
+
+    let account_id = resolve_account_id(account_id).await?;
+
+    let Some(account) = account::get_account(&account_id).await? else {
+        not_found!("Account not found");
+    };
+
+    account_lock::lock(&account, &req, None).await?;
+
+    info!(
+        caller_arn = auth.caller_arn,
+        account_id = account.id(),
+        ?req,
+        "Admin locked account"
+    );
+
+    Ok(Json(()))
+}
+
+#[instrument(err)]
+pub(crate) async fn unlock_account(
+    Extension(auth): Extension<AdminAuth>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    let account_id = params
+        .get("account_id")
+        .expect(":account_id should be in path for admin account routes");
+
+    let account_id = resolve_account_id(account_id).await?;
+
+    let Some(account) = account::get_account(&account_id).await? else {
+        not_found!("Account not found");
+    };
+
+    account_lock::unlock(&account).await?;
+
+    info!(
+        caller_arn = auth.caller_arn,
+        account_id = account.id(),
+        "Admin unlocked account"
+    );
+
+    Ok(Json(()))
+}
+
+// Runs database maintenance now rather than waiting for the next scheduled run. The request is held open until
+// maintenance completes, which may take a while for large installs.
+#[instrument(err)]
+pub(crate) async fn maintenance(
+    Extension(auth): Extension<AdminAuth>,
+) -> Result<Json<MaintenanceReport>> {
+    info!(caller_arn = auth.caller_arn, "Admin started maintenance");
+
+    Ok(Json(maintenance::run().await?))
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReadOnlyResponse {
+    enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetReadOnlyRequest {
+    enabled: bool,
+}
+
+pub(crate) async fn get_read_only() -> Json<ReadOnlyResponse> {
+    Json(ReadOnlyResponse {
+        enabled: read_only::is_enabled(),
+    })
+}
+
+// Puts the backend into or out of read-only mode, e.g. to stop changes during a disaster recovery failover. Only the
+// backend instance handling the request is toggled, and only until it restarts, so deployments with several instances
+// should set `ARCHODEX_READ_ONLY` instead.
+#[instrument]
+pub(crate) async fn set_read_only(
+    Extension(auth): Extension<AdminAuth>,
+    Json(req): Json<SetReadOnlyRequest>,
+) -> Json<ReadOnlyResponse> {
+    read_only::set_enabled(req.enabled);
+
+    warn!(
+        caller_arn = auth.caller_arn,
+        enabled = req.enabled,
+        "Admin toggled read-only mode"
+    );
+
+    Json(ReadOnlyResponse {
+        enabled: req.enabled,
+    })
+}
+
+#[instrument(err)]
+pub(crate) async fn reconcile(
+    Extension(auth): Extension<AdminAuth>,
+    Json(req): Json<ReconcileRequest>,
+) -> Result<Json<ReconciliationReport>> {
+    info!(
+        caller_arn = auth.caller_arn,
+        ?req,
+        "Admin started reconciliation"
+    );
+
+    Ok(Json(reconciliation::reconcile(req).await?))
+}
+
+#[cfg(feature = "archodex-com")]
+#[instrument(err)]
+pub(crate) async fn sweep_orphaned_service_databases(
+    Extension(auth): Extension<AdminAuth>,
+) -> Result<Json<crate::provisioning::SweepOrphanedServiceDatabasesResponse>> {
+    info!(
+        caller_arn = auth.caller_arn,
+        "Admin started sweep of orphaned service databases"
+    );
+
+    Ok(Json(
+        crate::provisioning::sweep_orphaned_service_databases().await?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_header_only_when_listed_in_signed_headers() {
+        let authorization = "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20250101/us-east-1/sts/aws4_request, SignedHeaders=content-type;host;x-amz-date;x-archodex-server-id, Signature=abc";
+
+        assert!(signs_header(authorization, SERVER_ID_HEADER));
+        assert!(!signs_header(authorization, "x-amz-security-token"));
+
+        let unsigned = "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20250101/us-east-1/sts/aws4_request, SignedHeaders=content-type;host;x-amz-date, Signature=x-archodex-server-id";
+
+        assert!(!signs_header(unsigned, SERVER_ID_HEADER));
+        assert!(!signs_header("x-archodex-server-id", SERVER_ID_HEADER));
+    }
+}
@@ -0,0 +1,280 @@
+use axum::{Extension, Json, extract::Query};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::Uuid;
+use tracing::{error, instrument};
+use utoipa::{IntoParams, ToSchema};
+
+use archodex_error::bad_request;
+
+use crate::{
+    Result,
+    account::Account,
+    auth::DashboardAuth,
+    clock,
+    db::{QueryCheckFirstRealError as _, accounts_db_for_account},
+    next_binding,
+    router::RequestId,
+    surrealdb_deserializers,
+    user::User,
+};
+
+const DEFAULT_PAGE_LIMIT: u32 = 100;
+const MAX_PAGE_LIMIT: u32 = 1000;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditAction {
+    AccountCreated,
+    AccountDeleted,
+    ReportApiKeyCreated,
+    ReportApiKeyRevoked,
+    PersonalAccessTokenCreated,
+    PersonalAccessTokenRevoked,
+    ResourceEnvironmentsSet,
+    MemberInvited,
+    MemberRemoved,
+    MemberInvitationAccepted,
+    AccountDataExported,
+    AccountDataImported,
+    EnrichersSet,
+    EventRetentionSet,
+    ResourceRetentionSet,
+    ResourcesPruned,
+    ConfigPromoted,
+    AccountLocked,
+    AccountUnlocked,
+    ReportApiKeysFrozen,
+    ReportApiKeysUnfrozen,
+    TransformationsSet,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditLogEntry {
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    id: Uuid,
+    action: AuditAction,
+    actor: User,
+    #[serde(
+        default,
+        deserialize_with = "surrealdb_deserializers::uuid::deserialize_optional"
+    )]
+    personal_access_token: Option<Uuid>,
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    request_id: Uuid,
+    details: Option<serde_json::Value>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct AuditLogEntryPublic {
+    id: Uuid,
+    action: AuditAction,
+    actor_user_id: Uuid,
+    /// Set when the action was made with a personal access token rather than a dashboard session
+    personal_access_token_id: Option<Uuid>,
+    /// ID of the request that made the action, as returned in its `X-Archodex-Request-Id` header
+    request_id: Uuid,
+    /// Action-specific details, e.g. the ID of the report API key that was created
+    details: Option<serde_json::Value>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<AuditLogEntry> for AuditLogEntryPublic {
+    fn from(record: AuditLogEntry) -> Self {
+        Self {
+            id: record.id,
+            action: record.action,
+            actor_user_id: record.actor.id(),
+            personal_access_token_id: record.personal_access_token,
+            request_id: record.request_id,
+            details: record.details,
+            created_at: record.created_at,
+        }
+    }
+}
+
+fn audit_log_thing(id: Uuid) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from((
+        "audit_log",
+        surrealdb::sql::Id::Uuid(surrealdb::sql::Uuid::from(id)),
+    ))
+}
+
+// Records an administrative action in the audit log of the account it was made in, in the account's accounts database
+// shard. The action has already been made, so failing to record it is logged rather than failing the request.
+#[instrument(skip(auth, request_id, details))]
+pub(crate) async fn record(
+    account_id: &str,
+    auth: &DashboardAuth,
+    request_id: RequestId,
+    action: AuditAction,
+    details: Option<serde_json::Value>,
+) {
+    insert(
+        account_id,
+        auth.principal(),
+        auth.personal_access_token()
+            .map(|personal_access_token| personal_access_token.id()),
+        request_id.id(),
+        action,
+        details,
+    )
+    .await;
+}
+
+// Records an action made on a user's behalf after their request finished, e.g. by a bulk operation they started.
+// `request_id` is the ID of the request that started the action.
+#[instrument(skip(actor, details))]
+pub(crate) async fn record_on_behalf_of(
+    account_id: &str,
+    actor: &User,
+    request_id: Uuid,
+    action: AuditAction,
+    details: Option<serde_json::Value>,
+) {
+    insert(account_id, actor, None, request_id, action, details).await;
+}
+
+async fn insert(
+    account_id: &str,
+    actor: &User,
+    personal_access_token_id: Option<Uuid>,
+    request_id: Uuid,
+    action: AuditAction,
+    details: Option<serde_json::Value>,
+) {
+    let entry_binding = next_binding();
+    let account_binding = next_binding();
+    let action_binding = next_binding();
+    let actor_binding = next_binding();
+    let personal_access_token_binding = next_binding();
+    let request_id_binding = next_binding();
+    let details_binding = next_binding();
+    let now_binding = next_binding();
+
+    let personal_access_token = personal_access_token_id.map(|personal_access_token_id| {
+        surrealdb::sql::Thing::from((
+            "personal_access_token",
+            surrealdb::sql::Id::Uuid(surrealdb::sql::Uuid::from(personal_access_token_id)),
+        ))
+    });
+
+    let res = async {
+        accounts_db_for_account(account_id)
+            .await?
+            .query(format!(
+                "CREATE ${entry_binding} CONTENT {{
+                    account: ${account_binding},
+                    action: ${action_binding},
+                    actor: ${actor_binding},
+                    personal_access_token: ${personal_access_token_binding},
+                    request_id: ${request_id_binding},
+                    details: ${details_binding},
+                    created_at: ${now_binding},
+                }} RETURN NONE"
+            ))
+            .bind((entry_binding, audit_log_thing(Uuid::now_v7())))
+            .bind((
+                account_binding,
+                surrealdb::sql::Thing::from((
+                    "account",
+                    surrealdb::sql::Id::String(account_id.to_string()),
+                )),
+            ))
+            .bind((action_binding, action))
+            .bind((actor_binding, surrealdb::sql::Thing::from(actor)))
+            .bind((personal_access_token_binding, personal_access_token))
+            .bind((request_id_binding, surrealdb::sql::Uuid::from(request_id)))
+            .bind((details_binding, details))
+            .bind((now_binding, clock::now_value()))
+            .await?
+            .check_first_real_error()?;
+
+        Result::Ok(())
+    }
+    .await;
+
+    if let Err(err) = res {
+        error!(
+            ?err,
+            account_id,
+            ?action,
+            "Failed to record audit log entry"
+        );
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListAuditLogParams {
+    limit: Option<u32>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListAuditLogResponse {
+    entries: Vec<AuditLogEntryPublic>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+// Lists the account's audit log, newest entries first. Entry IDs are UUIDv7s, so they sort by creation time and the ID
+// of the last entry of a page is the cursor of the next.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/audit_log",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ListAuditLogParams),
+    responses((status = 200, body = ListAuditLogResponse))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn list_audit_log(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Query(params): Query<ListAuditLogParams>,
+) -> Result<Json<ListAuditLogResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if !(1..=MAX_PAGE_LIMIT).contains(&limit) {
+        bad_request!("limit must be between 1 and {MAX_PAGE_LIMIT}");
+    }
+
+    let cursor = match params.cursor.as_deref().map(Uuid::parse_str) {
+        Some(Ok(cursor)) => Some(audit_log_thing(cursor)),
+        Some(Err(_)) => bad_request!("Invalid cursor"),
+        None => None,
+    };
+
+    let mut entries = accounts_db_for_account(account.id())
+        .await?
+        .query(
+            "SELECT * FROM audit_log
+                WHERE account = $account AND ($cursor IS NONE OR id < $cursor)
+                ORDER BY id DESC
+                LIMIT $page_fetch_limit",
+        )
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind(("cursor", cursor))
+        .bind(("page_fetch_limit", limit + 1))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<AuditLogEntry>>(0)?;
+
+    // One extra entry is fetched to determine whether another page exists
+    let next_cursor = if entries.len() > limit as usize {
+        entries.truncate(limit as usize);
+        entries.last().map(|entry| entry.id.to_string())
+    } else {
+        None
+    };
+
+    Ok(Json(ListAuditLogResponse {
+        entries: entries.into_iter().map(AuditLogEntryPublic::from).collect(),
+        next_cursor,
+    }))
+}
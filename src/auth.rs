@@ -1,93 +1,472 @@
-use std::{collections::HashMap, time::SystemTime};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
 
-use axum::{extract::Request, middleware::Next, response::Response};
+use axum::{extract::Request, http::StatusCode, middleware::Next, response::Response};
 use josekit::{
     JoseError,
-    jwk::JwkSet,
-    jws::alg::rsassa::{RsassaJwsAlgorithm, RsassaJwsVerifier},
+    jwk::{Jwk, JwkSet},
+    jws::{
+        JwsVerifier,
+        alg::{ecdsa::EcdsaJwsAlgorithm, rsassa::RsassaJwsAlgorithm},
+    },
     jwt,
 };
 use reqwest::header::AUTHORIZATION;
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
 use surrealdb::{Surreal, Uuid, engine::any::Any};
-use tokio::sync::OnceCell;
 use tracing::{Instrument as _, error_span, info, instrument, warn};
 
 use crate::{
     Result,
-    db::{QueryCheckFirstRealError, accounts_db},
-    env::Env,
+    account_member::ACTIVE_ACCESS_CONDITION,
+    client_ip::ClientIp,
+    clock,
+    db::{QueryCheckFirstRealError, accounts_db_for_account},
+    env::{DashboardOidcAudience, Env},
+    personal_access_token::{PersonalAccessToken, PersonalAccessTokenAuth},
     report_api_key::{ReportApiKey, ReportApiKeyIsValidQueryResponse, ReportApiKeyQueries},
     user::User,
 };
 use archodex_error::{
-    anyhow::{Context as _, anyhow},
-    not_found, unauthorized,
+    PublicError,
+    anyhow::{self, Context as _, anyhow, ensure},
+    forbidden, not_found, unauthorized,
 };
 
-static JWK_SET: OnceCell<(JwkSet, HashMap<String, RsassaJwsVerifier>)> = OnceCell::const_new();
-
-pub(crate) async fn jwks(
-    jwks_issuer: &str,
-) -> &'static (JwkSet, HashMap<String, RsassaJwsVerifier>) {
-    JWK_SET
-        .get_or_init(|| async {
-            let jwks_url = format!("{jwks_issuer}/.well-known/jwks.json");
-
-            info!("Fetching JWKS from {jwks_url}");
-
-            let client = reqwest::Client::new();
-
-            let jwks_bytes = client
-                .get(jwks_url)
-                .send()
-                .await
-                .expect("Failed to request Cognito jwks")
-                .bytes()
-                .await
-                .expect("Failed to receive Cognito jwks bytes");
-
-            let jwks =
-                JwkSet::from_bytes(jwks_bytes.as_ref()).expect("Failed to parse Cognito jwks");
-
-            let verifiers = jwks
-                .keys()
-                .iter()
-                .map(|jwk| {
-                    (
-                        jwk.key_id()
-                            .expect("Cognito jwk missing 'kid' field")
-                            .to_owned(),
-                        match jwk.algorithm() {
-                            Some("RS256") => RsassaJwsAlgorithm::Rs256,
-                            Some("RS384") => RsassaJwsAlgorithm::Rs384,
-                            Some("RS512") => RsassaJwsAlgorithm::Rs512,
-                            Some(alg) => {
-                                panic!("Unsupported Cognito jwk algorithm {alg}");
-                            }
-                            None => {
-                                panic!("Cognito jwk missing 'alg' field");
-                            }
-                        }
-                        .verifier_from_jwk(jwk)
-                        .expect("Failed to create verifier from Cognito jwk"),
-                    )
-                })
-                .collect::<HashMap<_, _>>();
-
-            (jwks, verifiers)
-        })
+// How long results of dashboard account access checks are cached. Membership changes made through this backend
+// invalidate cached results immediately; changes made through other backend instances apply once entries expire.
+const ACCOUNT_ACCESS_CACHE_TTL: Duration = Duration::from_secs(30);
+
+// Expired entries are pruned when the cache grows beyond this many entries
+const ACCOUNT_ACCESS_CACHE_PRUNE_THRESHOLD: usize = 10_000;
+
+type AccountAccessCache = HashMap<(Uuid, String), (bool, Instant)>;
+
+static ACCOUNT_ACCESS_CACHE: LazyLock<Mutex<AccountAccessCache>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn account_access_cache() -> std::sync::MutexGuard<'static, AccountAccessCache> {
+    ACCOUNT_ACCESS_CACHE
+        .lock()
+        .expect("Account access cache mutex should not be poisoned")
+}
+
+fn cache_account_access(user: &User, account_id: &str, has_access: bool) {
+    let mut cache = account_access_cache();
+
+    if cache.len() >= ACCOUNT_ACCESS_CACHE_PRUNE_THRESHOLD {
+        cache.retain(|_, (_, cached_at)| cached_at.elapsed() < ACCOUNT_ACCESS_CACHE_TTL);
+    }
+
+    cache.insert(
+        (user.id(), account_id.to_string()),
+        (has_access, Instant::now()),
+    );
+}
+
+fn cached_account_access(user: &User, account_id: &str) -> Option<bool> {
+    let cache = account_access_cache();
+
+    match cache.get(&(user.id(), account_id.to_string())) {
+        Some((has_access, cached_at)) if cached_at.elapsed() < ACCOUNT_ACCESS_CACHE_TTL => {
+            Some(*has_access)
+        }
+        _ => None,
+    }
+}
+
+// Drops cached access check results for an account. Must be called whenever the account's membership changes.
+pub(crate) fn invalidate_account_access(account_id: &str) {
+    account_access_cache().retain(|(_, cached_account_id), _| cached_account_id != account_id);
+}
+
+// Keys dashboard access tokens are verified with, indexed by key ID
+struct DashboardJwks {
+    verifiers: HashMap<String, Box<dyn JwsVerifier>>,
+}
+
+// Signing keys are refetched at least this often, so keys the OIDC provider stopped publishing (e.g. after rotating or
+// revoking them) stop being accepted
+const DASHBOARD_JWKS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+// Signing keys are refetched at most this often, so tokens with unknown key IDs can't make the backend hammer the OIDC
+// provider
+const DASHBOARD_JWKS_MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+struct CachedDashboardJwks {
+    jwks: Arc<DashboardJwks>,
+    fetched_at: Instant,
+    last_fetch_attempt: Instant,
+}
+
+static DASHBOARD_JWKS: tokio::sync::Mutex<Option<CachedDashboardJwks>> =
+    tokio::sync::Mutex::const_new(None);
+
+#[derive(Deserialize)]
+struct OidcDiscoveryDocument {
+    issuer: String,
+    jwks_uri: String,
+}
+
+fn jws_verifier(jwk: &Jwk) -> std::result::Result<Box<dyn JwsVerifier>, JoseError> {
+    // Providers aren't required to set the algorithm of their keys, in which case RSA keys are assumed to be used for
+    // RS256 as recommended by OpenID Connect
+    let algorithm = match (jwk.algorithm(), jwk.key_type()) {
+        (Some(algorithm), _) => algorithm,
+        (None, "RSA") => "RS256",
+        (None, key_type) => {
+            return Err(JoseError::InvalidJwkFormat(anyhow!(
+                "JWK of type {key_type} is missing 'alg' field"
+            )));
+        }
+    };
+
+    Ok(match algorithm {
+        "RS256" => Box::new(RsassaJwsAlgorithm::Rs256.verifier_from_jwk(jwk)?),
+        "RS384" => Box::new(RsassaJwsAlgorithm::Rs384.verifier_from_jwk(jwk)?),
+        "RS512" => Box::new(RsassaJwsAlgorithm::Rs512.verifier_from_jwk(jwk)?),
+        "ES256" => Box::new(EcdsaJwsAlgorithm::Es256.verifier_from_jwk(jwk)?),
+        "ES384" => Box::new(EcdsaJwsAlgorithm::Es384.verifier_from_jwk(jwk)?),
+        "ES512" => Box::new(EcdsaJwsAlgorithm::Es512.verifier_from_jwk(jwk)?),
+        algorithm => {
+            return Err(JoseError::UnsupportedSignatureAlgorithm(anyhow!(
+                "Unsupported JWK algorithm {algorithm}"
+            )));
+        }
+    })
+}
+
+// Returns the signing keys of the OIDC provider. Keys are cached, and refetched once they are older than
+// `DASHBOARD_JWKS_MAX_AGE` or when `key_id` is set and isn't one of them, e.g. after the provider rotated its keys. If
+// refetching fails, the cached keys are used until the next attempt. A failed first fetch is retried by the next request.
+async fn dashboard_jwks(issuer: &str, key_id: Option<&str>) -> anyhow::Result<Arc<DashboardJwks>> {
+    let mut cached = DASHBOARD_JWKS.lock().await;
+
+    if let Some(cached) = cached.as_mut() {
+        let is_stale = cached.fetched_at.elapsed() >= DASHBOARD_JWKS_MAX_AGE;
+        let is_missing_key =
+            key_id.is_some_and(|key_id| !cached.jwks.verifiers.contains_key(key_id));
+
+        if !(is_stale || is_missing_key)
+            || cached.last_fetch_attempt.elapsed() < DASHBOARD_JWKS_MIN_REFETCH_INTERVAL
+        {
+            return Ok(Arc::clone(&cached.jwks));
+        }
+
+        cached.last_fetch_attempt = Instant::now();
+
+        match fetch_dashboard_jwks(issuer).await {
+            Ok(jwks) => {
+                cached.jwks = Arc::new(jwks);
+                cached.fetched_at = Instant::now();
+            }
+            Err(err) => warn!(?err, "Failed to refetch JWKS, using cached keys"),
+        }
+
+        return Ok(Arc::clone(&cached.jwks));
+    }
+
+    let jwks = Arc::new(fetch_dashboard_jwks(issuer).await?);
+
+    *cached = Some(CachedDashboardJwks {
+        jwks: Arc::clone(&jwks),
+        fetched_at: Instant::now(),
+        last_fetch_attempt: Instant::now(),
+    });
+
+    Ok(jwks)
+}
+
+// Fetches the signing keys of the OIDC provider through its discovery document
+async fn fetch_dashboard_jwks(issuer: &str) -> anyhow::Result<DashboardJwks> {
+    let discovery_url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+
+    info!("Fetching OIDC discovery document from {discovery_url}");
+
+    let client = reqwest::Client::new();
+
+    let discovery_document_bytes = client
+        .get(&discovery_url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .context("Failed to request OIDC discovery document")?
+        .bytes()
         .await
+        .context("Failed to receive OIDC discovery document bytes")?;
+
+    let discovery_document =
+        serde_json::from_slice::<OidcDiscoveryDocument>(&discovery_document_bytes)
+            .context("Failed to parse OIDC discovery document")?;
+
+    // OpenID Connect Discovery requires the document's issuer to match the issuer it was fetched from
+    ensure!(
+        discovery_document.issuer == issuer,
+        "OIDC discovery document issuer {:?} does not match configured issuer {issuer:?}",
+        discovery_document.issuer
+    );
+
+    info!("Fetching JWKS from {}", discovery_document.jwks_uri);
+
+    let jwks_bytes = client
+        .get(&discovery_document.jwks_uri)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .context("Failed to request JWKS")?
+        .bytes()
+        .await
+        .context("Failed to receive JWKS bytes")?;
+
+    let jwk_set = JwkSet::from_bytes(jwks_bytes.as_ref()).context("Failed to parse JWKS")?;
+
+    let mut verifiers = HashMap::new();
+
+    for jwk in jwk_set.keys() {
+        // Some providers publish encryption keys alongside signing keys
+        if jwk.key_use().is_some_and(|key_use| key_use != "sig") {
+            continue;
+        }
+
+        let Some(key_id) = jwk.key_id() else {
+            warn!("Skipping JWK without 'kid' field");
+            continue;
+        };
+
+        match jws_verifier(jwk) {
+            Ok(verifier) => {
+                verifiers.insert(key_id.to_owned(), verifier);
+            }
+            Err(err) => warn!(key_id, ?err, "Skipping JWK that can't verify signatures"),
+        }
+    }
+
+    ensure!(!verifiers.is_empty(), "JWKS has no usable signing keys");
+
+    Ok(DashboardJwks { verifiers })
+}
+
+// Authenticates dashboard requests by their bearer access token.
+//
+// Dashboard requests are authenticated by the process-wide provider, which by default verifies tokens issued by the
+// configured OpenID Connect provider. Tests can install a `FixedDashboardAuthProvider` for their thread to exercise
+// dashboard routes without an identity provider.
+pub(crate) trait DashboardAuthProvider: Send + Sync {
+    // Returns the ID of the user the access token was issued to, or an unauthorized error if the token is not valid
+    fn authenticate<'a>(
+        &'a self,
+        access_token: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Uuid>> + Send + 'a>>;
+
+    // Checks that the provider can authenticate access tokens, e.g. that the identity provider's signing keys were
+    // fetched, so `/readyz` fails until dashboard requests can be served. Providers that don't depend on other services
+    // have no check.
+    fn readiness_check(&self) -> Option<Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>> {
+        None
+    }
+}
+
+// The default provider, which verifies access tokens against the signing keys of the OpenID Connect provider (e.g.
+// Cognito) configured by `Env::dashboard_oidc()`
+struct OidcDashboardAuthProvider;
+
+impl DashboardAuthProvider for OidcDashboardAuthProvider {
+    fn authenticate<'a>(
+        &'a self,
+        access_token: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Uuid>> + Send + 'a>> {
+        Box::pin(verify_oidc_access_token(access_token))
+    }
+
+    fn readiness_check(&self) -> Option<Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>> {
+        Some(Box::pin(async {
+            dashboard_jwks(&Env::dashboard_oidc().issuer, None).await?;
+
+            Ok(())
+        }))
+    }
+}
+
+async fn verify_oidc_access_token(access_token: &str) -> Result<Uuid> {
+    let oidc = Env::dashboard_oidc();
+
+    // Tokens signed with a key that isn't cached make the keys be refetched, as the provider may have rotated its keys
+    let key_id = jwt::decode_header(access_token).ok().and_then(|header| {
+        header
+            .claim("kid")
+            .and_then(josekit::Value::as_str)
+            .map(str::to_owned)
+    });
+
+    let jwks = dashboard_jwks(&oidc.issuer, key_id.as_deref()).await?;
+
+    // Tokens without a key ID or with an unknown one have no verifier, which fails verification
+    let user_id = match jwt::decode_with_verifier_selector(access_token, |header| {
+        Ok(header
+            .key_id()
+            .and_then(|key_id| jwks.verifiers.get(key_id))
+            .map(|verifier| &**verifier))
+    }) {
+        Ok((payload, _header)) => {
+            let Some(josekit::Value::String(user_id)) = payload.claim(&oidc.user_id_claim) else {
+                warn!(
+                    claim = oidc.user_id_claim,
+                    "Missing or invalid user ID claim in JWT"
+                );
+                unauthorized!();
+            };
+
+            let mut validator = jwt::JwtPayloadValidator::new();
+
+            validator.set_base_time(SystemTime::from(clock::now()));
+            validator.set_issuer(&oidc.issuer);
+
+            match &oidc.audience {
+                DashboardOidcAudience::Aud(audience) => validator.set_audience(audience),
+                DashboardOidcAudience::CognitoClientId(client_id) => {
+                    validator.set_claim("client_id", client_id.as_str().into());
+                    validator.set_claim("token_use", "access".into());
+                }
+            }
+
+            match validator.validate(&payload) {
+                Ok(()) => user_id.to_owned(),
+                Err(err) => {
+                    warn!(?err, "Failed to validate JWT");
+                    unauthorized!();
+                }
+            }
+        }
+        Err(err) => {
+            warn!(?err, "Failed to verify JWT");
+            unauthorized!();
+        }
+    };
+
+    Ok(Uuid::parse_str(&user_id)
+        .with_context(|| format!("Failed to parse user ID {user_id:?} as UUID"))?)
+}
+
+// Provider that accepts a single access token as a single user, for tests and `ARCHODEX_DEV_AUTH_TOKEN`. Any other token
+// is rejected. Dev auth isn't available in archodex.com builds, so they only include it in tests.
+#[cfg(any(test, not(feature = "archodex-com")))]
+struct FixedDashboardAuthProvider {
+    // Tokens are compared by their digests, so the time a comparison takes doesn't reveal how much of a guessed token
+    // matches
+    access_token_digest: Vec<u8>,
+    user_id: Uuid,
+}
+
+#[cfg(any(test, not(feature = "archodex-com")))]
+impl FixedDashboardAuthProvider {
+    fn new(access_token: impl AsRef<str>, user_id: Uuid) -> Self {
+        Self {
+            access_token_digest: Sha256::digest(access_token.as_ref().as_bytes()).to_vec(),
+            user_id,
+        }
+    }
+}
+
+#[cfg(any(test, not(feature = "archodex-com")))]
+impl DashboardAuthProvider for FixedDashboardAuthProvider {
+    fn authenticate<'a>(
+        &'a self,
+        access_token: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<Uuid>> + Send + 'a>> {
+        Box::pin(async move {
+            if Sha256::digest(access_token.as_bytes()).as_slice() != self.access_token_digest {
+                warn!("Access token does not match fixed dashboard access token");
+                unauthorized!();
+            }
+
+            Ok(self.user_id)
+        })
+    }
+}
+
+static DASHBOARD_AUTH_PROVIDER: LazyLock<Arc<dyn DashboardAuthProvider>> =
+    LazyLock::new(default_dashboard_auth_provider);
+
+#[cfg(test)]
+thread_local! {
+    // Overrides the process-wide provider for the test running on this thread
+    static TEST_DASHBOARD_AUTH_PROVIDER: std::cell::RefCell<Option<Arc<dyn DashboardAuthProvider>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+// Self-hosted installs may opt into dev auth, which replaces OIDC entirely
+fn default_dashboard_auth_provider() -> Arc<dyn DashboardAuthProvider> {
+    #[cfg(not(feature = "archodex-com"))]
+    if let Some(dev_auth) = Env::dev_auth() {
+        warn!(
+            user_id = %dev_auth.user_id,
+            "Dashboard requests are authenticated with ARCHODEX_DEV_AUTH_TOKEN instead of OIDC access tokens"
+        );
+
+        return Arc::new(FixedDashboardAuthProvider::new(
+            &dev_auth.token,
+            dev_auth.user_id,
+        ));
+    }
+
+    Arc::new(OidcDashboardAuthProvider)
+}
+
+// Authenticates dashboard requests on the current thread with `provider` instead of the process-wide provider
+#[cfg(test)]
+fn set_test_dashboard_auth_provider(provider: Arc<dyn DashboardAuthProvider>) {
+    TEST_DASHBOARD_AUTH_PROVIDER.set(Some(provider));
+}
+
+pub(crate) fn dashboard_auth_provider() -> Arc<dyn DashboardAuthProvider> {
+    #[cfg(test)]
+    if let Some(provider) = TEST_DASHBOARD_AUTH_PROVIDER.with_borrow(Clone::clone) {
+        return provider;
+    }
+
+    Arc::clone(&DASHBOARD_AUTH_PROVIDER)
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct DashboardAuth {
     principal: User,
+    // Set when the request was authenticated with a personal access token rather than a dashboard session
+    personal_access_token: Option<PersonalAccessTokenAuth>,
 }
 
 impl DashboardAuth {
-    pub(crate) async fn authenticate(mut req: Request, next: Next) -> Result<Response> {
+    pub(crate) async fn authenticate(req: Request, next: Next) -> Result<Response> {
+        Self::authenticate_request(req, next, false).await
+    }
+
+    // Authenticates routes CI jobs and scripts may call with personal access tokens as well as dashboard sessions
+    pub(crate) async fn authenticate_allowing_personal_access_tokens(
+        req: Request,
+        next: Next,
+    ) -> Result<Response> {
+        Self::authenticate_request(req, next, true).await
+    }
+
+    async fn authenticate_request(
+        mut req: Request,
+        next: Next,
+        allow_personal_access_tokens: bool,
+    ) -> Result<Response> {
         let authorization = req.headers().get(AUTHORIZATION);
         let dashboard_auth = async move {
+            #[cfg(feature = "chaos")]
+            if crate::chaos::inject_auth_failure() {
+                unauthorized!();
+            }
+
             let Some(authorization) = authorization else {
                 warn!("Missing Authorization header");
                 unauthorized!();
@@ -103,53 +482,26 @@ impl DashboardAuth {
                 unauthorized!();
             };
 
-            let cognito_user_pool_id = Env::cognito_user_pool_id();
-            let cognito_client_id = Env::cognito_client_id();
-
-            let jwks_issuer =
-                format!("https://cognito-idp.us-west-2.amazonaws.com/{cognito_user_pool_id}");
-
-            let (jwk_set, verifier_map) = jwks(&jwks_issuer).await;
-
-            let user_id = match jwt::decode_with_verifier_in_jwk_set(access_token, jwk_set, |jwk| {
-                Ok(verifier_map
-                    .get(jwk.key_id().ok_or(JoseError::InvalidJwkFormat(anyhow!(
-                        "Cognito jwk missing 'kid' field"
-                    )))?)
-                    .map(|verifier| verifier as &dyn josekit::jws::JwsVerifier))
-            }) {
-                Ok((payload, _header)) => {
-                    let Some(josekit::Value::String(sub)) = payload.claim("sub") else {
-                        warn!("Missing or invalid sub claim in JWT");
-                        unauthorized!();
-                    };
-
-                    let mut validator = jwt::JwtPayloadValidator::new();
-
-                    validator.set_base_time(SystemTime::now());
-                    validator.set_issuer(&jwks_issuer);
-                    validator.set_claim("client_id", cognito_client_id.into());
-                    validator.set_claim("token_use", "access".into());
-
-                    match validator.validate(&payload) {
-                        Ok(()) => Result::Ok(sub.to_owned()),
-                        Err(err) => {
-                            warn!(?err, "Failed to validate JWT");
-                            unauthorized!();
-                        }
-                    }
-                }
-                Err(err) => {
-                    warn!(?err, "Failed to verify JWT");
+            if PersonalAccessToken::is_value(access_token) {
+                if !allow_personal_access_tokens {
+                    warn!("Personal access tokens may not be used for this route");
                     unauthorized!();
                 }
-            }?;
 
-            let user_id = Uuid::parse_str(&user_id)
-                .with_context(|| format!("Failed to parse user ID {user_id:?} as UUID"))?;
+                let (principal, personal_access_token) =
+                    PersonalAccessToken::authenticate(access_token).await?;
+
+                return Result::Ok(DashboardAuth {
+                    principal,
+                    personal_access_token: Some(personal_access_token),
+                });
+            }
+
+            let user_id = dashboard_auth_provider().authenticate(access_token).await?;
 
             Result::Ok(DashboardAuth {
                 principal: User::new(user_id),
+                personal_access_token: None,
             })
         }
         .instrument(error_span!("authenticate"))
@@ -166,36 +518,92 @@ impl DashboardAuth {
         &self.principal
     }
 
+    pub(crate) fn personal_access_token(&self) -> Option<&PersonalAccessTokenAuth> {
+        self.personal_access_token.as_ref()
+    }
+
+    // Personal access tokens act as the user who created them, so the user must still have access to the account
     #[instrument]
     pub(crate) async fn validate_account_access(&self, account_id: &str) -> Result<()> {
-        if accounts_db()
-            .await?
-            .query("SELECT 1 FROM $user->has_access->(account WHERE record::id(id) == $account_id)")
-            .bind(("user", surrealdb::sql::Thing::from(&self.principal)))
-            .bind(("account_id", account_id.to_string()))
-            .await?
-            .check_first_real_error()?
-            .take::<Option<u8>>((0, "1"))?
-            .is_none()
+        if let Some(personal_access_token) = &self.personal_access_token
+            && personal_access_token.account_id() != account_id
         {
+            warn!(
+                personal_access_token_id = %personal_access_token.id(),
+                "Personal access token is not valid for account"
+            );
+            not_found!("Account not found");
+        }
+
+        let has_access = if let Some(has_access) =
+            cached_account_access(&self.principal, account_id)
+        {
+            has_access
+        } else {
+            let has_access = accounts_db_for_account(account_id)
+                .await?
+                .query(format!("SELECT 1 FROM $user->(has_access WHERE {ACTIVE_ACCESS_CONDITION})->(account WHERE record::id(id) == $account_id)"))
+                .bind(("user", surrealdb::sql::Thing::from(&self.principal)))
+                .bind(("account_id", account_id.to_string()))
+                .await?
+                .check_first_real_error()?
+                .take::<Option<u8>>((0, "1"))?
+                .is_some();
+
+            cache_account_access(&self.principal, account_id, has_access);
+
+            has_access
+        };
+
+        if !has_access {
             warn!("Account does not exist or principal does not have access to account");
             not_found!("Account not found");
         }
 
         Ok(())
     }
+
+    // Verifies the principal owns the account
+    #[instrument]
+    pub(crate) async fn validate_account_owner(&self, account_id: &str) -> Result<()> {
+        if !self.principal.is_account_owner(account_id).await? {
+            warn!("Principal is not the owner of the account");
+            forbidden!("Only the account owner may perform this action");
+        }
+
+        Ok(())
+    }
+
+    // Records access to a batch of accounts the principal is already known to have access to, e.g. from listing their
+    // accounts, so that navigating into any of them doesn't need a separate access check query.
+    pub(crate) fn record_account_access<'a>(&self, account_ids: impl IntoIterator<Item = &'a str>) {
+        for account_id in account_ids {
+            cache_account_access(&self.principal, account_id, true);
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct ReportApiKeyAuth {
     account_id: String,
     key_id: u32,
+    // Recorded as the address the key was last used from
+    client_ip: Option<IpAddr>,
 }
 
 impl ReportApiKeyAuth {
     pub(crate) async fn authenticate(mut req: Request, next: Next) -> Result<Response> {
         let authorization = req.headers().get(AUTHORIZATION);
-        let report_api_key_auth = async move {
+        let client_ip = req
+            .extensions()
+            .get::<ClientIp>()
+            .and_then(|ClientIp(client_ip)| *client_ip);
+        let mut report_api_key_auth = async move {
+            #[cfg(feature = "chaos")]
+            if crate::chaos::inject_auth_failure() {
+                unauthorized!();
+            }
+
             let Some(report_api_key_value) = authorization else {
                 warn!("Missing Authorization header");
                 unauthorized!();
@@ -206,20 +614,13 @@ impl ReportApiKeyAuth {
                 unauthorized!();
             };
 
-            let (account_id, key_id) =
-                match ReportApiKey::validate_value(report_api_key_value).await {
-                    Ok((account_id, key_id)) => (account_id, key_id),
-                    Err(err) => {
-                        warn!(?err, "Failed to validate report key value");
-                        unauthorized!();
-                    }
-                };
-
-            Result::Ok(ReportApiKeyAuth { account_id, key_id })
+            ReportApiKeyAuth::from_value(report_api_key_value).await
         }
         .instrument(error_span!("authenticate"))
         .await?;
 
+        report_api_key_auth.client_ip = client_ip;
+
         tracing::Span::current().record("auth", tracing::field::debug(&report_api_key_auth));
 
         req.extensions_mut().insert(report_api_key_auth);
@@ -227,36 +628,38 @@ impl ReportApiKeyAuth {
         Ok(next.run(req).await)
     }
 
-    #[instrument(err, level = "error", skip_all)]
-    async fn _authenticate(req: &Request) -> Result<ReportApiKeyAuth> {
-        let Some(report_api_key_value) = req.headers().get(AUTHORIZATION) else {
-            warn!("Missing Authorization header");
-            unauthorized!();
-        };
-
-        let Ok(report_api_key_value) = report_api_key_value.to_str() else {
-            warn!("Failed to parse Authorization header value as string");
-            unauthorized!();
-        };
-
+    // Validates a report API key value from an Authorization header or another transport, e.g. a Kafka record header.
+    // The caller must still validate the key against the account's resources database.
+    pub(crate) async fn from_value(report_api_key_value: &str) -> Result<ReportApiKeyAuth> {
         let (account_id, key_id) = match ReportApiKey::validate_value(report_api_key_value).await {
             Ok((account_id, key_id)) => (account_id, key_id),
+            // Failures to load the API private key are not the client's fault and should be retried
+            Err(err) if err.is::<PublicError>() => return Err(err.into()),
             Err(err) => {
                 warn!(?err, "Failed to validate report key value");
                 unauthorized!();
             }
         };
 
-        Ok(ReportApiKeyAuth { account_id, key_id })
+        Ok(ReportApiKeyAuth {
+            account_id,
+            key_id,
+            client_ip: None,
+        })
     }
 
     pub(crate) fn account_id(&self) -> &str {
         &self.account_id
     }
 
+    pub(crate) fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    // Validates the key exists and has not been revoked or suspended, and records its use
     pub(crate) async fn validate_account_access(&self, db: &Surreal<Any>) -> Result<()> {
         let Some(response) = db
-            .report_api_key_is_valid_query(self.key_id)
+            .use_report_api_key_query(self.key_id, self.client_ip)
             .await?
             .check_first_real_error()?
             .take::<Option<ReportApiKeyIsValidQueryResponse>>(0)?
@@ -269,6 +672,19 @@ impl ReportApiKeyAuth {
             unauthorized!();
         };
 
+        if response.is_suspended() {
+            warn!(
+                key_id = self.key_id,
+                account_id = self.account_id,
+                "Report key is suspended in account database",
+            );
+            return Err(PublicError::new(
+                StatusCode::FORBIDDEN,
+                "Report API key is suspended while the account's report API keys are frozen",
+            )
+            .with_code("report_api_key_suspended"));
+        }
+
         if !response.is_valid() {
             warn!(
                 key_id = self.key_id,
@@ -281,3 +697,124 @@ impl ReportApiKeyAuth {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        Extension, Router,
+        body::Body,
+        http::{Request, StatusCode},
+        middleware,
+        routing::get,
+    };
+    use tower::ServiceExt as _;
+
+    use super::*;
+
+    const ACCESS_TOKEN: &str = "test-access-token";
+
+    fn dashboard_router(allow_personal_access_tokens: bool) -> Router {
+        let user_id = Uuid::from_u128(1);
+
+        set_test_dashboard_auth_provider(Arc::new(FixedDashboardAuthProvider::new(
+            ACCESS_TOKEN,
+            user_id,
+        )));
+
+        let router = Router::new().route(
+            "/account/:account_id/resources",
+            get(|Extension(auth): Extension<DashboardAuth>| async move {
+                auth.principal().id().to_string()
+            }),
+        );
+
+        if allow_personal_access_tokens {
+            router.layer(middleware::from_fn(
+                DashboardAuth::authenticate_allowing_personal_access_tokens,
+            ))
+        } else {
+            router.layer(middleware::from_fn(DashboardAuth::authenticate))
+        }
+    }
+
+    async fn get_resources(router: Router, authorization: Option<&str>) -> (StatusCode, String) {
+        let mut req = Request::get("/account/1/resources");
+
+        if let Some(authorization) = authorization {
+            req = req.header(AUTHORIZATION, authorization);
+        }
+
+        let res = router
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn dashboard_route_accepts_provider_access_token() {
+        let (status, body) = get_resources(
+            dashboard_router(false),
+            Some(&format!("Bearer {ACCESS_TOKEN}")),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, Uuid::from_u128(1).to_string());
+    }
+
+    #[tokio::test]
+    async fn dashboard_route_rejects_other_access_token() {
+        let (status, _) =
+            get_resources(dashboard_router(false), Some("Bearer other-access-token")).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn dashboard_route_rejects_missing_authorization() {
+        let (status, _) = get_resources(dashboard_router(false), None).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn dashboard_route_rejects_non_bearer_authorization() {
+        let (status, _) = get_resources(
+            dashboard_router(false),
+            Some(&format!("Basic {ACCESS_TOKEN}")),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn dashboard_route_rejects_personal_access_token_unless_allowed() {
+        let (status, _) = get_resources(
+            dashboard_router(false),
+            Some("Bearer archodex_pat_1_00000000-0000-0000-0000-000000000000_secret"),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn dashboard_route_allowing_personal_access_tokens_accepts_provider_access_token() {
+        let (status, body) = get_resources(
+            dashboard_router(true),
+            Some(&format!("Bearer {ACCESS_TOKEN}")),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, Uuid::from_u128(1).to_string());
+    }
+}
@@ -0,0 +1,509 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Deserialize;
+use surrealdb::{
+    Surreal,
+    engine::any::Any,
+    opt::{Config, capabilities::Capabilities},
+};
+use tracing::{info, instrument};
+
+use archodex_error::anyhow::{self, Context as _, bail};
+
+use crate::{
+    clock,
+    db::{ArchodexSurrealDatabase, lock_embedded_db},
+    env::{BackupConfig, BackupLocation, Env},
+    worker,
+};
+
+// Snapshots are named by the UTC time they were taken, so they sort chronologically
+const SNAPSHOT_NAME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+// Suffix of local snapshots that are still being written, or whose backup failed
+const PARTIAL_SUFFIX: &str = ".partial";
+
+// Databases in a snapshot, each exported to `<database>.surql`. The resources database is missing from snapshots of
+// installs without accounts.
+const DATABASES: [ArchodexSurrealDatabase; 2] = [
+    ArchodexSurrealDatabase::Accounts,
+    ArchodexSurrealDatabase::Resources,
+];
+
+fn snapshot_file(database: ArchodexSurrealDatabase) -> String {
+    format!("{}.surql", database.name())
+}
+
+fn is_snapshot_name(name: &str) -> bool {
+    chrono::NaiveDateTime::parse_from_str(name, SNAPSHOT_NAME_FORMAT).is_ok()
+}
+
+/// Writes a snapshot of the embedded database to `ARCHODEX_BACKUP_DESTINATION` every
+/// `ARCHODEX_BACKUP_INTERVAL_SECONDS` until the process exits. Scheduled backups are disabled when the destination is
+/// unset.
+///
+/// Each snapshot is a directory (or S3 prefix) named by the time it was taken, holding a SurrealQL export of the
+/// accounts and resources databases. Requests wait while the databases are exported, so the exports are consistent
+/// with each other. Only the newest `ARCHODEX_BACKUP_RETAIN` snapshots are kept. Snapshots are restored with
+/// `ARCHODEX_RESTORE_BACKUP` or the server's `--restore-backup` flag.
+pub async fn run_worker() {
+    let Some(config) = Env::backup() else {
+        return;
+    };
+
+    info!(
+        destination = %config.destination,
+        retain = config.retain,
+        "Configured database backups"
+    );
+
+    worker::run_periodically("backup", config.interval, || back_up(config)).await;
+}
+
+#[instrument(err, skip_all)]
+async fn back_up(config: &BackupConfig) -> anyhow::Result<()> {
+    let snapshot = clock::now().format(SNAPSHOT_NAME_FORMAT).to_string();
+
+    // Snapshots are exported to a staging directory first, so a failed backup never leaves a partial snapshot behind
+    let staging = match &config.destination {
+        BackupLocation::Local(directory) => directory.join(format!(".{snapshot}{PARTIAL_SUFFIX}")),
+        #[cfg(feature = "s3-backups")]
+        BackupLocation::S3 { .. } => {
+            std::env::temp_dir().join(format!("archodex-backup-{snapshot}"))
+        }
+    };
+
+    tokio::fs::create_dir_all(&staging)
+        .await
+        .with_context(|| format!("Failed to create backup directory {}", staging.display()))?;
+
+    let res = async {
+        export_databases(&staging).await?;
+
+        match &config.destination {
+            BackupLocation::Local(directory) => {
+                tokio::fs::rename(&staging, directory.join(&snapshot))
+                    .await
+                    .context("Failed to move backup snapshot into place")?
+            }
+            #[cfg(feature = "s3-backups")]
+            BackupLocation::S3 { bucket, prefix } => {
+                s3::upload_snapshot(bucket, &s3::key(prefix, &snapshot), &staging).await?;
+            }
+        }
+
+        anyhow::Ok(())
+    }
+    .await;
+
+    // The staging directory no longer exists after a local snapshot was moved into place
+    let _ = tokio::fs::remove_dir_all(&staging).await;
+
+    res?;
+
+    info!(snapshot, destination = %config.destination, "Wrote backup snapshot");
+
+    prune_snapshots(config).await
+}
+
+#[derive(Deserialize)]
+struct NamespaceInfo {
+    databases: BTreeMap<String, String>,
+}
+
+// Exports each database to `<directory>/<database>.surql`. Every other user of the embedded database waits until the
+// exports complete.
+async fn export_databases(directory: &Path) -> anyhow::Result<()> {
+    let mut db = lock_embedded_db().await?;
+
+    let namespace = db
+        .database(ArchodexSurrealDatabase::Accounts)
+        .await?
+        .query("INFO FOR NS")
+        .await?
+        .take::<Option<NamespaceInfo>>(0)?
+        .context("Missing 'archodex' namespace info")?;
+
+    for database in DATABASES {
+        if !namespace.databases.contains_key(database.name()) {
+            continue;
+        }
+
+        db.database(database)
+            .await?
+            .export(directory.join(snapshot_file(database)))
+            .await
+            .with_context(|| format!("Failed to export '{}' database", database.name()))?;
+    }
+
+    Ok(())
+}
+
+// Deletes all but the newest `ARCHODEX_BACKUP_RETAIN` snapshots, along with local snapshots left behind by failed backups
+async fn prune_snapshots(config: &BackupConfig) -> anyhow::Result<()> {
+    match &config.destination {
+        BackupLocation::Local(directory) => {
+            let mut snapshots = vec![];
+
+            let mut entries = tokio::fs::read_dir(directory).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+
+                if name.ends_with(PARTIAL_SUFFIX) {
+                    tokio::fs::remove_dir_all(entry.path()).await?;
+                } else if is_snapshot_name(&name) {
+                    snapshots.push(name);
+                }
+            }
+
+            snapshots.sort();
+
+            for snapshot in snapshots.iter().rev().skip(config.retain) {
+                tokio::fs::remove_dir_all(directory.join(snapshot)).await?;
+                info!(snapshot, "Deleted expired backup snapshot");
+            }
+        }
+        #[cfg(feature = "s3-backups")]
+        BackupLocation::S3 { bucket, prefix } => {
+            let mut snapshots = s3::list_snapshots(bucket, prefix).await?;
+
+            snapshots.sort();
+
+            for snapshot in snapshots.iter().rev().skip(config.retain) {
+                s3::delete_snapshot(bucket, &s3::key(prefix, snapshot)).await?;
+                info!(snapshot, "Deleted expired backup snapshot");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Opens the embedded database directly, rather than through the connection shared by requests, so that it is closed
+// again before migrations open it
+async fn connect() -> anyhow::Result<Surreal<Any>> {
+    let db = surrealdb::engine::any::connect((
+        Env::surrealdb_url(),
+        Config::default()
+            .capabilities(Capabilities::default().with_live_query_notifications(false))
+            .strict(),
+    ))
+    .await?;
+
+    if let Some(creds) = Env::surrealdb_creds() {
+        db.signin(creds)
+            .await
+            .context("Failed to sign in to SurrealDB with SURREALDB_USERNAME and SURREALDB_PASSWORD environment values")?;
+    }
+
+    db.query("DEFINE NAMESPACE IF NOT EXISTS archodex;")
+        .await?
+        .check()?;
+
+    db.use_ns("archodex").await?;
+
+    Ok(db)
+}
+
+/// Restores the snapshot named by `ARCHODEX_RESTORE_BACKUP`, unless it is the snapshot that was last restored.
+///
+/// Restoring the same snapshot again requires the `--restore-backup` flag, so leaving the variable set doesn't discard
+/// data written since the restore each time the backend starts. This must be called before the accounts database is
+/// migrated, see `restore()`.
+///
+/// # Errors
+///
+/// Will return `Err` if the snapshot can't be read or restored.
+pub async fn restore_on_startup() -> anyhow::Result<()> {
+    let Some(snapshot) = Env::restore_backup() else {
+        return Ok(());
+    };
+
+    let last_restored = {
+        let db = connect().await?;
+
+        db.query("DEFINE DATABASE IF NOT EXISTS accounts;")
+            .await?
+            .check()?;
+
+        db.use_db("accounts").await?;
+
+        db.query("RETURN $restored_backup")
+            .await?
+            .take::<Option<String>>(0)?
+    };
+
+    if last_restored.as_deref() == Some(snapshot.to_string().as_str()) {
+        info!(%snapshot, "Backup snapshot in ARCHODEX_RESTORE_BACKUP was already restored");
+        return Ok(());
+    }
+
+    restore_snapshot(snapshot).await
+}
+
+/// Replaces the contents of the embedded database with a snapshot written by the backup worker, named by its directory
+/// or `s3://<bucket>/<prefix>`.
+///
+/// The embedded database can't be opened twice, so this must be called before anything else uses it. The accounts
+/// database must be migrated afterwards, as the snapshot may have been written by an older version of the backend. The
+/// resources database is migrated here.
+///
+/// # Errors
+///
+/// Will return `Err` if the snapshot can't be read or restored. The database may be left partially restored, in which
+/// case restoring again replaces it.
+pub async fn restore(snapshot: &str) -> anyhow::Result<()> {
+    let snapshot = match BackupLocation::parse(snapshot) {
+        Ok(snapshot) => snapshot,
+        Err(problem) => bail!("Invalid backup snapshot {snapshot:?}: {problem}"),
+    };
+
+    restore_snapshot(&snapshot).await
+}
+
+#[instrument(err)]
+async fn restore_snapshot(snapshot: &BackupLocation) -> anyhow::Result<()> {
+    info!(%snapshot, "Restoring backup snapshot...");
+
+    match snapshot {
+        BackupLocation::Local(directory) => import_databases(directory, snapshot).await,
+        #[cfg(feature = "s3-backups")]
+        BackupLocation::S3 { bucket, prefix } => {
+            let staging = std::env::temp_dir().join(format!(
+                "archodex-restore-{}",
+                clock::now().format(SNAPSHOT_NAME_FORMAT)
+            ));
+
+            let res = async {
+                s3::download_snapshot(bucket, prefix, &staging).await?;
+                import_databases(&staging, snapshot).await
+            }
+            .await;
+
+            let _ = tokio::fs::remove_dir_all(&staging).await;
+
+            res
+        }
+    }
+}
+
+async fn import_databases(directory: &Path, snapshot: &BackupLocation) -> anyhow::Result<()> {
+    let files = DATABASES.map(|database| (database, directory.join(snapshot_file(database))));
+
+    for (database, file) in &files {
+        if *database == ArchodexSurrealDatabase::Accounts && !file.exists() {
+            bail!(
+                "{snapshot} is not a backup snapshot, it has no {}",
+                snapshot_file(*database)
+            );
+        }
+    }
+
+    let db = connect().await?;
+
+    for (database, file) in files {
+        let name = database.name();
+
+        db.query(format!("REMOVE DATABASE IF EXISTS {name};"))
+            .await?
+            .check()
+            .with_context(|| format!("Failed to remove '{name}' database"))?;
+
+        if !file.exists() {
+            continue;
+        }
+
+        db.query(format!("DEFINE DATABASE {name};"))
+            .await?
+            .check()
+            .with_context(|| format!("Failed to define '{name}' database"))?;
+
+        db.use_db(name).await?;
+
+        db.import(&file)
+            .await
+            .with_context(|| format!("Failed to import '{name}' database"))?;
+
+        if database == ArchodexSurrealDatabase::Resources {
+            migrator::migrate_account_resources_database(&db)
+                .await
+                .context("Failed to migrate 'resources' database")?;
+        }
+
+        info!(database = name, "Restored database");
+    }
+
+    db.use_db("accounts").await?;
+
+    db.query("DEFINE PARAM OVERWRITE $restored_backup VALUE $snapshot;")
+        .bind(("snapshot", snapshot.to_string()))
+        .await?
+        .check()
+        .context("Failed to record restored backup snapshot")?;
+
+    info!(%snapshot, "Restored backup snapshot");
+
+    Ok(())
+}
+
+#[cfg(feature = "s3-backups")]
+mod s3 {
+    use std::path::Path;
+
+    use aws_sdk_s3::{Client, primitives::ByteStream};
+    use tokio::sync::OnceCell;
+
+    use archodex_error::anyhow::{self, Context as _};
+
+    use super::{DATABASES, is_snapshot_name, snapshot_file};
+
+    async fn client() -> &'static Client {
+        static CLIENT: OnceCell<Client> = OnceCell::const_new();
+
+        CLIENT
+            .get_or_init(|| async {
+                Client::new(&aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await)
+            })
+            .await
+    }
+
+    pub(super) fn key(prefix: &str, name: &str) -> String {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{prefix}/{name}")
+        }
+    }
+
+    pub(super) async fn upload_snapshot(
+        bucket: &str,
+        snapshot_prefix: &str,
+        directory: &Path,
+    ) -> anyhow::Result<()> {
+        for database in DATABASES {
+            let file = snapshot_file(database);
+            let path = directory.join(&file);
+
+            if !path.exists() {
+                continue;
+            }
+
+            client()
+                .await
+                .put_object()
+                .bucket(bucket)
+                .key(key(snapshot_prefix, &file))
+                .body(ByteStream::from_path(&path).await?)
+                .send()
+                .await
+                .with_context(|| {
+                    format!("Failed to upload {file} to s3://{bucket}/{snapshot_prefix}")
+                })?;
+        }
+
+        Ok(())
+    }
+
+    pub(super) async fn download_snapshot(
+        bucket: &str,
+        snapshot_prefix: &str,
+        directory: &Path,
+    ) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(directory).await?;
+
+        let keys = list_keys(bucket, &key(snapshot_prefix, "")).await?;
+
+        for database in DATABASES {
+            let file = snapshot_file(database);
+            let object_key = key(snapshot_prefix, &file);
+
+            if !keys.contains(&object_key) {
+                continue;
+            }
+
+            let object = client()
+                .await
+                .get_object()
+                .bucket(bucket)
+                .key(&object_key)
+                .send()
+                .await
+                .with_context(|| format!("Failed to download s3://{bucket}/{object_key}"))?;
+
+            let bytes = object.body.collect().await?.into_bytes();
+
+            tokio::fs::write(directory.join(&file), bytes).await?;
+        }
+
+        Ok(())
+    }
+
+    // Names of the snapshots under the destination prefix
+    pub(super) async fn list_snapshots(bucket: &str, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let prefix = key(prefix, "");
+
+        let mut pages = client()
+            .await
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(&prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+
+        let mut snapshots = vec![];
+
+        while let Some(page) = pages.next().await {
+            for common_prefix in page?.common_prefixes() {
+                if let Some(name) = common_prefix
+                    .prefix()
+                    .and_then(|name| name.strip_prefix(&prefix))
+                    .map(|name| name.trim_end_matches('/'))
+                    .filter(|name| is_snapshot_name(name))
+                {
+                    snapshots.push(name.to_string());
+                }
+            }
+        }
+
+        Ok(snapshots)
+    }
+
+    async fn list_keys(bucket: &str, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let mut pages = client()
+            .await
+            .list_objects_v2()
+            .bucket(bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+
+        let mut keys = vec![];
+
+        while let Some(page) = pages.next().await {
+            keys.extend(
+                page?
+                    .contents()
+                    .iter()
+                    .filter_map(|object| object.key().map(str::to_string)),
+            );
+        }
+
+        Ok(keys)
+    }
+
+    pub(super) async fn delete_snapshot(bucket: &str, snapshot_prefix: &str) -> anyhow::Result<()> {
+        for object_key in list_keys(bucket, &key(snapshot_prefix, "")).await? {
+            client()
+                .await
+                .delete_object()
+                .bucket(bucket)
+                .key(&object_key)
+                .send()
+                .await
+                .with_context(|| format!("Failed to delete s3://{bucket}/{object_key}"))?;
+        }
+
+        Ok(())
+    }
+}
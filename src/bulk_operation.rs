@@ -0,0 +1,770 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use aes_gcm::{
+    AeadCore, Aes128Gcm, KeyInit,
+    aead::{self, Aead},
+};
+use axum::{Extension, Json, extract::Path};
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{self, Context as _, anyhow, bail, ensure},
+    bad_request, conflict, forbidden, not_found,
+};
+
+use crate::{
+    Result,
+    account::{
+        Account, AccountQueries as _, external_account_id, get_account, invalidate_cached_account,
+    },
+    account_lock::AccountLockMode,
+    account_member::{AccountMember, AccountMemberPublic, AccountMemberQueries as _},
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    clock,
+    db::{
+        DBConnection, QueryCheckFirstRealError as _, accounts_db_for_account,
+        is_record_exists_error, primary_accounts_db,
+    },
+    env::Env,
+    event_retention,
+    report_api_key::{
+        MAX_CREATE_ATTEMPTS, ReportApiKey, ReportApiKeyQueries as _,
+        create_report_api_key_statement, revoke_report_api_key_statement,
+    },
+    resource_retention,
+    router::RequestId,
+    surrealdb_deserializers,
+    user::User,
+    worker::{self, PendingAccounts},
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_ATTEMPTS: u32 = 3;
+const NONCE_LENGTH: usize = 12;
+
+static PENDING_ACCOUNTS: PendingAccounts = PendingAccounts::new();
+
+// Operations that have been processing for longer than this are assumed to belong to a worker that died, and are
+// claimed again. Accounts the dead worker finished are not processed again.
+const PROCESSING_TIMEOUT: chrono::Duration = chrono::Duration::minutes(15);
+
+// Finished operations are deleted after this long, along with the values of the report API keys they rotated
+const FINISHED_OPERATION_RETENTION: chrono::Duration = chrono::Duration::days(7);
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum BulkOperationKind {
+    /// Replaces each unrevoked report API key with a new key with the same description and revokes the replaced key.
+    /// The values of the new keys are returned with the operation.
+    RotateReportApiKeys,
+    /// Sets how many days events are kept after they were last seen, or keeps events forever if null
+    SetEventRetention { retention_days: Option<u32> },
+    /// Sets how many days resources are kept after they were last seen, or keeps resources forever if null
+    SetResourceRetention { retention_days: Option<u32> },
+    /// Lists the users with access to each account, including invited users who haven't accepted yet
+    ListMembers,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum BulkOperationStatus {
+    Pending,
+    Processing,
+    /// Every account was processed. Accounts the operation failed for are reported in the operation's results.
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RotatedReportApiKey {
+    revoked_report_api_key_id: u32,
+    report_api_key_id: u32,
+    // Base64 encoded nonce and value of the new key, encrypted with the operation's ID as associated data
+    encrypted_report_api_key_value: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BulkOperationAccountResult {
+    account_id: String,
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rotated_report_api_keys: Vec<RotatedReportApiKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    members: Option<Vec<AccountMemberPublic>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkOperation {
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    id: Uuid,
+    operation: BulkOperationKind,
+    account_ids: Vec<String>,
+    results: Vec<BulkOperationAccountResult>,
+    status: BulkOperationStatus,
+    attempts: u32,
+    error: Option<String>,
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    request_id: Uuid,
+    created_at: DateTime<Utc>,
+    created_by: User,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct RotatedReportApiKeyPublic {
+    revoked_report_api_key_id: u32,
+    report_api_key_id: u32,
+    report_api_key_value: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BulkOperationAccountResultPublic {
+    account_id: String,
+    /// Set if the operation failed for the account, in which case the account was left unchanged
+    error: Option<String>,
+    /// Keys created by `rotate_report_api_keys`, along with the IDs of the keys they replaced
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rotated_report_api_keys: Vec<RotatedReportApiKeyPublic>,
+    /// Members of the account listed by `list_members`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    members: Option<Vec<AccountMemberPublic>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BulkOperationPublic {
+    id: Uuid,
+    operation: BulkOperationKind,
+    status: BulkOperationStatus,
+    /// Number of accounts the operation applies to, i.e. the accounts the user owned when starting it
+    accounts_total: usize,
+    /// Number of accounts processed so far, including accounts the operation failed for
+    accounts_processed: usize,
+    accounts_failed: usize,
+    /// Results of the processed accounts, in the order they were processed
+    results: Vec<BulkOperationAccountResultPublic>,
+    /// Set if the operation failed before every account was processed
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+impl BulkOperation {
+    async fn into_public(self) -> anyhow::Result<BulkOperationPublic> {
+        let mut results = Vec::with_capacity(self.results.len());
+
+        for result in self.results {
+            let mut rotated_report_api_keys =
+                Vec::with_capacity(result.rotated_report_api_keys.len());
+
+            for rotated_report_api_key in result.rotated_report_api_keys {
+                rotated_report_api_keys.push(RotatedReportApiKeyPublic {
+                    revoked_report_api_key_id: rotated_report_api_key.revoked_report_api_key_id,
+                    report_api_key_id: rotated_report_api_key.report_api_key_id,
+                    report_api_key_value: decrypt_report_api_key_value(
+                        self.id,
+                        &rotated_report_api_key.encrypted_report_api_key_value,
+                    )
+                    .await?,
+                });
+            }
+
+            results.push(BulkOperationAccountResultPublic {
+                account_id: external_account_id(&result.account_id).unwrap_or(result.account_id),
+                error: result.error,
+                rotated_report_api_keys,
+                members: result.members,
+            });
+        }
+
+        Ok(BulkOperationPublic {
+            id: self.id,
+            operation: self.operation,
+            status: self.status,
+            accounts_total: self.account_ids.len(),
+            accounts_processed: results.len(),
+            accounts_failed: results
+                .iter()
+                .filter(|result| result.error.is_some())
+                .count(),
+            results,
+            error: self.error,
+            created_at: self.created_at,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+        })
+    }
+}
+
+fn bulk_operation_thing(id: Uuid) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from((
+        "bulk_operation",
+        surrealdb::sql::Id::Uuid(surrealdb::sql::Uuid::from(id)),
+    ))
+}
+
+// Values of rotated keys are kept until the operation is deleted, so they are encrypted with the operation's ID as
+// associated data like event destination credentials
+async fn encrypt_report_api_key_value(
+    bulk_operation_id: Uuid,
+    report_api_key_value: &str,
+) -> anyhow::Result<String> {
+    let cipher = Aes128Gcm::new(&Env::api_private_key().await?);
+    let nonce = Aes128Gcm::generate_nonce(&mut rand::rngs::OsRng);
+
+    let encrypted = cipher
+        .encrypt(
+            &nonce,
+            aead::Payload {
+                msg: report_api_key_value.as_bytes(),
+                aad: bulk_operation_id.as_bytes(),
+            },
+        )
+        .map_err(|err| anyhow!("Failed to encrypt rotated report API key value: {err}"))?;
+
+    Ok(BASE64_STANDARD.encode([nonce.as_slice(), &encrypted].concat()))
+}
+
+async fn decrypt_report_api_key_value(
+    bulk_operation_id: Uuid,
+    encrypted_report_api_key_value: &str,
+) -> anyhow::Result<String> {
+    let encrypted_report_api_key_value = BASE64_STANDARD
+        .decode(encrypted_report_api_key_value)
+        .context("Failed to decode encrypted rotated report API key value")?;
+
+    ensure!(
+        encrypted_report_api_key_value.len() > NONCE_LENGTH,
+        "Encrypted rotated report API key value is too short"
+    );
+
+    let (nonce, encrypted) = encrypted_report_api_key_value.split_at(NONCE_LENGTH);
+
+    let cipher = Aes128Gcm::new(&Env::api_private_key().await?);
+
+    let decrypted = cipher
+        .decrypt(
+            aead::Nonce::<Aes128Gcm>::from_slice(nonce),
+            aead::Payload {
+                msg: encrypted,
+                aad: bulk_operation_id.as_bytes(),
+            },
+        )
+        .map_err(|err| anyhow!("Failed to decrypt rotated report API key value: {err}"))?;
+
+    String::from_utf8(decrypted).context("Decrypted rotated report API key value is not UTF-8")
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CreateBulkOperationRequest {
+    operation: BulkOperationKind,
+}
+
+// Starts applying an operation to every account the user owns. Operations are processed by the bulk operation worker,
+// and their progress and results are retrieved with `GET /bulk_operation/{bulk_operation_id}`.
+#[utoipa::path(
+    post,
+    path = "/bulk_operations",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    request_body = CreateBulkOperationRequest,
+    responses((status = 200, body = BulkOperationPublic))
+)]
+#[instrument(err, skip(auth, request_id))]
+pub(crate) async fn create_bulk_operation(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<CreateBulkOperationRequest>,
+) -> Result<Json<BulkOperationPublic>> {
+    match req.operation {
+        BulkOperationKind::SetEventRetention { retention_days } => {
+            event_retention::validate_retention_days(retention_days)?;
+        }
+        BulkOperationKind::SetResourceRetention { retention_days } => {
+            resource_retention::validate_retention_days(retention_days)?;
+        }
+        BulkOperationKind::RotateReportApiKeys | BulkOperationKind::ListMembers => {}
+    }
+
+    let account_ids = auth
+        .principal()
+        .list_owned_accounts()
+        .await?
+        .iter()
+        .map(|account| account.id().to_string())
+        .collect::<Vec<_>>();
+
+    if account_ids.is_empty() {
+        bad_request!(
+            "Bulk operations apply to the accounts you own, and you don't own any accounts"
+        );
+    }
+
+    let bulk_operation = primary_accounts_db()
+        .await?
+        .query("CREATE $bulk_operation CONTENT { operation: $operation, account_ids: $account_ids, request_id: $request_id, created_by: $user }")
+        .bind(("bulk_operation", bulk_operation_thing(Uuid::now_v7())))
+        .bind(("operation", req.operation))
+        .bind(("account_ids", account_ids.clone()))
+        .bind(("request_id", surrealdb::sql::Uuid::from(request_id.id())))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await?
+        .check_first_real_error()?
+        .take::<Option<BulkOperation>>(0)?
+        .context("Create bulk operation query should return a bulk operation instance")?;
+
+    info!(
+        bulk_operation_id = %bulk_operation.id,
+        accounts = account_ids.len(),
+        "Created bulk operation"
+    );
+
+    for account_id in &account_ids {
+        PENDING_ACCOUNTS.mark(account_id);
+    }
+
+    Ok(Json(bulk_operation.into_public().await?))
+}
+
+// Returns the progress and results of a bulk operation. Only the user who started the operation may retrieve it, as its
+// results include the values of rotated report API keys.
+#[utoipa::path(
+    get,
+    path = "/bulk_operation/{bulk_operation_id}",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("bulk_operation_id" = String, Path)),
+    responses((status = 200, body = BulkOperationPublic))
+)]
+#[instrument(err, skip(auth))]
+pub(crate) async fn get_bulk_operation(
+    Extension(auth): Extension<DashboardAuth>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<BulkOperationPublic>> {
+    let Some(bulk_operation_id) = params.get("bulk_operation_id") else {
+        bail!("Missing bulk_operation_id");
+    };
+
+    let Ok(bulk_operation_id) = Uuid::parse_str(bulk_operation_id) else {
+        bad_request!("Invalid bulk operation ID");
+    };
+
+    let Some(bulk_operation) = primary_accounts_db()
+        .await?
+        .query("SELECT * FROM $bulk_operation WHERE created_by = $user")
+        .bind(("bulk_operation", bulk_operation_thing(bulk_operation_id)))
+        .bind(("user", surrealdb::sql::Thing::from(auth.principal())))
+        .await?
+        .check_first_real_error()?
+        .take::<Option<BulkOperation>>(0)?
+    else {
+        not_found!("Bulk operation not found");
+    };
+
+    Ok(Json(bulk_operation.into_public().await?))
+}
+
+#[derive(Deserialize)]
+struct PendingBulkOperation {
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    id: Uuid,
+}
+
+/// Runs the bulk operation worker until the process exits.
+///
+/// The worker applies bulk operations, which users start to change or list every account they own at once, to each of
+/// their accounts. Operations are kept in the first accounts database shard rather than with an account, so each run
+/// processes every pending operation. Operations are processed as soon as this process creates one, and are swept for
+/// operations of workers that died and operations created elsewhere every minute. Each account's result is recorded as
+/// soon as it is processed, so operations claimed again continue with the accounts that weren't processed yet.
+pub async fn run_worker() {
+    worker::run_on_demand(
+        "bulk_operation",
+        &PENDING_ACCOUNTS,
+        SWEEP_INTERVAL,
+        |_account_ids: Option<BTreeSet<String>>| process_pending_bulk_operations(),
+    )
+    .await;
+}
+
+#[instrument(err)]
+pub(crate) async fn process_pending_bulk_operations() -> Result<()> {
+    let now = clock::now();
+
+    let pending_bulk_operations = primary_accounts_db()
+        .await?
+        .query("DELETE bulk_operation WHERE finished_at IS NOT NONE AND finished_at < $expired_before")
+        .query("SELECT id, created_at FROM bulk_operation WHERE status = 'pending' OR (status = 'processing' AND started_at < $stale_before) ORDER BY created_at")
+        .bind(("expired_before", surrealdb::sql::Datetime::from(now - FINISHED_OPERATION_RETENTION)))
+        .bind(("stale_before", surrealdb::sql::Datetime::from(now - PROCESSING_TIMEOUT)))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<PendingBulkOperation>>(1)?;
+
+    // Operations left processing by a failure here are claimed again once they time out
+    for pending_bulk_operation in pending_bulk_operations {
+        if let Err(err) = process_bulk_operation(pending_bulk_operation.id).await {
+            warn!(
+                bulk_operation_id = %pending_bulk_operation.id,
+                ?err,
+                "Failed to process bulk operation"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// The accounts database connection is only held for each query, as processing an account opens its own connections and
+// a non-concurrent (e.g. RocksDB) database only has one.
+#[instrument(err)]
+async fn process_bulk_operation(bulk_operation_id: Uuid) -> Result<()> {
+    let bulk_operation_thing = bulk_operation_thing(bulk_operation_id);
+
+    // Another worker may have claimed the operation since it was listed, in which case nothing is returned
+    let Some(bulk_operation) = primary_accounts_db()
+        .await?
+        .query("UPDATE $bulk_operation SET status = 'processing', started_at = $now, attempts += 1 WHERE status = 'pending' OR (status = 'processing' AND started_at < $stale_before) RETURN AFTER")
+        .bind(("bulk_operation", bulk_operation_thing.clone()))
+        .bind(("now", clock::now_value()))
+        .bind(("stale_before", surrealdb::sql::Datetime::from(clock::now() - PROCESSING_TIMEOUT)))
+        .await?
+        .check_first_real_error()?
+        .take::<Option<BulkOperation>>(0)?
+    else {
+        return Ok(());
+    };
+
+    let attempts = bulk_operation.attempts;
+
+    if attempts > MAX_ATTEMPTS {
+        warn!(attempts, "Bulk operation did not finish, marking it failed");
+
+        primary_accounts_db()
+            .await?
+            .query("UPDATE $bulk_operation SET status = 'failed', finished_at = $now, error = $error WHERE attempts = $attempts RETURN NONE")
+            .bind(("bulk_operation", bulk_operation_thing))
+            .bind(("now", clock::now_value()))
+            .bind(("error", format!("Bulk operation did not finish after {MAX_ATTEMPTS} attempts")))
+            .bind(("attempts", attempts))
+            .await?
+            .check_first_real_error()?;
+
+        return Ok(());
+    }
+
+    let processed_account_ids = bulk_operation
+        .results
+        .iter()
+        .map(|result| result.account_id.clone())
+        .collect::<BTreeSet<_>>();
+
+    for account_id in &bulk_operation.account_ids {
+        if processed_account_ids.contains(account_id) {
+            continue;
+        }
+
+        let result = match process_account(&bulk_operation, account_id).await {
+            Ok(result) => result,
+            Err(err) => {
+                warn!(account_id, %err, "Bulk operation failed for account");
+
+                BulkOperationAccountResult {
+                    account_id: account_id.clone(),
+                    error: Some(err.to_string()),
+                    rotated_report_api_keys: vec![],
+                    members: None,
+                }
+            }
+        };
+
+        // The operation is only updated while this worker's claim holds, so a worker that took too long and lost its
+        // claim stops rather than recording results twice
+        let claimed = primary_accounts_db()
+            .await?
+            .query("UPDATE $bulk_operation SET results += $result WHERE status = 'processing' AND attempts = $attempts RETURN VALUE id")
+            .bind(("bulk_operation", bulk_operation_thing.clone()))
+            .bind(("result", result))
+            .bind(("attempts", attempts))
+            .await?
+            .check_first_real_error()?
+            .take::<Option<surrealdb::RecordId>>(0)?
+            .is_some();
+
+        if !claimed {
+            warn!("Bulk operation was claimed by another worker, stopping");
+            return Ok(());
+        }
+    }
+
+    primary_accounts_db()
+        .await?
+        .query("UPDATE $bulk_operation SET status = 'succeeded', finished_at = $now, error = NONE WHERE status = 'processing' AND attempts = $attempts RETURN NONE")
+        .bind(("bulk_operation", bulk_operation_thing))
+        .bind(("now", clock::now_value()))
+        .bind(("attempts", attempts))
+        .await?
+        .check_first_real_error()?;
+
+    info!(
+        accounts = bulk_operation.account_ids.len(),
+        attempts, "Finished bulk operation"
+    );
+
+    Ok(())
+}
+
+// Applies the operation to one account. The user who started the operation must still own the account, and the account
+// must not have been made read-only since, as a request made by the user now would be rejected.
+#[instrument(err, skip(bulk_operation), fields(bulk_operation_id = %bulk_operation.id))]
+async fn process_account(
+    bulk_operation: &BulkOperation,
+    account_id: &str,
+) -> Result<BulkOperationAccountResult> {
+    let Some(account) = get_account(account_id)
+        .await?
+        .filter(|account| !account.is_deleted())
+    else {
+        not_found!("Account not found");
+    };
+
+    if !bulk_operation
+        .created_by
+        .is_account_owner(account.id())
+        .await?
+    {
+        forbidden!("Only the account owner may perform this action");
+    }
+
+    let mut result = BulkOperationAccountResult {
+        account_id: account_id.to_string(),
+        error: None,
+        rotated_report_api_keys: vec![],
+        members: None,
+    };
+
+    let changes_account = !matches!(bulk_operation.operation, BulkOperationKind::ListMembers);
+
+    if changes_account
+        && let Some(lock) = account.lock()
+        && lock.mode() == AccountLockMode::ReadOnly
+    {
+        return Err(lock.error("changes are not allowed"));
+    }
+
+    match bulk_operation.operation {
+        BulkOperationKind::ListMembers => {
+            result.members = Some(
+                accounts_db_for_account(account.id())
+                    .await?
+                    .list_account_members_query(&account)
+                    .await?
+                    .check_first_real_error()?
+                    .take::<Vec<AccountMember>>(0)?
+                    .into_iter()
+                    .map(AccountMemberPublic::from)
+                    .collect(),
+            );
+        }
+        BulkOperationKind::RotateReportApiKeys => {
+            result.rotated_report_api_keys =
+                rotate_report_api_keys(bulk_operation, &account).await?;
+        }
+        BulkOperationKind::SetEventRetention { retention_days } => {
+            accounts_db_for_account(account.id())
+                .await?
+                .set_account_event_retention_query(
+                    &account,
+                    retention_days,
+                    &bulk_operation.created_by,
+                )
+                .await?
+                .check_first_real_error()?;
+
+            invalidate_cached_account(account.id());
+
+            audit_log::record_on_behalf_of(
+                account.id(),
+                &bulk_operation.created_by,
+                bulk_operation.request_id,
+                AuditAction::EventRetentionSet,
+                Some(json!({
+                    "retention_days": retention_days,
+                    "bulk_operation_id": bulk_operation.id,
+                })),
+            )
+            .await;
+        }
+        BulkOperationKind::SetResourceRetention { retention_days } => {
+            accounts_db_for_account(account.id())
+                .await?
+                .set_account_resource_retention_query(
+                    &account,
+                    retention_days,
+                    &bulk_operation.created_by,
+                )
+                .await?
+                .check_first_real_error()?;
+
+            invalidate_cached_account(account.id());
+
+            audit_log::record_on_behalf_of(
+                account.id(),
+                &bulk_operation.created_by,
+                bulk_operation.request_id,
+                AuditAction::ResourceRetentionSet,
+                Some(json!({
+                    "retention_days": retention_days,
+                    "bulk_operation_id": bulk_operation.id,
+                })),
+            )
+            .await;
+        }
+    }
+
+    Ok(result)
+}
+
+// Replaces each of the account's keys with a new key, returning the IDs of both keys and the new key's encrypted value.
+// Reports sent with the replaced keys are rejected until their agents are given the new values.
+async fn rotate_report_api_keys(
+    bulk_operation: &BulkOperation,
+    account: &Account,
+) -> Result<Vec<RotatedReportApiKey>> {
+    // The resources database connection is released before recording the rotations, as a non-concurrent (e.g. RocksDB)
+    // database only has one
+    let (report_api_keys, created_report_api_keys) = {
+        let db = account.resources_db().await?;
+
+        let report_api_keys = db
+            .list_report_api_keys_query()
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<ReportApiKey>>(0)?;
+
+        // Rotating suspended keys would reinstate agents the freeze is meant to stop
+        if report_api_keys.iter().any(ReportApiKey::is_suspended) {
+            conflict!("Report API keys are frozen, unfreeze them before rotating them");
+        }
+
+        if report_api_keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let created_report_api_keys =
+            replace_report_api_keys(&db, bulk_operation, account, &report_api_keys).await?;
+
+        (report_api_keys, created_report_api_keys)
+    };
+
+    let mut rotated_report_api_keys = Vec::with_capacity(report_api_keys.len());
+
+    for (report_api_key, (created_report_api_key, created_report_api_key_value)) in
+        report_api_keys.iter().zip(created_report_api_keys)
+    {
+        info!(
+            revoked_report_api_key_id = report_api_key.id(),
+            report_api_key_id = created_report_api_key.id(),
+            "Rotated Report API Key"
+        );
+
+        for (action, report_api_key_id) in [
+            (
+                AuditAction::ReportApiKeyCreated,
+                created_report_api_key.id(),
+            ),
+            (AuditAction::ReportApiKeyRevoked, report_api_key.id()),
+        ] {
+            audit_log::record_on_behalf_of(
+                account.id(),
+                &bulk_operation.created_by,
+                bulk_operation.request_id,
+                action,
+                Some(json!({
+                    "report_api_key_id": report_api_key_id,
+                    "bulk_operation_id": bulk_operation.id,
+                })),
+            )
+            .await;
+        }
+
+        rotated_report_api_keys.push(RotatedReportApiKey {
+            revoked_report_api_key_id: report_api_key.id(),
+            report_api_key_id: created_report_api_key.id(),
+            encrypted_report_api_key_value: encrypt_report_api_key_value(
+                bulk_operation.id,
+                &created_report_api_key_value,
+            )
+            .await?,
+        });
+    }
+
+    Ok(rotated_report_api_keys)
+}
+
+// Creates the new keys and revokes the keys they replace in a single transaction, so either every key of the account is
+// rotated or none is. New keys have random IDs, so the transaction is retried with new keys if one of their IDs collides
+// with an existing key.
+async fn replace_report_api_keys(
+    db: &DBConnection,
+    bulk_operation: &BulkOperation,
+    account: &Account,
+    report_api_keys: &[ReportApiKey],
+) -> Result<Vec<(ReportApiKey, String)>> {
+    for _ in 0..MAX_CREATE_ATTEMPTS {
+        let mut created_report_api_keys = Vec::with_capacity(report_api_keys.len());
+        for report_api_key in report_api_keys {
+            created_report_api_keys.push(
+                ReportApiKey::generate(
+                    report_api_key.description().map(str::to_string),
+                    &bulk_operation.created_by,
+                    account.id(),
+                    account.salt(),
+                )
+                .await?,
+            );
+        }
+
+        let mut query = db.query(BeginStatement::default());
+
+        for (created_report_api_key, _) in &created_report_api_keys {
+            query = create_report_api_key_statement(query, created_report_api_key);
+        }
+
+        for report_api_key in report_api_keys {
+            query = revoke_report_api_key_statement(
+                query,
+                report_api_key.id(),
+                &bulk_operation.created_by,
+            );
+        }
+
+        let res = query
+            .query(CommitStatement::default())
+            .await?
+            .check_first_real_error();
+
+        match res {
+            Ok(_) => return Ok(created_report_api_keys),
+            Err(err) if is_record_exists_error(&err) => {
+                warn!("Report key ID collided with an existing key, retrying with new IDs");
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    bail!("Failed to rotate report keys: Key IDs collided {MAX_CREATE_ATTEMPTS} times");
+}
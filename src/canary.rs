@@ -0,0 +1,179 @@
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use surrealdb::Uuid;
+use tracing::{Instrument as _, error, info, info_span, instrument};
+
+use archodex_error::{anyhow::Context as _, bail};
+
+use crate::{
+    Result,
+    account::get_account,
+    clock,
+    db::QueryCheckFirstRealError as _,
+    env::Env,
+    report::{self, Request},
+};
+
+const CANARY_RESOURCE_TYPE: &str = "Archodex Canary";
+const CANARY_RESOURCE_ID: &str = "canary";
+const PROBE_RESOURCE_TYPE: &str = "Archodex Canary Probe";
+const PROBE_RESOURCE_ID: &str = "probe";
+const PROBE_EVENT_TYPE: &str = "Probe";
+
+#[derive(Deserialize)]
+struct ProbedResource {
+    probe_id: Option<String>,
+    last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct ProbedEvent {
+    last_seen_at: DateTime<Utc>,
+}
+
+/// Runs the canary probe until the process exits.
+///
+/// Each probe reports a resource and an event tagged with a unique probe ID into the canary account, then reads them
+/// back, logging the ingestion and query latencies and whether the data read back matches what was reported. Operators
+/// should alert on `canary_ok = false` probe results and on missing probe results.
+pub async fn run() {
+    let Some(account_id) = Env::canary_account_id() else {
+        return;
+    };
+
+    info!(account_id, "Starting canary probe");
+
+    loop {
+        let start = Instant::now();
+
+        match probe(account_id).instrument(info_span!("canary")).await {
+            Ok(latencies) => info!(
+                account_id,
+                canary_ok = true,
+                ingest_latency_ms = latencies.ingest.as_millis(),
+                query_latency_ms = latencies.query.as_millis(),
+                total_latency_ms = start.elapsed().as_millis(),
+                "Canary probe succeeded"
+            ),
+            Err(err) => error!(
+                account_id,
+                canary_ok = false,
+                total_latency_ms = start.elapsed().as_millis(),
+                ?err,
+                "Canary probe failed"
+            ),
+        }
+
+        tokio::time::sleep(Env::canary_interval()).await;
+    }
+}
+
+struct ProbeLatencies {
+    ingest: Duration,
+    query: Duration,
+}
+
+#[instrument(err)]
+async fn probe(account_id: &str) -> Result<ProbeLatencies> {
+    let Some(account) = get_account(account_id).await? else {
+        bail!("Canary account {account_id} does not exist");
+    };
+
+    let probe_id = Uuid::now_v7().to_string();
+    let probed_at = clock::now();
+
+    let canary_resource_id = serde_json::json!([
+        { "type": CANARY_RESOURCE_TYPE, "id": CANARY_RESOURCE_ID },
+    ]);
+    let probe_resource_id = serde_json::json!([
+        { "type": CANARY_RESOURCE_TYPE, "id": CANARY_RESOURCE_ID },
+        { "type": PROBE_RESOURCE_TYPE, "id": PROBE_RESOURCE_ID },
+    ]);
+
+    // The report is deserialized from JSON like reports from agents, so the probe covers request parsing too
+    let req: Request = serde_json::from_value(serde_json::json!({
+        "resource_captures": [{
+            "type": CANARY_RESOURCE_TYPE,
+            "id": CANARY_RESOURCE_ID,
+            "globally_unique": true,
+            "first_seen_at": probed_at,
+            "last_seen_at": probed_at,
+            "contains": [{
+                "type": PROBE_RESOURCE_TYPE,
+                "id": PROBE_RESOURCE_ID,
+                "first_seen_at": probed_at,
+                "last_seen_at": probed_at,
+                "attributes": { "probe_id": probe_id },
+            }],
+        }],
+        "event_captures": [{
+            "principals": [{ "id": canary_resource_id }],
+            "resources": [probe_resource_id],
+            "events": [{
+                "type": PROBE_EVENT_TYPE,
+                "first_seen_at": probed_at,
+                "last_seen_at": probed_at,
+            }],
+        }],
+    }))
+    .context("Failed to build canary report")?;
+
+    let ingest_start = Instant::now();
+    report::ingest(&account, req).await?;
+    let ingest = ingest_start.elapsed();
+
+    let query_start = Instant::now();
+    let mut res = account
+        .resources_db()
+        .await?
+        .query("SELECT attributes.probe_id AS probe_id, last_seen_at FROM ONLY type::thing('resource', $probe_resource_id)")
+        .query("SELECT last_seen_at FROM event WHERE in = type::thing('resource', $canary_resource_id) AND out = type::thing('resource', $probe_resource_id) AND type = $event_type")
+        .bind((
+            "canary_resource_id",
+            [[CANARY_RESOURCE_TYPE, CANARY_RESOURCE_ID]],
+        ))
+        .bind((
+            "probe_resource_id",
+            [
+                [CANARY_RESOURCE_TYPE, CANARY_RESOURCE_ID],
+                [PROBE_RESOURCE_TYPE, PROBE_RESOURCE_ID],
+            ],
+        ))
+        .bind(("event_type", PROBE_EVENT_TYPE))
+        .await?
+        .check_first_real_error()?;
+    let query = query_start.elapsed();
+
+    let Some(resource) = res.take::<Option<ProbedResource>>(0)? else {
+        bail!("Canary probe resource was not found after ingestion");
+    };
+
+    if resource.probe_id.as_deref() != Some(probe_id.as_str()) {
+        bail!(
+            "Canary probe resource has probe ID {:?}, expected {probe_id:?}",
+            resource.probe_id
+        );
+    }
+
+    if resource.last_seen_at < probed_at {
+        bail!(
+            "Canary probe resource was last seen at {}, expected at least {probed_at}",
+            resource.last_seen_at
+        );
+    }
+
+    let Some(event) = res.take::<Option<ProbedEvent>>(1)? else {
+        bail!("Canary probe event was not found after ingestion");
+    };
+
+    if event.last_seen_at < probed_at {
+        bail!(
+            "Canary probe event was last seen at {}, expected at least {probed_at}",
+            event.last_seen_at
+        );
+    }
+
+    Ok(ProbeLatencies { ingest, query })
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use rand::Rng as _;
+use tracing::warn;
+
+use archodex_error::{anyhow, bad_request};
+
+use crate::{Result, env::Env};
+
+// Comma-separated faults to inject into the request, e.g. `db_error, auth_failure, latency_ms=500`
+const CHAOS_HEADER: &str = "x-archodex-chaos";
+
+#[derive(Clone, Copy, Debug, Default)]
+struct RequestFaults {
+    db_error: bool,
+    auth_failure: bool,
+    latency: Option<Duration>,
+}
+
+tokio::task_local! {
+    static REQUEST_FAULTS: RequestFaults;
+}
+
+fn parse_request_faults(header: &str) -> Result<RequestFaults> {
+    let mut faults = RequestFaults::default();
+
+    for fault in header
+        .split(',')
+        .map(str::trim)
+        .filter(|fault| !fault.is_empty())
+    {
+        match fault.split_once('=') {
+            None if fault == "db_error" => faults.db_error = true,
+            None if fault == "auth_failure" => faults.auth_failure = true,
+            Some(("latency_ms", latency_ms)) => {
+                let Ok(latency_ms) = latency_ms.trim().parse::<u64>() else {
+                    bad_request!("Invalid latency_ms in {CHAOS_HEADER} header");
+                };
+                faults.latency = Some(Duration::from_millis(latency_ms));
+            }
+            _ => bad_request!("Unknown fault {fault:?} in {CHAOS_HEADER} header"),
+        }
+    }
+
+    Ok(faults)
+}
+
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && rand::thread_rng().gen_bool(probability.min(1.0))
+}
+
+// Injects faults for resilience testing. Faults are injected at the resources database and authentication seams, either
+// randomly with the probabilities configured by the `ARCHODEX_CHAOS_*` environment variables or for a single request
+// with the `X-Archodex-Chaos` header. This middleware delays the request if latency is injected and makes the request's
+// faults visible to the seams below. It is only compiled with the `chaos` feature, which must never be enabled in
+// production builds since any client may request faults.
+pub(crate) async fn inject(req: Request, next: Next) -> Result<Response> {
+    let mut faults = match req.headers().get(CHAOS_HEADER) {
+        Some(header) => {
+            let Ok(header) = header.to_str() else {
+                bad_request!("Invalid {CHAOS_HEADER} header");
+            };
+            parse_request_faults(header)?
+        }
+        None => RequestFaults::default(),
+    };
+
+    let config = Env::chaos();
+    if faults.latency.is_none() && roll(config.latency_probability) {
+        faults.latency = Some(config.latency);
+    }
+
+    if let Some(latency) = faults.latency {
+        warn!(?latency, "Injecting request latency");
+        tokio::time::sleep(latency).await;
+    }
+
+    Ok(REQUEST_FAULTS.scope(faults, next.run(req)).await)
+}
+
+// Called when connecting to a resources database. Background workers outside of requests only see random faults.
+pub(crate) fn inject_db_error() -> anyhow::Result<()> {
+    let requested = REQUEST_FAULTS
+        .try_with(|faults| faults.db_error)
+        .unwrap_or(false);
+
+    if requested || roll(Env::chaos().db_error_probability) {
+        warn!("Injecting resources database error");
+        anyhow::bail!("Injected resources database error");
+    }
+
+    Ok(())
+}
+
+// Called before authenticating a request. Returns whether authentication should fail.
+pub(crate) fn inject_auth_failure() -> bool {
+    let requested = REQUEST_FAULTS
+        .try_with(|faults| faults.auth_failure)
+        .unwrap_or(false);
+
+    if requested || roll(Env::chaos().auth_failure_probability) {
+        warn!("Injecting authentication failure");
+        return true;
+    }
+
+    false
+}
@@ -0,0 +1,112 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{HeaderMap, header::FORWARDED},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Span;
+
+use crate::env::{ClientIpHeader, Env};
+
+// Address of the client that sent a request, resolved through trusted proxies. `None` when the address of the peer
+// isn't known.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientIp(pub(crate) Option<IpAddr>);
+
+fn is_trusted_proxy(ip: IpAddr) -> bool {
+    Env::trusted_proxies()
+        .iter()
+        .any(|network| network.contains(&ip))
+}
+
+// Parses an address of a forwarding header, which may include a port (e.g. `192.0.2.1:4711` or `[2001:db8::1]:4711`).
+// Returns `None` for addresses that aren't IPs, e.g. `unknown` or obfuscated identifiers in `Forwarded` headers.
+fn parse_forwarded_address(address: &str) -> Option<IpAddr> {
+    let address = address.trim().trim_matches('"');
+
+    address
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| {
+            address
+                .parse::<SocketAddr>()
+                .ok()
+                .map(|address| address.ip())
+        })
+        .or_else(|| {
+            address
+                .strip_prefix('[')
+                .and_then(|address| address.strip_suffix(']'))
+                .and_then(|address| address.parse::<IpAddr>().ok())
+        })
+}
+
+// Addresses requests were forwarded for, ordered from the client to the proxy nearest to this backend
+fn forwarded_addresses(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = match Env::client_ip_header() {
+        ClientIpHeader::XForwardedFor => headers.get_all("x-forwarded-for"),
+        ClientIpHeader::Forwarded => headers.get_all(FORWARDED),
+    };
+
+    let elements = values
+        .iter()
+        .map(|value| value.to_str().unwrap_or_default())
+        .flat_map(|value| value.split(','));
+
+    match Env::client_ip_header() {
+        ClientIpHeader::XForwardedFor => elements.map(parse_forwarded_address).collect(),
+        ClientIpHeader::Forwarded => elements
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| parse_forwarded_address(value))
+            })
+            .collect(),
+    }
+}
+
+// Resolves the client's address from the peer's address. Forwarding headers are only believed when they were received
+// from trusted proxies: the addresses are walked from the nearest proxy towards the client, and the first address that
+// isn't a trusted proxy is the client. Anything further along could have been forged by the client.
+fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let mut client_ip = peer;
+
+    if !is_trusted_proxy(client_ip) {
+        return client_ip;
+    }
+
+    for address in forwarded_addresses(headers).into_iter().rev() {
+        // The walk stops at addresses that can't be parsed, as the client can't be identified beyond them
+        let Some(address) = address else {
+            break;
+        };
+
+        client_ip = address;
+
+        if !is_trusted_proxy(client_ip) {
+            break;
+        }
+    }
+
+    client_ip
+}
+
+// Resolves the client's address and records it in the request span, so it is logged with every event of the request
+pub(crate) async fn resolve(mut req: Request, next: Next) -> Response {
+    let client_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| resolve_client_ip(peer.ip(), req.headers()));
+
+    if let Some(client_ip) = client_ip {
+        Span::current().record("client_ip", tracing::field::display(client_ip));
+    }
+
+    req.extensions_mut().insert(ClientIp(client_ip));
+
+    next.run(req).await
+}
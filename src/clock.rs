@@ -0,0 +1,73 @@
+use std::sync::{Arc, LazyLock, RwLock};
+
+use chrono::{DateTime, Utc};
+
+/// Source of the current time for the backend.
+///
+/// All wall-clock reads, including timestamps written by database queries, go through the process-wide clock so tests
+/// of time-dependent behavior (e.g. debug capture expiry and event delivery backoff) can control time instead of
+/// sleeping. Elapsed-time measurements use `std::time::Instant` and are not affected.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The default clock, which reads the system time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+static CLOCK: LazyLock<RwLock<Arc<dyn Clock>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(SystemClock)));
+
+/// Replaces the process-wide clock.
+///
+/// # Panics
+///
+/// Will panic if the clock lock was poisoned by a panic in another thread.
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    *CLOCK.write().expect("Clock lock should not be poisoned") = clock;
+}
+
+#[cfg(test)]
+thread_local! {
+    // Overrides the process-wide clock for the test running on this thread
+    static TEST_CLOCK: std::cell::RefCell<Option<Arc<dyn Clock>>> = const { std::cell::RefCell::new(None) };
+}
+
+// Reads the time on the current thread from `clock` instead of the process-wide clock
+#[cfg(test)]
+pub(crate) fn set_test_clock(clock: Arc<dyn Clock>) {
+    TEST_CLOCK.set(Some(clock));
+}
+
+// A clock stopped at a fixed time
+#[cfg(test)]
+pub(crate) struct FixedClock(pub(crate) DateTime<Utc>);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+pub(crate) fn now() -> DateTime<Utc> {
+    #[cfg(test)]
+    if let Some(clock) = TEST_CLOCK.with_borrow(Clone::clone) {
+        return clock.now();
+    }
+
+    CLOCK
+        .read()
+        .expect("Clock lock should not be poisoned")
+        .now()
+}
+
+// The current time as a query binding value, for queries that would otherwise use SurrealDB's `time::now()`
+pub(crate) fn now_value() -> surrealdb::sql::Datetime {
+    surrealdb::sql::Datetime::from(now())
+}
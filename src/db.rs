@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::sync::LazyLock;
+use std::sync::{LazyLock, OnceLock};
 
 use axum::{
     Extension,
@@ -17,7 +17,7 @@ use tracing::{info, instrument, warn};
 
 use crate::{
     Result,
-    account::{Account, AccountQueries},
+    account::{Account, get_cached_account, resolve_account_id},
     auth::{DashboardAuth, ReportApiKeyAuth},
     env::Env,
 };
@@ -29,8 +29,8 @@ use archodex_error::{
 #[derive(Default)]
 pub(crate) struct BeginReadonlyStatement;
 
-impl surrealdb::opt::IntoQuery for BeginReadonlyStatement {
-    fn into_query(self) -> surrealdb::Result<Vec<surrealdb::sql::Statement>> {
+impl BeginReadonlyStatement {
+    fn statement() -> surrealdb::sql::Statement {
         let begin = {
             #[cfg(not(feature = "archodex-com"))]
             {
@@ -42,7 +42,73 @@ impl surrealdb::opt::IntoQuery for BeginReadonlyStatement {
             }
         };
 
-        Ok(vec![surrealdb::sql::Statement::Begin(begin)])
+        surrealdb::sql::Statement::Begin(begin)
+    }
+}
+
+impl surrealdb::opt::IntoQuery for BeginReadonlyStatement {
+    fn into_query(self) -> surrealdb::Result<Vec<surrealdb::sql::Statement>> {
+        Ok(vec![Self::statement()])
+    }
+}
+
+impl std::fmt::Display for BeginReadonlyStatement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", Self::statement())
+    }
+}
+
+// Starts a query on a connection or appends to one being built, so statements can be sent alone or as part of a larger
+// transaction
+pub(crate) trait QueryBuilder<'r, C: surrealdb::Connection> {
+    fn query(self, query: impl surrealdb::opt::IntoQuery) -> surrealdb::method::Query<'r, C>;
+}
+
+impl<'r, C: surrealdb::Connection> QueryBuilder<'r, C> for &'r Surreal<C> {
+    fn query(self, query: impl surrealdb::opt::IntoQuery) -> surrealdb::method::Query<'r, C> {
+        Surreal::query(self, query)
+    }
+}
+
+impl<'r, C: surrealdb::Connection> QueryBuilder<'r, C> for surrealdb::method::Query<'r, C> {
+    fn query(self, query: impl surrealdb::opt::IntoQuery) -> surrealdb::method::Query<'r, C> {
+        surrealdb::method::Query::query(self, query)
+    }
+}
+
+// A fixed query for a hot path, which is parsed once per process instead of every time it's sent. Prepared queries use
+// fixed binding names rather than names from `next_binding()`, so they must not be combined with other queries that
+// bind the same names.
+pub(crate) struct PreparedQuery {
+    text: &'static str,
+    statements: OnceLock<Vec<surrealdb::sql::Statement>>,
+}
+
+impl PreparedQuery {
+    pub(crate) const fn new(text: &'static str) -> Self {
+        Self {
+            text,
+            statements: OnceLock::new(),
+        }
+    }
+}
+
+impl surrealdb::opt::IntoQuery for &PreparedQuery {
+    fn into_query(self) -> surrealdb::Result<Vec<surrealdb::sql::Statement>> {
+        if let Some(statements) = self.statements.get() {
+            return Ok(statements.clone());
+        }
+
+        let statements = surrealdb::sql::parse(self.text)?.0.0;
+
+        // Concurrent first uses may both parse the query, which is harmless
+        Ok(self.statements.get_or_init(|| statements).clone())
+    }
+}
+
+impl std::fmt::Display for PreparedQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.text)
     }
 }
 
@@ -77,16 +143,65 @@ pub(crate) async fn migrate_service_data_database(
 }
 
 #[cfg(feature = "rocksdb")]
-#[derive(PartialEq)]
-enum ArchodexSurrealDatabase {
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum ArchodexSurrealDatabase {
     Accounts,
     Resources,
 }
 
+#[cfg(feature = "rocksdb")]
+impl ArchodexSurrealDatabase {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ArchodexSurrealDatabase::Accounts => "accounts",
+            ArchodexSurrealDatabase::Resources => "resources",
+        }
+    }
+}
+
 #[cfg(feature = "rocksdb")]
 struct NonconcurrentDBState {
     connection: Surreal<Any>,
-    current_database: ArchodexSurrealDatabase,
+    // `None` while the selected database is unknown, i.e. after a `use_db()` call was cancelled (e.g. by a request
+    // timeout) before it completed. The database is selected again by the next user of the connection.
+    current_database: Option<ArchodexSurrealDatabase>,
+}
+
+#[cfg(feature = "rocksdb")]
+impl NonconcurrentDBState {
+    async fn use_database(&mut self, database: ArchodexSurrealDatabase) -> anyhow::Result<()> {
+        if self.current_database != Some(database) {
+            self.current_database = None;
+            self.connection.use_db(database.name()).await?;
+            self.current_database = Some(database);
+        }
+
+        Ok(())
+    }
+}
+
+// Exclusive use of the embedded database's connection. Every other user of the database waits until this is dropped, so
+// e.g. a backup sees the accounts and resources databases at the same point in time.
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+pub(crate) struct EmbeddedDBLock(tokio::sync::MutexGuard<'static, NonconcurrentDBState>);
+
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+impl EmbeddedDBLock {
+    pub(crate) async fn database(
+        &mut self,
+        database: ArchodexSurrealDatabase,
+    ) -> anyhow::Result<&Surreal<Any>> {
+        self.0.use_database(database).await?;
+
+        Ok(&self.0.connection)
+    }
+}
+
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+pub(crate) async fn lock_embedded_db() -> anyhow::Result<EmbeddedDBLock> {
+    let connection = get_nonconcurrent_db_connection(Env::surrealdb_url()).await?;
+
+    Ok(EmbeddedDBLock(connection.lock().await))
 }
 
 #[cfg(feature = "rocksdb")]
@@ -116,16 +231,23 @@ async fn get_nonconcurrent_db_connection(
 
             db.use_ns("archodex").use_db("accounts").await?;
 
-            anyhow::Ok(Mutex::new(NonconcurrentDBState { connection: db, current_database: ArchodexSurrealDatabase::Accounts }))
+            anyhow::Ok(Mutex::new(NonconcurrentDBState { connection: db, current_database: Some(ArchodexSurrealDatabase::Accounts) }))
         })
         .await
 }
 
 #[instrument(err)]
-async fn get_concurrent_db_connection(url: &str) -> anyhow::Result<Surreal<Any>> {
-    static ACCOUNTS_DB: OnceCell<Surreal<Any>> = OnceCell::const_new();
+async fn get_concurrent_db_connection(shard: usize) -> anyhow::Result<Surreal<Any>> {
+    static ACCOUNTS_DBS: LazyLock<Vec<OnceCell<Surreal<Any>>>> = LazyLock::new(|| {
+        Env::accounts_shards()
+            .iter()
+            .map(|_| OnceCell::new())
+            .collect()
+    });
 
-    Ok(ACCOUNTS_DB
+    let url = &Env::accounts_shards()[shard].surrealdb_url;
+
+    Ok(ACCOUNTS_DBS[shard]
         .get_or_try_init(|| async {
             let db = surrealdb::engine::any::connect((
                 url,
@@ -150,6 +272,8 @@ async fn get_concurrent_db_connection(url: &str) -> anyhow::Result<Surreal<Any>>
 }
 
 pub(crate) enum DBConnection {
+    // The connection's lock is released when this is dropped, including when a timed out request's handler is dropped
+    // mid-query, so cancelled work doesn't block other requests
     #[cfg(feature = "rocksdb")]
     Nonconcurrent(tokio::sync::MappedMutexGuard<'static, Surreal<Any>>),
     Concurrent(Surreal<Any>),
@@ -167,22 +291,20 @@ impl std::ops::Deref for DBConnection {
     }
 }
 
+// Connects to the accounts database shard at index `shard` of `Env::accounts_shards()`
 #[instrument(err)]
-pub(crate) async fn accounts_db() -> Result<DBConnection> {
-    #[cfg(feature = "archodex-com")]
-    let surrealdb_url = Env::accounts_surrealdb_url();
-    #[cfg(not(feature = "archodex-com"))]
-    let surrealdb_url = Env::surrealdb_url();
+pub(crate) async fn accounts_db_shard(shard: usize) -> Result<DBConnection> {
+    #[cfg(feature = "rocksdb")]
+    let surrealdb_url = Env::accounts_shards()[shard].surrealdb_url.as_str();
 
     #[cfg(feature = "rocksdb")]
     if surrealdb_url.starts_with("rocksdb:") {
         let connection = get_nonconcurrent_db_connection(surrealdb_url).await?;
         let mut db_state = connection.lock().await;
 
-        if db_state.current_database != ArchodexSurrealDatabase::Accounts {
-            db_state.connection.use_db("accounts").await?;
-            db_state.current_database = ArchodexSurrealDatabase::Accounts;
-        }
+        db_state
+            .use_database(ArchodexSurrealDatabase::Accounts)
+            .await?;
 
         return Ok(DBConnection::Nonconcurrent(
             tokio::sync::MutexGuard::try_map(db_state, |state| Some(&mut state.connection))
@@ -191,27 +313,75 @@ pub(crate) async fn accounts_db() -> Result<DBConnection> {
     }
 
     Ok(DBConnection::Concurrent(
-        get_concurrent_db_connection(surrealdb_url).await?,
+        get_concurrent_db_connection(shard).await?,
+    ))
+}
+
+// Connects to the first accounts database shard, which holds the records that aren't scoped to an account, i.e. account
+// ID reservations, Kafka consumer offsets, health checks and bulk operations. Self-hosted backends have a single shard, which holds every
+// record.
+pub(crate) async fn primary_accounts_db() -> Result<DBConnection> {
+    accounts_db_shard(0).await
+}
+
+// Connects to the accounts database shard holding an account's records
+pub(crate) async fn accounts_db_for_account(account_id: &str) -> Result<DBConnection> {
+    let account_id = account_id
+        .parse::<u64>()
+        .with_context(|| format!("Invalid account ID {account_id:?}"))?;
+
+    accounts_db_shard(migrator::accounts_shard_index(
+        Env::accounts_shards(),
+        account_id,
     ))
+    .await
 }
 
+// Runs a query spanning accounts against every accounts database shard and concatenates the results. Shards are queried
+// one at a time, so only one connection is held at once.
+pub(crate) async fn query_accounts_db_shards<T: IntoIterator, F: Future<Output = Result<T>>>(
+    query: impl Fn(DBConnection) -> F,
+) -> Result<Vec<T::Item>> {
+    let mut results = vec![];
+
+    for shard in 0..Env::accounts_shards().len() {
+        results.extend(query(accounts_db_shard(shard).await?).await?);
+    }
+
+    Ok(results)
+}
+
+// Runs a statement spanning accounts against every accounts database shard, one at a time
+pub(crate) async fn for_each_accounts_db_shard<F: Future<Output = Result<()>>>(
+    statement: impl Fn(DBConnection) -> F,
+) -> Result<()> {
+    for shard in 0..Env::accounts_shards().len() {
+        statement(accounts_db_shard(shard).await?).await?;
+    }
+
+    Ok(())
+}
+
+// Connections to the SurrealDB instances holding resources databases, shared by the accounts on each instance
+static RESOURCES_DBS_BY_URL: LazyLock<RwLock<HashMap<String, Surreal<Any>>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
 #[instrument(err)]
 pub(crate) async fn resources_db(
     service_data_surrealdb_url: &str,
     account_id: &str,
 ) -> anyhow::Result<DBConnection> {
-    static DBS_BY_URL: LazyLock<RwLock<HashMap<String, Surreal<Any>>>> =
-        LazyLock::new(|| RwLock::new(HashMap::new()));
+    #[cfg(feature = "chaos")]
+    crate::chaos::inject_db_error()?;
 
     #[cfg(feature = "rocksdb")]
     if service_data_surrealdb_url.starts_with("rocksdb:") {
         let connection = get_nonconcurrent_db_connection(service_data_surrealdb_url).await?;
         let mut db_state = connection.lock().await;
 
-        if db_state.current_database != ArchodexSurrealDatabase::Resources {
-            db_state.connection.use_db("resources").await?;
-            db_state.current_database = ArchodexSurrealDatabase::Resources;
-        }
+        db_state
+            .use_database(ArchodexSurrealDatabase::Resources)
+            .await?;
 
         return Ok(DBConnection::Nonconcurrent(
             tokio::sync::MutexGuard::try_map(db_state, |state| Some(&mut state.connection))
@@ -219,14 +389,14 @@ pub(crate) async fn resources_db(
         ));
     }
 
-    let dbs_by_url = DBS_BY_URL.read().await;
+    let dbs_by_url = RESOURCES_DBS_BY_URL.read().await;
 
     let db = if let Some(db) = dbs_by_url.get(service_data_surrealdb_url) {
         db.clone()
     } else {
         drop(dbs_by_url);
 
-        let mut dbs_by_url = DBS_BY_URL.write().await;
+        let mut dbs_by_url = RESOURCES_DBS_BY_URL.write().await;
 
         if let Some(db) = dbs_by_url.get(service_data_surrealdb_url) {
             db.clone()
@@ -262,7 +432,44 @@ pub(crate) async fn resources_db(
     Ok(DBConnection::Concurrent(db))
 }
 
-#[instrument(err, skip_all)]
+// Checks that resources databases can be connected to and queried. Self-hosted backends check the resources database
+// shared by their accounts. archodex.com accounts' resources databases are spread over service data instances that are
+// only known once their accounts are used, so each instance this backend has connected to is checked instead.
+#[cfg(not(feature = "archodex-com"))]
+pub(crate) async fn check_resources_dbs() -> Result<()> {
+    // Self-hosted resources databases aren't namespaced by account, so no account ID is needed
+    resources_db(Env::surrealdb_url(), "")
+        .await?
+        .query("RETURN true")
+        .await?
+        .check_first_real_error()?;
+
+    Ok(())
+}
+
+#[cfg(feature = "archodex-com")]
+pub(crate) async fn check_resources_dbs() -> Result<()> {
+    let dbs = RESOURCES_DBS_BY_URL
+        .read()
+        .await
+        .iter()
+        .map(|(url, db)| (url.clone(), db.clone()))
+        .collect::<Vec<_>>();
+
+    for (url, db) in dbs {
+        db.query("RETURN true")
+            .await
+            .and_then(surrealdb::Response::check)
+            .with_context(|| format!("Failed to query SurrealDB instance {url}"))?;
+    }
+
+    Ok(())
+}
+
+// Validates access and inserts the `Account` record into request extensions. No resources database connection is
+// opened here; handlers call `Account::resources_db()` only when they actually query it, so routes like account
+// deletion validation don't pay for a connection they never use.
+#[instrument(err, skip_all, fields(account_id))]
 pub(crate) async fn dashboard_auth_account(
     Extension(auth): Extension<DashboardAuth>,
     Path(params): Path<HashMap<String, String>>,
@@ -273,17 +480,14 @@ pub(crate) async fn dashboard_auth_account(
         .get("account_id")
         .expect(":account_id should be in path for dashboard account authentication");
 
-    auth.validate_account_access(account_id).await?;
+    let account_id = resolve_account_id(account_id).await?;
 
-    let account = accounts_db()
-        .await?
-        .get_account_by_id(account_id.to_owned())
-        .await?
-        .check_first_real_error()?
-        .take::<Option<Account>>(0)
-        .with_context(|| format!("Failed to get record for account ID {account_id:?}"))?;
+    // Handlers run within this span, so errors they log are correlated with the account
+    tracing::Span::current().record("account_id", &account_id);
 
-    let Some(account) = account else {
+    auth.validate_account_access(&account_id).await?;
+
+    let Some(account) = get_cached_account(&account_id).await? else {
         not_found!("Account not found");
     };
 
@@ -292,30 +496,53 @@ pub(crate) async fn dashboard_auth_account(
     Ok(next.run(req).await)
 }
 
-#[instrument(err, skip_all)]
+#[instrument(err, skip_all, fields(account_id = auth.account_id()))]
 pub(crate) async fn report_api_key_account(
     Extension(auth): Extension<ReportApiKeyAuth>,
     mut req: Request,
     next: Next,
 ) -> Result<Response> {
-    let account = accounts_db()
-        .await?
-        .get_account_by_id(auth.account_id().to_owned())
-        .await?
-        .check_first_real_error()?
-        .take::<Option<Account>>(0)
-        .context("Failed to get account record")?;
+    let account = account_for_report_api_key(&auth).await?;
+
+    req.extensions_mut().insert(account);
+
+    Ok(next.run(req).await)
+}
 
-    let Some(account) = account else {
+// Fetches the account a report API key belongs to and verifies the key has not been revoked.
+#[instrument(err, skip_all)]
+pub(crate) async fn account_for_report_api_key(auth: &ReportApiKeyAuth) -> Result<Account> {
+    let Some(account) = get_cached_account(auth.account_id()).await? else {
         not_found!("Account not found");
     };
 
     auth.validate_account_access(&*(account.resources_db().await?))
         .await?;
 
-    req.extensions_mut().insert(account);
+    Ok(account)
+}
 
-    Ok(next.run(req).await)
+// Whether a query failed because it created a record whose ID is already taken. Embedded databases return a structured
+// error, but errors from remote databases only carry the message.
+pub(crate) fn is_record_exists_error(err: &surrealdb::Error) -> bool {
+    match err {
+        surrealdb::Error::Db(surrealdb::error::Db::RecordExists { .. }) => true,
+        surrealdb::Error::Api(surrealdb::error::Api::Query(message)) => {
+            message.starts_with("Database record `") && message.ends_with("` already exists")
+        }
+        _ => false,
+    }
+}
+
+// Whether a transaction failed because it conflicted with a concurrent transaction, in which case it can be retried
+pub(crate) fn is_transaction_conflict_error(err: &surrealdb::Error) -> bool {
+    match err {
+        surrealdb::Error::Db(surrealdb::error::Db::TxRetryable) => true,
+        surrealdb::Error::Api(surrealdb::error::Api::Query(message)) => {
+            *message == surrealdb::error::Db::TxRetryable.to_string()
+        }
+        _ => false,
+    }
 }
 
 // Like surrealdb::Response::check, but skips over QueryNotExecuted errors.
@@ -0,0 +1,556 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Extension, Json,
+    body::{Body, Bytes},
+    extract::{MatchedPath, Request},
+    http::{HeaderMap, HeaderValue, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt as _, channel::Channel};
+use serde::{Deserialize, Serialize};
+use surrealdb::Uuid;
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+
+use archodex_error::{anyhow::Context as _, bad_request};
+
+use crate::{
+    Result,
+    account::{Account, AccountQueries, external_account_id, invalidate_cached_account},
+    auth::DashboardAuth,
+    clock,
+    db::{QueryCheckFirstRealError, accounts_db_for_account},
+    next_binding, surrealdb_deserializers,
+};
+
+const MAX_CAPTURE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Exchanges with bodies larger than this are not buffered, and are passed through without being captured
+const MAX_BUFFERED_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+// Only the start of larger bodies is stored
+const MAX_STORED_BODY_SIZE: usize = 1024 * 1024;
+
+// Credentials are never stored, even though the owner consented to capturing their traffic
+const REDACTED_HEADERS: [&str; 4] = [
+    "authorization",
+    "cookie",
+    "set-cookie",
+    "x-archodex-iam-request",
+];
+
+// Routes whose bodies contain secrets, e.g. newly created report API keys and personal access tokens, by method and the
+// end of their path. Their exchanges are captured with redacted bodies.
+const REDACTED_BODY_ROUTES: [(&str, &str); 3] = [
+    ("POST", "/report_api_keys"),
+    ("PUT", "/config"),
+    ("POST", "/personal_access_tokens"),
+];
+
+const REDACTED_BODY: &[u8] = b"[REDACTED]";
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct EnableDebugCaptureRequest {
+    duration_minutes: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DebugCaptureStatus {
+    enabled_until: Option<DateTime<Utc>>,
+}
+
+// Enables debug capture for the account until the requested duration elapses, discarding any earlier captures. Only the
+// account owner may enable capture, since captured requests include report contents and dashboard traffic.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/debug_capture",
+    tag = "debug_capture",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = EnableDebugCaptureRequest,
+    responses((status = 200, body = DebugCaptureStatus))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn enable_debug_capture(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<EnableDebugCaptureRequest>,
+) -> Result<Json<DebugCaptureStatus>> {
+    let duration = Duration::from_secs(req.duration_minutes.saturating_mul(60));
+    if duration.is_zero() || duration > MAX_CAPTURE_DURATION {
+        bad_request!(
+            "duration_minutes must be between 1 and {}",
+            MAX_CAPTURE_DURATION.as_secs() / 60
+        );
+    }
+
+    auth.validate_account_owner(account.id()).await?;
+
+    account
+        .resources_db()
+        .await?
+        .query("DELETE debug_capture")
+        .await?
+        .check_first_real_error()?;
+
+    let enabled_until = clock::now()
+        + chrono::Duration::from_std(duration)
+            .context("Capture duration should fit in a chrono Duration")?;
+
+    accounts_db_for_account(account.id())
+        .await?
+        .set_account_debug_capture_until_query(&account, Some(enabled_until), auth.principal())
+        .await?
+        .check_first_real_error()?;
+
+    invalidate_cached_account(account.id());
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        %enabled_until,
+        "Enabled debug capture"
+    );
+
+    Ok(Json(DebugCaptureStatus {
+        enabled_until: Some(enabled_until),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/debug_capture",
+    tag = "debug_capture",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = DebugCaptureStatus))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn get_debug_capture_status(
+    Extension(account): Extension<Account>,
+) -> Result<Json<DebugCaptureStatus>> {
+    Ok(Json(DebugCaptureStatus {
+        enabled_until: account
+            .debug_capture_until()
+            .filter(|enabled_until| *enabled_until > clock::now()),
+    }))
+}
+
+// Disables debug capture and deletes everything that was captured
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}/debug_capture",
+    tag = "debug_capture",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn disable_debug_capture(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+) -> Result<Json<()>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    accounts_db_for_account(account.id())
+        .await?
+        .set_account_debug_capture_until_query(&account, None, auth.principal())
+        .await?
+        .check_first_real_error()?;
+
+    invalidate_cached_account(account.id());
+
+    account
+        .resources_db()
+        .await?
+        .query("DELETE debug_capture")
+        .await?
+        .check_first_real_error()?;
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        "Disabled debug capture"
+    );
+
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+struct DebugCaptureRecord {
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    id: Uuid,
+    method: String,
+    uri: String,
+    request_headers: BTreeMap<String, String>,
+    #[serde(deserialize_with = "surrealdb_deserializers::bytes::deserialize")]
+    request_body: Vec<u8>,
+    request_body_truncated: bool,
+    status: u16,
+    response_headers: BTreeMap<String, String>,
+    #[serde(deserialize_with = "surrealdb_deserializers::bytes::deserialize")]
+    response_body: Vec<u8>,
+    response_body_truncated: bool,
+    duration_ms: u64,
+    captured_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct DebugCaptureExchange {
+    id: Uuid,
+    captured_at: DateTime<Utc>,
+    duration_ms: u64,
+    request: DebugCaptureMessage,
+    response: DebugCaptureMessage,
+}
+
+#[derive(Serialize)]
+struct DebugCaptureMessage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    headers: BTreeMap<String, String>,
+    body_base64: String,
+    body_truncated: bool,
+}
+
+impl From<DebugCaptureRecord> for DebugCaptureExchange {
+    fn from(record: DebugCaptureRecord) -> Self {
+        Self {
+            id: record.id,
+            captured_at: record.captured_at,
+            duration_ms: record.duration_ms,
+            request: DebugCaptureMessage {
+                method: Some(record.method),
+                uri: Some(record.uri),
+                status: None,
+                headers: record.request_headers,
+                body_base64: BASE64_STANDARD.encode(record.request_body),
+                body_truncated: record.request_body_truncated,
+            },
+            response: DebugCaptureMessage {
+                method: None,
+                uri: None,
+                status: Some(record.status),
+                headers: record.response_headers,
+                body_base64: BASE64_STANDARD.encode(record.response_body),
+                body_truncated: record.response_body_truncated,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DebugCaptureBundle {
+    account_id: String,
+    generated_at: DateTime<Utc>,
+    exchanges: Vec<DebugCaptureExchange>,
+}
+
+// Returns everything captured for the account as a JSON file download
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/debug_capture/bundle",
+    tag = "debug_capture",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, description = "Captured requests and responses as a JSON file download", content_type = "application/json"))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn download_debug_capture_bundle(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+) -> Result<Response> {
+    auth.validate_account_owner(account.id()).await?;
+
+    let exchanges = account
+        .resources_db()
+        .await?
+        .query("SELECT * FROM debug_capture ORDER BY id")
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<DebugCaptureRecord>>(0)?
+        .into_iter()
+        .map(DebugCaptureExchange::from)
+        .collect();
+
+    let generated_at = clock::now();
+
+    let account_id = external_account_id(account.id()).unwrap_or_else(|| account.id().to_string());
+
+    let bundle = DebugCaptureBundle {
+        account_id,
+        generated_at,
+        exchanges,
+    };
+
+    let content_disposition = format!(
+        "attachment; filename=\"archodex-debug-capture-{}-{}.json\"",
+        bundle.account_id,
+        generated_at.format("%Y%m%dT%H%M%SZ")
+    );
+
+    Ok((
+        [(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_str(&content_disposition)
+                .context("Content-Disposition header should be valid")?,
+        )],
+        Json(bundle),
+    )
+        .into_response())
+}
+
+fn captured_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut captured = BTreeMap::<String, String>::new();
+
+    for (name, value) in headers {
+        let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+            "[REDACTED]".to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+
+        captured
+            .entry(name.as_str().to_string())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+
+    captured
+}
+
+// Whether the request's route may carry secrets in its bodies. Requests whose route is unknown are assumed to.
+fn has_secret_bodies(req: &Request) -> bool {
+    req.extensions().get::<MatchedPath>().is_none_or(|path| {
+        let path = path.as_str();
+
+        // Event destinations are configured with webhook secrets and credentials
+        path.contains("/event_destination")
+            || REDACTED_BODY_ROUTES
+                .iter()
+                .any(|(method, route)| req.method().as_str() == *method && path.ends_with(route))
+    })
+}
+
+// Reads the whole body if it is at most `MAX_BUFFERED_BODY_SIZE` bytes. Otherwise returns a body that replays what was
+// read followed by the rest, so the body can be passed on without being captured.
+async fn buffer_body(mut body: Body) -> std::result::Result<Bytes, Body> {
+    let mut buffered = Vec::new();
+
+    while let Some(frame) = body.frame().await {
+        match frame {
+            Ok(frame) => match frame.into_data() {
+                Ok(data) if buffered.len() + data.len() <= MAX_BUFFERED_BODY_SIZE => {
+                    buffered.extend_from_slice(&data);
+                }
+                Ok(data) => {
+                    buffered.extend_from_slice(&data);
+                    return Err(replay_body(buffered, None, body));
+                }
+                // Trailers aren't captured
+                Err(_) => {}
+            },
+            Err(err) => return Err(replay_body(buffered, Some(err), body)),
+        }
+    }
+
+    Ok(Bytes::from(buffered))
+}
+
+// Returns a body of the `buffered` bytes, followed by `err` if reading more of the body failed, or else by the `rest` of
+// the body
+fn replay_body(buffered: Vec<u8>, err: Option<axum::Error>, mut rest: Body) -> Body {
+    let (mut sender, body) = Channel::<Bytes, axum::Error>::new(1);
+
+    tokio::spawn(async move {
+        if sender.send_data(Bytes::from(buffered)).await.is_err() {
+            return;
+        }
+
+        if let Some(err) = err {
+            sender.abort(err);
+            return;
+        }
+
+        while let Some(frame) = rest.frame().await {
+            match frame {
+                Ok(frame) => {
+                    if sender.send(frame).await.is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    sender.abort(err);
+                    return;
+                }
+            }
+        }
+    });
+
+    Body::new(body)
+}
+
+fn stored_body(body: &[u8]) -> (surrealdb::sql::Bytes, bool) {
+    let truncated = body.len() > MAX_STORED_BODY_SIZE;
+    let stored = &body[..body.len().min(MAX_STORED_BODY_SIZE)];
+
+    (surrealdb::sql::Bytes::from(stored.to_vec()), truncated)
+}
+
+// Records request/response pairs for accounts that have debug capture enabled. Must be layered inside the middleware
+// that inserts the request's `Account` extension. Exchanges with bodies too large to buffer are passed through without
+// being captured.
+pub(crate) async fn capture(req: Request, next: Next) -> Result<Response> {
+    let Some(account) = req.extensions().get::<Account>().cloned() else {
+        return Ok(next.run(req).await);
+    };
+
+    // Don't capture the endpoints used to manage and download captures, or data exports, imports, and streamed reports,
+    // which are too large to buffer
+    let capture_active = account
+        .debug_capture_until()
+        .is_some_and(|enabled_until| enabled_until > clock::now())
+        && !req.uri().path().contains("/debug_capture")
+        && !req.uri().path().ends_with("/export")
+        && !req.uri().path().ends_with("/import")
+        && !req.uri().path().ends_with("/report/stream");
+
+    if !capture_active {
+        return Ok(next.run(req).await);
+    }
+
+    let redact_bodies = has_secret_bodies(&req);
+
+    let (parts, body) = req.into_parts();
+
+    let (request_body, body) = if redact_bodies {
+        (Bytes::from_static(REDACTED_BODY), body)
+    } else {
+        match buffer_body(body).await {
+            Ok(request_body) => (request_body.clone(), Body::from(request_body)),
+            Err(body) => {
+                warn!(
+                    account_id = account.id(),
+                    "Request body is too large to capture, not capturing the exchange"
+                );
+                return Ok(next.run(Request::from_parts(parts, body)).await);
+            }
+        }
+    };
+
+    let method = parts.method.to_string();
+    let uri = parts.uri.to_string();
+    let request_headers = captured_headers(&parts.headers);
+
+    let start = Instant::now();
+    let response = next.run(Request::from_parts(parts, body)).await;
+    let duration = start.elapsed();
+
+    let (parts, body) = response.into_parts();
+
+    let (response_body, body) = if redact_bodies {
+        (Bytes::from_static(REDACTED_BODY), body)
+    } else {
+        match buffer_body(body).await {
+            Ok(response_body) => (response_body.clone(), Body::from(response_body)),
+            Err(body) => {
+                warn!(
+                    account_id = account.id(),
+                    "Response body is too large to capture, not capturing the exchange"
+                );
+                return Ok(Response::from_parts(parts, body));
+            }
+        }
+    };
+
+    let status = parts.status.as_u16();
+    let response_headers = captured_headers(&parts.headers);
+
+    // Store the capture in the background so debug capture doesn't slow down responses
+    tokio::spawn(async move {
+        let (request_body, request_body_truncated) = stored_body(&request_body);
+        let (response_body, response_body_truncated) = stored_body(&response_body);
+
+        let capture_binding = next_binding();
+        let method_binding = next_binding();
+        let uri_binding = next_binding();
+        let request_headers_binding = next_binding();
+        let request_body_binding = next_binding();
+        let request_body_truncated_binding = next_binding();
+        let status_binding = next_binding();
+        let response_headers_binding = next_binding();
+        let response_body_binding = next_binding();
+        let response_body_truncated_binding = next_binding();
+        let duration_ms_binding = next_binding();
+
+        let result = async {
+            account
+                .resources_db()
+                .await?
+                .query(format!(
+                    "CREATE ${capture_binding} CONTENT {{
+                        method: ${method_binding},
+                        uri: ${uri_binding},
+                        request_headers: ${request_headers_binding},
+                        request_body: ${request_body_binding},
+                        request_body_truncated: ${request_body_truncated_binding},
+                        status: ${status_binding},
+                        response_headers: ${response_headers_binding},
+                        response_body: ${response_body_binding},
+                        response_body_truncated: ${response_body_truncated_binding},
+                        duration_ms: ${duration_ms_binding},
+                    }} RETURN NONE"
+                ))
+                .bind((
+                    capture_binding,
+                    surrealdb::sql::Thing::from((
+                        "debug_capture",
+                        surrealdb::sql::Id::Uuid(surrealdb::sql::Uuid::from(Uuid::now_v7())),
+                    )),
+                ))
+                .bind((method_binding, method))
+                .bind((uri_binding, uri))
+                .bind((request_headers_binding, request_headers))
+                .bind((request_body_binding, request_body))
+                .bind((request_body_truncated_binding, request_body_truncated))
+                .bind((status_binding, status))
+                .bind((response_headers_binding, response_headers))
+                .bind((response_body_binding, response_body))
+                .bind((response_body_truncated_binding, response_body_truncated))
+                .bind((
+                    duration_ms_binding,
+                    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+                ))
+                .await?
+                .check_first_real_error()?;
+
+            archodex_error::anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(err) = result {
+            warn!(
+                account_id = account.id(),
+                ?err,
+                "Failed to store debug capture"
+            );
+        }
+    });
+
+    Ok(Response::from_parts(parts, body))
+}
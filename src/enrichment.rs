@@ -0,0 +1,291 @@
+use std::{collections::BTreeSet, net::IpAddr};
+
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use archodex_error::bad_request;
+
+use crate::{
+    Result,
+    account::{Account, AccountQueries as _, invalidate_cached_account},
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError as _, accounts_db_for_account},
+    env::Env,
+    resource::ResourceIdPart,
+    router::RequestId,
+};
+
+// Built-in enrichers an account can enable. Enrichers run on every resource of a report before it is upserted.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EnricherKind {
+    // Adds `cloud_provider` to resources whose ID or `ip_address` attribute is an address within one of the
+    // `ARCHODEX_ENRICHMENT_CLOUD_IP_RANGES` networks
+    CloudProvider,
+    // Adds `account_name` to resources within an `Account` resource named in `ARCHODEX_ENRICHMENT_ACCOUNT_NAMES`
+    AccountName,
+    // Adds `image_registry`, `image_repository`, `image_tag`, and `image_digest` parsed from a resource's `image`
+    // attribute, e.g. `ghcr.io/archodex/agent:1.2.3@sha256:...`
+    ContainerImage,
+}
+
+impl EnricherKind {
+    fn enricher(self) -> &'static dyn Enricher {
+        match self {
+            EnricherKind::CloudProvider => &CloudProviderEnricher,
+            EnricherKind::AccountName => &AccountNameEnricher,
+            EnricherKind::ContainerImage => &ContainerImageEnricher,
+        }
+    }
+
+    // Whether the backend has the configuration the enricher needs
+    fn is_configured(self) -> bool {
+        match self {
+            EnricherKind::CloudProvider => !Env::enrichment_cloud_ip_ranges().is_empty(),
+            EnricherKind::AccountName => !Env::enrichment_account_names().is_empty(),
+            EnricherKind::ContainerImage => true,
+        }
+    }
+}
+
+// Adds attributes to reported resources before they are upserted. Enrichers only add attributes the agent didn't report,
+// so reported values always win.
+pub(crate) trait Enricher: Send + Sync {
+    // `resource_id` is the resource's full ID, from the root of its hierarchy. `attributes` holds the attributes the
+    // agent reported along with those added by enrichers that ran earlier.
+    fn enrich(&self, resource_id: &[ResourceIdPart], attributes: &mut Map<String, Value>);
+}
+
+fn add_attribute(attributes: &mut Map<String, Value>, key: &str, value: impl Into<Value>) {
+    if !attributes.contains_key(key) {
+        attributes.insert(key.to_string(), value.into());
+    }
+}
+
+struct CloudProviderEnricher;
+
+impl Enricher for CloudProviderEnricher {
+    fn enrich(&self, resource_id: &[ResourceIdPart], attributes: &mut Map<String, Value>) {
+        let address = resource_id
+            .last()
+            .and_then(|part| part.id.parse::<IpAddr>().ok())
+            .or_else(|| {
+                attributes
+                    .get("ip_address")
+                    .and_then(Value::as_str)
+                    .and_then(|address| address.parse::<IpAddr>().ok())
+            });
+
+        let Some(address) = address else {
+            return;
+        };
+
+        if let Some((_, provider)) = Env::enrichment_cloud_ip_ranges()
+            .iter()
+            .find(|(network, _)| network.contains(&address))
+        {
+            add_attribute(attributes, "cloud_provider", provider.as_str());
+        }
+    }
+}
+
+struct AccountNameEnricher;
+
+impl Enricher for AccountNameEnricher {
+    fn enrich(&self, resource_id: &[ResourceIdPart], attributes: &mut Map<String, Value>) {
+        if let Some(name) = resource_id
+            .iter()
+            .filter(|part| part.r#type == "Account")
+            .find_map(|part| Env::enrichment_account_names().get(&part.id))
+        {
+            add_attribute(attributes, "account_name", name.as_str());
+        }
+    }
+}
+
+struct ContainerImageEnricher;
+
+// Parts of a container image reference, e.g. `registry.example.com:5000/team/app:1.0@sha256:...`
+struct ImageReference<'a> {
+    registry: &'a str,
+    repository: String,
+    tag: Option<&'a str>,
+    digest: Option<&'a str>,
+}
+
+// Parses an image reference the way Docker does: the first path component is a registry only if it looks like a host
+// (contains `.` or `:`, or is `localhost`), and references without a registry are Docker Hub images
+fn parse_image_reference(reference: &str) -> Option<ImageReference<'_>> {
+    let (name, digest) = match reference.split_once('@') {
+        Some((name, digest)) if digest.contains(':') => (name, Some(digest)),
+        Some(_) => return None,
+        None => (reference, None),
+    };
+
+    // A `:` after the last `/` separates the tag, while one before it is a registry port
+    let (name, tag) = match name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, Some(tag)),
+        _ => (name, None),
+    };
+
+    let (registry, repository) = match name.split_once('/') {
+        Some((host, repository))
+            if host.contains('.') || host.contains(':') || host == "localhost" =>
+        {
+            (host, repository.to_string())
+        }
+        Some(_) => ("docker.io", name.to_string()),
+        None => ("docker.io", format!("library/{name}")),
+    };
+
+    if repository.is_empty() || tag.is_some_and(str::is_empty) {
+        return None;
+    }
+
+    Some(ImageReference {
+        registry,
+        repository,
+        tag,
+        digest,
+    })
+}
+
+impl Enricher for ContainerImageEnricher {
+    fn enrich(&self, _resource_id: &[ResourceIdPart], attributes: &mut Map<String, Value>) {
+        // The reference borrows from the image, which can't be borrowed from `attributes` while attributes are added
+        let Some(image) = attributes
+            .get("image")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+        else {
+            return;
+        };
+
+        let Some(reference) = parse_image_reference(&image) else {
+            return;
+        };
+
+        add_attribute(attributes, "image_registry", reference.registry);
+        add_attribute(attributes, "image_repository", reference.repository);
+
+        if let Some(tag) = reference.tag {
+            add_attribute(attributes, "image_tag", tag);
+        }
+
+        if let Some(digest) = reference.digest {
+            add_attribute(attributes, "image_digest", digest);
+        }
+    }
+}
+
+// Enrichers the account has enabled, in a stable order. Enabled enrichers whose configuration has since been removed from
+// the backend are skipped.
+pub(crate) fn enrichers(account: &Account) -> Vec<&'static dyn Enricher> {
+    account
+        .enrichers()
+        .iter()
+        .filter(|kind| kind.is_configured())
+        .map(|kind| kind.enricher())
+        .collect()
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EnrichersResponse {
+    enabled: BTreeSet<EnricherKind>,
+    // Enrichers this backend is configured to run, which may be enabled
+    available: BTreeSet<EnricherKind>,
+}
+
+impl EnrichersResponse {
+    fn new(enabled: BTreeSet<EnricherKind>) -> Self {
+        Self {
+            enabled,
+            available: [
+                EnricherKind::CloudProvider,
+                EnricherKind::AccountName,
+                EnricherKind::ContainerImage,
+            ]
+            .into_iter()
+            .filter(|kind| kind.is_configured())
+            .collect(),
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/enrichers",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = EnrichersResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn get_enrichers(
+    Extension(account): Extension<Account>,
+) -> Result<Json<EnrichersResponse>> {
+    Ok(Json(EnrichersResponse::new(account.enrichers().clone())))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetEnrichersRequest {
+    enabled: BTreeSet<EnricherKind>,
+}
+
+// Sets the enrichers that run on the account's reports. Resources that were already ingested are enriched the next time
+// they are reported.
+#[utoipa::path(
+    put,
+    path = "/account/{account_id}/enrichers",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = SetEnrichersRequest,
+    responses((status = 200, body = EnrichersResponse))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn set_enrichers(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<SetEnrichersRequest>,
+) -> Result<Json<EnrichersResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    if let Some(kind) = req.enabled.iter().find(|kind| !kind.is_configured()) {
+        bad_request!("The {kind:?} enricher isn't configured on this backend");
+    }
+
+    accounts_db_for_account(account.id())
+        .await?
+        .set_account_enrichers_query(&account, &req.enabled, auth.principal())
+        .await?
+        .check_first_real_error()?;
+
+    invalidate_cached_account(account.id());
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        enabled = ?req.enabled,
+        "Set account enrichers"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::EnrichersSet,
+        Some(json!({ "enabled": req.enabled })),
+    )
+    .await;
+
+    Ok(Json(EnrichersResponse::new(req.enabled)))
+}
@@ -1,118 +1,1175 @@
-use std::sync::LazyLock;
+use std::{collections::HashMap, net::SocketAddr, sync::LazyLock};
+
+use archodex_error::anyhow;
+use ipnet::IpNet;
+use migrator::AccountsShard;
+use serde::Deserialize;
 
 #[cfg(not(feature = "archodex-com"))]
 use tokio::sync::RwLock;
 
 pub struct Env {
-    port: u16,
+    bind_addresses: Vec<SocketAddr>,
+    internal_bind_addresses: Vec<SocketAddr>,
     archodex_domain: String,
-    accounts_surrealdb_url: String,
+    cors_origins: Vec<String>,
+    accounts_shards: Vec<AccountsShard>,
     #[cfg(not(feature = "archodex-com"))]
     surrealdb_url: String,
     surrealdb_creds: Option<surrealdb::opt::auth::Root<'static>>,
     #[cfg(feature = "archodex-com")]
     endpoint: String,
-    cognito_user_pool_id: String,
-    cognito_client_id: String,
+    #[cfg(feature = "archodex-com")]
+    eventbridge_bus_name: Option<String>,
+    dashboard_oidc: DashboardOidcConfig,
+    #[cfg(not(feature = "archodex-com"))]
+    dev_auth: Option<DevAuthConfig>,
     #[cfg(not(feature = "archodex-com"))]
     api_private_key: RwLock<Option<aes_gcm::Key<aes_gcm::Aes128Gcm>>>,
+    account_id_hmac_key: Option<Vec<u8>>,
+    admin_iam_role_arns: Vec<String>,
+    canary_account_id: Option<String>,
+    canary_interval: std::time::Duration,
+    storage_usage_interval: std::time::Duration,
+    health_check_interval: std::time::Duration,
+    inference_interval: std::time::Duration,
+    event_retention_interval: std::time::Duration,
+    resource_retention_interval: std::time::Duration,
+    account_storage_limit_bytes: Option<u64>,
+    storage_usage_alert_percent: u64,
+    maintenance_interval: Option<std::time::Duration>,
+    access_audit_interval: Option<std::time::Duration>,
+    access_audit_fix: bool,
+    metrics_enabled: bool,
+    read_only: bool,
+    #[cfg(not(feature = "archodex-com"))]
+    webhook_allow_private_networks: bool,
+    surrealdb_request_id_param: bool,
+    report_rate_limit: Option<ReportRateLimitConfig>,
+    report_max_body_bytes: usize,
+    export_upload_max_bytes: usize,
+    max_principal_chain_length: usize,
+    max_resource_id_bytes: usize,
+    hashed_principal_chain_ids: bool,
+    trusted_proxies: Vec<IpNet>,
+    client_ip_header: ClientIpHeader,
+    enrichment_cloud_ip_ranges: Vec<(IpNet, String)>,
+    enrichment_account_names: HashMap<String, String>,
+    max_connections: usize,
+    connection_idle_timeout: std::time::Duration,
+    tcp_keepalive: std::time::Duration,
+    http2_max_concurrent_streams: u32,
+    http2_keepalive_interval: std::time::Duration,
+    http2_keepalive_timeout: std::time::Duration,
+    report_timeout: std::time::Duration,
+    report_stream_idle_timeout: std::time::Duration,
+    dashboard_read_timeout: std::time::Duration,
+    #[cfg(feature = "chaos")]
+    chaos: ChaosConfig,
+    #[cfg(feature = "kafka")]
+    kafka_report_consumer: Option<KafkaReportConsumerConfig>,
+    #[cfg(feature = "sqs")]
+    sqs_report_consumer: Option<SqsReportConsumerConfig>,
+    #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+    backup: Option<BackupConfig>,
+    #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+    restore_backup: Option<BackupLocation>,
+}
+
+// Header trusted proxies record the addresses they forwarded requests for in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ClientIpHeader {
+    XForwardedFor,
+    // RFC 7239
+    Forwarded,
+}
+
+// OpenID Connect provider that issues the access tokens dashboard requests are authenticated with
+pub(crate) struct DashboardOidcConfig {
+    // Must exactly match the `iss` claim of access tokens. The provider's discovery document is fetched from
+    // `<issuer>/.well-known/openid-configuration`.
+    pub(crate) issuer: String,
+    pub(crate) audience: DashboardOidcAudience,
+    // Claim holding the user's ID, which must be a UUID
+    pub(crate) user_id_claim: String,
+}
+
+pub(crate) enum DashboardOidcAudience {
+    // Matched against the `aud` claim
+    Aud(String),
+    // Cognito access tokens have no `aud` claim, so they are matched by their `client_id` claim instead
+    CognitoClientId(String),
+}
+
+// Static bearer token accepted in place of OIDC access tokens, for single-user self-hosted installs and local dashboard
+// development
+#[cfg(not(feature = "archodex-com"))]
+pub(crate) struct DevAuthConfig {
+    pub(crate) token: String,
+    pub(crate) user_id: surrealdb::Uuid,
+}
+
+// Token bucket parameters applied to each report API key
+pub(crate) struct ReportRateLimitConfig {
+    pub(crate) reports_per_second: f64,
+    pub(crate) burst: u32,
+}
+
+// Probabilities of randomly injected faults, used for resilience testing
+#[cfg(feature = "chaos")]
+pub(crate) struct ChaosConfig {
+    pub(crate) db_error_probability: f64,
+    pub(crate) auth_failure_probability: f64,
+    pub(crate) latency_probability: f64,
+    pub(crate) latency: std::time::Duration,
+}
+
+#[cfg(feature = "kafka")]
+pub struct KafkaReportConsumerConfig {
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub consumer_group: String,
+    pub partitions: Option<Vec<i32>>,
+    pub sasl_plain_credentials: Option<(String, String)>,
+    pub tls: bool,
+}
+
+#[cfg(feature = "sqs")]
+pub struct SqsReportConsumerConfig {
+    pub queue_url: String,
+    pub pollers: usize,
+}
+
+// Scheduled snapshots of the embedded database
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+pub(crate) struct BackupConfig {
+    pub(crate) destination: BackupLocation,
+    pub(crate) interval: std::time::Duration,
+    // Number of snapshots kept at the destination. Older snapshots are deleted after each new snapshot is written.
+    pub(crate) retain: usize,
+}
+
+// A local directory or an `s3://<bucket>/<prefix>` location. Backup destinations hold one snapshot per subdirectory or
+// sub-prefix, while restore sources name a single snapshot.
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+#[derive(Clone, Debug)]
+pub(crate) enum BackupLocation {
+    Local(std::path::PathBuf),
+    #[cfg(feature = "s3-backups")]
+    S3 {
+        bucket: String,
+        prefix: String,
+    },
+}
+
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+impl BackupLocation {
+    pub(crate) fn parse(location: &str) -> Result<Self, String> {
+        let Some(path) = location.strip_prefix("s3://") else {
+            return Ok(BackupLocation::Local(location.into()));
+        };
+
+        #[cfg(feature = "s3-backups")]
+        {
+            let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+
+            if bucket.is_empty() {
+                return Err(format!("{location:?} has no bucket name"));
+            }
+
+            Ok(BackupLocation::S3 {
+                bucket: bucket.to_string(),
+                prefix: prefix.trim_matches('/').to_string(),
+            })
+        }
+
+        #[cfg(not(feature = "s3-backups"))]
+        {
+            let _ = path;
+            Err("s3:// locations require a backend built with the `s3-backups` feature".to_string())
+        }
+    }
+}
+
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+impl std::fmt::Display for BackupLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupLocation::Local(path) => write!(f, "{}", path.display()),
+            #[cfg(feature = "s3-backups")]
+            BackupLocation::S3 { bucket, prefix } => write!(f, "s3://{bucket}/{prefix}"),
+        }
+    }
+}
+
+/// A single problem found while validating the backend's environment variables.
+pub struct EnvProblem {
+    pub vars: &'static str,
+    pub problem: String,
+}
+
+/// Every problem found while validating the backend's environment variables.
+///
+/// The `Display` implementation renders a table of all problems so that misconfigured deployments (e.g. Helm values)
+/// can be fixed in one pass rather than one panic at a time.
+pub struct EnvDiagnostics {
+    pub problems: Vec<EnvProblem>,
+}
+
+impl std::fmt::Display for EnvDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let vars_width = self
+            .problems
+            .iter()
+            .map(|problem| problem.vars.len())
+            .chain(Some("VARIABLE".len()))
+            .max()
+            .unwrap_or_default();
+
+        writeln!(
+            f,
+            "Invalid environment configuration (archodex-com: {}, rocksdb: {}):",
+            cfg!(feature = "archodex-com"),
+            cfg!(feature = "rocksdb"),
+        )?;
+        writeln!(f)?;
+        writeln!(f, "  {:vars_width$}  PROBLEM", "VARIABLE")?;
+
+        for problem in &self.problems {
+            writeln!(f, "  {:vars_width$}  {}", problem.vars, problem.problem)?;
+        }
+
+        Ok(())
+    }
 }
 
+impl std::fmt::Debug for EnvDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for EnvDiagnostics {}
+
+// Settings that may be set in the TOML file at `ARCHODEX_CONFIG` instead of in environment variables, e.g.:
+//
+// ```toml
+// port = 5732
+// cors_origins = ["https://archodex.example.com"]
+//
+// [surrealdb]
+// url = "ws://surrealdb:8000"
+//
+// [oidc]
+// issuer = "https://auth.example.com"
+// audience = "archodex"
+// ```
+//
+// Each setting stands in for an environment variable, which takes precedence when set. Values from the file are
+// validated like the environment variables, and problems with them are reported under the variables' names.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    port: Option<u16>,
+    bind_addresses: Option<Vec<String>>,
+    internal_bind_addresses: Option<Vec<String>>,
+    archodex_domain: Option<String>,
+    cors_origins: Option<Vec<String>>,
+    #[serde(default)]
+    surrealdb: SurrealdbConfigFile,
+    #[serde(default)]
+    cognito: CognitoConfigFile,
+    #[serde(default)]
+    oidc: OidcConfigFile,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SurrealdbConfigFile {
+    url: Option<String>,
+    accounts_url: Option<String>,
+    // Shards in the form `FIRST_ACCOUNT_ID=SURREALDB_URL`, in ascending order of their first account IDs
+    accounts_urls: Option<Vec<String>>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CognitoConfigFile {
+    user_pool_id: Option<String>,
+    client_id: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OidcConfigFile {
+    issuer: Option<String>,
+    audience: Option<String>,
+    user_id_claim: Option<String>,
+}
+
+impl ConfigFile {
+    // Reads the file at `ARCHODEX_CONFIG`, which can only be set in the environment. No settings are read from a file if
+    // it isn't set.
+    fn load(reader: &mut EnvReader) -> Self {
+        let Some(path) = reader.optional("ARCHODEX_CONFIG") else {
+            return Self::default();
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                reader.problem("ARCHODEX_CONFIG", format!("Failed to read {path:?}: {err}"));
+                return Self::default();
+            }
+        };
+
+        toml::from_str(&contents).unwrap_or_else(|err| {
+            let line = err
+                .span()
+                .map_or(1, |span| contents[..span.start].matches('\n').count() + 1);
+
+            reader.problem(
+                "ARCHODEX_CONFIG",
+                format!("Failed to parse {path:?} at line {line}: {}", err.message()),
+            );
+            Self::default()
+        })
+    }
+
+    // Values of the settings in the file by the environment variable each stands in for
+    fn into_vars(self) -> HashMap<&'static str, String> {
+        let list = |values: Option<Vec<String>>| values.map(|values| values.join(","));
+
+        [
+            ("PORT", self.port.map(|port| port.to_string())),
+            ("ARCHODEX_BIND_ADDRESSES", list(self.bind_addresses)),
+            (
+                "ARCHODEX_INTERNAL_BIND_ADDRESSES",
+                list(self.internal_bind_addresses),
+            ),
+            ("ARCHODEX_DOMAIN", self.archodex_domain),
+            ("ARCHODEX_CORS_ORIGINS", list(self.cors_origins)),
+            ("SURREALDB_URL", self.surrealdb.url),
+            ("ACCOUNTS_SURREALDB_URL", self.surrealdb.accounts_url),
+            (
+                "ACCOUNTS_SURREALDB_URLS",
+                list(self.surrealdb.accounts_urls),
+            ),
+            ("SURREALDB_USERNAME", self.surrealdb.username),
+            ("SURREALDB_PASSWORD", self.surrealdb.password),
+            ("COGNITO_USER_POOL_ID", self.cognito.user_pool_id),
+            ("COGNITO_CLIENT_ID", self.cognito.client_id),
+            ("ARCHODEX_OIDC_ISSUER", self.oidc.issuer),
+            ("ARCHODEX_OIDC_AUDIENCE", self.oidc.audience),
+            ("ARCHODEX_OIDC_USER_ID_CLAIM", self.oidc.user_id_claim),
+        ]
+        .into_iter()
+        .filter_map(|(var, value)| Some((var, value?)))
+        .collect()
+    }
+}
+
+// Collects problems while reading environment variables so they can be reported together
+#[derive(Default)]
+struct EnvReader {
+    problems: Vec<EnvProblem>,
+    // Settings from the `ARCHODEX_CONFIG` file, by the environment variable each stands in for
+    config_file_vars: HashMap<&'static str, String>,
+}
+
+impl EnvReader {
+    fn problem(&mut self, vars: &'static str, problem: impl Into<String>) {
+        self.problems.push(EnvProblem {
+            vars,
+            problem: problem.into(),
+        });
+    }
+
+    // Returns the value of `var`, treating empty values as unset, or else the value of the config file setting standing in
+    // for it
+    fn optional(&mut self, var: &'static str) -> Option<String> {
+        match std::env::var(var) {
+            Ok(value) if !value.is_empty() => Some(value),
+            Ok(_) | Err(std::env::VarError::NotPresent) => self.config_file_vars.get(var).cloned(),
+            Err(std::env::VarError::NotUnicode(_)) => {
+                self.problem(var, "Value is not valid unicode");
+                None
+            }
+        }
+    }
+
+    fn with_default(&mut self, var: &'static str, default: &str) -> String {
+        self.optional(var).unwrap_or_else(|| default.to_string())
+    }
+
+    fn positive_seconds(&mut self, var: &'static str, default: &str) -> std::time::Duration {
+        let value = self.with_default(var, default);
+        match value.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => std::time::Duration::from_secs(seconds),
+            _ => {
+                self.problem(
+                    var,
+                    format!("{value:?} is not a positive number of seconds"),
+                );
+                std::time::Duration::ZERO
+            }
+        }
+    }
+
+    // Parses a comma separated list of socket addresses. IPv6 addresses must be bracketed, e.g. `[::]:5732`.
+    fn socket_addresses(&mut self, var: &'static str, default: &str) -> Vec<SocketAddr> {
+        let value = self.with_default(var, default);
+
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .filter_map(|address| match address.parse::<SocketAddr>() {
+                Ok(address) => Some(address),
+                Err(_) => {
+                    self.problem(var, format!("{address:?} is not a valid socket address"));
+                    None
+                }
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "archodex-com")]
+    fn required(&mut self, var: &'static str) -> String {
+        self.optional(var).unwrap_or_else(|| {
+            self.problem(var, "Must be set");
+            String::new()
+        })
+    }
+
+    fn forbidden(&mut self, var: &'static str, reason: &str) {
+        if std::env::var_os(var).is_some() || self.config_file_vars.contains_key(var) {
+            self.problem(var, format!("Must not be set {reason}"));
+        }
+    }
+
+    // Reads two variables that must either both be set or both be unset
+    fn pair(
+        &mut self,
+        vars: &'static str,
+        first: &'static str,
+        second: &'static str,
+    ) -> Option<(String, String)> {
+        match (self.optional(first), self.optional(second)) {
+            (Some(first), Some(second)) => Some((first, second)),
+            (None, None) => None,
+            _ => {
+                self.problem(vars, "Must be set or unset together");
+                None
+            }
+        }
+    }
+
+    fn surrealdb_url(&mut self, var: &'static str, url: &str) {
+        let scheme = url.split_once("://").map(|(scheme, _)| scheme);
+
+        match scheme {
+            Some("ws" | "wss") => {}
+            Some("rocksdb") if cfg!(feature = "rocksdb") => {}
+            Some("rocksdb") => self.problem(
+                var,
+                "rocksdb:// URLs require a backend built with the `rocksdb` feature",
+            ),
+            _ => self.problem(
+                var,
+                format!("{url:?} is not a supported SurrealDB URL (expected ws://, wss://, or rocksdb://)"),
+            ),
+        }
+    }
+}
+
+static ENV: LazyLock<Result<Env, EnvDiagnostics>> = LazyLock::new(Env::load);
+
 impl Env {
     fn get() -> &'static Self {
-        static ENV: LazyLock<Env> = LazyLock::new(|| {
-            let port = std::env::var("PORT")
-                .unwrap_or_else(|_| {
-                    #[cfg(not(feature = "archodex-com"))]
-                    {
-                        "5732".into()
-                    }
+        match &*ENV {
+            Ok(env) => env,
+            Err(diagnostics) => panic!("{diagnostics}"),
+        }
+    }
 
-                    #[cfg(feature = "archodex-com")]
-                    {
-                        "5731".into()
-                    }
-                })
-                .parse::<u16>()
-                .expect("Failed to parse PORT env var as u16");
+    /// Validates every environment variable used by the backend for the features it was built with.
+    ///
+    /// This should be called at startup, before serving requests, so that all configuration problems are reported
+    /// together instead of panicking on the first invalid variable when it is first used.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with every configuration problem found if the environment is invalid.
+    pub fn validate() -> Result<(), &'static EnvDiagnostics> {
+        ENV.as_ref().map(|_| ())
+    }
 
-            let archodex_domain = env_with_default_for_empty("ARCHODEX_DOMAIN", "archodex.com");
+    fn load() -> Result<Self, EnvDiagnostics> {
+        let mut reader = EnvReader::default();
+        reader.config_file_vars = ConfigFile::load(&mut reader).into_vars();
 
+        // Unit tests don't connect to SurrealDB or serve requests, but the settings they need must still be set
+        #[cfg(test)]
+        for (var, value) in [
+            #[cfg(feature = "archodex-com")]
+            ("ACCOUNTS_SURREALDB_URL", "ws://localhost:8000"),
             #[cfg(not(feature = "archodex-com"))]
-            let (_, surrealdb_url) = (
-                std::env::var("ACCOUNTS_SURREALDB_URL").expect_err(
-                    "ACCOUNTS_SURREALDB_URL env var should not be set in non-archodex-com builds",
-                ),
-                env_with_default_for_empty("SURREALDB_URL", "rocksdb://db"),
+            ("SURREALDB_URL", "ws://localhost:8000"),
+            ("ENDPOINT", "http://localhost:5731"),
+        ] {
+            reader
+                .config_file_vars
+                .entry(var)
+                .or_insert_with(|| value.to_string());
+        }
+
+        #[cfg(not(feature = "archodex-com"))]
+        let default_port = "5732";
+        #[cfg(feature = "archodex-com")]
+        let default_port = "5731";
+
+        let port = reader.with_default("PORT", default_port);
+        let port = port.parse::<u16>().unwrap_or_else(|_| {
+            reader.problem("PORT", format!("{port:?} is not a valid port number"));
+            0
+        });
+
+        let bind_addresses =
+            reader.socket_addresses("ARCHODEX_BIND_ADDRESSES", &format!("0.0.0.0:{port}"));
+        let internal_bind_addresses =
+            reader.socket_addresses("ARCHODEX_INTERNAL_BIND_ADDRESSES", "");
+
+        if let Some(address) = internal_bind_addresses
+            .iter()
+            .find(|address| bind_addresses.contains(address))
+        {
+            reader.problem(
+                "ARCHODEX_BIND_ADDRESSES, ARCHODEX_INTERNAL_BIND_ADDRESSES",
+                format!("{address} is used by both the public and internal listeners"),
             );
+        }
 
-            #[cfg(feature = "archodex-com")]
-            let (accounts_surrealdb_url, _) = (
-                std::env::var("ACCOUNTS_SURREALDB_URL")
-                    .expect("Missing ACCOUNTS_SURREALDB_URL env var"),
-                std::env::var("SURREALDB_URL")
-                    .expect_err("SURREALDB_URL env var should not be set in archodex-com builds"),
+        let archodex_domain = reader.with_default("ARCHODEX_DOMAIN", "archodex.com");
+
+        let cors_origins = reader
+            .with_default("ARCHODEX_CORS_ORIGINS", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .filter_map(|origin| {
+                if !(origin.starts_with("https://") || origin.starts_with("http://"))
+                    || origin.ends_with('/')
+                    || !origin.chars().all(|c| c.is_ascii_graphic())
+                {
+                    reader.problem(
+                        "ARCHODEX_CORS_ORIGINS",
+                        format!("{origin:?} is not an origin, e.g. https://archodex.example.com"),
+                    );
+                    return None;
+                }
+
+                Some(origin.to_string())
+            })
+            .collect();
+
+        #[cfg(not(feature = "archodex-com"))]
+        let surrealdb_url = {
+            reader.forbidden("ACCOUNTS_SURREALDB_URL", "in self-hosted builds");
+            let surrealdb_url = reader.with_default("SURREALDB_URL", "rocksdb://db");
+            reader.surrealdb_url("SURREALDB_URL", &surrealdb_url);
+            surrealdb_url
+        };
+
+        #[cfg(feature = "archodex-com")]
+        let accounts_shards = {
+            reader.forbidden("SURREALDB_URL", "in archodex-com builds");
+
+            match (
+                reader.optional("ACCOUNTS_SURREALDB_URL"),
+                reader.optional("ACCOUNTS_SURREALDB_URLS"),
+            ) {
+                (Some(accounts_surrealdb_url), None) => {
+                    reader.surrealdb_url("ACCOUNTS_SURREALDB_URL", &accounts_surrealdb_url);
+                    vec![AccountsShard {
+                        first_account_id: migrator::MIN_ACCOUNT_ID,
+                        surrealdb_url: accounts_surrealdb_url,
+                    }]
+                }
+                (None, Some(accounts_surrealdb_urls)) => {
+                    match migrator::parse_accounts_shards(&accounts_surrealdb_urls) {
+                        Ok(accounts_shards) => {
+                            for shard in &accounts_shards {
+                                reader
+                                    .surrealdb_url("ACCOUNTS_SURREALDB_URLS", &shard.surrealdb_url);
+                            }
+                            // Embedded databases are served by a single shared connection
+                            if accounts_shards.len() > 1
+                                && accounts_shards
+                                    .iter()
+                                    .any(|shard| shard.surrealdb_url.starts_with("rocksdb:"))
+                            {
+                                reader.problem(
+                                    "ACCOUNTS_SURREALDB_URLS",
+                                    "rocksdb:// URLs can't be used with multiple shards",
+                                );
+                            }
+                            accounts_shards
+                        }
+                        Err(problem) => {
+                            reader.problem("ACCOUNTS_SURREALDB_URLS", problem);
+                            vec![]
+                        }
+                    }
+                }
+                (Some(_), Some(_)) => {
+                    reader.problem(
+                        "ACCOUNTS_SURREALDB_URL, ACCOUNTS_SURREALDB_URLS",
+                        "Must not both be set",
+                    );
+                    vec![]
+                }
+                (None, None) => {
+                    reader.problem(
+                        "ACCOUNTS_SURREALDB_URL, ACCOUNTS_SURREALDB_URLS",
+                        "One must be set",
+                    );
+                    vec![]
+                }
+            }
+        };
+
+        let surrealdb_creds = reader
+            .pair(
+                "SURREALDB_USERNAME, SURREALDB_PASSWORD",
+                "SURREALDB_USERNAME",
+                "SURREALDB_PASSWORD",
+            )
+            .map(|(username, password)| surrealdb::opt::auth::Root {
+                username: Box::leak(Box::new(username)),
+                password: Box::leak(Box::new(password)),
+            });
+
+        #[cfg(feature = "archodex-com")]
+        let endpoint = reader.required("ENDPOINT");
+
+        #[cfg(feature = "archodex-com")]
+        let eventbridge_bus_name = reader.optional("ARCHODEX_EVENTBRIDGE_BUS_NAME");
+        #[cfg(not(feature = "archodex-com"))]
+        reader.forbidden("ARCHODEX_EVENTBRIDGE_BUS_NAME", "in self-hosted builds");
+
+        let dashboard_oidc = dashboard_oidc_config(&mut reader);
+
+        #[cfg(not(feature = "archodex-com"))]
+        let dev_auth = dev_auth_config(&mut reader);
+        #[cfg(feature = "archodex-com")]
+        reader.forbidden("ARCHODEX_DEV_AUTH_TOKEN", "in archodex-com builds");
+
+        #[cfg(not(feature = "archodex-com"))]
+        if let Some(hex_bytes) = reader.optional("ARCHODEX_API_PRIVATE_KEY")
+            && !matches!(hex::decode(hex_bytes), Ok(bytes) if bytes.len() == 16)
+        {
+            reader.problem(
+                "ARCHODEX_API_PRIVATE_KEY",
+                "Must be 16 bytes (32 characters) hex encoded",
             );
+        }
 
-            let surrealdb_username = match std::env::var("SURREALDB_USERNAME") {
-                Ok(surrealdb_username) if !surrealdb_username.is_empty() => {
-                    Some(surrealdb_username)
+        let account_id_hmac_key = reader.optional("ARCHODEX_ACCOUNT_ID_HMAC_KEY").and_then(
+            |hex_bytes| match hex::decode(hex_bytes) {
+                Ok(bytes) if bytes.len() >= 32 => Some(bytes),
+                _ => {
+                    reader.problem(
+                        "ARCHODEX_ACCOUNT_ID_HMAC_KEY",
+                        "Must be at least 32 bytes (64 characters) hex encoded",
+                    );
+                    None
                 }
-                Ok(_) | Err(std::env::VarError::NotPresent) => None,
-                Err(err) => panic!("Invalid SURREALDB_USERNAME env var: {err:?}"),
-            };
-            let surrealdb_password = match std::env::var("SURREALDB_PASSWORD") {
-                Ok(surrealdb_password) if !surrealdb_password.is_empty() => {
-                    Some(surrealdb_password)
+            },
+        );
+
+        let admin_iam_role_arns = reader
+            .optional("ARCHODEX_ADMIN_IAM_ROLE_ARNS")
+            .map(|arns| {
+                arns.split(',')
+                    .map(str::trim)
+                    .filter(|arn| !arn.is_empty())
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        for arn in &admin_iam_role_arns {
+            if !(arn.starts_with("arn:aws:iam::") && arn.contains(":role/")) {
+                reader.problem(
+                    "ARCHODEX_ADMIN_IAM_ROLE_ARNS",
+                    format!("{arn:?} is not an IAM role ARN"),
+                );
+            }
+        }
+
+        let canary_account_id = reader.optional("ARCHODEX_CANARY_ACCOUNT_ID");
+
+        let canary_interval = reader.positive_seconds("ARCHODEX_CANARY_INTERVAL_SECONDS", "60");
+
+        let storage_usage_interval =
+            reader.positive_seconds("ARCHODEX_STORAGE_USAGE_INTERVAL_SECONDS", "3600");
+
+        let health_check_interval =
+            reader.positive_seconds("ARCHODEX_HEALTH_CHECK_INTERVAL_SECONDS", "60");
+
+        let inference_interval =
+            reader.positive_seconds("ARCHODEX_INFERENCE_INTERVAL_SECONDS", "300");
+
+        let event_retention_interval =
+            reader.positive_seconds("ARCHODEX_EVENT_RETENTION_INTERVAL_SECONDS", "3600");
+
+        let resource_retention_interval =
+            reader.positive_seconds("ARCHODEX_RESOURCE_RETENTION_INTERVAL_SECONDS", "3600");
+
+        let account_storage_limit_bytes = reader
+            .optional("ARCHODEX_ACCOUNT_STORAGE_LIMIT_BYTES")
+            .and_then(|limit| match limit.parse::<u64>() {
+                Ok(limit) if limit > 0 => Some(limit),
+                _ => {
+                    reader.problem(
+                        "ARCHODEX_ACCOUNT_STORAGE_LIMIT_BYTES",
+                        format!("{limit:?} is not a positive number of bytes"),
+                    );
+                    None
                 }
-                Ok(_) | Err(std::env::VarError::NotPresent) => None,
-                Err(err) => panic!("Invalid SURREALDB_PASSWORD env var: {err:?}"),
-            };
+            });
+
+        let storage_usage_alert_percent =
+            reader.with_default("ARCHODEX_STORAGE_USAGE_ALERT_PERCENT", "80");
+        let storage_usage_alert_percent = match storage_usage_alert_percent.parse::<u64>() {
+            Ok(percent) if (1..=100).contains(&percent) => percent,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_STORAGE_USAGE_ALERT_PERCENT",
+                    format!(
+                        "{storage_usage_alert_percent:?} is not a percentage between 1 and 100"
+                    ),
+                );
+                0
+            }
+        };
 
-            let surrealdb_creds = match (surrealdb_username, surrealdb_password) {
-                (Some(surrealdb_username), Some(surrealdb_password)) => {
-                    Some(surrealdb::opt::auth::Root {
-                        username: Box::leak(Box::new(surrealdb_username)),
-                        password: Box::leak(Box::new(surrealdb_password)),
-                    })
+        let maintenance_interval = reader
+            .optional("ARCHODEX_MAINTENANCE_INTERVAL_SECONDS")
+            .and_then(|seconds| match seconds.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => Some(std::time::Duration::from_secs(seconds)),
+                _ => {
+                    reader.problem(
+                        "ARCHODEX_MAINTENANCE_INTERVAL_SECONDS",
+                        format!("{seconds:?} is not a positive number of seconds"),
+                    );
+                    None
                 }
-                (None, None) => None,
-                _ => panic!(
-                    "Both SURREALDB_USERNAME and SURREALDB_PASSWORD must be set or unset together"
-                ),
-            };
+            });
 
-            Env {
-                port,
-                archodex_domain,
-                #[cfg(feature = "archodex-com")]
-                accounts_surrealdb_url,
-                #[cfg(not(feature = "archodex-com"))]
-                accounts_surrealdb_url: surrealdb_url.clone(),
-                #[cfg(not(feature = "archodex-com"))]
-                surrealdb_url,
-                surrealdb_creds,
-                #[cfg(feature = "archodex-com")]
-                endpoint: std::env::var("ENDPOINT").expect("Missing ENDPOINT env var"),
-                cognito_user_pool_id: env_with_default_for_empty(
-                    "COGNITO_USER_POOL_ID",
-                    "us-west-2_Mf1K95El6",
-                ),
-                cognito_client_id: env_with_default_for_empty(
-                    "COGNITO_CLIENT_ID",
-                    "1a5vsre47o6pa39p3p81igfken",
-                ),
-                #[cfg(not(feature = "archodex-com"))]
-                api_private_key: RwLock::new(None),
+        let access_audit_interval = reader
+            .optional("ARCHODEX_ACCESS_AUDIT_INTERVAL_SECONDS")
+            .and_then(|seconds| match seconds.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => Some(std::time::Duration::from_secs(seconds)),
+                _ => {
+                    reader.problem(
+                        "ARCHODEX_ACCESS_AUDIT_INTERVAL_SECONDS",
+                        format!("{seconds:?} is not a positive number of seconds"),
+                    );
+                    None
+                }
+            });
+
+        let access_audit_fix = reader.with_default("ARCHODEX_ACCESS_AUDIT_FIX", "false");
+        let access_audit_fix = match access_audit_fix.as_str() {
+            "true" => true,
+            "false" => false,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_ACCESS_AUDIT_FIX",
+                    format!("{access_audit_fix:?} must be \"true\" or \"false\""),
+                );
+                false
             }
-        });
+        };
+
+        let metrics_enabled = reader.with_default("ARCHODEX_METRICS_ENABLED", "false");
+        let metrics_enabled = match metrics_enabled.as_str() {
+            "true" => true,
+            "false" => false,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_METRICS_ENABLED",
+                    format!("{metrics_enabled:?} must be \"true\" or \"false\""),
+                );
+                false
+            }
+        };
+
+        let read_only = reader.with_default("ARCHODEX_READ_ONLY", "false");
+        let read_only = match read_only.as_str() {
+            "true" => true,
+            "false" => false,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_READ_ONLY",
+                    format!("{read_only:?} must be \"true\" or \"false\""),
+                );
+                false
+            }
+        };
+
+        #[cfg(not(feature = "archodex-com"))]
+        let webhook_allow_private_networks =
+            reader.with_default("ARCHODEX_WEBHOOK_ALLOW_PRIVATE_NETWORKS", "false");
+        #[cfg(not(feature = "archodex-com"))]
+        let webhook_allow_private_networks = match webhook_allow_private_networks.as_str() {
+            "true" => true,
+            "false" => false,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_WEBHOOK_ALLOW_PRIVATE_NETWORKS",
+                    format!("{webhook_allow_private_networks:?} must be \"true\" or \"false\""),
+                );
+                false
+            }
+        };
+        #[cfg(feature = "archodex-com")]
+        reader.forbidden(
+            "ARCHODEX_WEBHOOK_ALLOW_PRIVATE_NETWORKS",
+            "in archodex-com builds",
+        );
+
+        let surrealdb_request_id_param =
+            reader.with_default("ARCHODEX_SURREALDB_REQUEST_ID_PARAM", "false");
+        let surrealdb_request_id_param = match surrealdb_request_id_param.as_str() {
+            "true" => true,
+            "false" => false,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_SURREALDB_REQUEST_ID_PARAM",
+                    format!("{surrealdb_request_id_param:?} must be \"true\" or \"false\""),
+                );
+                false
+            }
+        };
+
+        let report_rate_limit = report_rate_limit_config(&mut reader);
+
+        let report_max_body_bytes =
+            reader.with_default("ARCHODEX_REPORT_MAX_BODY_BYTES", "16777216");
+        let report_max_body_bytes = match report_max_body_bytes.parse::<usize>() {
+            Ok(report_max_body_bytes) if report_max_body_bytes > 0 => report_max_body_bytes,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_REPORT_MAX_BODY_BYTES",
+                    format!("{report_max_body_bytes:?} is not a positive number of bytes"),
+                );
+                0
+            }
+        };
+
+        let export_upload_max_bytes =
+            reader.with_default("ARCHODEX_EXPORT_UPLOAD_MAX_BYTES", "268435456");
+        let export_upload_max_bytes = match export_upload_max_bytes.parse::<usize>() {
+            Ok(export_upload_max_bytes) if export_upload_max_bytes > 0 => export_upload_max_bytes,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_EXPORT_UPLOAD_MAX_BYTES",
+                    format!("{export_upload_max_bytes:?} is not a positive number of bytes"),
+                );
+                0
+            }
+        };
+
+        let max_principal_chain_length =
+            reader.with_default("ARCHODEX_MAX_PRINCIPAL_CHAIN_LENGTH", "16");
+        let max_principal_chain_length = match max_principal_chain_length.parse::<usize>() {
+            Ok(max_principal_chain_length) if max_principal_chain_length > 0 => {
+                max_principal_chain_length
+            }
+            _ => {
+                reader.problem(
+                    "ARCHODEX_MAX_PRINCIPAL_CHAIN_LENGTH",
+                    format!("{max_principal_chain_length:?} is not a positive number"),
+                );
+                0
+            }
+        };
+
+        let max_resource_id_bytes = reader.with_default("ARCHODEX_MAX_RESOURCE_ID_BYTES", "8192");
+        let max_resource_id_bytes = match max_resource_id_bytes.parse::<usize>() {
+            Ok(max_resource_id_bytes) if max_resource_id_bytes > 0 => max_resource_id_bytes,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_MAX_RESOURCE_ID_BYTES",
+                    format!("{max_resource_id_bytes:?} is not a positive number of bytes"),
+                );
+                0
+            }
+        };
+
+        let hashed_principal_chain_ids =
+            reader.with_default("ARCHODEX_HASHED_PRINCIPAL_CHAIN_IDS", "false");
+        let hashed_principal_chain_ids = match hashed_principal_chain_ids.as_str() {
+            "true" => true,
+            "false" => false,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_HASHED_PRINCIPAL_CHAIN_IDS",
+                    format!("{hashed_principal_chain_ids:?} must be \"true\" or \"false\""),
+                );
+                false
+            }
+        };
+
+        let trusted_proxies = reader
+            .with_default("ARCHODEX_TRUSTED_PROXY_CIDRS", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|cidr| !cidr.is_empty())
+            .filter_map(|cidr| {
+                // Bare addresses are accepted as single address networks
+                let network = cidr.parse::<IpNet>().or_else(|_| {
+                    cidr.parse::<std::net::IpAddr>()
+                        .map(IpNet::from)
+                        .map_err(|_| ())
+                });
+
+                if network.is_err() {
+                    reader.problem(
+                        "ARCHODEX_TRUSTED_PROXY_CIDRS",
+                        format!("{cidr:?} is not a valid CIDR"),
+                    );
+                }
+
+                network.ok()
+            })
+            .collect::<Vec<_>>();
+
+        let client_ip_header = reader.with_default("ARCHODEX_CLIENT_IP_HEADER", "x-forwarded-for");
+        let client_ip_header = match client_ip_header.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => ClientIpHeader::XForwardedFor,
+            "forwarded" => ClientIpHeader::Forwarded,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_CLIENT_IP_HEADER",
+                    format!("{client_ip_header:?} must be \"x-forwarded-for\" or \"forwarded\""),
+                );
+                ClientIpHeader::XForwardedFor
+            }
+        };
+
+        let enrichment_cloud_ip_ranges = reader
+            .with_default("ARCHODEX_ENRICHMENT_CLOUD_IP_RANGES", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let range = entry
+                    .split_once('=')
+                    .filter(|(provider, _)| !provider.trim().is_empty())
+                    .and_then(|(provider, cidr)| {
+                        cidr.trim()
+                            .parse::<IpNet>()
+                            .ok()
+                            .map(|network| (network, provider.trim().to_string()))
+                    });
+
+                if range.is_none() {
+                    reader.problem(
+                        "ARCHODEX_ENRICHMENT_CLOUD_IP_RANGES",
+                        format!("{entry:?} is not a <provider>=<CIDR> pair"),
+                    );
+                }
+
+                range
+            })
+            .collect::<Vec<_>>();
+
+        let enrichment_account_names = reader
+            .with_default("ARCHODEX_ENRICHMENT_ACCOUNT_NAMES", "")
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let name = entry
+                    .split_once('=')
+                    .map(|(account_id, name)| (account_id.trim(), name.trim()))
+                    .filter(|(account_id, name)| !account_id.is_empty() && !name.is_empty())
+                    .map(|(account_id, name)| (account_id.to_string(), name.to_string()));
 
-        &ENV
+                if name.is_none() {
+                    reader.problem(
+                        "ARCHODEX_ENRICHMENT_ACCOUNT_NAMES",
+                        format!("{entry:?} is not an <account ID>=<name> pair"),
+                    );
+                }
+
+                name
+            })
+            .collect::<HashMap<_, _>>();
+
+        let max_connections = reader.with_default("ARCHODEX_MAX_CONNECTIONS", "1024");
+        let max_connections = match max_connections.parse::<usize>() {
+            Ok(max_connections) if max_connections > 0 => max_connections,
+            _ => {
+                reader.problem(
+                    "ARCHODEX_MAX_CONNECTIONS",
+                    format!("{max_connections:?} is not a positive number of connections"),
+                );
+                0
+            }
+        };
+        let connection_idle_timeout =
+            reader.positive_seconds("ARCHODEX_CONNECTION_IDLE_TIMEOUT_SECONDS", "60");
+        let tcp_keepalive = reader.positive_seconds("ARCHODEX_TCP_KEEPALIVE_SECONDS", "60");
+
+        let http2_max_concurrent_streams =
+            reader.with_default("ARCHODEX_HTTP2_MAX_CONCURRENT_STREAMS", "256");
+        let http2_max_concurrent_streams = match http2_max_concurrent_streams.parse::<u32>() {
+            Ok(http2_max_concurrent_streams) if http2_max_concurrent_streams > 0 => {
+                http2_max_concurrent_streams
+            }
+            _ => {
+                reader.problem(
+                    "ARCHODEX_HTTP2_MAX_CONCURRENT_STREAMS",
+                    format!("{http2_max_concurrent_streams:?} is not a positive number of streams"),
+                );
+                0
+            }
+        };
+        let http2_keepalive_interval =
+            reader.positive_seconds("ARCHODEX_HTTP2_KEEPALIVE_INTERVAL_SECONDS", "30");
+        let http2_keepalive_timeout =
+            reader.positive_seconds("ARCHODEX_HTTP2_KEEPALIVE_TIMEOUT_SECONDS", "20");
+
+        let report_timeout = reader.positive_seconds("ARCHODEX_REPORT_TIMEOUT_SECONDS", "300");
+        let report_stream_idle_timeout =
+            reader.positive_seconds("ARCHODEX_REPORT_STREAM_IDLE_TIMEOUT_SECONDS", "60");
+        let dashboard_read_timeout =
+            reader.positive_seconds("ARCHODEX_DASHBOARD_READ_TIMEOUT_SECONDS", "30");
+
+        #[cfg(feature = "chaos")]
+        let chaos = chaos_config(&mut reader);
+
+        #[cfg(feature = "kafka")]
+        let kafka_report_consumer = kafka_report_consumer_config(&mut reader);
+
+        #[cfg(feature = "sqs")]
+        let sqs_report_consumer = sqs_report_consumer_config(&mut reader);
+
+        #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+        let backup = backup_config(&mut reader, &surrealdb_url);
+        #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+        let restore_backup =
+            backup_location(&mut reader, "ARCHODEX_RESTORE_BACKUP", &surrealdb_url);
+        #[cfg(any(not(feature = "rocksdb"), feature = "archodex-com"))]
+        for var in [
+            "ARCHODEX_BACKUP_DESTINATION",
+            "ARCHODEX_BACKUP_INTERVAL_SECONDS",
+            "ARCHODEX_BACKUP_RETAIN",
+            "ARCHODEX_RESTORE_BACKUP",
+        ] {
+            reader.forbidden(var, "unless self-hosted with the `rocksdb` feature");
+        }
+
+        if !reader.problems.is_empty() {
+            return Err(EnvDiagnostics {
+                problems: reader.problems,
+            });
+        }
+
+        Ok(Env {
+            bind_addresses,
+            internal_bind_addresses,
+            archodex_domain,
+            cors_origins,
+            #[cfg(feature = "archodex-com")]
+            accounts_shards,
+            #[cfg(not(feature = "archodex-com"))]
+            accounts_shards: vec![AccountsShard {
+                first_account_id: migrator::MIN_ACCOUNT_ID,
+                surrealdb_url: surrealdb_url.clone(),
+            }],
+            #[cfg(not(feature = "archodex-com"))]
+            surrealdb_url,
+            surrealdb_creds,
+            #[cfg(feature = "archodex-com")]
+            endpoint,
+            #[cfg(feature = "archodex-com")]
+            eventbridge_bus_name,
+            dashboard_oidc,
+            #[cfg(not(feature = "archodex-com"))]
+            dev_auth,
+            #[cfg(not(feature = "archodex-com"))]
+            api_private_key: RwLock::new(None),
+            account_id_hmac_key,
+            admin_iam_role_arns,
+            canary_account_id,
+            canary_interval,
+            storage_usage_interval,
+            health_check_interval,
+            inference_interval,
+            event_retention_interval,
+            resource_retention_interval,
+            account_storage_limit_bytes,
+            storage_usage_alert_percent,
+            maintenance_interval,
+            access_audit_interval,
+            access_audit_fix,
+            metrics_enabled,
+            read_only,
+            #[cfg(not(feature = "archodex-com"))]
+            webhook_allow_private_networks,
+            surrealdb_request_id_param,
+            report_rate_limit,
+            report_max_body_bytes,
+            export_upload_max_bytes,
+            max_principal_chain_length,
+            max_resource_id_bytes,
+            hashed_principal_chain_ids,
+            trusted_proxies,
+            client_ip_header,
+            enrichment_cloud_ip_ranges,
+            enrichment_account_names,
+            max_connections,
+            connection_idle_timeout,
+            tcp_keepalive,
+            http2_max_concurrent_streams,
+            http2_keepalive_interval,
+            http2_keepalive_timeout,
+            report_timeout,
+            report_stream_idle_timeout,
+            dashboard_read_timeout,
+            #[cfg(feature = "chaos")]
+            chaos,
+            #[cfg(feature = "kafka")]
+            kafka_report_consumer,
+            #[cfg(feature = "sqs")]
+            sqs_report_consumer,
+            #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+            backup,
+            #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+            restore_backup,
+        })
+    }
+
+    /// Addresses the public API is served on, from the comma separated `ARCHODEX_BIND_ADDRESSES` (e.g.
+    /// `0.0.0.0:5732,[::]:5732`). Defaults to all IPv4 interfaces on `PORT`.
+    #[must_use]
+    pub fn bind_addresses() -> &'static [SocketAddr] {
+        &Self::get().bind_addresses
     }
 
+    /// Addresses the internal routes (`/livez`, `/readyz`, `/metrics`, and the admin API) are served on, from the comma
+    /// separated `ARCHODEX_INTERNAL_BIND_ADDRESSES`. When empty, internal routes are served with the public API. Load
+    /// balancer health checks and Kubernetes probes must use an internal address when one is configured.
     #[must_use]
-    pub fn port() -> u16 {
-        Self::get().port
+    pub fn internal_bind_addresses() -> &'static [SocketAddr] {
+        &Self::get().internal_bind_addresses
     }
 
     #[must_use]
@@ -120,9 +1177,17 @@ impl Env {
         Self::get().archodex_domain.as_str()
     }
 
+    // Origins dashboards may call the public API from in addition to `https://app.<ARCHODEX_DOMAIN>` and the local
+    // dashboard dev server, from the comma separated `ARCHODEX_CORS_ORIGINS`
+    pub(crate) fn cors_origins() -> &'static [String] {
+        &Self::get().cors_origins
+    }
+
+    /// Shards of the accounts database, in ascending order of their first account IDs. Self-hosted backends have a
+    /// single shard.
     #[must_use]
-    pub fn accounts_surrealdb_url() -> &'static str {
-        Self::get().accounts_surrealdb_url.as_str()
+    pub fn accounts_shards() -> &'static [AccountsShard] {
+        &Self::get().accounts_shards
     }
 
     #[cfg(not(feature = "archodex-com"))]
@@ -140,18 +1205,31 @@ impl Env {
         Self::get().endpoint.as_str()
     }
 
-    pub(crate) fn cognito_user_pool_id() -> &'static str {
-        Self::get().cognito_user_pool_id.as_str()
+    // EventBridge bus that resource and account lifecycle events are published to for archodex.com's internal services.
+    // Events aren't published when this is `None`.
+    #[cfg(feature = "archodex-com")]
+    pub(crate) fn eventbridge_bus_name() -> Option<&'static str> {
+        Self::get().eventbridge_bus_name.as_deref()
     }
 
-    pub(crate) fn cognito_client_id() -> &'static str {
-        Self::get().cognito_client_id.as_str()
+    pub(crate) fn dashboard_oidc() -> &'static DashboardOidcConfig {
+        &Self::get().dashboard_oidc
     }
 
-    pub(crate) async fn api_private_key() -> aes_gcm::Key<aes_gcm::Aes128Gcm> {
+    #[cfg(not(feature = "archodex-com"))]
+    pub(crate) fn dev_auth() -> Option<&'static DevAuthConfig> {
+        Self::get().dev_auth.as_ref()
+    }
+
+    // Returns the key used to encrypt and decrypt report API keys and event destination credentials.
+    //
+    // Transient failures to load the key (e.g. the accounts database, SSM, or KMS being briefly unavailable) are retried
+    // a few times. If the key still can't be loaded the error is a 503 `PublicError` so clients retry the request later
+    // instead of the failure taking down the process.
+    pub(crate) async fn api_private_key() -> anyhow::Result<aes_gcm::Key<aes_gcm::Aes128Gcm>> {
         // In self-hosted mode we use either the API private key material from the ARCHODEX_API_PRIVATE_KEY environment
-        // variable or from the account database record. If neither exists we panic. If both exist we also panic, as
-        // this is almost certainly a misconfiguration.
+        // variable or from the account database record. If neither exists or both exist, this is almost certainly a
+        // misconfiguration and an error is returned.
         //
         // The purpose of the ARCHODEX_API_PRIVATE_KEY is to allow the key material to be stored elsewhere outside of
         // the database, but if it isn't set then we generate key material when the account is created and save it in
@@ -161,7 +1239,7 @@ impl Env {
             use serde::Deserialize;
 
             use crate::{
-                db::{QueryCheckFirstRealError as _, accounts_db},
+                db::{QueryCheckFirstRealError as _, primary_accounts_db},
                 surrealdb_deserializers,
             };
 
@@ -175,65 +1253,73 @@ impl Env {
             }
 
             if let Some(api_private_key) = Self::get().api_private_key.read().await.as_ref() {
-                return *api_private_key;
+                return Ok(*api_private_key);
             }
 
             let mut lock = Self::get().api_private_key.write().await;
             if let Some(api_private_key) = lock.as_ref() {
-                return *api_private_key;
+                return Ok(*api_private_key);
             }
 
-            let api_private_key_from_db = accounts_db()
-                .await
-                .expect("should be able to connect to accounts database")
-                .query("SELECT api_private_key FROM account WHERE deleted_at IS NONE LIMIT 1")
-                .await
-                .expect("should be able to query accounts database")
-                .check_first_real_error()
-                .expect("should be able to check query errors")
-                .take::<Option<ApiPrivateKeyResult>>(0)
-                .expect("should be able to take first result")
-                .expect("should be able to extract api_private_key from result")
-                .api_private_key;
-
-            let api_private_key_from_env = match std::env::var("ARCHODEX_API_PRIVATE_KEY") {
-                Ok(hex_bytes) => {
-                    let bytes = hex::decode(hex_bytes).expect(
-                        "environment variable ARCHODEX_API_PRIVATE_KEY must be hex encoded",
-                    );
-
-                    assert!(
-                        bytes.len() == 16,
-                        "environment variable ARCHODEX_API_PRIVATE_KEY must be 16 bytes hex encoded"
-                    );
+            let api_private_key_from_db = retry_transient("load API private key from accounts database", || async {
+                anyhow::Ok(
+                    primary_accounts_db()
+                        .await
+                        .map_err(|err| anyhow::anyhow!("Failed to connect to accounts database: {err}"))?
+                        .query("SELECT api_private_key FROM account WHERE deleted_at IS NONE LIMIT 1")
+                        .await?
+                        .check_first_real_error()?
+                        .take::<Option<ApiPrivateKeyResult>>(0)?
+                        .and_then(|result| result.api_private_key),
+                )
+            })
+            .await?;
 
-                    Some(bytes)
-                }
-                Err(_) => None,
-            };
+            // The format of ARCHODEX_API_PRIVATE_KEY is checked by `Env::validate()` at startup
+            let api_private_key_from_env = std::env::var("ARCHODEX_API_PRIVATE_KEY")
+                .ok()
+                .filter(|hex_bytes| !hex_bytes.is_empty())
+                .map(hex::decode)
+                .transpose()
+                .map_err(|err| {
+                    anyhow::anyhow!("ARCHODEX_API_PRIVATE_KEY must be hex encoded: {err}")
+                })?;
 
             let api_private_key_bytes = match (api_private_key_from_db, api_private_key_from_env) {
-                (Some(_), Some(_)) => panic!(
+                (Some(_), Some(_)) => anyhow::bail!(
                     "ARCHODEX_API_PRIVATE_KEY environment variable must not be set if the variable was not set when this account was created"
                 ),
                 (Some(db_bytes), None) => db_bytes,
                 (None, Some(env_bytes)) => env_bytes,
-                (None, None) => panic!(
+                (None, None) => anyhow::bail!(
                     "Missing ARCHODEX_API_PRIVATE_KEY environment variable, it must be set to the same value as when this account was created"
                 ),
             };
 
+            anyhow::ensure!(
+                api_private_key_bytes.len() == 16,
+                "API private key must be 16 bytes long"
+            );
+
             let api_private_key =
                 aes_gcm::Key::<aes_gcm::Aes128Gcm>::clone_from_slice(&api_private_key_bytes);
 
             lock.replace(api_private_key);
 
-            api_private_key
+            Ok(api_private_key)
         }
 
         #[cfg(feature = "archodex-com")]
         {
-            archodex_com::api_private_key().await.clone()
+            // `archodex_com::api_private_key()` panics if the key can't be fetched or decrypted. Running it in its own
+            // task turns a panic into a retryable error rather than unwinding through the request handler.
+            retry_transient("load API private key from SSM and KMS", || async {
+                tokio::spawn(archodex_com::api_private_key())
+                    .await
+                    .map(|api_private_key| api_private_key.clone())
+                    .map_err(|err| anyhow::anyhow!("Failed to load API private key: {err}"))
+            })
+            .await
         }
     }
 
@@ -245,17 +1331,577 @@ impl Env {
         }
     }
 
+    // Key for deriving opaque external account IDs. External IDs are only used in URLs when this is set.
+    pub(crate) fn account_id_hmac_key() -> Option<&'static [u8]> {
+        Self::get().account_id_hmac_key.as_deref()
+    }
+
+    // IAM roles whose SigV4-signed requests may use the admin API. The admin API is disabled when this is empty.
+    pub(crate) fn admin_iam_role_arns() -> &'static [String] {
+        &Self::get().admin_iam_role_arns
+    }
+
+    /// ID of the account the canary probe reports into and queries. The canary is disabled when this is unset.
+    ///
+    /// The account should be dedicated to the canary, as the probe continuously writes synthetic resources and events
+    /// into it.
+    #[must_use]
+    pub fn canary_account_id() -> Option<&'static str> {
+        Self::get().canary_account_id.as_deref()
+    }
+
+    pub(crate) fn canary_interval() -> std::time::Duration {
+        Self::get().canary_interval
+    }
+
+    // How often the storage usage of each account is estimated
+    pub(crate) fn storage_usage_interval() -> std::time::Duration {
+        Self::get().storage_usage_interval
+    }
+
+    // How often the health check worker checks the backend's dependencies
+    pub(crate) fn health_check_interval() -> std::time::Duration {
+        Self::get().health_check_interval
+    }
+
+    // How often the inference worker derives new edges from each account's events
+    pub(crate) fn inference_interval() -> std::time::Duration {
+        Self::get().inference_interval
+    }
+
+    // How often expired events are pruned from accounts with an event retention period
+    pub(crate) fn event_retention_interval() -> std::time::Duration {
+        Self::get().event_retention_interval
+    }
+
+    // How often stale resources are pruned from accounts with a resource retention period
+    pub(crate) fn resource_retention_interval() -> std::time::Duration {
+        Self::get().resource_retention_interval
+    }
+
+    // Storage each account's plan allows. Storage usage is estimated but not alerted on when this is `None`.
+    pub(crate) fn account_storage_limit_bytes() -> Option<u64> {
+        Self::get().account_storage_limit_bytes
+    }
+
+    // Percentage of `account_storage_limit_bytes()` at which an account's storage usage is alerted on
+    pub(crate) fn storage_usage_alert_percent() -> u64 {
+        Self::get().storage_usage_alert_percent
+    }
+
+    // How often database maintenance runs. Scheduled maintenance is disabled when this is `None`, but it can still be
+    // run through the admin API.
+    pub(crate) fn maintenance_interval() -> Option<std::time::Duration> {
+        Self::get().maintenance_interval
+    }
+
+    // How often the accounts database is audited for drift. Scheduled audits are disabled when this is `None`.
+    pub(crate) fn access_audit_interval() -> Option<std::time::Duration> {
+        Self::get().access_audit_interval
+    }
+
+    // Whether scheduled audits fix the drift they find in the accounts database
+    pub(crate) fn access_audit_fix() -> bool {
+        Self::get().access_audit_fix
+    }
+
+    // Whether Prometheus metrics are served at `/metrics`. Metrics are unauthenticated, so the route should only be
+    // reachable from the operator's network, e.g. by serving it on an internal listener.
+    pub(crate) fn metrics_enabled() -> bool {
+        Self::get().metrics_enabled
+    }
+
+    // Whether the backend starts in read-only mode, e.g. for public demo instances or when failing over to a read
+    // replica. Admins can toggle read-only mode of a running backend.
+    pub(crate) fn read_only() -> bool {
+        Self::get().read_only
+    }
+
+    // Whether webhooks and Kafka event destinations may be delivered to loopback, private, and link-local addresses,
+    // e.g. to endpoints on the same network as a self-hosted backend. The hosted service never delivers events to them.
+    pub(crate) fn webhook_allow_private_networks() -> bool {
+        #[cfg(not(feature = "archodex-com"))]
+        return Self::get().webhook_allow_private_networks;
+
+        #[cfg(feature = "archodex-com")]
+        false
+    }
+
+    // Whether traced queries bind the ID of the request they're sent for to `$archodex_request_id`, so that SurrealDB's
+    // logs of the query's variables can be correlated with the request
+    pub(crate) fn surrealdb_request_id_param() -> bool {
+        Self::get().surrealdb_request_id_param
+    }
+
+    // Rate limit of reports from each report API key. Rate limiting is disabled when this is `None`.
+    pub(crate) fn report_rate_limit() -> Option<&'static ReportRateLimitConfig> {
+        Self::get().report_rate_limit.as_ref()
+    }
+
+    // Largest report body accepted, measured after decompression
+    pub(crate) fn report_max_body_bytes() -> usize {
+        Self::get().report_max_body_bytes
+    }
+
+    // Largest archive uploaded by `POST /account/{account_id}/export`. Uploaded archives are built in memory, so larger
+    // exports must be downloaded instead.
+    pub(crate) fn export_upload_max_bytes() -> usize {
+        Self::get().export_upload_max_bytes
+    }
+
+    // Longest principal chain accepted in reports. Chains are the IDs of `principal_chain` records, so long chains make
+    // large index keys.
+    pub(crate) fn max_principal_chain_length() -> usize {
+        Self::get().max_principal_chain_length
+    }
+
+    // Largest resource ID accepted in reports, counting the bytes of the type and ID of each of its parts. Resource IDs
+    // are the IDs of `resource` records and appear in the IDs of `principal_chain` records, so deeply nested resources
+    // make large index keys.
+    pub(crate) fn max_resource_id_bytes() -> usize {
+        Self::get().max_resource_id_bytes
+    }
+
+    // Whether new principal chains are stored with a hash of the chain as their record ID, keeping the full chain in
+    // their `chain` field. Existing chains keep their IDs until the `hash_principal_chain_ids` admin route rewrites
+    // them.
+    pub(crate) fn hashed_principal_chain_ids() -> bool {
+        Self::get().hashed_principal_chain_ids
+    }
+
+    // Networks of proxies (e.g. load balancers or CDNs) whose forwarding headers are trusted to identify clients
+    pub(crate) fn trusted_proxies() -> &'static [IpNet] {
+        &Self::get().trusted_proxies
+    }
+
+    // Networks of cloud providers (e.g. `aws=3.5.140.0/22`) used by the `cloud_provider` enricher, from the comma
+    // separated `ARCHODEX_ENRICHMENT_CLOUD_IP_RANGES`. The first network containing an address wins.
+    pub(crate) fn enrichment_cloud_ip_ranges() -> &'static [(IpNet, String)] {
+        &Self::get().enrichment_cloud_ip_ranges
+    }
+
+    // Friendly names of cloud accounts (e.g. `123456789012=production`) used by the `account_name` enricher, from the
+    // comma separated `ARCHODEX_ENRICHMENT_ACCOUNT_NAMES`
+    pub(crate) fn enrichment_account_names() -> &'static HashMap<String, String> {
+        &Self::get().enrichment_account_names
+    }
+
+    pub(crate) fn client_ip_header() -> ClientIpHeader {
+        Self::get().client_ip_header
+    }
+
+    /// Maximum number of connections each listener serves at once. Further connections wait in the listener's backlog
+    /// until an open connection closes.
+    #[must_use]
+    pub fn max_connections() -> usize {
+        Self::get().max_connections
+    }
+
+    /// How long a connection may wait for the headers of its next request before it is closed
+    #[must_use]
+    pub fn connection_idle_timeout() -> std::time::Duration {
+        Self::get().connection_idle_timeout
+    }
+
+    /// How long a connection may be silent before TCP keepalive probes check whether the peer is still there
+    #[must_use]
+    pub fn tcp_keepalive() -> std::time::Duration {
+        Self::get().tcp_keepalive
+    }
+
+    /// Maximum number of requests an HTTP/2 connection may have in flight at once. Agents that share a connection for
+    /// their reports queue further requests on the client side rather than opening more connections.
+    #[must_use]
+    pub fn http2_max_concurrent_streams() -> u32 {
+        Self::get().http2_max_concurrent_streams
+    }
+
+    /// How often HTTP/2 connections are pinged to keep them open through proxies and detect peers that went away
+    #[must_use]
+    pub fn http2_keepalive_interval() -> std::time::Duration {
+        Self::get().http2_keepalive_interval
+    }
+
+    /// How long to wait for a reply to an HTTP/2 ping before closing the connection
+    #[must_use]
+    pub fn http2_keepalive_timeout() -> std::time::Duration {
+        Self::get().http2_keepalive_timeout
+    }
+
+    // Time budget of report API requests, which may ingest large reports, and of each batch of streamed reports
+    pub(crate) fn report_timeout() -> std::time::Duration {
+        Self::get().report_timeout
+    }
+
+    // How long a streamed report may go without sending data before it is failed
+    pub(crate) fn report_stream_idle_timeout() -> std::time::Duration {
+        Self::get().report_stream_idle_timeout
+    }
+
+    // Time budget of dashboard API requests that only read data
+    pub(crate) fn dashboard_read_timeout() -> std::time::Duration {
+        Self::get().dashboard_read_timeout
+    }
+
     #[cfg(feature = "archodex-com")]
     pub(crate) fn user_account_limit() -> u32 {
         5
     }
+
+    #[cfg(feature = "chaos")]
+    pub(crate) fn chaos() -> &'static ChaosConfig {
+        &Self::get().chaos
+    }
+
+    #[cfg(feature = "kafka")]
+    #[must_use]
+    pub fn kafka_report_consumer() -> Option<&'static KafkaReportConsumerConfig> {
+        Self::get().kafka_report_consumer.as_ref()
+    }
+
+    #[cfg(feature = "sqs")]
+    #[must_use]
+    pub fn sqs_report_consumer() -> Option<&'static SqsReportConsumerConfig> {
+        Self::get().sqs_report_consumer.as_ref()
+    }
+
+    // Scheduled backups are disabled when this is `None`
+    #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+    pub(crate) fn backup() -> Option<&'static BackupConfig> {
+        Self::get().backup.as_ref()
+    }
+
+    // Snapshot restored at startup, unless it is the snapshot last restored
+    #[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+    pub(crate) fn restore_backup() -> Option<&'static BackupLocation> {
+        Self::get().restore_backup.as_ref()
+    }
+}
+
+// Dashboard access tokens are issued by archodex.com's Cognito user pool unless another OpenID Connect provider (e.g.
+// Keycloak, Auth0, or Azure AD) is configured with `ARCHODEX_OIDC_ISSUER` and `ARCHODEX_OIDC_AUDIENCE`
+fn dashboard_oidc_config(reader: &mut EnvReader) -> DashboardOidcConfig {
+    let user_id_claim = reader.with_default("ARCHODEX_OIDC_USER_ID_CLAIM", "sub");
+
+    if let Some((issuer, audience)) = reader.pair(
+        "ARCHODEX_OIDC_ISSUER, ARCHODEX_OIDC_AUDIENCE",
+        "ARCHODEX_OIDC_ISSUER",
+        "ARCHODEX_OIDC_AUDIENCE",
+    ) {
+        reader.forbidden("COGNITO_USER_POOL_ID", "when ARCHODEX_OIDC_ISSUER is set");
+        reader.forbidden("COGNITO_CLIENT_ID", "when ARCHODEX_OIDC_ISSUER is set");
+
+        // Keys are fetched from the issuer, so they must be fetched over TLS unless the issuer runs locally
+        if !(issuer.starts_with("https://")
+            || issuer.starts_with("http://localhost:")
+            || issuer.starts_with("http://127.0.0.1:"))
+        {
+            reader.problem(
+                "ARCHODEX_OIDC_ISSUER",
+                format!("{issuer:?} must be an https:// URL"),
+            );
+        }
+
+        return DashboardOidcConfig {
+            issuer,
+            audience: DashboardOidcAudience::Aud(audience),
+            user_id_claim,
+        };
+    }
+
+    let user_pool_id = reader.with_default("COGNITO_USER_POOL_ID", "us-west-2_Mf1K95El6");
+    let client_id = reader.with_default("COGNITO_CLIENT_ID", "1a5vsre47o6pa39p3p81igfken");
+
+    // User pool IDs are prefixed with the pool's region, e.g. `us-west-2_Mf1K95El6`
+    let region = match user_pool_id.split_once('_') {
+        Some((region, _)) if !region.is_empty() => region.to_string(),
+        _ => {
+            reader.problem(
+                "COGNITO_USER_POOL_ID",
+                format!("{user_pool_id:?} is not a Cognito user pool ID"),
+            );
+            String::new()
+        }
+    };
+
+    DashboardOidcConfig {
+        issuer: format!("https://cognito-idp.{region}.amazonaws.com/{user_pool_id}"),
+        audience: DashboardOidcAudience::CognitoClientId(client_id),
+        user_id_claim,
+    }
+}
+
+// Dev auth is opt-in by setting both `ARCHODEX_DEV_AUTH_TOKEN` and `ARCHODEX_DEV_AUTH_USER_ID`
+#[cfg(not(feature = "archodex-com"))]
+fn dev_auth_config(reader: &mut EnvReader) -> Option<DevAuthConfig> {
+    let (token, user_id) = reader.pair(
+        "ARCHODEX_DEV_AUTH_TOKEN, ARCHODEX_DEV_AUTH_USER_ID",
+        "ARCHODEX_DEV_AUTH_TOKEN",
+        "ARCHODEX_DEV_AUTH_USER_ID",
+    )?;
+
+    // The token grants full dashboard access as the user, so it must not be trivially guessable
+    if token.len() < 32 {
+        reader.problem("ARCHODEX_DEV_AUTH_TOKEN", "Must be at least 32 characters");
+    }
+
+    let user_id = match surrealdb::Uuid::parse_str(&user_id) {
+        Ok(user_id) => user_id,
+        Err(_) => {
+            reader.problem(
+                "ARCHODEX_DEV_AUTH_USER_ID",
+                format!("{user_id:?} is not a UUID"),
+            );
+            surrealdb::Uuid::nil()
+        }
+    };
+
+    Some(DevAuthConfig { token, user_id })
+}
+
+fn report_rate_limit_config(reader: &mut EnvReader) -> Option<ReportRateLimitConfig> {
+    let reports_per_second = reader.with_default("ARCHODEX_REPORT_RATE_LIMIT_PER_SECOND", "0");
+    let reports_per_second = match reports_per_second.parse::<f64>() {
+        Ok(reports_per_second) if reports_per_second.is_finite() && reports_per_second >= 0.0 => {
+            reports_per_second
+        }
+        _ => {
+            reader.problem(
+                "ARCHODEX_REPORT_RATE_LIMIT_PER_SECOND",
+                format!(
+                    "{reports_per_second:?} is not a non-negative number of reports per second"
+                ),
+            );
+            0.0
+        }
+    };
+
+    let burst = reader.with_default("ARCHODEX_REPORT_RATE_LIMIT_BURST", "60");
+    let burst = match burst.parse::<u32>() {
+        Ok(burst) if burst > 0 => burst,
+        _ => {
+            reader.problem(
+                "ARCHODEX_REPORT_RATE_LIMIT_BURST",
+                format!("{burst:?} is not a positive number of reports"),
+            );
+            0
+        }
+    };
+
+    // A rate of 0, the default, disables rate limiting
+    if reports_per_second == 0.0 {
+        return None;
+    }
+
+    Some(ReportRateLimitConfig {
+        reports_per_second,
+        burst,
+    })
+}
+
+#[cfg(feature = "chaos")]
+fn chaos_config(reader: &mut EnvReader) -> ChaosConfig {
+    let mut probability = |var: &'static str| {
+        let value = reader.with_default(var, "0");
+        match value.parse::<f64>() {
+            Ok(probability) if (0.0..=1.0).contains(&probability) => probability,
+            _ => {
+                reader.problem(
+                    var,
+                    format!("{value:?} is not a probability between 0 and 1"),
+                );
+                0.0
+            }
+        }
+    };
+
+    let db_error_probability = probability("ARCHODEX_CHAOS_DB_ERROR_PROBABILITY");
+    let auth_failure_probability = probability("ARCHODEX_CHAOS_AUTH_FAILURE_PROBABILITY");
+    let latency_probability = probability("ARCHODEX_CHAOS_LATENCY_PROBABILITY");
+
+    let latency_ms = reader.with_default("ARCHODEX_CHAOS_LATENCY_MS", "1000");
+    let latency = latency_ms.parse::<u64>().map_or_else(
+        |_| {
+            reader.problem(
+                "ARCHODEX_CHAOS_LATENCY_MS",
+                format!("{latency_ms:?} is not a number of milliseconds"),
+            );
+            std::time::Duration::ZERO
+        },
+        std::time::Duration::from_millis,
+    );
+
+    ChaosConfig {
+        db_error_probability,
+        auth_failure_probability,
+        latency_probability,
+        latency,
+    }
 }
 
-fn env_with_default_for_empty(var: &str, default: &str) -> String {
-    match std::env::var(var) {
-        Err(std::env::VarError::NotPresent) => default.to_string(),
-        Ok(value) if value.is_empty() => default.to_string(),
-        Ok(value) => value,
-        Err(err) => panic!("Invalid {var} env var: {err:?}"),
+#[cfg(feature = "kafka")]
+fn kafka_report_consumer_config(reader: &mut EnvReader) -> Option<KafkaReportConsumerConfig> {
+    let (brokers, topic) = reader.pair(
+        "ARCHODEX_KAFKA_REPORTS_BROKERS, ARCHODEX_KAFKA_REPORTS_TOPIC",
+        "ARCHODEX_KAFKA_REPORTS_BROKERS",
+        "ARCHODEX_KAFKA_REPORTS_TOPIC",
+    )?;
+
+    let partitions = reader
+        .optional("ARCHODEX_KAFKA_REPORTS_PARTITIONS")
+        .and_then(|partitions| {
+            match partitions
+                .split(',')
+                .map(|partition| partition.trim().parse::<i32>())
+                .collect::<Result<Vec<_>, _>>()
+            {
+                Ok(partitions) => Some(partitions),
+                Err(_) => {
+                    reader.problem(
+                        "ARCHODEX_KAFKA_REPORTS_PARTITIONS",
+                        format!(
+                            "{partitions:?} is not a comma-separated list of partition numbers"
+                        ),
+                    );
+                    None
+                }
+            }
+        });
+
+    let sasl_plain_credentials = reader.pair(
+        "ARCHODEX_KAFKA_REPORTS_SASL_USERNAME, ARCHODEX_KAFKA_REPORTS_SASL_PASSWORD",
+        "ARCHODEX_KAFKA_REPORTS_SASL_USERNAME",
+        "ARCHODEX_KAFKA_REPORTS_SASL_PASSWORD",
+    );
+
+    let tls = reader.with_default("ARCHODEX_KAFKA_REPORTS_TLS", "false");
+    let tls = match tls.as_str() {
+        "true" => true,
+        "false" => false,
+        _ => {
+            reader.problem(
+                "ARCHODEX_KAFKA_REPORTS_TLS",
+                format!("{tls:?} must be \"true\" or \"false\""),
+            );
+            false
+        }
+    };
+
+    Some(KafkaReportConsumerConfig {
+        brokers: brokers
+            .split(',')
+            .map(|broker| broker.trim().to_owned())
+            .collect(),
+        topic,
+        consumer_group: reader
+            .with_default("ARCHODEX_KAFKA_REPORTS_CONSUMER_GROUP", "archodex-backend"),
+        partitions,
+        sasl_plain_credentials,
+        tls,
+    })
+}
+
+#[cfg(feature = "sqs")]
+fn sqs_report_consumer_config(reader: &mut EnvReader) -> Option<SqsReportConsumerConfig> {
+    let queue_url = reader.optional("ARCHODEX_SQS_REPORTS_QUEUE_URL")?;
+
+    let pollers = reader.with_default("ARCHODEX_SQS_REPORTS_POLLERS", "1");
+    let pollers = match pollers.parse::<usize>() {
+        Ok(pollers) if pollers > 0 => pollers,
+        _ => {
+            reader.problem(
+                "ARCHODEX_SQS_REPORTS_POLLERS",
+                format!("{pollers:?} is not a positive number"),
+            );
+            1
+        }
+    };
+
+    Some(SqsReportConsumerConfig { queue_url, pollers })
+}
+
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+fn backup_location(
+    reader: &mut EnvReader,
+    var: &'static str,
+    surrealdb_url: &str,
+) -> Option<BackupLocation> {
+    let location = reader.optional(var)?;
+
+    if !surrealdb_url.starts_with("rocksdb:") {
+        reader.problem(
+            var,
+            "Backups are only supported with a rocksdb:// SURREALDB_URL. Back up remote SurrealDB instances with their own tooling.",
+        );
+        return None;
+    }
+
+    match BackupLocation::parse(&location) {
+        Ok(location) => Some(location),
+        Err(problem) => {
+            reader.problem(var, problem);
+            None
+        }
+    }
+}
+
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+fn backup_config(reader: &mut EnvReader, surrealdb_url: &str) -> Option<BackupConfig> {
+    let destination = backup_location(reader, "ARCHODEX_BACKUP_DESTINATION", surrealdb_url);
+
+    let interval = reader.positive_seconds("ARCHODEX_BACKUP_INTERVAL_SECONDS", "86400");
+
+    let retain = reader.with_default("ARCHODEX_BACKUP_RETAIN", "7");
+    let retain = match retain.parse::<usize>() {
+        Ok(retain) if retain > 0 => retain,
+        _ => {
+            reader.problem(
+                "ARCHODEX_BACKUP_RETAIN",
+                format!("{retain:?} is not a positive number"),
+            );
+            1
+        }
+    };
+
+    Some(BackupConfig {
+        destination: destination?,
+        interval,
+        retain,
+    })
+}
+
+const TRANSIENT_RETRY_ATTEMPTS: u32 = 3;
+const TRANSIENT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(250);
+const SERVICE_UNAVAILABLE_RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Calls `f` until it succeeds, up to `TRANSIENT_RETRY_ATTEMPTS` times with linear backoff. If every attempt fails, the
+// last error is logged and a 503 `PublicError` asking the client to retry is returned in its place.
+async fn retry_transient<T, F, Fut>(description: &str, f: F) -> anyhow::Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < TRANSIENT_RETRY_ATTEMPTS => {
+                tracing::warn!(?err, attempt, "Failed to {description}, retrying");
+                tokio::time::sleep(TRANSIENT_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            Err(err) => {
+                tracing::error!(?err, attempts = attempt, "Failed to {description}");
+
+                return Err(anyhow::anyhow!(
+                    archodex_error::PublicError::new(
+                        axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                        "Service temporarily unavailable, please retry",
+                    )
+                    .with_retry_after(SERVICE_UNAVAILABLE_RETRY_AFTER)
+                ));
+            }
+        }
     }
 }
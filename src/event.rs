@@ -1,10 +1,11 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
+use utoipa::ToSchema;
 
-use crate::{principal_chain::PrincipalChainId, resource::ResourceId};
+use crate::{db::PreparedQuery, principal_chain::PrincipalChainId, resource::ResourceId};
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, ToSchema)]
 pub(crate) struct Event {
     pub(crate) principal: ResourceId,
     pub(crate) r#type: String,
@@ -103,7 +104,23 @@ impl<'de> Deserialize<'de> for Event {
 }
 
 impl Event {
-    pub(crate) fn get_all() -> &'static str {
-        "$events = SELECT * OMIT id FROM event PARALLEL;"
+    // Selects all events seen during the `$since`/`$until` time range. Either bound may be NONE.
+    pub(crate) fn get_all() -> &'static PreparedQuery {
+        static QUERY: PreparedQuery = PreparedQuery::new(
+            "$events = SELECT * OMIT id FROM event WHERE ($since IS NONE OR last_seen_at >= $since) AND ($until IS NONE OR first_seen_at <= $until) PARALLEL;",
+        );
+
+        &QUERY
+    }
+
+    // Selects the events whose principal is one of the resources in `$resources`, e.g. the current page of resources,
+    // and that were seen during the `$since`/`$until` time range
+    pub(crate) fn get_for_principals_in_resources() -> &'static str {
+        "$events = SELECT * OMIT id FROM event WHERE $resources.id CONTAINS in AND ($since IS NONE OR last_seen_at >= $since) AND ($until IS NONE OR first_seen_at <= $until) PARALLEL;"
+    }
+
+    // Removes events from `$events` that were not seen during the `$since`/`$until` time range
+    pub(crate) fn filter_by_time_range() -> &'static str {
+        "$events = SELECT * FROM $events WHERE ($since IS NONE OR last_seen_at >= $since) AND ($until IS NONE OR first_seen_at <= $until);"
     }
 }
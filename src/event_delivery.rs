@@ -0,0 +1,802 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs as _},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rand::Rng as _;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use surrealdb::{Surreal, engine::any::Any};
+use tracing::{instrument, warn};
+
+use archodex_error::anyhow::{self, Context as _, bail, ensure};
+
+use crate::{
+    Result,
+    account::Account,
+    clock,
+    db::QueryCheckFirstRealError as _,
+    env::Env,
+    event_destination::{
+        EventDestination, EventDestinationCredentials, EventDestinationTarget,
+        surrealdb_thing_from_event_destination_id,
+    },
+    resource::ResourceId,
+    rng, surrealdb_deserializers,
+    worker::{self, PendingAccounts},
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const BATCH_SIZE: u32 = 100;
+const MAX_ATTEMPTS: u32 = 10;
+const MAX_BACKOFF_SECONDS: i64 = 60 * 60;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+// Connecting to Kafka brokers and producing a record are retried with backoff until this deadline, so unreachable
+// brokers or missing topics fail the delivery rather than holding up deliveries of other destinations
+#[cfg(feature = "kafka")]
+const KAFKA_TIMEOUT: Duration = Duration::from_secs(10);
+
+static PENDING_ACCOUNTS: PendingAccounts = PendingAccounts::new();
+
+// Types of the events that can be delivered, which event destinations can subscribe to
+pub(crate) const DELIVERED_EVENT_TYPES: [&str; 4] = [
+    "report.ingested",
+    "resource.created",
+    "event_type.observed",
+    "storage_usage.alert",
+];
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+pub(crate) enum DeliveredEvent {
+    #[serde(rename = "report.ingested")]
+    ReportIngested {
+        account_id: String,
+        occurred_at: DateTime<Utc>,
+        resource_captures: usize,
+        event_captures: usize,
+    },
+    // A report contained a resource that didn't exist before
+    #[serde(rename = "resource.created")]
+    ResourceCreated {
+        account_id: String,
+        occurred_at: DateTime<Utc>,
+        resource_id: ResourceId,
+    },
+    // A report contained an event of a type that hadn't been seen in the account before. The principal and resource
+    // are those of the first event of the type.
+    #[serde(rename = "event_type.observed")]
+    EventTypeObserved {
+        account_id: String,
+        occurred_at: DateTime<Utc>,
+        event_type: String,
+        principal: ResourceId,
+        resource: ResourceId,
+    },
+    // The account's estimated storage usage reached the alert threshold of its storage limit
+    #[serde(rename = "storage_usage.alert")]
+    StorageUsageAlert {
+        account_id: String,
+        occurred_at: DateTime<Utc>,
+        estimated_bytes: u64,
+        limit_bytes: u64,
+    },
+}
+
+impl DeliveredEvent {
+    fn r#type(&self) -> &'static str {
+        match self {
+            DeliveredEvent::ReportIngested { .. } => "report.ingested",
+            DeliveredEvent::ResourceCreated { .. } => "resource.created",
+            DeliveredEvent::EventTypeObserved { .. } => "event_type.observed",
+            DeliveredEvent::StorageUsageAlert { .. } => "storage_usage.alert",
+        }
+    }
+}
+
+// Selects the account's event destinations into `$event_destinations`, which `enqueue_event_statement()` statements
+// enqueue events for. This must precede them in the transaction. Webhook destinations receive events only once their
+// endpoint is verified.
+pub(crate) const SELECT_EVENT_DESTINATIONS: &str = "LET $event_destinations = SELECT id, event_types FROM event_destination WHERE target.kind != 'webhook' OR verified_at IS NOT NONE;";
+
+// Types of the events at least one of an account's event destinations subscribes to. Statements enqueueing other events
+// are left out of transactions, so that reports ingested into accounts without destinations aren't slowed down.
+#[derive(Default)]
+pub(crate) struct SubscribedEventTypes(HashSet<String>);
+
+impl SubscribedEventTypes {
+    #[instrument(err, skip_all)]
+    pub(crate) async fn get(db: &Surreal<Any>) -> anyhow::Result<Self> {
+        let destinations_event_types = db
+            .query("SELECT VALUE event_types FROM event_destination WHERE target.kind != 'webhook' OR verified_at IS NOT NONE")
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<Option<Vec<String>>>>(0)?;
+
+        Ok(Self(
+            destinations_event_types
+                .into_iter()
+                .flat_map(|event_types| {
+                    event_types
+                        .unwrap_or_else(|| DELIVERED_EVENT_TYPES.map(str::to_string).to_vec())
+                })
+                .collect(),
+        ))
+    }
+
+    // Whether no event destination subscribes to any event, in which case no events are enqueued
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// Returns a statement that enqueues `event` for every destination in `$event_destinations` subscribed to the event's
+// type, or an empty string if no destination is. The statement should be part of the transaction that produced the
+// event so the event is enqueued if and only if the change commits. The event is written into the statement rather than
+// bound, so that it can be used in the body of conditional statements.
+pub(crate) fn enqueue_event_statement(
+    event: &DeliveredEvent,
+    subscribed_event_types: &SubscribedEventTypes,
+) -> anyhow::Result<String> {
+    if !subscribed_event_types.0.contains(event.r#type()) {
+        return Ok(String::new());
+    }
+
+    let event_type = surrealdb::sql::Value::from(event.r#type());
+    let payload = crate::value::surrealdb_value_from_json_value(serde_json::to_value(event)?);
+
+    Ok(format!(
+        "FOR $destination IN $event_destinations {{
+            IF $destination.event_types IS NONE OR $destination.event_types CONTAINS {event_type} {{
+                CREATE event_delivery CONTENT {{ destination: $destination.id, payload: {payload} }} RETURN NONE;
+            }};
+        }};"
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct PendingEventDelivery {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    destination: EventDestination,
+    payload: serde_json::Value,
+    attempts: u32,
+}
+
+impl PendingEventDelivery {
+    fn thing(&self) -> surrealdb::sql::Thing {
+        surrealdb::sql::Thing::from(("event_delivery", self.id.as_str()))
+    }
+}
+
+// Records an attempt to deliver an event in the destination's delivery history
+const RECORD_ATTEMPT_QUERY: &str = "CREATE event_delivery_attempt CONTENT { destination: $destination, delivery_id: $delivery_id, event_type: $event_type, payload: $payload, succeeded: $succeeded, status_code: $status_code, error: $error, attempted_at: $now } RETURN NONE";
+
+// Status code of the webhook response that failed a delivery, if the endpoint responded at all
+fn error_status_code(err: &anyhow::Error) -> Option<u16> {
+    err.chain()
+        .find_map(|err| err.downcast_ref::<reqwest::Error>())
+        .and_then(reqwest::Error::status)
+        .map(|status| status.as_u16())
+}
+
+/// Runs the event delivery worker until the process exits.
+///
+/// The worker delivers pending events in each account's `event_delivery` outbox to the account's event destinations.
+/// Accounts are processed as soon as this process enqueues events for them, and every account is swept for retries and
+/// events enqueued elsewhere every minute. Events are removed from the outbox only after the destination accepts them.
+/// Failed deliveries are retried with exponential backoff and are dead-lettered after repeated failures.
+pub async fn run_worker() {
+    worker::run_on_demand(
+        "event_delivery",
+        &PENDING_ACCOUNTS,
+        SWEEP_INTERVAL,
+        deliver_pending_events,
+    )
+    .await;
+}
+
+// Wakes the worker to deliver events enqueued for the account, once the transaction enqueueing them has committed
+pub(crate) fn wake_worker(account_id: &str) {
+    PENDING_ACCOUNTS.mark(account_id);
+}
+
+// Delivers pending events of the accounts in `account_ids`, or of every account if `None`
+#[instrument(err, skip_all)]
+pub(crate) async fn deliver_pending_events(account_ids: Option<BTreeSet<String>>) -> Result<()> {
+    worker::for_each_account("event_delivery", account_ids, |account| async move {
+        deliver_account_pending_events(&account).await
+    })
+    .await
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn deliver_account_pending_events(account: &Account) -> anyhow::Result<()> {
+    let pending_event_deliveries = account
+        .resources_db()
+        .await?
+        .query("SELECT * FROM event_delivery WHERE status = 'pending' AND next_attempt_at <= $now ORDER BY created_at LIMIT $limit FETCH destination")
+        .bind(("limit", BATCH_SIZE))
+        .bind(("now", clock::now_value()))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<PendingEventDelivery>>(0)?;
+
+    if pending_event_deliveries.is_empty() {
+        return Ok(());
+    }
+
+    // A full batch may have left more events pending, which are delivered right after this batch
+    if pending_event_deliveries.len() == BATCH_SIZE as usize {
+        wake_worker(account.id());
+    }
+
+    // The resources database connection is released while publishing so that a slow destination doesn't block other
+    // users of a non-concurrent (e.g. RocksDB) database.
+    for event_delivery in pending_event_deliveries {
+        let result = publish(
+            &event_delivery.destination,
+            account.id(),
+            &event_delivery.payload,
+        )
+        .await;
+
+        let db = account.resources_db().await?;
+
+        let (status_code, error) = match &result {
+            Ok(status_code) => (*status_code, None),
+            Err(err) => (error_status_code(err), Some(format!("{err:#}"))),
+        };
+
+        let record_attempt = db
+            .query(RECORD_ATTEMPT_QUERY)
+            .bind((
+                "destination",
+                surrealdb_thing_from_event_destination_id(event_delivery.destination.id()),
+            ))
+            .bind(("delivery_id", event_delivery.id.clone()))
+            .bind((
+                "event_type",
+                event_delivery
+                    .payload
+                    .get("type")
+                    .and_then(serde_json::Value::as_str)
+                    .map(str::to_owned),
+            ))
+            .bind((
+                "payload",
+                crate::value::surrealdb_value_from_json_value(event_delivery.payload.clone()),
+            ))
+            .bind(("succeeded", result.is_ok()))
+            .bind(("status_code", status_code))
+            .bind(("error", error))
+            .bind(("now", clock::now_value()));
+
+        match result {
+            Ok(_) => {
+                record_attempt
+                    .query("DELETE $event_delivery")
+                    .bind(("event_delivery", event_delivery.thing()))
+                    .await?
+                    .check_first_real_error()?;
+            }
+            Err(err) => {
+                let attempts = event_delivery.attempts + 1;
+                let status = if attempts >= MAX_ATTEMPTS {
+                    "dead_lettered"
+                } else {
+                    "pending"
+                };
+                let backoff_seconds = 2_i64.saturating_pow(attempts).min(MAX_BACKOFF_SECONDS);
+
+                warn!(
+                    event_delivery_id = event_delivery.id,
+                    event_destination_id = %event_delivery.destination.id(),
+                    attempts,
+                    status,
+                    ?err,
+                    "Failed to deliver event"
+                );
+
+                record_attempt
+                    .query("UPDATE $event_delivery SET attempts = $attempts, status = $status, last_error = $error, next_attempt_at = $now + type::duration($backoff) RETURN NONE")
+                    .bind(("event_delivery", event_delivery.thing()))
+                    .bind(("attempts", attempts))
+                    .bind(("status", status))
+                    .bind(("backoff", format!("{backoff_seconds}s")))
+                    .await?
+                    .check_first_real_error()?;
+            }
+        }
+    }
+
+    // Delivery history is pruned only while deliveries are attempted, so an account's last week of history is kept
+    // even after its events stop
+    account
+        .resources_db()
+        .await?
+        .query("DELETE event_delivery_attempt WHERE attempted_at < $now - 1w RETURN NONE")
+        .bind(("now", clock::now_value()))
+        .await?
+        .check_first_real_error()?;
+
+    Ok(())
+}
+
+// Returns the status code of the webhook endpoint's response, or `None` for other destinations
+#[instrument(err, skip(payload), fields(event_destination_id = %destination.id()))]
+async fn publish(
+    destination: &EventDestination,
+    account_id: &str,
+    payload: &serde_json::Value,
+) -> anyhow::Result<Option<u16>> {
+    let credentials = destination.credentials().await?;
+    let body = serde_json::to_string(payload)?;
+
+    match destination.target() {
+        EventDestinationTarget::Webhook { url } => {
+            Box::pin(publish_webhook(url, credentials, body))
+                .await
+                .map(Some)
+        }
+        #[cfg(feature = "sqs")]
+        EventDestinationTarget::Sqs { queue_url, region } => {
+            Box::pin(publish_sqs(queue_url, region, credentials, body))
+                .await
+                .map(|()| None)
+        }
+        #[cfg(feature = "kafka")]
+        EventDestinationTarget::Kafka { brokers, topic } => {
+            Box::pin(publish_kafka(brokers, topic, credentials, account_id, body))
+                .await
+                .map(|()| None)
+        }
+        #[allow(unreachable_patterns)]
+        _ => {
+            let _ = (credentials, account_id, body);
+            bail!("Event destination kind is not supported by this backend")
+        }
+    }
+}
+
+// Webhook URLs are chosen by users but requested by the backend, so they must not reach the backend's own network, e.g.
+// the instance metadata service. Hosts given as IP addresses are checked here, and hostnames are checked when they are
+// resolved to connect. Returns the reason a URL is refused.
+pub(crate) fn validate_webhook_url(url: &str) -> anyhow::Result<()> {
+    let url = reqwest::Url::parse(url).context("Invalid webhook URL")?;
+
+    #[cfg(feature = "archodex-com")]
+    ensure!(url.scheme() == "https", "Webhook URL must be an HTTPS URL");
+    #[cfg(not(feature = "archodex-com"))]
+    ensure!(
+        matches!(url.scheme(), "http" | "https"),
+        "Webhook URL must be an HTTP or HTTPS URL"
+    );
+
+    let Some(host) = url.host_str() else {
+        bail!("Webhook URL must have a host");
+    };
+
+    // IPv6 hosts are bracketed, and IPv4 hosts are normalized to dotted decimal by parsing
+    if let Ok(ip) = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        ensure!(
+            Env::webhook_allow_private_networks() || is_public_ip(ip),
+            "Webhook URL must not be a loopback, private, or link-local address"
+        );
+    }
+
+    Ok(())
+}
+
+// Kafka brokers are chosen by users but connected to by the backend, so like webhook URLs they must not reach the
+// backend's own network. Brokers given as IP addresses are checked here, and hostnames are checked when they are
+// resolved to connect. Returns the reason a broker is refused.
+#[cfg(feature = "kafka")]
+pub(crate) fn validate_kafka_broker(broker: &str) -> anyhow::Result<()> {
+    let (host, _port) = parse_kafka_broker(broker)?;
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        ensure!(
+            Env::webhook_allow_private_networks() || is_public_ip(ip),
+            "Kafka broker must not be a loopback, private, or link-local address"
+        );
+    }
+
+    Ok(())
+}
+
+// Splits a broker like `host:port` or `[ipv6]:port` into its host and port
+#[cfg(feature = "kafka")]
+fn parse_kafka_broker(broker: &str) -> anyhow::Result<(&str, u16)> {
+    let Some((host, port)) = broker.rsplit_once(':') else {
+        bail!("Kafka broker {broker:?} must be a host and port");
+    };
+
+    let port = port
+        .parse()
+        .with_context(|| format!("Kafka broker {broker:?} has an invalid port"))?;
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    ensure!(!host.is_empty(), "Kafka broker {broker:?} must have a host");
+
+    Ok((host, port))
+}
+
+// Whether an address is reachable on the public internet, as opposed to loopback, private, link-local (including the
+// instance metadata service), shared, multicast, and other special-purpose ranges
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ipv4(ip);
+            }
+
+            let segments = ip.segments();
+
+            // NAT64 addresses embed an IPv4 address in their last 32 bits
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., high, low] = segments;
+                return is_public_ipv4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)));
+            }
+
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                // Documentation addresses
+                || segments[..2] == [0x2001, 0xdb8])
+        }
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network"
+        || a == 0
+        // Shared address space used for carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved
+        || a >= 240)
+}
+
+// Resolves a host like the system resolver, but refuses hosts that resolve to any non-public address. Checking the
+// addresses connected to, rather than the URL, also covers hostnames that resolve to private addresses.
+async fn resolve_public_host(host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    let addrs = {
+        let host = host.to_owned();
+        tokio::task::spawn_blocking(move || (host.as_str(), port).to_socket_addrs()).await??
+    }
+    .collect::<Vec<_>>();
+
+    if let Some(addr) = addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+        bail!("Host {host} resolves to non-public address {}", addr.ip());
+    }
+
+    Ok(addrs)
+}
+
+// Resolves webhook hosts with `resolve_public_host`
+struct PublicAddressResolver;
+
+impl reqwest::dns::Resolve for PublicAddressResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_owned();
+
+        Box::pin(async move {
+            let addrs = resolve_public_host(&host, 0).await?;
+
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+// Redirects aren't followed, as they could point webhook requests at addresses that weren't validated, and proxies
+// aren't used, as they would resolve hosts themselves
+static WEBHOOK_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    let builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy();
+
+    let builder = if Env::webhook_allow_private_networks() {
+        builder
+    } else {
+        builder.dns_resolver(Arc::new(PublicAddressResolver))
+    };
+
+    builder
+        .build()
+        .expect("Webhook HTTP client should be constructible")
+});
+
+fn webhook_secret(credentials: Option<EventDestinationCredentials>) -> anyhow::Result<String> {
+    match credentials {
+        Some(EventDestinationCredentials::Webhook { secret }) => Ok(secret),
+        Some(_) => bail!("Webhook event destination has credentials of another kind"),
+        None => bail!("Webhook event destination has no signing secret"),
+    }
+}
+
+// Webhook requests are signed like `X-Archodex-Signature: t=<unix timestamp>,v1=<signature>`, where the signature is
+// the hex encoded HMAC-SHA256 of `<unix timestamp>.<body>` keyed with the destination's secret. Receivers should
+// recompute the signature and reject stale timestamps to prevent replays.
+async fn send_webhook(url: &str, secret: &str, body: String) -> anyhow::Result<reqwest::Response> {
+    use hmac::{Hmac, Mac as _};
+    use sha2::Sha256;
+
+    validate_webhook_url(url)?;
+
+    let timestamp = clock::now().timestamp();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC should accept keys of any length");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    let signature = hex::encode(mac.finalize().into_bytes());
+
+    WEBHOOK_CLIENT
+        .post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(
+            "x-archodex-signature",
+            format!("t={timestamp},v1={signature}"),
+        )
+        .body(body)
+        .send()
+        .await
+        .context("Failed to send webhook request")
+}
+
+// Returns the status code of the endpoint's response
+async fn publish_webhook(
+    url: &str,
+    credentials: Option<EventDestinationCredentials>,
+    body: String,
+) -> anyhow::Result<u16> {
+    let secret = webhook_secret(credentials)?;
+
+    let response = send_webhook(url, &secret, body).await?;
+    let status = response.status();
+
+    response
+        .error_for_status()
+        .context("Webhook endpoint rejected the event")?;
+
+    Ok(status.as_u16())
+}
+
+// Verifies that a webhook destination's endpoint is controlled by whoever configured it. A signed
+// `webhook.verification` event with a random challenge is sent to the endpoint, which must respond with the challenge,
+// either as the whole response body or as the `challenge` field of a JSON body.
+#[instrument(err, skip_all, fields(event_destination_id = %destination.id()))]
+pub(crate) async fn verify_webhook(destination: &EventDestination) -> anyhow::Result<()> {
+    let EventDestinationTarget::Webhook { url } = destination.target() else {
+        bail!("Only webhook event destinations are verified");
+    };
+
+    let secret = webhook_secret(destination.credentials().await?)?;
+    let challenge = hex::encode(rng::with_rng(|rng| rng.r#gen::<[u8; 16]>()));
+
+    let body = serde_json::to_string(&json!({
+        "type": "webhook.verification",
+        "event_destination_id": destination.id(),
+        "challenge": challenge,
+    }))?;
+
+    let response = send_webhook(url, &secret, body)
+        .await?
+        .error_for_status()
+        .context("Webhook endpoint rejected the verification challenge")?
+        .text()
+        .await
+        .context("Failed to read webhook endpoint response")?;
+
+    let echoed_challenge = serde_json::from_str::<serde_json::Value>(&response)
+        .ok()
+        .and_then(|response| {
+            response
+                .get("challenge")
+                .and_then(serde_json::Value::as_str)
+                .map(str::to_owned)
+        })
+        .unwrap_or_else(|| response.trim().to_owned());
+
+    ensure!(
+        echoed_challenge == challenge,
+        "Webhook endpoint did not respond with the verification challenge"
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "sqs")]
+async fn publish_sqs(
+    queue_url: &str,
+    region: &str,
+    credentials: Option<EventDestinationCredentials>,
+    body: String,
+) -> anyhow::Result<()> {
+    let credentials = match credentials {
+        Some(EventDestinationCredentials::Sqs {
+            access_key_id,
+            secret_access_key,
+            session_token,
+        }) => aws_sdk_sqs::config::Credentials::new(
+            access_key_id,
+            secret_access_key,
+            session_token,
+            None,
+            "archodex-event-destination",
+        ),
+        Some(_) => bail!("SQS event destination has credentials of another kind"),
+        // Never fall back to the backend's own AWS credentials, which could send to any queue the backend can reach
+        None => bail!("SQS event destination has no credentials"),
+    };
+
+    let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .region(aws_config::Region::new(region.to_owned()))
+        .credentials_provider(credentials)
+        .load()
+        .await;
+
+    aws_sdk_sqs::Client::new(&config)
+        .send_message()
+        .queue_url(queue_url)
+        .message_body(body)
+        .send()
+        .await
+        .context("Failed to send message to SQS queue")?;
+
+    Ok(())
+}
+
+#[cfg(feature = "kafka")]
+async fn publish_kafka(
+    brokers: &[String],
+    topic: &str,
+    credentials: Option<EventDestinationCredentials>,
+    account_id: &str,
+    body: String,
+) -> anyhow::Result<()> {
+    use rskafka::{
+        BackoffConfig,
+        client::{
+            ClientBuilder, Credentials, SaslConfig,
+            partition::{Compression, UnknownTopicHandling},
+        },
+        record::Record,
+    };
+
+    use crate::{event_destination::KafkaSaslMechanism, kafka_report_consumer};
+
+    if !Env::webhook_allow_private_networks() {
+        for broker in brokers {
+            let (host, port) = parse_kafka_broker(broker)?;
+            resolve_public_host(host, port).await?;
+        }
+    }
+
+    // Credentials are sent to the brokers, so connections are always encrypted
+    let mut client_builder = ClientBuilder::new(brokers.to_vec())
+        .tls_config(Arc::new(kafka_report_consumer::tls_config()?))
+        .backoff_config(BackoffConfig {
+            deadline: Some(KAFKA_TIMEOUT),
+            ..BackoffConfig::default()
+        });
+
+    match credentials {
+        Some(EventDestinationCredentials::Kafka {
+            mechanism,
+            username,
+            password,
+        }) => {
+            let credentials = Credentials::new(username, password);
+            client_builder = client_builder.sasl_config(match mechanism {
+                KafkaSaslMechanism::Plain => SaslConfig::Plain(credentials),
+                KafkaSaslMechanism::ScramSha256 => SaslConfig::ScramSha256(credentials),
+                KafkaSaslMechanism::ScramSha512 => SaslConfig::ScramSha512(credentials),
+            });
+        }
+        Some(_) => bail!("Kafka event destination has credentials of another kind"),
+        None => {}
+    }
+
+    let publish = async {
+        let client = client_builder
+            .build()
+            .await
+            .context("Failed to connect to Kafka brokers")?;
+
+        client
+            .partition_client(topic.to_owned(), 0, UnknownTopicHandling::Error)
+            .await
+            .context("Failed to create Kafka partition client")?
+            .produce(
+                vec![Record {
+                    key: Some(account_id.as_bytes().to_vec()),
+                    value: Some(body.into_bytes()),
+                    headers: std::collections::BTreeMap::new(),
+                    timestamp: clock::now(),
+                }],
+                Compression::NoCompression,
+            )
+            .await
+            .context("Failed to produce record to Kafka topic")?;
+
+        anyhow::Ok(())
+    };
+
+    tokio::time::timeout(KAFKA_TIMEOUT, publish)
+        .await
+        .context("Timed out publishing to Kafka brokers")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_ips() {
+        for ip in [
+            "1.1.1.1",
+            "8.8.8.8",
+            "2606:4700:4700::1111",
+            "::ffff:1.1.1.1",
+            "64:ff9b::101:101",
+        ] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip} should be public");
+        }
+    }
+
+    #[test]
+    fn non_public_ips() {
+        for ip in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "192.0.0.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "ff02::1",
+            "2001:db8::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(
+                !is_public_ip(ip.parse().unwrap()),
+                "{ip} should not be public"
+            );
+        }
+    }
+}
@@ -0,0 +1,793 @@
+use std::collections::HashMap;
+
+use aes_gcm::{
+    AeadCore, Aes128Gcm, KeyInit,
+    aead::{self, Aead},
+};
+use axum::{Extension, Json, extract::Path};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    Uuid,
+    sql::statements::{BeginStatement, CommitStatement},
+};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use archodex_error::{
+    anyhow::{self, Context as _, anyhow, bail, ensure},
+    bad_request, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    auth::DashboardAuth,
+    clock,
+    db::QueryCheckFirstRealError,
+    env::Env,
+    event_delivery::{self, DELIVERED_EVENT_TYPES, verify_webhook},
+    next_binding, surrealdb_deserializers,
+    user::User,
+};
+
+const NONCE_LENGTH: usize = 12;
+
+// Delivery history is listed newest first, up to this many attempts
+const MAX_LISTED_DELIVERY_ATTEMPTS: u32 = 100;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum EventDestinationTarget {
+    Sqs { queue_url: String, region: String },
+    Kafka { brokers: Vec<String>, topic: String },
+    // Events are POSTed to the URL as JSON, signed with the destination's secret
+    Webhook { url: String },
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum KafkaSaslMechanism {
+    #[default]
+    Plain,
+    ScramSha256,
+    ScramSha512,
+}
+
+// Secrets needed to deliver to a destination. These are never returned by the API and are stored encrypted.
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum EventDestinationCredentials {
+    Sqs {
+        access_key_id: String,
+        secret_access_key: String,
+        session_token: Option<String>,
+    },
+    Kafka {
+        #[serde(default)]
+        mechanism: KafkaSaslMechanism,
+        username: String,
+        password: String,
+    },
+    Webhook {
+        secret: String,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct EventDestination {
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    id: Uuid,
+    description: Option<String>,
+    target: EventDestinationTarget,
+    #[serde(default)]
+    event_types: Option<Vec<String>>,
+    #[serde(
+        default,
+        deserialize_with = "surrealdb_deserializers::bytes::deserialize_optional"
+    )]
+    encrypted_credentials: Option<Vec<u8>>,
+    created_at: Option<DateTime<Utc>>,
+    #[allow(dead_code)]
+    created_by: User,
+    #[serde(default)]
+    verified_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct EventDestinationPublic {
+    id: Uuid,
+    description: Option<String>,
+    target: EventDestinationTarget,
+    /// Types of the events delivered to the destination, or all events if not set
+    event_types: Option<Vec<String>>,
+    has_credentials: bool,
+    created_at: Option<DateTime<Utc>>,
+    /// When the webhook endpoint last echoed a verification challenge. Webhook destinations receive events only once
+    /// verified, other destinations don't need to be.
+    verified_at: Option<DateTime<Utc>>,
+}
+
+impl From<EventDestination> for EventDestinationPublic {
+    fn from(record: EventDestination) -> Self {
+        Self {
+            id: record.id,
+            description: record.description,
+            target: record.target,
+            event_types: record.event_types,
+            has_credentials: record.encrypted_credentials.is_some(),
+            created_at: record.created_at,
+            verified_at: record.verified_at,
+        }
+    }
+}
+
+impl EventDestination {
+    pub(crate) fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub(crate) fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub(crate) fn target(&self) -> &EventDestinationTarget {
+        &self.target
+    }
+
+    pub(crate) fn event_types(&self) -> Option<&[String]> {
+        self.event_types.as_deref()
+    }
+
+    // Credentials are encrypted with the destination's ID as associated data, so they can't be moved to another
+    // destination without decrypting and encrypting them again
+    #[instrument(err, skip(credentials))]
+    pub(crate) async fn encrypt_credentials(
+        id: Uuid,
+        credentials: &EventDestinationCredentials,
+    ) -> anyhow::Result<Vec<u8>> {
+        let cipher = Aes128Gcm::new(&Env::api_private_key().await?);
+        let nonce = Aes128Gcm::generate_nonce(&mut rand::rngs::OsRng);
+
+        let encrypted = cipher
+            .encrypt(
+                &nonce,
+                aead::Payload {
+                    msg: &serde_json::to_vec(credentials)?,
+                    aad: id.as_bytes(),
+                },
+            )
+            .map_err(|err| anyhow!("Failed to encrypt event destination credentials: {err}"))?;
+
+        Ok([nonce.as_slice(), &encrypted].concat())
+    }
+
+    #[instrument(err, skip(self), fields(id = %self.id))]
+    pub(crate) async fn credentials(&self) -> anyhow::Result<Option<EventDestinationCredentials>> {
+        let Some(encrypted_credentials) = &self.encrypted_credentials else {
+            return Ok(None);
+        };
+
+        ensure!(
+            encrypted_credentials.len() > NONCE_LENGTH,
+            "Encrypted event destination credentials are too short"
+        );
+
+        let (nonce, encrypted) = encrypted_credentials.split_at(NONCE_LENGTH);
+
+        let cipher = Aes128Gcm::new(&Env::api_private_key().await?);
+
+        let decrypted = cipher
+            .decrypt(
+                aead::Nonce::<Aes128Gcm>::from_slice(nonce),
+                aead::Payload {
+                    msg: encrypted,
+                    aad: self.id.as_bytes(),
+                },
+            )
+            .map_err(|err| anyhow!("Failed to decrypt event destination credentials: {err}"))?;
+
+        Ok(Some(serde_json::from_slice(&decrypted).context(
+            "Failed to parse decrypted event destination credentials",
+        )?))
+    }
+}
+
+pub(crate) trait EventDestinationQueries<'r, C: surrealdb::Connection> {
+    fn list_event_destinations_query(&'r self) -> surrealdb::method::Query<'r, C>;
+    fn create_event_destination_query(
+        &'r self,
+        id: Uuid,
+        description: Option<String>,
+        target: &EventDestinationTarget,
+        event_types: Option<Vec<String>>,
+        encrypted_credentials: Option<Vec<u8>>,
+        created_by: &User,
+    ) -> anyhow::Result<surrealdb::method::Query<'r, C>>;
+    fn get_event_destination_query(&'r self, id: Uuid) -> surrealdb::method::Query<'r, C>;
+    fn delete_event_destination_query(&'r self, id: Uuid) -> surrealdb::method::Query<'r, C>;
+    fn verify_event_destination_query(&'r self, id: Uuid) -> surrealdb::method::Query<'r, C>;
+    fn list_dead_lettered_event_deliveries_query(
+        &'r self,
+        destination_id: Uuid,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn redrive_event_deliveries_query(
+        &'r self,
+        destination_id: Uuid,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn list_event_delivery_attempts_query(
+        &'r self,
+        destination_id: Uuid,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn redeliver_event_query(
+        &'r self,
+        destination_id: Uuid,
+        delivery_id: &str,
+    ) -> surrealdb::method::Query<'r, C>;
+}
+
+pub(crate) fn surrealdb_thing_from_event_destination_id(id: Uuid) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from((
+        "event_destination",
+        surrealdb::sql::Id::Uuid(surrealdb::sql::Uuid::from(id)),
+    ))
+}
+
+impl<'r, C: surrealdb::Connection> EventDestinationQueries<'r, C> for surrealdb::Surreal<C> {
+    fn list_event_destinations_query(&'r self) -> surrealdb::method::Query<'r, C> {
+        self.query("SELECT * FROM event_destination")
+    }
+
+    fn create_event_destination_query(
+        &'r self,
+        id: Uuid,
+        description: Option<String>,
+        target: &EventDestinationTarget,
+        event_types: Option<Vec<String>>,
+        encrypted_credentials: Option<Vec<u8>>,
+        created_by: &User,
+    ) -> anyhow::Result<surrealdb::method::Query<'r, C>> {
+        let event_destination_binding = next_binding();
+        let description_binding = next_binding();
+        let target_binding = next_binding();
+        let event_types_binding = next_binding();
+        let encrypted_credentials_binding = next_binding();
+        let created_by_binding = next_binding();
+
+        Ok(self
+            .query(format!("CREATE ${event_destination_binding} CONTENT {{ description: ${description_binding}, target: ${target_binding}, event_types: ${event_types_binding}, encrypted_credentials: ${encrypted_credentials_binding}, created_by: ${created_by_binding} }}"))
+            .bind((event_destination_binding, surrealdb_thing_from_event_destination_id(id)))
+            .bind((description_binding, description))
+            .bind((target_binding, crate::value::surrealdb_value_from_json_value(serde_json::to_value(target)?)))
+            .bind((event_types_binding, event_types))
+            .bind((encrypted_credentials_binding, encrypted_credentials.map(surrealdb::sql::Bytes::from)))
+            .bind((created_by_binding, surrealdb::sql::Thing::from(created_by))))
+    }
+
+    fn get_event_destination_query(&'r self, id: Uuid) -> surrealdb::method::Query<'r, C> {
+        let event_destination_binding = next_binding();
+
+        self.query(format!("SELECT * FROM ONLY ${event_destination_binding}"))
+            .bind((
+                event_destination_binding,
+                surrealdb_thing_from_event_destination_id(id),
+            ))
+    }
+
+    fn delete_event_destination_query(&'r self, id: Uuid) -> surrealdb::method::Query<'r, C> {
+        let event_destination_binding = next_binding();
+
+        self.query(BeginStatement::default())
+            .query(format!(
+                "DELETE event_delivery WHERE destination = ${event_destination_binding}"
+            ))
+            .query(format!("DELETE ${event_destination_binding} RETURN BEFORE"))
+            .query(format!(
+                "DELETE event_delivery_attempt WHERE destination = ${event_destination_binding}"
+            ))
+            .query(CommitStatement::default())
+            .bind((
+                event_destination_binding,
+                surrealdb_thing_from_event_destination_id(id),
+            ))
+    }
+
+    fn verify_event_destination_query(&'r self, id: Uuid) -> surrealdb::method::Query<'r, C> {
+        let event_destination_binding = next_binding();
+        let now_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE ${event_destination_binding} SET verified_at = ${now_binding} RETURN AFTER"
+        ))
+        .bind((
+            event_destination_binding,
+            surrealdb_thing_from_event_destination_id(id),
+        ))
+        .bind((now_binding, clock::now_value()))
+    }
+
+    fn list_dead_lettered_event_deliveries_query(
+        &'r self,
+        destination_id: Uuid,
+    ) -> surrealdb::method::Query<'r, C> {
+        let event_destination_binding = next_binding();
+
+        self.query(format!("SELECT record::id(id) AS id, payload, attempts, last_error, created_at FROM event_delivery WHERE destination = ${event_destination_binding} AND status = 'dead_lettered' ORDER BY created_at"))
+            .bind((event_destination_binding, surrealdb_thing_from_event_destination_id(destination_id)))
+    }
+
+    fn redrive_event_deliveries_query(
+        &'r self,
+        destination_id: Uuid,
+    ) -> surrealdb::method::Query<'r, C> {
+        let event_destination_binding = next_binding();
+        let now_binding = next_binding();
+
+        self.query(format!("UPDATE event_delivery SET status = 'pending', attempts = 0, next_attempt_at = ${now_binding} WHERE destination = ${event_destination_binding} AND status = 'dead_lettered' RETURN NONE"))
+            .bind((event_destination_binding, surrealdb_thing_from_event_destination_id(destination_id)))
+            .bind((now_binding, clock::now_value()))
+    }
+
+    fn list_event_delivery_attempts_query(
+        &'r self,
+        destination_id: Uuid,
+    ) -> surrealdb::method::Query<'r, C> {
+        let event_destination_binding = next_binding();
+
+        self.query(format!("SELECT delivery_id, event_type, payload, succeeded, status_code, error, attempted_at FROM event_delivery_attempt WHERE destination = ${event_destination_binding} ORDER BY attempted_at DESC LIMIT {MAX_LISTED_DELIVERY_ATTEMPTS}"))
+            .bind((event_destination_binding, surrealdb_thing_from_event_destination_id(destination_id)))
+    }
+
+    // Delivers the event again, using the payload of its most recent attempt. Deliveries still in the outbox, e.g.
+    // dead-lettered ones, are moved back to the pending queue, and delivered events are enqueued again under the same
+    // delivery ID. Returns whether the destination has a delivery attempt with the ID.
+    fn redeliver_event_query(
+        &'r self,
+        destination_id: Uuid,
+        delivery_id: &str,
+    ) -> surrealdb::method::Query<'r, C> {
+        let event_destination_binding = next_binding();
+        let delivery_id_binding = next_binding();
+        let now_binding = next_binding();
+
+        self.query(BeginStatement::default())
+            .query(format!("LET $delivery = type::thing('event_delivery', ${delivery_id_binding})"))
+            .query(format!("LET $attempt = (SELECT payload, attempted_at FROM event_delivery_attempt WHERE destination = ${event_destination_binding} AND delivery_id = ${delivery_id_binding} ORDER BY attempted_at DESC LIMIT 1)[0]"))
+            .query(format!("IF $attempt IS NOT NONE {{
+                IF record::exists($delivery) {{
+                    UPDATE $delivery SET status = 'pending', attempts = 0, next_attempt_at = ${now_binding} RETURN NONE;
+                }} ELSE {{
+                    CREATE $delivery CONTENT {{ destination: ${event_destination_binding}, payload: $attempt.payload }} RETURN NONE;
+                }};
+            }}"))
+            .query("RETURN $attempt IS NOT NONE")
+            .query(CommitStatement::default())
+            .bind((event_destination_binding, surrealdb_thing_from_event_destination_id(destination_id)))
+            .bind((delivery_id_binding, delivery_id.to_owned()))
+            .bind((now_binding, clock::now_value()))
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListEventDestinationsResponse {
+    event_destinations: Vec<EventDestinationPublic>,
+    degraded: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/event_destinations",
+    tag = "event_destinations",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = ListEventDestinationsResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn list_event_destinations(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListEventDestinationsResponse>> {
+    let Some(db) = account.resources_db_if_available().await else {
+        return Ok(Json(ListEventDestinationsResponse {
+            event_destinations: vec![],
+            degraded: true,
+        }));
+    };
+
+    let event_destinations = db
+        .list_event_destinations_query()
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<EventDestination>>(0)?
+        .into_iter()
+        .map(EventDestinationPublic::from)
+        .collect();
+
+    Ok(Json(ListEventDestinationsResponse {
+        event_destinations,
+        degraded: false,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CreateEventDestinationRequest {
+    description: Option<String>,
+    target: EventDestinationTarget,
+    /// Types of the events to deliver to the destination, e.g. `resource.created`. All events are delivered if not set.
+    event_types: Option<Vec<String>>,
+    credentials: Option<EventDestinationCredentials>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/event_destinations",
+    tag = "event_destinations",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = CreateEventDestinationRequest,
+    responses((status = 200, body = EventDestinationPublic))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn create_event_destination(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Json(req): Json<CreateEventDestinationRequest>,
+) -> Result<Json<EventDestinationPublic>> {
+    match (&req.target, &req.credentials) {
+        (EventDestinationTarget::Sqs { .. }, Some(EventDestinationCredentials::Sqs { .. }))
+        | (
+            EventDestinationTarget::Kafka { .. },
+            None | Some(EventDestinationCredentials::Kafka { .. }),
+        )
+        | (
+            EventDestinationTarget::Webhook { .. },
+            Some(EventDestinationCredentials::Webhook { .. }),
+        ) => {}
+        // Queue URLs are chosen by users, so sending with the backend's own AWS credentials would let them send to any
+        // queue the backend can reach, including its own
+        (EventDestinationTarget::Sqs { .. }, None) => {
+            bad_request!("SQS event destinations require credentials with access to the queue")
+        }
+        (EventDestinationTarget::Webhook { .. }, None) => {
+            bad_request!("Webhook event destinations require credentials with a signing secret")
+        }
+        _ => bad_request!("Credentials kind must match the destination target kind"),
+    }
+
+    match &req.target {
+        EventDestinationTarget::Sqs { .. } if !cfg!(feature = "sqs") => {
+            bad_request!("SQS event destinations are not supported by this backend")
+        }
+        EventDestinationTarget::Kafka { .. } if !cfg!(feature = "kafka") => {
+            bad_request!("Kafka event destinations are not supported by this backend")
+        }
+        EventDestinationTarget::Kafka { brokers, .. } if brokers.is_empty() => {
+            bad_request!("At least one Kafka broker must be provided")
+        }
+        #[cfg(feature = "kafka")]
+        EventDestinationTarget::Kafka { brokers, .. } => {
+            for broker in brokers {
+                if let Err(err) = event_delivery::validate_kafka_broker(broker) {
+                    bad_request!("{err}");
+                }
+            }
+        }
+        EventDestinationTarget::Webhook { url } => {
+            if let Err(err) = event_delivery::validate_webhook_url(url) {
+                bad_request!("{err}");
+            }
+        }
+        _ => {}
+    }
+
+    if let Some(EventDestinationCredentials::Webhook { secret }) = &req.credentials
+        && secret.is_empty()
+    {
+        bad_request!("Webhook signing secret must not be empty");
+    }
+
+    if let Some(event_types) = &req.event_types {
+        if event_types.is_empty() {
+            bad_request!("event_types must not be empty, omit it to deliver all events");
+        }
+
+        if let Some(event_type) = event_types
+            .iter()
+            .find(|event_type| !DELIVERED_EVENT_TYPES.contains(&event_type.as_str()))
+        {
+            bad_request!(
+                "Unknown event type {event_type:?}, expected one of {}",
+                DELIVERED_EVENT_TYPES.join(", ")
+            );
+        }
+    }
+
+    let id = Uuid::now_v7();
+
+    let encrypted_credentials = match &req.credentials {
+        Some(credentials) => Some(EventDestination::encrypt_credentials(id, credentials).await?),
+        None => None,
+    };
+
+    let event_destination = account
+        .resources_db()
+        .await?
+        .create_event_destination_query(
+            id,
+            req.description,
+            &req.target,
+            req.event_types,
+            encrypted_credentials,
+            auth.principal(),
+        )?
+        .await?
+        .check_first_real_error()?
+        .take::<Option<EventDestination>>(0)?
+        .context("Create event destination query should return an event destination instance")?;
+
+    info!(event_destination_id = %event_destination.id(), "Created event destination");
+
+    Ok(Json(EventDestinationPublic::from(event_destination)))
+}
+
+fn event_destination_id_from_params(params: &HashMap<String, String>) -> Result<Uuid> {
+    let Some(event_destination_id) = params.get("event_destination_id") else {
+        bail!("Missing event_destination_id");
+    };
+
+    let Ok(event_destination_id) = Uuid::parse_str(event_destination_id) else {
+        bad_request!("Invalid event destination ID");
+    };
+
+    Ok(event_destination_id)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}/event_destination/{event_destination_id}",
+    tag = "event_destinations",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("event_destination_id" = String, Path)),
+    responses((status = 200))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn delete_event_destination(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    let event_destination_id = event_destination_id_from_params(&params)?;
+
+    let mut res = account
+        .resources_db()
+        .await?
+        .delete_event_destination_query(event_destination_id)
+        .await?
+        .check_first_real_error()?;
+
+    if res.take::<Option<EventDestination>>(1)?.is_none() {
+        not_found!("Event destination not found");
+    }
+
+    info!(%event_destination_id, "Deleted event destination");
+
+    Ok(Json(()))
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub(crate) struct DeadLetteredEventDelivery {
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    id: String,
+    payload: serde_json::Value,
+    attempts: u32,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListDeadLetteredEventDeliveriesResponse {
+    event_deliveries: Vec<DeadLetteredEventDelivery>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/event_destination/{event_destination_id}/dead_letters",
+    tag = "event_destinations",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("event_destination_id" = String, Path)),
+    responses((status = 200, body = ListDeadLetteredEventDeliveriesResponse))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn list_dead_lettered_event_deliveries(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<ListDeadLetteredEventDeliveriesResponse>> {
+    let event_destination_id = event_destination_id_from_params(&params)?;
+
+    let event_deliveries = account
+        .resources_db()
+        .await?
+        .list_dead_lettered_event_deliveries_query(event_destination_id)
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<DeadLetteredEventDelivery>>(0)?;
+
+    Ok(Json(ListDeadLetteredEventDeliveriesResponse {
+        event_deliveries,
+    }))
+}
+
+// Moves all dead-lettered deliveries for a destination back into the pending queue, e.g. after fixing the destination's
+// permissions.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/event_destination/{event_destination_id}/redrive",
+    tag = "event_destinations",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("event_destination_id" = String, Path)),
+    responses((status = 200))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn redrive_event_deliveries(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    let event_destination_id = event_destination_id_from_params(&params)?;
+
+    account
+        .resources_db()
+        .await?
+        .redrive_event_deliveries_query(event_destination_id)
+        .await?
+        .check_first_real_error()?;
+
+    event_delivery::wake_worker(account.id());
+
+    info!(%event_destination_id, "Redrove dead-lettered event deliveries");
+
+    Ok(Json(()))
+}
+
+// Sends a verification challenge to the endpoint of a webhook destination, which must respond with the challenge for the
+// destination to start receiving events. See `event_delivery::verify_webhook()`.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/event_destination/{event_destination_id}/verify",
+    tag = "event_destinations",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("event_destination_id" = String, Path)),
+    responses((status = 200, body = EventDestinationPublic))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn verify_event_destination(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<EventDestinationPublic>> {
+    let event_destination_id = event_destination_id_from_params(&params)?;
+
+    let Some(event_destination) = account
+        .resources_db()
+        .await?
+        .get_event_destination_query(event_destination_id)
+        .await?
+        .check_first_real_error()?
+        .take::<Option<EventDestination>>(0)?
+    else {
+        not_found!("Event destination not found");
+    };
+
+    if !matches!(
+        event_destination.target(),
+        EventDestinationTarget::Webhook { .. }
+    ) {
+        bad_request!("Only webhook event destinations need to be verified");
+    }
+
+    if let Err(err) = verify_webhook(&event_destination).await {
+        bad_request!("Webhook endpoint verification failed: {err:#}");
+    }
+
+    let Some(event_destination) = account
+        .resources_db()
+        .await?
+        .verify_event_destination_query(event_destination_id)
+        .await?
+        .check_first_real_error()?
+        .take::<Option<EventDestination>>(0)?
+    else {
+        not_found!("Event destination not found");
+    };
+
+    info!(%event_destination_id, "Verified webhook event destination");
+
+    Ok(Json(EventDestinationPublic::from(event_destination)))
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+pub(crate) struct EventDeliveryAttempt {
+    /// Identifies the delivery to redeliver, shared by all attempts to deliver the event
+    delivery_id: String,
+    event_type: Option<String>,
+    payload: serde_json::Value,
+    succeeded: bool,
+    /// Status code of the webhook endpoint's response, unset for other destinations and requests that got no response
+    status_code: Option<u16>,
+    error: Option<String>,
+    attempted_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListEventDeliveryAttemptsResponse {
+    event_delivery_attempts: Vec<EventDeliveryAttempt>,
+}
+
+// Lists the destination's most recent delivery attempts from the last week, successful or not, newest first
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/event_destination/{event_destination_id}/deliveries",
+    tag = "event_destinations",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("event_destination_id" = String, Path)),
+    responses((status = 200, body = ListEventDeliveryAttemptsResponse))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn list_event_delivery_attempts(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<ListEventDeliveryAttemptsResponse>> {
+    let event_destination_id = event_destination_id_from_params(&params)?;
+
+    let event_delivery_attempts = account
+        .resources_db()
+        .await?
+        .list_event_delivery_attempts_query(event_destination_id)
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<EventDeliveryAttempt>>(0)?;
+
+    Ok(Json(ListEventDeliveryAttemptsResponse {
+        event_delivery_attempts,
+    }))
+}
+
+// Delivers an event from the destination's delivery history again, whether its delivery failed or succeeded, e.g. to
+// replay events while setting up an integration.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/event_destination/{event_destination_id}/delivery/{delivery_id}/redeliver",
+    tag = "event_destinations",
+    security(("dashboard" = [])),
+    params(
+        ("account_id" = String, Path),
+        ("event_destination_id" = String, Path),
+        ("delivery_id" = String, Path)
+    ),
+    responses((status = 200))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn redeliver_event(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    let event_destination_id = event_destination_id_from_params(&params)?;
+
+    let Some(delivery_id) = params.get("delivery_id") else {
+        bail!("Missing delivery_id");
+    };
+
+    let mut res = account
+        .resources_db()
+        .await?
+        .redeliver_event_query(event_destination_id, delivery_id)
+        .await?
+        .check_first_real_error()?;
+
+    if res.take::<Option<bool>>(res.num_statements() - 1)? != Some(true) {
+        not_found!("Event delivery not found in the destination's delivery history");
+    }
+
+    event_delivery::wake_worker(account.id());
+
+    info!(%event_destination_id, delivery_id, "Redelivering event");
+
+    Ok(Json(()))
+}
@@ -0,0 +1,222 @@
+use axum::{Extension, Json};
+use chrono::TimeDelta;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use archodex_error::bad_request;
+
+use crate::{
+    Result,
+    account::{Account, AccountQueries as _, invalidate_cached_account},
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    clock,
+    db::{QueryCheckFirstRealError as _, accounts_db_for_account},
+    env::Env,
+    revision,
+    router::RequestId,
+    traced_query::TracedQuery,
+    worker,
+};
+
+// Maximum number of events and of principal chains deleted per transaction. Each batch also rescans the events and
+// derived edges referencing what it deletes, so batches are kept small enough to not hold up report ingestion.
+const BATCH_SIZE: u32 = 1000;
+
+// Longest retention period an account can set, 10 years
+const MAX_RETENTION_DAYS: u32 = 3650;
+
+pub(crate) fn validate_retention_days(retention_days: Option<u32>) -> Result<()> {
+    if let Some(retention_days) = retention_days
+        && !(1..=MAX_RETENTION_DAYS).contains(&retention_days)
+    {
+        bad_request!("Retention must be between 1 and {MAX_RETENTION_DAYS} days");
+    }
+
+    Ok(())
+}
+
+// Deletes one batch of the events and principal chains last seen before `$expired_before` and returns the numbers of
+// each deleted. Chains still referenced by newer events are removed from those events first, so each event keeps only
+// the chains seen within the retention period. An event is last seen when its newest chain is, so live events always
+// keep at least one chain. Derived edges lose the deleted events they were inferred from, and are deleted once none are
+// left.
+const PRUNE_QUERY: &str = "
+    BEGIN;
+
+    LET $events = SELECT VALUE id FROM event WHERE last_seen_at < $expired_before LIMIT $limit;
+    LET $principal_chains = SELECT VALUE id FROM principal_chain WHERE last_seen_at < $expired_before LIMIT $limit;
+
+    DELETE $events;
+    UPDATE event SET principal_chains = array::complement(principal_chains, $principal_chains) WHERE principal_chains CONTAINSANY $principal_chains RETURN NONE;
+    DELETE $principal_chains;
+    DELETE derived_edge WHERE events CONTAINSANY $events AND array::is_empty(array::complement(events, $events));
+    UPDATE derived_edge SET events = array::complement(events, $events) WHERE events CONTAINSANY $events RETURN NONE;
+
+    RETURN { events: array::len($events), principal_chains: array::len($principal_chains) };
+
+    COMMIT;";
+
+#[derive(Default, Deserialize)]
+struct PruneCounts {
+    events: u32,
+    principal_chains: u32,
+}
+
+/// Prunes expired events from accounts with an event retention period every `ARCHODEX_EVENT_RETENTION_INTERVAL_SECONDS`
+/// until the process exits.
+///
+/// Events last seen longer ago than their account's retention period are deleted, along with principal chains last seen
+/// as long ago. Newer events referencing deleted chains keep their other chains. Accounts without a retention period
+/// keep their events forever.
+pub async fn run_worker() {
+    worker::run_periodically(
+        "event_retention",
+        Env::event_retention_interval(),
+        prune_accounts_events,
+    )
+    .await;
+}
+
+#[instrument(err)]
+pub(crate) async fn prune_accounts_events() -> Result<()> {
+    worker::for_each_account("event_retention", None, |account| async move {
+        let Some(retention_days) = account.event_retention_days() else {
+            return Ok(());
+        };
+
+        prune_account_events(&account, retention_days).await
+    })
+    .await
+}
+
+// Each batch is its own transaction. Batches that conflict with concurrent report ingestion of the same events are
+// retried on the next run.
+#[instrument(err, skip(account), fields(account_id = account.id()))]
+async fn prune_account_events(account: &Account, retention_days: u32) -> Result<()> {
+    let expired_before = clock::now() - TimeDelta::days(i64::from(retention_days));
+
+    let mut events = 0;
+    let mut principal_chains = 0;
+
+    loop {
+        // The resources database connection is released between batches, as a non-concurrent (e.g. RocksDB) database
+        // only has one
+        let db = account.resources_db().await?;
+
+        let mut res = TracedQuery::new(&db, "event_retention")
+            .query(PRUNE_QUERY)
+            .bind((
+                "expired_before",
+                surrealdb::sql::Datetime::from(expired_before),
+            ))
+            .bind(("limit", BATCH_SIZE))
+            .execute()
+            .await?
+            .check_first_real_error()?;
+
+        let counts = res
+            .take::<Option<PruneCounts>>(res.num_statements() - 1)?
+            .unwrap_or_default();
+
+        if counts.events > 0 || counts.principal_chains > 0 {
+            revision::bump(&db).await?;
+        }
+
+        events += counts.events;
+        principal_chains += counts.principal_chains;
+
+        if counts.events < BATCH_SIZE && counts.principal_chains < BATCH_SIZE {
+            break;
+        }
+    }
+
+    if events > 0 || principal_chains > 0 {
+        info!(events, principal_chains, "Pruned expired events");
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EventRetentionResponse {
+    /// Events last seen more than this many days ago are deleted. Events are kept forever if not set.
+    retention_days: Option<u32>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/event_retention",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = EventRetentionResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn get_event_retention(
+    Extension(account): Extension<Account>,
+) -> Result<Json<EventRetentionResponse>> {
+    Ok(Json(EventRetentionResponse {
+        retention_days: account.event_retention_days(),
+    }))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetEventRetentionRequest {
+    /// Number of days events are kept after they were last seen, or null to keep events forever
+    retention_days: Option<u32>,
+}
+
+// Sets how long the account's events are kept after they were last seen. Expired events are deleted by the event
+// retention worker on its next run, so shortening the retention period deletes events without further confirmation.
+#[utoipa::path(
+    put,
+    path = "/account/{account_id}/event_retention",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = SetEventRetentionRequest,
+    responses((status = 200, body = EventRetentionResponse))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn set_event_retention(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<SetEventRetentionRequest>,
+) -> Result<Json<EventRetentionResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    validate_retention_days(req.retention_days)?;
+
+    accounts_db_for_account(account.id())
+        .await?
+        .set_account_event_retention_query(&account, req.retention_days, auth.principal())
+        .await?
+        .check_first_real_error()?;
+
+    invalidate_cached_account(account.id());
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        retention_days = req.retention_days,
+        "Set account event retention"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::EventRetentionSet,
+        Some(json!({ "retention_days": req.retention_days })),
+    )
+    .await;
+
+    Ok(Json(EventRetentionResponse {
+        retention_days: req.retention_days,
+    }))
+}
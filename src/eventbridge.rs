@@ -0,0 +1,174 @@
+use serde::Serialize;
+use surrealdb::Uuid;
+use tokio::sync::OnceCell;
+use tracing::{Instrument as _, info_span, instrument, warn};
+
+use archodex_error::anyhow::{self, Context as _, bail};
+
+use crate::{env::Env, resource::ResourceId};
+
+// Source of every event, which EventBridge rules match on
+const EVENT_SOURCE: &str = "com.archodex.backend";
+
+// PutEvents accepts at most 10 entries per request
+const MAX_ENTRIES_PER_REQUEST: usize = 10;
+
+// PutEvents requests are limited to 256 KiB. Resource IDs are split across events well under the limit so a request of
+// full events is still small enough.
+const MAX_RESOURCE_IDS_DETAIL_BYTES: usize = 16 * 1024;
+
+static CLIENT: OnceCell<aws_sdk_eventbridge::Client> = OnceCell::const_new();
+
+// Events published to archodex.com's EventBridge bus for internal services, e.g. billing and notifications, to react to
+// without polling SurrealDB. Events are published after the change that produced them is committed, on a best effort
+// basis. Consumers needing every change should reconcile against the accounts and resources databases.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub(crate) enum PlatformEvent {
+    AccountCreated {
+        account_id: String,
+        created_by_user_id: Uuid,
+    },
+    AccountDeleted {
+        account_id: String,
+        deleted_by_user_id: Uuid,
+    },
+    AccountTransferred {
+        account_id: String,
+        from_user_id: Uuid,
+        to_user_id: Uuid,
+    },
+    // Resources that didn't exist before a report was ingested. A report creating many resources is published as
+    // several events.
+    ResourcesCreated {
+        account_id: String,
+        resource_ids: Vec<ResourceId>,
+    },
+}
+
+impl PlatformEvent {
+    fn detail_type(&self) -> &'static str {
+        match self {
+            PlatformEvent::AccountCreated { .. } => "account.created",
+            PlatformEvent::AccountDeleted { .. } => "account.deleted",
+            PlatformEvent::AccountTransferred { .. } => "account.transferred",
+            PlatformEvent::ResourcesCreated { .. } => "resources.created",
+        }
+    }
+
+    // Splits created resources into events small enough to be published
+    pub(crate) fn resources_created(
+        account_id: &str,
+        resource_ids: Vec<ResourceId>,
+    ) -> Vec<PlatformEvent> {
+        let mut events = vec![];
+        let mut event_resource_ids = vec![];
+        let mut event_resource_ids_bytes = 0;
+
+        for resource_id in resource_ids {
+            let resource_id_bytes = serde_json::to_vec(&resource_id)
+                .expect("Resource IDs should serialize to JSON")
+                .len();
+
+            if !event_resource_ids.is_empty()
+                && event_resource_ids_bytes + resource_id_bytes > MAX_RESOURCE_IDS_DETAIL_BYTES
+            {
+                events.push(PlatformEvent::ResourcesCreated {
+                    account_id: account_id.to_owned(),
+                    resource_ids: std::mem::take(&mut event_resource_ids),
+                });
+                event_resource_ids_bytes = 0;
+            }
+
+            event_resource_ids.push(resource_id);
+            event_resource_ids_bytes += resource_id_bytes;
+        }
+
+        if !event_resource_ids.is_empty() {
+            events.push(PlatformEvent::ResourcesCreated {
+                account_id: account_id.to_owned(),
+                resource_ids: event_resource_ids,
+            });
+        }
+
+        events
+    }
+}
+
+// Whether events are published, in which case callers should collect the data events need
+pub(crate) fn enabled() -> bool {
+    Env::eventbridge_bus_name().is_some()
+}
+
+// Publishes events in the background so that requests aren't slowed down or failed by EventBridge. Failures are logged.
+pub(crate) fn publish(events: Vec<PlatformEvent>) {
+    let Some(bus_name) = Env::eventbridge_bus_name() else {
+        return;
+    };
+
+    if events.is_empty() {
+        return;
+    }
+
+    tokio::spawn(
+        async move {
+            if let Err(err) = put_events(bus_name, &events).await {
+                warn!(
+                    ?err,
+                    events = events.len(),
+                    "Failed to publish events to EventBridge"
+                );
+            }
+        }
+        .instrument(info_span!("eventbridge")),
+    );
+}
+
+#[instrument(err, skip(events))]
+async fn put_events(bus_name: &str, events: &[PlatformEvent]) -> anyhow::Result<()> {
+    let client = CLIENT
+        .get_or_init(|| async {
+            aws_sdk_eventbridge::Client::new(
+                &aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await,
+            )
+        })
+        .await;
+
+    for events in events.chunks(MAX_ENTRIES_PER_REQUEST) {
+        let entries = events
+            .iter()
+            .map(|event| {
+                Ok(aws_sdk_eventbridge::types::PutEventsRequestEntry::builder()
+                    .event_bus_name(bus_name)
+                    .source(EVENT_SOURCE)
+                    .detail_type(event.detail_type())
+                    .detail(serde_json::to_string(event)?)
+                    .build())
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let output = client
+            .put_events()
+            .set_entries(Some(entries))
+            .send()
+            .await
+            .context("Failed to put events to EventBridge")?;
+
+        // Entries are accepted or rejected individually, so a successful request may still have failed entries
+        if output.failed_entry_count() > 0 {
+            let errors = output
+                .entries()
+                .iter()
+                .filter_map(|entry| entry.error_message())
+                .collect::<Vec<_>>();
+
+            bail!(
+                "EventBridge rejected {} of {} events: {errors:?}",
+                output.failed_entry_count(),
+                events.len()
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use axum::{Extension, Json, extract::Query};
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
+
+use archodex_error::bad_request;
+
+use crate::{
+    Result,
+    account::Account,
+    db::QueryCheckFirstRealError,
+    event::Event,
+    resource::{ResourceId, surrealdb_thing_from_resource_id},
+    traced_query::TracedQuery,
+};
+
+const DEFAULT_PAGE_LIMIT: u32 = 100;
+const MAX_PAGE_LIMIT: u32 = 1000;
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+// Events are sorted by `last_seen_at`, with ties broken by the event's principal, resource, and type, which together
+// uniquely identify an event. `principal` and `resource` are JSON encoded resource IDs.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListEventsParams {
+    limit: Option<u32>,
+    cursor: Option<String>,
+    #[serde(default)]
+    #[param(inline)]
+    order: SortOrder,
+    r#type: Option<String>,
+    principal: Option<String>,
+    resource: Option<String>,
+}
+
+// Sort key of the last event of a page
+#[derive(Deserialize, Serialize)]
+struct EventCursor {
+    last_seen_at: DateTime<Utc>,
+    principal: ResourceId,
+    resource: ResourceId,
+    r#type: String,
+}
+
+impl From<&Event> for EventCursor {
+    fn from(event: &Event) -> Self {
+        Self {
+            last_seen_at: event.last_seen_at,
+            principal: event.principal.clone(),
+            resource: event.resource.clone(),
+            r#type: event.r#type.clone(),
+        }
+    }
+}
+
+impl From<EventCursor> for surrealdb::sql::Value {
+    fn from(cursor: EventCursor) -> Self {
+        surrealdb::sql::Value::from(vec![
+            surrealdb::sql::Value::from(surrealdb::sql::Datetime::from(cursor.last_seen_at)),
+            surrealdb_thing_from_resource_id(cursor.principal),
+            surrealdb_thing_from_resource_id(cursor.resource),
+            surrealdb::sql::Value::from(cursor.r#type),
+        ])
+    }
+}
+
+fn encode_cursor(cursor: &EventCursor) -> Result<String> {
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor)?))
+}
+
+fn decode_cursor(cursor: &str) -> Result<EventCursor> {
+    let Some(cursor) = BASE64_URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+    else {
+        bad_request!("Invalid cursor");
+    };
+
+    Ok(cursor)
+}
+
+fn parse_resource_id(param: &str, value: Option<&str>) -> Result<Option<surrealdb::sql::Value>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+
+    match serde_json::from_str::<ResourceId>(value) {
+        Ok(resource_id) => Ok(Some(surrealdb_thing_from_resource_id(resource_id))),
+        Err(err) => bad_request!("Invalid `{param}` query parameter: {err}"),
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListEventsResponse {
+    events: Vec<Event>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/events",
+    tag = "events",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ListEventsParams),
+    responses((status = 200, body = ListEventsResponse))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn list_events(
+    Extension(account): Extension<Account>,
+    Query(params): Query<ListEventsParams>,
+) -> Result<Json<ListEventsResponse>> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if !(1..=MAX_PAGE_LIMIT).contains(&limit) {
+        bad_request!("limit must be between 1 and {MAX_PAGE_LIMIT}");
+    }
+
+    let cursor = params
+        .cursor
+        .as_deref()
+        .map(decode_cursor)
+        .transpose()?
+        .map(surrealdb::sql::Value::from);
+
+    let principal = parse_resource_id("principal", params.principal.as_deref())?;
+    let resource = parse_resource_id("resource", params.resource.as_deref())?;
+
+    let (cursor_operator, direction) = match params.order {
+        SortOrder::Asc => (">", "ASC"),
+        SortOrder::Desc => ("<", "DESC"),
+    };
+
+    let db = account.resources_db().await?;
+
+    let query = TracedQuery::new(&db, "list_events")
+        .query(format!(
+            "SELECT *, fn::principal_chains(principal_chains) AS principal_chains OMIT id FROM event
+                WHERE ($event_type IS NONE OR type = $event_type)
+                    AND ($principal IS NONE OR in = $principal)
+                    AND ($resource IS NONE OR out = $resource)
+                    AND ($cursor IS NONE OR [last_seen_at, in, out, type] {cursor_operator} $cursor)
+                ORDER BY last_seen_at {direction}, in {direction}, out {direction}, type {direction}
+                LIMIT $page_fetch_limit"
+        ))
+        .bind(("event_type", params.r#type))
+        .bind(("principal", principal))
+        .bind(("resource", resource))
+        .bind(("cursor", cursor))
+        .bind(("page_fetch_limit", limit + 1));
+
+    let mut events = query
+        .execute()
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<Event>>(0)?;
+
+    // One extra event is fetched to determine whether another page exists
+    let next_cursor = if events.len() > limit as usize {
+        events.truncate(limit as usize);
+        events
+            .last()
+            .map(|event| encode_cursor(&EventCursor::from(event)))
+            .transpose()?
+    } else {
+        None
+    };
+
+    Ok(Json(ListEventsResponse {
+        events,
+        next_cursor,
+    }))
+}
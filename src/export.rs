@@ -0,0 +1,512 @@
+use std::collections::BTreeSet;
+
+use axum::{
+    Extension, Json,
+    body::{Body, Bytes},
+    extract::Query,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse as _, Response},
+};
+use chrono::{DateTime, Utc};
+use http_body_util::channel::{Channel, Sender};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::json;
+use tracing::{Instrument as _, info, info_span, instrument, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use archodex_error::{PublicError, anyhow, anyhow::Context as _, bad_request};
+
+use crate::{
+    Result,
+    account::{Account, external_account_id},
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    clock,
+    db::QueryCheckFirstRealError as _,
+    env::Env,
+    principal_chain::PrincipalChainId,
+    resource::ResourceId,
+    router::RequestId,
+    traced_query::TracedQuery,
+};
+
+// Version of the archive format. Bump it whenever a record's fields change incompatibly.
+pub(crate) const EXPORT_VERSION: u32 = 1;
+
+// Records read from the resources database per query
+const PAGE_LIMIT: u32 = 1000;
+
+// Pages buffered between reading the resources database and sending the response body
+const STREAM_BUFFER_PAGES: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExportFormat {
+    // A JSON array of records
+    #[default]
+    Json,
+    // One record per line
+    Jsonl,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "json",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ExportHeader {
+    pub(crate) version: u32,
+    pub(crate) account_id: String,
+    pub(crate) exported_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ExportedResource {
+    pub(crate) id: ResourceId,
+    pub(crate) environments: BTreeSet<String>,
+    pub(crate) attributes: serde_json::Value,
+    pub(crate) first_seen_at: DateTime<Utc>,
+    pub(crate) last_seen_at: DateTime<Utc>,
+}
+
+// A `contains` relation. Global containers, e.g. the AWS region a bucket is in, are the containers of resources whose ID
+// has a single part.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ExportedContains {
+    pub(crate) container: ResourceId,
+    pub(crate) resource: ResourceId,
+    pub(crate) first_seen_at: DateTime<Utc>,
+    pub(crate) last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ExportedPrincipalChain {
+    pub(crate) chain: PrincipalChainId,
+    pub(crate) first_seen_at: DateTime<Utc>,
+    pub(crate) last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ExportedEvent {
+    pub(crate) principal: ResourceId,
+    pub(crate) r#type: String,
+    pub(crate) resource: ResourceId,
+    pub(crate) principal_chains: Vec<PrincipalChainId>,
+    pub(crate) has_direct_principal_chain: bool,
+    pub(crate) first_seen_at: DateTime<Utc>,
+    pub(crate) last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, ToSchema)]
+pub(crate) struct ExportCounts {
+    pub(crate) resources: u64,
+    pub(crate) contains: u64,
+    pub(crate) principal_chains: u64,
+    pub(crate) events: u64,
+}
+
+// A record of an archive. Archives start with a header, then hold every resource, `contains` relation, principal chain,
+// and event in that order, so each record only refers to records before it, and end with the record counts. An archive
+// without the final counts is truncated.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ExportRecord {
+    Header(ExportHeader),
+    Resource(ExportedResource),
+    Contains(ExportedContains),
+    PrincipalChain(ExportedPrincipalChain),
+    Event(ExportedEvent),
+    End(ExportCounts),
+}
+
+// Serializes records in the requested format, a page at a time
+struct ArchiveWriter {
+    format: ExportFormat,
+    buf: Vec<u8>,
+    records: u64,
+    bytes: u64,
+}
+
+impl ArchiveWriter {
+    fn new(format: ExportFormat) -> Self {
+        Self {
+            format,
+            buf: vec![],
+            records: 0,
+            bytes: 0,
+        }
+    }
+
+    fn write(&mut self, record: &ExportRecord) -> Result<()> {
+        match self.format {
+            ExportFormat::Json => {
+                self.buf
+                    .extend_from_slice(if self.records == 0 { b"[" } else { b"," });
+                serde_json::to_writer(&mut self.buf, record)?;
+            }
+            ExportFormat::Jsonl => {
+                serde_json::to_writer(&mut self.buf, record)?;
+                self.buf.push(b'\n');
+            }
+        }
+
+        self.records += 1;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        if let ExportFormat::Json = self.format {
+            self.buf.extend_from_slice(b"]");
+        }
+    }
+
+    // Takes the records written since the last call
+    fn take(&mut self) -> Bytes {
+        self.bytes += self.buf.len() as u64;
+        Bytes::from(std::mem::take(&mut self.buf))
+    }
+}
+
+// Where an archive is written: the response body of a download, or a buffer of at most `max_bytes` that is uploaded
+// once complete
+enum ExportSink {
+    Stream(Sender<Bytes, anyhow::Error>),
+    Buffer { buf: Vec<u8>, max_bytes: usize },
+}
+
+impl ExportSink {
+    async fn send(&mut self, chunk: Bytes) -> Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        match self {
+            ExportSink::Stream(sender) => sender
+                .send_data(chunk)
+                .await
+                .map_err(|_| anyhow::anyhow!("Export download was closed by the client"))?,
+            ExportSink::Buffer { buf, max_bytes } => {
+                if buf.len() + chunk.len() > *max_bytes {
+                    return Err(PublicError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        format!(
+                            "Account data export is larger than the {max_bytes} byte upload limit, download it instead"
+                        ),
+                    ));
+                }
+
+                buf.extend_from_slice(&chunk);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Reads the records of a table a page at a time, in record ID order. `page` selects a page of records into `$page`, and
+// `fields` selects the exported fields from `$page`.
+async fn export_table<T: DeserializeOwned>(
+    account: &Account,
+    writer: &mut ArchiveWriter,
+    sink: &mut ExportSink,
+    page: &str,
+    fields: &str,
+    record: fn(T) -> ExportRecord,
+) -> Result<u64> {
+    let mut cursor: Option<surrealdb::RecordId> = None;
+    let mut exported = 0;
+
+    loop {
+        // Each page gets its own connection, so self-hosted backends serve other requests while large accounts export
+        let db = account.resources_db().await?;
+
+        let mut res = TracedQuery::new(&db, "export")
+            .query(page)
+            .query(fields)
+            .query("RETURN array::last($page).id;")
+            .bind(("cursor", cursor))
+            .bind(("page_limit", PAGE_LIMIT))
+            .execute()
+            .await?
+            .check_first_real_error()?;
+
+        let records = res.take::<Vec<T>>(1)?;
+        cursor = res.take::<Option<surrealdb::RecordId>>(2)?;
+
+        drop(db);
+
+        exported += records.len() as u64;
+
+        let last_page = records.len() < PAGE_LIMIT as usize;
+
+        for exported_record in records {
+            writer.write(&record(exported_record))?;
+        }
+
+        sink.send(writer.take()).await?;
+
+        if last_page || cursor.is_none() {
+            return Ok(exported);
+        }
+    }
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id(), ?format))]
+async fn write_archive(
+    account: &Account,
+    format: ExportFormat,
+    sink: &mut ExportSink,
+) -> Result<(ExportCounts, u64)> {
+    let mut writer = ArchiveWriter::new(format);
+
+    writer.write(&ExportRecord::Header(ExportHeader {
+        version: EXPORT_VERSION,
+        account_id: external_account_id(account.id()).unwrap_or_else(|| account.id().to_string()),
+        exported_at: clock::now(),
+    }))?;
+
+    let counts = ExportCounts {
+        resources: export_table(
+            account,
+            &mut writer,
+            sink,
+            "LET $page = SELECT * FROM resource WHERE id != resource:[] AND ($cursor IS NONE OR id > $cursor) ORDER BY id LIMIT $page_limit;",
+            "SELECT id, environments, attributes, first_seen_at, last_seen_at FROM $page;",
+            ExportRecord::Resource,
+        )
+        .await?,
+        contains: export_table(
+            account,
+            &mut writer,
+            sink,
+            "LET $page = SELECT * FROM contains WHERE $cursor IS NONE OR id > $cursor ORDER BY id LIMIT $page_limit;",
+            "SELECT in AS container, out AS resource, first_seen_at, last_seen_at FROM $page;",
+            ExportRecord::Contains,
+        )
+        .await?,
+        principal_chains: export_table(
+            account,
+            &mut writer,
+            sink,
+            "LET $page = SELECT * FROM principal_chain WHERE $cursor IS NONE OR id > $cursor ORDER BY id LIMIT $page_limit;",
+            "SELECT chain ?? record::id(id) AS chain, first_seen_at, last_seen_at FROM $page;",
+            ExportRecord::PrincipalChain,
+        )
+        .await?,
+        events: export_table(
+            account,
+            &mut writer,
+            sink,
+            "LET $page = SELECT * FROM event WHERE $cursor IS NONE OR id > $cursor ORDER BY id LIMIT $page_limit;",
+            "SELECT in AS principal, type, out AS resource, fn::principal_chains(principal_chains) AS principal_chains, has_direct_principal_chain, first_seen_at, last_seen_at FROM $page;",
+            ExportRecord::Event,
+        )
+        .await?,
+    };
+
+    writer.write(&ExportRecord::End(counts))?;
+    writer.finish();
+    sink.send(writer.take()).await?;
+
+    info!(?counts, bytes = writer.bytes, "Exported account data");
+
+    Ok((counts, writer.bytes))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DownloadExportParams {
+    #[serde(default)]
+    #[param(inline)]
+    format: ExportFormat,
+}
+
+// Streams every resource, `contains` relation, principal chain, and event of the account as a versioned archive file
+// download. The archive is read a page at a time while it is sent, so records written during the export may or may not
+// be included. Only the account owner may export the account's data.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/export",
+    tag = "export",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), DownloadExportParams),
+    responses((status = 200, description = "The account's data as a JSON or JSONL archive file download"))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn download_export(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Query(params): Query<DownloadExportParams>,
+) -> Result<Response> {
+    auth.validate_account_owner(account.id()).await?;
+
+    // Fail before the response starts if the resources database is unavailable
+    drop(account.resources_db().await?);
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::AccountDataExported,
+        Some(json!({ "format": params.format })),
+    )
+    .await;
+
+    let content_disposition = format!(
+        "attachment; filename=\"archodex-export-{}-{}.{}\"",
+        external_account_id(account.id()).unwrap_or_else(|| account.id().to_string()),
+        clock::now().format("%Y%m%dT%H%M%SZ"),
+        params.format.extension(),
+    );
+
+    let (sender, body) = Channel::<Bytes, anyhow::Error>::new(STREAM_BUFFER_PAGES);
+
+    let span = info_span!("export_stream", account_id = account.id());
+
+    tokio::spawn(
+        async move {
+            let mut sink = ExportSink::Stream(sender);
+
+            // The response status has already been sent, so failures abort the body, leaving the archive without its
+            // final `end` record
+            if let Err(err) = write_archive(&account, params.format, &mut sink).await {
+                warn!(?err, "Failed to stream account data export");
+
+                if let ExportSink::Stream(sender) = sink {
+                    sender.abort(anyhow::anyhow!("Failed to export account data: {err}"));
+                }
+            }
+        }
+        .instrument(span),
+    );
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(params.format.content_type()),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                HeaderValue::from_str(&content_disposition)
+                    .context("Content-Disposition header should be valid")?,
+            ),
+        ],
+        Body::new(body),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UploadExportRequest {
+    #[serde(default)]
+    format: ExportFormat,
+    // Presigned S3 `PutObject` URL the archive is uploaded to
+    upload_url: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct UploadExportResponse {
+    version: u32,
+    format: ExportFormat,
+    counts: ExportCounts,
+    bytes: u64,
+}
+
+// Exports the account's data like `GET /account/{account_id}/export`, then uploads the archive to a presigned S3 URL.
+// Presigned uploads must declare their length, so the archive is built in memory before it is uploaded, and exports larger
+// than `ARCHODEX_EXPORT_UPLOAD_MAX_BYTES` are rejected.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/export",
+    tag = "export",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = UploadExportRequest,
+    responses((status = 200, body = UploadExportResponse))
+)]
+#[instrument(err, skip(auth, account, request_id, req), fields(format = ?req.format))]
+pub(crate) async fn upload_export(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<UploadExportRequest>,
+) -> Result<Json<UploadExportResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    let Ok(upload_url) = reqwest::Url::parse(&req.upload_url) else {
+        bad_request!("Invalid upload URL");
+    };
+
+    if upload_url.scheme() != "https" {
+        bad_request!("Upload URL must be an HTTPS URL");
+    }
+
+    // The hosted service only uploads to S3, so the URL can't be used to reach other hosts from within the service
+    #[cfg(feature = "archodex-com")]
+    if !upload_url
+        .host_str()
+        .is_some_and(|host| host.ends_with(".amazonaws.com"))
+    {
+        bad_request!("Upload URL must be a presigned S3 URL");
+    }
+
+    let mut sink = ExportSink::Buffer {
+        buf: vec![],
+        max_bytes: Env::export_upload_max_bytes(),
+    };
+
+    let (counts, bytes) = write_archive(&account, req.format, &mut sink).await?;
+
+    let ExportSink::Buffer { buf: archive, .. } = sink else {
+        unreachable!("Upload exports are written to a buffer");
+    };
+
+    let res = reqwest::Client::new()
+        .put(upload_url)
+        .header(reqwest::header::CONTENT_TYPE, req.format.content_type())
+        .body(archive)
+        .send()
+        .await
+        .context("Failed to upload account data export")?;
+
+    // Presigned URLs expire, so rejections are reported to the caller rather than as server errors
+    if !res.status().is_success() {
+        bad_request!(
+            "Upload URL rejected the account data export with status {}",
+            res.status()
+        );
+    }
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::AccountDataExported,
+        Some(json!({ "format": req.format, "uploaded": true, "counts": counts })),
+    )
+    .await;
+
+    Ok(Json(UploadExportResponse {
+        version: EXPORT_VERSION,
+        format: req.format,
+        counts,
+        bytes,
+    }))
+}
@@ -0,0 +1,50 @@
+use tracing::{info, instrument, warn};
+
+use crate::{Result, account::list_live_accounts, env::Env, read_only};
+
+/// Stores the external ID of every account whose external ID is missing or was derived from a different HMAC key, e.g.
+/// after `ARCHODEX_ACCOUNT_ID_HMAC_KEY` is first set or rotated.
+///
+/// URLs with an account's new external ID aren't resolved until it is stored, so this runs once at startup rather than
+/// when accounts are listed. Accounts that fail to be backfilled are logged as warnings rather than failing startup.
+/// Nothing is backfilled while the backend is in read-only mode.
+pub async fn backfill() {
+    if let Err(err) = backfill_accounts().await {
+        warn!(?err, "Failed to backfill account external IDs");
+    }
+}
+
+#[instrument(err)]
+pub(crate) async fn backfill_accounts() -> Result<()> {
+    if Env::account_id_hmac_key().is_none() {
+        return Ok(());
+    }
+
+    if read_only::is_enabled() {
+        info!("Not backfilling account external IDs in read-only mode");
+        return Ok(());
+    }
+
+    let accounts = list_live_accounts().await?;
+
+    let mut backfilled = 0;
+
+    for account in &accounts {
+        match account.ensure_external_id().await {
+            Ok(true) => backfilled += 1,
+            Ok(false) => {}
+            Err(err) => warn!(
+                account_id = account.id(),
+                ?err,
+                "Failed to backfill external ID for account"
+            ),
+        }
+    }
+
+    info!(
+        accounts = accounts.len(),
+        backfilled, "Backfilled account external IDs"
+    );
+
+    Ok(())
+}
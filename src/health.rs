@@ -0,0 +1,468 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use axum::{Json, extract::Query, http::StatusCode};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex, task::JoinSet};
+use tracing::{instrument, warn};
+
+use archodex_error::{anyhow, bad_request};
+
+use crate::{
+    Result,
+    auth::dashboard_auth_provider,
+    clock,
+    db::{
+        QueryCheckFirstRealError as _, accounts_db_shard, check_resources_dbs, primary_accounts_db,
+    },
+    env::Env,
+    worker,
+};
+
+// Checks slower than this are recorded as failures, so a hung dependency doesn't stall the history
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Checks older than this are dropped from memory and deleted from the accounts database
+const RETENTION: TimeDelta = TimeDelta::days(7);
+
+// How often new checks are persisted to the accounts database
+const PERSIST_INTERVAL: Duration = Duration::from_secs(300);
+
+// Failures returned by the history route, most recent first
+const MAX_RECENT_FAILURES: usize = 100;
+
+const DEFAULT_HISTORY_HOURS: u32 = 24;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct DependencyCheck {
+    // `accounts_db:<shard index>` for each accounts database shard, `runtime` for the backend's async runtime, and for
+    // readiness checks `resources_db` for resources databases and `jwks` for the dashboard's identity provider
+    dependency: String,
+    ok: bool,
+    duration_ms: u64,
+    error: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct HealthCheck {
+    checked_at: DateTime<Utc>,
+    dependencies: Vec<DependencyCheck>,
+}
+
+// A check as stored in the `health_check` table of the first accounts database shard
+#[derive(Serialize)]
+struct HealthCheckRecord {
+    checked_at: surrealdb::sql::Datetime,
+    dependencies: Vec<DependencyCheck>,
+}
+
+#[derive(Default)]
+struct History {
+    // Checks of the retention period, oldest first
+    checks: VecDeque<HealthCheck>,
+    // Checks not yet persisted to the accounts database
+    unpersisted: Vec<HealthCheck>,
+    started_at: Option<DateTime<Utc>>,
+    // When checks were last persisted, or `None` if they never were
+    last_persisted: Option<Instant>,
+}
+
+static HISTORY: LazyLock<Mutex<History>> = LazyLock::new(Mutex::default);
+
+/// Checks the backend's dependencies every `ARCHODEX_HEALTH_CHECK_INTERVAL_SECONDS` until the process exits, recording
+/// the results in a ring buffer served by `GET /health/history`.
+///
+/// Each check times a trivial query against every accounts database shard, which for self-hosted backends is the same
+/// SurrealDB instance that holds the resources databases, and the time to schedule a task on the backend's async
+/// runtime, which grows when the backend itself is overloaded. Comparing the two shows whether slowness was caused by
+/// the database or the backend. Checks are persisted to the first accounts database shard every few minutes and loaded
+/// again at startup, so the history survives restarts.
+pub async fn run_worker() {
+    {
+        let mut history = HISTORY.lock().await;
+        history.started_at = Some(clock::now());
+        history.last_persisted = Some(Instant::now());
+
+        match load_persisted_checks().await {
+            Ok(checks) => history.checks = checks.into(),
+            Err(err) => warn!(?err, "Failed to load persisted health checks"),
+        }
+    }
+
+    worker::run_periodically("health_check", Env::health_check_interval(), || async {
+        record_check().await;
+        anyhow::Ok(())
+    })
+    .await;
+}
+
+// Checks the backend's dependencies once and persists the results right away, for processes that don't keep a history
+// between checks, e.g. Lambda functions running the check on a schedule
+#[instrument(err)]
+pub(crate) async fn record_and_persist_check() -> Result<()> {
+    let check = check_dependencies().await;
+
+    warn_failed_dependencies(&check);
+
+    let oldest_retained = check.checked_at - RETENTION;
+
+    persist_checks(&[check], oldest_retained).await
+}
+
+// Checks the backend's dependencies and records the results, persisting them if they weren't persisted for a while
+async fn record_check() {
+    let check = check_dependencies().await;
+
+    warn_failed_dependencies(&check);
+
+    let oldest_retained = check.checked_at - RETENTION;
+
+    let unpersisted = {
+        let mut history = HISTORY.lock().await;
+
+        history.checks.push_back(check.clone());
+        history.unpersisted.push(check);
+
+        while history
+            .checks
+            .front()
+            .is_some_and(|check| check.checked_at < oldest_retained)
+        {
+            history.checks.pop_front();
+        }
+
+        // Checks that couldn't be persisted are dropped once they would be deleted anyway
+        history
+            .unpersisted
+            .retain(|check| check.checked_at >= oldest_retained);
+
+        if history
+            .last_persisted
+            .is_none_or(|last_persisted| last_persisted.elapsed() >= PERSIST_INTERVAL)
+        {
+            history.last_persisted = Some(Instant::now());
+            std::mem::take(&mut history.unpersisted)
+        } else {
+            vec![]
+        }
+    };
+
+    if !unpersisted.is_empty()
+        && let Err(err) = persist_checks(&unpersisted, oldest_retained).await
+    {
+        warn!(?err, "Failed to persist health checks, retrying later");
+        HISTORY.lock().await.unpersisted.splice(0..0, unpersisted);
+    }
+}
+
+fn warn_failed_dependencies(check: &HealthCheck) {
+    for dependency in check
+        .dependencies
+        .iter()
+        .filter(|dependency| !dependency.ok)
+    {
+        warn!(
+            dependency = dependency.dependency,
+            duration_ms = dependency.duration_ms,
+            error = dependency.error,
+            "Health check failed"
+        );
+    }
+}
+
+async fn check_accounts_db_shard(shard: usize) -> DependencyCheck {
+    check_dependency(format!("accounts_db:{shard}"), async move {
+        accounts_db_shard(shard)
+            .await?
+            .query("RETURN true")
+            .await?
+            .check_first_real_error()?;
+
+        Ok(())
+    })
+    .await
+}
+
+async fn check_dependencies() -> HealthCheck {
+    let checked_at = clock::now();
+    let mut dependencies = vec![];
+
+    for shard in 0..Env::accounts_shards().len() {
+        dependencies.push(check_accounts_db_shard(shard).await);
+    }
+
+    dependencies.push(
+        check_dependency("runtime".to_string(), async {
+            Ok(tokio::spawn(async {}).await.map_err(anyhow::Error::from)?)
+        })
+        .await,
+    );
+
+    HealthCheck {
+        checked_at,
+        dependencies,
+    }
+}
+
+async fn check_dependency(
+    dependency: String,
+    check: impl Future<Output = Result<()>>,
+) -> DependencyCheck {
+    let start = Instant::now();
+
+    let error = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some(format!("Timed out after {CHECK_TIMEOUT:?}")),
+    };
+
+    DependencyCheck {
+        dependency,
+        ok: error.is_none(),
+        duration_ms: u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX),
+        error,
+    }
+}
+
+#[instrument(err)]
+async fn load_persisted_checks() -> Result<Vec<HealthCheck>> {
+    Ok(primary_accounts_db()
+        .await?
+        .query(
+            "SELECT checked_at, dependencies FROM health_check WHERE checked_at >= $since ORDER BY checked_at",
+        )
+        .bind((
+            "since",
+            surrealdb::sql::Datetime::from(clock::now() - RETENTION),
+        ))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<HealthCheck>>(0)?)
+}
+
+#[instrument(err, skip(checks), fields(checks = checks.len()))]
+async fn persist_checks(checks: &[HealthCheck], oldest_retained: DateTime<Utc>) -> Result<()> {
+    primary_accounts_db()
+        .await?
+        .query("INSERT INTO health_check $checks RETURN NONE")
+        .query("DELETE health_check WHERE checked_at < $oldest_retained")
+        .bind((
+            "checks",
+            checks
+                .iter()
+                .map(|check| HealthCheckRecord {
+                    checked_at: surrealdb::sql::Datetime::from(check.checked_at),
+                    dependencies: check.dependencies.clone(),
+                })
+                .collect::<Vec<_>>(),
+        ))
+        .bind((
+            "oldest_retained",
+            surrealdb::sql::Datetime::from(oldest_retained),
+        ))
+        .await?
+        .check_first_real_error()?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct HealthHistoryParams {
+    // Hours of history to return, up to the 7 day retention period
+    hours: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DependencyUptime {
+    checks: usize,
+    failures: usize,
+    uptime_percent: f64,
+    average_duration_ms: u64,
+    max_duration_ms: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct DependencyFailure {
+    checked_at: DateTime<Utc>,
+    dependency: String,
+    duration_ms: u64,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct HealthHistoryResponse {
+    // When this backend started checking its dependencies
+    started_at: Option<DateTime<Utc>>,
+    interval_seconds: u64,
+    // Uptime and check durations of each dependency over the requested period
+    dependencies: BTreeMap<String, DependencyUptime>,
+    recent_failures: Vec<DependencyFailure>,
+    // Every check of the requested period, oldest first
+    checks: Vec<HealthCheck>,
+}
+
+// Returns the recorded health checks of the backend's dependencies, with each dependency's uptime and the most recent
+// failures. Checks persisted by other backends sharing the accounts database are included after a restart.
+#[instrument(err, skip_all)]
+pub(crate) async fn history(
+    Query(params): Query<HealthHistoryParams>,
+) -> Result<Json<HealthHistoryResponse>> {
+    let hours = params.hours.unwrap_or(DEFAULT_HISTORY_HOURS);
+    if hours == 0 || TimeDelta::hours(i64::from(hours)) > RETENTION {
+        bad_request!("hours must be between 1 and {}", RETENTION.num_hours());
+    }
+
+    let since = clock::now() - TimeDelta::hours(i64::from(hours));
+
+    let history = HISTORY.lock().await;
+
+    let checks = history
+        .checks
+        .iter()
+        .filter(|check| check.checked_at >= since)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut dependencies = BTreeMap::<String, DependencyUptime>::new();
+    let mut total_durations_ms = BTreeMap::<String, u64>::new();
+
+    for dependency in checks.iter().flat_map(|check| &check.dependencies) {
+        let uptime =
+            dependencies
+                .entry(dependency.dependency.clone())
+                .or_insert(DependencyUptime {
+                    checks: 0,
+                    failures: 0,
+                    uptime_percent: 100.0,
+                    average_duration_ms: 0,
+                    max_duration_ms: 0,
+                });
+
+        uptime.checks += 1;
+        if !dependency.ok {
+            uptime.failures += 1;
+        }
+        uptime.max_duration_ms = uptime.max_duration_ms.max(dependency.duration_ms);
+
+        *total_durations_ms
+            .entry(dependency.dependency.clone())
+            .or_default() += dependency.duration_ms;
+    }
+
+    for (dependency, uptime) in &mut dependencies {
+        #[allow(clippy::cast_precision_loss)]
+        {
+            uptime.uptime_percent =
+                100.0 * (uptime.checks - uptime.failures) as f64 / uptime.checks as f64;
+        }
+        uptime.average_duration_ms = total_durations_ms[dependency] / uptime.checks as u64;
+    }
+
+    let recent_failures = checks
+        .iter()
+        .rev()
+        .flat_map(|check| {
+            check
+                .dependencies
+                .iter()
+                .filter(|dependency| !dependency.ok)
+                .map(|dependency| DependencyFailure {
+                    checked_at: check.checked_at,
+                    dependency: dependency.dependency.clone(),
+                    duration_ms: dependency.duration_ms,
+                    error: dependency.error.clone(),
+                })
+        })
+        .take(MAX_RECENT_FAILURES)
+        .collect();
+
+    Ok(Json(HealthHistoryResponse {
+        started_at: history.started_at,
+        interval_seconds: Env::health_check_interval().as_secs(),
+        dependencies,
+        recent_failures,
+        checks,
+    }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct LivenessResponse {
+    status: &'static str,
+}
+
+// Responds as long as the process can serve requests, without checking its dependencies, so orchestrators only restart
+// backends that are stuck rather than ones waiting for a database to come back
+pub(crate) async fn livez() -> Json<LivenessResponse> {
+    Json(LivenessResponse { status: "ok" })
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReadinessResponse {
+    // `ready` if every check passed, otherwise `not_ready`
+    status: &'static str,
+    checks: Vec<DependencyCheck>,
+}
+
+// Checks whether the backend can serve requests: every accounts database shard can be queried, resources databases
+// can be connected to, and the signing keys of the dashboard's identity provider were fetched. Checks run concurrently,
+// and a 503 response is returned if any fails so orchestrators and load balancers hold traffic back until the backend's
+// dependencies are available.
+#[instrument(skip_all)]
+pub(crate) async fn readyz() -> (StatusCode, Json<ReadinessResponse>) {
+    let mut tasks = JoinSet::new();
+
+    for shard in 0..Env::accounts_shards().len() {
+        tasks.spawn(async move { Some(check_accounts_db_shard(shard).await) });
+    }
+
+    tasks.spawn(async {
+        Some(check_dependency("resources_db".to_string(), check_resources_dbs()).await)
+    });
+
+    tasks.spawn(async {
+        let provider = dashboard_auth_provider();
+        let check = provider.readiness_check()?;
+
+        Some(check_dependency("jwks".to_string(), check).await)
+    });
+
+    let mut checks = tasks
+        .join_all()
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    checks.sort_by(|a, b| a.dependency.cmp(&b.dependency));
+
+    for check in checks.iter().filter(|check| !check.ok) {
+        warn!(
+            dependency = check.dependency,
+            duration_ms = check.duration_ms,
+            error = check.error,
+            "Readiness check failed"
+        );
+    }
+
+    if checks.iter().all(|check| check.ok) {
+        (
+            StatusCode::OK,
+            Json(ReadinessResponse {
+                status: "ready",
+                checks,
+            }),
+        )
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ReadinessResponse {
+                status: "not_ready",
+                checks,
+            }),
+        )
+    }
+}
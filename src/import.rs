@@ -0,0 +1,651 @@
+use std::path::Path;
+
+use axum::{
+    Extension, Json,
+    body::{Body, Bytes},
+    http::StatusCode,
+};
+use http_body_util::BodyExt as _;
+use serde::Serialize;
+use serde_json::json;
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tokio::io::AsyncReadExt as _;
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+
+use archodex_error::{PublicError, anyhow, bad_request, conflict, not_found};
+
+use crate::{
+    Result,
+    account::{Account, get_account, resolve_account_id},
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    db::QueryCheckFirstRealError as _,
+    env::Env,
+    export::{EXPORT_VERSION, ExportCounts, ExportHeader, ExportRecord},
+    resource::surrealdb_thing_from_resource_id,
+    router::RequestId,
+    traced_query::TracedQuery,
+    value::surrealdb_value_from_json_value,
+};
+
+// Records written to the resources database per transaction
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+// Bytes read from an archive file at a time
+const FILE_READ_BYTES: usize = 64 * 1024;
+
+// Splits an archive into records as it is read. JSON archives are an array of records and JSONL archives have one record
+// per line, so the records of either format are found by skipping the whitespace, commas, and brackets between them.
+#[derive(Default)]
+struct ArchiveReader {
+    buf: Vec<u8>,
+    // Start of the bytes not yet parsed into records
+    pos: usize,
+    records: u64,
+}
+
+impl ArchiveReader {
+    fn push(&mut self, data: &[u8]) {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(data);
+    }
+
+    // Returns the next record, or `None` if more of the archive must be read first
+    fn next_record(&mut self) -> Result<Option<ExportRecord>> {
+        self.pos += self.buf[self.pos..]
+            .iter()
+            .take_while(|byte| matches!(byte, b' ' | b'\t' | b'\r' | b'\n' | b',' | b'[' | b']'))
+            .count();
+
+        let mut records =
+            serde_json::Deserializer::from_slice(&self.buf[self.pos..]).into_iter::<ExportRecord>();
+
+        match records.next() {
+            Some(Ok(record)) => {
+                self.pos += records.byte_offset();
+                self.records += 1;
+                Ok(Some(record))
+            }
+            Some(Err(err)) if err.is_eof() => {
+                // The report body limit bounds each record, like it bounds each record of a streamed report
+                if self.buf.len() - self.pos > Env::report_max_body_bytes() {
+                    return Err(PublicError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        format!("Archive record {} is too large", self.records + 1),
+                    ));
+                }
+
+                Ok(None)
+            }
+            Some(Err(err)) => bad_request!("Invalid archive record {}: {err}", self.records + 1),
+            None => Ok(None),
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.pos < self.buf.len() {
+            bad_request!("Archive ends within record {}", self.records + 1);
+        }
+
+        Ok(())
+    }
+}
+
+// Records waiting to be written in the next transaction
+#[derive(Default)]
+struct ImportBatch {
+    resources: Vec<surrealdb::sql::Value>,
+    contains: Vec<surrealdb::sql::Value>,
+    principal_chains: Vec<surrealdb::sql::Value>,
+    events: Vec<surrealdb::sql::Value>,
+}
+
+impl ImportBatch {
+    fn len(&self) -> usize {
+        self.resources.len() + self.contains.len() + self.principal_chains.len() + self.events.len()
+    }
+
+    fn push(&mut self, record: ExportRecord) {
+        match record {
+            ExportRecord::Resource(resource) => {
+                let mut object = surrealdb::sql::Object::default();
+                object.insert(
+                    "id".to_string(),
+                    surrealdb_thing_from_resource_id(resource.id),
+                );
+                object.insert(
+                    "environments".to_string(),
+                    resource
+                        .environments
+                        .into_iter()
+                        .map(surrealdb::sql::Value::from)
+                        .collect::<Vec<_>>()
+                        .into(),
+                );
+                // Resources without attributes are exported with empty or null attributes, which default to empty
+                if let serde_json::Value::Object(attributes) = resource.attributes
+                    && !attributes.is_empty()
+                {
+                    object.insert(
+                        "attributes".to_string(),
+                        surrealdb_value_from_json_value(attributes.into()),
+                    );
+                }
+                object.insert(
+                    "first_seen_at".to_string(),
+                    surrealdb::sql::Datetime::from(resource.first_seen_at).into(),
+                );
+                object.insert(
+                    "last_seen_at".to_string(),
+                    surrealdb::sql::Datetime::from(resource.last_seen_at).into(),
+                );
+                self.resources.push(object.into());
+            }
+            ExportRecord::Contains(contains) => {
+                let mut object = surrealdb::sql::Object::default();
+                object.insert(
+                    "in".to_string(),
+                    surrealdb_thing_from_resource_id(contains.container),
+                );
+                object.insert(
+                    "out".to_string(),
+                    surrealdb_thing_from_resource_id(contains.resource),
+                );
+                object.insert(
+                    "first_seen_at".to_string(),
+                    surrealdb::sql::Datetime::from(contains.first_seen_at).into(),
+                );
+                object.insert(
+                    "last_seen_at".to_string(),
+                    surrealdb::sql::Datetime::from(contains.last_seen_at).into(),
+                );
+                self.contains.push(object.into());
+            }
+            ExportRecord::PrincipalChain(principal_chain) => {
+                let mut object = surrealdb::sql::Object::default();
+                object.insert(
+                    "chain".to_string(),
+                    surrealdb::sql::Array::from(principal_chain.chain).into(),
+                );
+                object.insert(
+                    "first_seen_at".to_string(),
+                    surrealdb::sql::Datetime::from(principal_chain.first_seen_at).into(),
+                );
+                object.insert(
+                    "last_seen_at".to_string(),
+                    surrealdb::sql::Datetime::from(principal_chain.last_seen_at).into(),
+                );
+                self.principal_chains.push(object.into());
+            }
+            ExportRecord::Event(event) => {
+                let mut object = surrealdb::sql::Object::default();
+                object.insert(
+                    "principal".to_string(),
+                    surrealdb_thing_from_resource_id(event.principal),
+                );
+                object.insert("type".to_string(), event.r#type.into());
+                object.insert(
+                    "resource".to_string(),
+                    surrealdb_thing_from_resource_id(event.resource),
+                );
+                object.insert(
+                    "principal_chains".to_string(),
+                    event
+                        .principal_chains
+                        .into_iter()
+                        .map(|chain| {
+                            surrealdb::sql::Value::from(surrealdb::sql::Array::from(chain))
+                        })
+                        .collect::<Vec<_>>()
+                        .into(),
+                );
+                object.insert(
+                    "has_direct_principal_chain".to_string(),
+                    event.has_direct_principal_chain.into(),
+                );
+                object.insert(
+                    "first_seen_at".to_string(),
+                    surrealdb::sql::Datetime::from(event.first_seen_at).into(),
+                );
+                object.insert(
+                    "last_seen_at".to_string(),
+                    surrealdb::sql::Datetime::from(event.last_seen_at).into(),
+                );
+                self.events.push(object.into());
+            }
+            ExportRecord::Header(_) | ExportRecord::End(_) => {
+                unreachable!("Header and end records are not batched")
+            }
+        }
+    }
+}
+
+// Replays the records of an archive into an account's resources database. Principal chains are identified the way this
+// backend identifies new chains, so chains exported from a backend with a different
+// `ARCHODEX_HASHED_PRINCIPAL_CHAIN_IDS` setting are re-identified, along with the events referring to them.
+struct Importer<'a> {
+    account: &'a Account,
+    header: Option<ExportHeader>,
+    end: Option<ExportCounts>,
+    batch: ImportBatch,
+    imported: ExportCounts,
+    // IDs of the resources inserted by committed batches, for deleting them if the import fails
+    inserted_resources: Vec<surrealdb::sql::Value>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(account: &'a Account) -> Self {
+        Self {
+            account,
+            header: None,
+            end: None,
+            batch: ImportBatch::default(),
+            imported: ExportCounts::default(),
+            inserted_resources: Vec::new(),
+        }
+    }
+
+    async fn add(&mut self, record: ExportRecord) -> Result<()> {
+        if self.end.is_some() {
+            bad_request!("Archive has records after its end record");
+        }
+
+        match (self.header.is_some(), record) {
+            (false, ExportRecord::Header(header)) => {
+                if header.version > EXPORT_VERSION {
+                    bad_request!(
+                        "Archive version {} is newer than the latest version this backend imports ({EXPORT_VERSION})",
+                        header.version
+                    );
+                }
+
+                if header.version != EXPORT_VERSION {
+                    bad_request!("Archive version {} is not supported", header.version);
+                }
+
+                info!(
+                    source_account_id = header.account_id,
+                    exported_at = %header.exported_at,
+                    "Importing account data archive"
+                );
+
+                self.header = Some(header);
+            }
+            (false, _) => bad_request!("Archive must start with a header record"),
+            (true, ExportRecord::Header(_)) => bad_request!("Archive has more than one header"),
+            (true, ExportRecord::End(counts)) => self.end = Some(counts),
+            (true, record) => {
+                self.batch.push(record);
+
+                if self.batch.len() >= IMPORT_BATCH_SIZE {
+                    self.flush().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes the batched records in a transaction. Each record refers only to records before it in the archive, which
+    // are in this or an earlier batch.
+    async fn flush(&mut self) -> Result<()> {
+        let batch = std::mem::take(&mut self.batch);
+
+        if batch.len() == 0 {
+            return Ok(());
+        }
+
+        let resource_ids = batch
+            .resources
+            .iter()
+            .map(|resource| match resource {
+                surrealdb::sql::Value::Object(object) => object["id"].clone(),
+                _ => unreachable!("Batched resources are objects"),
+            })
+            .collect::<Vec<_>>();
+
+        let counts = ExportCounts {
+            resources: batch.resources.len() as u64,
+            contains: batch.contains.len() as u64,
+            principal_chains: batch.principal_chains.len() as u64,
+            events: batch.events.len() as u64,
+        };
+
+        // The hash of a chain is computed by the same function report ingestion uses
+        let (principal_chain_id, principal_chain_thing) = if Env::hashed_principal_chain_ids() {
+            (
+                "id: fn::principal_chain_hash($principal_chain.chain), chain: $principal_chain.chain",
+                "type::thing('principal_chain', fn::principal_chain_hash($chain))",
+            )
+        } else {
+            (
+                "id: $principal_chain.chain",
+                "type::thing('principal_chain', $chain)",
+            )
+        };
+
+        let db = self.account.resources_db().await?;
+
+        let mut query = TracedQuery::new(&db, "import").query(BeginStatement::default());
+
+        if !batch.resources.is_empty() {
+            query = query
+                .query("INSERT INTO resource $resources RETURN NONE;")
+                .bind(("resources", surrealdb::sql::Value::from(batch.resources)));
+        }
+
+        if !batch.contains.is_empty() {
+            query = query
+                .query("INSERT RELATION INTO contains $contains RETURN NONE;")
+                .bind(("contains", surrealdb::sql::Value::from(batch.contains)));
+        }
+
+        if !batch.principal_chains.is_empty() {
+            query = query
+                .query(format!(
+                    "FOR $principal_chain IN $principal_chains {{
+                        INSERT INTO principal_chain {{ {principal_chain_id}, first_seen_at: $principal_chain.first_seen_at, last_seen_at: $principal_chain.last_seen_at }} RETURN NONE;
+                    }};"
+                ))
+                .bind((
+                    "principal_chains",
+                    surrealdb::sql::Value::from(batch.principal_chains),
+                ));
+        }
+
+        if !batch.events.is_empty() {
+            query = query
+                .query(format!(
+                    "FOR $event IN $events {{
+                        INSERT RELATION INTO event {{ in: $event.principal, out: $event.resource, type: $event.type, principal_chains: $event.principal_chains.map(|$chain| {principal_chain_thing}), has_direct_principal_chain: $event.has_direct_principal_chain, first_seen_at: $event.first_seen_at, last_seen_at: $event.last_seen_at }} RETURN NONE;
+                    }};"
+                ))
+                .bind(("events", surrealdb::sql::Value::from(batch.events)));
+        }
+
+        query
+            .query(CommitStatement::default())
+            .execute()
+            .await?
+            .check_first_real_error()?;
+
+        self.inserted_resources.extend(resource_ids);
+
+        self.imported.resources += counts.resources;
+        self.imported.contains += counts.contains;
+        self.imported.principal_chains += counts.principal_chains;
+        self.imported.events += counts.events;
+
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> Result<(ExportHeader, ExportCounts)> {
+        self.flush().await?;
+
+        let Some(header) = self.header.take() else {
+            bad_request!("Archive is empty");
+        };
+
+        let Some(end) = self.end else {
+            bad_request!("Archive is truncated: it has no end record");
+        };
+
+        if end != self.imported {
+            bad_request!(
+                "Archive records don't match its end record: expected {end:?}, found {:?}",
+                self.imported
+            );
+        }
+
+        Ok((header, self.imported))
+    }
+}
+
+// Returns an error if the account's resources database already has resources. Imports only fill fresh accounts, as
+// merging an archive into existing data would need the same upserts as report ingestion. Reports ingested while the
+// import runs aren't excluded, but an archive record that a report already created fails the import's insert.
+async fn ensure_fresh(account: &Account) -> Result<()> {
+    let has_resources = account
+        .resources_db()
+        .await?
+        .query(
+            "RETURN array::len(SELECT VALUE id FROM resource WHERE id != resource:[] LIMIT 1) > 0",
+        )
+        .await?
+        .check_first_real_error()?
+        .take::<Option<bool>>(0)?
+        .unwrap_or_default();
+
+    if has_resources {
+        conflict!("Account data can only be imported into an account without resources");
+    }
+
+    Ok(())
+}
+
+// Deletes one batch of the resources of a failed import, along with the events, principal chains, and `contains` and
+// derived edges referencing them, as resource retention does. Every imported edge and principal chain references an
+// imported resource, as archive records only refer to records before them.
+const DELETE_IMPORTED_QUERY: &str = "
+    BEGIN;
+
+    LET $principal_chains = SELECT VALUE id FROM principal_chain WHERE resources CONTAINSANY $resources;
+    LET $events = SELECT VALUE id FROM event WHERE $resources CONTAINS in OR $resources CONTAINS out;
+
+    DELETE $events;
+    UPDATE event SET principal_chains = array::complement(principal_chains, $principal_chains) WHERE principal_chains CONTAINSANY $principal_chains RETURN NONE;
+    DELETE $principal_chains;
+    DELETE contains WHERE $resources CONTAINS in OR $resources CONTAINS out;
+    DELETE derived_edge WHERE $resources CONTAINS in OR $resources CONTAINS out;
+    DELETE derived_edge WHERE events CONTAINSANY $events AND array::is_empty(array::complement(events, $events));
+    UPDATE derived_edge SET events = array::complement(events, $events) WHERE events CONTAINSANY $events RETURN NONE;
+    DELETE $resources;
+
+    COMMIT;";
+
+// Deletes the records of a failed import so the import can be retried. Only the resources the import inserted are
+// deleted, so resources reported while the import ran are kept.
+#[instrument(err, skip_all, fields(account_id = account.id(), resources = inserted_resources.len()))]
+async fn delete_imported(
+    account: &Account,
+    inserted_resources: Vec<surrealdb::sql::Value>,
+) -> Result<()> {
+    let db = account.resources_db().await?;
+
+    for resources in inserted_resources.chunks(IMPORT_BATCH_SIZE) {
+        TracedQuery::new(&db, "import_delete")
+            .query(DELETE_IMPORTED_QUERY)
+            .bind(("resources", surrealdb::sql::Value::from(resources.to_vec())))
+            .execute()
+            .await?
+            .check_first_real_error()?;
+    }
+
+    Ok(())
+}
+
+// Where an archive is read from
+enum ArchiveSource<'a> {
+    // A `POST /account/{account_id}/import` upload
+    Body(Body),
+    File {
+        file: tokio::fs::File,
+        path: &'a Path,
+        buf: Vec<u8>,
+    },
+}
+
+impl ArchiveSource<'_> {
+    // Reads the next chunk of the archive, returning `None` at its end
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>> {
+        match self {
+            Self::Body(body) => {
+                while let Some(frame) = body.frame().await {
+                    let Ok(frame) = frame else {
+                        bad_request!("Failed to read archive");
+                    };
+
+                    if let Ok(data) = frame.into_data() {
+                        return Ok(Some(data));
+                    }
+                }
+
+                Ok(None)
+            }
+            Self::File { file, path, buf } => {
+                let read = match file.read(buf).await {
+                    Ok(read) => read,
+                    Err(err) => bad_request!("Failed to read {}: {err}", path.display()),
+                };
+
+                Ok((read > 0).then(|| Bytes::copy_from_slice(&buf[..read])))
+            }
+        }
+    }
+}
+
+// Imports an archive read a chunk at a time from `source`
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn import_archive(
+    account: &Account,
+    mut source: ArchiveSource<'_>,
+) -> Result<(ExportHeader, ExportCounts)> {
+    ensure_fresh(account).await?;
+
+    let mut reader = ArchiveReader::default();
+    let mut importer = Importer::new(account);
+
+    let res = async {
+        while let Some(chunk) = source.next_chunk().await? {
+            reader.push(&chunk);
+
+            while let Some(record) = reader.next_record()? {
+                importer.add(record).await?;
+            }
+        }
+
+        reader.finish()?;
+
+        importer.finish().await
+    }
+    .await;
+
+    match res {
+        Ok((header, counts)) => {
+            info!(?counts, "Imported account data archive");
+            Ok((header, counts))
+        }
+        Err(err) => {
+            if let Err(delete_err) = delete_imported(account, importer.inserted_resources).await {
+                warn!(?delete_err, "Failed to delete records of failed import");
+            }
+
+            Err(err)
+        }
+    }
+}
+
+/// Imports an account data archive file, as downloaded from `GET /account/{account_id}/export`, into the account with
+/// the given ID, e.g. to move an account between a self-hosted backend and archodex.com. The account must not have any
+/// resources yet. Records are written in transactions of up to 1000 records, and the records of a failed import are
+/// deleted again.
+///
+/// # Errors
+///
+/// Returns an error if the account doesn't exist or already has resources, if the archive is invalid, truncated, or of
+/// an unsupported version, or if the resources database fails.
+pub async fn import_file(account_id: &str, path: &Path) -> anyhow::Result<()> {
+    let import = async {
+        let account_id = resolve_account_id(account_id).await?;
+
+        let Some(account) = get_account(&account_id).await? else {
+            not_found!("Account not found");
+        };
+
+        let file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(err) => bad_request!("Failed to open {}: {err}", path.display()),
+        };
+
+        import_archive(
+            &account,
+            ArchiveSource::File {
+                file,
+                path,
+                buf: vec![0; FILE_READ_BYTES],
+            },
+        )
+        .await
+    };
+
+    let (header, counts) = import
+        .await
+        .map_err(|err| anyhow::anyhow!("Failed to import {}: {err}", path.display()))?;
+
+    info!(
+        source_account_id = header.account_id,
+        resources = counts.resources,
+        contains = counts.contains,
+        principal_chains = counts.principal_chains,
+        events = counts.events,
+        "Imported account data"
+    );
+
+    Ok(())
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ImportResponse {
+    // Account ID the archive was exported from
+    source_account_id: String,
+    counts: ExportCounts,
+}
+
+// Replays an archive downloaded from `GET /account/{account_id}/export` into the account, e.g. to move an account between
+// a self-hosted backend and archodex.com. The account must not have any resources yet. The archive is read as it is
+// uploaded and written in batched transactions; if the import fails, the records already written are deleted again. Only
+// the account owner may import data.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/import",
+    tag = "export",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body(
+        content = String,
+        content_type = "application/x-ndjson",
+        description = "A JSON or JSONL archive of `GET /account/{account_id}/export`"
+    ),
+    responses((status = 200, body = ImportResponse))
+)]
+#[instrument(err, skip(auth, account, request_id, body))]
+pub(crate) async fn import_export(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    body: Body,
+) -> Result<Json<ImportResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    let (header, counts) = import_archive(&account, ArchiveSource::Body(body)).await?;
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::AccountDataImported,
+        Some(json!({
+            "source_account_id": header.account_id,
+            "exported_at": header.exported_at,
+            "counts": counts,
+        })),
+    )
+    .await;
+
+    Ok(Json(ImportResponse {
+        source_account_id: header.account_id,
+        counts,
+    }))
+}
@@ -0,0 +1,169 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use crate::{
+    Result, account::Account, clock, db::QueryCheckFirstRealError as _, env::Env,
+    resource::ResourceId, revision, traced_query::TracedQuery, worker,
+};
+
+// A rule inferring edges of one kind from the events between a principal and a resource
+struct InferenceRule {
+    // Recorded on the edges the rule infers, as their provenance
+    name: &'static str,
+    kind: &'static str,
+    // SurrealQL expression selecting the events supporting the edge from `$events`, every event between the principal
+    // and the resource
+    evidence: &'static str,
+    // SurrealQL condition on the supporting events in `$evidence` under which the edge is inferred. The edge is only
+    // inferred when there are supporting events.
+    condition: &'static str,
+}
+
+const RULES: &[InferenceRule] = &[
+    // A principal that kept acting on a resource for at least a day depends on it, e.g. a service reading from a database
+    InferenceRule {
+        name: "repeated_access",
+        kind: "depends_on",
+        evidence: "$events",
+        condition: "time::max($evidence.last_seen_at) >= time::min($evidence.first_seen_at) + 1d",
+    },
+    // A principal that created, changed, or deleted a resource writes to it
+    InferenceRule {
+        name: "write_events",
+        kind: "writes_to",
+        evidence: "$events.filter(|$event| $event.type INSIDE ['created', 'updated', 'deleted', 'written', 'rotated'])",
+        condition: "true",
+    },
+];
+
+// An edge inferred from events, as returned by graph queries with the resources it starts from
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct DerivedEdge {
+    source: ResourceId,
+    kind: String,
+    target: ResourceId,
+    // Rule that inferred the edge
+    rule: String,
+    // Events the edge was inferred from
+    events: Vec<DerivedEdgeEvent>,
+    first_seen_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    inferred_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct DerivedEdgeEvent {
+    principal: ResourceId,
+    r#type: String,
+    resource: ResourceId,
+}
+
+// Re-evaluates the rules for every principal and resource with events seen since the last run, creating, updating, and
+// deleting their derived edges, and returns the number of events considered.
+//
+// Events are considered by their `last_seen_at`, so events reported after later ones were processed are only
+// considered once they are seen again.
+fn inference_query() -> String {
+    let rules = RULES
+        .iter()
+        .map(|rule| {
+            let InferenceRule {
+                name,
+                kind,
+                evidence,
+                condition,
+            } = rule;
+
+            format!(
+                "LET $evidence = {evidence};
+                IF !array::is_empty($evidence) AND {condition} {{
+                    INSERT RELATION INTO derived_edge {{
+                        in: $pair.in,
+                        out: $pair.out,
+                        kind: '{kind}',
+                        rule: '{name}',
+                        events: $evidence.map(|$event| $event.id),
+                        first_seen_at: time::min($evidence.first_seen_at),
+                        last_seen_at: time::max($evidence.last_seen_at),
+                        inferred_at: $now,
+                    }} ON DUPLICATE KEY UPDATE
+                        rule = $input.rule,
+                        events = $input.events,
+                        first_seen_at = $input.first_seen_at,
+                        last_seen_at = $input.last_seen_at,
+                        inferred_at = $input.inferred_at
+                    RETURN NONE;
+                }} ELSE {{
+                    DELETE derived_edge WHERE in = $pair.in AND out = $pair.out AND kind = '{kind}' RETURN NONE;
+                }};"
+            )
+        })
+        .collect::<String>();
+
+    format!(
+        "LET $since = inference:current.events_seen_until;
+        LET $touched = SELECT in, out, last_seen_at FROM event WHERE $since IS NONE OR last_seen_at > $since;
+
+        FOR $pair IN $touched.map(|$event| {{ in: $event.in, out: $event.out }}).distinct() {{
+            LET $events = SELECT id, type, first_seen_at, last_seen_at FROM event WHERE in = $pair.in AND out = $pair.out;
+            {rules}
+        }};
+
+        IF !array::is_empty($touched) {{
+            UPSERT inference:current SET events_seen_until = time::max($touched.last_seen_at) RETURN NONE;
+        }};
+
+        RETURN array::len($touched);"
+    )
+}
+
+/// Infers relationships between resources from their events every `ARCHODEX_INFERENCE_INTERVAL_SECONDS` until the
+/// process exits.
+///
+/// Each rule derives edges of one kind, e.g. a principal that kept acting on a resource for at least a day
+/// `depends_on` it. Edges are materialized in each account's `derived_edge` relation along with the rule and the events
+/// they were inferred from, and are returned by graph queries with the resources they start from. Each run only
+/// re-evaluates the principals and resources with events seen since the previous run, so edges are kept up to date
+/// incrementally.
+pub async fn run_worker() {
+    worker::run_periodically("inference", Env::inference_interval(), infer_accounts_edges).await;
+}
+
+#[instrument(err)]
+pub(crate) async fn infer_accounts_edges() -> Result<()> {
+    worker::for_each_account("inference", None, |account| async move {
+        infer_account_edges(&account).await
+    })
+    .await
+}
+
+// The edges are updated in one transaction. A run that conflicts with concurrent report ingestion of the same events is
+// retried on the next run.
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn infer_account_edges(account: &Account) -> Result<()> {
+    let db = account.resources_db().await?;
+
+    let mut res = TracedQuery::new(&db, "inference")
+        .query(BeginStatement::default())
+        .query(inference_query())
+        .query(CommitStatement::default())
+        .bind(("now", clock::now_value()))
+        .execute()
+        .await?
+        .check_first_real_error()?;
+
+    let events = res
+        .take::<Option<u64>>(res.num_statements() - 1)?
+        .unwrap_or_default();
+
+    if events > 0 {
+        revision::bump(&db).await?;
+
+        info!(events, "Inferred derived edges");
+    }
+
+    Ok(())
+}
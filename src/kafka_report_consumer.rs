@@ -0,0 +1,298 @@
+use std::{sync::Arc, time::Duration};
+
+use rskafka::{
+    client::{
+        Client, ClientBuilder, Credentials, SaslConfig,
+        partition::{OffsetAt, PartitionClient, UnknownTopicHandling},
+    },
+    record::RecordAndOffset,
+};
+use tracing::{Instrument as _, error, info, info_span, instrument, warn};
+
+use archodex_error::anyhow::{self, Context as _, anyhow, bail};
+
+use crate::{
+    auth::ReportApiKeyAuth,
+    db::{QueryCheckFirstRealError as _, account_for_report_api_key, primary_accounts_db},
+    env::{Env, KafkaReportConsumerConfig},
+    report, report_api_key_usage,
+};
+
+const FETCH_MAX_BYTES: i32 = 50 * 1024 * 1024;
+const FETCH_MAX_WAIT_MS: i32 = 1_000;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Consumes report payloads from the Kafka topic configured through the `ARCHODEX_KAFKA_REPORTS_*` environment
+/// variables and ingests them like reports submitted to `/report`.
+///
+/// Each record value must be a JSON report and each record must have an `authorization` header containing the report
+/// API key value. Records that fail authentication or schema validation are logged and skipped. Other failures (e.g. an
+/// unavailable database) are retried without advancing the partition offset, giving at-least-once ingestion.
+///
+/// Record batches may be compressed with any codec Kafka supports. When `ARCHODEX_KAFKA_REPORTS_TLS` is `true`, brokers
+/// are connected to over TLS and their certificates are verified against the Mozilla root certificates.
+///
+/// Offsets are committed per consumer group in the accounts database. All configured partitions are consumed by this
+/// process, so instances sharing a consumer group should be assigned disjoint partitions through
+/// `ARCHODEX_KAFKA_REPORTS_PARTITIONS`.
+///
+/// # Errors
+///
+/// Will return `Err` if the Kafka brokers cannot be reached or the topic does not exist.
+pub async fn run() -> anyhow::Result<()> {
+    let Some(config) = Env::kafka_report_consumer() else {
+        return Ok(());
+    };
+
+    let client = connect(config).await?;
+
+    let partitions = match &config.partitions {
+        Some(partitions) => partitions.clone(),
+        None => client
+            .list_topics()
+            .await
+            .context("Failed to list Kafka topics")?
+            .into_iter()
+            .find(|topic| topic.name == config.topic)
+            .ok_or_else(|| anyhow!("Kafka topic {:?} does not exist", config.topic))?
+            .partitions
+            .into_iter()
+            .collect(),
+    };
+
+    info!(
+        topic = config.topic,
+        consumer_group = config.consumer_group,
+        ?partitions,
+        "Starting Kafka report consumer"
+    );
+
+    let mut tasks = tokio::task::JoinSet::new();
+
+    for partition in partitions {
+        let partition_client = client
+            .partition_client(config.topic.clone(), partition, UnknownTopicHandling::Retry)
+            .await
+            .with_context(|| format!("Failed to create Kafka client for partition {partition}"))?;
+
+        tasks.spawn(
+            consume_partition(config, partition_client)
+                .instrument(info_span!("kafka_report_consumer", partition)),
+        );
+    }
+
+    tasks.join_all().await;
+
+    Ok(())
+}
+
+// TLS configuration for Kafka brokers with certificates issued by public certificate authorities
+pub(crate) fn tls_config() -> anyhow::Result<rustls::ClientConfig> {
+    Ok(rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .context("Failed to configure TLS for Kafka brokers")?
+    .with_root_certificates(rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    })
+    .with_no_client_auth())
+}
+
+async fn connect(config: &KafkaReportConsumerConfig) -> anyhow::Result<Client> {
+    let mut client_builder = ClientBuilder::new(config.brokers.clone());
+
+    if let Some((username, password)) = &config.sasl_plain_credentials {
+        client_builder = client_builder.sasl_config(SaslConfig::Plain(Credentials::new(
+            username.clone(),
+            password.clone(),
+        )));
+    }
+
+    if config.tls {
+        client_builder = client_builder.tls_config(Arc::new(tls_config()?));
+    }
+
+    client_builder
+        .build()
+        .await
+        .context("Failed to connect to Kafka brokers")
+}
+
+async fn consume_partition(config: &KafkaReportConsumerConfig, partition_client: PartitionClient) {
+    let mut offset = loop {
+        match starting_offset(config, &partition_client).await {
+            Ok(offset) => break offset,
+            Err(err) => {
+                warn!(?err, "Failed to determine starting offset, retrying");
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    };
+
+    info!(offset, "Consuming Kafka partition");
+
+    loop {
+        let records = match partition_client
+            .fetch_records(offset, 1..FETCH_MAX_BYTES, FETCH_MAX_WAIT_MS)
+            .await
+        {
+            Ok((records, _high_watermark)) => records,
+            Err(err) => {
+                warn!(?err, offset, "Failed to fetch Kafka records, retrying");
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        if records.is_empty() {
+            continue;
+        }
+
+        for record in records {
+            while let Err(err) = process_record(&record).await {
+                warn!(
+                    ?err,
+                    offset = record.offset,
+                    "Failed to ingest Kafka record, retrying"
+                );
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+
+            offset = record.offset + 1;
+        }
+
+        while let Err(err) = commit_offset(config, partition_client.partition(), offset).await {
+            warn!(?err, offset, "Failed to commit Kafka offset, retrying");
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+}
+
+fn offset_thing(config: &KafkaReportConsumerConfig, partition: i32) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from((
+        "kafka_consumer_offset",
+        surrealdb::sql::Id::Array(surrealdb::sql::Array::from(vec![
+            surrealdb::sql::Value::from(config.consumer_group.as_str()),
+            surrealdb::sql::Value::from(config.topic.as_str()),
+            surrealdb::sql::Value::from(partition),
+        ])),
+    ))
+}
+
+#[instrument(err, skip_all)]
+async fn starting_offset(
+    config: &KafkaReportConsumerConfig,
+    partition_client: &PartitionClient,
+) -> anyhow::Result<i64> {
+    let committed_offset = primary_accounts_db()
+        .await
+        .map_err(|err| anyhow!("{err}"))?
+        .query("SELECT VALUE offset FROM $offset")
+        .bind(("offset", offset_thing(config, partition_client.partition())))
+        .await?
+        .check_first_real_error()?
+        .take::<Option<i64>>(0)?;
+
+    let earliest_offset = partition_client
+        .get_offset(OffsetAt::Earliest)
+        .await
+        .context("Failed to get earliest Kafka offset")?;
+
+    // Records may have been removed by the topic's retention policy since the offset was committed.
+    Ok(committed_offset.map_or(earliest_offset, |offset| offset.max(earliest_offset)))
+}
+
+#[instrument(err, skip(config))]
+async fn commit_offset(
+    config: &KafkaReportConsumerConfig,
+    partition: i32,
+    offset: i64,
+) -> anyhow::Result<()> {
+    primary_accounts_db()
+        .await
+        .map_err(|err| anyhow!("{err}"))?
+        .query("UPSERT $offset SET offset = $value RETURN NONE")
+        .bind(("offset", offset_thing(config, partition)))
+        .bind(("value", offset))
+        .await?
+        .check_first_real_error()?;
+
+    Ok(())
+}
+
+// Returns `Err` only for failures that may succeed on retry. Invalid records are logged and skipped.
+#[instrument(err, skip_all, fields(offset = record.offset))]
+async fn process_record(record: &RecordAndOffset) -> anyhow::Result<()> {
+    let Some(report_api_key_value) = record
+        .record
+        .headers
+        .get("authorization")
+        .and_then(|value| std::str::from_utf8(value).ok())
+    else {
+        error!("Skipping Kafka record without a valid authorization header");
+        return Ok(());
+    };
+
+    let Some(value) = &record.record.value else {
+        error!("Skipping Kafka record without a value");
+        return Ok(());
+    };
+
+    let req = match report::Request::from_json(value) {
+        Ok(req) => req,
+        Err(err) => {
+            error!(?err, "Skipping Kafka record that is not a valid report");
+            return Ok(());
+        }
+    };
+
+    if let Err(err) = req.validate() {
+        error!(?err, "Skipping Kafka record with invalid report items");
+        return Ok(());
+    }
+
+    let auth = match ReportApiKeyAuth::from_value(report_api_key_value).await {
+        Ok(auth) => auth,
+        Err(err) if err.status_code().is_client_error() => {
+            error!("Skipping Kafka record with an invalid report API key");
+            return Ok(());
+        }
+        Err(err) => bail!("Failed to validate report API key of Kafka record: {err}"),
+    };
+
+    let account = match account_for_report_api_key(&auth).await {
+        Ok(account) => account,
+        Err(err) if err.status_code().is_client_error() => {
+            error!(%err, "Skipping Kafka record for an unknown account or revoked report API key");
+            return Ok(());
+        }
+        Err(err) => bail!("Failed to look up account for Kafka record: {err}"),
+    };
+
+    let res = report::ingest(&account, req).await;
+
+    // Usage is recorded like reports submitted to `/report`, but failing to record it doesn't hold up the partition
+    let (resources_upserted, error) = match &res {
+        Ok(result) => (result.resources_upserted(), None),
+        Err(err) => (0, Some(err.to_string())),
+    };
+
+    let recorded = async {
+        let db = account.resources_db().await?;
+
+        report_api_key_usage::record(&db, auth.key_id(), resources_upserted, value.len(), error)
+            .await
+    }
+    .await;
+
+    if let Err(err) = recorded {
+        warn!(
+            ?err,
+            "Failed to record report API key usage of Kafka record"
+        );
+    }
+
+    res.map(|_| ())
+        .map_err(|err| anyhow!("Failed to ingest Kafka record: {err}"))
+}
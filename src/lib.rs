@@ -1,21 +1,78 @@
 mod account;
+mod account_config;
+mod account_lock;
+mod account_member;
+mod account_promotion;
+mod account_transfer;
 mod accounts;
+mod admin;
+mod audit_log;
 mod auth;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod client_ip;
 mod db;
+mod debug_capture;
+mod enrichment;
 mod event;
+mod event_destination;
+#[cfg(feature = "archodex-com")]
+mod eventbridge;
+mod events;
+mod export;
 mod global_container;
+mod metrics;
+mod personal_access_token;
+mod personal_access_tokens;
 mod principal_chain;
+#[cfg(feature = "archodex-com")]
+mod provisioning;
 mod query;
+mod rate_limit;
+mod read_only;
 mod report;
 mod report_api_key;
+mod report_api_key_usage;
 mod report_api_keys;
 mod resource;
+mod resource_search;
+mod revision;
 mod surrealdb_deserializers;
+mod timeout;
+mod traced_query;
+mod transformation;
 mod user;
 mod value;
+mod version;
+mod worker;
 
+#[cfg(all(feature = "rocksdb", not(feature = "archodex-com")))]
+pub mod backup;
+pub mod bulk_operation;
+pub mod canary;
+pub mod clock;
 pub mod env;
+pub mod event_delivery;
+pub mod event_retention;
+pub mod external_ids;
+pub mod health;
+pub mod import;
+pub mod inference;
+#[cfg(feature = "kafka")]
+pub mod kafka_report_consumer;
+pub mod maintenance;
+pub mod openapi;
+pub mod reconciliation;
+pub mod report_check;
+pub mod report_job;
+pub mod resource_retention;
+pub mod resources_indexes;
+pub mod rng;
 pub mod router;
+pub mod scheduled_job;
+#[cfg(feature = "sqs")]
+pub mod sqs_report_consumer;
+pub mod storage_usage;
 
 use std::sync::atomic::AtomicU64;
 
@@ -0,0 +1,120 @@
+use std::time::Instant;
+
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{info, instrument, warn};
+
+use archodex_error::{anyhow::Context as _, conflict};
+
+use crate::{
+    Result,
+    account::{Account, list_live_accounts},
+    db::query_accounts_db_shards,
+    env::Env,
+    worker,
+};
+
+// Held while maintenance runs, so scheduled and admin triggered runs don't rebuild the same indexes concurrently
+static RUNNING: Mutex<()> = Mutex::const_new(());
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct MaintenanceReport {
+    accounts_db_indexes_rebuilt: usize,
+    accounts_maintained: usize,
+    resources_db_indexes_rebuilt: usize,
+    /// Accounts whose maintenance failed. Their errors are logged.
+    failed_account_ids: Vec<String>,
+}
+
+/// Runs database maintenance every `ARCHODEX_MAINTENANCE_INTERVAL_SECONDS` until the process exits. Scheduled
+/// maintenance is disabled when the variable is unset.
+///
+/// SurrealDB doesn't expose storage engine compaction, which RocksDB and SurrealKV run in the background as data is
+/// written. Maintenance instead rebuilds every index of the accounts database and of each account's resources database,
+/// dropping index entries left behind by past writes so that long-running installs don't scan ever larger indexes. The
+/// space the rebuilt indexes free is reclaimed by the engine's background compaction. Requests using a RocksDB database
+/// wait while its indexes are rebuilt, so maintenance should be scheduled for quiet periods.
+pub async fn run_worker() {
+    let Some(interval) = Env::maintenance_interval() else {
+        return;
+    };
+
+    // Maintenance is slow, so it doesn't run at startup
+    tokio::time::sleep(interval).await;
+
+    worker::run_periodically("maintenance", interval, || async {
+        run().await.map(|_| ())
+    })
+    .await;
+}
+
+// Runs maintenance now, failing with a conflict if it is already running
+#[instrument(err)]
+pub(crate) async fn run() -> Result<MaintenanceReport> {
+    let Ok(_running) = RUNNING.try_lock() else {
+        conflict!("Maintenance is already running");
+    };
+
+    let start = Instant::now();
+
+    info!("Starting maintenance");
+
+    let mut report = MaintenanceReport {
+        accounts_db_indexes_rebuilt: query_accounts_db_shards(|db| async move {
+            Ok([migrator::rebuild_indexes(&db)
+                .await
+                .context("Failed to rebuild accounts database indexes")?])
+        })
+        .await?
+        .into_iter()
+        .sum(),
+        ..Default::default()
+    };
+
+    let accounts = list_live_accounts().await?;
+
+    for (maintained, account) in accounts.iter().enumerate() {
+        #[cfg(feature = "archodex-com")]
+        if account.service_data_surrealdb_url().is_none() {
+            continue;
+        }
+
+        info!(
+            account_id = account.id(),
+            progress = format!("{}/{}", maintained + 1, accounts.len()),
+            "Maintaining account resources database..."
+        );
+
+        match maintain_account(account).await {
+            Ok(indexes_rebuilt) => {
+                report.accounts_maintained += 1;
+                report.resources_db_indexes_rebuilt += indexes_rebuilt;
+            }
+            Err(err) => {
+                warn!(
+                    account_id = account.id(),
+                    ?err,
+                    "Failed to maintain account resources database"
+                );
+                report.failed_account_ids.push(account.id().to_owned());
+            }
+        }
+    }
+
+    info!(
+        ?report,
+        duration_ms = start.elapsed().as_millis(),
+        "Completed maintenance"
+    );
+
+    Ok(report)
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn maintain_account(account: &Account) -> Result<usize> {
+    let db = account.resources_db().await?;
+
+    Ok(migrator::rebuild_indexes(&db)
+        .await
+        .context("Failed to rebuild resources database indexes")?)
+}
@@ -0,0 +1,173 @@
+use std::{future::IntoFuture, sync::LazyLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::header::CONTENT_TYPE,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder as _, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use archodex_error::anyhow::Context as _;
+
+use crate::Result;
+
+struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    report_resources_ingested: IntCounter,
+    report_events_ingested: IntCounter,
+    surrealdb_query_duration: HistogramVec,
+    http_panics: IntCounter,
+    accounts_db_drift: IntGaugeVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new_custom(Some("archodex".to_string()), None)
+        .expect("Metrics registry prefix should be valid");
+
+    let http_requests = IntCounterVec::new(
+        Opts::new("http_requests_total", "HTTP requests by route and status"),
+        &["method", "route", "status"],
+    )
+    .expect("HTTP requests metric should be valid");
+
+    let http_request_duration = HistogramVec::new(
+        HistogramOpts::new(
+            "http_request_duration_seconds",
+            "HTTP request latencies by route",
+        ),
+        &["method", "route"],
+    )
+    .expect("HTTP request duration metric should be valid");
+
+    let report_resources_ingested = IntCounter::new(
+        "report_resources_ingested_total",
+        "Resources ingested from reports",
+    )
+    .expect("Report resources metric should be valid");
+
+    let report_events_ingested = IntCounter::new(
+        "report_events_ingested_total",
+        "Events ingested from reports",
+    )
+    .expect("Report events metric should be valid");
+
+    let surrealdb_query_duration = HistogramVec::new(
+        HistogramOpts::new(
+            "surrealdb_query_duration_seconds",
+            "SurrealDB query latencies by query",
+        ),
+        &["query"],
+    )
+    .expect("SurrealDB query duration metric should be valid");
+
+    let http_panics = IntCounter::new("http_panics_total", "Request handler panics")
+        .expect("HTTP panics metric should be valid");
+
+    let accounts_db_drift = IntGaugeVec::new(
+        Opts::new(
+            "accounts_db_drift",
+            "Findings of the last accounts database reconciliation by category",
+        ),
+        &["category"],
+    )
+    .expect("Accounts database drift metric should be valid");
+
+    for collector in [
+        Box::new(http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+        Box::new(http_request_duration.clone()),
+        Box::new(report_resources_ingested.clone()),
+        Box::new(report_events_ingested.clone()),
+        Box::new(surrealdb_query_duration.clone()),
+        Box::new(http_panics.clone()),
+        Box::new(accounts_db_drift.clone()),
+    ] {
+        registry
+            .register(collector)
+            .expect("Metrics should only be registered once");
+    }
+
+    Metrics {
+        registry,
+        http_requests,
+        http_request_duration,
+        report_resources_ingested,
+        report_events_ingested,
+        surrealdb_query_duration,
+        http_panics,
+        accounts_db_drift,
+    }
+});
+
+// Records the count and latency of requests to a route. This must be added with `route_layer` so the matched route is
+// known, which keeps the `route` label's cardinality bounded by the number of routes rather than by request URIs.
+pub(crate) async fn track(req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unknown".to_string(), |path| path.as_str().to_string());
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+
+    let metrics = &*METRICS;
+    metrics
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    metrics
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+pub(crate) fn record_report_ingested(resources: usize, events: usize) {
+    METRICS.report_resources_ingested.inc_by(resources as u64);
+    METRICS.report_events_ingested.inc_by(events as u64);
+}
+
+pub(crate) fn record_panic() {
+    METRICS.http_panics.inc();
+}
+
+pub(crate) fn record_accounts_db_drift(drift_counts: &[(&'static str, usize)]) {
+    for &(category, count) in drift_counts {
+        METRICS
+            .accounts_db_drift
+            .with_label_values(&[category])
+            .set(i64::try_from(count).unwrap_or(i64::MAX));
+    }
+}
+
+// Runs a SurrealDB query, recording its latency under the given query name
+pub(crate) async fn time_query<Q: IntoFuture>(query_name: &'static str, query: Q) -> Q::Output {
+    let start = Instant::now();
+    let output = query.await;
+
+    METRICS
+        .surrealdb_query_duration
+        .with_label_values(&[query_name])
+        .observe(start.elapsed().as_secs_f64());
+
+    output
+}
+
+// Serves all metrics in the Prometheus text exposition format
+pub(crate) async fn metrics() -> Result<Response> {
+    let encoder = TextEncoder::new();
+
+    let mut body = vec![];
+    encoder
+        .encode(&METRICS.registry.gather(), &mut body)
+        .context("Failed to encode metrics")?;
+
+    Ok(([(CONTENT_TYPE, encoder.format_type().to_string())], body).into_response())
+}
@@ -0,0 +1,198 @@
+use axum::Json;
+use serde::Serialize;
+use utoipa::{
+    Modify, OpenApi, ToSchema,
+    openapi::{
+        self, RefOr, ResponseBuilder,
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    },
+};
+
+use crate::{
+    account_config, account_lock, account_member, account_promotion, account_transfer, accounts,
+    audit_log, bulk_operation, debug_capture, enrichment, event_destination, event_retention,
+    events, export, import, personal_access_tokens, principal_chain, query, report,
+    report_api_keys, report_job, resource, resource_retention, resource_search, storage_usage,
+    transformation, version,
+};
+
+// Body of every error response, as serialized by `archodex_error::PublicError`
+#[derive(Serialize, ToSchema)]
+struct ErrorResponse {
+    message: String,
+    // Set for errors clients can react to, e.g. `record_exists`, `unique_index_violation`, `query_timeout`,
+    // `transaction_conflict`, or `database_unavailable`
+    code: Option<String>,
+}
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "dashboard",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .description(Some(
+                        "OIDC access token. Query and resource environment routes also accept personal access tokens.",
+                    ))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "report_api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("Authorization"))),
+        );
+    }
+}
+
+// Documents the error responses every route may return, rather than repeating them on each handler
+struct ErrorResponses;
+
+impl Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut openapi::OpenApi) {
+        let error_response = |description: &str| {
+            RefOr::T(
+                ResponseBuilder::new()
+                    .description(description)
+                    .content(
+                        "application/json",
+                        openapi::ContentBuilder::new()
+                            .schema(Some(RefOr::Ref(openapi::Ref::from_schema_name(
+                                "ErrorResponse",
+                            ))))
+                            .build(),
+                    )
+                    .build(),
+            )
+        };
+
+        for path_item in openapi.paths.paths.values_mut() {
+            for operation in [
+                &mut path_item.get,
+                &mut path_item.put,
+                &mut path_item.post,
+                &mut path_item.delete,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                let responses = &mut operation.responses.responses;
+                responses
+                    .entry("4XX".to_string())
+                    .or_insert_with(|| error_response("Client error"));
+                responses
+                    .entry("5XX".to_string())
+                    .or_insert_with(|| error_response("Server error"));
+            }
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Archodex API"),
+    paths(
+        accounts::list_accounts,
+        accounts::create_account,
+        accounts::delete_account,
+        bulk_operation::create_bulk_operation,
+        bulk_operation::get_bulk_operation,
+        audit_log::list_audit_log,
+        storage_usage::get_storage_usage,
+        account_config::apply_config,
+        account_promotion::promote_config,
+        account_lock::get_account_lock,
+        account_lock::lock_account,
+        account_lock::unlock_account,
+        account_member::list_account_members,
+        account_member::invite_account_member,
+        account_member::remove_account_member,
+        account_member::list_account_invitations,
+        account_member::accept_account_invitation,
+        account_transfer::initiate_account_transfer,
+        account_transfer::cancel_account_transfer,
+        account_transfer::list_incoming_account_transfers,
+        account_transfer::accept_account_transfer,
+        resource::set_environments,
+        resource::bulk_set_environments,
+        resource::delete_resource,
+        resource_retention::prune_resources,
+        resource_search::search_resources,
+        query::query,
+        events::list_events,
+        principal_chain::get,
+        report_api_keys::list_report_api_keys,
+        report_api_keys::create_report_api_key,
+        report_api_keys::get_report_api_key,
+        report_api_keys::update_report_api_key,
+        report_api_keys::revoke_report_api_key,
+        report_api_keys::freeze_report_api_keys,
+        report_api_keys::unfreeze_report_api_keys,
+        report_api_keys::get_report_api_key_usage,
+        personal_access_tokens::list_personal_access_tokens,
+        personal_access_tokens::create_personal_access_token,
+        personal_access_tokens::revoke_personal_access_token,
+        event_destination::list_event_destinations,
+        event_destination::create_event_destination,
+        event_destination::delete_event_destination,
+        event_destination::list_dead_lettered_event_deliveries,
+        event_destination::redrive_event_deliveries,
+        event_destination::verify_event_destination,
+        event_destination::list_event_delivery_attempts,
+        event_destination::redeliver_event,
+        debug_capture::enable_debug_capture,
+        debug_capture::get_debug_capture_status,
+        debug_capture::disable_debug_capture,
+        debug_capture::download_debug_capture_bundle,
+        export::download_export,
+        export::upload_export,
+        import::import_export,
+        enrichment::get_enrichers,
+        enrichment::set_enrichers,
+        transformation::get_transformations,
+        transformation::set_transformations,
+        event_retention::get_event_retention,
+        event_retention::set_event_retention,
+        resource_retention::get_resource_retention,
+        resource_retention::set_resource_retention,
+        report::report,
+        report::report_stream,
+        report_job::get_report_job,
+        version::version,
+    ),
+    components(schemas(ErrorResponse, query::QueryType)),
+    modifiers(&SecuritySchemes, &ErrorResponses),
+    tags(
+        (name = "accounts", description = "Accounts and their configuration"),
+        (name = "account_members", description = "Users with access to an account and invitations to accounts"),
+        (name = "account_transfers", description = "Transfers of account ownership between users"),
+        (name = "resources", description = "Resources reported into an account"),
+        (name = "query", description = "Resource and event graph queries"),
+        (name = "events", description = "Events between principals and resources"),
+        (name = "principal_chains", description = "Chains of principals that led to events"),
+        (name = "report_api_keys", description = "API keys used by agents to report into an account"),
+        (name = "personal_access_tokens", description = "Tokens CI jobs and scripts use to query and tag an account's resources"),
+        (name = "event_destinations", description = "Destinations new events are delivered to"),
+        (name = "debug_capture", description = "Capture of an account's requests for debugging"),
+        (name = "export", description = "Exports and imports of an account's resources, principal chains, and events"),
+        (name = "report", description = "Reports of resources and events from agents"),
+        (name = "version", description = "Version and build of the backend"),
+    )
+)]
+struct ApiDoc;
+
+/// The OpenAPI specification of the dashboard and report APIs. The admin API is intentionally left out.
+#[must_use]
+pub fn spec() -> openapi::OpenApi {
+    ApiDoc::openapi()
+}
+
+// Serves the OpenAPI specification
+pub(crate) async fn openapi() -> Json<openapi::OpenApi> {
+    Json(spec())
+}
@@ -0,0 +1,317 @@
+use base64::prelude::*;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use surrealdb::Uuid;
+use tracing::{instrument, warn};
+use utoipa::ToSchema;
+
+use archodex_error::{anyhow::Context as _, unauthorized};
+
+use crate::{
+    Result,
+    account::Account,
+    clock,
+    db::{QueryCheckFirstRealError as _, accounts_db_for_account},
+    next_binding, read_only, rng, surrealdb_deserializers,
+    user::User,
+};
+
+// Token values are `archodex_pat_<account ID>_<token ID>_<secret>`. The account ID locates the accounts database shard
+// holding the token without searching every shard.
+const PERSONAL_ACCESS_TOKEN_PREFIX: &str = "archodex_pat_";
+
+const SECRET_BYTES: usize = 32;
+
+// Tokens may be used for every request of a CI job, so their last use is only written when it's older than this
+const LAST_USED_UPDATE_INTERVAL: TimeDelta = TimeDelta::minutes(1);
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PersonalAccessToken {
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    id: Uuid,
+    #[serde(deserialize_with = "surrealdb_deserializers::string::deserialize")]
+    account: String,
+    description: Option<String>,
+    #[serde(deserialize_with = "surrealdb_deserializers::bytes::deserialize")]
+    token_hash: Vec<u8>,
+    created_at: Option<DateTime<Utc>>,
+    created_by: User,
+    revoked_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct PersonalAccessTokenPublic {
+    id: Uuid,
+    description: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+    /// The user the token acts as. The token stops working if the user loses access to the account.
+    created_by_user_id: Uuid,
+    /// When the token was last used to authenticate, accurate to within a minute. Unset if the token was never used.
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<PersonalAccessToken> for PersonalAccessTokenPublic {
+    fn from(record: PersonalAccessToken) -> Self {
+        Self {
+            id: record.id,
+            description: record.description,
+            created_at: record.created_at,
+            created_by_user_id: record.created_by.id(),
+            last_used_at: record.last_used_at,
+        }
+    }
+}
+
+// The personal access token a dashboard request was authenticated with. Requests authenticated with a token may only
+// access the account the token was created in.
+#[derive(Clone, Debug)]
+pub(crate) struct PersonalAccessTokenAuth {
+    id: Uuid,
+    account_id: String,
+}
+
+impl PersonalAccessTokenAuth {
+    pub(crate) fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub(crate) fn account_id(&self) -> &str {
+        &self.account_id
+    }
+}
+
+fn personal_access_token_thing(id: Uuid) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from((
+        "personal_access_token",
+        surrealdb::sql::Id::Uuid(surrealdb::sql::Uuid::from(id)),
+    ))
+}
+
+fn hash_secret(secret: &str) -> Vec<u8> {
+    Sha256::digest(secret.as_bytes()).to_vec()
+}
+
+pub(crate) trait PersonalAccessTokenQueries<'r, C: surrealdb::Connection> {
+    fn create_personal_access_token_query(
+        &'r self,
+        id: Uuid,
+        account: &Account,
+        description: Option<String>,
+        token_hash: Vec<u8>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn list_personal_access_tokens_query(
+        &'r self,
+        account: &Account,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn revoke_personal_access_token_query(
+        &'r self,
+        id: Uuid,
+        account: &Account,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn use_personal_access_token_query(
+        &'r self,
+        id: Uuid,
+        token_hash: Vec<u8>,
+    ) -> surrealdb::method::Query<'r, C>;
+}
+
+impl<'r, C: surrealdb::Connection> PersonalAccessTokenQueries<'r, C> for surrealdb::Surreal<C> {
+    fn create_personal_access_token_query(
+        &'r self,
+        id: Uuid,
+        account: &Account,
+        description: Option<String>,
+        token_hash: Vec<u8>,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let token_binding = next_binding();
+        let account_binding = next_binding();
+        let description_binding = next_binding();
+        let token_hash_binding = next_binding();
+        let principal_binding = next_binding();
+
+        self.query(format!(
+            "CREATE ${token_binding} CONTENT {{ account: ${account_binding}, description: ${description_binding}, token_hash: ${token_hash_binding}, created_by: ${principal_binding} }}"
+        ))
+        .bind((token_binding, personal_access_token_thing(id)))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((description_binding, description))
+        .bind((
+            token_hash_binding,
+            surrealdb::sql::Bytes::from(token_hash),
+        ))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+    }
+
+    fn list_personal_access_tokens_query(
+        &'r self,
+        account: &Account,
+    ) -> surrealdb::method::Query<'r, C> {
+        let account_binding = next_binding();
+
+        self.query(format!(
+            "SELECT * FROM personal_access_token WHERE account = ${account_binding} AND revoked_at IS NONE"
+        ))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+    }
+
+    fn revoke_personal_access_token_query(
+        &'r self,
+        id: Uuid,
+        account: &Account,
+        principal: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let token_binding = next_binding();
+        let account_binding = next_binding();
+        let principal_binding = next_binding();
+        let now_binding = next_binding();
+
+        self.query(format!(
+            "UPDATE ${token_binding} SET revoked_at = ${now_binding}, revoked_by = ${principal_binding} WHERE account = ${account_binding} AND revoked_at IS NONE"
+        ))
+        .bind((token_binding, personal_access_token_thing(id)))
+        .bind((account_binding, surrealdb::sql::Thing::from(account)))
+        .bind((principal_binding, surrealdb::sql::Thing::from(principal)))
+        .bind((now_binding, clock::now_value()))
+    }
+
+    // Fetches a token and records its use if the hash matches and it hasn't been revoked. Use isn't recorded in
+    // read-only mode, as authenticating must not write then. The first statement returns the token.
+    fn use_personal_access_token_query(
+        &'r self,
+        id: Uuid,
+        token_hash: Vec<u8>,
+    ) -> surrealdb::method::Query<'r, C> {
+        let token_binding = next_binding();
+        let token_hash_binding = next_binding();
+        let now_binding = next_binding();
+        let stale_before_binding = next_binding();
+
+        let now = clock::now();
+
+        let mut query = self.query(format!("SELECT * FROM ONLY ${token_binding}"));
+
+        if !read_only::is_enabled() {
+            query = query.query(format!("UPDATE ${token_binding} SET last_used_at = ${now_binding} WHERE token_hash = ${token_hash_binding} AND revoked_at IS NONE AND (last_used_at IS NONE OR last_used_at < ${stale_before_binding}) RETURN NONE"));
+        }
+
+        query
+            .bind((token_binding, personal_access_token_thing(id)))
+            .bind((token_hash_binding, surrealdb::sql::Bytes::from(token_hash)))
+            .bind((now_binding, surrealdb::sql::Datetime::from(now)))
+            .bind((
+                stale_before_binding,
+                surrealdb::sql::Datetime::from(now - LAST_USED_UPDATE_INTERVAL),
+            ))
+    }
+}
+
+impl PersonalAccessToken {
+    pub(crate) fn id(&self) -> Uuid {
+        self.id
+    }
+
+    // Creates a new token in the account's accounts database shard and generates its value. Only a hash of the value is
+    // stored, so the value can't be retrieved again.
+    #[instrument(err, skip(account, principal))]
+    pub(crate) async fn create(
+        account: &Account,
+        description: Option<String>,
+        principal: &User,
+    ) -> Result<(Self, String)> {
+        let id = Uuid::now_v7();
+
+        let mut secret_bytes = [0; SECRET_BYTES];
+        rng::with_rng(|rng| rng.fill_bytes(&mut secret_bytes));
+        let secret = BASE64_URL_SAFE_NO_PAD.encode(secret_bytes);
+
+        let value = format!(
+            "{PERSONAL_ACCESS_TOKEN_PREFIX}{}_{}_{secret}",
+            account.id(),
+            id.simple()
+        );
+
+        let personal_access_token = accounts_db_for_account(account.id())
+            .await?
+            .create_personal_access_token_query(
+                id,
+                account,
+                description,
+                hash_secret(&secret),
+                principal,
+            )
+            .await?
+            .check_first_real_error()?
+            .take::<Option<PersonalAccessToken>>(0)?
+            .context("Create personal access token query should return a personal access token")?;
+
+        Ok((personal_access_token, value))
+    }
+
+    pub(crate) fn is_value(value: &str) -> bool {
+        value.starts_with(PERSONAL_ACCESS_TOKEN_PREFIX)
+    }
+
+    // Validates a token value, returning the user the token acts as. The caller must still validate the user has access
+    // to the token's account.
+    #[instrument(err, skip_all)]
+    pub(crate) async fn authenticate(value: &str) -> Result<(User, PersonalAccessTokenAuth)> {
+        let Some((account_id, id, secret)) = value
+            .strip_prefix(PERSONAL_ACCESS_TOKEN_PREFIX)
+            .and_then(|rest| {
+                let mut parts = rest.splitn(3, '_');
+                Some((parts.next()?, parts.next()?, parts.next()?))
+            })
+        else {
+            warn!("Invalid personal access token format");
+            unauthorized!();
+        };
+
+        if account_id.parse::<u64>().is_err() {
+            warn!("Invalid personal access token account ID");
+            unauthorized!();
+        }
+
+        let Ok(id) = Uuid::parse_str(id) else {
+            warn!("Invalid personal access token ID");
+            unauthorized!();
+        };
+
+        let token_hash = hash_secret(secret);
+
+        let Some(personal_access_token) = accounts_db_for_account(account_id)
+            .await?
+            .use_personal_access_token_query(id, token_hash.clone())
+            .await?
+            .check_first_real_error()?
+            .take::<Option<PersonalAccessToken>>(0)?
+        else {
+            warn!(%id, "Personal access token does not exist");
+            unauthorized!();
+        };
+
+        if personal_access_token.account != account_id
+            || personal_access_token.token_hash != token_hash
+        {
+            warn!(%id, "Personal access token does not match");
+            unauthorized!();
+        }
+
+        if personal_access_token.revoked_at.is_some() {
+            warn!(%id, "Personal access token was revoked");
+            unauthorized!();
+        }
+
+        Ok((
+            personal_access_token.created_by,
+            PersonalAccessTokenAuth {
+                id,
+                account_id: personal_access_token.account,
+            },
+        ))
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use axum::{Extension, Json, extract::Path};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use surrealdb::Uuid;
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use archodex_error::{anyhow::bail, bad_request, not_found};
+
+use crate::{
+    Result,
+    account::Account,
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError, accounts_db_for_account},
+    personal_access_token::{
+        PersonalAccessToken, PersonalAccessTokenPublic, PersonalAccessTokenQueries,
+    },
+    router::RequestId,
+};
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ListPersonalAccessTokensResponse {
+    personal_access_tokens: Vec<PersonalAccessTokenPublic>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/personal_access_tokens",
+    tag = "personal_access_tokens",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = ListPersonalAccessTokensResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn list_personal_access_tokens(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ListPersonalAccessTokensResponse>> {
+    let personal_access_tokens = accounts_db_for_account(account.id())
+        .await?
+        .list_personal_access_tokens_query(&account)
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<PersonalAccessToken>>(0)?
+        .into_iter()
+        .map(PersonalAccessTokenPublic::from)
+        .collect();
+
+    Ok(Json(ListPersonalAccessTokensResponse {
+        personal_access_tokens,
+    }))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CreatePersonalAccessTokenRequest {
+    description: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CreatePersonalAccessTokenResponse {
+    personal_access_token: PersonalAccessTokenPublic,
+    /// Bearer token for the account's query and resource environment routes. It is only returned when the token is
+    /// created.
+    personal_access_token_value: String,
+}
+
+// Creates a token CI jobs and scripts can use in place of a dashboard session. The token acts as the user who created
+// it, but only within this account.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/personal_access_tokens",
+    tag = "personal_access_tokens",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = CreatePersonalAccessTokenRequest,
+    responses((status = 200, body = CreatePersonalAccessTokenResponse))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn create_personal_access_token(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<CreatePersonalAccessTokenRequest>,
+) -> Result<Json<CreatePersonalAccessTokenResponse>> {
+    let principal = auth.principal();
+
+    principal.ensure_user_record_exists(account.id()).await?;
+
+    let (personal_access_token, personal_access_token_value) =
+        PersonalAccessToken::create(&account, req.description, principal).await?;
+
+    info!(
+        account_id = account.id(),
+        personal_access_token_id = %personal_access_token.id(),
+        created_by_user_id = %principal.id(),
+        "Created personal access token"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::PersonalAccessTokenCreated,
+        Some(json!({ "personal_access_token_id": personal_access_token.id() })),
+    )
+    .await;
+
+    Ok(Json(CreatePersonalAccessTokenResponse {
+        personal_access_token: PersonalAccessTokenPublic::from(personal_access_token),
+        personal_access_token_value,
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}/personal_access_token/{personal_access_token_id}",
+    tag = "personal_access_tokens",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("personal_access_token_id" = String, Path)),
+    responses((status = 200))
+)]
+#[instrument(err, skip(auth, account))]
+pub(crate) async fn revoke_personal_access_token(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<()>> {
+    let Some(personal_access_token_id) = params.get("personal_access_token_id") else {
+        bail!("Missing personal_access_token_id");
+    };
+
+    let Ok(personal_access_token_id) = Uuid::parse_str(personal_access_token_id) else {
+        bad_request!("Invalid personal access token ID");
+    };
+
+    let personal_access_token = accounts_db_for_account(account.id())
+        .await?
+        .revoke_personal_access_token_query(personal_access_token_id, &account, auth.principal())
+        .await?
+        .check_first_real_error()?
+        .take::<Option<PersonalAccessToken>>(0)?;
+
+    if personal_access_token.is_none() {
+        not_found!("Personal access token not found");
+    }
+
+    info!(
+        account_id = account.id(),
+        %personal_access_token_id,
+        revoked_by_user_id = %auth.principal().id(),
+        "Revoked personal access token"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::PersonalAccessTokenRevoked,
+        Some(json!({ "personal_access_token_id": personal_access_token_id })),
+    )
+    .await;
+
+    Ok(Json(()))
+}
@@ -6,10 +6,11 @@ use serde::{Deserialize, Serialize};
 
 use archodex_error::{anyhow, bad_request, bail, ensure, not_found};
 use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
 
 use crate::{account::Account, db::QueryCheckFirstRealError, resource::ResourceId};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub(crate) struct PrincipalChainIdPart {
     pub(crate) id: ResourceId,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -61,7 +62,7 @@ impl TryFrom<surrealdb::sql::Object> for PrincipalChainIdPart {
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, ToSchema)]
 pub(crate) struct PrincipalChainId(Vec<PrincipalChainIdPart>);
 
 impl std::ops::Deref for PrincipalChainId {
@@ -188,18 +189,28 @@ impl<'de> Deserialize<'de> for PrincipalChainId {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 #[serde(deny_unknown_fields)]
 pub(super) struct GetRequest {
     id: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub(super) struct GetResponse {
     first_seen_at: DateTime<Utc>,
     last_seen_at: DateTime<Utc>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/principal_chain",
+    operation_id = "get_principal_chain",
+    tag = "principal_chains",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), GetRequest),
+    responses((status = 200, body = GetResponse))
+)]
 #[instrument(err, skip(account))]
 pub(super) async fn get(
     Extension(account): Extension<Account>,
@@ -213,7 +224,8 @@ pub(super) async fn get(
     let res = account
         .resources_db()
         .await?
-        .query("SELECT first_seen_at, last_seen_at FROM type::thing('principal_chain', $id)")
+        // The chain may be stored with either the chain itself or its hash as its ID
+        .query("SELECT first_seen_at, last_seen_at FROM type::thing('principal_chain', $id), type::thing('principal_chain', fn::principal_chain_hash($id))")
         .bind(("id", surrealdb::sql::Array::from(id)))
         .await?
         .check_first_real_error()?
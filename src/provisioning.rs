@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    Result,
+    account::get_accounts,
+    clock,
+    db::{QueryCheckFirstRealError, primary_accounts_db},
+};
+
+// Reservations younger than this may belong to account creations that are still in progress, so their service
+// databases are never swept
+const ORPHAN_GRACE_PERIOD: Duration = Duration::hours(1);
+
+// Records the service database provisioned for a reserved account ID before the account record exists, so the database
+// can be found and deleted if the account record is never created.
+#[instrument(err)]
+pub(crate) async fn record_service_database(
+    account_id: &str,
+    service_data_surrealdb_url: &str,
+) -> Result<()> {
+    primary_accounts_db()
+        .await?
+        .query("UPDATE type::thing('account_id_reservation', $account_id) SET service_data_surrealdb_url = $service_data_surrealdb_url RETURN NONE")
+        .bind(("account_id", account_id.to_string()))
+        .bind((
+            "service_data_surrealdb_url",
+            service_data_surrealdb_url.to_string(),
+        ))
+        .await?
+        .check_first_real_error()?;
+
+    Ok(())
+}
+
+// Deletes the service database provisioned for a reserved account ID and marks it deleted on the reservation
+#[instrument(err)]
+async fn delete_service_database(account_id: &str, service_data_surrealdb_url: &str) -> Result<()> {
+    archodex_com::delete_account_service_database(service_data_surrealdb_url, account_id).await?;
+
+    primary_accounts_db()
+        .await?
+        .query("UPDATE type::thing('account_id_reservation', $account_id) SET service_data_deleted_at = $now RETURN NONE")
+        .bind(("account_id", account_id.to_string()))
+        .bind(("now", clock::now_value()))
+        .await?
+        .check_first_real_error()?;
+
+    Ok(())
+}
+
+// Compensates for a failed account creation by deleting the service database provisioned for it. Failures are logged
+// rather than returned so the original error is reported to the caller; the orphan sweeper retries the deletion later.
+pub(crate) async fn discard_service_database(account_id: &str, service_data_surrealdb_url: &str) {
+    match delete_service_database(account_id, service_data_surrealdb_url).await {
+        Ok(()) => info!(
+            account_id,
+            "Deleted service database of account that failed to be created"
+        ),
+        Err(err) => error!(
+            account_id,
+            ?err,
+            "Failed to delete service database of account that failed to be created, it will be deleted by the orphan sweeper"
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct OrphanedServiceDatabase {
+    pub(crate) account_id: String,
+    service_data_surrealdb_url: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SweepOrphanedServiceDatabasesResponse {
+    deleted_account_ids: Vec<String>,
+    pub(crate) failed_account_ids: Vec<String>,
+}
+
+// Finds service databases that were provisioned for reserved account IDs whose account records were never created, e.g.
+// because the backend crashed mid-creation or compensation failed
+#[instrument(err)]
+pub(crate) async fn orphaned_service_databases() -> Result<Vec<OrphanedServiceDatabase>> {
+    let candidates = primary_accounts_db()
+        .await?
+        .query(
+            "SELECT record::id(id) AS account_id, service_data_surrealdb_url FROM account_id_reservation
+                WHERE service_data_surrealdb_url IS NOT NONE
+                    AND service_data_deleted_at IS NONE
+                    AND reserved_at < $cutoff",
+        )
+        .bind((
+            "cutoff",
+            surrealdb::sql::Datetime::from(clock::now() - ORPHAN_GRACE_PERIOD),
+        ))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<OrphanedServiceDatabase>>(0)?;
+
+    // Reservations are held by the primary shard, but account records by the shards for their IDs
+    let account_ids = get_accounts(
+        candidates
+            .iter()
+            .map(|candidate| candidate.account_id.clone())
+            .collect(),
+    )
+    .await?
+    .into_iter()
+    .map(|account| account.id().to_string())
+    .collect::<HashSet<_>>();
+
+    Ok(candidates
+        .into_iter()
+        .filter(|candidate| !account_ids.contains(&candidate.account_id))
+        .collect())
+}
+
+// Deletes the service databases found by `orphaned_service_databases()`
+#[instrument(err)]
+pub(crate) async fn sweep_orphaned_service_databases()
+-> Result<SweepOrphanedServiceDatabasesResponse> {
+    let orphans = orphaned_service_databases().await?;
+
+    let mut response = SweepOrphanedServiceDatabasesResponse {
+        deleted_account_ids: vec![],
+        failed_account_ids: vec![],
+    };
+
+    for orphan in orphans {
+        match delete_service_database(&orphan.account_id, &orphan.service_data_surrealdb_url).await
+        {
+            Ok(()) => {
+                info!(
+                    account_id = orphan.account_id,
+                    "Deleted orphaned service database"
+                );
+                response.deleted_account_ids.push(orphan.account_id);
+            }
+            Err(err) => {
+                warn!(
+                    account_id = orphan.account_id,
+                    ?err,
+                    "Failed to delete orphaned service database"
+                );
+                response.failed_account_ids.push(orphan.account_id);
+            }
+        }
+    }
+
+    Ok(response)
+}
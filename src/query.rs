@@ -1,6 +1,16 @@
-use axum::{Extension, Json, extract::Path};
+use axum::{
+    Extension, Json,
+    extract::{Path, Query},
+    http::{HeaderMap, StatusCode, header::ETAG},
+    response::{IntoResponse as _, Response},
+};
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
+
+use archodex_error::{anyhow::Context as _, bad_request};
 
 use crate::{
     Result,
@@ -8,35 +18,107 @@ use crate::{
     db::{BeginReadonlyStatement, QueryCheckFirstRealError},
     event::Event,
     global_container::GlobalContainer,
-    resource::Resource,
+    inference::DerivedEdge,
+    resource::{Resource, ResourceId, surrealdb_thing_from_resource_id},
+    revision,
+    traced_query::TracedQuery,
 };
 
-#[derive(Debug, Deserialize, Eq, PartialEq)]
+const MAX_PAGE_LIMIT: u32 = 10_000;
+
+#[derive(Debug, Deserialize, Eq, PartialEq, ToSchema)]
 #[serde(rename_all = "snake_case")]
 pub(super) enum QueryType {
     All,
     Secrets,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Pagination and time range parameters. Without a `limit` every matching resource and event is returned in one
+// response.
+//
+// Pages contain up to `limit` resources ordered by resource ID, the events whose principal is one of those resources,
+// and the global containers of both. `cursor` is the `next_cursor` value from the previous page.
+//
+// `since` and `until` restrict events to those seen during the time range, i.e. last seen at or after `since` and first
+// seen at or before `until`. Resources are not filtered by the time range.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(deny_unknown_fields)]
+pub(super) struct QueryParams {
+    limit: Option<u32>,
+    cursor: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub(super) struct QueryResponse {
     resources: Vec<Resource>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     global_containers: Vec<GlobalContainer>,
     #[serde(skip_serializing_if = "Option::is_none")]
     events: Option<Vec<Event>>,
+    // Edges inferred from the events of the returned resources, e.g. `depends_on`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    derived_edges: Vec<DerivedEdge>,
+    #[serde(default, skip_serializing)]
+    has_more: bool,
+    // Cursor for the next page, set when the response is paginated and more resources remain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    // Revision of the account's resources graph the response was read at, which is also the response's `ETag`
+    #[serde(default)]
+    revision: u64,
+    // Set when the resources database is unavailable and the response is empty rather than an error
+    #[serde(default)]
+    degraded: bool,
+}
+
+fn encode_cursor(resource_id: &ResourceId) -> Result<String> {
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(resource_id)?))
+}
+
+fn decode_cursor(cursor: &str) -> Result<ResourceId> {
+    let Some(resource_id) = BASE64_URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+    else {
+        bad_request!("Invalid cursor");
+    };
+
+    Ok(resource_id)
 }
 
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/query/{type}",
+    tag = "query",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("type" = QueryType, Path), QueryParams),
+    responses(
+        (status = 200, body = QueryResponse),
+        (status = 304, description = "The graph has not changed since the revision in `If-None-Match`"),
+    )
+)]
 #[instrument(err, skip_all)]
 pub(super) async fn query(
     Path((_account_id, r#type)): Path<(String, QueryType)>,
+    Query(params): Query<QueryParams>,
     Extension(account): Extension<Account>,
-) -> Result<Json<QueryResponse>> {
-    const BEGIN: &str = "LET $resources: set<object> = []; LET $events: set<object> = [];";
+    headers: HeaderMap,
+) -> Result<Response> {
+    const BEGIN: &str =
+        "LET $resources: set<object> = []; LET $events: set<object> = []; LET $has_more = false;";
+
+    // Trims the extra resource fetched to determine whether another page exists
+    const TRIM_PAGE: &str = "
+        $has_more = array::len($resources) > $page_limit;
+        $resources = array::slice($resources, 0, $page_limit);";
 
     const FINISH: &str = "{
         resources: $resources,
-        events: $events,
+        events: (SELECT *, fn::principal_chains(principal_chains) AS principal_chains FROM $events),
         global_containers: fn::fetch_global_containers(
             array::concat(
                 $resources.map(|$resource| $resource.id),
@@ -44,33 +126,139 @@ pub(super) async fn query(
                 $events.map(|$event| $event.out),
             ).distinct()
         ),
+        derived_edges: (SELECT
+            in AS source,
+            kind,
+            out AS target,
+            rule,
+            events.map(|$event| { principal: $event.in, type: $event.type, resource: $event.out }) AS events,
+            first_seen_at,
+            last_seen_at,
+            inferred_at
+            FROM derived_edge WHERE in INSIDE $resources.map(|$resource| $resource.id)),
+        has_more: $has_more,
+        revision: revision:current.revision ?? 0,
     };
-    
+
     COMMIT;";
 
-    let db = account.resources_db().await?;
+    if let Some(limit) = params.limit
+        && !(1..=MAX_PAGE_LIMIT).contains(&limit)
+    {
+        bad_request!("limit must be between 1 and {MAX_PAGE_LIMIT}");
+    }
+
+    if params.limit.is_none() && params.cursor.is_some() {
+        bad_request!("cursor requires limit");
+    }
+
+    if let (Some(since), Some(until)) = (params.since, params.until)
+        && since > until
+    {
+        bad_request!("since must not be after until");
+    }
+
+    let cursor = params
+        .cursor
+        .as_deref()
+        .map(decode_cursor)
+        .transpose()?
+        .map(surrealdb_thing_from_resource_id);
 
-    let query = match r#type {
-        QueryType::All => db
+    let Some(db) = account.resources_db_if_available().await else {
+        return Ok(Json(QueryResponse {
+            resources: vec![],
+            global_containers: vec![],
+            events: Some(vec![]),
+            derived_edges: vec![],
+            has_more: false,
+            next_cursor: None,
+            revision: 0,
+            degraded: true,
+        })
+        .into_response());
+    };
+
+    let current_revision = revision::current(&db).await?;
+
+    if revision::is_not_modified(&headers, current_revision) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [(ETAG, revision::etag(current_revision))],
+        )
+            .into_response());
+    }
+
+    let query = match (&r#type, params.limit) {
+        (QueryType::All, None) => TracedQuery::new(&db, "query")
             .query(BeginReadonlyStatement)
             .query(BEGIN)
             .query(Resource::get_all())
             .query(Event::get_all())
             .query(FINISH),
 
-        QueryType::Secrets => {
+        (QueryType::All, Some(_)) => TracedQuery::new(&db, "query")
+            .query(BeginReadonlyStatement)
+            .query(BEGIN)
+            .query(Resource::get_page())
+            .query(TRIM_PAGE)
+            .query(Event::get_for_principals_in_resources())
+            .query(FINISH),
+
+        (QueryType::Secrets, limit) => {
             const SECRETS_QUERY: &str = include_str!("query_secrets.surql");
 
-            db.query(BeginReadonlyStatement)
+            const PAGINATE_SECRETS: &str = "
+                $resources = SELECT * FROM $resources WHERE $cursor IS NONE OR id > $cursor ORDER BY id LIMIT $page_fetch_limit;";
+
+            const FILTER_SECRETS_EVENTS: &str =
+                "$events = SELECT * FROM $events WHERE $resources.id CONTAINS in;";
+
+            let query = TracedQuery::new(&db, "query")
+                .query(BeginReadonlyStatement)
                 .query(BEGIN)
                 .query(SECRETS_QUERY)
-                .query(FINISH)
+                .query(Event::filter_by_time_range());
+
+            if limit.is_some() {
+                query
+                    .query(PAGINATE_SECRETS)
+                    .query(TRIM_PAGE)
+                    .query(FILTER_SECRETS_EVENTS)
+                    .query(FINISH)
+            } else {
+                query.query(FINISH)
+            }
         }
     };
 
-    let mut res = query.await?.check_first_real_error()?;
+    let query = query
+        .bind(("since", params.since.map(surrealdb::sql::Datetime::from)))
+        .bind(("until", params.until.map(surrealdb::sql::Datetime::from)));
+
+    let query = match params.limit {
+        Some(limit) => query
+            .bind(("cursor", cursor))
+            .bind(("page_limit", limit))
+            .bind(("page_fetch_limit", limit + 1)),
+        None => query,
+    };
+
+    let mut res = query.execute().await?.check_first_real_error()?;
+
+    let mut query_response: QueryResponse = res
+        .take::<Option<QueryResponse>>(res.num_statements() - 1)?
+        .context("Query did not return a response")?;
 
-    let query_response: Option<QueryResponse> = res.take(res.num_statements() - 1)?;
+    if query_response.has_more
+        && let Some(last_resource) = query_response.resources.last()
+    {
+        query_response.next_cursor = Some(encode_cursor(&last_resource.id)?);
+    }
 
-    Ok(Json(query_response.unwrap()))
+    Ok((
+        [(ETAG, revision::etag(query_response.revision))],
+        Json(query_response),
+    )
+        .into_response())
 }
@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{Extension, extract::Request, http::StatusCode, middleware::Next, response::Response};
+use tracing::warn;
+
+use archodex_error::PublicError;
+
+use crate::{
+    Result,
+    auth::ReportApiKeyAuth,
+    client_ip::ClientIp,
+    env::{Env, ReportRateLimitConfig},
+};
+
+// Full buckets are pruned when there are more than this many buckets, as a full bucket is equivalent to no bucket
+const BUCKET_PRUNE_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, config: &ReportRateLimitConfig, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * config.reports_per_second).min(f64::from(config.burst));
+        self.refilled_at = now;
+    }
+}
+
+// Buckets are keyed by account ID and key ID, as key IDs are only unique within an account
+type Buckets = HashMap<(String, u32), Bucket>;
+
+static BUCKETS: LazyLock<Mutex<Buckets>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Takes a token from the key's bucket, returning how long until a token is available if the bucket is empty
+fn take_token(config: &ReportRateLimitConfig, account_id: &str, key_id: u32) -> Option<Duration> {
+    let mut buckets = BUCKETS
+        .lock()
+        .expect("Rate limit buckets mutex should not be poisoned");
+
+    let now = Instant::now();
+
+    if buckets.len() >= BUCKET_PRUNE_THRESHOLD {
+        buckets.retain(|_, bucket| {
+            bucket.refill(config, now);
+            bucket.tokens < f64::from(config.burst)
+        });
+    }
+
+    let bucket = buckets
+        .entry((account_id.to_string(), key_id))
+        .or_insert_with(|| Bucket {
+            tokens: f64::from(config.burst),
+            refilled_at: now,
+        });
+
+    bucket.refill(config, now);
+
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        None
+    } else {
+        Some(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / config.reports_per_second,
+        ))
+    }
+}
+
+// Limits the rate of reports from each report API key with a token bucket. This runs after the key is authenticated but
+// before the account is loaded so that a flooding agent is turned away without touching the database. Limits are
+// tracked per backend instance.
+pub(crate) async fn limit_reports(
+    Extension(auth): Extension<ReportApiKeyAuth>,
+    Extension(ClientIp(client_ip)): Extension<ClientIp>,
+    req: Request,
+    next: Next,
+) -> Result<Response> {
+    let Some(config) = Env::report_rate_limit() else {
+        return Ok(next.run(req).await);
+    };
+
+    if let Some(retry_after) = take_token(config, auth.account_id(), auth.key_id()) {
+        warn!(
+            account_id = auth.account_id(),
+            key_id = auth.key_id(),
+            client_ip = client_ip.map(tracing::field::display),
+            "Report API key exceeded rate limit"
+        );
+
+        // Retry-After is in whole seconds, so round up to avoid retrying before a token is available
+        return Err(PublicError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Report rate limit exceeded, please retry later",
+        )
+        .with_retry_after(Duration::from_secs_f64(retry_after.as_secs_f64().ceil())));
+    }
+
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: ReportRateLimitConfig = ReportRateLimitConfig {
+        reports_per_second: 2.0,
+        burst: 3,
+    };
+
+    #[test]
+    fn buckets_refill_at_the_configured_rate_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            refilled_at: now,
+        };
+
+        bucket.refill(&CONFIG, now + Duration::from_millis(500));
+        assert!((bucket.tokens - 1.0).abs() < f64::EPSILON);
+
+        bucket.refill(&CONFIG, now + Duration::from_secs(60));
+        assert!((bucket.tokens - 3.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn keys_are_limited_after_their_burst() {
+        for _ in 0..CONFIG.burst {
+            assert_eq!(take_token(&CONFIG, "rate_limit_test_burst", 1), None);
+        }
+
+        let retry_after = take_token(&CONFIG, "rate_limit_test_burst", 1)
+            .expect("Key should be limited once its burst is used up");
+        assert!(retry_after <= Duration::from_millis(500), "{retry_after:?}");
+
+        // Keys are limited separately
+        assert_eq!(take_token(&CONFIG, "rate_limit_test_burst", 2), None);
+        assert_eq!(
+            take_token(&CONFIG, "rate_limit_test_other_account", 1),
+            None
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::{
+    LazyLock,
+    atomic::{AtomicBool, Ordering},
+};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+
+use archodex_error::PublicError;
+
+use crate::{Result, env::Env};
+
+static READ_ONLY: LazyLock<AtomicBool> = LazyLock::new(|| AtomicBool::new(Env::read_only()));
+
+// Routes that only read despite not being `GET`s, which are allowed in read-only mode
+const READ_ROUTES: &[&str] = &[
+    "/account/:account_id/resources/search",
+    "/account/:account_id/export",
+];
+
+pub(crate) fn is_enabled() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+// Toggles read-only mode of this backend instance until it restarts, when it is reset to `ARCHODEX_READ_ONLY`
+pub(crate) fn set_enabled(enabled: bool) {
+    READ_ONLY.store(enabled, Ordering::Relaxed);
+}
+
+// Rejects requests that would change accounts or their resources while the backend is in read-only mode, including
+// reports. Rejections are 503s so agents retry their reports once the backend is writable again, e.g. after failing back
+// from a read replica.
+pub(crate) async fn reject_writes(req: Request, next: Next) -> Result<Response> {
+    let is_read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || req
+            .extensions()
+            .get::<MatchedPath>()
+            .is_some_and(|path| READ_ROUTES.contains(&path.as_str()));
+
+    if is_enabled() && !is_read {
+        return Err(PublicError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The backend is in read-only mode, changes are not allowed",
+        )
+        .with_code("read_only"));
+    }
+
+    Ok(next.run(req).await)
+}
@@ -0,0 +1,345 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    Result,
+    auth::invalidate_account_access,
+    clock,
+    db::{QueryCheckFirstRealError, for_each_accounts_db_shard, query_accounts_db_shards},
+    env::Env,
+    metrics, worker,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ReconcileRequest {
+    // Repairs drift in the categories that are safe to fix automatically. Other categories are only reported.
+    #[serde(default)]
+    fix: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct DanglingAccessEdge {
+    user_id: String,
+    account_id: String,
+}
+
+// Drift between the accounts database and the customer data it describes. Every category lists what was found before
+// any fixes were applied; `fixed` lists the categories that were repaired.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct ReconciliationReport {
+    // `has_access` edges to accounts that were deleted or no longer exist. Fixed by deleting the edges.
+    dangling_access_edges: Vec<DanglingAccessEdge>,
+    // Pending ownership transfers of deleted accounts. Fixed by cancelling the transfers.
+    stale_account_transfer_ids: Vec<String>,
+    // Live accounts that no user owns. These need manual attention, as the owner can't be inferred.
+    ownerless_account_ids: Vec<String>,
+    // Live accounts that no user has access to or has been invited to, a subset of `ownerless_account_ids`. These need
+    // manual attention.
+    accounts_without_users_ids: Vec<String>,
+    // Users without access or invitations to any live account, e.g. after their accounts were deleted. These are only
+    // reported, as audit logs, personal access tokens, and account history still refer to the users.
+    users_without_accounts_ids: Vec<String>,
+    // Service databases provisioned for account IDs whose account records were never created. Fixed by deleting the
+    // databases.
+    #[cfg(feature = "archodex-com")]
+    orphaned_service_database_account_ids: Vec<String>,
+    // Live accounts served by this endpoint that have no service database. These need manual attention.
+    #[cfg(feature = "archodex-com")]
+    accounts_without_service_database_ids: Vec<String>,
+    // Live accounts whose service database was deleted. These need manual attention.
+    #[cfg(feature = "archodex-com")]
+    accounts_with_deleted_service_database_ids: Vec<String>,
+    fixed: Vec<&'static str>,
+    // Categories whose fixes failed. The drift is left in place and is reported again by the next reconciliation.
+    failed_fixes: Vec<&'static str>,
+}
+
+impl ReconciliationReport {
+    // Number of findings in each category, as reported by the `accounts_db_drift` metric
+    fn drift_counts(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("dangling_access_edges", self.dangling_access_edges.len()),
+            (
+                "stale_account_transfers",
+                self.stale_account_transfer_ids.len(),
+            ),
+            ("ownerless_accounts", self.ownerless_account_ids.len()),
+            (
+                "accounts_without_users",
+                self.accounts_without_users_ids.len(),
+            ),
+            (
+                "users_without_accounts",
+                self.users_without_accounts_ids.len(),
+            ),
+            #[cfg(feature = "archodex-com")]
+            (
+                "orphaned_service_databases",
+                self.orphaned_service_database_account_ids.len(),
+            ),
+            #[cfg(feature = "archodex-com")]
+            (
+                "accounts_without_service_database",
+                self.accounts_without_service_database_ids.len(),
+            ),
+            #[cfg(feature = "archodex-com")]
+            (
+                "accounts_with_deleted_service_database",
+                self.accounts_with_deleted_service_database_ids.len(),
+            ),
+        ]
+    }
+}
+
+const DANGLING_ACCESS_EDGE_CONDITION: &str = "out.deleted_at IS NOT NONE OR !record::exists(out)";
+
+const STALE_ACCOUNT_TRANSFER_CONDITION: &str =
+    "accepted_at IS NONE AND cancelled_at IS NONE AND account.deleted_at IS NOT NONE";
+
+// Condition on users that have access or an invitation to a live account in the queried shard
+const USER_WITH_ACCOUNTS_CONDITION: &str =
+    "count(->has_access[WHERE record::exists(out) AND out.deleted_at IS NONE]) > 0";
+
+/// Audits the accounts database every `ARCHODEX_ACCESS_AUDIT_INTERVAL_SECONDS` until the process exits. Scheduled audits
+/// are disabled when the variable is unset, but reconciliation can still be run through `POST /admin/reconcile`.
+///
+/// Each audit finds the same drift as reconciliation and reports the number of findings of each category through the
+/// `accounts_db_drift` metric, so that operators can alert on the `has_access` graph drifting over time. When
+/// `ARCHODEX_ACCESS_AUDIT_FIX` is `true`, dangling `has_access` edges are deleted and stale transfers are cancelled.
+/// Other categories, including orphaned service databases, are only fixed through the admin route.
+pub async fn run_worker() {
+    let Some(interval) = Env::access_audit_interval() else {
+        return;
+    };
+
+    // Audits are slow, so they don't run at startup
+    tokio::time::sleep(interval).await;
+
+    worker::run_periodically("access_audit", interval, audit).await;
+}
+
+#[instrument(err)]
+pub(crate) async fn audit() -> Result<()> {
+    let mut report = find_drift().await?;
+
+    if Env::access_audit_fix() {
+        fix_accounts_db_drift(&mut report).await;
+    }
+
+    info!(
+        drift = ?report.drift_counts(),
+        fixed = ?report.fixed,
+        failed_fixes = ?report.failed_fixes,
+        "Completed access audit"
+    );
+
+    Ok(())
+}
+
+// Cross-checks account records, `has_access` edges, pending transfers, and (for archodex.com) provisioned service
+// databases, optionally fixing the categories of drift that can be repaired without losing live data
+#[instrument(err)]
+pub(crate) async fn reconcile(req: ReconcileRequest) -> Result<ReconciliationReport> {
+    let mut report = find_drift().await?;
+
+    if !req.fix {
+        return Ok(report);
+    }
+
+    fix_accounts_db_drift(&mut report).await;
+
+    #[cfg(feature = "archodex-com")]
+    if !report.orphaned_service_database_account_ids.is_empty() {
+        match crate::provisioning::sweep_orphaned_service_databases().await {
+            Ok(response) if response.failed_account_ids.is_empty() => {
+                report.fixed.push("orphaned_service_databases");
+            }
+            Ok(_) => report.failed_fixes.push("orphaned_service_databases"),
+            Err(err) => {
+                warn!(?err, "Failed to sweep orphaned service databases");
+                report.failed_fixes.push("orphaned_service_databases");
+            }
+        }
+    }
+
+    info!(
+        fixed = ?report.fixed,
+        failed_fixes = ?report.failed_fixes,
+        "Applied reconciliation fixes"
+    );
+
+    Ok(report)
+}
+
+// Fixes the categories of drift confined to the accounts database
+async fn fix_accounts_db_drift(report: &mut ReconciliationReport) {
+    if !report.dangling_access_edges.is_empty() {
+        match delete_dangling_access_edges().await {
+            Ok(()) => {
+                for edge in &report.dangling_access_edges {
+                    invalidate_account_access(&edge.account_id);
+                }
+                report.fixed.push("dangling_access_edges");
+            }
+            Err(err) => {
+                warn!(?err, "Failed to delete dangling access edges");
+                report.failed_fixes.push("dangling_access_edges");
+            }
+        }
+    }
+
+    if !report.stale_account_transfer_ids.is_empty() {
+        match cancel_stale_account_transfers().await {
+            Ok(()) => report.fixed.push("stale_account_transfers"),
+            Err(err) => {
+                warn!(?err, "Failed to cancel stale account transfers");
+                report.failed_fixes.push("stale_account_transfers");
+            }
+        }
+    }
+}
+
+#[instrument(err)]
+async fn find_drift() -> Result<ReconciliationReport> {
+    let mut report = ReconciliationReport::default();
+
+    // Users with a record in several shards only lack accounts if they lack them in every shard
+    let mut users_with_accounts_ids = BTreeSet::new();
+    let mut users_without_accounts_ids = BTreeSet::new();
+
+    // Account records, their `has_access` edges, and their transfers are held by the same shard
+    for (
+        dangling_access_edges,
+        stale_account_transfer_ids,
+        ownerless_account_ids,
+        accounts_without_users_ids,
+        shard_users_with_accounts_ids,
+        shard_users_without_accounts_ids,
+    ) in query_accounts_db_shards(|db| async move {
+            let mut res = db
+                .query(format!(
+                    "SELECT <string> record::id(in) AS user_id, record::id(out) AS account_id FROM has_access
+                        WHERE {DANGLING_ACCESS_EDGE_CONDITION}"
+                ))
+                .query(format!(
+                    "SELECT VALUE <string> record::id(id) FROM account_transfer WHERE {STALE_ACCOUNT_TRANSFER_CONDITION}"
+                ))
+                .query(
+                    "SELECT VALUE record::id(id) FROM account
+                        WHERE deleted_at IS NONE AND count(<-has_access[WHERE (role ?? 'owner') = 'owner']) = 0",
+                )
+                .query("SELECT VALUE record::id(id) FROM account WHERE deleted_at IS NONE AND count(<-has_access) = 0")
+                .query(format!(
+                    "SELECT VALUE <string> record::id(id) FROM user WHERE {USER_WITH_ACCOUNTS_CONDITION}"
+                ))
+                .query(format!(
+                    "SELECT VALUE <string> record::id(id) FROM user WHERE !({USER_WITH_ACCOUNTS_CONDITION})"
+                ))
+                .await?
+                .check_first_real_error()?;
+
+            Ok([(
+                res.take::<Vec<DanglingAccessEdge>>(0)?,
+                res.take::<Vec<String>>(1)?,
+                res.take::<Vec<String>>(2)?,
+                res.take::<Vec<String>>(3)?,
+                res.take::<Vec<String>>(4)?,
+                res.take::<Vec<String>>(5)?,
+            )])
+        })
+        .await?
+    {
+        report.dangling_access_edges.extend(dangling_access_edges);
+        report
+            .stale_account_transfer_ids
+            .extend(stale_account_transfer_ids);
+        report.ownerless_account_ids.extend(ownerless_account_ids);
+        report
+            .accounts_without_users_ids
+            .extend(accounts_without_users_ids);
+        users_with_accounts_ids.extend(shard_users_with_accounts_ids);
+        users_without_accounts_ids.extend(shard_users_without_accounts_ids);
+    }
+
+    report.users_without_accounts_ids = users_without_accounts_ids
+        .difference(&users_with_accounts_ids)
+        .cloned()
+        .collect();
+
+    #[cfg(feature = "archodex-com")]
+    {
+        report.orphaned_service_database_account_ids =
+            crate::provisioning::orphaned_service_databases()
+                .await?
+                .into_iter()
+                .map(|orphan| orphan.account_id)
+                .collect();
+
+        report.accounts_without_service_database_ids = query_accounts_db_shards(|db| async move {
+            Ok(db
+                .query(
+                    "SELECT VALUE record::id(id) FROM account
+                        WHERE deleted_at IS NONE AND endpoint = $endpoint AND service_data_surrealdb_url IS NONE",
+                )
+                .bind(("endpoint", crate::env::Env::endpoint().to_string()))
+                .await?
+                .check_first_real_error()?
+                .take::<Vec<String>>(0)?)
+        })
+        .await?;
+
+        // Reservations are held by the primary shard, but account records by the shards for their IDs
+        let deleted_service_database_account_ids = crate::db::primary_accounts_db()
+            .await?
+            .query(
+                "SELECT VALUE record::id(id) FROM account_id_reservation WHERE service_data_deleted_at IS NOT NONE",
+            )
+            .await?
+            .check_first_real_error()?
+            .take::<Vec<String>>(0)?;
+
+        report.accounts_with_deleted_service_database_ids =
+            crate::account::get_accounts(deleted_service_database_account_ids)
+                .await?
+                .into_iter()
+                .filter(|account| !account.is_deleted())
+                .map(|account| account.id().to_string())
+                .collect();
+    }
+
+    metrics::record_accounts_db_drift(&report.drift_counts());
+
+    Ok(report)
+}
+
+#[instrument(err)]
+async fn delete_dangling_access_edges() -> Result<()> {
+    for_each_accounts_db_shard(|db| async move {
+        db.query(format!(
+            "DELETE has_access WHERE {DANGLING_ACCESS_EDGE_CONDITION}"
+        ))
+        .await?
+        .check_first_real_error()?;
+
+        Ok(())
+    })
+    .await
+}
+
+#[instrument(err)]
+async fn cancel_stale_account_transfers() -> Result<()> {
+    for_each_accounts_db_shard(|db| async move {
+        db.query(format!(
+            "UPDATE account_transfer SET cancelled_at = $now WHERE {STALE_ACCOUNT_TRANSFER_CONDITION} RETURN NONE"
+        ))
+        .bind(("now", clock::now_value()))
+        .await?
+        .check_first_real_error()?;
+
+        Ok(())
+    })
+    .await
+}
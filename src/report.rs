@@ -1,94 +1,1244 @@
+mod proto {
+    include!(concat!(env!("OUT_DIR"), "/archodex.report.rs"));
+}
+
 use core::fmt::Debug;
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
-use axum::{Extension, Json};
+use axum::{
+    Extension, Json,
+    body::{Body, Bytes},
+    extract::FromRequest,
+    http::{
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+        header::{CONTENT_TYPE, LOCATION},
+    },
+    response::{IntoResponse, Response},
+};
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use http_body_util::BodyExt as _;
+use prost::Message as _;
+use serde::{Deserialize, Serialize};
 use surrealdb::{
+    Surreal,
     engine::any::Any,
-    method::Query,
     sql::statements::{BeginStatement, CommitStatement, InsertStatement, UpdateStatement},
 };
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use archodex_error::{
+    PublicError,
+    anyhow::{self, Context as _},
+    bad_request,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    clock,
+    db::{BeginReadonlyStatement, QueryCheckFirstRealError, is_transaction_conflict_error},
+    enrichment::{self, Enricher},
+    env::Env,
+    event_delivery::{
+        self, DeliveredEvent, SELECT_EVENT_DESTINATIONS, SubscribedEventTypes,
+        enqueue_event_statement,
+    },
+    metrics, next_binding,
+    report_api_key_usage::ResourcesUpserted,
+    report_job::{self, ReportJob},
+    resource::{ResourceId, ResourceIdPart, surrealdb_thing_from_resource_id},
+    revision, timeout,
+    traced_query::TracedQuery,
+    transformation::{self, TransformationRule},
+    value::surrealdb_value_from_json_value,
+};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+#[schema(as = ReportPrincipal)]
+#[serde(deny_unknown_fields)]
+struct Principal {
+    id: ResourceId,
+    event: Option<String>,
+}
+
+impl From<Principal> for surrealdb::sql::Value {
+    fn from(value: Principal) -> Self {
+        surrealdb::sql::Object::from(HashMap::from([
+            ("id", surrealdb::sql::Value::from(value.id)),
+            ("event", value.event.into()),
+        ]))
+        .into()
+    }
+}
+
+fn surrealdb_value_from_principal_chain(principal_chain: Vec<Principal>) -> surrealdb::sql::Value {
+    surrealdb::sql::Array::from(
+        principal_chain
+            .into_iter()
+            .map(surrealdb::sql::Value::from)
+            .collect::<Vec<_>>(),
+    )
+    .into()
+}
+
+// TODO: Implement deserializer to handle unknown fields. Serde's built-in
+// unknown field handling doesn't work with its flatten option.
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+struct ResourceTreeNode {
+    #[serde(flatten)]
+    id: ResourceIdPart,
+    globally_unique: Option<bool>,
+    first_seen_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    attributes: Option<serde_json::Map<String, serde_json::Value>>,
+    #[schema(no_recursion)]
+    contains: Option<Vec<ResourceTreeNode>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[schema(as = ReportEvent)]
+#[serde(deny_unknown_fields)]
+struct Event {
+    r#type: String,
+    first_seen_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct EventCapture {
+    principals: Vec<Principal>,
+    resources: Vec<ResourceId>,
+    events: Vec<Event>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[schema(as = Report)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Request {
+    // Version of the report schema the agent used. Reports without a version are from agents that predate versioning,
+    // which used version 1.
+    #[serde(default = "default_schema_version")]
+    schema_version: u32,
+    resource_captures: Vec<ResourceTreeNode>,
+    event_captures: Vec<EventCapture>,
+}
+
+impl Default for Request {
+    fn default() -> Self {
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            resource_captures: vec![],
+            event_captures: vec![],
+        }
+    }
+}
+
+// Latest report schema version this backend understands. Reports using older versions are parsed strictly, while reports
+// from agents using a newer version are accepted with the fields this backend doesn't know about dropped. The version is
+// returned in the `X-Archodex-Report-Schema-Version` header so agents can avoid sending fields that would be dropped.
+const REPORT_SCHEMA_VERSION: u32 = 1;
+
+const REPORT_SCHEMA_VERSION_HEADER: &str = "x-archodex-report-schema-version";
+
+fn default_schema_version() -> u32 {
+    1
+}
+
+// Fields of each report object in the latest report schema version
+const REPORT_FIELDS: &[&str] = &["schema_version", "resource_captures", "event_captures"];
+const RESOURCE_TREE_NODE_FIELDS: &[&str] = &[
+    "type",
+    "id",
+    "globally_unique",
+    "first_seen_at",
+    "last_seen_at",
+    "attributes",
+    "contains",
+];
+const EVENT_CAPTURE_FIELDS: &[&str] = &["principals", "resources", "events"];
+const PRINCIPAL_FIELDS: &[&str] = &["id", "event"];
+const EVENT_FIELDS: &[&str] = &["type", "first_seen_at", "last_seen_at"];
+
+fn retain_fields<'a>(
+    value: &'a mut serde_json::Value,
+    fields: &[&str],
+) -> Option<&'a mut serde_json::Map<String, serde_json::Value>> {
+    let object = value.as_object_mut()?;
+    object.retain(|field, _| fields.contains(&field.as_str()));
+    Some(object)
+}
+
+fn retain_array_fields(
+    object: &mut serde_json::Map<String, serde_json::Value>,
+    array_field: &str,
+    fields: &[&str],
+) {
+    if let Some(serde_json::Value::Array(elements)) = object.get_mut(array_field) {
+        for element in elements {
+            retain_fields(element, fields);
+        }
+    }
+}
+
+fn retain_resource_tree_node_fields(node: &mut serde_json::Value) {
+    if let Some(node) = retain_fields(node, RESOURCE_TREE_NODE_FIELDS)
+        && let Some(serde_json::Value::Array(children)) = node.get_mut("contains")
+    {
+        for child in children {
+            retain_resource_tree_node_fields(child);
+        }
+    }
+}
+
+// Parses a JSON report from an agent using a newer schema version than this backend understands by dropping the fields
+// the backend doesn't know about. Returns `None` if the report isn't from a newer schema version.
+fn report_from_newer_schema_version(body: &[u8]) -> Option<Request> {
+    let mut report = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+
+    let schema_version = report.get("schema_version")?.as_u64()?;
+    if schema_version <= u64::from(REPORT_SCHEMA_VERSION) {
+        return None;
+    }
+
+    let report_object = retain_fields(&mut report, REPORT_FIELDS)?;
+
+    if let Some(serde_json::Value::Array(resource_captures)) =
+        report_object.get_mut("resource_captures")
+    {
+        for resource_capture in resource_captures {
+            retain_resource_tree_node_fields(resource_capture);
+        }
+    }
+
+    if let Some(serde_json::Value::Array(event_captures)) = report_object.get_mut("event_captures")
+    {
+        for event_capture in event_captures {
+            if let Some(event_capture) = retain_fields(event_capture, EVENT_CAPTURE_FIELDS) {
+                retain_array_fields(event_capture, "principals", PRINCIPAL_FIELDS);
+                retain_array_fields(event_capture, "events", EVENT_FIELDS);
+            }
+        }
+    }
+
+    // Resource ID parts only deserialize from borrowed strings, so the report is parsed from serialized JSON rather than
+    // from the value
+    let report = serde_json::from_slice::<Request>(&serde_json::to_vec(&report).ok()?).ok()?;
+
+    info!(
+        schema_version,
+        "Accepted report from an agent using a newer schema version, ignoring unknown fields"
+    );
+
+    Some(report)
+}
+
+fn is_json_content_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+// Agents may send reports encoded with the protobuf schema in `report.proto` instead of JSON, which is cheaper to encode
+// and smaller for deeply nested resource trees
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+#[axum::async_trait]
+impl<S: Send + Sync> FromRequest<S> for Request {
+    type Rejection = Response;
+
+    async fn from_request(
+        req: axum::extract::Request,
+        state: &S,
+    ) -> std::result::Result<Self, Response> {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let report = if content_type.starts_with(PROTOBUF_CONTENT_TYPE) {
+            Request::from_protobuf(&body).map_err(IntoResponse::into_response)?
+        } else if is_json_content_type(&content_type) {
+            match Json::<Request>::from_bytes(&body) {
+                Ok(Json(report)) => report,
+                Err(rejection) => report_from_newer_schema_version(&body)
+                    .ok_or_else(|| rejection.into_response())?,
+            }
+        } else {
+            return Err((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Expected request with `Content-Type: application/json` or `Content-Type: application/x-protobuf`",
+            )
+                .into_response());
+        };
+
+        if report.schema_version == 0 {
+            return Err(PublicError::new(
+                StatusCode::BAD_REQUEST,
+                "Report schema version must be at least 1",
+            )
+            .into_response());
+        }
+
+        report.validate().map_err(IntoResponse::into_response)?;
+
+        Ok(report)
+    }
+}
+
+impl Request {
+    // Parses a JSON report, accepting reports from agents using newer schema versions
+    pub(crate) fn from_json(body: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(body)
+            .or_else(|err| report_from_newer_schema_version(body).ok_or(err))
+    }
+
+    fn from_protobuf(body: &[u8]) -> Result<Self> {
+        let report = match proto::Report::decode(body) {
+            Ok(report) => report,
+            Err(err) => bad_request!("Invalid protobuf report: {err}"),
+        };
+
+        Request::try_from(report)
+    }
+}
+
+fn datetime_from_proto(
+    timestamp: Option<prost_types::Timestamp>,
+    field: &str,
+) -> Result<DateTime<Utc>> {
+    let Some(timestamp) = timestamp else {
+        bad_request!("Protobuf report is missing `{field}`");
+    };
+
+    let Some(datetime) = u32::try_from(timestamp.nanos)
+        .ok()
+        .and_then(|nanos| DateTime::from_timestamp(timestamp.seconds, nanos))
+    else {
+        bad_request!("Protobuf report has an invalid `{field}` timestamp");
+    };
+
+    Ok(datetime)
+}
+
+// Whole numbers are converted to integers so attributes are stored the same as they would be from a JSON report
+#[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+fn json_value_from_proto(value: prost_types::Value) -> serde_json::Value {
+    use prost_types::value::Kind;
+
+    match value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::NumberValue(value)) => {
+            if value.fract() == 0.0 && value >= i64::MIN as f64 && value < i64::MAX as f64 {
+                serde_json::Value::from(value as i64)
+            } else {
+                serde_json::Number::from_f64(value).map_or(serde_json::Value::Null, Into::into)
+            }
+        }
+        Some(Kind::StringValue(value)) => serde_json::Value::String(value),
+        Some(Kind::BoolValue(value)) => serde_json::Value::Bool(value),
+        Some(Kind::StructValue(value)) => serde_json::Value::Object(json_map_from_proto(value)),
+        Some(Kind::ListValue(value)) => serde_json::Value::Array(
+            value
+                .values
+                .into_iter()
+                .map(json_value_from_proto)
+                .collect(),
+        ),
+    }
+}
+
+fn json_map_from_proto(value: prost_types::Struct) -> serde_json::Map<String, serde_json::Value> {
+    value
+        .fields
+        .into_iter()
+        .map(|(key, value)| (key, json_value_from_proto(value)))
+        .collect()
+}
+
+impl From<proto::ResourceIdPart> for ResourceIdPart {
+    fn from(value: proto::ResourceIdPart) -> Self {
+        ResourceIdPart {
+            r#type: value.r#type,
+            id: value.id,
+        }
+    }
+}
+
+impl From<proto::ResourceId> for ResourceId {
+    fn from(value: proto::ResourceId) -> Self {
+        value
+            .parts
+            .into_iter()
+            .map(ResourceIdPart::from)
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+impl TryFrom<proto::ResourceTreeNode> for ResourceTreeNode {
+    type Error = PublicError;
+
+    fn try_from(value: proto::ResourceTreeNode) -> Result<Self> {
+        let Some(id) = value.id else {
+            bad_request!("Protobuf report has a resource capture that is missing `id`");
+        };
+
+        Ok(ResourceTreeNode {
+            id: id.into(),
+            globally_unique: value.globally_unique,
+            first_seen_at: datetime_from_proto(value.first_seen_at, "first_seen_at")?,
+            last_seen_at: datetime_from_proto(value.last_seen_at, "last_seen_at")?,
+            attributes: value.attributes.map(json_map_from_proto),
+            contains: if value.contains.is_empty() {
+                None
+            } else {
+                Some(
+                    value
+                        .contains
+                        .into_iter()
+                        .map(ResourceTreeNode::try_from)
+                        .collect::<Result<_>>()?,
+                )
+            },
+        })
+    }
+}
+
+impl TryFrom<proto::Principal> for Principal {
+    type Error = PublicError;
+
+    fn try_from(value: proto::Principal) -> Result<Self> {
+        let Some(id) = value.id else {
+            bad_request!("Protobuf report has a principal that is missing `id`");
+        };
+
+        Ok(Principal {
+            id: id.into(),
+            event: value.event,
+        })
+    }
+}
+
+impl TryFrom<proto::Event> for Event {
+    type Error = PublicError;
+
+    fn try_from(value: proto::Event) -> Result<Self> {
+        Ok(Event {
+            r#type: value.r#type,
+            first_seen_at: datetime_from_proto(value.first_seen_at, "first_seen_at")?,
+            last_seen_at: datetime_from_proto(value.last_seen_at, "last_seen_at")?,
+        })
+    }
+}
+
+impl TryFrom<proto::EventCapture> for EventCapture {
+    type Error = PublicError;
+
+    fn try_from(value: proto::EventCapture) -> Result<Self> {
+        Ok(EventCapture {
+            principals: value
+                .principals
+                .into_iter()
+                .map(Principal::try_from)
+                .collect::<Result<_>>()?,
+            resources: value.resources.into_iter().map(ResourceId::from).collect(),
+            events: value
+                .events
+                .into_iter()
+                .map(Event::try_from)
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl TryFrom<proto::Report> for Request {
+    type Error = PublicError;
+
+    fn try_from(value: proto::Report) -> Result<Self> {
+        Ok(Request {
+            // Protobuf reports ignore unknown fields, so reports from newer schema versions need no special handling
+            schema_version: value.schema_version.max(1),
+            resource_captures: value
+                .resource_captures
+                .into_iter()
+                .map(ResourceTreeNode::try_from)
+                .collect::<Result<_>>()?,
+            event_captures: value
+                .event_captures
+                .into_iter()
+                .map(EventCapture::try_from)
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+// A line of a streamed report, e.g. `{"resource_capture": {...}}` or `{"event_capture": {...}}`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum StreamRecord {
+    ResourceCapture(ResourceTreeNode),
+    EventCapture(EventCapture),
+}
+
+// Streamed reports are ingested in transactions of about this many resources and events, which bounds memory use and
+// transaction size no matter how large the report is
+const STREAM_BATCH_SIZE: usize = 1000;
+
+impl ResourceTreeNode {
+    // Number of resources in the tree rooted at this node
+    fn num_resources(&self) -> usize {
+        1 + self
+            .contains
+            .iter()
+            .flatten()
+            .map(ResourceTreeNode::num_resources)
+            .sum::<usize>()
+    }
+}
+
+impl EventCapture {
+    // Number of events upserted for this capture, one for each principal, resource, and event type combination
+    fn num_events(&self) -> usize {
+        self.principals.len() * self.resources.len() * self.events.len()
+    }
+}
+
+// Ingestion transactions conflict when concurrent reports of an account upsert the same records, e.g. the containers
+// every agent of the account reports. Conflicting transactions are retried after a short delay this many times before
+// failing with `transaction_conflict`.
+const MAX_INGEST_ATTEMPTS: u32 = 3;
+const INGEST_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+// Timestamps further than this in the future are assumed to come from an agent with a skewed clock
+const MAX_CLOCK_SKEW: chrono::Duration = chrono::Duration::minutes(5);
+
+// Summary of an ingested report, so agent operators can verify their captures are landing
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub(crate) struct IngestionResult {
+    resources_created: usize,
+    resources_updated: usize,
+    events_inserted: usize,
+    events_updated: usize,
+    // Revision of the account's resources graph once the report was ingested. Dry runs return the current revision.
+    revision: u64,
+    warnings: Vec<IngestionWarning>,
+}
+
+impl IngestionResult {
+    pub(crate) fn resources_upserted(&self) -> usize {
+        self.resources_created + self.resources_updated
+    }
+
+    fn merge(&mut self, other: IngestionResult) {
+        self.resources_created += other.resources_created;
+        self.resources_updated += other.resources_updated;
+        self.events_inserted += other.events_inserted;
+        self.events_updated += other.events_updated;
+        self.revision = self.revision.max(other.revision);
+        self.warnings.extend(other.warnings);
+    }
+}
+
+// A problem with an item of a report that didn't prevent it from being ingested
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct IngestionWarning {
+    // Line of the record in a streamed report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    // JSON pointer to the item within the report, or within the record of a streamed report
+    path: String,
+    message: String,
+}
+
+fn seen_at_warnings(
+    path: &str,
+    first_seen_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+    warnings: &mut Vec<IngestionWarning>,
+) {
+    let mut warn = |path: String, message: &str| {
+        warnings.push(IngestionWarning {
+            line: None,
+            path,
+            message: message.to_string(),
+        });
+    };
+
+    if last_seen_at < first_seen_at {
+        warn(
+            format!("{path}/last_seen_at"),
+            "last_seen_at is before first_seen_at",
+        );
+    }
+
+    if first_seen_at > now + MAX_CLOCK_SKEW {
+        warn(
+            format!("{path}/first_seen_at"),
+            "first_seen_at is in the future",
+        );
+    }
+
+    if last_seen_at > now + MAX_CLOCK_SKEW {
+        warn(
+            format!("{path}/last_seen_at"),
+            "last_seen_at is in the future",
+        );
+    }
+}
+
+impl ResourceTreeNode {
+    fn warnings(&self, path: &str, now: DateTime<Utc>, warnings: &mut Vec<IngestionWarning>) {
+        seen_at_warnings(path, self.first_seen_at, self.last_seen_at, now, warnings);
+
+        for (index, child) in self.contains.iter().flatten().enumerate() {
+            child.warnings(&format!("{path}/contains/{index}"), now, warnings);
+        }
+    }
+}
+
+impl EventCapture {
+    fn warnings(&self, path: &str, now: DateTime<Utc>, warnings: &mut Vec<IngestionWarning>) {
+        for (index, event) in self.events.iter().enumerate() {
+            seen_at_warnings(
+                &format!("{path}/events/{index}"),
+                event.first_seen_at,
+                event.last_seen_at,
+                now,
+                warnings,
+            );
+        }
+    }
+}
+
+impl Request {
+    // Problems with the report's items that are worth reporting back to the agent but don't prevent ingestion
+    fn warnings(&self) -> Vec<IngestionWarning> {
+        let now = clock::now();
+        let mut warnings = vec![];
+
+        for (index, resource_capture) in self.resource_captures.iter().enumerate() {
+            resource_capture.warnings(&format!("/resource_captures/{index}"), now, &mut warnings);
+        }
+
+        for (index, event_capture) in self.event_captures.iter().enumerate() {
+            event_capture.warnings(&format!("/event_captures/{index}"), now, &mut warnings);
+        }
+
+        warnings
+    }
+}
+
+impl StreamRecord {
+    fn warnings(&self, line: usize) -> Vec<IngestionWarning> {
+        let now = clock::now();
+        let mut warnings = vec![];
+
+        match self {
+            StreamRecord::ResourceCapture(resource_capture) => {
+                resource_capture.warnings("/resource_capture", now, &mut warnings);
+            }
+            StreamRecord::EventCapture(event_capture) => {
+                event_capture.warnings("/event_capture", now, &mut warnings);
+            }
+        }
+
+        for warning in &mut warnings {
+            warning.line = Some(line);
+        }
+
+        warnings
+    }
+}
+
+// Only this many invalid items are listed in an error response, so a report full of invalid items gets a bounded error
+const MAX_INVALID_ITEMS_LISTED: usize = 10;
+
+// Resource ID parts are stored in composite record IDs, which are used as keys of every index on the record, so their
+// size is bounded
+const MAX_RESOURCE_ID_PART_BYTES: usize = 1024;
+
+// A problem with an item of a report that prevents the report from being ingested
+#[derive(Debug)]
+struct InvalidItem {
+    // JSON pointer to the item within the report, or within the record of a streamed report
+    path: String,
+    message: String,
+}
+
+fn invalid_items_message(invalid_items: &[InvalidItem]) -> String {
+    let mut message = invalid_items
+        .iter()
+        .take(MAX_INVALID_ITEMS_LISTED)
+        .map(|invalid_item| format!("{}: {}", invalid_item.path, invalid_item.message))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    if invalid_items.len() > MAX_INVALID_ITEMS_LISTED {
+        message.push_str(&format!(
+            "; and {} more",
+            invalid_items.len() - MAX_INVALID_ITEMS_LISTED
+        ));
+    }
+
+    message
+}
+
+// Size of a resource ID part, as counted against `Env::max_resource_id_bytes()`
+fn resource_id_part_bytes(part: &ResourceIdPart) -> usize {
+    part.r#type.len() + part.id.len()
+}
+
+fn validate_resource_id_part(
+    path: String,
+    part: &ResourceIdPart,
+    invalid_items: &mut Vec<InvalidItem>,
+) {
+    for (field, value) in [("type", &part.r#type), ("id", &part.id)] {
+        let message = if value.is_empty() {
+            format!("{field} must not be empty")
+        } else if value.len() > MAX_RESOURCE_ID_PART_BYTES {
+            format!("{field} must be at most {MAX_RESOURCE_ID_PART_BYTES} bytes")
+        } else {
+            continue;
+        };
+
+        invalid_items.push(InvalidItem {
+            path: path.clone(),
+            message,
+        });
+    }
+}
+
+fn validate_resource_id(path: String, id: &ResourceId, invalid_items: &mut Vec<InvalidItem>) {
+    if id.is_empty() {
+        invalid_items.push(InvalidItem {
+            path,
+            message: "must not be empty".to_string(),
+        });
+        return;
+    }
+
+    for (index, part) in id.iter().enumerate() {
+        validate_resource_id_part(format!("{path}/{index}"), part, invalid_items);
+    }
+
+    let max_resource_id_bytes = Env::max_resource_id_bytes();
+    if id.iter().map(resource_id_part_bytes).sum::<usize>() > max_resource_id_bytes {
+        invalid_items.push(InvalidItem {
+            path,
+            message: format!("must be at most {max_resource_id_bytes} bytes"),
+        });
+    }
+}
+
+impl ResourceTreeNode {
+    // The node's resource ID is the ID parts of its ancestors followed by its own, so `parent_id_bytes` is the size of its
+    // parent's resource ID
+    fn validate(&self, path: &str, parent_id_bytes: usize, invalid_items: &mut Vec<InvalidItem>) {
+        validate_resource_id_part(path.to_string(), &self.id, invalid_items);
+
+        let id_bytes = parent_id_bytes + resource_id_part_bytes(&self.id);
+        let max_resource_id_bytes = Env::max_resource_id_bytes();
+        if id_bytes > max_resource_id_bytes {
+            // Every contained resource's ID is larger still, so they aren't listed as well
+            invalid_items.push(InvalidItem {
+                path: path.to_string(),
+                message: format!("resource ID must be at most {max_resource_id_bytes} bytes"),
+            });
+            return;
+        }
+
+        for (index, child) in self.contains.iter().flatten().enumerate() {
+            child.validate(&format!("{path}/contains/{index}"), id_bytes, invalid_items);
+        }
+    }
+}
+
+impl EventCapture {
+    // Each capture is the cross product of its principals, resources, and events, so a capture missing any of them
+    // describes no events at all. This is most likely an agent bug, so it's rejected rather than silently dropped.
+    fn validate(&self, path: &str, invalid_items: &mut Vec<InvalidItem>) {
+        let mut invalid = |path: String, message: String| {
+            invalid_items.push(InvalidItem { path, message });
+        };
+
+        if self.principals.is_empty() {
+            invalid(
+                format!("{path}/principals"),
+                "must not be empty".to_string(),
+            );
+        }
+
+        // The whole chain is the ID of its `principal_chain` record
+        let max_principal_chain_length = Env::max_principal_chain_length();
+        if self.principals.len() > max_principal_chain_length {
+            invalid(
+                format!("{path}/principals"),
+                format!("must have at most {max_principal_chain_length} principals"),
+            );
+        }
+
+        if self.resources.is_empty() {
+            invalid(format!("{path}/resources"), "must not be empty".to_string());
+        }
+
+        if self.events.is_empty() {
+            invalid(format!("{path}/events"), "must not be empty".to_string());
+        }
+
+        for (index, principal) in self.principals.iter().enumerate() {
+            validate_resource_id(
+                format!("{path}/principals/{index}/id"),
+                &principal.id,
+                invalid_items,
+            );
+        }
+
+        for (index, resource) in self.resources.iter().enumerate() {
+            validate_resource_id(format!("{path}/resources/{index}"), resource, invalid_items);
+        }
+    }
+}
+
+impl Request {
+    // Rejects reports with items that can't be ingested, listing each invalid item
+    pub(crate) fn validate(&self) -> Result<()> {
+        let mut invalid_items = vec![];
+
+        for (index, resource_capture) in self.resource_captures.iter().enumerate() {
+            resource_capture.validate(
+                &format!("/resource_captures/{index}"),
+                0,
+                &mut invalid_items,
+            );
+        }
+
+        for (index, event_capture) in self.event_captures.iter().enumerate() {
+            event_capture.validate(&format!("/event_captures/{index}"), &mut invalid_items);
+        }
+
+        if !invalid_items.is_empty() {
+            bad_request!("Invalid report: {}", invalid_items_message(&invalid_items));
+        }
+
+        Ok(())
+    }
+}
+
+impl StreamRecord {
+    fn validate(&self, line: usize) -> Result<()> {
+        let mut invalid_items = vec![];
+
+        match self {
+            StreamRecord::ResourceCapture(resource_capture) => {
+                resource_capture.validate("/resource_capture", 0, &mut invalid_items);
+            }
+            StreamRecord::EventCapture(event_capture) => {
+                event_capture.validate("/event_capture", &mut invalid_items);
+            }
+        }
+
+        if !invalid_items.is_empty() {
+            bad_request!(
+                "Invalid report record on line {line}: {}",
+                invalid_items_message(&invalid_items)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+// Variables counting the resources and events that didn't exist before ingestion, which are returned by the last
+// statement of the ingestion transaction. Created resources are collected in `$created_resources` rather than counted
+// when their IDs are needed after the transaction commits.
+const INGESTION_COUNTERS: &str =
+    "LET $resources_created = 0; LET $created_resources = []; LET $events_inserted = 0;";
+
+const INGESTION_RESULT: &str = "{
+    resources_created: $resources_created + array::len($created_resources),
+    created_resources: $created_resources,
+    events_inserted: $events_inserted,
+};";
+
+// Counts the resources and events a report would create, for dry runs. Reports may upsert the same resource or event
+// more than once, so only distinct records are counted.
+const DRY_RUN_RESULT: &str = "{
+    resources_created: array::len(array::distinct($resources).filter(|$resource| !record::exists($resource))),
+    events_inserted: array::len(array::distinct($events).filter(|$event|
+        count(SELECT id FROM event WHERE in = $event[0] AND out = $event[1] AND type = $event[2] LIMIT 1) = 0
+    )),
+    revision: revision:current.revision ?? 0,
+};";
+
+#[derive(Deserialize)]
+struct IngestionCounts {
+    resources_created: usize,
+    #[cfg_attr(not(feature = "archodex-com"), allow(dead_code))]
+    #[serde(default)]
+    created_resources: Vec<ResourceId>,
+    events_inserted: usize,
+    // Only returned by dry runs, as ingestion bumps the revision after its transaction commits
+    #[serde(default)]
+    revision: u64,
+}
+
+impl IngestionResult {
+    fn from_counts(counts: IngestionCounts, num_resources: usize, num_events: usize) -> Self {
+        Self {
+            resources_created: counts.resources_created,
+            resources_updated: num_resources.saturating_sub(counts.resources_created),
+            events_inserted: counts.events_inserted,
+            events_updated: num_events.saturating_sub(counts.events_inserted),
+            revision: counts.revision,
+            warnings: vec![],
+        }
+    }
+}
+
+impl Request {
+    fn num_resources(&self) -> usize {
+        self.resource_captures
+            .iter()
+            .map(ResourceTreeNode::num_resources)
+            .sum()
+    }
+
+    fn num_events(&self) -> usize {
+        self.event_captures
+            .iter()
+            .map(EventCapture::num_events)
+            .sum()
+    }
+}
+
+impl ResourceTreeNode {
+    // Collects the IDs of the resources upserted for the tree rooted at this node
+    fn resource_ids(
+        self,
+        prefix: &mut surrealdb::sql::Array,
+        resource_ids: &mut Vec<surrealdb::sql::Thing>,
+    ) {
+        let mut globally_unique_prefix = surrealdb::sql::Array::new();
+
+        let prefix = match self.globally_unique {
+            Some(true) => &mut globally_unique_prefix,
+            _ => prefix,
+        };
+
+        prefix.push(self.id.into());
+
+        resource_ids.push(surrealdb::sql::Thing::from((
+            "resource",
+            surrealdb::sql::Id::from(prefix.clone()),
+        )));
+
+        for child in self.contains.into_iter().flatten() {
+            child.resource_ids(prefix, resource_ids);
+        }
+
+        prefix.pop();
+    }
+}
+
+impl ResourceTreeNode {
+    // Adds the attributes of the account's enrichers to the tree rooted at this node. Nodes only get an attributes map if
+    // an enricher adds to it.
+    fn enrich(&mut self, prefix: &mut Vec<ResourceIdPart>, enrichers: &[&dyn Enricher]) {
+        let mut globally_unique_prefix = vec![];
+
+        let prefix = match self.globally_unique {
+            Some(true) => &mut globally_unique_prefix,
+            _ => prefix,
+        };
+
+        prefix.push(self.id.clone());
+
+        let mut attributes = self.attributes.take().unwrap_or_default();
+
+        for enricher in enrichers {
+            enricher.enrich(prefix, &mut attributes);
+        }
+
+        if !attributes.is_empty() {
+            self.attributes = Some(attributes);
+        }
+
+        for child in self.contains.iter_mut().flatten() {
+            child.enrich(prefix, enrichers);
+        }
+
+        prefix.pop();
+    }
+}
+
+impl ResourceTreeNode {
+    // Applies the account's transformation rules to the tree rooted at this node
+    fn transform(&mut self, rules: &[TransformationRule]) {
+        transformation::transform_resource(rules, &mut self.id, self.attributes.as_mut());
+
+        for child in self.contains.iter_mut().flatten() {
+            child.transform(rules);
+        }
+    }
+}
+
+impl Request {
+    // Applies the account's transformation rules to the report before it is planned or upserted
+    fn transform(&mut self, rules: &[TransformationRule]) {
+        if rules.is_empty() {
+            return;
+        }
+
+        for resource_tree_node in &mut self.resource_captures {
+            resource_tree_node.transform(rules);
+        }
+
+        for event_capture in &mut self.event_captures {
+            event_capture.transform(rules);
+        }
+    }
+}
+
+impl EventCapture {
+    fn transform(&mut self, rules: &[TransformationRule]) {
+        let resource_ids = self
+            .principals
+            .iter_mut()
+            .map(|principal| &mut principal.id)
+            .chain(&mut self.resources);
+
+        for resource_id in resource_ids {
+            for part in resource_id.iter_mut() {
+                transformation::transform_resource(rules, part, None);
+            }
+        }
+
+        for event_type in self
+            .principals
+            .iter_mut()
+            .filter_map(|principal| principal.event.as_mut())
+        {
+            transformation::transform_event_type(rules, event_type);
+        }
 
-use crate::{
-    Result,
-    account::Account,
-    db::QueryCheckFirstRealError,
-    next_binding,
-    resource::{ResourceId, ResourceIdPart, surrealdb_thing_from_resource_id},
-    value::surrealdb_value_from_json_value,
-};
+        for event in &mut self.events {
+            transformation::transform_event_type(rules, &mut event.r#type);
+        }
+    }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
-#[serde(deny_unknown_fields)]
-struct Principal {
-    id: ResourceId,
-    event: Option<String>,
+    // Collects the `[principal, resource, type]` keys of the events upserted for this capture
+    fn event_keys(self, event_keys: &mut Vec<surrealdb::sql::Value>) {
+        for principal in self.principals {
+            let principal_id = surrealdb_thing_from_resource_id(principal.id);
+
+            for resource in &self.resources {
+                let resource_id = surrealdb_thing_from_resource_id(resource.clone());
+
+                for event in &self.events {
+                    event_keys.push(
+                        vec![
+                            principal_id.clone(),
+                            resource_id.clone(),
+                            surrealdb::sql::Value::from(event.r#type.as_str()),
+                        ]
+                        .into(),
+                    );
+                }
+            }
+        }
+    }
 }
 
-impl From<Principal> for surrealdb::sql::Value {
-    fn from(value: Principal) -> Self {
-        surrealdb::sql::Object::from(HashMap::from([
-            ("id", surrealdb::sql::Value::from(value.id)),
-            ("event", value.event.into()),
-        ]))
-        .into()
+impl std::fmt::Display for IngestionWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+
+        write!(f, "{}: {}", self.path, self.message)
     }
 }
 
-fn surrealdb_value_from_principal_chain(principal_chain: Vec<Principal>) -> surrealdb::sql::Value {
-    surrealdb::sql::Array::from(
-        principal_chain
-            .into_iter()
-            .map(surrealdb::sql::Value::from)
-            .collect::<Vec<_>>(),
-    )
-    .into()
+impl Request {
+    // Parses and validates a JSON report the way `/report` does, returning it with its warnings
+    pub(crate) fn parse_checked(body: &[u8]) -> Result<(Self, Vec<IngestionWarning>)> {
+        let report = match Request::from_json(body) {
+            Ok(report) => report,
+            Err(err) => bad_request!("Invalid report: {err}"),
+        };
+
+        if report.schema_version == 0 {
+            bad_request!("Report schema version must be at least 1");
+        }
+
+        report.validate()?;
+
+        let warnings = report.warnings();
+
+        Ok((report, warnings))
+    }
+
+    // Parses and validates a streamed report the way `/report/stream` does, returning its records assembled into a single
+    // report with the warnings of each record
+    pub(crate) fn parse_checked_stream(body: &[u8]) -> Result<(Self, Vec<IngestionWarning>)> {
+        let mut report = Request::default();
+        let mut warnings = vec![];
+
+        for (index, line) in body.split(|&byte| byte == b'\n').enumerate() {
+            let line_number = index + 1;
+
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+
+            let record = match serde_json::from_slice::<StreamRecord>(line) {
+                Ok(record) => record,
+                Err(err) => bad_request!("Invalid report record on line {line_number}: {err}"),
+            };
+
+            record.validate(line_number)?;
+
+            warnings.extend(record.warnings(line_number));
+
+            match record {
+                StreamRecord::ResourceCapture(resource_capture) => {
+                    report.resource_captures.push(resource_capture);
+                }
+                StreamRecord::EventCapture(event_capture) => {
+                    report.event_captures.push(event_capture);
+                }
+            }
+        }
+
+        Ok((report, warnings))
+    }
+
+    // IDs of the distinct resources ingesting the report would upsert, in the order they first appear
+    pub(crate) fn planned_resource_ids(&self) -> Vec<ResourceId> {
+        let mut seen = HashSet::new();
+        let mut resource_ids = vec![];
+
+        for resource_capture in &self.resource_captures {
+            resource_capture.planned_resource_ids(&mut vec![], &mut seen, &mut resource_ids);
+        }
+
+        resource_ids
+    }
+
+    // `(principal, type, resource)` of the distinct events ingesting the report would upsert, in the order they first
+    // appear
+    pub(crate) fn planned_events(&self) -> Vec<(&ResourceId, &str, &ResourceId)> {
+        let mut seen = HashSet::new();
+        let mut events = vec![];
+
+        for event_capture in &self.event_captures {
+            for principal in &event_capture.principals {
+                for resource in &event_capture.resources {
+                    for event in &event_capture.events {
+                        let event = (&principal.id, event.r#type.as_str(), resource);
+
+                        if seen.insert(event) {
+                            events.push(event);
+                        }
+                    }
+                }
+            }
+        }
+
+        events
+    }
 }
 
-// TODO: Implement deserializer to handle unknown fields. Serde's built-in
-// unknown field handling doesn't work with its flatten option.
-#[derive(Debug, Deserialize)]
-struct ResourceTreeNode {
-    #[serde(flatten)]
-    id: ResourceIdPart,
-    globally_unique: Option<bool>,
-    first_seen_at: DateTime<Utc>,
-    last_seen_at: DateTime<Utc>,
-    attributes: Option<serde_json::Map<String, serde_json::Value>>,
-    contains: Option<Vec<ResourceTreeNode>>,
+impl ResourceTreeNode {
+    // Collects the IDs of the resources upserted for the tree rooted at this node, skipping IDs already collected
+    fn planned_resource_ids(
+        &self,
+        prefix: &mut Vec<ResourceIdPart>,
+        seen: &mut HashSet<ResourceId>,
+        resource_ids: &mut Vec<ResourceId>,
+    ) {
+        let mut globally_unique_prefix = vec![];
+
+        let prefix = match self.globally_unique {
+            Some(true) => &mut globally_unique_prefix,
+            _ => prefix,
+        };
+
+        prefix.push(self.id.clone());
+
+        let resource_id = ResourceId::from(prefix.clone());
+        if seen.insert(resource_id.clone()) {
+            resource_ids.push(resource_id);
+        }
+
+        for child in self.contains.iter().flatten() {
+            child.planned_resource_ids(prefix, seen, resource_ids);
+        }
+
+        prefix.pop();
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 #[serde(deny_unknown_fields)]
-struct Event {
-    r#type: String,
-    first_seen_at: DateTime<Utc>,
-    last_seen_at: DateTime<Utc>,
+pub(crate) struct ReportParams {
+    // Validates the report and returns what ingesting it would do, without writing anything
+    #[serde(default)]
+    dry_run: bool,
+    // When the report is acknowledged. Takes precedence over a `Prefer: respond-async` header.
+    ack: Option<ReportAck>,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
-struct EventCapture {
-    principals: Vec<Principal>,
-    resources: Vec<ResourceId>,
-    events: Vec<Event>,
+// Point at which a report is acknowledged, trading latency for durability
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ReportAck {
+    // Once the report is persisted to the report job queue, to be ingested in the background
+    Received,
+    // Once the report's ingestion transaction is committed
+    Committed,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub(super) struct Request {
-    resource_captures: Vec<ResourceTreeNode>,
-    event_captures: Vec<EventCapture>,
+// Whether the IDs of created resources are collected during ingestion, which is only needed to publish them
+fn collects_created_resources() -> bool {
+    #[cfg(feature = "archodex-com")]
+    {
+        crate::eventbridge::enabled()
+    }
+
+    #[cfg(not(feature = "archodex-com"))]
+    {
+        false
+    }
 }
 
 #[instrument(skip_all)]
 fn upsert_resource_tree_node<'a>(
-    mut query: Query<'a, Any>,
+    mut query: TracedQuery<'a>,
+    account_id: &str,
+    subscribed_event_types: &SubscribedEventTypes,
     prefix: &mut surrealdb::sql::Array,
     resource_tree_node: ResourceTreeNode,
-) -> Query<'a, Any> {
+) -> anyhow::Result<TracedQuery<'a>> {
     // INSERT INTO resource (id, first_seen_at, last_seen_at) VALUES (<id>, <first_seen_at>, <last_seen_at>) ON DUPLICATE KEY UPDATE last_seen_at = <last_seen_at> RETURN NONE
     let mut resource_upsert = InsertStatement::default();
     resource_upsert.into = Some(surrealdb::sql::Table::from("resource").into());
@@ -120,11 +1270,31 @@ fn upsert_resource_tree_node<'a>(
         resource_tree_node.last_seen_at.into(),
     )]));
 
-    resource_upsert.output = Some(surrealdb::sql::Output::None);
+    // Only records that already existed have a value before the upsert
+    resource_upsert.output = Some(surrealdb::sql::Output::Before);
 
     info!("Resource upsert: {resource_upsert}");
 
-    query = query.query(resource_upsert);
+    let enqueue_resource_created = enqueue_event_statement(
+        &DeliveredEvent::ResourceCreated {
+            account_id: account_id.to_owned(),
+            occurred_at: clock::now(),
+            resource_id: ResourceId::try_from(prefix.clone())?,
+        },
+        subscribed_event_types,
+    )?;
+
+    query = if collects_created_resources() {
+        let resource_id = surrealdb::sql::Value::from(prefix.clone());
+
+        query.query(format!(
+            "$created_resources = IF ({resource_upsert})[0] IS NONE {{ {enqueue_resource_created} array::append($created_resources, {resource_id}) }} ELSE {{ $created_resources }};"
+        ))
+    } else {
+        query.query(format!(
+            "$resources_created = $resources_created + IF ({resource_upsert})[0] IS NONE {{ {enqueue_resource_created} 1 }} ELSE {{ 0 }};"
+        ))
+    };
 
     if let Some(attributes) = resource_tree_node.attributes
         && !attributes.is_empty()
@@ -155,44 +1325,66 @@ fn upsert_resource_tree_node<'a>(
 
     if let Some(children) = resource_tree_node.contains {
         for child in children {
-            query = upsert_resource_tree_node(query, prefix, child);
+            query = upsert_resource_tree_node(
+                query,
+                account_id,
+                subscribed_event_types,
+                prefix,
+                child,
+            )?;
         }
     }
 
     prefix.pop();
 
-    query
+    Ok(query)
 }
 
 #[allow(clippy::too_many_lines)]
 #[instrument(skip_all)]
-fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, Any> {
-    let first_seen_at = report
-        .events
-        .iter()
-        .min_by_key(|&event| event.first_seen_at)
-        .unwrap()
-        .first_seen_at;
+fn upsert_events<'a>(
+    mut query: TracedQuery<'a>,
+    account_id: &str,
+    subscribed_event_types: &SubscribedEventTypes,
+    report: EventCapture,
+) -> anyhow::Result<TracedQuery<'a>> {
+    // Captures without events are rejected when reports are validated, but reports enqueued before validation was added
+    // may still contain them. They describe no events, so there's nothing to upsert.
+    let (Some(first_seen_at), Some(last_seen_at)) = (
+        report.events.iter().map(|event| event.first_seen_at).min(),
+        report.events.iter().map(|event| event.last_seen_at).max(),
+    ) else {
+        return Ok(query);
+    };
 
-    let last_seen_at = report
-        .events
-        .iter()
-        .max_by_key(|&event| event.last_seen_at)
-        .unwrap()
-        .last_seen_at;
+    // Without principals or resources there are no events either, and no principal chain to record
+    if report.principals.is_empty() || report.resources.is_empty() {
+        return Ok(query);
+    }
 
     let principal_chain_id_var = next_binding();
     let principals_binding = next_binding();
     let first_seen_at_binding = next_binding();
     let last_seen_at_binding = next_binding();
 
-    let statement = format!(
-        "${principal_chain_id_var} = INSERT INTO principal_chain
-        (id, first_seen_at, last_seen_at)
-        VALUES (${principals_binding}, ${first_seen_at_binding}, ${last_seen_at_binding})
-        ON DUPLICATE KEY UPDATE last_seen_at = ${last_seen_at_binding}
-        RETURN id;"
-    );
+    // Hashed chain IDs keep the chain's index keys a fixed size, however long the chain and its principals' IDs are
+    let statement = if Env::hashed_principal_chain_ids() {
+        format!(
+            "${principal_chain_id_var} = INSERT INTO principal_chain
+            (id, chain, first_seen_at, last_seen_at)
+            VALUES (fn::principal_chain_hash(${principals_binding}), ${principals_binding}, ${first_seen_at_binding}, ${last_seen_at_binding})
+            ON DUPLICATE KEY UPDATE last_seen_at = ${last_seen_at_binding}
+            RETURN id;"
+        )
+    } else {
+        format!(
+            "${principal_chain_id_var} = INSERT INTO principal_chain
+            (id, first_seen_at, last_seen_at)
+            VALUES (${principals_binding}, ${first_seen_at_binding}, ${last_seen_at_binding})
+            ON DUPLICATE KEY UPDATE last_seen_at = ${last_seen_at_binding}
+            RETURN id;"
+        )
+    };
 
     let principals_value = surrealdb_value_from_principal_chain(report.principals.clone());
     let first_seen_at_value = surrealdb::sql::Datetime::from(first_seen_at);
@@ -218,6 +1410,9 @@ fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, A
 
     let last_principal = report.principals.last().cloned();
 
+    // Event types are checked for being new to the account once per capture, with the first event of each type
+    let mut event_types_checked = HashSet::new();
+
     for principal in report.principals {
         let has_direct_principal_chain_value = Some(&principal) == last_principal.as_ref();
         let has_direct_principal_chain_update = if has_direct_principal_chain_value {
@@ -226,7 +1421,7 @@ fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, A
             ""
         };
 
-        let principal_id_value = surrealdb_thing_from_resource_id(principal.id);
+        let principal_id_value = surrealdb_thing_from_resource_id(principal.id.clone());
 
         for resource in &report.resources {
             let resource_id_value = surrealdb_thing_from_resource_id(resource.clone());
@@ -239,15 +1434,38 @@ fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, A
                 let first_seen_at_binding = next_binding();
                 let last_seen_at_binding = next_binding();
 
+                // Only events that already existed have a value before the upsert
                 let statement = format!(
-                    "INSERT RELATION INTO event
+                    "$events_inserted = $events_inserted + IF (INSERT RELATION INTO event
                     (in, out, type, principal_chains, has_direct_principal_chain, first_seen_at, last_seen_at)
                     VALUES (${principal_id_binding}, ${resource_id_binding}, ${type_binding}, [${principal_chain_id_var}[0].id], ${has_direct_principal_chain_binding}, ${first_seen_at_binding}, ${last_seen_at_binding})
                     ON DUPLICATE KEY UPDATE principal_chains += ${principal_chain_id_var}[0].id, last_seen_at = ${last_seen_at_binding}{has_direct_principal_chain_update}
-                    RETURN NONE;"
+                    RETURN BEFORE)[0] IS NONE {{ 1 }} ELSE {{ 0 }};"
                 );
 
                 let type_value = surrealdb::sql::Strand::from(event.r#type.as_str());
+
+                if event_types_checked.insert(event.r#type.as_str()) {
+                    let enqueue_event_type_observed = enqueue_event_statement(
+                        &DeliveredEvent::EventTypeObserved {
+                            account_id: account_id.to_owned(),
+                            occurred_at: clock::now(),
+                            event_type: event.r#type.clone(),
+                            principal: principal.id.clone(),
+                            resource: resource.clone(),
+                        },
+                        subscribed_event_types,
+                    )?;
+
+                    if !enqueue_event_type_observed.is_empty() {
+                        query = query
+                            .query(format!(
+                                "IF array::is_empty(SELECT VALUE id FROM event WHERE type = ${type_binding} LIMIT 1) {{ {enqueue_event_type_observed} }};"
+                            ))
+                            .bind((type_binding.clone(), type_value.clone()));
+                    }
+                }
+
                 let first_seen_at_value = surrealdb::sql::Datetime::from(event.first_seen_at);
                 let last_seen_at_value = surrealdb::sql::Datetime::from(event.last_seen_at);
 
@@ -284,32 +1502,556 @@ fn upsert_events(mut query: Query<'_, Any>, report: EventCapture) -> Query<'_, A
         }
     }
 
-    query
+    Ok(query)
 }
 
-#[instrument(err, skip(account))]
+#[utoipa::path(
+    post,
+    path = "/report",
+    tag = "report",
+    security(("report_api_key" = [])),
+    request_body(
+        description = "A report, either as JSON or as a `Report` message of the protobuf schema in `report.proto`",
+        content(
+            (Request = "application/json"),
+            (String = "application/x-protobuf")
+        )
+    ),
+    params(
+        ("Prefer" = Option<String>, Header, description = "`respond-async` to ingest the report in the background, like `ack=received`. Ignored for dry runs."),
+        ReportParams
+    ),
+    responses(
+        (status = 200, body = IngestionResult),
+        (status = 202, body = ReportJob, description = "The report was accepted for asynchronous ingestion, which can be tracked at the `Location` URL")
+    )
+)]
+#[instrument(err, skip(account, headers))]
 pub(crate) async fn report(
     Extension(account): Extension<Account>,
-    Json(req): Json<Request>,
-) -> Result<()> {
-    let db = account.resources_db().await?;
+    headers: HeaderMap,
+    axum::extract::Query(params): axum::extract::Query<ReportParams>,
+    req: Request,
+) -> Result<Response> {
+    if params.dry_run && params.ack == Some(ReportAck::Received) {
+        bad_request!("ack=received cannot be used for dry runs");
+    }
+
+    let prefers_async = params.ack.is_none() && report_job::prefers_async(&headers);
+
+    let ack = params.ack.unwrap_or(if prefers_async {
+        ReportAck::Received
+    } else {
+        ReportAck::Committed
+    });
+
+    let mut response = if params.dry_run {
+        Json(plan(&account, req).await?).into_response()
+    } else if ack == ReportAck::Received {
+        let num_resources = req.num_resources();
+        let report_job = report_job::enqueue(&account, &req).await?;
+
+        let mut response = (
+            StatusCode::ACCEPTED,
+            [(LOCATION, format!("/report/jobs/{}", report_job.id()))],
+            Extension(ResourcesUpserted(num_resources)),
+            Json(report_job),
+        )
+            .into_response();
+
+        if prefers_async {
+            response.headers_mut().insert(
+                HeaderName::from_static("preference-applied"),
+                HeaderValue::from_static("respond-async"),
+            );
+        }
+
+        response
+    } else {
+        let result = ingest(&account, req).await?;
 
-    let mut query = db.query(BeginStatement::default());
+        (
+            Extension(ResourcesUpserted(result.resources_upserted())),
+            Json(result),
+        )
+            .into_response()
+    };
+
+    response.headers_mut().insert(
+        HeaderName::from_static(REPORT_SCHEMA_VERSION_HEADER),
+        HeaderValue::from(REPORT_SCHEMA_VERSION),
+    );
+
+    Ok(response)
+}
+
+#[utoipa::path(
+    post,
+    path = "/report/stream",
+    tag = "report",
+    security(("report_api_key" = [])),
+    request_body(
+        content = String,
+        content_type = "application/x-ndjson",
+        description = "Newline-delimited JSON records, each either `{\"resource_capture\": ...}` or `{\"event_capture\": ...}` with the same contents as an element of the report's `resource_captures` or `event_captures`"
+    ),
+    responses((status = 200, body = IngestionResult))
+)]
+#[instrument(err, skip(account, body))]
+pub(crate) async fn report_stream(
+    Extension(account): Extension<Account>,
+    mut body: Body,
+) -> Result<Response> {
+    // The report body limit applies to each record rather than to the whole stream
+    let max_record_bytes = Env::report_max_body_bytes();
+
+    let mut buffer = Vec::<u8>::new();
+    let mut scanned = 0;
+    let mut line_number = 0;
+
+    let mut batch = Request::default();
+    let mut batch_size = 0;
+    let mut resource_captures = 0;
+    let mut event_captures = 0;
+    let mut result = IngestionResult::default();
+
+    let mut add_record = async |line: &[u8], line_number: usize| -> Result<()> {
+        if line.trim_ascii().is_empty() {
+            return Ok(());
+        }
+
+        let record = match serde_json::from_slice::<StreamRecord>(line) {
+            Ok(record) => record,
+            Err(err) => bad_request!("Invalid report record on line {line_number}: {err}"),
+        };
+
+        record.validate(line_number)?;
+
+        result.warnings.extend(record.warnings(line_number));
+
+        match record {
+            StreamRecord::ResourceCapture(resource_capture) => {
+                batch_size += resource_capture.num_resources();
+                resource_captures += 1;
+                batch.resource_captures.push(resource_capture);
+            }
+            StreamRecord::EventCapture(event_capture) => {
+                batch_size += event_capture.num_events();
+                event_captures += 1;
+                batch.event_captures.push(event_capture);
+            }
+        }
+
+        if batch_size >= STREAM_BATCH_SIZE {
+            result.merge(
+                timeout::within(
+                    Env::report_timeout(),
+                    ingest_batch(&account, std::mem::take(&mut batch), None),
+                )
+                .await?,
+            );
+            batch_size = 0;
+        }
+
+        Ok(())
+    };
+
+    loop {
+        let frame =
+            match tokio::time::timeout(Env::report_stream_idle_timeout(), body.frame()).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(_) => {
+                    return Err(PublicError::new(
+                        StatusCode::REQUEST_TIMEOUT,
+                        "Report stream sent no data for too long",
+                    )
+                    .with_code("report_stream_idle"));
+                }
+            };
+
+        let Ok(frame) = frame else {
+            bad_request!("Failed to read report stream");
+        };
+
+        let Ok(data) = frame.into_data() else {
+            continue;
+        };
+
+        buffer.extend_from_slice(&data);
+
+        while let Some(newline) = buffer[scanned..].iter().position(|&byte| byte == b'\n') {
+            let line = buffer.drain(..=scanned + newline).collect::<Vec<_>>();
+            scanned = 0;
+            line_number += 1;
+            add_record(&line, line_number).await?;
+        }
 
+        scanned = buffer.len();
+
+        if buffer.len() > max_record_bytes {
+            return Err(PublicError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Report record on line {} is too large", line_number + 1),
+            ));
+        }
+    }
+
+    // The last record may not be followed by a newline
+    add_record(&buffer, line_number + 1).await?;
+
+    // Earlier batches were committed as they filled, so a stream that fails part way through is partially ingested.
+    // Ingestion only upserts, so agents can safely resend the whole stream.
+    result.merge(
+        timeout::within(
+            Env::report_timeout(),
+            ingest_batch(
+                &account,
+                batch,
+                Some(&DeliveredEvent::ReportIngested {
+                    account_id: account.id().to_owned(),
+                    occurred_at: clock::now(),
+                    resource_captures,
+                    event_captures,
+                }),
+            ),
+        )
+        .await?,
+    );
+
+    Ok((
+        Extension(ResourcesUpserted(result.resources_upserted())),
+        Json(result),
+    )
+        .into_response())
+}
+
+// Upserts the contents of a report into the account's resources database in a single transaction. This is shared by
+// every ingestion path, e.g. the `/report` route and the Kafka report consumer.
+#[instrument(err, skip_all)]
+pub(crate) async fn ingest(account: &Account, req: Request) -> Result<IngestionResult> {
+    let warnings = req.warnings();
+
+    let ingested_event = DeliveredEvent::ReportIngested {
+        account_id: account.id().to_owned(),
+        occurred_at: clock::now(),
+        resource_captures: req.resource_captures.len(),
+        event_captures: req.event_captures.len(),
+    };
+
+    let mut result = ingest_batch(account, req, Some(&ingested_event)).await?;
+    result.warnings = warnings;
+
+    Ok(result)
+}
+
+// Returns the result ingesting a report would have without writing anything. SurrealDB discards the results of every
+// statement in a cancelled transaction, so rather than running the upserts and rolling them back, the records they would
+// create are looked up in a read-only transaction.
+#[instrument(err, skip_all)]
+async fn plan(account: &Account, mut req: Request) -> Result<IngestionResult> {
+    req.transform(account.transformations());
+
+    let warnings = req.warnings();
+    let num_resources = req.num_resources();
+    let num_events = req.num_events();
+
+    let mut resource_ids = vec![];
     for resource_tree_node in req.resource_captures {
-        query =
-            upsert_resource_tree_node(query, &mut surrealdb::sql::Array::new(), resource_tree_node);
+        resource_tree_node.resource_ids(&mut surrealdb::sql::Array::new(), &mut resource_ids);
+    }
+
+    let mut event_keys = vec![];
+    for event_capture in req.event_captures {
+        event_capture.event_keys(&mut event_keys);
+    }
+
+    let mut res = account
+        .resources_db()
+        .await?
+        .query(BeginReadonlyStatement)
+        .query(DRY_RUN_RESULT)
+        .query(CommitStatement::default())
+        .bind(("resources", resource_ids))
+        .bind(("events", event_keys))
+        .await?
+        .check_first_real_error()?;
+
+    let counts = res
+        .take::<Option<IngestionCounts>>(0)?
+        .context("Report dry run query did not return a result")?;
+
+    let mut result = IngestionResult::from_counts(counts, num_resources, num_events);
+    result.warnings = warnings;
+
+    Ok(result)
+}
+
+// Builds the transaction upserting a batch of captures, enqueueing `ingested_event` for delivery in the same transaction.
+// The transaction's last statement returns the `IngestionCounts`.
+fn ingestion_query<'a>(
+    db: &'a Surreal<Any>,
+    account_id: &str,
+    subscribed_event_types: &SubscribedEventTypes,
+    ingested_event: Option<&DeliveredEvent>,
+    batch: Request,
+) -> anyhow::Result<TracedQuery<'a>> {
+    let mut query = TracedQuery::new(db, "report_ingest")
+        .query(BeginStatement::default())
+        .query(INGESTION_COUNTERS)
+        .query(SELECT_EVENT_DESTINATIONS);
+
+    if let Some(ingested_event) = ingested_event {
+        let enqueue_ingested_event =
+            enqueue_event_statement(ingested_event, subscribed_event_types)?;
+
+        if !enqueue_ingested_event.is_empty() {
+            query = query.query(enqueue_ingested_event);
+        }
+    }
+
+    for resource_tree_node in batch.resource_captures {
+        query = upsert_resource_tree_node(
+            query,
+            account_id,
+            subscribed_event_types,
+            &mut surrealdb::sql::Array::new(),
+            resource_tree_node,
+        )?;
     }
 
-    for events_report in req.event_captures {
-        query = upsert_events(query, events_report);
+    for events_report in batch.event_captures {
+        query = upsert_events(query, account_id, subscribed_event_types, events_report)?;
     }
 
-    query = query.query(CommitStatement::default());
+    query = query
+        .query(INGESTION_RESULT)
+        .query(CommitStatement::default());
 
     info!("Full query:\n{query:?}");
 
-    query.await?.check_first_real_error()?;
+    Ok(query)
+}
+
+// Upserts a batch of captures in a single transaction, enqueueing `ingested_event` for delivery in the same transaction.
+// The result doesn't include warnings, which are collected from the whole report by the caller.
+#[instrument(err, skip_all)]
+async fn ingest_batch(
+    account: &Account,
+    mut batch: Request,
+    ingested_event: Option<&DeliveredEvent>,
+) -> Result<IngestionResult> {
+    batch.transform(account.transformations());
+
+    let enrichers = enrichment::enrichers(account);
+    if !enrichers.is_empty() {
+        for resource_tree_node in &mut batch.resource_captures {
+            resource_tree_node.enrich(&mut vec![], &enrichers);
+        }
+    }
+
+    let db = account.resources_db().await?;
+
+    let num_resources = batch.num_resources();
+    let num_events = batch.num_events();
+
+    let subscribed_event_types = SubscribedEventTypes::get(&db).await?;
+
+    let mut attempt = 1;
+
+    let mut res = loop {
+        // The query consumes the batch, so it's rebuilt from a copy for each attempt
+        let query = ingestion_query(
+            &db,
+            account.id(),
+            &subscribed_event_types,
+            ingested_event,
+            batch.clone(),
+        )?;
+
+        match query
+            .execute()
+            .await
+            .and_then(QueryCheckFirstRealError::check_first_real_error)
+        {
+            Ok(res) => break res,
+            Err(err) if attempt < MAX_INGEST_ATTEMPTS && is_transaction_conflict_error(&err) => {
+                warn!(
+                    attempt,
+                    "Report ingestion conflicted with a concurrent transaction, retrying"
+                );
+                tokio::time::sleep(INGEST_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+
+    let mut counts = res
+        .take::<Option<IngestionCounts>>(res.num_statements() - 1)?
+        .context("Report ingestion query did not return a result")?;
+
+    counts.revision = revision::bump(&db).await?;
+
+    metrics::record_report_ingested(num_resources, num_events);
+
+    if !subscribed_event_types.is_empty() {
+        event_delivery::wake_worker(account.id());
+    }
+
+    #[cfg(feature = "archodex-com")]
+    crate::eventbridge::publish(crate::eventbridge::PlatformEvent::resources_created(
+        account.id(),
+        std::mem::take(&mut counts.created_resources),
+    ));
+
+    Ok(IngestionResult::from_counts(
+        counts,
+        num_resources,
+        num_events,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::Surreal;
+
+    use super::*;
+
+    fn resource_id(id: &str) -> ResourceId {
+        ResourceId::from(vec![
+            ResourceIdPart {
+                r#type: "AWS Partition".to_string(),
+                id: "aws".to_string(),
+            },
+            ResourceIdPart {
+                r#type: "IAM Role".to_string(),
+                id: id.to_string(),
+            },
+        ])
+    }
+
+    fn event_capture(principals: usize, resources: usize, events: usize) -> EventCapture {
+        let now = clock::now();
+
+        EventCapture {
+            principals: (0..principals)
+                .map(|index| Principal {
+                    id: resource_id(&format!("principal-{index}")),
+                    event: None,
+                })
+                .collect(),
+            resources: (0..resources)
+                .map(|index| resource_id(&format!("resource-{index}")))
+                .collect(),
+            events: (0..events)
+                .map(|_| Event {
+                    r#type: "sts:AssumeRole".to_string(),
+                    first_seen_at: now,
+                    last_seen_at: now,
+                })
+                .collect(),
+        }
+    }
+
+    fn invalid_paths(event_capture: &EventCapture) -> Vec<String> {
+        let mut invalid_items = vec![];
+        event_capture.validate("/event_captures/0", &mut invalid_items);
+
+        invalid_items
+            .into_iter()
+            .map(|invalid_item| invalid_item.path)
+            .collect()
+    }
+
+    fn upserted_statements(event_capture: EventCapture) -> usize {
+        let db = Surreal::<surrealdb::engine::any::Any>::init();
+        let query = TracedQuery::new(&db, "report");
+
+        upsert_events(
+            query,
+            "1234567890",
+            &SubscribedEventTypes::default(),
+            event_capture,
+        )
+        .expect("Event capture should be upserted")
+        .num_statements()
+    }
+
+    #[test]
+    fn valid_event_capture() {
+        assert!(invalid_paths(&event_capture(2, 1, 1)).is_empty());
+    }
+
+    #[test]
+    fn empty_event_captures_are_invalid() {
+        assert_eq!(
+            invalid_paths(&event_capture(0, 1, 1)),
+            ["/event_captures/0/principals"]
+        );
+        assert_eq!(
+            invalid_paths(&event_capture(1, 0, 1)),
+            ["/event_captures/0/resources"]
+        );
+        assert_eq!(
+            invalid_paths(&event_capture(1, 1, 0)),
+            ["/event_captures/0/events"]
+        );
+        assert_eq!(
+            invalid_paths(&event_capture(0, 0, 0)),
+            [
+                "/event_captures/0/principals",
+                "/event_captures/0/resources",
+                "/event_captures/0/events"
+            ]
+        );
+    }
+
+    #[test]
+    fn event_capture_with_empty_resource_id_is_invalid() {
+        let mut event_capture = event_capture(1, 1, 1);
+        event_capture.resources[0] = ResourceId::from(vec![]);
+
+        assert_eq!(
+            invalid_paths(&event_capture),
+            ["/event_captures/0/resources/0"]
+        );
+    }
+
+    #[test]
+    fn event_capture_with_too_many_principals_is_invalid() {
+        let event_capture = event_capture(Env::max_principal_chain_length() + 1, 1, 1);
+
+        assert_eq!(
+            invalid_paths(&event_capture),
+            ["/event_captures/0/principals"]
+        );
+    }
+
+    #[test]
+    fn report_with_empty_event_capture_is_rejected() {
+        let report = Request {
+            event_captures: vec![event_capture(1, 1, 1), event_capture(1, 1, 0)],
+            ..Request::default()
+        };
+
+        let err = report.validate().expect_err("Report should be rejected");
+        assert!(
+            format!("{err:?}").contains("/event_captures/1/events: must not be empty"),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn empty_event_captures_upsert_nothing() {
+        assert_eq!(upserted_statements(event_capture(0, 1, 1)), 0);
+        assert_eq!(upserted_statements(event_capture(1, 0, 1)), 0);
+        assert_eq!(upserted_statements(event_capture(1, 1, 0)), 0);
+        assert_eq!(upserted_statements(event_capture(0, 0, 0)), 0);
+    }
 
-    Ok(())
+    #[test]
+    fn event_captures_upsert_their_principal_chain_and_events() {
+        assert!(upserted_statements(event_capture(2, 2, 2)) > 1);
+    }
 }
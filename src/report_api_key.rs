@@ -2,25 +2,51 @@ pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/archodex.report_api_key.rs"));
 }
 
+use std::net::IpAddr;
+
 use aes_gcm::{
     AeadCore, Aes128Gcm, KeyInit,
     aead::{self, Aead},
 };
 use base64::prelude::*;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use prost::Message;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use archodex_error::anyhow::{self, Context as _, anyhow, bail, ensure};
-use tracing::instrument;
+use tracing::{instrument, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    clock,
+    db::{
+        DBConnection, PreparedQuery, QueryBuilder, QueryCheckFirstRealError as _,
+        is_record_exists_error,
+    },
+    env::Env,
+    next_binding, rng, surrealdb_deserializers,
+    user::User,
+};
+
+// Version 1 keys have 6 digit IDs, which collide often enough in accounts with many keys that version 2 keys widened them
+// to 10 digits. Both versions are accepted, but only version 2 keys are created.
+const REPORT_API_KEY_VERSION: u32 = 2;
+const V1_KEY_ID_RANGE: std::ops::RangeInclusive<u32> = 100_000..=999_999;
+const V2_KEY_ID_RANGE: std::ops::RangeInclusive<u32> = 1_000_000_000..=u32::MAX;
+
+// Creation gives up after this many key ID collisions in a row, which is vanishingly unlikely unless the RNG is broken
+pub(crate) const MAX_CREATE_ATTEMPTS: usize = 5;
 
-use crate::{env::Env, next_binding, surrealdb_deserializers, user::User};
+// Keys are used for every report, so their last use is only written when it's older than this or the key is used from a
+// different address. This keeps agents reporting often from writing the key record on every report.
+const LAST_USED_UPDATE_INTERVAL: TimeDelta = TimeDelta::minutes(1);
 
 #[derive(Clone, Debug, Deserialize)]
 pub(crate) struct ReportApiKey {
     #[serde(deserialize_with = "surrealdb_deserializers::u32::deserialize")]
     id: u32,
+    version: u32,
     description: Option<String>,
     created_at: Option<DateTime<Utc>>,
     created_by: User,
@@ -28,14 +54,25 @@ pub(crate) struct ReportApiKey {
     revoked_at: Option<DateTime<Utc>>,
     #[allow(dead_code)]
     revoked_by: Option<User>,
+    #[serde(default)]
+    suspended_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+    last_used_from: Option<String>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, ToSchema)]
 pub(crate) struct ReportApiKeyPublic {
     #[serde(deserialize_with = "surrealdb_deserializers::u32::deserialize")]
     id: u32,
     description: Option<String>,
     created_at: Option<DateTime<Utc>>,
+    /// Set while the key is suspended by a freeze of the account's report API keys
+    suspended_at: Option<DateTime<Utc>>,
+    /// When the key was last used to authenticate, accurate to within a minute. Unset if the key was never used.
+    last_used_at: Option<DateTime<Utc>>,
+    /// Client IP address the key was last used from. Unset if the key was never used or was last used without a known
+    /// address, e.g. through the Kafka report consumer.
+    last_used_from: Option<String>,
 }
 
 impl From<ReportApiKey> for ReportApiKeyPublic {
@@ -44,33 +81,106 @@ impl From<ReportApiKey> for ReportApiKeyPublic {
             id: record.id,
             description: record.description,
             created_at: record.created_at,
+            suspended_at: record.suspended_at,
+            last_used_at: record.last_used_at,
+            last_used_from: record.last_used_from,
         }
     }
 }
 
 impl ReportApiKey {
-    pub(crate) fn new(description: Option<String>, created_by: User) -> Self {
+    fn new(description: Option<String>, created_by: User) -> Self {
         Self {
-            id: rand::thread_rng().gen_range::<u32, _>(100_000..=999_999),
+            id: rng::with_rng(|rng| rng.gen_range(V2_KEY_ID_RANGE)),
+            version: REPORT_API_KEY_VERSION,
             description,
             created_at: None,
             created_by,
             revoked_at: None,
             revoked_by: None,
+            suspended_at: None,
+            last_used_at: None,
+            last_used_from: None,
         }
     }
 
+    // Picks a random ID for a new key and generates its value without creating the key, e.g. to create it as part of a
+    // larger transaction. Creating the key fails if its ID collides with an existing key, so callers should retry with a
+    // newly generated key up to `MAX_CREATE_ATTEMPTS` times.
+    pub(crate) async fn generate(
+        description: Option<String>,
+        created_by: &User,
+        account_id: &str,
+        account_salt: &[u8],
+    ) -> anyhow::Result<(Self, String)> {
+        let report_api_key = Self::new(description, created_by.clone());
+
+        let report_api_key_value = report_api_key
+            .generate_value(account_id, account_salt.to_vec())
+            .await?;
+
+        Ok((report_api_key, report_api_key_value))
+    }
+
+    // Creates a new key in the account's resources database and generates its value. Key IDs are random, so a new ID is
+    // picked if it collides with an existing key rather than failing the request.
+    #[instrument(err, skip(db, account_salt))]
+    pub(crate) async fn create(
+        db: &DBConnection,
+        description: Option<String>,
+        created_by: &User,
+        account_id: &str,
+        account_salt: &[u8],
+    ) -> anyhow::Result<(Self, String)> {
+        for _ in 0..MAX_CREATE_ATTEMPTS {
+            let (report_api_key, report_api_key_value) =
+                Self::generate(description.clone(), created_by, account_id, account_salt).await?;
+
+            let res = db
+                .create_report_api_key_query(&report_api_key)
+                .await?
+                .check_first_real_error();
+
+            match res {
+                Ok(mut res) => {
+                    let report_api_key = res.take::<Option<Self>>(0)?.context(
+                        "Create report API key query should return a report key instance",
+                    )?;
+
+                    return Ok((report_api_key, report_api_key_value));
+                }
+                Err(err) if is_record_exists_error(&err) => {
+                    warn!(
+                        report_api_key_id = report_api_key.id,
+                        "Report key ID collided with an existing key, retrying with a new ID"
+                    );
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        bail!("Failed to create report key: Key IDs collided {MAX_CREATE_ATTEMPTS} times");
+    }
+
     pub(crate) fn id(&self) -> u32 {
         self.id
     }
 
+    pub(crate) fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub(crate) fn is_suspended(&self) -> bool {
+        self.suspended_at.is_some()
+    }
+
     #[instrument(err)]
-    pub(crate) async fn generate_value(
+    async fn generate_value(
         &self,
         account_id: &str,
         account_salt: Vec<u8>,
     ) -> anyhow::Result<String> {
-        let cipher = Aes128Gcm::new(&Env::api_private_key().await);
+        let cipher = Aes128Gcm::new(&Env::api_private_key().await?);
         let nonce = Aes128Gcm::generate_nonce(&mut rand::rngs::OsRng);
 
         let message = proto::ReportApiKeyEncryptedContents {
@@ -97,7 +207,7 @@ impl ReportApiKey {
             .map_err(|err| anyhow!("Failed to encrypt account ID: {err}"))?;
 
         let report_api_key = proto::ReportApiKey {
-            version: 1,
+            version: self.version,
             #[cfg(feature = "archodex-com")]
             endpoint: Some(Env::endpoint().to_owned()),
             #[cfg(not(feature = "archodex-com"))]
@@ -134,11 +244,6 @@ impl ReportApiKey {
             .parse::<u32>()
             .context("Invalid report key value: Key ID is not a number")?;
 
-        ensure!(
-            (100_000..=999_999).contains(&key_id),
-            "Invalid report key value: Key ID is out of range"
-        );
-
         let value = BASE64_STANDARD
             .decode(value)
             .context("Failed to base64 decode report key value")?;
@@ -151,6 +256,17 @@ impl ReportApiKey {
         let value = proto::ReportApiKey::decode(value.as_slice())
             .context("Invalid report key value: Failed to decode report key value as protobuf")?;
 
+        let key_id_range = match value.version {
+            1 => V1_KEY_ID_RANGE,
+            2 => V2_KEY_ID_RANGE,
+            version => bail!("Invalid report key value: Unsupported version {version}"),
+        };
+
+        ensure!(
+            key_id_range.contains(&key_id),
+            "Invalid report key value: Key ID is out of range"
+        );
+
         #[cfg(feature = "archodex-com")]
         {
             let Some(endpoint) = &value.endpoint else {
@@ -175,7 +291,7 @@ impl ReportApiKey {
         );
 
         let nonce = aead::Nonce::<Aes128Gcm>::from_slice(&value.nonce);
-        let cipher = Aes128Gcm::new(&Env::api_private_key().await);
+        let cipher = Aes128Gcm::new(&Env::api_private_key().await?);
 
         let aad = proto::ReportApiKeyEncryptedAad {
             key_id,
@@ -211,28 +327,52 @@ impl ReportApiKey {
 
 pub(crate) trait ReportApiKeyQueries<'r, C: surrealdb::Connection> {
     fn list_report_api_keys_query(&'r self) -> surrealdb::method::Query<'r, C>;
+    fn get_report_api_key_query(
+        &'r self,
+        report_api_key_id: u32,
+    ) -> surrealdb::method::Query<'r, C>;
     fn create_report_api_key_query(
         &'r self,
         report_api_key: &ReportApiKey,
     ) -> surrealdb::method::Query<'r, C>;
+    fn update_report_api_key_description_query(
+        &'r self,
+        report_api_key_id: u32,
+        description: Option<String>,
+    ) -> surrealdb::method::Query<'r, C>;
     fn revoke_report_api_key_query(
         &'r self,
         report_api_key_id: u32,
         revoked_by: &User,
     ) -> surrealdb::method::Query<'r, C>;
-    fn report_api_key_is_valid_query(&'r self, id: u32) -> surrealdb::method::Query<'r, C>;
+    fn freeze_report_api_keys_query(
+        &'r self,
+        suspended_by: &User,
+    ) -> surrealdb::method::Query<'r, C>;
+    fn unfreeze_report_api_keys_query(&'r self) -> surrealdb::method::Query<'r, C>;
+    fn use_report_api_key_query(
+        &'r self,
+        id: u32,
+        used_from: Option<IpAddr>,
+    ) -> surrealdb::method::Query<'r, C>;
     type ReportApiKeyIsValidQueryResponse;
 }
 
 #[derive(Deserialize)]
 pub(crate) struct ReportApiKeyIsValidQueryResponse {
     valid: bool,
+    #[serde(default)]
+    suspended: bool,
 }
 
 impl ReportApiKeyIsValidQueryResponse {
     pub(crate) fn is_valid(&self) -> bool {
         self.valid
     }
+
+    pub(crate) fn is_suspended(&self) -> bool {
+        self.suspended
+    }
 }
 
 impl<'r, C: surrealdb::Connection> ReportApiKeyQueries<'r, C> for surrealdb::Surreal<C> {
@@ -240,32 +380,15 @@ impl<'r, C: surrealdb::Connection> ReportApiKeyQueries<'r, C> for surrealdb::Sur
         self.query("SELECT * FROM report_api_key WHERE type::is::none(revoked_at)")
     }
 
-    fn create_report_api_key_query(
-        &'r self,
-        report_api_key: &ReportApiKey,
-    ) -> surrealdb::method::Query<'r, C> {
-        let report_api_key_binding = next_binding();
-        let description_binding = next_binding();
-        let created_by_binding = next_binding();
-
-        self
-            .query(format!("CREATE ${report_api_key_binding} CONTENT {{ description: ${description_binding}, created_by: ${created_by_binding} }}"))
-            .bind((report_api_key_binding, surrealdb::sql::Thing::from(report_api_key)))
-            .bind((description_binding, report_api_key.description.clone()))
-            .bind((created_by_binding, surrealdb::sql::Thing::from(&report_api_key.created_by)))
-    }
-
-    fn revoke_report_api_key_query(
+    fn get_report_api_key_query(
         &'r self,
         report_api_key_id: u32,
-        revoked_by: &User,
     ) -> surrealdb::method::Query<'r, C> {
         let report_api_key_binding = next_binding();
-        let revoked_by_binding = next_binding();
 
-        self.query(
-            format!("UPDATE ${report_api_key_binding} SET revoked_at = time::now(), revoked_by = ${revoked_by_binding} WHERE revoked_at IS NONE"),
-        )
+        self.query(format!(
+            "SELECT * FROM ${report_api_key_binding} WHERE type::is::none(revoked_at)"
+        ))
         .bind((
             report_api_key_binding,
             surrealdb::sql::Thing::from((
@@ -273,17 +396,115 @@ impl<'r, C: surrealdb::Connection> ReportApiKeyQueries<'r, C> for surrealdb::Sur
                 surrealdb::sql::Id::from(i64::from(report_api_key_id)),
             )),
         ))
-        .bind((revoked_by_binding, surrealdb::sql::Thing::from(revoked_by)))
     }
 
-    fn report_api_key_is_valid_query(
+    fn create_report_api_key_query(
+        &'r self,
+        report_api_key: &ReportApiKey,
+    ) -> surrealdb::method::Query<'r, C> {
+        create_report_api_key_statement(self, report_api_key)
+    }
+
+    fn update_report_api_key_description_query(
         &'r self,
         report_api_key_id: u32,
+        description: Option<String>,
     ) -> surrealdb::method::Query<'r, C> {
-        let report_api_key_binding = next_binding();
+        update_report_api_key_description_statement(self, report_api_key_id, description)
+    }
+
+    fn revoke_report_api_key_query(
+        &'r self,
+        report_api_key_id: u32,
+        revoked_by: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        revoke_report_api_key_statement(self, report_api_key_id, revoked_by)
+    }
+
+    // Suspends every unrevoked key that isn't suspended yet, returning the IDs of the keys suspended
+    fn freeze_report_api_keys_query(
+        &'r self,
+        suspended_by: &User,
+    ) -> surrealdb::method::Query<'r, C> {
+        let suspended_by_binding = next_binding();
+        let now_binding = next_binding();
 
         self.query(format!(
-            "SELECT type::is::none(revoked_at) AS valid FROM ${report_api_key_binding}"
+            "UPDATE report_api_key SET suspended_at = ${now_binding}, suspended_by = ${suspended_by_binding} WHERE revoked_at IS NONE AND suspended_at IS NONE RETURN VALUE record::id(id)"
+        ))
+        .bind((suspended_by_binding, surrealdb::sql::Thing::from(suspended_by)))
+        .bind((now_binding, clock::now_value()))
+    }
+
+    // Reinstates every suspended key that hasn't been revoked since, returning the IDs of the keys reinstated
+    fn unfreeze_report_api_keys_query(&'r self) -> surrealdb::method::Query<'r, C> {
+        self.query("UPDATE report_api_key SET suspended_at = NONE, suspended_by = NONE WHERE revoked_at IS NONE AND suspended_at IS NOT NONE RETURN VALUE record::id(id)")
+    }
+
+    // Checks whether a key is valid and records its use if it is. The first statement returns the validity.
+    fn use_report_api_key_query(
+        &'r self,
+        report_api_key_id: u32,
+        used_from: Option<IpAddr>,
+    ) -> surrealdb::method::Query<'r, C> {
+        // Runs for every authenticated report, so it's only parsed once
+        static QUERY: PreparedQuery = PreparedQuery::new(
+            "SELECT type::is::none(revoked_at) AND type::is::none(suspended_at) AS valid, type::is::none(revoked_at) AND type::is::datetime(suspended_at) AS suspended FROM $report_api_key;
+            UPDATE $report_api_key SET last_used_at = $now, last_used_from = $used_from WHERE revoked_at IS NONE AND suspended_at IS NONE AND (last_used_at IS NONE OR last_used_at < $stale_before OR last_used_from != $used_from) RETURN NONE;",
+        );
+
+        let now = clock::now();
+
+        self.query(&QUERY)
+            .bind((
+                "report_api_key",
+                surrealdb::sql::Thing::from((
+                    "report_api_key",
+                    surrealdb::sql::Id::from(i64::from(report_api_key_id)),
+                )),
+            ))
+            .bind(("now", surrealdb::sql::Datetime::from(now)))
+            .bind(("used_from", used_from.map(|ip| ip.to_string())))
+            .bind((
+                "stale_before",
+                surrealdb::sql::Datetime::from(now - LAST_USED_UPDATE_INTERVAL),
+            ))
+    }
+
+    type ReportApiKeyIsValidQueryResponse = ReportApiKeyIsValidQueryResponse;
+}
+
+// Creates a key. The statement doesn't begin a transaction, so callers can create keys along with other changes in the
+// same transaction.
+pub(crate) fn create_report_api_key_statement<'r, C: surrealdb::Connection>(
+    query: impl QueryBuilder<'r, C>,
+    report_api_key: &ReportApiKey,
+) -> surrealdb::method::Query<'r, C> {
+    let report_api_key_binding = next_binding();
+    let version_binding = next_binding();
+    let description_binding = next_binding();
+    let created_by_binding = next_binding();
+
+    query
+        .query(format!("CREATE ${report_api_key_binding} CONTENT {{ version: ${version_binding}, description: ${description_binding}, created_by: ${created_by_binding} }}"))
+        .bind((report_api_key_binding, surrealdb::sql::Thing::from(report_api_key)))
+        .bind((version_binding, report_api_key.version))
+        .bind((description_binding, report_api_key.description.clone()))
+        .bind((created_by_binding, surrealdb::sql::Thing::from(&report_api_key.created_by)))
+}
+
+// Sets an unrevoked key's description, like `create_report_api_key_statement` without beginning a transaction
+pub(crate) fn update_report_api_key_description_statement<'r, C: surrealdb::Connection>(
+    query: impl QueryBuilder<'r, C>,
+    report_api_key_id: u32,
+    description: Option<String>,
+) -> surrealdb::method::Query<'r, C> {
+    let report_api_key_binding = next_binding();
+    let description_binding = next_binding();
+
+    query
+        .query(format!(
+            "UPDATE ${report_api_key_binding} SET description = ${description_binding} WHERE revoked_at IS NONE"
         ))
         .bind((
             report_api_key_binding,
@@ -292,9 +513,30 @@ impl<'r, C: surrealdb::Connection> ReportApiKeyQueries<'r, C> for surrealdb::Sur
                 surrealdb::sql::Id::from(i64::from(report_api_key_id)),
             )),
         ))
-    }
+        .bind((description_binding, description))
+}
 
-    type ReportApiKeyIsValidQueryResponse = ReportApiKeyIsValidQueryResponse;
+// Revokes a key unless it's already revoked, like `create_report_api_key_statement` without beginning a transaction
+pub(crate) fn revoke_report_api_key_statement<'r, C: surrealdb::Connection>(
+    query: impl QueryBuilder<'r, C>,
+    report_api_key_id: u32,
+    revoked_by: &User,
+) -> surrealdb::method::Query<'r, C> {
+    let report_api_key_binding = next_binding();
+    let revoked_by_binding = next_binding();
+    let now_binding = next_binding();
+
+    query
+        .query(format!("UPDATE ${report_api_key_binding} SET revoked_at = ${now_binding}, revoked_by = ${revoked_by_binding} WHERE revoked_at IS NONE"))
+        .bind((
+            report_api_key_binding,
+            surrealdb::sql::Thing::from((
+                "report_api_key",
+                surrealdb::sql::Id::from(i64::from(report_api_key_id)),
+            )),
+        ))
+        .bind((revoked_by_binding, surrealdb::sql::Thing::from(revoked_by)))
+        .bind((now_binding, clock::now_value()))
 }
 
 impl From<&ReportApiKey> for surrealdb::sql::Thing {
@@ -305,3 +547,38 @@ impl From<&ReportApiKey> for surrealdb::sql::Thing {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{SeedableRng as _, rngs::StdRng};
+    use surrealdb::Uuid;
+
+    use super::*;
+
+    fn new_key_ids(seed: u64, count: usize) -> Vec<u32> {
+        rng::set_test_rng(Box::new(StdRng::seed_from_u64(seed)));
+
+        (0..count)
+            .map(|_| ReportApiKey::new(None, User::new(Uuid::nil())).id())
+            .collect()
+    }
+
+    #[test]
+    fn key_ids_are_reproducible_with_a_seeded_rng() {
+        assert_eq!(new_key_ids(1, 10), new_key_ids(1, 10));
+        assert_ne!(new_key_ids(1, 10), new_key_ids(2, 10));
+    }
+
+    #[test]
+    fn new_keys_are_version_2() {
+        for key_id in new_key_ids(3, 100) {
+            assert!(
+                V2_KEY_ID_RANGE.contains(&key_id),
+                "{key_id} is out of range"
+            );
+        }
+
+        let report_api_key = ReportApiKey::new(None, User::new(Uuid::nil()));
+        assert_eq!(report_api_key.version, REPORT_API_KEY_VERSION);
+    }
+}
@@ -0,0 +1,206 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use axum::{
+    Extension,
+    body::Body,
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+use chrono::{DateTime, Utc};
+use http_body_util::BodyExt as _;
+use serde::{Deserialize, Serialize};
+use surrealdb::{Surreal, engine::any::Any};
+use tracing::{instrument, warn};
+use utoipa::ToSchema;
+
+use archodex_error::anyhow;
+
+use crate::{
+    account::Account, auth::ReportApiKeyAuth, clock, db::QueryCheckFirstRealError as _,
+    next_binding,
+};
+
+// Error responses are small JSON bodies, so they are buffered to record their message
+const MAX_ERROR_BODY_BYTES: usize = 64 * 1024;
+
+// Recorded error messages are truncated to this many characters
+const MAX_ERROR_MESSAGE_CHARS: usize = 1024;
+
+/// Cumulative ingestion usage of a report API key since it was created.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub(crate) struct ReportApiKeyUsage {
+    reports_submitted: u64,
+    /// Resources upserted by reports ingested synchronously, or that will be upserted by reports accepted for
+    /// asynchronous ingestion. Dry runs don't upsert resources.
+    resources_upserted: u64,
+    /// Bytes of reports submitted, after decompression
+    bytes_ingested: u64,
+    last_report_at: Option<DateTime<Utc>>,
+    /// Status and message of the last report that failed, e.g. `400 Bad Request: Invalid protobuf report`
+    last_error: Option<String>,
+    last_error_at: Option<DateTime<Utc>>,
+}
+
+// Set on report responses by handlers so the usage middleware knows how many resources the report upserted
+#[derive(Clone, Copy)]
+pub(crate) struct ResourcesUpserted(pub(crate) usize);
+
+pub(crate) fn report_api_key_usage_thing(report_api_key_id: u32) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from((
+        "report_api_key_usage",
+        surrealdb::sql::Id::from(i64::from(report_api_key_id)),
+    ))
+}
+
+// Returns the usage of a key, which is unset if the key was never used to submit a report
+pub(crate) async fn get(
+    db: &Surreal<Any>,
+    report_api_key_id: u32,
+) -> anyhow::Result<Option<ReportApiKeyUsage>> {
+    Ok(db
+        .query("SELECT * FROM $report_api_key_usage")
+        .bind((
+            "report_api_key_usage",
+            report_api_key_usage_thing(report_api_key_id),
+        ))
+        .await?
+        .check_first_real_error()?
+        .take::<Option<ReportApiKeyUsage>>(0)?)
+}
+
+// Adds a submitted report to a key's usage. `error` is set if the report failed.
+#[instrument(err, skip(db, error))]
+pub(crate) async fn record(
+    db: &Surreal<Any>,
+    report_api_key_id: u32,
+    resources_upserted: usize,
+    bytes_ingested: usize,
+    error: Option<String>,
+) -> anyhow::Result<()> {
+    let usage_binding = next_binding();
+    let resources_upserted_binding = next_binding();
+    let bytes_ingested_binding = next_binding();
+    let now_binding = next_binding();
+    let error_binding = next_binding();
+    let error_at_binding = next_binding();
+
+    let now = clock::now_value();
+    let error_at = error.as_ref().map(|_| now.clone());
+
+    db.query(format!(
+        "UPSERT ${usage_binding} SET
+            reports_submitted = (reports_submitted ?? 0) + 1,
+            resources_upserted = (resources_upserted ?? 0) + ${resources_upserted_binding},
+            bytes_ingested = (bytes_ingested ?? 0) + ${bytes_ingested_binding},
+            last_report_at = ${now_binding},
+            last_error = ${error_binding} ?? last_error,
+            last_error_at = ${error_at_binding} ?? last_error_at
+        RETURN NONE"
+    ))
+    .bind((usage_binding, report_api_key_usage_thing(report_api_key_id)))
+    .bind((resources_upserted_binding, resources_upserted))
+    .bind((bytes_ingested_binding, bytes_ingested))
+    .bind((now_binding, now))
+    .bind((error_binding, error))
+    .bind((error_at_binding, error_at))
+    .await?
+    .check_first_real_error()?;
+
+    Ok(())
+}
+
+// Records the usage of the key a report was submitted with. This is added to each report submission route, rather than
+// to every route authenticated with report API keys, so that polling for report jobs isn't counted. It runs inside the
+// decompression layer, so bytes are counted after decompression. Failing to record usage doesn't fail the report.
+pub(crate) async fn track(
+    Extension(auth): Extension<ReportApiKeyAuth>,
+    Extension(account): Extension<Account>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let bytes_ingested = Arc::new(AtomicUsize::new(0));
+
+    let (parts, body) = req.into_parts();
+    let body_bytes_ingested = bytes_ingested.clone();
+    let body = Body::new(body.map_frame(move |frame| {
+        if let Some(data) = frame.data_ref() {
+            body_bytes_ingested.fetch_add(data.len(), Ordering::Relaxed);
+        }
+        frame
+    }));
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    let resources_upserted = response
+        .extensions()
+        .get::<ResourcesUpserted>()
+        .map_or(0, |resources_upserted| resources_upserted.0);
+
+    let (response, error) =
+        if response.status().is_client_error() || response.status().is_server_error() {
+            let (response, error) = with_error_message(response).await;
+            (response, Some(error))
+        } else {
+            (response, None)
+        };
+
+    let res = async {
+        let db = account.resources_db().await?;
+
+        record(
+            &db,
+            auth.key_id(),
+            resources_upserted,
+            bytes_ingested.load(Ordering::Relaxed),
+            error,
+        )
+        .await
+    }
+    .await;
+
+    if let Err(err) = res {
+        warn!(
+            ?err,
+            key_id = auth.key_id(),
+            "Failed to record report API key usage"
+        );
+    }
+
+    response
+}
+
+// Reads the message of an error response, returning the response with its body restored
+async fn with_error_message(response: Response) -> (Response, String) {
+    #[derive(Deserialize)]
+    struct ErrorMessage {
+        message: String,
+    }
+
+    let (parts, body) = response.into_parts();
+    let status = parts.status;
+
+    // Bodies this large aren't error messages, so only the status is kept
+    let Ok(body) = axum::body::to_bytes(body, MAX_ERROR_BODY_BYTES).await else {
+        return (status.into_response(), status.to_string());
+    };
+
+    let message = serde_json::from_slice::<ErrorMessage>(&body).map_or_else(
+        |_| String::from_utf8_lossy(&body).trim().to_string(),
+        |error_message| error_message.message,
+    );
+
+    let error = if message.is_empty() {
+        status.to_string()
+    } else {
+        format!("{status}: {message}")
+    };
+
+    (
+        Response::from_parts(parts, Body::from(body)),
+        error.chars().take(MAX_ERROR_MESSAGE_CHARS).collect(),
+    )
+}
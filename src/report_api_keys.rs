@@ -2,30 +2,49 @@ use std::collections::HashMap;
 
 use axum::{Extension, Json, extract::Path};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tracing::{info, instrument};
+use utoipa::ToSchema;
 
 use archodex_error::{anyhow::bail, bad_request, not_found};
 
 use crate::{
     Result,
     account::Account,
+    audit_log::{self, AuditAction},
     auth::DashboardAuth,
     db::QueryCheckFirstRealError,
     report_api_key::{ReportApiKey, ReportApiKeyPublic, ReportApiKeyQueries},
+    report_api_key_usage::{self, ReportApiKeyUsage},
+    router::RequestId,
 };
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub(crate) struct ListReportApiKeysResponse {
     report_api_keys: Vec<ReportApiKeyPublic>,
+    degraded: bool,
 }
 
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/report_api_keys",
+    tag = "report_api_keys",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = ListReportApiKeysResponse))
+)]
 #[instrument(err, skip_all)]
 pub(crate) async fn list_report_api_keys(
     Extension(account): Extension<Account>,
 ) -> Result<Json<ListReportApiKeysResponse>> {
-    let report_api_keys = account
-        .resources_db()
-        .await?
+    let Some(db) = account.resources_db_if_available().await else {
+        return Ok(Json(ListReportApiKeysResponse {
+            report_api_keys: vec![],
+            degraded: true,
+        }));
+    };
+
+    let report_api_keys = db
         .list_report_api_keys_query()
         .await?
         .check_first_real_error()?
@@ -34,70 +53,211 @@ pub(crate) async fn list_report_api_keys(
         .map(ReportApiKeyPublic::from)
         .collect();
 
-    Ok(Json(ListReportApiKeysResponse { report_api_keys }))
+    Ok(Json(ListReportApiKeysResponse {
+        report_api_keys,
+        degraded: false,
+    }))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub(crate) struct CreateReportApiKeyRequest {
     description: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub(crate) struct CreateReportApiKeyResponse {
     report_api_key: ReportApiKeyPublic,
     report_api_key_value: String,
 }
 
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/report_api_keys",
+    tag = "report_api_keys",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = CreateReportApiKeyRequest,
+    responses((status = 200, body = CreateReportApiKeyResponse))
+)]
 #[instrument(err, skip(auth, account))]
 pub(crate) async fn create_report_api_key(
     Extension(auth): Extension<DashboardAuth>,
     Extension(account): Extension<Account>,
-    Path(params): Path<HashMap<String, String>>,
+    Extension(request_id): Extension<RequestId>,
     Json(req): Json<CreateReportApiKeyRequest>,
 ) -> Result<Json<CreateReportApiKeyResponse>> {
-    let Some(account_id) = params.get("account_id") else {
-        bail!("Missing account ID");
+    let db = account.resources_db().await?;
+
+    let (report_api_key, report_api_key_value) = ReportApiKey::create(
+        &db,
+        req.description,
+        auth.principal(),
+        account.id(),
+        account.salt(),
+    )
+    .await?;
+
+    info!(
+        report_api_key_id = report_api_key.id(),
+        "Created Report API Key"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::ReportApiKeyCreated,
+        Some(json!({ "report_api_key_id": report_api_key.id() })),
+    )
+    .await;
+
+    Ok(Json(CreateReportApiKeyResponse {
+        report_api_key: ReportApiKeyPublic::from(report_api_key),
+        report_api_key_value,
+    }))
+}
+
+fn report_api_key_id_from_params(params: &HashMap<String, String>) -> Result<u32> {
+    let Some(report_api_key_id_string) = params.get("report_api_key_id") else {
+        bail!("Missing report_api_key_id");
+    };
+
+    let Ok(report_api_key_id) = report_api_key_id_string.parse() else {
+        bad_request!("Invalid route key ID");
+    };
+
+    Ok(report_api_key_id)
+}
+
+// Returns a single report API key by ID. This allows clients like the Terraform provider to import existing keys and
+// refresh their state without listing every key in the account.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/report_api_key/{report_api_key_id}",
+    tag = "report_api_keys",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("report_api_key_id" = String, Path)),
+    responses((status = 200, body = ReportApiKeyPublic))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn get_report_api_key(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<ReportApiKeyPublic>> {
+    let report_api_key_id = report_api_key_id_from_params(&params)?;
+
+    let Some(report_api_key) = account
+        .resources_db()
+        .await?
+        .get_report_api_key_query(report_api_key_id)
+        .await?
+        .check_first_real_error()?
+        .take::<Option<ReportApiKey>>(0)?
+    else {
+        not_found!("Report key not found");
     };
 
-    let report_api_key = ReportApiKey::new(req.description, auth.principal().clone());
-    let report_api_key_value = report_api_key
-        .generate_value(account_id, account.salt().to_owned())
-        .await?;
+    Ok(Json(ReportApiKeyPublic::from(report_api_key)))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UpdateReportApiKeyRequest {
+    description: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ReportApiKeyFieldChange {
+    field: &'static str,
+    old: serde_json::Value,
+    new: serde_json::Value,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct UpdateReportApiKeyResponse {
+    report_api_key: ReportApiKeyPublic,
+    changes: Vec<ReportApiKeyFieldChange>,
+}
+
+// Idempotently sets the mutable fields of a report API key. The response lists each field that changed so clients can
+// render plan-style diffs. Repeating the same request results in an empty list of changes and no database write.
+#[utoipa::path(
+    put,
+    path = "/account/{account_id}/report_api_key/{report_api_key_id}",
+    tag = "report_api_keys",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("report_api_key_id" = String, Path)),
+    request_body = UpdateReportApiKeyRequest,
+    responses((status = 200, body = UpdateReportApiKeyResponse))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn update_report_api_key(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+    Json(req): Json<UpdateReportApiKeyRequest>,
+) -> Result<Json<UpdateReportApiKeyResponse>> {
+    let report_api_key_id = report_api_key_id_from_params(&params)?;
 
     let db = account.resources_db().await?;
 
-    let query = db.create_report_api_key_query(&report_api_key);
+    let Some(report_api_key) = db
+        .get_report_api_key_query(report_api_key_id)
+        .await?
+        .check_first_real_error()?
+        .take::<Option<ReportApiKey>>(0)?
+    else {
+        not_found!("Report key not found");
+    };
+
+    if report_api_key.description() == req.description.as_deref() {
+        return Ok(Json(UpdateReportApiKeyResponse {
+            report_api_key: ReportApiKeyPublic::from(report_api_key),
+            changes: vec![],
+        }));
+    }
+
+    let changes = vec![ReportApiKeyFieldChange {
+        field: "description",
+        old: report_api_key.description().into(),
+        new: req.description.as_deref().into(),
+    }];
 
-    let report_api_key = query
+    let Some(report_api_key) = db
+        .update_report_api_key_description_query(report_api_key_id, req.description)
         .await?
         .check_first_real_error()?
         .take::<Option<ReportApiKey>>(0)?
-        .expect("Create report API key query should return a report key instance");
+    else {
+        not_found!("Report key not found");
+    };
 
     info!(
         report_api_key_id = report_api_key.id(),
-        "Created Report API Key"
+        "Updated Report API Key"
     );
 
-    Ok(Json(CreateReportApiKeyResponse {
+    Ok(Json(UpdateReportApiKeyResponse {
         report_api_key: ReportApiKeyPublic::from(report_api_key),
-        report_api_key_value,
+        changes,
     }))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}/report_api_key/{report_api_key_id}",
+    tag = "report_api_keys",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("report_api_key_id" = String, Path)),
+    responses((status = 200))
+)]
 #[instrument(err, skip(auth, account))]
 pub(crate) async fn revoke_report_api_key(
     Extension(auth): Extension<DashboardAuth>,
     Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
     Path(params): Path<HashMap<String, String>>,
 ) -> Result<Json<()>> {
-    let Some(report_api_key_id_string) = params.get("report_api_key_id") else {
-        bail!("Missing report_api_key_id");
-    };
-
-    let Ok(report_api_key_id) = report_api_key_id_string.parse() else {
-        bad_request!("Invalid route key ID");
-    };
+    let report_api_key_id = report_api_key_id_from_params(&params)?;
 
     let report_api_key = account
         .resources_db()
@@ -111,5 +271,146 @@ pub(crate) async fn revoke_report_api_key(
         not_found!("Report key not found");
     }
 
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::ReportApiKeyRevoked,
+        Some(json!({ "report_api_key_id": report_api_key_id })),
+    )
+    .await;
+
     Ok(Json(()))
 }
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct FreezeReportApiKeysResponse {
+    /// IDs of the keys whose state changed
+    report_api_key_ids: Vec<u32>,
+}
+
+// Suspends every report API key of the account at once, without revoking them, e.g. while investigating a compromised
+// environment. Reports sent with suspended keys, including through the SQS and Kafka report consumers, are rejected
+// until the keys are unfrozen. Any member can freeze the keys, and freezing is allowed while the account is read-only,
+// so ingestion can always be stopped quickly. Keys created after freezing are not suspended.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/report_api_keys/freeze",
+    tag = "report_api_keys",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = FreezeReportApiKeysResponse))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn freeze_report_api_keys(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+) -> Result<Json<FreezeReportApiKeysResponse>> {
+    let report_api_key_ids = account
+        .resources_db()
+        .await?
+        .freeze_report_api_keys_query(auth.principal())
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<u32>>(0)?;
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        ?report_api_key_ids,
+        "Froze report API keys"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::ReportApiKeysFrozen,
+        Some(json!({ "report_api_key_ids": report_api_key_ids })),
+    )
+    .await;
+
+    Ok(Json(FreezeReportApiKeysResponse { report_api_key_ids }))
+}
+
+// Reinstates the report API keys suspended by freezing them. Keys revoked while frozen stay revoked. Only the account
+// owner can unfreeze the keys.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/report_api_keys/unfreeze",
+    tag = "report_api_keys",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = FreezeReportApiKeysResponse))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn unfreeze_report_api_keys(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+) -> Result<Json<FreezeReportApiKeysResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    let report_api_key_ids = account
+        .resources_db()
+        .await?
+        .unfreeze_report_api_keys_query()
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<u32>>(0)?;
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        ?report_api_key_ids,
+        "Unfroze report API keys"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::ReportApiKeysUnfrozen,
+        Some(json!({ "report_api_key_ids": report_api_key_ids })),
+    )
+    .await;
+
+    Ok(Json(FreezeReportApiKeysResponse { report_api_key_ids }))
+}
+
+// Returns the ingestion usage of a report API key, e.g. to find out why an agent has gone quiet. Keys that never
+// submitted a report have zero usage.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/report_api_key/{report_api_key_id}/usage",
+    tag = "report_api_keys",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), ("report_api_key_id" = String, Path)),
+    responses((status = 200, body = ReportApiKeyUsage))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn get_report_api_key_usage(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<ReportApiKeyUsage>> {
+    let report_api_key_id = report_api_key_id_from_params(&params)?;
+
+    let db = account.resources_db().await?;
+
+    let report_api_key = db
+        .get_report_api_key_query(report_api_key_id)
+        .await?
+        .check_first_real_error()?
+        .take::<Option<ReportApiKey>>(0)?;
+
+    if report_api_key.is_none() {
+        not_found!("Report key not found");
+    }
+
+    let usage = report_api_key_usage::get(&db, report_api_key_id)
+        .await?
+        .unwrap_or_default();
+
+    Ok(Json(usage))
+}
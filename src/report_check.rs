@@ -0,0 +1,131 @@
+use std::path::Path;
+
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+
+use archodex_error::anyhow::{self, Context as _, bail};
+
+use crate::{report::Request, resource::ResourceId};
+
+// Formats resource IDs like `Partition "aws" > Account "123456789012"`, quoting IDs as they may contain any character
+fn format_resource_id(resource_id: &ResourceId) -> String {
+    resource_id
+        .iter()
+        .map(|part| format!("{} {:?}", part.r#type, part.id))
+        .collect::<Vec<_>>()
+        .join(" > ")
+}
+
+// Streamed reports are recognized by their file extension, as any single line streamed report is also valid JSON
+fn is_stream(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "ndjson" || extension == "jsonl")
+}
+
+/// Validates a report file the way the backend does when ingesting it, then prints the resources and events ingesting
+/// it would upsert along with any warnings. Files with an `.ndjson` or `.jsonl` extension are validated as streamed
+/// reports, as sent to `/report/stream`, and any other file as a JSON report, as sent to `/report`. Validation limits
+/// are read from the same `ARCHODEX_*` environment variables as the backend's.
+///
+/// If a backend URL is given, the report is then posted to its `/report?dry_run=true` route with the report API key in
+/// `ARCHODEX_REPORT_API_KEY`, and the backend's response is printed, which includes how many of the resources and
+/// events would be created rather than updated. Streamed reports are posted as a single JSON report, so their records
+/// must fit within the backend's report body limit together.
+///
+/// # Errors
+///
+/// Returns an error if the file can't be read or the report is invalid, or if posting the report fails or the backend
+/// rejects it.
+pub async fn check_file(path: &Path, backend_url: Option<&str>) -> anyhow::Result<()> {
+    let body = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let (report, warnings) = if is_stream(path) {
+        Request::parse_checked_stream(&body)
+    } else {
+        Request::parse_checked(&body)
+    }
+    .map_err(|err| anyhow::anyhow!("{} is not a valid report: {err}", path.display()))?;
+
+    let resource_ids = report.planned_resource_ids();
+    let events = report.planned_events();
+
+    println!(
+        "{} is a valid report of {} resources and {} events",
+        path.display(),
+        resource_ids.len(),
+        events.len()
+    );
+
+    if !resource_ids.is_empty() {
+        println!();
+        println!("Resources:");
+
+        for resource_id in &resource_ids {
+            println!("  {}", format_resource_id(resource_id));
+        }
+    }
+
+    if !events.is_empty() {
+        println!();
+        println!("Events:");
+
+        for (principal, event_type, resource) in &events {
+            println!(
+                "  {} --{event_type}--> {}",
+                format_resource_id(principal),
+                format_resource_id(resource)
+            );
+        }
+    }
+
+    if !warnings.is_empty() {
+        println!();
+        println!("Warnings:");
+
+        for warning in &warnings {
+            println!("  {warning}");
+        }
+    }
+
+    let Some(backend_url) = backend_url else {
+        return Ok(());
+    };
+
+    let Ok(report_api_key) = std::env::var("ARCHODEX_REPORT_API_KEY") else {
+        bail!("ARCHODEX_REPORT_API_KEY must be set to a report API key to post the report");
+    };
+
+    let body = serde_json::to_vec(&report).context("Failed to serialize report")?;
+
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/report?dry_run=true",
+            backend_url.trim_end_matches('/')
+        ))
+        .header(AUTHORIZATION, report_api_key)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .context("Failed to post report to backend")?;
+
+    let status = response.status();
+    let response = response
+        .text()
+        .await
+        .context("Failed to read backend response")?;
+
+    if !status.is_success() {
+        bail!("Backend rejected report with {status}: {response}");
+    }
+
+    let result = serde_json::from_str::<serde_json::Value>(&response)
+        .context("Backend responded with invalid JSON")?;
+
+    println!();
+    println!("Dry run result from {backend_url}:");
+    println!("{}", serde_json::to_string_pretty(&result)?);
+
+    Ok(())
+}
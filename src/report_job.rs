@@ -0,0 +1,295 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use axum::{Extension, Json, extract::Path, http::HeaderMap};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use archodex_error::{
+    anyhow::{self, Context as _},
+    bad_request, bail, not_found,
+};
+
+use crate::{
+    Result,
+    account::Account,
+    clock,
+    db::QueryCheckFirstRealError as _,
+    report::{self, Request},
+    surrealdb_deserializers,
+    worker::{self, PendingAccounts},
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const BATCH_SIZE: u32 = 10;
+const MAX_ATTEMPTS: u32 = 3;
+
+static PENDING_ACCOUNTS: PendingAccounts = PendingAccounts::new();
+
+// Jobs that have been processing for longer than this are assumed to belong to a worker that died, and are claimed
+// again
+const PROCESSING_TIMEOUT: chrono::Duration = chrono::Duration::minutes(15);
+
+const FINISHED_JOB_RETENTION: chrono::Duration = chrono::Duration::days(7);
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum ReportJobStatus {
+    Pending,
+    Processing,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct ReportJob {
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    id: Uuid,
+    status: ReportJobStatus,
+    attempts: u32,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+impl ReportJob {
+    pub(crate) fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+fn report_job_thing(id: Uuid) -> surrealdb::sql::Thing {
+    surrealdb::sql::Thing::from((
+        "report_job",
+        surrealdb::sql::Id::Uuid(surrealdb::sql::Uuid::from(id)),
+    ))
+}
+
+// Whether the client asked for the report to be ingested asynchronously with a `Prefer: respond-async` header
+// (RFC 7240)
+pub(crate) fn prefers_async(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case("respond-async"))
+}
+
+// Stores a report to be ingested by the report job worker
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+pub(crate) async fn enqueue(account: &Account, report: &Request) -> Result<ReportJob> {
+    let payload = serde_json::to_string(report).context("Failed to serialize report")?;
+
+    let report_job = account
+        .resources_db()
+        .await?
+        .query("CREATE $report_job CONTENT { payload: $payload } RETURN id, status, attempts, error, created_at, started_at, finished_at")
+        .bind(("report_job", report_job_thing(Uuid::now_v7())))
+        .bind(("payload", payload))
+        .await?
+        .check_first_real_error()?
+        .take::<Option<ReportJob>>(0)?
+        .context("Create report job query should return a report job instance")?;
+
+    info!(report_job_id = %report_job.id, "Enqueued report job");
+
+    PENDING_ACCOUNTS.mark(account.id());
+
+    Ok(report_job)
+}
+
+#[utoipa::path(
+    get,
+    path = "/report/jobs/{report_job_id}",
+    tag = "report",
+    security(("report_api_key" = [])),
+    params(("report_job_id" = String, Path)),
+    responses((status = 200, body = ReportJob))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn get_report_job(
+    Extension(account): Extension<Account>,
+    Path(params): Path<HashMap<String, String>>,
+) -> Result<Json<ReportJob>> {
+    let Some(report_job_id) = params.get("report_job_id") else {
+        bail!("Missing report_job_id");
+    };
+
+    let Ok(report_job_id) = Uuid::parse_str(report_job_id) else {
+        bad_request!("Invalid report job ID");
+    };
+
+    let Some(report_job) = account
+        .resources_db()
+        .await?
+        .query("SELECT * OMIT payload FROM $report_job")
+        .bind(("report_job", report_job_thing(report_job_id)))
+        .await?
+        .check_first_real_error()?
+        .take::<Option<ReportJob>>(0)?
+    else {
+        not_found!("Report job not found");
+    };
+
+    Ok(Json(report_job))
+}
+
+#[derive(Deserialize)]
+struct PendingReportJob {
+    #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
+    id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct ClaimedReportJob {
+    payload: String,
+    attempts: u32,
+}
+
+/// Runs the report job worker until the process exits.
+///
+/// The worker ingests reports that were accepted for asynchronous ingestion from each account's `report_job` table.
+/// Accounts are processed as soon as this process enqueues a report for them, and every account is swept for retries,
+/// jobs of workers that died, and jobs enqueued elsewhere every minute. Jobs that fail are retried a limited number of
+/// times before they are marked failed.
+pub async fn run_worker() {
+    worker::run_on_demand(
+        "report_job",
+        &PENDING_ACCOUNTS,
+        SWEEP_INTERVAL,
+        process_pending_report_jobs,
+    )
+    .await;
+}
+
+// Processes pending report jobs of the accounts in `account_ids`, or of every account if `None`
+#[instrument(err, skip_all)]
+pub(crate) async fn process_pending_report_jobs(
+    account_ids: Option<BTreeSet<String>>,
+) -> Result<()> {
+    worker::for_each_account("report_job", account_ids, |account| async move {
+        // Queued reports are kept until the account is unlocked
+        if account.lock().is_some() {
+            return Ok(());
+        }
+
+        process_account_report_jobs(&account).await
+    })
+    .await
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn process_account_report_jobs(account: &Account) -> anyhow::Result<()> {
+    let now = clock::now();
+
+    let pending_report_jobs = account
+        .resources_db()
+        .await?
+        .query("DELETE report_job WHERE finished_at IS NOT NONE AND finished_at < $expired_before")
+        .query("SELECT id, created_at FROM report_job WHERE status = 'pending' OR (status = 'processing' AND started_at < $stale_before) ORDER BY created_at LIMIT $limit")
+        .bind(("expired_before", surrealdb::sql::Datetime::from(now - FINISHED_JOB_RETENTION)))
+        .bind(("stale_before", surrealdb::sql::Datetime::from(now - PROCESSING_TIMEOUT)))
+        .bind(("limit", BATCH_SIZE))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<PendingReportJob>>(1)?;
+
+    // A full batch may have left more jobs pending, which are processed right after this batch
+    if pending_report_jobs.len() == BATCH_SIZE as usize {
+        PENDING_ACCOUNTS.mark(account.id());
+    }
+
+    // The resources database connection is released while ingesting, as ingestion opens its own connection and a
+    // non-concurrent (e.g. RocksDB) database only has one.
+    for pending_report_job in pending_report_jobs {
+        let report_job = report_job_thing(pending_report_job.id);
+
+        // Another worker may have claimed the job since it was listed, in which case nothing is returned
+        let Some(claimed_report_job) = account
+            .resources_db()
+            .await?
+            .query("UPDATE $report_job SET status = 'processing', started_at = $now, attempts += 1 WHERE status = 'pending' OR (status = 'processing' AND started_at < $stale_before) RETURN payload, attempts")
+            .bind(("report_job", report_job.clone()))
+            .bind(("now", clock::now_value()))
+            .bind(("stale_before", surrealdb::sql::Datetime::from(clock::now() - PROCESSING_TIMEOUT)))
+            .await?
+            .check_first_real_error()?
+            .take::<Option<ClaimedReportJob>>(0)?
+        else {
+            continue;
+        };
+
+        let attempts = claimed_report_job.attempts;
+
+        let result = if attempts > MAX_ATTEMPTS {
+            Err(anyhow::anyhow!(
+                "Report job did not finish after {MAX_ATTEMPTS} attempts"
+            ))
+        } else {
+            ingest(account, &claimed_report_job.payload).await
+        };
+
+        let db = account.resources_db().await?;
+
+        match result {
+            Ok(()) => {
+                info!(report_job_id = %pending_report_job.id, attempts, "Ingested report job");
+
+                db.query("UPDATE $report_job SET status = 'succeeded', finished_at = $now, payload = NONE, error = NONE RETURN NONE")
+                    .bind(("report_job", report_job))
+                    .bind(("now", clock::now_value()))
+                    .await?
+                    .check_first_real_error()?;
+            }
+            Err(err) => {
+                // Reports that can't be parsed will never succeed, so they aren't retried
+                let status = if attempts >= MAX_ATTEMPTS || err.is::<serde_json::Error>() {
+                    ReportJobStatus::Failed
+                } else {
+                    ReportJobStatus::Pending
+                };
+
+                warn!(
+                    report_job_id = %pending_report_job.id,
+                    attempts,
+                    ?status,
+                    ?err,
+                    "Failed to ingest report job"
+                );
+
+                let query = if status == ReportJobStatus::Failed {
+                    "UPDATE $report_job SET status = 'failed', finished_at = $now, payload = NONE, error = $error RETURN NONE"
+                } else {
+                    "UPDATE $report_job SET status = 'pending', error = $error RETURN NONE"
+                };
+
+                db.query(query)
+                    .bind(("report_job", report_job))
+                    .bind(("now", clock::now_value()))
+                    .bind(("error", err.to_string()))
+                    .await?
+                    .check_first_real_error()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn ingest(account: &Account, payload: &str) -> anyhow::Result<()> {
+    // Jobs may have been enqueued by a newer backend during a rolling deployment
+    let report = Request::from_json(payload.as_bytes())?;
+
+    // Only the public part of ingestion errors is kept, as job errors are shown to agents
+    report::ingest(account, report)
+        .await
+        .map(|_| ())
+        .map_err(|err| anyhow::anyhow!(err))
+}
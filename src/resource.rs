@@ -1,15 +1,25 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 use axum::{Extension, Json};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use archodex_error::{anyhow, bail, ensure};
+use archodex_error::{anyhow, bad_request, bail, ensure, not_found};
+use surrealdb::sql::statements::{BeginStatement, CommitStatement};
 use tracing::instrument;
-
-use crate::account::Account;
-
-#[derive(Clone, Debug, Eq, Serialize, PartialEq)]
+use utoipa::ToSchema;
+
+use crate::{
+    account::Account,
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    db::{PreparedQuery, QueryBuilder, QueryCheckFirstRealError},
+    next_binding, revision,
+    router::RequestId,
+};
+
+#[derive(Clone, Debug, Eq, Hash, Serialize, PartialEq, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct ResourceIdPart {
     pub(crate) r#type: String,
@@ -135,9 +145,17 @@ impl<'de> Deserialize<'de> for ResourceIdPart {
     }
 }
 
-#[derive(Clone, Debug, Eq, Serialize, PartialEq)]
+// Resource IDs are the path of type/ID pairs from the root of the resource hierarchy, e.g. an AWS partition, account,
+// region, and then the resource itself
+#[derive(Clone, Debug, Eq, Hash, Serialize, PartialEq, ToSchema)]
 pub(crate) struct ResourceId(Vec<ResourceIdPart>);
 
+impl From<Vec<ResourceIdPart>> for ResourceId {
+    fn from(value: Vec<ResourceIdPart>) -> Self {
+        ResourceId(value)
+    }
+}
+
 impl std::ops::Deref for ResourceId {
     type Target = Vec<ResourceIdPart>;
 
@@ -146,6 +164,12 @@ impl std::ops::Deref for ResourceId {
     }
 }
 
+impl std::ops::DerefMut for ResourceId {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 impl From<ResourceId> for surrealdb::sql::Array {
     fn from(value: ResourceId) -> Self {
         surrealdb::sql::Array::from(
@@ -284,7 +308,7 @@ impl<'de> Deserialize<'de> for ResourceId {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub(crate) struct Resource {
     pub(crate) id: ResourceId,
     #[serde(default, skip_serializing_if = "HashSet::is_empty")]
@@ -296,36 +320,251 @@ pub(crate) struct Resource {
 }
 
 impl Resource {
-    pub(crate) fn get_all() -> &'static str {
-        "$resources = SELECT * FROM resource WHERE id != resource:[] PARALLEL;"
+    pub(crate) fn get_all() -> &'static PreparedQuery {
+        static QUERY: PreparedQuery = PreparedQuery::new(
+            "$resources = SELECT * FROM resource WHERE id != resource:[] PARALLEL;",
+        );
+
+        &QUERY
+    }
+
+    // Selects up to `$page_fetch_limit` resources ordered by ID, starting after the `$cursor` resource ID if set
+    pub(crate) fn get_page() -> &'static str {
+        "$resources = SELECT * FROM resource WHERE id != resource:[] AND ($cursor IS NONE OR id > $cursor) ORDER BY id LIMIT $page_fetch_limit;"
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 #[serde(deny_unknown_fields)]
 pub(super) struct SetTagsRequest {
     resource_id: ResourceId,
     environments: HashSet<String>,
 }
 
-#[instrument(err, skip(account))]
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/resource/set_environments",
+    tag = "resources",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = SetTagsRequest,
+    responses((status = 200))
+)]
+#[instrument(err, skip(auth, account))]
 pub(super) async fn set_environments(
+    Extension(auth): Extension<DashboardAuth>,
     Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
     Json(req): Json<SetTagsRequest>,
 ) -> crate::Result<()> {
-    const QUERY: &str =
-        "BEGIN; UPDATE resource SET environments = $envs WHERE id = $resource_id; COMMIT;";
+    let details = json!(&req);
+
+    let db = account.resources_db().await?;
+
+    set_environments_statement(
+        db.query(BeginStatement::default()),
+        req.resource_id,
+        req.environments,
+    )
+    .query(CommitStatement::default())
+    .await?
+    .check_first_real_error()?;
+
+    revision::bump(&db).await?;
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::ResourceEnvironmentsSet,
+        Some(details),
+    )
+    .await;
 
-    account
-        .resources_db()
-        .await?
+    Ok(())
+}
+
+// Sets a resource's environments. Resources that don't exist are skipped. The statement doesn't begin a transaction, so
+// callers can apply other changes in the same transaction, and must bump the graph revision once it commits.
+pub(crate) fn set_environments_statement<'r, C: surrealdb::Connection>(
+    query: impl QueryBuilder<'r, C>,
+    resource_id: ResourceId,
+    environments: HashSet<String>,
+) -> surrealdb::method::Query<'r, C> {
+    let resource_id_binding = next_binding();
+    let environments_binding = next_binding();
+
+    query
+        .query(format!(
+            "UPDATE resource SET environments = ${environments_binding} WHERE id = ${resource_id_binding} RETURN NONE;"
+        ))
+        .bind((
+            resource_id_binding,
+            surrealdb_thing_from_resource_id(resource_id),
+        ))
+        .bind((environments_binding, environments))
+}
+
+// Limits the size of a single bulk update transaction
+const MAX_BULK_SET_ENVIRONMENTS: usize = 1000;
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(super) struct BulkSetTagsRequest {
+    resources: Vec<SetTagsRequest>,
+}
+
+// Sets the environments of many resources in one transaction, e.g. when tagging resources after an import. Resources
+// that don't exist are skipped, matching `set_environments`.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/resources/set_environments",
+    tag = "resources",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = BulkSetTagsRequest,
+    responses((status = 200))
+)]
+#[instrument(err, skip_all, fields(resources = req.resources.len()))]
+pub(super) async fn bulk_set_environments(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<BulkSetTagsRequest>,
+) -> crate::Result<()> {
+    const QUERY: &str = "
+        BEGIN;
+
+        FOR $update IN $updates {
+            LET $resource = $update.resource_id;
+            UPDATE $resource SET environments = $update.environments RETURN NONE;
+        };
+
+        COMMIT;";
+
+    if req.resources.len() > MAX_BULK_SET_ENVIRONMENTS {
+        bad_request!("At most {MAX_BULK_SET_ENVIRONMENTS} resources may be updated at once");
+    }
+
+    let details = json!(&req);
+
+    let updates = req
+        .resources
+        .into_iter()
+        .map(|update| {
+            surrealdb::sql::Value::from(surrealdb::sql::Object::from(BTreeMap::from([
+                (
+                    "resource_id".to_string(),
+                    surrealdb_thing_from_resource_id(update.resource_id),
+                ),
+                (
+                    "environments".to_string(),
+                    surrealdb::sql::Value::from(
+                        update
+                            .environments
+                            .into_iter()
+                            .map(surrealdb::sql::Value::from)
+                            .collect::<Vec<_>>(),
+                    ),
+                ),
+            ])))
+        })
+        .collect::<Vec<_>>();
+
+    {
+        let db = account.resources_db().await?;
+
+        db.query(QUERY)
+            .bind(("updates", surrealdb::sql::Value::from(updates)))
+            .await?
+            .check_first_real_error()?;
+
+        revision::bump(&db).await?;
+    }
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::ResourceEnvironmentsSet,
+        Some(details),
+    )
+    .await;
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(super) struct DeleteResourceRequest {
+    resource_id: ResourceId,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct DeleteResourceResponse {
+    deleted_resources: u64,
+}
+
+// Deletes a resource, every resource it transitively contains, and all `contains` and `event` edges and principal
+// chains that reference any of them. This allows removing garbage data reported by an agent without wiping the account.
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}/resource",
+    tag = "resources",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = DeleteResourceRequest,
+    responses((status = 200, body = DeleteResourceResponse))
+)]
+#[instrument(err, skip(account))]
+pub(super) async fn delete_resource(
+    Extension(account): Extension<Account>,
+    Json(req): Json<DeleteResourceRequest>,
+) -> crate::Result<Json<DeleteResourceResponse>> {
+    const QUERY: &str = "
+        BEGIN;
+
+        LET $resources = array::concat([$resource_id], $resource_id.{..+collect}->contains->resource);
+
+        // Drop IDs of resources that don't exist
+        LET $resources = SELECT VALUE id FROM $resources;
+
+        LET $principal_chains = SELECT VALUE id FROM principal_chain WHERE resources CONTAINSANY $resources;
+
+        DELETE event WHERE $resources CONTAINS in OR $resources CONTAINS out;
+        UPDATE event SET principal_chains = array::complement(principal_chains, $principal_chains) WHERE principal_chains CONTAINSANY $principal_chains;
+        DELETE $principal_chains;
+        DELETE contains WHERE $resources CONTAINS in OR $resources CONTAINS out;
+        DELETE $resources;
+
+        RETURN array::len($resources);
+
+        COMMIT;";
+
+    if req.resource_id.is_empty() {
+        bad_request!("The root resource cannot be deleted");
+    }
+
+    let db = account.resources_db().await?;
+
+    let mut res = db
         .query(QUERY)
-        .bind(("envs", req.environments))
         .bind((
             "resource_id",
             surrealdb_thing_from_resource_id(req.resource_id),
         ))
-        .await?;
+        .await?
+        .check_first_real_error()?;
 
-    Ok(())
+    let deleted_resources = res
+        .take::<Option<u64>>(res.num_statements() - 1)?
+        .unwrap_or_default();
+
+    if deleted_resources == 0 {
+        not_found!("Resource not found");
+    }
+
+    revision::bump(&db).await?;
+
+    Ok(Json(DeleteResourceResponse { deleted_resources }))
 }
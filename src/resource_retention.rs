@@ -0,0 +1,298 @@
+use axum::{Extension, Json, extract::Query};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, instrument};
+use utoipa::{IntoParams, ToSchema};
+
+use archodex_error::{PublicError, bad_request};
+
+use crate::{
+    Result,
+    account::{Account, AccountQueries as _, invalidate_cached_account},
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    clock,
+    db::{QueryCheckFirstRealError as _, accounts_db_for_account},
+    env::Env,
+    resource::{ResourceId, surrealdb_thing_from_resource_id},
+    revision,
+    router::RequestId,
+    traced_query::TracedQuery,
+    worker,
+};
+
+// Maximum number of stale resources considered per transaction. Each batch also scans the events, principal chains, and
+// edges referencing what it deletes, so batches are kept small enough to not hold up report ingestion.
+const BATCH_SIZE: u32 = 1000;
+
+// Longest retention period an account can set, 10 years
+const MAX_RETENTION_DAYS: u32 = 3650;
+
+pub(crate) fn validate_retention_days(retention_days: Option<u32>) -> Result<()> {
+    if let Some(retention_days) = retention_days
+        && !(1..=MAX_RETENTION_DAYS).contains(&retention_days)
+    {
+        bad_request!("Retention must be between 1 and {MAX_RETENTION_DAYS} days");
+    }
+
+    Ok(())
+}
+
+// Deletes the stale resources of one batch of up to `$limit` resources last seen before `$not_seen_since`, ordered by ID
+// and starting after `$after`. A stale resource is kept while it transitively contains a resource that isn't stale, so
+// pruning never orphans a live resource. The events, principal chains, and `contains` and derived edges referencing the
+// deleted resources are deleted with them, as `DELETE /account/{account_id}/resource` does. Returns the number of
+// resources deleted, the number scanned, and the ID of the last resource scanned to continue after.
+const PRUNE_QUERY: &str = "
+    BEGIN;
+
+    LET $scanned = SELECT VALUE id FROM resource WHERE id != resource:[] AND last_seen_at < $not_seen_since AND ($after IS NONE OR id > $after) ORDER BY id LIMIT $limit;
+    LET $resources = $scanned.filter(|$resource| ($resource.{..+collect}->contains->resource).all(|$contained| $contained.last_seen_at < $not_seen_since));
+
+    LET $principal_chains = SELECT VALUE id FROM principal_chain WHERE resources CONTAINSANY $resources;
+    LET $events = SELECT VALUE id FROM event WHERE $resources CONTAINS in OR $resources CONTAINS out;
+
+    DELETE $events;
+    UPDATE event SET principal_chains = array::complement(principal_chains, $principal_chains) WHERE principal_chains CONTAINSANY $principal_chains RETURN NONE;
+    DELETE $principal_chains;
+    DELETE contains WHERE $resources CONTAINS in OR $resources CONTAINS out;
+    DELETE derived_edge WHERE $resources CONTAINS in OR $resources CONTAINS out;
+    DELETE derived_edge WHERE events CONTAINSANY $events AND array::is_empty(array::complement(events, $events));
+    UPDATE derived_edge SET events = array::complement(events, $events) WHERE events CONTAINSANY $events RETURN NONE;
+    DELETE $resources;
+
+    RETURN { deleted: array::len($resources), scanned: array::len($scanned), last_scanned: array::last($scanned.map(|$resource| record::id($resource))) };
+
+    COMMIT;";
+
+#[derive(Deserialize)]
+struct PruneBatch {
+    deleted: u64,
+    scanned: u32,
+    last_scanned: Option<ResourceId>,
+}
+
+/// Prunes stale resources from accounts with a resource retention period every
+/// `ARCHODEX_RESOURCE_RETENTION_INTERVAL_SECONDS` until the process exits.
+///
+/// Resources last seen longer ago than their account's retention period are deleted along with their edges, events, and
+/// principal chains, unless they contain a resource seen more recently. Accounts without a retention period keep their
+/// resources forever.
+pub async fn run_worker() {
+    worker::run_periodically(
+        "resource_retention",
+        Env::resource_retention_interval(),
+        prune_accounts_resources,
+    )
+    .await;
+}
+
+#[instrument(err)]
+pub(crate) async fn prune_accounts_resources() -> Result<()> {
+    worker::for_each_account("resource_retention", None, |account| async move {
+        let Some(retention_days) = account.resource_retention_days() else {
+            return Ok(());
+        };
+
+        let not_seen_since = clock::now() - TimeDelta::days(i64::from(retention_days));
+
+        let deleted_resources = prune_stale_resources(&account, not_seen_since).await?;
+
+        if deleted_resources > 0 {
+            info!(
+                account_id = account.id(),
+                deleted_resources, "Pruned stale resources"
+            );
+        }
+
+        Ok::<_, PublicError>(())
+    })
+    .await
+}
+
+// Deletes the account's resources last seen before `not_seen_since` in batches and returns the number deleted. Each
+// batch is its own transaction, so a failed prune may have deleted some of the stale resources already.
+#[instrument(err, skip(account), fields(account_id = account.id()))]
+async fn prune_stale_resources(account: &Account, not_seen_since: DateTime<Utc>) -> Result<u64> {
+    let mut deleted_resources = 0;
+    let mut after = None;
+
+    loop {
+        // The resources database connection is released between batches, as a non-concurrent (e.g. RocksDB) database
+        // only has one
+        let db = account.resources_db().await?;
+
+        let mut res = TracedQuery::new(&db, "resource_retention")
+            .query(PRUNE_QUERY)
+            .bind((
+                "not_seen_since",
+                surrealdb::sql::Datetime::from(not_seen_since),
+            ))
+            .bind(("after", after.map(surrealdb_thing_from_resource_id)))
+            .bind(("limit", BATCH_SIZE))
+            .execute()
+            .await?
+            .check_first_real_error()?;
+
+        let Some(batch) = res.take::<Option<PruneBatch>>(res.num_statements() - 1)? else {
+            break;
+        };
+
+        if batch.deleted > 0 {
+            revision::bump(&db).await?;
+        }
+
+        deleted_resources += batch.deleted;
+
+        if batch.scanned < BATCH_SIZE {
+            break;
+        }
+
+        after = batch.last_scanned;
+    }
+
+    Ok(deleted_resources)
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PruneResourcesParams {
+    // Resources last seen before this time are deleted
+    not_seen_since: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct PruneResourcesResponse {
+    deleted_resources: u64,
+}
+
+// Deletes resources last seen before `not_seen_since`, e.g. the resources an agent stopped reporting after being
+// redeployed, along with all `contains`, `event`, and derived edges and principal chains that reference them. Stale
+// resources that contain a resource seen since are kept.
+#[utoipa::path(
+    delete,
+    path = "/account/{account_id}/resources/prune",
+    tag = "resources",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path), PruneResourcesParams),
+    responses((status = 200, body = PruneResourcesResponse))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn prune_resources(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Query(params): Query<PruneResourcesParams>,
+) -> Result<Json<PruneResourcesResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    if params.not_seen_since > clock::now() {
+        bad_request!("not_seen_since must not be in the future");
+    }
+
+    let deleted_resources = prune_stale_resources(&account, params.not_seen_since).await?;
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        deleted_resources,
+        "Pruned stale resources"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::ResourcesPruned,
+        Some(json!({
+            "not_seen_since": params.not_seen_since,
+            "deleted_resources": deleted_resources,
+        })),
+    )
+    .await;
+
+    Ok(Json(PruneResourcesResponse { deleted_resources }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ResourceRetentionResponse {
+    /// Resources last seen more than this many days ago are pruned. Resources are kept forever if not set.
+    retention_days: Option<u32>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/resource_retention",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = ResourceRetentionResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn get_resource_retention(
+    Extension(account): Extension<Account>,
+) -> Result<Json<ResourceRetentionResponse>> {
+    Ok(Json(ResourceRetentionResponse {
+        retention_days: account.resource_retention_days(),
+    }))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetResourceRetentionRequest {
+    /// Number of days resources are kept after they were last seen, or null to keep resources forever
+    retention_days: Option<u32>,
+}
+
+// Sets how long the account's resources are kept after they were last seen. Stale resources are deleted by the resource
+// retention worker on its next run, so shortening the retention period deletes resources without further confirmation.
+#[utoipa::path(
+    put,
+    path = "/account/{account_id}/resource_retention",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = SetResourceRetentionRequest,
+    responses((status = 200, body = ResourceRetentionResponse))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn set_resource_retention(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<SetResourceRetentionRequest>,
+) -> Result<Json<ResourceRetentionResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    validate_retention_days(req.retention_days)?;
+
+    accounts_db_for_account(account.id())
+        .await?
+        .set_account_resource_retention_query(&account, req.retention_days, auth.principal())
+        .await?
+        .check_first_real_error()?;
+
+    invalidate_cached_account(account.id());
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        retention_days = req.retention_days,
+        "Set account resource retention"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::ResourceRetentionSet,
+        Some(json!({ "retention_days": req.retention_days })),
+    )
+    .await;
+
+    Ok(Json(ResourceRetentionResponse {
+        retention_days: req.retention_days,
+    }))
+}
@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use archodex_error::bad_request;
+
+use crate::{
+    Result, account::Account, db::QueryCheckFirstRealError, next_binding, resource::ResourceId,
+    traced_query::TracedQuery,
+};
+
+const DEFAULT_SEARCH_LIMIT: u32 = 100;
+const MAX_SEARCH_LIMIT: u32 = 1000;
+const MAX_PREDICATES: usize = 20;
+
+// A condition on a resource attribute. `attribute` is a dot-separated path into the resource's attributes object, e.g.
+// `region` or `tags.team`.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum AttributePredicate {
+    Eq {
+        attribute: String,
+        value: serde_json::Value,
+    },
+    Ne {
+        attribute: String,
+        value: serde_json::Value,
+    },
+    Gt {
+        attribute: String,
+        #[schema(value_type = f64)]
+        value: serde_json::Number,
+    },
+    Gte {
+        attribute: String,
+        #[schema(value_type = f64)]
+        value: serde_json::Number,
+    },
+    Lt {
+        attribute: String,
+        #[schema(value_type = f64)]
+        value: serde_json::Number,
+    },
+    Lte {
+        attribute: String,
+        #[schema(value_type = f64)]
+        value: serde_json::Number,
+    },
+    // Matches string attributes containing a substring and array attributes containing an element
+    Contains {
+        attribute: String,
+        value: serde_json::Value,
+    },
+    Exists {
+        attribute: String,
+    },
+    NotExists {
+        attribute: String,
+    },
+}
+
+impl AttributePredicate {
+    fn attribute(&self) -> &str {
+        match self {
+            AttributePredicate::Eq { attribute, .. }
+            | AttributePredicate::Ne { attribute, .. }
+            | AttributePredicate::Gt { attribute, .. }
+            | AttributePredicate::Gte { attribute, .. }
+            | AttributePredicate::Lt { attribute, .. }
+            | AttributePredicate::Lte { attribute, .. }
+            | AttributePredicate::Contains { attribute, .. }
+            | AttributePredicate::Exists { attribute }
+            | AttributePredicate::NotExists { attribute } => attribute,
+        }
+    }
+
+    // Returns the predicate's operator and value, or `None` for predicates without a value
+    fn operator_and_value(self) -> (&'static str, Option<serde_json::Value>) {
+        match self {
+            AttributePredicate::Eq { value, .. } => ("=", Some(value)),
+            AttributePredicate::Ne { value, .. } => ("!=", Some(value)),
+            AttributePredicate::Gt { value, .. } => (">", Some(value.into())),
+            AttributePredicate::Gte { value, .. } => (">=", Some(value.into())),
+            AttributePredicate::Lt { value, .. } => ("<", Some(value.into())),
+            AttributePredicate::Lte { value, .. } => ("<=", Some(value.into())),
+            AttributePredicate::Contains { value, .. } => ("CONTAINS", Some(value)),
+            AttributePredicate::Exists { .. } => ("IS NOT", None),
+            AttributePredicate::NotExists { .. } => ("IS", None),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SearchResourcesRequest {
+    resource_type: Option<String>,
+    #[serde(default)]
+    predicates: Vec<AttributePredicate>,
+    limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct ResourceSearchResult {
+    id: ResourceId,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    environments: HashSet<String>,
+    #[serde(default)]
+    attributes: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_seen_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SearchResourcesResponse {
+    resources: Vec<ResourceSearchResult>,
+}
+
+// Finds resources whose attributes match all of the given predicates. Attribute paths and values are always bound as
+// query parameters, never interpolated into the query.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/resources/search",
+    tag = "resources",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = SearchResourcesRequest,
+    responses((status = 200, body = SearchResourcesResponse))
+)]
+#[instrument(err, skip(account))]
+pub(crate) async fn search_resources(
+    Extension(account): Extension<Account>,
+    Json(req): Json<SearchResourcesRequest>,
+) -> Result<Json<SearchResourcesResponse>> {
+    let limit = req.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        bad_request!("limit must be between 1 and {MAX_SEARCH_LIMIT}");
+    }
+
+    if req.predicates.len() > MAX_PREDICATES {
+        bad_request!("At most {MAX_PREDICATES} predicates may be given");
+    }
+
+    let mut conditions = vec!["id != resource:[]".to_string()];
+    let mut bindings = vec![];
+
+    if let Some(resource_type) = req.resource_type {
+        let resource_type_binding = next_binding();
+        conditions.push(format!("resource_type = ${resource_type_binding}"));
+        bindings.push((
+            resource_type_binding,
+            serde_json::Value::from(resource_type),
+        ));
+    }
+
+    for predicate in req.predicates {
+        let attribute = predicate.attribute();
+        if attribute.split('.').any(str::is_empty) {
+            bad_request!("Invalid attribute path {attribute:?}");
+        }
+
+        let mut path = "attributes".to_string();
+        for segment in attribute.split('.') {
+            let segment_binding = next_binding();
+            path.push_str(&format!("[${segment_binding}]"));
+            bindings.push((segment_binding, serde_json::Value::from(segment)));
+        }
+
+        match predicate.operator_and_value() {
+            (operator, Some(value)) => {
+                let value_binding = next_binding();
+                conditions.push(format!("{path} {operator} ${value_binding}"));
+                bindings.push((value_binding, value));
+            }
+            (operator, None) => conditions.push(format!("{path} {operator} NONE")),
+        }
+    }
+
+    let limit_binding = next_binding();
+    bindings.push((limit_binding.clone(), serde_json::Value::from(limit)));
+
+    let db = account.resources_db().await?;
+
+    let query = bindings.into_iter().fold(
+        TracedQuery::new(&db, "resource_search").query(format!(
+            "SELECT * FROM resource WHERE {} ORDER BY id LIMIT ${limit_binding}",
+            conditions.join(" AND ")
+        )),
+        |query, binding| query.bind(binding),
+    );
+
+    let resources = query
+        .execute()
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<ResourceSearchResult>>(0)?;
+
+    Ok(Json(SearchResourcesResponse { resources }))
+}
@@ -0,0 +1,73 @@
+use tracing::{info, instrument, warn};
+
+use archodex_error::anyhow;
+
+use crate::{
+    Result,
+    account::{Account, list_live_accounts},
+};
+
+/// Checks that the resources database of every account served by this backend has the indexes graph queries rely on.
+///
+/// Resources databases are only migrated when their account is created, so accounts created before an index was added
+/// lack it until their database is migrated again. Queries still work without the indexes, but degrade to table scans
+/// on large accounts, so each account missing indexes is logged as a warning rather than failing startup.
+pub async fn check() {
+    if let Err(err) = check_accounts().await {
+        warn!(?err, "Failed to check resources database indexes");
+    }
+}
+
+#[instrument(err)]
+pub(crate) async fn check_accounts() -> Result<()> {
+    let accounts = list_live_accounts().await?;
+
+    let mut accounts_missing_indexes = 0;
+
+    for account in &accounts {
+        #[cfg(feature = "archodex-com")]
+        if account.service_data_surrealdb_url().is_none() {
+            continue;
+        }
+
+        match check_account(account).await {
+            Ok(true) => {}
+            Ok(false) => accounts_missing_indexes += 1,
+            Err(err) => warn!(
+                account_id = account.id(),
+                ?err,
+                "Failed to check resources database indexes for account"
+            ),
+        }
+    }
+
+    info!(
+        accounts = accounts.len(),
+        accounts_missing_indexes, "Checked resources database indexes"
+    );
+
+    Ok(())
+}
+
+// Returns whether the account's resources database has every required index
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn check_account(account: &Account) -> anyhow::Result<bool> {
+    let db = account.resources_db().await?;
+
+    let missing_indexes = migrator::missing_account_resources_indexes(&db).await?;
+
+    if missing_indexes.is_empty() {
+        return Ok(true);
+    }
+
+    warn!(
+        account_id = account.id(),
+        missing_indexes = ?missing_indexes
+            .iter()
+            .map(|(table, index)| format!("{table}.{index}"))
+            .collect::<Vec<_>>(),
+        "Resources database is missing indexes, graph queries will scan tables until it is migrated"
+    );
+
+    Ok(false)
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use axum::http::{HeaderMap, HeaderValue, header::IF_NONE_MATCH};
+use surrealdb::{Surreal, engine::any::Any};
+use tracing::warn;
+
+use crate::{
+    Result,
+    db::{QueryCheckFirstRealError as _, is_transaction_conflict_error},
+};
+
+// Concurrent bumps conflict over the revision record. A bump is a single small write, so conflicting bumps are retried
+// after a short delay rather than failing the change they record.
+const MAX_BUMP_ATTEMPTS: u32 = 5;
+const BUMP_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+// Returns the revision of an account's resources graph, which is bumped after every transaction that changes resources
+// or events. Accounts that were never changed are at revision 0.
+pub(crate) async fn current(db: &Surreal<Any>) -> Result<u64> {
+    Ok(db
+        .query("RETURN revision:current.revision ?? 0")
+        .await?
+        .check_first_real_error()?
+        .take::<Option<u64>>(0)?
+        .unwrap_or_default())
+}
+
+// Responses derived only from the resources graph are unchanged until the revision is bumped, so the revision is their
+// entity tag
+pub(crate) fn etag(revision: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{revision}\""))
+        .expect("Revision entity tag should be a valid header value")
+}
+
+// Whether a conditional request's `If-None-Match` header matches the entity tag of the revision, in which case the
+// client's cached response is still current. Entity tags are compared weakly, as required for `If-None-Match`.
+pub(crate) fn is_not_modified(headers: &HeaderMap, revision: u64) -> bool {
+    let etag = etag(revision);
+
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/").as_bytes() == etag.as_bytes())
+}
+
+// Bumps the revision of an account's resources graph and returns the new revision. It must be called after every
+// transaction that changes resources or events commits. Bumping in its own transaction keeps changes from conflicting
+// with each other over the revision record, so e.g. reports of several agents of an account are ingested concurrently.
+// A reader may see a change before its bump, which only makes it read the change once more after the bump.
+pub(crate) async fn bump(db: &Surreal<Any>) -> Result<u64> {
+    let mut attempt = 1;
+
+    loop {
+        let res = db
+            .query("RETURN fn::bump_revision()")
+            .await
+            .and_then(surrealdb::Response::check_first_real_error);
+
+        match res {
+            Ok(mut res) => return Ok(res.take::<Option<u64>>(0)?.unwrap_or_default()),
+            Err(err) if attempt < MAX_BUMP_ATTEMPTS && is_transaction_conflict_error(&err) => {
+                warn!(
+                    attempt,
+                    "Revision bump conflicted with a concurrent bump, retrying"
+                );
+                tokio::time::sleep(BUMP_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
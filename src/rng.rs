@@ -0,0 +1,66 @@
+use std::sync::{LazyLock, Mutex};
+
+use rand::RngCore;
+
+/// The default random number generator, which draws from the thread-local cryptographically secure generator.
+pub struct SystemRng;
+
+impl RngCore for SystemRng {
+    fn next_u32(&mut self) -> u32 {
+        rand::thread_rng().next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand::thread_rng().next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand::thread_rng().fill_bytes(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        rand::thread_rng().try_fill_bytes(dest)
+    }
+}
+
+static RNG: LazyLock<Mutex<Box<dyn RngCore + Send>>> =
+    LazyLock::new(|| Mutex::new(Box::new(SystemRng)));
+
+/// Replaces the process-wide random number generator used for account IDs, report API key IDs, salts, and API private
+/// keys.
+///
+/// Tests can install a seeded generator to make generated IDs deterministic, e.g. to reproduce ID collisions. Nonces
+/// used for encryption always come from the operating system and are not affected.
+///
+/// # Panics
+///
+/// Will panic if the RNG lock was poisoned by a panic in another thread.
+pub fn set_rng(rng: Box<dyn RngCore + Send>) {
+    *RNG.lock().expect("RNG lock should not be poisoned") = rng;
+}
+
+#[cfg(test)]
+thread_local! {
+    // Overrides the process-wide generator for the test running on this thread
+    static TEST_RNG: std::cell::RefCell<Option<Box<dyn RngCore + Send>>> = const { std::cell::RefCell::new(None) };
+}
+
+// Draws random numbers on the current thread from `rng` instead of the process-wide generator
+#[cfg(test)]
+pub(crate) fn set_test_rng(rng: Box<dyn RngCore + Send>) {
+    TEST_RNG.set(Some(rng));
+}
+
+pub(crate) fn with_rng<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    #[cfg(test)]
+    if let Some(mut rng) = TEST_RNG.take() {
+        let result = f(rng.as_mut());
+        TEST_RNG.set(Some(rng));
+        return result;
+    }
+
+    f(RNG
+        .lock()
+        .expect("RNG lock should not be poisoned")
+        .as_mut())
+}
@@ -1,101 +1,473 @@
-use std::time::Duration;
+use std::{any::Any, time::Duration};
 
 use axum::{
     Router,
+    extract::{DefaultBodyLimit, Request},
     http::{
-        HeaderValue,
+        HeaderName, HeaderValue, StatusCode,
         header::{AUTHORIZATION, CONTENT_TYPE},
     },
-    middleware,
-    routing::{delete, get, post},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
 };
 use tower::ServiceBuilder;
 use tower_http::{
+    catch_panic::CatchPanicLayer,
     cors::{AllowMethods, AllowOrigin, CorsLayer},
+    decompression::RequestDecompressionLayer,
     trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer},
 };
-use tracing::{Level, Span, error_span};
+use tracing::{Level, Span, error, error_span};
 use uuid::Uuid;
 
+use archodex_error::PublicError;
+
 use crate::{
-    accounts,
+    account_config, account_lock, account_member, account_promotion, account_transfer, accounts,
+    admin,
+    admin::AdminAuth,
+    audit_log,
     auth::{DashboardAuth, ReportApiKeyAuth},
+    bulk_operation, client_ip,
     db::{dashboard_auth_account, report_api_key_account},
+    debug_capture, enrichment,
     env::Env,
-    principal_chain, query, report, report_api_keys, resource,
+    event_destination, event_retention, events, export, health, import, metrics, openapi,
+    personal_access_tokens, principal_chain, query, rate_limit, read_only, report,
+    report_api_key_usage, report_api_keys, report_job, resource, resource_retention,
+    resource_search, storage_usage, timeout, transformation, version,
 };
 
+/// Router serving both the public API and internal routes, for deployments with a single listener (e.g. Lambda).
+///
 /// # Panics
 ///
 /// Will panic if `Env::archodex_domain()` is not a valid domain.
 pub fn router() -> Router {
+    with_request_handling(public_routes().merge(internal_routes()))
+}
+
+/// Router serving the public API, for servers that serve internal routes on separate listeners.
+///
+/// # Panics
+///
+/// Will panic if `Env::archodex_domain()` is not a valid domain.
+pub fn public_router() -> Router {
+    with_request_handling(public_routes())
+}
+
+/// Router serving internal routes (health checks, metrics, and the admin API), which operators may firewall separately
+/// from the public API.
+pub fn internal_router() -> Router {
+    with_request_handling(internal_routes())
+}
+
+fn public_routes() -> Router {
     let cors_layer = CorsLayer::new()
         .allow_methods(AllowMethods::mirror_request())
-        .allow_origin(AllowOrigin::list([
-            HeaderValue::from_str(&format!("https://app.{}", Env::archodex_domain()))
-                .expect("Failed to parse archodex domain as HeaderValue"),
-            HeaderValue::from_str("http://localhost:5173")
-                .expect("Failed to parse localhost as HeaderValue"),
-        ]))
+        .allow_origin(AllowOrigin::list(
+            [
+                HeaderValue::from_str(&format!("https://app.{}", Env::archodex_domain()))
+                    .expect("Failed to parse archodex domain as HeaderValue"),
+                HeaderValue::from_str("http://localhost:5173")
+                    .expect("Failed to parse localhost as HeaderValue"),
+            ]
+            .into_iter()
+            .chain(Env::cors_origins().iter().map(|origin| {
+                HeaderValue::from_str(origin).expect("Failed to parse CORS origin as HeaderValue")
+            })),
+        ))
         .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+        .expose_headers([REQUEST_ID_HEADER])
         .allow_credentials(true);
 
     #[cfg(not(feature = "archodex-com"))]
     let cors_layer = cors_layer.allow_private_network(true);
 
+    // Reads get a short time budget so a runaway query fails fast. Writes are left unbounded, as some (e.g. account
+    // creation) provision resources and shouldn't be abandoned part way through.
+    let read_timeout =
+        middleware::from_fn_with_state(Env::dashboard_read_timeout(), timeout::limit);
+
     let dashboard_authed_router = Router::new()
         .nest(
             "/account/:account_id",
             Router::new()
+                .route("/resource", delete(resource::delete_resource))
                 .route(
-                    "/resource/set_environments",
-                    post(resource::set_environments),
+                    "/resources/prune",
+                    delete(resource_retention::prune_resources),
+                )
+                .route(
+                    "/events",
+                    get(events::list_events).layer(read_timeout.clone()),
+                )
+                .route(
+                    "/principal_chain",
+                    get(principal_chain::get).layer(read_timeout.clone()),
                 )
-                .route("/query/:type", get(query::query))
-                .route("/principal_chain", get(principal_chain::get))
                 .route(
                     "/report_api_keys",
-                    get(report_api_keys::list_report_api_keys),
+                    get(report_api_keys::list_report_api_keys).layer(read_timeout.clone()),
                 )
                 .route(
                     "/report_api_keys",
                     post(report_api_keys::create_report_api_key),
                 )
+                .route(
+                    "/report_api_keys/unfreeze",
+                    post(report_api_keys::unfreeze_report_api_keys),
+                )
+                .route(
+                    "/report_api_key/:report_api_key_id",
+                    get(report_api_keys::get_report_api_key).layer(read_timeout.clone()),
+                )
+                .route(
+                    "/report_api_key/:report_api_key_id",
+                    put(report_api_keys::update_report_api_key),
+                )
                 .route(
                     "/report_api_key/:report_api_key_id",
                     delete(report_api_keys::revoke_report_api_key),
                 )
-                .route("/", delete(accounts::delete_account)),
+                .route(
+                    "/report_api_key/:report_api_key_id/usage",
+                    get(report_api_keys::get_report_api_key_usage).layer(read_timeout.clone()),
+                )
+                .route(
+                    "/personal_access_tokens",
+                    get(personal_access_tokens::list_personal_access_tokens)
+                        .layer(read_timeout.clone()),
+                )
+                .route(
+                    "/personal_access_tokens",
+                    post(personal_access_tokens::create_personal_access_token),
+                )
+                .route(
+                    "/personal_access_token/:personal_access_token_id",
+                    delete(personal_access_tokens::revoke_personal_access_token),
+                )
+                .route(
+                    "/event_destinations",
+                    get(event_destination::list_event_destinations).layer(read_timeout.clone()),
+                )
+                .route(
+                    "/event_destinations",
+                    post(event_destination::create_event_destination),
+                )
+                .route(
+                    "/event_destination/:event_destination_id",
+                    delete(event_destination::delete_event_destination),
+                )
+                .route(
+                    "/event_destination/:event_destination_id/dead_letters",
+                    get(event_destination::list_dead_lettered_event_deliveries)
+                        .layer(read_timeout.clone()),
+                )
+                .route(
+                    "/event_destination/:event_destination_id/redrive",
+                    post(event_destination::redrive_event_deliveries),
+                )
+                .route(
+                    "/event_destination/:event_destination_id/verify",
+                    post(event_destination::verify_event_destination),
+                )
+                .route(
+                    "/event_destination/:event_destination_id/deliveries",
+                    get(event_destination::list_event_delivery_attempts)
+                        .layer(read_timeout.clone()),
+                )
+                .route(
+                    "/event_destination/:event_destination_id/delivery/:delivery_id/redeliver",
+                    post(event_destination::redeliver_event),
+                )
+                .route(
+                    "/members",
+                    get(account_member::list_account_members).layer(read_timeout.clone()),
+                )
+                .route(
+                    "/audit_log",
+                    get(audit_log::list_audit_log).layer(read_timeout.clone()),
+                )
+                .route(
+                    "/storage_usage",
+                    get(storage_usage::get_storage_usage).layer(read_timeout.clone()),
+                )
+                .route("/members", post(account_member::invite_account_member))
+                .route(
+                    "/member/:user_id",
+                    delete(account_member::remove_account_member),
+                )
+                .route(
+                    "/transfer",
+                    post(account_transfer::initiate_account_transfer),
+                )
+                .route(
+                    "/transfer",
+                    delete(account_transfer::cancel_account_transfer),
+                )
+                .route(
+                    "/debug_capture",
+                    get(debug_capture::get_debug_capture_status).layer(read_timeout.clone()),
+                )
+                .route("/debug_capture", post(debug_capture::enable_debug_capture))
+                .route(
+                    "/debug_capture",
+                    delete(debug_capture::disable_debug_capture),
+                )
+                .route(
+                    "/debug_capture/bundle",
+                    get(debug_capture::download_debug_capture_bundle).layer(read_timeout.clone()),
+                )
+                .route(
+                    "/enrichers",
+                    get(enrichment::get_enrichers).layer(read_timeout.clone()),
+                )
+                .route("/enrichers", put(enrichment::set_enrichers))
+                .route(
+                    "/transformations",
+                    get(transformation::get_transformations).layer(read_timeout.clone()),
+                )
+                .route("/transformations", put(transformation::set_transformations))
+                .route(
+                    "/event_retention",
+                    get(event_retention::get_event_retention).layer(read_timeout.clone()),
+                )
+                .route(
+                    "/event_retention",
+                    put(event_retention::set_event_retention),
+                )
+                .route(
+                    "/resource_retention",
+                    get(resource_retention::get_resource_retention).layer(read_timeout.clone()),
+                )
+                .route(
+                    "/resource_retention",
+                    put(resource_retention::set_resource_retention),
+                )
+                // Exports and imports stream for as long as the account's data takes to read or write, so they have no
+                // time budget
+                .route("/export", get(export::download_export))
+                .route("/import", post(import::import_export))
+                .route("/config", put(account_config::apply_config))
+                .route("/promote", post(account_promotion::promote_config))
+                .route("/", delete(accounts::delete_account))
+                .layer(
+                    ServiceBuilder::new().layer(middleware::from_fn(account_lock::reject_writes)),
+                )
+                // Routes that only read the account despite being `POST`s are outside the read-only check, as are the
+                // lock routes so that read-only accounts can be unlocked and freezing report API keys so that ingestion
+                // can always be stopped
+                .route(
+                    "/resources/search",
+                    post(resource_search::search_resources).layer(read_timeout.clone()),
+                )
+                .route("/export", post(export::upload_export))
+                .route(
+                    "/report_api_keys/freeze",
+                    post(report_api_keys::freeze_report_api_keys),
+                )
+                .route(
+                    "/lock",
+                    get(account_lock::get_account_lock).layer(read_timeout.clone()),
+                )
+                .route("/lock", put(account_lock::lock_account))
+                .route("/lock", delete(account_lock::unlock_account))
+                .layer(ServiceBuilder::new().layer(middleware::from_fn(debug_capture::capture))),
         )
         .layer(ServiceBuilder::new().layer(middleware::from_fn(dashboard_auth_account)))
-        .route("/accounts", get(accounts::list_accounts))
+        .route(
+            "/accounts",
+            get(accounts::list_accounts).layer(read_timeout.clone()),
+        )
         .route("/accounts", post(accounts::create_account))
+        .route(
+            "/bulk_operations",
+            post(bulk_operation::create_bulk_operation),
+        )
+        .route(
+            "/bulk_operation/:bulk_operation_id",
+            get(bulk_operation::get_bulk_operation).layer(read_timeout.clone()),
+        )
+        .route(
+            "/account_transfers",
+            get(account_transfer::list_incoming_account_transfers).layer(read_timeout.clone()),
+        )
+        .route(
+            "/account_transfer/:account_transfer_id/accept",
+            post(account_transfer::accept_account_transfer),
+        )
+        .route(
+            "/account_invitations",
+            get(account_member::list_account_invitations).layer(read_timeout.clone()),
+        )
+        .route(
+            "/account_invitation/:account_id/accept",
+            post(account_member::accept_account_invitation),
+        )
         .layer(ServiceBuilder::new().layer(middleware::from_fn(DashboardAuth::authenticate)))
-        .route("/health", get(|| async { "Ok" }))
+        .route_layer(middleware::from_fn(metrics::track))
+        .route("/openapi.json", get(openapi::openapi))
+        .route("/version", get(version::version))
+        .layer(middleware::from_fn(read_only::reject_writes))
         .layer(cors_layer.clone());
 
+    // Routes CI jobs and scripts may call with personal access tokens as well as dashboard sessions. Every other
+    // dashboard route rejects personal access tokens.
+    let personal_access_token_authed_router = Router::new()
+        .nest(
+            "/account/:account_id",
+            Router::new()
+                .route(
+                    "/resource/set_environments",
+                    post(resource::set_environments),
+                )
+                .route(
+                    "/resources/set_environments",
+                    post(resource::bulk_set_environments),
+                )
+                .route(
+                    "/query/:type",
+                    get(query::query).layer(read_timeout.clone()),
+                )
+                .layer(
+                    ServiceBuilder::new().layer(middleware::from_fn(account_lock::reject_writes)),
+                )
+                .layer(ServiceBuilder::new().layer(middleware::from_fn(debug_capture::capture))),
+        )
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(dashboard_auth_account)))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(
+            DashboardAuth::authenticate_allowing_personal_access_tokens,
+        )))
+        .route_layer(middleware::from_fn(metrics::track))
+        .layer(middleware::from_fn(read_only::reject_writes))
+        .layer(cors_layer.clone());
+
+    let track_usage = middleware::from_fn(report_api_key_usage::track);
+
+    // Rejected reports of locked accounts are not counted as usage of their report API key
+    let reject_locked = middleware::from_fn(account_lock::reject_reports);
+
+    // Streamed reports may take arbitrarily long, so they apply the budget to each batch they ingest instead and fail
+    // streams that stall
+    let report_timeout = middleware::from_fn_with_state(Env::report_timeout(), timeout::limit);
+
     let report_api_key_authed_router = Router::new()
-        .route("/report", post(report::report))
+        .route(
+            "/report",
+            post(report::report)
+                .layer(track_usage.clone())
+                .layer(reject_locked.clone())
+                .layer(report_timeout.clone()),
+        )
+        .route(
+            "/report/stream",
+            post(report::report_stream)
+                .layer(track_usage)
+                .layer(reject_locked),
+        )
+        .route(
+            "/report/jobs/:report_job_id",
+            get(report_job::get_report_job).layer(report_timeout),
+        )
+        // Agents may gzip or zstd compress reports. The body limit applies to the decompressed report, so oversized
+        // reports are rejected with a 413 while being read rather than exhausting memory. Streamed reports apply the
+        // limit to each record instead.
+        .layer(DefaultBodyLimit::max(Env::report_max_body_bytes()))
+        .layer(RequestDecompressionLayer::new())
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(debug_capture::capture)))
         .layer(ServiceBuilder::new().layer(middleware::from_fn(report_api_key_account)))
-        .layer(ServiceBuilder::new().layer(middleware::from_fn(ReportApiKeyAuth::authenticate)));
-
-    let default_on_response_trace_handler = DefaultOnResponse::new().level(Level::INFO);
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(rate_limit::limit_reports)))
+        .layer(ServiceBuilder::new().layer(middleware::from_fn(ReportApiKeyAuth::authenticate)))
+        .route_layer(middleware::from_fn(metrics::track))
+        .layer(middleware::from_fn(read_only::reject_writes));
 
     Router::new()
         .merge(dashboard_authed_router)
+        .merge(personal_access_token_authed_router)
         .merge(report_api_key_authed_router)
+}
+
+// Operational routes, which are served on the internal listeners when they are configured so they can be firewalled
+// separately from the public API
+fn internal_routes() -> Router {
+    let router = Router::new()
+        .route("/livez", get(health::livez))
+        .route("/readyz", get(health::readyz))
+        // Kept for load balancers and deployments configured before liveness and readiness were split
+        .route("/health", get(health::livez))
+        .route("/health/history", get(health::history));
+
+    // Metrics are only served when enabled, as they are not authenticated
+    let router = if Env::metrics_enabled() {
+        router.route("/metrics", get(metrics::metrics))
+    } else {
+        router
+    };
+
+    // The admin API is only served when IAM roles are configured to use it
+    let admin_router = if Env::admin_iam_role_arns().is_empty() {
+        Router::new()
+    } else {
+        let admin_router = Router::new()
+            .route("/admin/account/:account_id", get(admin::get_account))
+            .route(
+                "/admin/account/:account_id/hash_principal_chain_ids",
+                post(admin::hash_principal_chain_ids),
+            )
+            .route("/admin/account/:account_id/lock", put(admin::lock_account))
+            .route(
+                "/admin/account/:account_id/lock",
+                delete(admin::unlock_account),
+            )
+            .route("/admin/maintenance", post(admin::maintenance))
+            .route("/admin/read_only", get(admin::get_read_only))
+            .route("/admin/read_only", put(admin::set_read_only))
+            .route("/admin/reconcile", post(admin::reconcile));
+
+        #[cfg(feature = "archodex-com")]
+        let admin_router = admin_router.route(
+            "/admin/sweep_orphaned_service_databases",
+            post(admin::sweep_orphaned_service_databases),
+        );
+
+        admin_router
+            .layer(ServiceBuilder::new().layer(middleware::from_fn(AdminAuth::authenticate)))
+    };
+
+    router.merge(admin_router)
+}
+
+// Applies the layers every request is handled with, whichever listener it was received on
+fn with_request_handling(router: Router) -> Router {
+    let default_on_response_trace_handler = DefaultOnResponse::new().level(Level::INFO);
+
+    // Faults are injected outside of authentication so that injected auth failures are seen by the auth middleware
+    #[cfg(feature = "chaos")]
+    let router = router.layer(middleware::from_fn(crate::chaos::inject));
+
+    router
+        .layer(middleware::from_fn(client_ip::resolve))
+        .layer(CatchPanicLayer::custom(handle_panic))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &axum::http::Request<_>| {
                     use tracing::field::Empty;
 
+                    let request_id = request
+                        .extensions()
+                        .get::<RequestId>()
+                        .expect("Request ID should be set before tracing requests");
+
                     let span = error_span!(
                         "request",
                         method = %request.method(),
                         uri = %request.uri(),
                         auth = Empty,
-                        request_id = %Uuid::now_v7(),
+                        request_id = %request_id.0,
                         "X-Request-ID" = Empty,
+                        client_ip = Empty,
                         version = ?request.version(),
                     );
 
@@ -117,4 +489,65 @@ pub fn router() -> Router {
                     },
                 ),
         )
+        .layer(middleware::from_fn(set_request_id))
+}
+
+// Returned on every response so users can reference a request, e.g. when reporting an error
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-archodex-request-id");
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RequestId(Uuid);
+
+impl RequestId {
+    pub(crate) fn id(self) -> Uuid {
+        self.0
+    }
+}
+
+tokio::task_local! {
+    // ID of the request the current task is handling, for code without access to the request's extensions
+    static CURRENT_REQUEST_ID: RequestId;
+}
+
+// Returns the ID of the request the current task is handling. Tasks spawned by a request, e.g. background report jobs,
+// have no request ID.
+pub(crate) fn current_request_id() -> Option<RequestId> {
+    CURRENT_REQUEST_ID.try_with(|request_id| *request_id).ok()
+}
+
+// Generates the ID of each request. This runs outside the trace layer so the request span can include the ID.
+async fn set_request_id(mut req: Request, next: Next) -> Response {
+    let request_id = RequestId(Uuid::now_v7());
+    req.extensions_mut().insert(request_id);
+
+    let mut response = CURRENT_REQUEST_ID.scope(request_id, next.run(req)).await;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id.0.to_string()) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    response
+}
+
+// Converts a handler panic into a 500 response, so the connection (or Lambda invocation) survives and the client gets
+// a normal error with the request ID header. This runs within the request span, so the panic is logged with the
+// request ID.
+fn handle_panic(panic: Box<dyn Any + Send + 'static>) -> Response {
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic payload");
+
+    error!(panic = message, "Request handler panicked");
+
+    metrics::record_panic();
+
+    PublicError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        StatusCode::INTERNAL_SERVER_ERROR
+            .canonical_reason()
+            .unwrap_or_default(),
+    )
+    .into_response()
 }
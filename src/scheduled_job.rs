@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument as _, info, info_span};
+
+use archodex_error::anyhow::{self, Context as _};
+
+use crate::{
+    bulk_operation, event_delivery, event_retention, external_ids, health, inference, maintenance,
+    reconciliation, report_job, resource_retention, resources_indexes, storage_usage,
+};
+
+/// A background job that can be run once on demand instead of by its worker, e.g. by EventBridge schedules invoking the
+/// Lambda function, which doesn't run workers between invocations.
+///
+/// Each run does the work of one iteration of the job's worker, so schedules should match the intervals the workers are
+/// configured with, e.g. every minute for event delivery, report jobs, and bulk operations.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduledJob {
+    /// Delivers pending events to the accounts' event destinations
+    EventDelivery,
+    /// Ingests reports queued for asynchronous ingestion
+    ReportJobs,
+    /// Applies bulk operations to the accounts of the users who started them
+    BulkOperations,
+    /// Prunes events older than the accounts' event retention periods
+    EventRetention,
+    /// Prunes resources older than the accounts' resource retention periods
+    ResourceRetention,
+    /// Estimates the storage used by each account's resources database
+    StorageUsage,
+    /// Infers relationships between resources from their events
+    Inference,
+    /// Rebuilds the indexes of the accounts and resources databases
+    Maintenance,
+    /// Audits the accounts database for drift, fixing it if `ARCHODEX_ACCESS_AUDIT_FIX` is `true`
+    AccessAudit,
+    /// Stores missing external account IDs, e.g. after `ARCHODEX_ACCOUNT_ID_HMAC_KEY` is first set or rotated
+    ExternalIds,
+    /// Checks the backend's dependencies and records the results in the health check history
+    HealthCheck,
+    /// Checks that each account's resources database has the indexes graph queries rely on
+    ResourcesIndexes,
+}
+
+impl ScheduledJob {
+    /// Runs the job once.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the job fails. Jobs that process each account separately only fail if the accounts can't be
+    /// listed, and log the accounts they fail to process instead.
+    pub async fn run(self) -> anyhow::Result<()> {
+        info!(job = ?self, "Running scheduled job");
+
+        let res = match self {
+            Self::EventDelivery => {
+                event_delivery::deliver_pending_events(None)
+                    .instrument(info_span!("event_delivery"))
+                    .await
+            }
+            Self::ReportJobs => {
+                report_job::process_pending_report_jobs(None)
+                    .instrument(info_span!("report_job"))
+                    .await
+            }
+            Self::BulkOperations => {
+                bulk_operation::process_pending_bulk_operations()
+                    .instrument(info_span!("bulk_operation"))
+                    .await
+            }
+            Self::EventRetention => {
+                event_retention::prune_accounts_events()
+                    .instrument(info_span!("event_retention"))
+                    .await
+            }
+            Self::ResourceRetention => {
+                resource_retention::prune_accounts_resources()
+                    .instrument(info_span!("resource_retention"))
+                    .await
+            }
+            Self::StorageUsage => {
+                storage_usage::estimate_accounts_storage_usage()
+                    .instrument(info_span!("storage_usage"))
+                    .await
+            }
+            Self::Inference => {
+                inference::infer_accounts_edges()
+                    .instrument(info_span!("inference"))
+                    .await
+            }
+            Self::Maintenance => maintenance::run()
+                .instrument(info_span!("maintenance"))
+                .await
+                .map(|_| ()),
+            Self::AccessAudit => {
+                reconciliation::audit()
+                    .instrument(info_span!("access_audit"))
+                    .await
+            }
+            Self::ExternalIds => {
+                external_ids::backfill_accounts()
+                    .instrument(info_span!("external_ids"))
+                    .await
+            }
+            Self::HealthCheck => {
+                health::record_and_persist_check()
+                    .instrument(info_span!("health_check"))
+                    .await
+            }
+            Self::ResourcesIndexes => {
+                resources_indexes::check_accounts()
+                    .instrument(info_span!("resources_indexes"))
+                    .await
+            }
+        };
+
+        res.map_err(|err| anyhow::anyhow!(err))
+            .with_context(|| format!("Scheduled job {self:?} failed"))
+    }
+}
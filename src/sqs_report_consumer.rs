@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use aws_sdk_sqs::{Client, types::DeleteMessageBatchRequestEntry, types::Message};
+use tracing::{Instrument as _, error, info, info_span, instrument, warn};
+
+use archodex_error::anyhow::{self, Context as _, anyhow, bail};
+
+use crate::{
+    auth::ReportApiKeyAuth,
+    db::account_for_report_api_key,
+    env::{Env, SqsReportConsumerConfig},
+    report, report_api_key_usage,
+};
+
+// ReceiveMessage returns at most 10 messages per request
+const MAX_MESSAGES: i32 = 10;
+// Long polling keeps idle pollers from spinning on empty receives
+const WAIT_TIME_SECONDS: i32 = 20;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Consumes report payloads from the SQS queue configured through the `ARCHODEX_SQS_REPORTS_*` environment variables
+/// and ingests them like reports submitted to `/report`, so that high-volume agents can send reports to the queue
+/// instead of holding HTTP connections open.
+///
+/// Each message body must be a JSON report and each message must have a string `authorization` message attribute
+/// containing the report API key value. Messages that fail authentication or schema validation are logged and deleted.
+/// Messages that fail for other reasons (e.g. an unavailable database) are left on the queue and received again once
+/// their visibility timeout, which is set to `ARCHODEX_REPORT_TIMEOUT_SECONDS`, expires, giving at-least-once
+/// ingestion. Queues should have a redrive policy so that messages that never succeed are moved to a dead-letter queue.
+///
+/// `ARCHODEX_SQS_REPORTS_POLLERS` messages are received and ingested concurrently. Reports larger than SQS's message
+/// size limit must still be submitted to `/report`.
+pub async fn run() {
+    let Some(config) = Env::sqs_report_consumer() else {
+        return;
+    };
+
+    let client =
+        Client::new(&aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await);
+
+    info!(
+        queue_url = config.queue_url,
+        pollers = config.pollers,
+        "Starting SQS report consumer"
+    );
+
+    let mut tasks = tokio::task::JoinSet::new();
+
+    for poller in 0..config.pollers {
+        tasks.spawn(
+            poll(config, client.clone()).instrument(info_span!("sqs_report_consumer", poller)),
+        );
+    }
+
+    tasks.join_all().await;
+}
+
+async fn poll(config: &SqsReportConsumerConfig, client: Client) {
+    let visibility_timeout = i32::try_from(Env::report_timeout().as_secs()).unwrap_or(i32::MAX);
+
+    loop {
+        let messages = match client
+            .receive_message()
+            .queue_url(&config.queue_url)
+            .max_number_of_messages(MAX_MESSAGES)
+            .wait_time_seconds(WAIT_TIME_SECONDS)
+            .visibility_timeout(visibility_timeout)
+            .message_attribute_names("authorization")
+            .send()
+            .await
+        {
+            Ok(output) => output.messages.unwrap_or_default(),
+            Err(err) => {
+                warn!(?err, "Failed to receive SQS messages, retrying");
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        let mut processed = vec![];
+
+        for message in messages {
+            match process_message(&message).await {
+                Ok(()) => processed.push(message),
+                Err(err) => warn!(
+                    ?err,
+                    message_id = message.message_id(),
+                    "Failed to ingest SQS message, leaving it to be received again"
+                ),
+            }
+        }
+
+        if processed.is_empty() {
+            continue;
+        }
+
+        // Processed messages that fail to be deleted are received and ingested again, which is harmless as ingestion
+        // upserts
+        if let Err(err) = delete_messages(config, &client, &processed).await {
+            warn!(?err, "Failed to delete processed SQS messages");
+        }
+    }
+}
+
+#[instrument(err, skip_all, fields(messages = messages.len()))]
+async fn delete_messages(
+    config: &SqsReportConsumerConfig,
+    client: &Client,
+    messages: &[Message],
+) -> anyhow::Result<()> {
+    let entries = messages
+        .iter()
+        .enumerate()
+        .map(|(index, message)| {
+            DeleteMessageBatchRequestEntry::builder()
+                .id(index.to_string())
+                .set_receipt_handle(message.receipt_handle.clone())
+                .build()
+                .context("Failed to build SQS delete entry")
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let output = client
+        .delete_message_batch()
+        .queue_url(&config.queue_url)
+        .set_entries(Some(entries))
+        .send()
+        .await
+        .context("Failed to delete SQS messages")?;
+
+    // Entries are deleted individually, so a successful request may still have failed entries
+    if !output.failed.is_empty() {
+        let errors = output
+            .failed
+            .iter()
+            .map(|entry| entry.message.as_deref().unwrap_or(entry.code.as_str()))
+            .collect::<Vec<_>>();
+
+        bail!(
+            "SQS failed to delete {} of {} messages: {errors:?}",
+            output.failed.len(),
+            messages.len()
+        );
+    }
+
+    Ok(())
+}
+
+// Returns `Err` only for failures that may succeed on retry. Invalid messages are logged and skipped.
+#[instrument(err, skip_all, fields(message_id = message.message_id()))]
+async fn process_message(message: &Message) -> anyhow::Result<()> {
+    let Some(report_api_key_value) = message
+        .message_attributes
+        .as_ref()
+        .and_then(|attributes| attributes.get("authorization"))
+        .and_then(|attribute| attribute.string_value.as_deref())
+    else {
+        error!("Skipping SQS message without a valid authorization attribute");
+        return Ok(());
+    };
+
+    let Some(body) = &message.body else {
+        error!("Skipping SQS message without a body");
+        return Ok(());
+    };
+
+    let req = match report::Request::from_json(body.as_bytes()) {
+        Ok(req) => req,
+        Err(err) => {
+            error!(?err, "Skipping SQS message that is not a valid report");
+            return Ok(());
+        }
+    };
+
+    if let Err(err) = req.validate() {
+        error!(?err, "Skipping SQS message with invalid report items");
+        return Ok(());
+    }
+
+    let auth = match ReportApiKeyAuth::from_value(report_api_key_value).await {
+        Ok(auth) => auth,
+        Err(err) if err.status_code().is_client_error() => {
+            error!("Skipping SQS message with an invalid report API key");
+            return Ok(());
+        }
+        Err(err) => bail!("Failed to validate report API key of SQS message: {err}"),
+    };
+
+    let account = match account_for_report_api_key(&auth).await {
+        Ok(account) => account,
+        Err(err) if err.status_code().is_client_error() => {
+            error!(%err, "Skipping SQS message for an unknown account or revoked report API key");
+            return Ok(());
+        }
+        Err(err) => bail!("Failed to look up account for SQS message: {err}"),
+    };
+
+    let res = report::ingest(&account, req).await;
+
+    // Usage is recorded like reports submitted to `/report`, but failing to record it doesn't fail the message
+    let (resources_upserted, error) = match &res {
+        Ok(result) => (result.resources_upserted(), None),
+        Err(err) => (0, Some(err.to_string())),
+    };
+
+    let recorded = async {
+        let db = account.resources_db().await?;
+
+        report_api_key_usage::record(&db, auth.key_id(), resources_upserted, body.len(), error)
+            .await
+    }
+    .await;
+
+    if let Err(err) = recorded {
+        warn!(?err, "Failed to record report API key usage of SQS message");
+    }
+
+    res.map(|_| ())
+        .map_err(|err| anyhow!("Failed to ingest SQS message: {err}"))
+}
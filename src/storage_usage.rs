@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+
+use axum::{Extension, Json};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
+
+use archodex_error::anyhow::{self, Context as _};
+
+use crate::{
+    Result,
+    account::Account,
+    clock,
+    db::{QueryCheckFirstRealError as _, accounts_db_for_account},
+    env::Env,
+    event_delivery::{
+        self, DeliveredEvent, SELECT_EVENT_DESTINATIONS, SubscribedEventTypes,
+        enqueue_event_statement,
+    },
+    worker,
+};
+
+// Tables of the resources database whose records make up an account's storage usage
+const ESTIMATED_TABLES: [&str; 8] = [
+    "resource",
+    "contains",
+    "event",
+    "principal_chain",
+    "derived_edge",
+    "event_delivery",
+    "report_job",
+    "revision",
+];
+
+// Number of records of each table whose sizes are averaged
+const SAMPLE_SIZE: u32 = 100;
+
+// Measurements older than this are deleted when an account's usage is next estimated
+const RETENTION: TimeDelta = TimeDelta::days(90);
+
+// Measurements returned by the storage usage route
+const TREND: TimeDelta = TimeDelta::days(30);
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct TableStorageUsage {
+    records: u64,
+    /// Average size of a sample of the table's records, serialized as JSON
+    average_record_bytes: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct StorageUsageMeasurement {
+    estimated_bytes: u64,
+    tables: BTreeMap<String, TableStorageUsage>,
+    measured_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct RecordCount {
+    records: u64,
+}
+
+/// Runs the storage usage worker until the process exits.
+///
+/// SurrealDB doesn't report the storage used by a database, so the worker estimates the storage of each account's
+/// resources database as the record count of each table times the average size of a sample of its records. Estimates
+/// exclude indexes and storage engine overhead, so they track growth rather than exact disk usage. Each estimate is
+/// recorded in the account's accounts database shard. When `ARCHODEX_ACCOUNT_STORAGE_LIMIT_BYTES` is set, accounts whose
+/// estimates reach `ARCHODEX_STORAGE_USAGE_ALERT_PERCENT` of it are logged with `storage_usage_alert = true` and a
+/// `storage_usage.alert` event is delivered to the account's event destinations. Operators should alert on these logs.
+pub async fn run_worker() {
+    worker::run_periodically(
+        "storage_usage",
+        Env::storage_usage_interval(),
+        estimate_accounts_storage_usage,
+    )
+    .await;
+}
+
+#[instrument(err)]
+pub(crate) async fn estimate_accounts_storage_usage() -> Result<()> {
+    worker::for_each_account("storage_usage", None, |account| async move {
+        estimate_account_storage_usage(&account).await
+    })
+    .await
+}
+
+#[instrument(err, skip_all, fields(account_id = account.id()))]
+async fn estimate_account_storage_usage(account: &Account) -> Result<()> {
+    let measurement = estimate(account).await?;
+
+    let now = measurement.measured_at;
+    let interval_seconds = i64::try_from(Env::storage_usage_interval().as_secs())
+        .context("Storage usage interval is too long")?;
+    let interval_start = DateTime::from_timestamp(
+        now.timestamp() - now.timestamp().rem_euclid(interval_seconds),
+        0,
+    )
+    .context("Failed to compute start of storage usage interval")?;
+
+    let mut res = accounts_db_for_account(account.id())
+        .await?
+        .query(
+            "SELECT VALUE estimated_bytes FROM storage_usage
+                WHERE account = $account AND measured_at < $interval_start
+                ORDER BY measured_at DESC
+                LIMIT 1",
+        )
+        .query(
+            "UPSERT type::thing('storage_usage', [$account_id, $interval_start]) CONTENT {
+                account: $account,
+                estimated_bytes: $estimated_bytes,
+                tables: $tables,
+                measured_at: $now,
+            } RETURN NONE",
+        )
+        .query("DELETE storage_usage WHERE account = $account AND measured_at < $expired_before")
+        .bind(("account", surrealdb::sql::Thing::from(account)))
+        .bind(("account_id", account.id().to_string()))
+        .bind((
+            "interval_start",
+            surrealdb::sql::Datetime::from(interval_start),
+        ))
+        .bind(("estimated_bytes", measurement.estimated_bytes))
+        .bind(("tables", measurement.tables))
+        .bind(("now", surrealdb::sql::Datetime::from(now)))
+        .bind((
+            "expired_before",
+            surrealdb::sql::Datetime::from(now - RETENTION),
+        ))
+        .await?
+        .check_first_real_error()?;
+
+    let previous_estimated_bytes = res.take::<Option<u64>>(0)?;
+
+    info!(
+        account_id = account.id(),
+        estimated_bytes = measurement.estimated_bytes,
+        "Estimated storage usage"
+    );
+
+    let Some(limit_bytes) = Env::account_storage_limit_bytes() else {
+        return Ok(());
+    };
+
+    let alert_bytes = limit_bytes / 100 * Env::storage_usage_alert_percent();
+
+    if measurement.estimated_bytes < alert_bytes {
+        return Ok(());
+    }
+
+    warn!(
+        account_id = account.id(),
+        storage_usage_alert = true,
+        estimated_bytes = measurement.estimated_bytes,
+        limit_bytes,
+        "Storage usage is near the account's limit"
+    );
+
+    // Destinations are only notified when usage crosses the threshold, not on every estimate above it
+    if previous_estimated_bytes.is_some_and(|previous| previous >= alert_bytes) {
+        return Ok(());
+    }
+
+    let db = account.resources_db().await?;
+
+    let enqueue_statement = enqueue_event_statement(
+        &DeliveredEvent::StorageUsageAlert {
+            account_id: account.id().to_string(),
+            occurred_at: now,
+            estimated_bytes: measurement.estimated_bytes,
+            limit_bytes,
+        },
+        &SubscribedEventTypes::get(&db).await?,
+    )?;
+
+    if !enqueue_statement.is_empty() {
+        db.query(format!(
+            "BEGIN; {SELECT_EVENT_DESTINATIONS} {enqueue_statement} COMMIT;"
+        ))
+        .await?
+        .check_first_real_error()?;
+
+        event_delivery::wake_worker(account.id());
+    }
+
+    Ok(())
+}
+
+// Estimates the storage used by the account's resources database
+async fn estimate(account: &Account) -> anyhow::Result<StorageUsageMeasurement> {
+    let query = ESTIMATED_TABLES
+        .iter()
+        .map(|table| {
+            format!(
+                "SELECT count() AS records FROM {table} GROUP ALL;
+                SELECT VALUE string::len(<string> $this) FROM {table} LIMIT {SAMPLE_SIZE};"
+            )
+        })
+        .collect::<String>();
+
+    let mut res = account
+        .resources_db()
+        .await?
+        .query(query)
+        .await?
+        .check_first_real_error()?;
+
+    let mut tables = BTreeMap::new();
+    let mut estimated_bytes = 0;
+
+    for (index, table) in ESTIMATED_TABLES.iter().enumerate() {
+        let records = res
+            .take::<Option<RecordCount>>(index * 2)?
+            .map_or(0, |count| count.records);
+        let sample = res.take::<Vec<u64>>(index * 2 + 1)?;
+
+        let average_record_bytes = if sample.is_empty() {
+            0
+        } else {
+            sample.iter().sum::<u64>() / sample.len() as u64
+        };
+
+        estimated_bytes += records * average_record_bytes;
+
+        tables.insert(
+            (*table).to_string(),
+            TableStorageUsage {
+                records,
+                average_record_bytes,
+            },
+        );
+    }
+
+    Ok(StorageUsageMeasurement {
+        estimated_bytes,
+        tables,
+        measured_at: clock::now(),
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct GetStorageUsageResponse {
+    /// Storage the account's plan allows, if limited
+    limit_bytes: Option<u64>,
+    /// Estimated usage at which alerts are raised, if the account's storage is limited
+    alert_bytes: Option<u64>,
+    /// Estimates of the last 30 days, oldest first. The last is the current estimate.
+    measurements: Vec<StorageUsageMeasurement>,
+}
+
+// Returns the account's estimated storage usage and its trend. Usage is estimated periodically in the background, so
+// new accounts have no measurements until the first estimate.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/storage_usage",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = GetStorageUsageResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn get_storage_usage(
+    Extension(account): Extension<Account>,
+) -> Result<Json<GetStorageUsageResponse>> {
+    let measurements = accounts_db_for_account(account.id())
+        .await?
+        .query(
+            "SELECT estimated_bytes, tables, measured_at FROM storage_usage
+                WHERE account = $account AND measured_at >= $since
+                ORDER BY measured_at",
+        )
+        .bind(("account", surrealdb::sql::Thing::from(&account)))
+        .bind((
+            "since",
+            surrealdb::sql::Datetime::from(clock::now() - TREND),
+        ))
+        .await?
+        .check_first_real_error()?
+        .take::<Vec<StorageUsageMeasurement>>(0)?;
+
+    let limit_bytes = Env::account_storage_limit_bytes();
+
+    Ok(Json(GetStorageUsageResponse {
+        limit_bytes,
+        alert_bytes: limit_bytes.map(|limit| limit / 100 * Env::storage_usage_alert_percent()),
+        measurements,
+    }))
+}
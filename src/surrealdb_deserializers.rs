@@ -175,6 +175,37 @@ pub(crate) mod uuid {
 
         deserializer.deserialize_any(Visitor)
     }
+
+    pub(crate) fn deserialize_optional<'de, D>(deserializer: D) -> Result<Option<Uuid>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct OptionalVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for OptionalVisitor {
+            type Value = Option<Uuid>;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an optional String in UUID format or SurrealDB RecordId")
+            }
+
+            fn visit_none<E>(self) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(None)
+            }
+
+            fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                Ok(Some(deserialize(deserializer)?))
+            }
+        }
+
+        deserializer.deserialize_option(OptionalVisitor)
+    }
 }
 
 pub(crate) mod bytes {
@@ -210,7 +241,6 @@ pub(crate) mod bytes {
         deserializer.deserialize_any(Visitor)
     }
 
-    #[cfg(not(feature = "archodex-com"))]
     pub(crate) fn deserialize_optional<'de, D>(deserializer: D) -> Result<Option<Value>, D::Error>
     where
         D: serde::Deserializer<'de>,
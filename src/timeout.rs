@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use tracing::warn;
+
+use archodex_error::PublicError;
+
+use crate::Result;
+
+// Fails requests that take longer than `budget` with a 504. The handler's future is dropped, which cancels any SurrealDB
+// query it is awaiting and releases the database connection it holds.
+pub(crate) async fn limit(
+    State(budget): State<Duration>,
+    req: Request,
+    next: Next,
+) -> Result<Response> {
+    within(budget, async { Ok(next.run(req).await) }).await
+}
+
+// Fails with a 504 like `limit` if `future` takes longer than `budget`, for budgets of parts of a request, e.g. each batch
+// of a streamed report
+pub(crate) async fn within<T>(
+    budget: Duration,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    if let Ok(res) = tokio::time::timeout(budget, future).await {
+        return res;
+    }
+
+    warn!(
+        budget_seconds = budget.as_secs(),
+        "Request exceeded its time budget"
+    );
+
+    Err(
+        PublicError::new(StatusCode::GATEWAY_TIMEOUT, "Request timed out")
+            .with_code("request_timeout"),
+    )
+}
@@ -0,0 +1,179 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+use surrealdb::{
+    Surreal,
+    engine::any::Any,
+    method::Query,
+    sql::{Statement, Subquery, Value},
+};
+use tracing::{Instrument as _, Level, debug, debug_span, info_span};
+
+use crate::{env::Env, metrics, router::current_request_id};
+
+// Parameter the ID of the request a query is sent for is bound to, when enabled by
+// `ARCHODEX_SURREALDB_REQUEST_ID_PARAM`
+const REQUEST_ID_PARAM: &str = "archodex_request_id";
+
+// Kind and target table of a statement, recorded on its span
+#[derive(Debug)]
+struct StatementSummary {
+    kind: &'static str,
+    table: Option<String>,
+}
+
+impl From<&Statement> for StatementSummary {
+    fn from(statement: &Statement) -> Self {
+        let (kind, target) = match statement {
+            Statement::Select(select) => ("SELECT", select.what.0.first()),
+            Statement::Insert(insert) => ("INSERT", insert.into.as_ref()),
+            Statement::Upsert(upsert) => ("UPSERT", upsert.what.0.first()),
+            Statement::Update(update) => ("UPDATE", update.what.0.first()),
+            Statement::Create(create) => ("CREATE", create.what.0.first()),
+            Statement::Delete(delete) => ("DELETE", delete.what.0.first()),
+            Statement::Relate(relate) => ("RELATE", Some(&relate.kind)),
+            // Report ingestion passes the results of inserts to later statements through parameters
+            Statement::Set(set) => match &set.what {
+                Value::Subquery(subquery) => match subquery.as_ref() {
+                    Subquery::Select(select) => ("LET SELECT", select.what.0.first()),
+                    Subquery::Insert(insert) => ("LET INSERT", insert.into.as_ref()),
+                    Subquery::Upsert(upsert) => ("LET UPSERT", upsert.what.0.first()),
+                    Subquery::Update(update) => ("LET UPDATE", update.what.0.first()),
+                    Subquery::Create(create) => ("LET CREATE", create.what.0.first()),
+                    Subquery::Delete(delete) => ("LET DELETE", delete.what.0.first()),
+                    Subquery::Relate(relate) => ("LET RELATE", Some(&relate.kind)),
+                    _ => ("LET", None),
+                },
+                _ => ("LET", None),
+            },
+            Statement::Ifelse(_) => ("IF", None),
+            Statement::Foreach(_) => ("FOR", None),
+            Statement::Output(_) => ("RETURN", None),
+            Statement::Value(_) => ("VALUE", None),
+            Statement::Begin(_) => ("BEGIN", None),
+            Statement::Commit(_) => ("COMMIT", None),
+            Statement::Cancel(_) => ("CANCEL", None),
+            Statement::Define(_) => ("DEFINE", None),
+            Statement::Remove(_) => ("REMOVE", None),
+            Statement::Rebuild(_) => ("REBUILD", None),
+            Statement::Info(_) => ("INFO", None),
+            Statement::Throw(_) => ("THROW", None),
+            _ => ("OTHER", None),
+        };
+
+        let table = target.and_then(|target| match target {
+            Value::Table(table) => Some(table.0.clone()),
+            Value::Thing(thing) => Some(thing.tb.clone()),
+            Value::Param(param) => Some(param.to_string()),
+            _ => None,
+        });
+
+        Self { kind, table }
+    }
+}
+
+// A query whose execution is traced statement by statement, so that a slow request can be broken down by the statements
+// it ran. The query gets a `surrealdb_query` span within the current (e.g. request) span, recording its statement and
+// binding counts and its latency metric. When debug logs of this module are enabled, each statement also gets a
+// `surrealdb_statement` child span recording its kind, target table, and the execution time SurrealDB reported.
+// Statements are only summarized when their spans are enabled, as summarizing re-parses them.
+#[derive(Debug)]
+pub(crate) struct TracedQuery<'r> {
+    name: &'static str,
+    query: Query<'r, Any>,
+    statements: Option<Vec<StatementSummary>>,
+    num_statements: usize,
+    num_bindings: usize,
+}
+
+impl<'r> TracedQuery<'r> {
+    // Starts an empty query named `name`, the label of its `surrealdb_query_duration_seconds` metric
+    pub(crate) fn new(db: &'r Surreal<Any>, name: &'static str) -> Self {
+        Self {
+            name,
+            query: db.query(Vec::<Statement>::new()),
+            statements: tracing::enabled!(Level::DEBUG).then(Vec::new),
+            num_statements: 0,
+            num_bindings: 0,
+        }
+    }
+
+    pub(crate) fn query(mut self, surql: impl surrealdb::opt::IntoQuery + Display) -> Self {
+        if let Some(statements) = &mut self.statements {
+            // Statements that fail to parse fail the query when it's sent, so they don't need summaries
+            if let Ok(query) = surrealdb::sql::parse(&surql.to_string()) {
+                statements.extend(query.0.0.iter().map(StatementSummary::from));
+            }
+        }
+
+        self.num_statements += 1;
+        self.query = self.query.query(surql);
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn num_statements(&self) -> usize {
+        self.num_statements
+    }
+
+    pub(crate) fn bind(mut self, bindings: impl Serialize + 'static) -> Self {
+        self.num_bindings += 1;
+        self.query = self.query.bind(bindings);
+        self
+    }
+
+    pub(crate) async fn execute(self) -> surrealdb::Result<surrealdb::Response> {
+        // Text added to the query may hold several statements, so the count is only exact once statements are summarized
+        let num_statements = self
+            .statements
+            .as_ref()
+            .map_or(self.num_statements, Vec::len);
+
+        let span = info_span!(
+            "surrealdb_query",
+            query = self.name,
+            statements = num_statements,
+            bindings = self.num_bindings,
+        );
+
+        let query = match current_request_id().filter(|_| Env::surrealdb_request_id_param()) {
+            Some(request_id) => self.query.bind((
+                REQUEST_ID_PARAM,
+                surrealdb::sql::Uuid::from(request_id.id()),
+            )),
+            None => self.query,
+        };
+
+        let response = metrics::time_query(self.name, query.with_stats())
+            .instrument(span.clone())
+            .await?;
+
+        if let Some(statements) = self.statements {
+            for (index, statement) in statements.into_iter().enumerate() {
+                // SurrealDB 2.3 only exposes a statement's stats without taking its result through this deprecated
+                // method
+                #[allow(deprecated)]
+                let stats = <usize as surrealdb::opt::QueryResult<surrealdb::Value>>::stats(
+                    &index,
+                    &response.0,
+                );
+
+                let duration_ms = stats
+                    .and_then(|stats| stats.execution_time)
+                    .map(|execution_time| execution_time.as_secs_f64() * 1000.0);
+
+                debug_span!(
+                    parent: &span,
+                    "surrealdb_statement",
+                    index,
+                    kind = statement.kind,
+                    table = statement.table.as_deref(),
+                    duration_ms,
+                )
+                .in_scope(|| debug!("Executed statement"));
+            }
+        }
+
+        Ok(response.into_inner())
+    }
+}
@@ -0,0 +1,207 @@
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use tracing::{info, instrument};
+use utoipa::ToSchema;
+
+use archodex_error::bad_request;
+
+use crate::{
+    Result,
+    account::{Account, AccountQueries as _, invalidate_cached_account},
+    audit_log::{self, AuditAction},
+    auth::DashboardAuth,
+    db::{QueryCheckFirstRealError as _, accounts_db_for_account},
+    resource::ResourceIdPart,
+    router::RequestId,
+};
+
+const MAX_RULES: usize = 100;
+
+// Rules that rewrite an account's reports before they are upserted, e.g. to fix the output of misconfigured agents
+// without redeploying them. Rules apply in order to every resource ID part, attribute map, and event type of a report,
+// including the resource IDs of event captures, so resources stay linked to their events.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub(crate) enum TransformationRule {
+    /// Renames resources of type `from`, e.g. `AWS Account` to `Account`
+    RenameResourceType { from: String, to: String },
+    /// Renames events of type `from`
+    RenameEventType { from: String, to: String },
+    /// Drops an attribute from reported resources, or only from resources of `resource_type` if set
+    DropAttribute {
+        attribute: String,
+        resource_type: Option<String>,
+    },
+    /// Replaces the `from` prefix of resource IDs with `to`, or only of resources of `resource_type` if set, e.g. to
+    /// strip an environment prefix one agent adds to its hostnames
+    RewriteIdPrefix {
+        from: String,
+        to: String,
+        resource_type: Option<String>,
+    },
+}
+
+fn matches_resource_type(resource_type: Option<&String>, part: &ResourceIdPart) -> bool {
+    resource_type.is_none_or(|resource_type| *resource_type == part.r#type)
+}
+
+// Applies the rules to a part of a resource ID. `attributes` are those of the resource the part identifies, or `None`
+// for parts of ancestors' IDs and of IDs in event captures.
+pub(crate) fn transform_resource(
+    rules: &[TransformationRule],
+    part: &mut ResourceIdPart,
+    mut attributes: Option<&mut Map<String, Value>>,
+) {
+    for rule in rules {
+        match rule {
+            TransformationRule::RenameResourceType { from, to } => {
+                if part.r#type == *from {
+                    part.r#type.clone_from(to);
+                }
+            }
+            TransformationRule::RenameEventType { .. } => {}
+            TransformationRule::DropAttribute {
+                attribute,
+                resource_type,
+            } => {
+                if let Some(attributes) = attributes.as_deref_mut()
+                    && matches_resource_type(resource_type.as_ref(), part)
+                {
+                    attributes.remove(attribute);
+                }
+            }
+            TransformationRule::RewriteIdPrefix {
+                from,
+                to,
+                resource_type,
+            } => {
+                // IDs are never rewritten to be empty, as reports with empty IDs are rejected
+                if matches_resource_type(resource_type.as_ref(), part)
+                    && let Some(rest) = part.id.strip_prefix(from.as_str())
+                    && !(rest.is_empty() && to.is_empty())
+                {
+                    part.id = format!("{to}{rest}");
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn transform_event_type(rules: &[TransformationRule], event_type: &mut String) {
+    for rule in rules {
+        if let TransformationRule::RenameEventType { from, to } = rule
+            && event_type == from
+        {
+            event_type.clone_from(to);
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct TransformationsResponse {
+    rules: Vec<TransformationRule>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/transformations",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    responses((status = 200, body = TransformationsResponse))
+)]
+#[instrument(err, skip_all)]
+pub(crate) async fn get_transformations(
+    Extension(account): Extension<Account>,
+) -> Result<Json<TransformationsResponse>> {
+    Ok(Json(TransformationsResponse {
+        rules: account.transformations().to_vec(),
+    }))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SetTransformationsRequest {
+    /// Rules applied in order to the account's reports. An empty list removes all rules.
+    rules: Vec<TransformationRule>,
+}
+
+fn validate_rule(rule: &TransformationRule) -> Result<()> {
+    match rule {
+        TransformationRule::RenameResourceType { from, to }
+        | TransformationRule::RenameEventType { from, to } => {
+            if from.is_empty() || to.is_empty() {
+                bad_request!("Types must not be empty");
+            }
+        }
+        TransformationRule::DropAttribute { attribute, .. } => {
+            if attribute.is_empty() {
+                bad_request!("Attribute must not be empty");
+            }
+        }
+        TransformationRule::RewriteIdPrefix { from, .. } => {
+            if from.is_empty() {
+                bad_request!("Prefix to rewrite must not be empty");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Sets the rules that rewrite the account's reports before they are upserted. Resources and events that were already
+// ingested aren't changed, so renaming a type or rewriting IDs leaves the resources and events ingested under the old
+// type or ID until they are pruned.
+#[utoipa::path(
+    put,
+    path = "/account/{account_id}/transformations",
+    tag = "accounts",
+    security(("dashboard" = [])),
+    params(("account_id" = String, Path)),
+    request_body = SetTransformationsRequest,
+    responses((status = 200, body = TransformationsResponse))
+)]
+#[instrument(err, skip(auth, account, request_id))]
+pub(crate) async fn set_transformations(
+    Extension(auth): Extension<DashboardAuth>,
+    Extension(account): Extension<Account>,
+    Extension(request_id): Extension<RequestId>,
+    Json(req): Json<SetTransformationsRequest>,
+) -> Result<Json<TransformationsResponse>> {
+    auth.validate_account_owner(account.id()).await?;
+
+    if req.rules.len() > MAX_RULES {
+        bad_request!("At most {MAX_RULES} transformation rules can be set");
+    }
+
+    for rule in &req.rules {
+        validate_rule(rule)?;
+    }
+
+    accounts_db_for_account(account.id())
+        .await?
+        .set_account_transformations_query(&account, &req.rules, auth.principal())?
+        .await?
+        .check_first_real_error()?;
+
+    invalidate_cached_account(account.id());
+
+    info!(
+        account_id = account.id(),
+        user_id = %auth.principal().id(),
+        rules = req.rules.len(),
+        "Set account transformations"
+    );
+
+    audit_log::record(
+        account.id(),
+        &auth,
+        request_id,
+        AuditAction::TransformationsSet,
+        Some(json!({ "rules": req.rules })),
+    )
+    .await;
+
+    Ok(Json(TransformationsResponse { rules: req.rules }))
+}
@@ -5,10 +5,15 @@ use tracing::instrument;
 use crate::{
     Result,
     account::Account,
-    db::{QueryCheckFirstRealError, accounts_db},
+    account_member::ACTIVE_ACCESS_CONDITION,
+    db::{QueryCheckFirstRealError, accounts_db_for_account, query_accounts_db_shards},
     surrealdb_deserializers,
 };
 
+// Account ID allocation gives up after this many generated IDs in a row are taken
+#[cfg(feature = "archodex-com")]
+const MAX_ACCOUNT_ID_ATTEMPTS: usize = 5;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct User {
     #[serde(deserialize_with = "surrealdb_deserializers::uuid::deserialize")]
@@ -20,9 +25,15 @@ impl User {
         Self { id }
     }
 
+    pub(crate) fn id(&self) -> Uuid {
+        self.id
+    }
+
+    // Creates the user's record in the accounts database shard holding an account, so that the user can be related to
+    // the account
     #[instrument(err)]
-    pub(crate) async fn ensure_user_record_exists(&self) -> Result<()> {
-        accounts_db()
+    pub(crate) async fn ensure_user_record_exists(&self, account_id: &str) -> Result<()> {
+        accounts_db_for_account(account_id)
             .await?
             .query("UPSERT $user RETURN NONE")
             .bind(("user", surrealdb::sql::Thing::from(self)))
@@ -32,27 +43,44 @@ impl User {
         Ok(())
     }
 
+    // Allocates a random ID for a new account by reserving it. IDs of existing accounts and IDs reserved by concurrent
+    // account creations are skipped, so the returned ID can be provisioned without conflicting with another account.
     #[cfg(feature = "archodex-com")]
     #[instrument(err)]
     pub(crate) async fn next_account_id(&self) -> Result<String> {
-        use crate::env::Env;
-        use archodex_error::{anyhow::anyhow, conflict};
+        use crate::{
+            account::get_account,
+            clock,
+            db::{is_record_exists_error, primary_accounts_db},
+            env::Env,
+            rng,
+        };
+        use archodex_error::conflict;
         use rand::Rng as _;
-        use tracing::info;
+        use tracing::{info, warn};
 
         #[derive(Deserialize)]
         struct NumUserAccountsResults {
             num_user_accounts: u32,
         }
 
-        let NumUserAccountsResults { num_user_accounts } = accounts_db()
-            .await?
-            .query("SELECT COUNT(->has_access->(account WHERE deleted_at IS NONE)) AS num_user_accounts FROM ONLY $user")
-            .bind(("user", surrealdb::sql::Thing::from(self)))
-            .await?
-            .check_first_real_error()?
-            .take::<Option<NumUserAccountsResults>>(0)?
-            .ok_or_else(|| anyhow!("Failed to query whether user has an account"))?;
+        let num_user_accounts = query_accounts_db_shards(|db| async move {
+            let NumUserAccountsResults { num_user_accounts } = db
+                .query(format!("SELECT COUNT(->(has_access WHERE {ACTIVE_ACCESS_CONDITION})->(account WHERE deleted_at IS NONE)) AS num_user_accounts FROM ONLY $user"))
+                .bind(("user", surrealdb::sql::Thing::from(self)))
+                .await?
+                .check_first_real_error()?
+                .take::<Option<NumUserAccountsResults>>(0)?
+                // The user record only exists in shards holding accounts the user has had access to
+                .unwrap_or(NumUserAccountsResults {
+                    num_user_accounts: 0,
+                });
+
+            Ok(vec![num_user_accounts])
+        })
+        .await?
+        .into_iter()
+        .sum::<u32>();
 
         info!(num_user_accounts, "Retrieved number of accounts for user");
 
@@ -60,13 +88,43 @@ impl User {
             conflict!("User account limit exceeded");
         }
 
-        let account_id = rand::thread_rng()
-            .gen_range::<u64, _>(1_000_000_000..=9_999_999_999)
-            .to_string();
-
-        info!(account_id, "Generated new account ID");
+        for _ in 0..MAX_ACCOUNT_ID_ATTEMPTS {
+            let account_id =
+                rng::with_rng(|rng| rng.gen_range::<u64, _>(1_000_000_000..=9_999_999_999))
+                    .to_string();
+
+            // Accounts created before IDs were reserved have no reservation
+            let reserved = if get_account(&account_id).await?.is_some() {
+                false
+            } else {
+                let res = primary_accounts_db()
+                    .await?
+                    .query("CREATE type::thing('account_id_reservation', $account_id) CONTENT { reserved_at: $now, reserved_by: $user } RETURN NONE")
+                    .bind(("account_id", account_id.clone()))
+                    .bind(("now", clock::now_value()))
+                    .bind(("user", surrealdb::sql::Thing::from(self)))
+                    .await?
+                    .check_first_real_error();
+
+                match res {
+                    Ok(_) => true,
+                    Err(err) if is_record_exists_error(&err) => false,
+                    Err(err) => return Err(err.into()),
+                }
+            };
+
+            if reserved {
+                info!(account_id, "Reserved new account ID");
+                return Ok(account_id);
+            }
+
+            warn!(
+                account_id,
+                "Generated account ID is already taken, retrying with a new ID"
+            );
+        }
 
-        Ok(account_id)
+        conflict!("Failed to allocate a new account ID, please try again");
     }
 
     #[instrument(err)]
@@ -76,15 +134,55 @@ impl User {
             accounts: Vec<Account>,
         }
 
-        Ok(accounts_db()
+        query_accounts_db_shards(|db| async move {
+            Ok(db
+                .query(format!("SELECT ->(has_access WHERE {ACTIVE_ACCESS_CONDITION})->(account WHERE deleted_at IS NONE).* AS accounts FROM ONLY $user"))
+                .bind(("user", surrealdb::sql::Thing::from(self)))
+                .await?
+                .check_first_real_error()?
+                .take::<Option<ListAccountResults>>(0)?
+                .unwrap_or_default()
+                .accounts)
+        })
+        .await
+    }
+
+    // Lists the live accounts the user owns, e.g. to apply a bulk operation to. Legacy `has_access` edges without a role
+    // were only created for account creators, so they are treated as owners.
+    #[instrument(err)]
+    pub(crate) async fn list_owned_accounts(&self) -> Result<Vec<Account>> {
+        #[derive(Default, Deserialize)]
+        struct ListAccountResults {
+            accounts: Vec<Account>,
+        }
+
+        query_accounts_db_shards(|db| async move {
+            Ok(db
+                .query(format!("SELECT ->(has_access WHERE ({ACTIVE_ACCESS_CONDITION}) AND (role ?? 'owner') == 'owner')->(account WHERE deleted_at IS NONE).* AS accounts FROM ONLY $user"))
+                .bind(("user", surrealdb::sql::Thing::from(self)))
+                .await?
+                .check_first_real_error()?
+                .take::<Option<ListAccountResults>>(0)?
+                .unwrap_or_default()
+                .accounts)
+        })
+        .await
+    }
+
+    // Whether the user owns the account. Legacy `has_access` edges without a role were only created for account creators,
+    // so they are treated as owners.
+    #[instrument(err)]
+    pub(crate) async fn is_account_owner(&self, account_id: &str) -> Result<bool> {
+        Ok(accounts_db_for_account(account_id)
             .await?
-            .query("SELECT ->has_access->(account WHERE deleted_at IS NONE).* AS accounts FROM ONLY $user")
+            .query("SELECT VALUE role ?? 'owner' FROM $user->has_access WHERE record::id(out) == $account_id")
             .bind(("user", surrealdb::sql::Thing::from(self)))
+            .bind(("account_id", account_id.to_string()))
             .await?
             .check_first_real_error()?
-            .take::<Option<ListAccountResults>>(0)?
-            .unwrap_or_default()
-            .accounts)
+            .take::<Vec<String>>(0)?
+            .iter()
+            .any(|role| role == "owner"))
     }
 }
 
@@ -0,0 +1,50 @@
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+// Cargo features that change the backend's behavior, in the order they are listed in the manifest
+const FEATURES: &[(&str, bool)] = &[
+    ("archodex-com", cfg!(feature = "archodex-com")),
+    ("chaos", cfg!(feature = "chaos")),
+    ("kafka", cfg!(feature = "kafka")),
+    ("rocksdb", cfg!(feature = "rocksdb")),
+    ("s3-backups", cfg!(feature = "s3-backups")),
+    ("sqs", cfg!(feature = "sqs")),
+];
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct VersionResponse {
+    /// Version of the backend crate
+    version: &'static str,
+    /// Commit the backend was built from, or `unknown` if it was built outside a git checkout
+    git_sha: &'static str,
+    built_at: DateTime<Utc>,
+    /// Cargo features the backend was built with, e.g. `archodex-com` and `rocksdb`
+    features: Vec<&'static str>,
+}
+
+// Returns the version and build of the running backend, e.g. so support can tell which build a self-hosted customer
+// runs. The build script injects the git SHA and build timestamp at compile time.
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "version",
+    responses((status = 200, body = VersionResponse))
+)]
+pub(crate) async fn version() -> Json<VersionResponse> {
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("ARCHODEX_GIT_SHA"),
+        built_at: env!("ARCHODEX_BUILD_TIMESTAMP")
+            .parse()
+            .ok()
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .expect("Build script should set a valid build timestamp"),
+        features: FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(feature, _)| *feature)
+            .collect(),
+    })
+}
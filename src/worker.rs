@@ -0,0 +1,153 @@
+use std::{collections::BTreeSet, fmt::Debug, sync::Mutex, time::Duration};
+
+use tokio::sync::Notify;
+use tracing::{Instrument as _, info, info_span, warn};
+
+use crate::{
+    Result,
+    account::{Account, get_account, list_live_accounts},
+};
+
+// Runs `job` every `interval` until the process exits. Failures are logged and the job runs again after the interval.
+pub(crate) async fn run_periodically<F, Fut, E>(job_name: &'static str, interval: Duration, job: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = std::result::Result<(), E>>,
+    E: Debug,
+{
+    info!(job = job_name, ?interval, "Starting worker");
+
+    loop {
+        if let Err(err) = job().instrument(info_span!("worker", job = job_name)).await {
+            warn!(job = job_name, ?err, "Worker run failed");
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// Accounts that requests handled by this process queued work for, e.g. events to deliver. Waking the worker lets it
+// process the work right away, and only in these accounts, instead of polling every account for work.
+pub(crate) struct PendingAccounts {
+    account_ids: Mutex<BTreeSet<String>>,
+    notify: Notify,
+}
+
+impl PendingAccounts {
+    pub(crate) const fn new() -> Self {
+        Self {
+            account_ids: Mutex::new(BTreeSet::new()),
+            notify: Notify::const_new(),
+        }
+    }
+
+    // Wakes the worker to process the account's work
+    pub(crate) fn mark(&self, account_id: &str) {
+        self.account_ids
+            .lock()
+            .expect("Pending accounts lock should not be poisoned")
+            .insert(account_id.to_string());
+
+        self.notify.notify_one();
+    }
+
+    fn take(&self) -> BTreeSet<String> {
+        std::mem::take(
+            &mut *self
+                .account_ids
+                .lock()
+                .expect("Pending accounts lock should not be poisoned"),
+        )
+    }
+}
+
+// Runs `job` for the accounts in `pending` whenever accounts are marked, and for every account every `sweep_interval`.
+// Sweeps pick up work that isn't marked, e.g. retries whose backoff elapsed and work queued by other backend instances.
+// `job` is passed the IDs of the accounts to process, or `None` for every account.
+pub(crate) async fn run_on_demand<F, Fut, E>(
+    job_name: &'static str,
+    pending: &'static PendingAccounts,
+    sweep_interval: Duration,
+    job: F,
+) where
+    F: Fn(Option<BTreeSet<String>>) -> Fut,
+    Fut: Future<Output = std::result::Result<(), E>>,
+    E: Debug,
+{
+    info!(job = job_name, ?sweep_interval, "Starting worker");
+
+    let mut next_sweep = tokio::time::Instant::now();
+
+    loop {
+        let account_ids = tokio::select! {
+            () = tokio::time::sleep_until(next_sweep) => {
+                next_sweep = tokio::time::Instant::now() + sweep_interval;
+                // Accounts marked before the sweep are processed by it
+                pending.take();
+                None
+            }
+            () = pending.notify.notified() => Some(pending.take()),
+        };
+
+        if account_ids.as_ref().is_some_and(BTreeSet::is_empty) {
+            continue;
+        }
+
+        if let Err(err) = job(account_ids)
+            .instrument(info_span!("worker", job = job_name))
+            .await
+        {
+            warn!(job = job_name, ?err, "Worker run failed");
+        }
+    }
+}
+
+// Runs `job` for each live account with a provisioned resources database, or only for the accounts in `account_ids` if
+// set. Failures are logged per account, so an account that fails doesn't hold up the others.
+pub(crate) async fn for_each_account<F, Fut, E>(
+    job_name: &'static str,
+    account_ids: Option<BTreeSet<String>>,
+    job: F,
+) -> Result<()>
+where
+    F: Fn(Account) -> Fut,
+    Fut: Future<Output = std::result::Result<(), E>>,
+    E: Debug,
+{
+    let accounts = match account_ids {
+        Some(account_ids) => {
+            let mut accounts = Vec::with_capacity(account_ids.len());
+
+            for account_id in account_ids {
+                if let Some(account) = get_account(&account_id).await?
+                    && !account.is_deleted()
+                {
+                    accounts.push(account);
+                }
+            }
+
+            accounts
+        }
+        None => list_live_accounts().await?,
+    };
+
+    for account in accounts {
+        #[cfg(feature = "archodex-com")]
+        if account.service_data_surrealdb_url().is_none() {
+            continue;
+        }
+
+        let account_id = account.id().to_string();
+
+        if let Err(err) = job(account).await {
+            warn!(
+                job = job_name,
+                account_id,
+                ?err,
+                "Worker run failed for account"
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::{path::Path, process::Command};
+
+use anyhow::{Context as _, bail};
+
+use crate::workspace_root;
+
+// Runs a command, failing if it can't be started or exits unsuccessfully
+fn run(command: &mut Command, description: &str) -> anyhow::Result<()> {
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to {description}"))?;
+
+    if !status.success() {
+        bail!("Failed to {description}: exited with {status}");
+    }
+
+    Ok(())
+}
+
+pub(crate) fn run_all(out_dir: &Path) -> anyhow::Result<()> {
+    // clientgen runs from the workspace root, so it is given an absolute path
+    let out_dir = std::path::absolute(out_dir)
+        .with_context(|| format!("Failed to resolve output directory {}", out_dir.display()))?;
+
+    run(
+        Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+            .args(["run", "--package", "clientgen", "--"])
+            .arg(&out_dir)
+            .current_dir(workspace_root()),
+        "generate clients",
+    )?;
+
+    let typescript = out_dir.join("typescript");
+
+    run(
+        Command::new("npm").arg("install").current_dir(&typescript),
+        "install TypeScript client dependencies",
+    )?;
+    run(
+        Command::new("npm")
+            .args(["run", "build"])
+            .current_dir(&typescript),
+        "build TypeScript client",
+    )?;
+    // The package's build only compiles `src/`. The example reads its settings from `process.env`, so it needs Node.js
+    // types, which aren't a dependency of the client.
+    run(
+        Command::new("npm")
+            .args(["install", "--no-save", "@types/node"])
+            .current_dir(&typescript),
+        "install Node.js types",
+    )?;
+    run(
+        Command::new("npx")
+            .args([
+                "tsc",
+                "--noEmit",
+                "--strict",
+                "--target",
+                "es2020",
+                "--module",
+                "commonjs",
+                "--lib",
+                "es2020,dom",
+                "--types",
+                "node",
+                "examples/report.ts",
+            ])
+            .current_dir(&typescript),
+        "type-check TypeScript example",
+    )?;
+
+    // Builds the wheel into `python/dist/`
+    run(
+        Command::new("python3")
+            .args([
+                "-m",
+                "pip",
+                "wheel",
+                "--no-deps",
+                "--wheel-dir",
+                "dist",
+                ".",
+            ])
+            .current_dir(out_dir.join("python")),
+        "build Python client",
+    )?;
+    run(
+        Command::new("python3")
+            .args(["-m", "py_compile", "examples/report.py"])
+            .current_dir(out_dir.join("python")),
+        "compile Python example",
+    )?;
+
+    // Builds the example too, as it is a package of the client's module
+    run(
+        Command::new("go")
+            .args(["build", "./..."])
+            .current_dir(out_dir.join("go")),
+        "build Go client",
+    )?;
+
+    println!("Built clients in {}", out_dir.display());
+
+    Ok(())
+}
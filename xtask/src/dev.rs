@@ -0,0 +1,101 @@
+use std::{
+    net::TcpStream,
+    process::{Child, Command},
+    time::Duration,
+};
+
+use anyhow::{Context as _, bail};
+
+use crate::{BACKEND_URL, DEV_AUTH_TOKEN, DEV_AUTH_USER_ID, seed, workspace_root};
+
+const SURREALDB_ADDRESS: &str = "127.0.0.1:8000";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Kills the child process when dropped, so SurrealDB isn't left running when the backend fails
+struct ChildGuard(Child);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_surrealdb() -> anyhow::Result<ChildGuard> {
+    let surrealdb = Command::new("surreal")
+        .args([
+            "start",
+            "--bind",
+            SURREALDB_ADDRESS,
+            "--user",
+            "root",
+            "--pass",
+            "root",
+            "--log",
+            "warn",
+            "memory",
+        ])
+        .spawn()
+        .context("Failed to start SurrealDB. Is the `surreal` CLI installed (see https://surrealdb.com/install)?")?;
+    let mut surrealdb = ChildGuard(surrealdb);
+
+    while TcpStream::connect(SURREALDB_ADDRESS).is_err() {
+        if let Some(status) = surrealdb.0.try_wait()? {
+            bail!("SurrealDB exited with {status}. Is {SURREALDB_ADDRESS} already in use?");
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    Ok(surrealdb)
+}
+
+// The backend is built on first run, so there is no time limit on it becoming healthy
+fn start_backend() -> anyhow::Result<ChildGuard> {
+    let backend = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .args(["run", "--package", "server"])
+        .current_dir(workspace_root())
+        .env("SURREALDB_URL", format!("ws://{SURREALDB_ADDRESS}"))
+        .env("SURREALDB_USERNAME", "root")
+        .env("SURREALDB_PASSWORD", "root")
+        .env("ARCHODEX_DEV_AUTH_TOKEN", DEV_AUTH_TOKEN)
+        .env("ARCHODEX_DEV_AUTH_USER_ID", DEV_AUTH_USER_ID)
+        .spawn()
+        .context("Failed to start backend")?;
+    let mut backend = ChildGuard(backend);
+
+    let client = reqwest::blocking::Client::new();
+
+    while !client
+        .get(format!("{BACKEND_URL}/readyz"))
+        .send()
+        .is_ok_and(|response| response.status().is_success())
+    {
+        if let Some(status) = backend.0.try_wait()? {
+            bail!("Backend exited with {status}");
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+
+    Ok(backend)
+}
+
+pub(crate) fn run() -> anyhow::Result<()> {
+    let _surrealdb = start_surrealdb()?;
+    let mut backend = start_backend()?;
+
+    seed::run(BACKEND_URL, DEV_AUTH_TOKEN)?;
+
+    println!();
+    println!("Press Ctrl-C to stop the backend and SurrealDB. All data is lost when they stop.");
+
+    let status = backend.0.wait().context("Failed to wait for backend")?;
+
+    if !status.success() {
+        bail!("Backend exited with {status}");
+    }
+
+    Ok(())
+}
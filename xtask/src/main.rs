@@ -0,0 +1,57 @@
+//! Developer workflow automation, run with `cargo xtask <command>`.
+//!
+//! - `cargo xtask dev` starts an in-memory database with `surreal start` and the self-hosted backend against it, then
+//!   seeds a demo account. Requires the `surreal` CLI on the `PATH`. Data is lost when the command exits.
+//! - `cargo xtask seed [backend URL]` creates a demo account in a running backend with dev auth enabled (default
+//!   `http://localhost:5732`), or reuses its existing account, and reports demo resources and events to it.
+//! - `cargo xtask proto [output directory]` generates the Rust code of the report protobuf schemas into a directory
+//!   (default `target/proto`) for review. Builds generate their own copy, so this is never needed to build.
+//! - `cargo xtask clients [output directory]` generates the TypeScript, Python, and Go API clients and their example
+//!   agents with `clientgen` into a directory (default `target/clients`), then builds each client with its own
+//!   toolchain. Requires Node.js, Java, Python with pip, and Go on the `PATH`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+
+mod clients;
+mod dev;
+mod proto;
+mod seed;
+
+const USAGE: &str = "Usage: cargo xtask <dev | seed [backend URL] | proto [output directory] | clients [output directory]>";
+
+// Dashboard token the backend started by `cargo xtask dev` accepts, and the token `cargo xtask seed` authenticates with
+// unless `ARCHODEX_DEV_AUTH_TOKEN` is set
+const DEV_AUTH_TOKEN: &str = "archodex-xtask-dev-dashboard-token";
+
+const DEV_AUTH_USER_ID: &str = "00000000-0000-0000-0000-000000000000";
+
+const BACKEND_URL: &str = "http://localhost:5732";
+
+fn workspace_root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("xtask crate should be in the workspace root")
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+
+    match (args.next().as_deref(), args.next(), args.next()) {
+        (Some("dev"), None, None) => dev::run(),
+        (Some("seed"), url, None) => {
+            let token = std::env::var("ARCHODEX_DEV_AUTH_TOKEN")
+                .unwrap_or_else(|_| DEV_AUTH_TOKEN.to_string());
+
+            seed::run(url.as_deref().unwrap_or(BACKEND_URL), &token)
+        }
+        (Some("proto"), out_dir, None) => proto::run(
+            &out_dir.map_or_else(|| workspace_root().join("target/proto"), PathBuf::from),
+        ),
+        (Some("clients"), out_dir, None) => clients::run_all(
+            &out_dir.map_or_else(|| workspace_root().join("target/clients"), PathBuf::from),
+        ),
+        _ => bail!(USAGE),
+    }
+}
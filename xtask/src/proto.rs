@@ -0,0 +1,27 @@
+use std::path::Path;
+
+use anyhow::Context as _;
+
+use crate::workspace_root;
+
+// Schemas compiled by the backend's build script, relative to `src/`
+const PROTOS: [&str; 2] = ["report_api_key.proto", "report.proto"];
+
+pub(crate) fn run(out_dir: &Path) -> anyhow::Result<()> {
+    let src_dir = workspace_root().join("src");
+
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create directory {}", out_dir.display()))?;
+
+    prost_build::Config::new()
+        .out_dir(out_dir)
+        .compile_protos(
+            &PROTOS.map(|proto| src_dir.join(proto)),
+            std::slice::from_ref(&src_dir),
+        )
+        .context("Failed to compile protobuf schemas")?;
+
+    println!("Generated protobuf code in {}", out_dir.display());
+
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use anyhow::{Context as _, bail};
+use reqwest::blocking::{Client, RequestBuilder};
+use serde_json::{Value, json};
+
+// ID of the account created when the backend has none. Self-hosted account IDs are any 10-digit number.
+const DEMO_ACCOUNT_ID: &str = "1000000001";
+
+// Sends a request and returns its JSON response, failing with the response body on error statuses
+fn send(request: RequestBuilder) -> anyhow::Result<Value> {
+    let response = request
+        .send()
+        .context("Failed to send request to backend")?;
+    let status = response.status();
+    let body = response.text().context("Failed to read backend response")?;
+
+    if !status.is_success() {
+        bail!("Backend responded with {status}: {body}");
+    }
+
+    if body.is_empty() {
+        return Ok(Value::Null);
+    }
+
+    serde_json::from_str(&body).context("Backend responded with invalid JSON")
+}
+
+// A small AWS account with a function reading a secret through its role, so the dashboard has resources, events, and a
+// principal chain to show
+fn demo_report() -> Value {
+    let now = chrono::Utc::now().to_rfc3339();
+    let seen = |mut value: Value| {
+        value["first_seen_at"] = json!(now);
+        value["last_seen_at"] = json!(now);
+        value
+    };
+
+    let account = [
+        json!({ "type": "Partition", "id": "aws" }),
+        json!({ "type": "Account", "id": "123456789012" }),
+    ];
+    let region = json!({ "type": "Region", "id": "us-east-1" });
+    let function = json!({ "type": "Lambda Function", "id": "orders-api" });
+    let role = json!({ "type": "IAM Role", "id": "orders-api" });
+    let secret = json!({ "type": "Secret", "id": "orders/database-password" });
+
+    json!({
+        "resource_captures": [seen(json!({
+            "type": "Partition",
+            "id": "aws",
+            "globally_unique": true,
+            "contains": [seen(json!({
+                "type": "Account",
+                "id": "123456789012",
+                "contains": [
+                    seen(json!({
+                        "type": "Region",
+                        "id": "us-east-1",
+                        "contains": [
+                            seen(json!({ "type": "Lambda Function", "id": "orders-api" })),
+                            seen(json!({
+                                "type": "Secret",
+                                "id": "orders/database-password",
+                                "attributes": { "rotation_enabled": true },
+                            })),
+                        ],
+                    })),
+                    seen(json!({ "type": "IAM Role", "id": "orders-api" })),
+                ],
+            }))],
+        }))],
+        "event_captures": [{
+            "principals": [
+                { "id": [&account[0], &account[1], &region, &function] },
+                { "id": [&account[0], &account[1], &role] },
+            ],
+            "resources": [[&account[0], &account[1], &region, &secret]],
+            "events": [seen(json!({ "type": "read" }))],
+        }],
+    })
+}
+
+pub(crate) fn run(url: &str, token: &str) -> anyhow::Result<()> {
+    let url = url.trim_end_matches('/');
+    let client = Client::new();
+
+    let accounts = send(client.get(format!("{url}/accounts")).bearer_auth(token)).context(
+        "Failed to list accounts. Is the backend running with ARCHODEX_DEV_AUTH_TOKEN set?",
+    )?;
+
+    // Self-hosted backends have at most one account
+    let account_id = if let Some(account_id) = accounts["accounts"][0]["id"].as_str() {
+        println!("Using existing account {account_id}");
+        account_id.to_string()
+    } else {
+        let account = send(
+            client
+                .post(format!("{url}/accounts"))
+                .bearer_auth(token)
+                .json(&json!({ "account_id": DEMO_ACCOUNT_ID })),
+        )
+        .context("Failed to create demo account")?;
+
+        let account_id = account["id"]
+            .as_str()
+            .context("Created account has no ID")?
+            .to_string();
+        println!("Created account {account_id}");
+        account_id
+    };
+
+    let report_api_key = send(
+        client
+            .post(format!("{url}/account/{account_id}/report_api_keys"))
+            .bearer_auth(token)
+            .json(&json!({ "description": "Created by cargo xtask seed" })),
+    )
+    .context("Failed to create report API key")?;
+
+    let report_api_key_value = report_api_key["report_api_key_value"]
+        .as_str()
+        .context("Created report API key has no value")?;
+
+    send(
+        client
+            .post(format!("{url}/report"))
+            .header(reqwest::header::AUTHORIZATION, report_api_key_value)
+            .json(&demo_report()),
+    )
+    .context("Failed to report demo resources and events")?;
+
+    println!("Reported demo resources and events");
+    println!();
+    println!("Backend:         {url}");
+    println!("Account ID:      {account_id}");
+    println!("Dashboard token: {token}");
+    println!("Report API key:  {report_api_key_value}");
+
+    Ok(())
+}